@@ -0,0 +1,551 @@
+// Advanced Interrupt Architecture (https://github.com/riscv/riscv-aia, v1.0), like QEMU virt's aia=aplic and aia=aplic-imsic
+// APLICs take the interrupt lines of devices, each domain either signals harts through an interrupt delivery control
+// per hart (direct mode) or writes MSIs to IMSICs. There is an M-level root domain and a S-level child it can delegate to.
+// IMSIC interrupt files are hart state (`CPU::interrupt_files`), reached through the *iselect/*ireg/*topei CSRs,
+// their MMIO pages only queue the identities written to them until the hart they belong to steps.
+// Guest interrupt files and the major interrupt priorities (iprio) aren't implemented, iprio reads 0.
+use serde::Deserialize;
+
+use crate::board::DeviceKind;
+use crate::mem::MemoryRegion;
+use crate::vm::VM;
+use crate::uguest;
+
+/// Interrupt identities of an interrupt file, like QEMU's default
+pub const IMSIC_IDS: uguest = 255;
+/// Interrupt sources of an APLIC domain, like QEMU virt's
+pub const APLIC_SOURCES: usize = 96;
+/// Each hart has a 4KiB page in the IMSIC of each level
+pub const IMSIC_HART_STRIDE: uguest = 0x1000;
+
+// Registers *iselect selects
+pub const ISELECT_IPRIO0: uguest = 0x30;
+pub const ISELECT_IPRIO15: uguest = 0x3F;
+pub const ISELECT_EIDELIVERY: uguest = 0x70;
+pub const ISELECT_EITHRESHOLD: uguest = 0x72;
+pub const ISELECT_EIP0: uguest = 0x80;
+pub const ISELECT_EIE0: uguest = 0xC0;
+pub const ISELECT_EIE63: uguest = 0xFF;
+
+/// M-level or S-level, the index in `CPU::interrupt_files`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    #[default]
+    Machine,
+    Supervisor,
+}
+impl Level {
+    /// MEIP or SEIP, the bit of mip the level's external interrupt sets
+    pub fn eip(self) -> uguest {
+        match self {
+            Self::Machine => 1 << 11,
+            Self::Supervisor => 1 << 9,
+        }
+    }
+}
+
+const WORDS: usize = (IMSIC_IDS as usize).div_ceil(64);
+/// An IMSIC interrupt file: pending and enabled bits for each identity, identity 0 doesn't exist
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InterruptFile {
+    pending: [u64; WORDS],
+    enabled: [u64; WORDS],
+    delivery: bool,
+    threshold: uguest,
+}
+impl InterruptFile {
+    pub fn set_pending(&mut self, id: uguest) {
+        if (1..=IMSIC_IDS).contains(&id) {
+            self.pending[id as usize / 64] |= 1 << (id % 64);
+        }
+    }
+    /// Lowest identity pending and enabled, under the threshold when there is one, 0 if there is none
+    pub fn top(&self) -> uguest {
+        let Some((word, bits)) = self.pending.iter().zip(self.enabled).map(|(pending, enabled)| pending & enabled)
+            .enumerate().find(|(_, bits)| *bits != 0) else {return 0};
+        let id = word as uguest * 64 + bits.trailing_zeros() as uguest;
+        if self.threshold == 0 || id < self.threshold {id} else {0}
+    }
+    /// Level of the external interrupt the file raises
+    pub fn interrupt(&self) -> bool {
+        self.delivery && self.top() != 0
+    }
+    /// Identity and priority (the same here) of the top interrupt, what *topei reads
+    pub fn topei(&self) -> uguest {
+        let top = self.top();
+        top << 16 | top
+    }
+    /// A write to *topei, the top interrupt isn't pending anymore
+    pub fn claim(&mut self) {
+        let top = self.top();
+        self.pending[top as usize / 64] &= !(1 << (top % 64));
+    }
+    /// Registers *iselect can select, eip and eie are 64 bits so odd ones don't exist
+    pub fn valid_select(select: uguest) -> bool {
+        matches!(select, ISELECT_IPRIO0..=ISELECT_IPRIO15 | ISELECT_EIDELIVERY | ISELECT_EITHRESHOLD)
+            || ((ISELECT_EIP0..=ISELECT_EIE63).contains(&select) && select.is_multiple_of(2))
+    }
+    /// Bits of the eip or eie register `select`, none above the implemented identities
+    fn bits(&mut self, select: uguest) -> Option<&mut u64> {
+        let bits = if select >= ISELECT_EIE0 {&mut self.enabled} else {&mut self.pending};
+        bits.get_mut((select - ISELECT_EIP0) as usize % 64 / 2)
+    }
+    /// *ireg, with *iselect = `select`
+    pub fn read(&self, select: uguest) -> uguest {
+        match select {
+            ISELECT_EIDELIVERY => self.delivery as uguest,
+            ISELECT_EITHRESHOLD => self.threshold,
+            ISELECT_EIP0..=ISELECT_EIE63 => self.clone().bits(select).map_or(0, |bits| *bits),
+            _ => 0,
+        }
+    }
+    pub fn write(&mut self, select: uguest, value: uguest) {
+        match select {
+            // Only IMSIC delivery, not the one from an APLIC (0x4000_0000)
+            ISELECT_EIDELIVERY => self.delivery = value & 1 != 0,
+            ISELECT_EITHRESHOLD if value <= IMSIC_IDS => self.threshold = value,
+            ISELECT_EIP0..=ISELECT_EIE63 => {
+                let first = (select - ISELECT_EIP0).is_multiple_of(64);
+                if let Some(bits) = self.bits(select) {
+                    *bits = if first {value & !1} else {value};
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// The IMSIC pages of a level: writing an identity to seteipnum_le (seteipnum_be 4 bytes after) of a hart's page
+/// makes it pending in its interrupt file
+#[derive(Debug, Clone, Default)]
+pub struct Imsic {
+    pub level: Level,
+    /// Hart and identity of the MSIs received and not delivered yet
+    messages: Vec<(uguest, uguest)>,
+}
+impl Imsic {
+    pub fn new(level: Level) -> Self {
+        Self { level, messages: Vec::new() }
+    }
+    /// Identities sent to `hart`, they leave the queue
+    pub fn take_messages(&mut self, hart: uguest) -> Vec<uguest> {
+        let (taken, kept) = self.messages.drain(..).partition(|(target, _)| *target == hart);
+        self.messages = kept;
+        taken.into_iter().map(|(_, id)| id).collect()
+    }
+}
+impl MemoryRegion for Imsic {
+    fn read(&mut self, _offset: uguest) -> u8 {
+        0
+    }
+    /// Only whole 32 bits writes
+    fn write(&mut self, _offset: uguest, _val: u8) {}
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        let Ok(word) = <[u8; 4]>::try_from(buffer) else {return};
+        let id = match offset % IMSIC_HART_STRIDE {
+            0 => u32::from_le_bytes(word),
+            4 => u32::from_be_bytes(word),
+            _ => return,
+        };
+        self.messages.push((offset / IMSIC_HART_STRIDE, id as uguest));
+    }
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {
+        crate::snapshot::cloned(self)
+    }
+    fn restore(&mut self, state: &dyn std::any::Any) -> color_eyre::Result<()> {
+        crate::snapshot::restore_cloned(self, state)
+    }
+}
+
+// APLIC registers, the ones of the interrupt delivery controls are relative to the control of the hart
+const DOMAINCFG: uguest = 0x0000;
+const SOURCECFG: uguest = 0x0004;
+const MMSIADDRCFG: uguest = 0x1BC0;
+const SMSIADDRCFGH: uguest = 0x1BCC;
+const SETIP: uguest = 0x1C00;
+const SETIPNUM: uguest = 0x1CDC;
+const IN_CLRIP: uguest = 0x1D00;
+const CLRIPNUM: uguest = 0x1DDC;
+const SETIE: uguest = 0x1E00;
+const SETIENUM: uguest = 0x1EDC;
+const CLRIE: uguest = 0x1F00;
+const CLRIENUM: uguest = 0x1FDC;
+const SETIPNUM_LE: uguest = 0x2000;
+const SETIPNUM_BE: uguest = 0x2004;
+const GENMSI: uguest = 0x3000;
+const TARGET: uguest = 0x3004;
+const IDC: uguest = 0x4000;
+const IDC_SIZE: uguest = 32;
+const IDELIVERY: uguest = 0x00;
+const IFORCE: uguest = 0x04;
+const ITHRESHOLD: uguest = 0x08;
+const TOPI: uguest = 0x18;
+const CLAIMI: uguest = 0x1C;
+
+/// domaincfg bits: interrupts enabled, MSI delivery mode
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+/// sourcecfg.D, the source is delegated to a child domain
+const SOURCECFG_D: u32 = 1 << 10;
+/// genmsi.Busy
+const GENMSI_BUSY: u32 = 1 << 12;
+
+/// Source modes of sourcecfg.SM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceMode {
+    Inactive,
+    Detached,
+    EdgeRising,
+    EdgeFalling,
+    LevelHigh,
+    LevelLow,
+}
+impl SourceMode {
+    fn new(sm: u32) -> Option<Self> {
+        Some(match sm {
+            0 => Self::Inactive,
+            1 => Self::Detached,
+            4 => Self::EdgeRising,
+            5 => Self::EdgeFalling,
+            6 => Self::LevelHigh,
+            7 => Self::LevelLow,
+            _ => return None,
+        })
+    }
+    fn level_sensitive(self) -> bool {
+        matches!(self, Self::LevelHigh | Self::LevelLow)
+    }
+}
+
+/// Interrupt delivery control of a hart, for direct mode
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Idc {
+    delivery: bool,
+    force: bool,
+    threshold: u32,
+}
+
+/// An APLIC interrupt domain, sources are numbered from 1
+#[derive(Debug, Clone)]
+pub struct Aplic {
+    pub level: Level,
+    domaincfg: u32,
+    sourcecfg: Vec<u32>,
+    /// Sources this domain handles, the parent delegates them (all of them for the root domain)
+    delegated: Vec<bool>,
+    pending: Vec<bool>,
+    enabled: Vec<bool>,
+    /// Rectified input of each source at the last update
+    input: Vec<bool>,
+    target: Vec<u32>,
+    /// mmsiaddrcfg, mmsiaddrcfgh, smsiaddrcfg and smsiaddrcfgh, only the root domain has them
+    msiaddrcfg: [u32; 4],
+    genmsi: u32,
+    idcs: Vec<Idc>,
+}
+impl Aplic {
+    /// The root domain is the M-level one, it handles every source until it delegates them
+    pub fn new(level: Level, harts: usize) -> Self {
+        let sources = APLIC_SOURCES + 1;
+        Self {
+            level,
+            domaincfg: 0,
+            sourcecfg: vec![0; sources],
+            delegated: vec![level == Level::Machine; sources],
+            pending: vec![false; sources],
+            enabled: vec![false; sources],
+            input: vec![false; sources],
+            target: vec![0; sources],
+            msiaddrcfg: [0; 4],
+            genmsi: 0,
+            idcs: vec![Idc::default(); harts],
+        }
+    }
+    fn source(offset: uguest, base: uguest) -> usize {
+        ((offset - base) / 4) as usize + 1
+    }
+    fn mode(&self, source: usize) -> SourceMode {
+        let cfg = self.sourcecfg[source];
+        if !self.delegated[source] || cfg & SOURCECFG_D != 0 {return SourceMode::Inactive}
+        SourceMode::new(cfg & 0b111).unwrap_or(SourceMode::Inactive)
+    }
+    fn msi_mode(&self) -> bool {
+        self.domaincfg & DOMAINCFG_DM != 0
+    }
+    /// Sources this domain delegates to its child
+    pub fn delegations(&self) -> Vec<bool> {
+        self.sourcecfg.iter().zip(&self.delegated).map(|(cfg, delegated)| *delegated && cfg & SOURCECFG_D != 0).collect()
+    }
+    /// Given by the parent domain, sources taken back lose their configuration
+    pub fn set_delegated(&mut self, delegated: Vec<bool>) {
+        for (source, delegated) in delegated.iter().enumerate().skip(1) {
+            if !delegated {
+                (self.sourcecfg[source], self.pending[source], self.enabled[source], self.target[source]) = (0, false, false, 0);
+            }
+        }
+        self.delegated = delegated;
+    }
+
+    fn set_pending(&mut self, source: usize, pending: bool) {
+        let Some(mode) = self.sourcecfg.get(source).and(Some(self.mode(source))) else {return};
+        match mode {
+            SourceMode::Inactive => {},
+            // The pending bit follows the input in direct mode, in MSI mode it can only be set while the input is high
+            SourceMode::LevelHigh | SourceMode::LevelLow if !self.msi_mode() => {},
+            SourceMode::LevelHigh | SourceMode::LevelLow if pending => self.pending[source] = self.input[source],
+            _ => self.pending[source] = pending,
+        }
+    }
+    fn set_enabled(&mut self, source: usize, enabled: bool) {
+        if source < self.enabled.len() && self.mode(source) != SourceMode::Inactive {
+            self.enabled[source] = enabled;
+        }
+    }
+    /// Bits of `bits` for the 32 sources of word `word`
+    fn word(bits: &[bool], word: usize) -> u32 {
+        (0..32).filter(|bit| bits.get(word*32 + bit).copied().unwrap_or(false)).fold(0, |word, bit| word | 1 << bit)
+    }
+
+    /// Samples the interrupt lines, `raised` are the sources whose line is high
+    pub fn update(&mut self, raised: &[u32]) {
+        for source in 1..self.sourcecfg.len() {
+            let line = raised.contains(&(source as u32));
+            let mode = self.mode(source);
+            let input = match mode {
+                SourceMode::EdgeRising | SourceMode::LevelHigh => line,
+                SourceMode::EdgeFalling | SourceMode::LevelLow => !line,
+                SourceMode::Inactive | SourceMode::Detached => false,
+            };
+            if mode.level_sensitive() && !self.msi_mode() {
+                self.pending[source] = input;
+            } else if mode.level_sensitive() && !input {
+                self.pending[source] = false;
+            } else if input && !self.input[source] {
+                self.pending[source] = true;
+            }
+            self.input[source] = input;
+        }
+    }
+    /// Hart index of the target of `source`
+    fn hart(&self, source: usize) -> usize {
+        (self.target[source] >> 18) as usize
+    }
+    /// Source and priority of the top interrupt of `hart` in direct mode
+    fn top(&self, hart: usize) -> Option<(usize, u32)> {
+        let threshold = self.idcs.get(hart)?.threshold;
+        (1..self.sourcecfg.len())
+            .filter(|&source| self.pending[source] && self.enabled[source] && self.hart(source) == hart)
+            .map(|source| (source, self.target[source] & 0xFF))
+            .filter(|(_, priority)| threshold == 0 || *priority < threshold)
+            .min_by_key(|(source, priority)| (*priority, *source))
+    }
+    fn topi(&self, hart: usize) -> u32 {
+        self.top(hart).map_or(0, |(source, priority)| (source as u32) << 16 | priority)
+    }
+    /// Level of the external interrupt of `hart` in direct mode
+    pub fn interrupt(&self, hart: usize) -> bool {
+        let Some(idc) = self.idcs.get(hart) else {return false};
+        !self.msi_mode() && self.domaincfg & DOMAINCFG_IE != 0 && idc.delivery && (idc.force || self.top(hart).is_some())
+    }
+    /// Address the MSIs to `hart` go to, from the msiaddrcfg registers of the root domain (`config`)
+    pub fn msi_address(config: [u32; 4], level: Level, hart: usize) -> uguest {
+        let high = config[1] as uguest;
+        let (lhxw, hhxw, hhxs) = (high >> 12 & 0xF, high >> 16 & 0x7, high >> 24 & 0x1F);
+        let (low, ppn_high, lhxs) = match level {
+            Level::Machine => (config[0], high, high >> 20 & 0x7),
+            Level::Supervisor => (config[2], config[3] as uguest, config[3] as uguest >> 20 & 0x7),
+        };
+        let ppn = (ppn_high & 0xFFF) << 32 | low as uguest;
+        let hart = hart as uguest;
+        let group = (hart >> lhxw) & ((1 << hhxw) - 1);
+        let index = hart & ((1 << lhxw) - 1);
+        (ppn | group << (hhxs + 12) | index << lhxs) << 12
+    }
+    pub fn msiaddrcfg(&self) -> [u32; 4] {
+        self.msiaddrcfg
+    }
+    /// In MSI mode, the MSIs (hart and identity) to send for the pending and enabled sources, which stop being pending
+    pub fn messages(&mut self) -> Vec<(usize, u32)> {
+        let mut messages = Vec::new();
+        if self.genmsi & GENMSI_BUSY != 0 {
+            messages.push(((self.genmsi >> 18) as usize, self.genmsi & 0x7FF));
+            self.genmsi &= !GENMSI_BUSY;
+        }
+        if !self.msi_mode() || self.domaincfg & DOMAINCFG_IE == 0 {return messages}
+        for source in 1..self.sourcecfg.len() {
+            if self.pending[source] && self.enabled[source] {
+                self.pending[source] = false;
+                messages.push((self.hart(source), self.target[source] & 0x7FF));
+            }
+        }
+        messages
+    }
+
+    fn read_register(&mut self, offset: uguest) -> u32 {
+        let sources = self.sourcecfg.len() as uguest;
+        let root = self.level == Level::Machine;
+        match offset {
+            // Bit 31 reads 1, big endian isn't supported
+            DOMAINCFG => 0x8000_0000 | self.domaincfg,
+            SOURCECFG..MMSIADDRCFG if Self::source(offset, SOURCECFG) < sources as usize => {
+                let source = Self::source(offset, SOURCECFG);
+                if self.delegated[source] {self.sourcecfg[source]} else {0}
+            },
+            MMSIADDRCFG..=SMSIADDRCFGH if root => self.msiaddrcfg[((offset - MMSIADDRCFG) / 4) as usize],
+            SETIP..SETIPNUM => Self::word(&self.pending, ((offset - SETIP) / 4) as usize),
+            IN_CLRIP..CLRIPNUM => Self::word(&self.input, ((offset - IN_CLRIP) / 4) as usize),
+            SETIE..SETIENUM => Self::word(&self.enabled, ((offset - SETIE) / 4) as usize),
+            GENMSI => self.genmsi,
+            TARGET..IDC if Self::source(offset, TARGET) < sources as usize => self.target[Self::source(offset, TARGET)],
+            IDC.. => {
+                let hart = ((offset - IDC) / IDC_SIZE) as usize;
+                let Some(idc) = self.idcs.get(hart).copied() else {return 0};
+                match (offset - IDC) % IDC_SIZE {
+                    IDELIVERY => idc.delivery as u32,
+                    IFORCE => idc.force as u32,
+                    ITHRESHOLD => idc.threshold,
+                    TOPI => self.topi(hart),
+                    // Claims the top interrupt, a forced one when there is none
+                    CLAIMI => {
+                        let topi = self.topi(hart);
+                        match self.top(hart) {
+                            Some((source, _)) if !self.mode(source).level_sensitive() => self.pending[source] = false,
+                            Some(_) => {},
+                            None => self.idcs[hart].force = false,
+                        }
+                        topi
+                    },
+                    _ => 0,
+                }
+            },
+            _ => 0,
+        }
+    }
+    fn write_register(&mut self, offset: uguest, value: u32) {
+        let sources = self.sourcecfg.len();
+        let root = self.level == Level::Machine;
+        match offset {
+            DOMAINCFG => self.domaincfg = value & (DOMAINCFG_IE | DOMAINCFG_DM),
+            SOURCECFG..MMSIADDRCFG => {
+                let source = Self::source(offset, SOURCECFG);
+                if source >= sources || !self.delegated[source] {return}
+                // Only the root domain has a child to delegate to, child index 0
+                self.sourcecfg[source] = if value & SOURCECFG_D != 0 {
+                    if root {SOURCECFG_D} else {0}
+                } else if SourceMode::new(value & 0b111).is_some() {value & 0b111} else {0};
+                if self.mode(source) == SourceMode::Inactive {
+                    (self.pending[source], self.enabled[source]) = (false, false);
+                }
+            },
+            // Not locked, the L bit stays 0
+            MMSIADDRCFG..=SMSIADDRCFGH if root => self.msiaddrcfg[((offset - MMSIADDRCFG) / 4) as usize] = value & 0x7FFF_FFFF,
+            SETIP..SETIPNUM | IN_CLRIP..CLRIPNUM | SETIE..SETIENUM | CLRIE..CLRIENUM => {
+                let base = offset & !0xFF;
+                let first = ((offset - base) / 4) as usize * 32;
+                for bit in (0..32).filter(|bit| value & 1 << bit != 0) {
+                    match base {
+                        SETIP => self.set_pending(first + bit, true),
+                        IN_CLRIP => self.set_pending(first + bit, false),
+                        SETIE => self.set_enabled(first + bit, true),
+                        _ => self.set_enabled(first + bit, false),
+                    }
+                }
+            },
+            SETIPNUM | SETIPNUM_LE => self.set_pending(value as usize, true),
+            SETIPNUM_BE => self.set_pending(value.swap_bytes() as usize, true),
+            CLRIPNUM => self.set_pending(value as usize, false),
+            SETIENUM => self.set_enabled(value as usize, true),
+            CLRIENUM => self.set_enabled(value as usize, false),
+            GENMSI if self.msi_mode() => self.genmsi = value & !0x3F800 | GENMSI_BUSY,
+            TARGET..IDC => {
+                let source = Self::source(offset, TARGET);
+                if source >= sources || self.mode(source) == SourceMode::Inactive {return}
+                let hart = (value >> 18).min(self.idcs.len() as u32 - 1);
+                self.target[source] = if self.msi_mode() {
+                    hart << 18 | value & 0x7FF
+                } else {
+                    // Priority 0 is 1
+                    hart << 18 | (value & 0xFF).max(1)
+                };
+            },
+            IDC.. => {
+                let hart = ((offset - IDC) / IDC_SIZE) as usize;
+                let Some(idc) = self.idcs.get_mut(hart) else {return};
+                match (offset - IDC) % IDC_SIZE {
+                    IDELIVERY => idc.delivery = value & 1 != 0,
+                    IFORCE => idc.force = value & 1 != 0,
+                    ITHRESHOLD => idc.threshold = value & 0xFF,
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+    }
+}
+impl MemoryRegion for Aplic {
+    fn read(&mut self, offset: uguest) -> u8 {
+        (self.read_register(offset & !3) >> (offset % 4 * 8)) as u8
+    }
+    fn read_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
+        // claimi has a side effect, registers are read once
+        if buffer.len() == 4 && offset.is_multiple_of(4) {
+            buffer.copy_from_slice(&self.read_register(offset).to_le_bytes());
+        } else {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = self.read(offset + i as uguest)
+            }
+        }
+    }
+    /// Registers are written 32 bits at a time
+    fn write(&mut self, _offset: uguest, _val: u8) {}
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        if let (Ok(word), true) = (<[u8; 4]>::try_from(buffer), offset.is_multiple_of(4)) {
+            self.write_register(offset, u32::from_le_bytes(word));
+        }
+    }
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {
+        crate::snapshot::cloned(self)
+    }
+    fn restore(&mut self, state: &dyn std::any::Any) -> color_eyre::Result<()> {
+        crate::snapshot::restore_cloned(self, state)
+    }
+}
+
+impl VM {
+    /// Takes the interrupt lines of the devices through the APLICs to the harts, called once devices ticked
+    /// Only the current hart gets its external interrupts and MSIs, the other ones get theirs when they step
+    pub(crate) fn route_interrupts(&mut self) {
+        let controller = |kind: DeviceKind, level: Level| self.board.interrupt_controller(kind, level).map(|device| device.base);
+        let (root, child) = (controller(DeviceKind::Aplic, Level::Machine), controller(DeviceKind::Aplic, Level::Supervisor));
+        let imsics = [controller(DeviceKind::Imsic, Level::Machine), controller(DeviceKind::Imsic, Level::Supervisor)];
+        if root.is_none() && imsics == [None, None] {return}
+
+        let raised: Vec<u32> = self.mem.pending_irqs().collect();
+        let hart = self.cpu.hartid as usize;
+        let mut messages = Vec::new();
+        let mut external = 0;
+        let mut delegations = None;
+        let mut msiaddrcfg = [0; 4];
+        for (base, level) in [(root, Level::Machine), (child, Level::Supervisor)] {
+            let Some(aplic) = base.and_then(|base| self.mem.device_mut::<Aplic>(base)) else {continue};
+            if let Some(delegated) = delegations.take() {aplic.set_delegated(delegated)}
+            if level == Level::Machine {msiaddrcfg = aplic.msiaddrcfg()}
+            aplic.update(&raised);
+            if aplic.interrupt(hart) {external |= level.eip()}
+            messages.extend(aplic.messages().into_iter().map(|(hart, id)| (Aplic::msi_address(msiaddrcfg, level, hart), id)));
+            delegations = Some(aplic.delegations());
+        }
+        self.cpu.external_interrupts = external;
+        // MSIs are writes like any other, they usually land in an IMSIC
+        for (addr, id) in messages {
+            let _ = self.mem.set(addr, id);
+        }
+        for (base, level) in imsics.into_iter().zip([Level::Machine, Level::Supervisor]) {
+            let Some(imsic) = base.and_then(|base| self.mem.device_mut::<Imsic>(base)) else {continue};
+            for id in imsic.take_messages(self.cpu.hartid) {
+                self.cpu.interrupt_files[level as usize].set_pending(id);
+            }
+        }
+    }
+}
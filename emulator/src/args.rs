@@ -0,0 +1,199 @@
+use std::path::PathBuf;
+
+use crate::board::{Board, DeviceKind};
+use crate::uguest;
+
+/// Options of a VM run, shared by the command line and the library
+#[derive(clap::Args, Debug, Clone)]
+pub struct RunArgs {
+    /// Machine to emulate, a preset (virt, sifive_u) or a description file (.toml or .ron), like QEMU's -machine, options can follow (virt,aia=aplic-imsic,pcie=on)
+    #[arg(short = 'M', long, default_value = "virt")]
+    pub machine: String,
+
+    /// Size of the first RAM region of the machine, like QEMU's -m (e.g. 128M, 1G), the description's by default
+    #[arg(short = 'm', long, value_parser = parse_size)]
+    pub mem_size: Option<uguest>,
+
+    /// ISA of the harts (e.g. rv64imac_zba_zbb), replaces the description's, misa and the device tree follow it
+    #[arg(long)]
+    pub isa: Option<String>,
+
+    /// Bits of the vector registers when the ISA has V (e.g. rv64imacv), the description's by default
+    #[arg(long)]
+    pub vlen: Option<usize>,
+
+    /// Firmware the kernel runs on, like QEMU's -bios
+    #[arg(long, value_enum, default_value_t = Bios::Sbi)]
+    pub bios: Bios,
+
+    /// Kernel command line, the bootargs of the device tree
+    #[arg(long, default_value = "")]
+    pub append: String,
+
+    /// How mtime advances: icount (one tick per retired instruction), icount=TICKS or realtime (the host clock), harts waiting
+    /// in WFI for a timer skip to its deadline either way
+    #[arg(long, default_value = "icount")]
+    pub clock: crate::clock::ClockMode,
+
+    /// Time of the goldfish RTC: host, or seconds since the epoch to start from for reproducible runs, adds the RTC
+    #[arg(long)]
+    pub rtc_clock: Option<crate::rtc::Clock>,
+
+    /// File handed to the guest through fw_cfg as the initrd, like QEMU's -initrd, adds fw_cfg
+    #[arg(long)]
+    pub initrd: Option<PathBuf>,
+
+    /// File handed to the guest through fw_cfg, like QEMU's -fw_cfg: name=opt/org.example/file,file=PATH or name=...,string=TEXT
+    #[arg(long = "fw-cfg", value_parser = parse_fw_cfg)]
+    pub fw_cfg: Vec<FwCfgFile>,
+
+    /// Host file backing a flash bank, like QEMU's -drive if=pflash, given twice for both banks, changes persist
+    #[arg(long)]
+    pub pflash: Vec<PathBuf>,
+
+    /// Disk on a virtio-blk of a virtio-mmio transport, like QEMU's -drive: file=PATH (raw image), then snapshot=on to keep
+    /// the writes in memory, overlay=PATH to keep them in a sparse file (created, it must not exist, and removed at exit), on-exit=commit|discard (discard by default) for
+    /// what becomes of them, and readonly=on, adds the transports
+    #[arg(long)]
+    pub drive: Vec<crate::block::DriveConfig>,
+
+    /// Network card on a virtio-mmio transport, like QEMU's -nic: loopback, echo (answers ARP and pings) or hub=PATH
+    /// (Unix socket shared with other instances), then mac=aa:bb:cc:dd:ee:ff, link=on|off and pcap=PATH, adds the transports
+    #[arg(long)]
+    pub net: Vec<crate::virtio::net::NetConfig>,
+
+    /// virtio-gpu display on a virtio-mmio transport, with optional xres=WIDTH, yres=HEIGHT (1280x800 by default),
+    /// frames=DIR to save a frame there on every flush, and format=png|ppm, adds the transports
+    #[arg(long, num_args = 0..=1, default_missing_value = "")]
+    pub gpu: Option<crate::virtio::gpu::GpuConfig>,
+
+    /// virtio-rng entropy source on a virtio-mmio transport, like QEMU's -device virtio-rng: seed=N (0 by default) for
+    /// the same bytes on every run, or host for /dev/urandom, adds the transports
+    #[arg(long, num_args = 0..=1, default_missing_value = "")]
+    pub rng: Option<crate::virtio::rng::RngConfig>,
+
+    /// Port of a virtio-console on a virtio-mmio transport, like QEMU's -device virtserialport: name=NAME, in=PATH
+    /// (file or pipe read for the guest) and out=PATH (what the guest writes), the first is the console (hvc0), adds the transports
+    #[arg(long = "console-port")]
+    pub console_port: Vec<crate::virtio::console::PortConfig>,
+
+    /// virtio-input device on a virtio-mmio transport, like QEMU's -device virtio-keyboard-device: keyboard or mouse,
+    /// then script=PATH of events to play, one per line: key|rel|abs CODE VALUE, syn, press CODE or wait STEPS, adds the transports
+    #[arg(long)]
+    pub input: Vec<crate::virtio::input::InputConfig>,
+
+    /// Unix socket serving monitor commands, like QEMU's -monitor unix:PATH,server: screendump FILE, set_link NIC on|off,
+    /// commit DRIVE, savevm|loadvm|delvm NAME, quit
+    #[arg(long)]
+    pub monitor: Option<PathBuf>,
+
+    /// Stop after this many retired instructions
+    #[arg(long)]
+    pub max_instructions: Option<u64>,
+
+    /// Profile the guest, writing folded stacks (for flamegraph tools) to this file
+    /// and printing a per-function table at exit
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+
+    /// Sample one instruction every N, 1 counts them all
+    #[arg(long, default_value_t = 1, requires = "profile")]
+    pub profile_period: u64,
+
+    /// Handle RISC-V semihosting calls (console, files, exit...) instead of raising breakpoints
+    #[arg(long)]
+    pub semihosting: bool,
+
+    /// Directory the guest can open files in through semihosting
+    #[arg(long, default_value = ".", requires = "semihosting")]
+    pub semihosting_root: PathBuf,
+
+    /// Command line returned by SYS_GET_CMDLINE, the kernel file by default
+    #[arg(long, requires = "semihosting")]
+    pub semihosting_cmdline: Option<String>,
+}
+
+impl RunArgs {
+    /// Description of the machine to run, with the RAM size, ISA and VLEN asked for
+    pub fn board(&self) -> color_eyre::Result<Board> {
+        let mut board = Board::load(&self.machine)?;
+        if let Some(isa) = &self.isa {
+            crate::cpu::isa::parse(isa)?;
+            board.isa = isa.clone();
+        }
+        if let Some(vlen) = self.vlen {
+            board.vlen = vlen;
+        }
+        // Like on QEMU virt, where they are always there
+        if self.rtc_clock.is_some() && board.device(DeviceKind::Rtc).is_none() {
+            board = board.with_rtc(true);
+        }
+        if self.bios == Bios::None && board.device(DeviceKind::Clint).is_none() {
+            board = board.with_clint(true);
+        }
+        if (self.initrd.is_some() || !self.fw_cfg.is_empty()) && board.device(DeviceKind::FwCfg).is_none() {
+            board = board.with_fw_cfg(true);
+        }
+        let virtio = !self.drive.is_empty() || !self.net.is_empty() || self.gpu.is_some() || self.rng.is_some()
+            || !self.console_port.is_empty() || !self.input.is_empty();
+        if virtio && board.device(DeviceKind::VirtioMmio).is_none() {
+            board = board.with_virtio_mmio(true);
+        }
+        if board.devices.iter().filter(|device| device.kind == DeviceKind::Flash).count() < self.pflash.len() {
+            board = board.with_pflash(self.pflash.len())?;
+        }
+        Ok(match self.mem_size {
+            Some(size) => board.with_ram_size(size),
+            None => board,
+        })
+    }
+}
+
+/// A file of `--fw-cfg`, with its content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FwCfgFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+/// name=NAME,file=PATH or name=NAME,string=TEXT, the file is read right away
+pub fn parse_fw_cfg(option: &str) -> Result<FwCfgFile, String> {
+    let (mut name, mut data) = (None, None);
+    for field in option.split(',') {
+        match field.split_once('=') {
+            Some(("name", value)) => name = Some(value.to_string()),
+            Some(("file", path)) => data = Some(std::fs::read(path).map_err(|err| format!("Can't read {path}: {err}"))?),
+            Some(("string", text)) => data = Some(text.as_bytes().to_vec()),
+            _ => return Err(format!("Unknown fw_cfg field {field:?}, they are name, file and string")),
+        }
+    }
+    match (name, data) {
+        (Some(name), Some(data)) => Ok(FwCfgFile { name, data }),
+        _ => Err(format!("fw_cfg files need a name and a file or string: {option:?}")),
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bios {
+    /// Built-in SBI implementation, the kernel starts in S-mode with a0 = hartid and a1 = device tree
+    Sbi,
+    /// No firmware, the kernel starts in M-mode
+    None,
+}
+
+/// Parses sizes the same way QEMU's `-m` does, e.g. "128M", "1G", "4096" (bytes without suffix)
+pub fn parse_size(size: &str) -> Result<uguest, String> {
+    let size = size.trim();
+    let (digits, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => size.split_at(idx),
+        None => (size, ""),
+    };
+    let value: uguest = digits.parse().map_err(|err| format!("Invalid size {size:?}: {err}"))?;
+    let multiplier: uguest = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(format!("Unknown size unit {unit:?} in {size:?}")),
+    };
+    value.checked_mul(multiplier).ok_or_else(|| format!("Size {size:?} is too big"))
+}
@@ -0,0 +1,290 @@
+// Assembler for the syntax the disassembler writes (see cpu/disasm.rs), commas between operands are optional
+// One instruction per line, with labels, `#` comments, `.word` and a few pseudo-instructions, enough for tests and small programs
+use std::collections::HashMap;
+
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat};
+use color_eyre::Result;
+
+use crate::cpu::csr::CsrID;
+use crate::cpu::disasm::{self, Operands, ORDERINGS, SYSTEM, VMA_FENCES};
+use crate::cpu::instructions::Instruction32;
+use crate::cpu::raw_instructions::{InstructionDescription32, INSTRUCTIONS32};
+use crate::cpu::reg::REGS;
+use crate::cpu::vector::{self, VType};
+use crate::{iguest, uguest};
+
+/// Directives that don't emit anything
+const IGNORED_DIRECTIVES: [&str; 5] = [".text", ".section", ".globl", ".global", ".option"];
+
+/// Machine code of `source` once loaded at `base`, labels and `.` in targets are addresses
+pub fn assemble(source: &str, base: uguest) -> Result<Vec<u8>> {
+    let lines: Vec<(usize, &str)> = source.lines().enumerate()
+        .map(|(number, line)| (number+1, line.split('#').next().unwrap_or_default().trim()))
+        .collect();
+    // Every instruction is 4 bytes, so labels are known before encoding anything
+    let mut labels = HashMap::new();
+    let mut statements = Vec::new();
+    let mut pc = base;
+    for (number, mut line) in lines {
+        while let Some((label, rest)) = line.split_once(':') {
+            if label.is_empty() || !label.chars().all(|c| c.is_alphanumeric() || "_.$".contains(c)) {break}
+            if labels.insert(label, pc).is_some() {bail!("Line {number}: label {label} is defined twice")}
+            line = rest.trim();
+        }
+        if line.is_empty() || IGNORED_DIRECTIVES.iter().any(|directive| line.split_whitespace().next() == Some(directive)) {continue}
+        statements.push((number, line, pc));
+        pc += 4;
+    }
+    let mut code = Vec::with_capacity(statements.len()*4);
+    for (number, line, pc) in statements {
+        let word = Assembler { labels: &labels, pc }.statement(line).with_context(|| format!("Line {number}: {line}"))?;
+        code.extend_from_slice(&word.to_le_bytes());
+    }
+    Ok(code)
+}
+
+struct Assembler<'a> {
+    labels: &'a HashMap<&'a str, uguest>,
+    pc: uguest,
+}
+impl Assembler<'_> {
+    fn statement(&self, line: &str) -> Result<u32> {
+        let (mnemonic, operands) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let operands: Vec<&str> = operands.split(|c: char| c == ',' || c.is_whitespace()).filter(|operand| !operand.is_empty()).collect();
+        if let Some(word) = self.pseudo(mnemonic, &operands)? {return Ok(word)}
+        if mnemonic == ".word" {
+            let [value] = operands[..] else {bail!(".word takes a value")};
+            return Ok(number(value)? as u32)
+        }
+        if let Some((_, imm)) = SYSTEM.iter().find(|(name, _)| *name == mnemonic) {
+            if !operands.is_empty() {bail!("{mnemonic} has no operands")}
+            return Ok(imm << 20 | 0b1110011)
+        }
+        if let Some((_, fun7)) = VMA_FENCES.iter().find(|(name, _)| *name == mnemonic) {
+            let (rs1, rs2) = match operands[..] {
+                [] => (0, 0),
+                [rs1] => (reg(rs1)?, 0),
+                [rs1, rs2] => (reg(rs1)?, reg(rs2)?),
+                _ => bail!("{mnemonic} takes up to two registers"),
+            };
+            return Ok(fun7 << 25 | rs2 << 20 | rs1 << 15 | 0b1110011)
+        }
+        if let Some(form) = vector::forms().iter().find(|form| form.mnemonic == mnemonic) {
+            return vector_instruction(form, &operands)
+        }
+        // Atomics take their ordering as a suffix
+        let (mnemonic, ordering) = ORDERINGS.iter().enumerate().skip(1).rev()
+            .find_map(|(bits, suffix)| Some((mnemonic.strip_suffix(suffix)?, bits as u32)))
+            .filter(|(mnemonic, _)| mnemonic.starts_with("amo") || mnemonic.starts_with("lr") || mnemonic.starts_with("sc"))
+            .unwrap_or((mnemonic, 0));
+        let &(name, format, mask, _) = find(mnemonic)?;
+        let mask = Instruction32(mask.0);
+        let kind = disasm::operands(name, format, mask);
+        if kind == Operands::Vector {bail!("Unknown instruction {mnemonic}")}
+        let expected = match kind {
+            Operands::None | Operands::System => 0,
+            Operands::Unary | Operands::Load | Operands::Store | Operands::LoadReserved | Operands::Jump | Operands::Upper
+                | Operands::HypervisorLoad | Operands::HypervisorStore => 2,
+            Operands::Fence => if operands.is_empty() {0} else {2},
+            _ => 3,
+        };
+        if operands.len() != expected {bail!("{mnemonic} takes {expected} operands, got {}", operands.len())}
+        let op = |i: usize| operands[i];
+        let raw = mask.0 | ordering << 25;
+        Ok(match kind {
+            Operands::Register => r(raw, reg(op(0))?, reg(op(1))?, reg(op(2))?),
+            Operands::Unary => r(raw, reg(op(0))?, reg(op(1))?, 0),
+            Operands::Immediate => i(raw, reg(op(0))?, reg(op(1))?, number(op(2))?)?,
+            Operands::Shift => {
+                let shamt = number(op(2))?;
+                let bits = disasm::shamt_bits(name, mask.opcode());
+                if !(0..1 << bits).contains(&shamt) {bail!("Shift amount {shamt} doesn't fit in {bits} bits")}
+                r(raw, reg(op(0))?, reg(op(1))?, 0) | (shamt as u32) << 20
+            },
+            Operands::Load => {
+                let (offset, rs1) = memory(op(1))?;
+                i(raw, reg(op(0))?, rs1, offset)?
+            },
+            Operands::Store => {
+                let (offset, rs1) = memory(op(1))?;
+                if !(-2048..2048).contains(&offset) {bail!("Offset {offset} doesn't fit in 12 bits")}
+                let offset = offset as u32;
+                r(raw, 0, rs1, reg(op(0))?) | (offset & 0x1F) << 7 | (offset >> 5 & 0x7F) << 25
+            },
+            Operands::Branch => {
+                let offset = self.offset(op(2), 13)? as u32;
+                r(raw, 0, reg(op(0))?, reg(op(1))?) | (offset >> 11 & 1) << 7 | (offset >> 1 & 0xF) << 8 | (offset >> 5 & 0x3F) << 25 | (offset >> 12 & 1) << 31
+            },
+            Operands::Jump => jal(reg(op(0))?, self.offset(op(1), 21)?),
+            Operands::Upper => {
+                let imm = number(op(1))?;
+                if !(-(1 << 19)..1 << 20).contains(&imm) {bail!("{imm:#x} doesn't fit in 20 bits")}
+                r(raw, reg(op(0))?, 0, 0) | (imm as u32) << 12
+            },
+            Operands::Csr => r(raw, reg(op(0))?, reg(op(2))?, 0) | csr(op(1))? << 20,
+            Operands::CsrImmediate => {
+                let uimm = number(op(2))?;
+                if !(0..32).contains(&uimm) {bail!("{uimm} doesn't fit in 5 bits")}
+                r(raw, reg(op(0))?, uimm as u32, 0) | csr(op(1))? << 20
+            },
+            Operands::LoadReserved => r(raw, reg(op(0))?, address(op(1))?, 0),
+            Operands::Atomic => r(raw, reg(op(0))?, address(op(2))?, reg(op(1))?),
+            Operands::HypervisorLoad => r(raw, reg(op(0))?, address(op(1))?, 0),
+            Operands::HypervisorStore => r(raw, 0, address(op(1))?, reg(op(0))?),
+            Operands::Fence if operands.is_empty() => raw | 0b1111_1111 << 20,
+            Operands::Fence => raw | fence_set(op(0))? << 24 | fence_set(op(1))? << 20,
+            Operands::System | Operands::None | Operands::Vector => raw,
+        })
+    }
+
+    /// Pseudo-instructions that are a single instruction
+    fn pseudo(&self, mnemonic: &str, operands: &[&str]) -> Result<Option<u32>> {
+        let addi = |rd, rs1, imm| i(0b0010011, rd, rs1, imm);
+        let jalr = |rd, rs1| i(0b1100111, rd, rs1, 0);
+        Ok(Some(match (mnemonic, operands) {
+            ("nop", []) => addi(0, 0, 0)?,
+            ("mv", [rd, rs]) => addi(reg(rd)?, reg(rs)?, 0)?,
+            ("li", [rd, imm]) => addi(reg(rd)?, 0, number(imm)?).context("li only takes 12 bits immediates")?,
+            ("not", [rd, rs]) => i(0b0010011 | 0b100 << 12, reg(rd)?, reg(rs)?, -1)?,
+            ("neg", [rd, rs]) => r(0b0110011 | 0b0100000 << 25, reg(rd)?, 0, reg(rs)?),
+            ("j", [target]) => jal(0, self.offset(target, 21)?),
+            ("jal", [target]) => jal(1, self.offset(target, 21)?),
+            ("jr", [rs]) => jalr(0, reg(rs)?)?,
+            ("jalr", [rs]) => jalr(1, reg(rs)?)?,
+            ("ret", []) => jalr(0, 1)?,
+            _ => return Ok(None),
+        }))
+    }
+
+    /// Offset from the instruction to a label, an address or `.+offset`, it has to fit in `bits` signed bits
+    fn offset(&self, target: &str, bits: u32) -> Result<iguest> {
+        let address = if let Some(&address) = self.labels.get(target) {
+            address
+        } else if let Some(relative) = target.strip_prefix('.') {
+            self.pc.wrapping_add(number(relative)? as uguest)
+        } else {
+            number(target).with_context(|| format!("{target} is neither a label nor an address"))? as uguest
+        };
+        let offset = address.wrapping_sub(self.pc) as iguest;
+        if offset % 2 != 0 || !(-(1 << (bits-1))..1 << (bits-1)).contains(&offset) {
+            bail!("{target} is out of reach ({offset:#x} from {:#x})", self.pc)
+        }
+        Ok(offset)
+    }
+}
+
+/// Operands of a vector instruction, following its form, the mask (v0.t) can be left out
+fn vector_instruction(form: &vector::Form, operands: &[&str]) -> Result<u32> {
+    let mut operands = operands.iter().copied();
+    let mut raw = form.bits;
+    for kind in form.operands.split(' ') {
+        let value = match kind {
+            "vm" => match operands.next() {
+                Some("v0.t") => 0,
+                Some(operand) => bail!("Expected v0.t, got {operand}"),
+                None => 1,
+            },
+            "v0" if operands.next() == Some("v0") => continue,
+            "v0" => bail!("{} takes v0 as its mask", form.mnemonic),
+            "vtypei" | "vtypei10" => {
+                let vtype: Vec<&str> = operands.by_ref().take(4).collect();
+                let vtype = VType::parse(&vtype).with_context(|| format!("Invalid vtype {}", vtype.join(" ")))?;
+                vtype.encode() as u32
+            },
+            _ => {
+                let operand = operands.next().with_context(|| format!("{} takes {}", form.mnemonic, form.operands))?;
+                match kind {
+                    "vd" | "vs1" | "vs2" | "vs3" => match operand.strip_prefix('v').and_then(|number| number.parse().ok()) {
+                        Some(reg @ 0..32) => reg,
+                        _ => bail!("Unknown vector register {operand}"),
+                    },
+                    "(rs1)" => address(operand)?,
+                    "simm" => match number(operand)? {
+                        imm @ -16..16 => imm as u32 & 0x1F,
+                        imm => bail!("{imm} doesn't fit in 5 bits"),
+                    },
+                    "uimm" => match number(operand)? {
+                        imm @ 0..32 => imm as u32,
+                        imm => bail!("{imm} doesn't fit in 5 bits"),
+                    },
+                    _ => reg(operand)?,
+                }
+            },
+        };
+        let (shift, _) = vector::field(kind).context("Unknown operand")?;
+        raw |= value << shift;
+    }
+    if let Some(operand) = operands.next() {bail!("Unexpected operand {operand}, {} takes {}", form.mnemonic, form.operands)}
+    Ok(raw)
+}
+
+fn find(mnemonic: &str) -> Result<&'static InstructionDescription32> {
+    INSTRUCTIONS32.iter().find(|(name, ..)| disasm::mnemonic(name) == mnemonic).with_context(|| format!("Unknown instruction {mnemonic}"))
+}
+
+fn r(raw: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    raw | rd << 7 | rs1 << 15 | rs2 << 20
+}
+fn i(raw: u32, rd: u32, rs1: u32, imm: iguest) -> Result<u32> {
+    if !(-2048..2048).contains(&imm) {bail!("{imm} doesn't fit in 12 bits")}
+    Ok(r(raw, rd, rs1, 0) | (imm as u32 & 0xFFF) << 20)
+}
+fn jal(rd: u32, offset: iguest) -> u32 {
+    let offset = offset as u32;
+    0b1101111 | rd << 7 | (offset >> 12 & 0xFF) << 12 | (offset >> 11 & 1) << 20 | (offset >> 1 & 0x3FF) << 21 | (offset >> 20 & 1) << 31
+}
+
+/// ABI name, x0 to x31 or fp
+fn reg(name: &str) -> Result<u32> {
+    if name == "fp" {return Ok(8)}
+    if let Some(reg) = REGS.iter().position(|reg| *reg == name) {return Ok(reg as u32)}
+    match name.strip_prefix('x').and_then(|number| number.parse().ok()) {
+        Some(reg @ 0..32) => Ok(reg),
+        _ => bail!("Unknown register {name}"),
+    }
+}
+/// Decimal, 0x hexadecimal or 0b binary, possibly negative or with a +
+fn number(text: &str) -> Result<iguest> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        uguest::from_str_radix(hex, 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        uguest::from_str_radix(binary, 2)
+    } else {
+        digits.parse()
+    }.map_err(|_| eyre!("Invalid number {text}"))? as iguest;
+    Ok(if negative {value.wrapping_neg()} else {value})
+}
+/// offset(reg), the offset can be left out
+fn memory(operand: &str) -> Result<(iguest, u32)> {
+    let (offset, rest) = operand.split_once('(').with_context(|| format!("{operand} isn't offset(register)"))?;
+    let offset = if offset.is_empty() {0} else {number(offset)?};
+    Ok((offset, reg(rest.strip_suffix(')').with_context(|| format!("Unclosed parenthesis in {operand}"))?)?))
+}
+/// (reg) of atomics
+fn address(operand: &str) -> Result<u32> {
+    match memory(operand)? {
+        (0, reg) => Ok(reg),
+        _ => bail!("Atomics don't take an offset ({operand})"),
+    }
+}
+/// A name or a number
+fn csr(name: &str) -> Result<u32> {
+    if let Ok(csr) = number(name) {
+        if !(0..0x1000).contains(&csr) {bail!("{name} isn't a CSR number")}
+        return Ok(csr as u32)
+    }
+    (0..0x1000).find(|&csr| CsrID::new(csr).info().is_some_and(|(csr_name, ..)| csr_name == name))
+        .map(u32::from).with_context(|| format!("Unknown CSR {name}"))
+}
+/// Set of iorw of a fence
+fn fence_set(set: &str) -> Result<u32> {
+    if set == "0" {return Ok(0)}
+    set.chars().try_fold(0, |bits, c| match "iorw".find(c) {
+        Some(i) => Ok(bits | 0b1000 >> i),
+        None => bail!("Invalid fence set {set}"),
+    })
+}
@@ -0,0 +1,310 @@
+// Disk images of virtio-blk, raw files addressed in 512 bytes sectors
+// The capacity is rounded up to a whole sector like QEMU does, what is past the end of the file reads as zeros
+// Without an overlay the image is written in place. With one (QEMU's -snapshot) the image is opened read-only and the
+// sectors the guest writes go to the overlay, in memory or in a sparse file at the same offsets; at exit they are
+// committed to the image or discarded. The overlay file is scratch space the emulator creates and removes at exit.
+// The snapshots of the VM (see `snapshot`) keep a copy of the overlay, restoring one puts the drive back as it was.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+
+pub const SECTOR: u64 = 512;
+
+/// What happens to the overlay at exit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnExit {
+    /// Written to the image
+    Commit,
+    /// Lost, like QEMU's -snapshot
+    #[default]
+    Discard,
+}
+impl std::str::FromStr for OnExit {
+    type Err = String;
+    fn from_str(option: &str) -> Result<Self, String> {
+        match option {
+            "commit" => Ok(Self::Commit),
+            "discard" => Ok(Self::Discard),
+            _ => Err(format!("Unknown exit action {option:?}, it is commit or discard")),
+        }
+    }
+}
+
+/// A drive of `--drive`: file=PATH, then snapshot=on|off, overlay=PATH (a sparse file instead of memory, created and
+/// removed at exit, implies snapshot=on), on-exit=commit|discard and readonly=on|off
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DriveConfig {
+    pub file: PathBuf,
+    pub snapshot: bool,
+    pub overlay: Option<PathBuf>,
+    pub on_exit: OnExit,
+    pub read_only: bool,
+}
+fn switch(field: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("{field} is on or off, not {value:?}")),
+    }
+}
+impl std::str::FromStr for DriveConfig {
+    type Err = String;
+    fn from_str(option: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut file = None;
+        for field in option.split(',') {
+            match field.split_once('=') {
+                Some(("file", path)) => file = Some(path.into()),
+                Some(("snapshot", value)) => config.snapshot = switch("snapshot", value)?,
+                Some(("overlay", path)) => config.overlay = Some(path.into()),
+                Some(("on-exit", action)) => config.on_exit = action.parse()?,
+                Some(("readonly", value)) => config.read_only = switch("readonly", value)?,
+                _ => return Err(format!("Unknown drive field {field:?}, they are file, snapshot, overlay, on-exit and readonly")),
+            }
+        }
+        config.file = file.ok_or("A drive needs file=PATH")?;
+        config.snapshot |= config.overlay.is_some();
+        Ok(config)
+    }
+}
+
+/// Where the written sectors are kept
+#[derive(Debug)]
+enum Store {
+    Memory(BTreeMap<u64, Vec<u8>>),
+    /// The data of a sector is at its offset in the file, `written` tells which ones have some
+    File { file: File, path: PathBuf, written: BTreeSet<u64> },
+}
+/// Sectors written since the image was opened or the overlay committed
+#[derive(Debug)]
+pub struct Overlay {
+    store: Store,
+}
+/// Content of an overlay at some point, for the snapshots of the VM
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OverlaySnapshot {
+    pub sectors: BTreeMap<u64, Vec<u8>>,
+}
+impl Overlay {
+    pub fn memory() -> Self {
+        Self { store: Store::Memory(BTreeMap::new()) }
+    }
+    /// A sparse file, scratch space removed when the disk is closed: it is created, never an existing file
+    pub fn file(path: &Path) -> Result<Self> {
+        let file = File::options().read(true).write(true).create_new(true).open(path)
+            .with_context(|| format!("Can't create the overlay {}, it must not exist", path.display()))?;
+        Ok(Self { store: Store::File { file, path: path.to_path_buf(), written: BTreeSet::new() } })
+    }
+    /// Written sectors
+    pub fn len(&self) -> usize {
+        match &self.store {
+            Store::Memory(sectors) => sectors.len(),
+            Store::File { written, .. } => written.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn contains(&self, sector: u64) -> bool {
+        match &self.store {
+            Store::Memory(sectors) => sectors.contains_key(&sector),
+            Store::File { written, .. } => written.contains(&sector),
+        }
+    }
+    fn read(&mut self, sector: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+        match &mut self.store {
+            Store::Memory(sectors) => buffer.copy_from_slice(&sectors[&sector][..buffer.len()]),
+            Store::File { file, .. } => {
+                file.seek(SeekFrom::Start(sector * SECTOR))?;
+                file.read_exact(buffer)?;
+            },
+        }
+        Ok(())
+    }
+    fn write(&mut self, sector: u64, data: &[u8]) -> std::io::Result<()> {
+        match &mut self.store {
+            Store::Memory(sectors) => {sectors.insert(sector, data.to_vec());},
+            Store::File { file, written, .. } => {
+                file.seek(SeekFrom::Start(sector * SECTOR))?;
+                file.write_all(data)?;
+                written.insert(sector);
+            },
+        }
+        Ok(())
+    }
+    /// Written sectors and their data
+    pub fn snapshot(&mut self) -> std::io::Result<OverlaySnapshot> {
+        let written: Vec<u64> = match &self.store {
+            Store::Memory(sectors) => return Ok(OverlaySnapshot { sectors: sectors.clone() }),
+            Store::File { written, .. } => written.iter().copied().collect(),
+        };
+        let mut snapshot = OverlaySnapshot::default();
+        for sector in written {
+            let mut data = vec![0; SECTOR as usize];
+            self.read(sector, &mut data)?;
+            snapshot.sectors.insert(sector, data);
+        }
+        Ok(snapshot)
+    }
+    /// Puts back the sectors of `snapshot`, those written since are forgotten
+    pub fn restore(&mut self, snapshot: &OverlaySnapshot) -> std::io::Result<()> {
+        self.clear()?;
+        for (sector, data) in &snapshot.sectors {
+            self.write(*sector, data)?;
+        }
+        Ok(())
+    }
+    fn clear(&mut self) -> std::io::Result<()> {
+        match &mut self.store {
+            Store::Memory(sectors) => sectors.clear(),
+            Store::File { file, written, .. } => {
+                written.clear();
+                file.set_len(0)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+pub struct Disk {
+    path: PathBuf,
+    image: File,
+    /// Bytes of the image, sectors past them are zeros
+    len: u64,
+    read_only: bool,
+    overlay: Option<Overlay>,
+    pub on_exit: OnExit,
+}
+impl Disk {
+    /// The image at `path`, written in place unless `read_only`
+    pub fn open(path: &Path, read_only: bool) -> Result<Self> {
+        let image = File::options().read(true).write(!read_only).open(path)
+            .with_context(|| format!("Can't open the disk image {}", path.display()))?;
+        let len = image.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), image, len, read_only, overlay: None, on_exit: OnExit::default() })
+    }
+    /// The image at `path`, never written: what the guest writes goes to `overlay`
+    pub fn with_overlay(path: &Path, overlay: Overlay) -> Result<Self> {
+        let mut disk = Self::open(path, true)?;
+        disk.read_only = false;
+        disk.overlay = Some(overlay);
+        Ok(disk)
+    }
+    /// The drive `config` describes
+    pub fn open_drive(config: &DriveConfig) -> Result<Self> {
+        let mut disk = match (&config.overlay, config.snapshot) {
+            (Some(overlay), _) => Self::with_overlay(&config.file, Overlay::file(overlay)?)?,
+            (None, true) => Self::with_overlay(&config.file, Overlay::memory())?,
+            (None, false) => Self::open(&config.file, config.read_only)?,
+        };
+        disk.read_only |= config.read_only;
+        disk.on_exit = config.on_exit;
+        Ok(disk)
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn sectors(&self) -> u64 {
+        self.len.div_ceil(SECTOR)
+    }
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+    pub fn overlay(&self) -> Option<&Overlay> {
+        self.overlay.as_ref()
+    }
+    pub fn overlay_mut(&mut self) -> Option<&mut Overlay> {
+        self.overlay.as_mut()
+    }
+    fn read_image(&mut self, sector: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+        buffer.fill(0);
+        let offset = sector * SECTOR;
+        if offset >= self.len {return Ok(())}
+        let len = buffer.len().min((self.len - offset) as usize);
+        self.image.seek(SeekFrom::Start(offset))?;
+        self.image.read_exact(&mut buffer[..len])
+    }
+    /// Reads whole sectors from `sector`
+    pub fn read(&mut self, sector: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+        for (i, chunk) in buffer.chunks_mut(SECTOR as usize).enumerate() {
+            let sector = sector + i as u64;
+            match &mut self.overlay {
+                Some(overlay) if overlay.contains(sector) => overlay.read(sector, chunk)?,
+                _ => self.read_image(sector, chunk)?,
+            }
+        }
+        Ok(())
+    }
+    /// Writes whole sectors from `sector`, a partial last one keeps the rest of its content
+    pub fn write(&mut self, sector: u64, data: &[u8]) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "The disk is read-only"))
+        }
+        if (sector * SECTOR + data.len() as u64).div_ceil(SECTOR) > self.sectors() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Writing past the end of the disk"))
+        }
+        if self.overlay.is_none() {
+            self.image.seek(SeekFrom::Start(sector * SECTOR))?;
+            self.image.write_all(data)?;
+            self.len = self.len.max(sector * SECTOR + data.len() as u64);
+            return Ok(())
+        }
+        for (i, chunk) in data.chunks(SECTOR as usize).enumerate() {
+            let sector = sector + i as u64;
+            let mut content = vec![0; SECTOR as usize];
+            if chunk.len() < content.len() {self.read(sector, &mut content)?}
+            content[..chunk.len()].copy_from_slice(chunk);
+            self.overlay.as_mut().unwrap().write(sector, &content)?;
+        }
+        Ok(())
+    }
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.overlay {
+            Some(_) => Ok(()),
+            None => self.image.sync_data(),
+        }
+    }
+    /// Writes the overlay to the image and empties it, returns how many sectors were written
+    pub fn commit(&mut self) -> Result<usize> {
+        let Some(overlay) = &mut self.overlay else {bail!("{} has no overlay to commit", self.path.display())};
+        let snapshot = overlay.snapshot()?;
+        let mut image = File::options().write(true).open(&self.path)
+            .with_context(|| format!("Can't open the disk image {} to commit", self.path.display()))?;
+        for (sector, data) in &snapshot.sectors {
+            // The last sector of an image that isn't a whole number of them keeps its size
+            let len = (self.len - sector * SECTOR).min(SECTOR) as usize;
+            image.seek(SeekFrom::Start(sector * SECTOR))?;
+            image.write_all(&data[..len])?;
+        }
+        image.sync_data()?;
+        overlay.clear()?;
+        Ok(snapshot.sectors.len())
+    }
+    /// Forgets the overlay, returns how many sectors it had
+    pub fn discard(&mut self) -> Result<usize> {
+        let Some(overlay) = &mut self.overlay else {bail!("{} has no overlay to discard", self.path.display())};
+        let sectors = overlay.len();
+        overlay.clear()?;
+        Ok(sectors)
+    }
+    /// Commits or discards the overlay as asked, and removes its file
+    pub fn close(&mut self) -> Result<()> {
+        let Some(overlay) = &self.overlay else {return self.flush().map_err(Into::into)};
+        match (overlay.is_empty(), self.on_exit) {
+            (true, _) => {},
+            (false, OnExit::Commit) => {
+                let sectors = self.commit()?;
+                log::info!("Committed {sectors} sectors to {}", self.path.display());
+            },
+            (false, OnExit::Discard) => {self.discard()?;},
+        }
+        if let Some(Overlay { store: Store::File { path, .. } }) = &self.overlay {
+            std::fs::remove_file(path).with_context(|| format!("Can't remove the overlay {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,361 @@
+// Machine descriptions: RAM regions, harts and devices of a machine, written in TOML or RON
+// The bus (`Board::bus`) and the device tree (`fdt::generate`) are both built from them, see machines/ for the presets
+use std::path::Path;
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+use serde::Deserialize;
+
+use crate::cpu::CPU;
+use crate::mem::{MemMap, Memory, MemoryMap, DRAM};
+use crate::{aia, clint, cpu, fw_cfg, pci, pflash, plic, rtc, uart, uguest, virtio};
+
+/// Built-in descriptions, by name
+pub const PRESETS: [(&str, &str); 2] = [
+    ("virt", include_str!("../machines/virt.toml")),
+    ("sifive_u", include_str!("../machines/sifive_u.toml")),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Board {
+    /// `model` and `compatible` of the device tree root
+    pub model: String,
+    pub compatible: String,
+    /// ISA string of every hart, e.g. rv64imac_zicsr, see `cpu::isa::parse`
+    #[serde(default = "default_isa")]
+    pub isa: String,
+    #[serde(default = "default_harts")]
+    pub harts: usize,
+    /// Bits of the vector registers when the ISA has V, a power of 2 between 128 and 65536
+    #[serde(default = "default_vlen")]
+    pub vlen: usize,
+    pub ram: Vec<Ram>,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+}
+fn default_isa() -> String {
+    "rv64imac".into()
+}
+fn default_harts() -> usize {
+    1
+}
+fn default_vlen() -> usize {
+    cpu::vector::DEFAULT_VLEN
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ram {
+    pub base: uguest,
+    /// Bytes, or a size like QEMU's -m takes ("128M")
+    #[serde(deserialize_with = "size")]
+    pub size: uguest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Device {
+    pub kind: DeviceKind,
+    pub base: uguest,
+    /// Length of the mapping, the usual one for the kind by default
+    #[serde(default, deserialize_with = "optional_size")]
+    pub size: Option<uguest>,
+    /// Interrupt source number on the interrupt controller
+    pub irq: Option<u32>,
+    /// Privilege level of an interrupt controller (APLIC domain or IMSIC), machine by default
+    #[serde(default)]
+    pub level: aia::Level,
+}
+impl Device {
+    pub fn size(&self) -> uguest {
+        self.size.unwrap_or(self.kind.default_size())
+    }
+}
+
+/// Devices a description can instantiate, named after their device tree `compatible`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum DeviceKind {
+    /// See `uart::UART`
+    #[serde(rename = "ns16550a")]
+    Uart,
+    /// See `plic::Plic`, two contexts per hart
+    #[serde(rename = "riscv,plic0")]
+    Plic,
+    /// See `aia::Aplic`, the M-level one is the root domain, the S-level one its child
+    #[serde(rename = "riscv,aplic")]
+    Aplic,
+    /// See `aia::Imsic`
+    #[serde(rename = "riscv,imsics")]
+    Imsic,
+    /// See `pci::PcieHost`, its irq is the one of INTA
+    #[serde(rename = "pci-host-ecam-generic")]
+    Pcie,
+    /// See `rtc::GoldfishRtc`
+    #[serde(rename = "google,goldfish-rtc")]
+    Rtc,
+    /// See `fw_cfg::FwCfg`
+    #[serde(rename = "qemu,fw-cfg-mmio")]
+    FwCfg,
+    /// See `pflash::Pflash`, a bank
+    #[serde(rename = "cfi-flash")]
+    Flash,
+    /// See `clint::Clint`
+    #[serde(rename = "riscv,clint0")]
+    Clint,
+    /// See `virtio::mmio::VirtioMmio`, a transport, empty until a device is plugged in
+    #[serde(rename = "virtio,mmio")]
+    VirtioMmio,
+}
+impl DeviceKind {
+    pub fn default_size(self) -> uguest {
+        match self {
+            Self::Uart => 0x100,
+            Self::Plic => MemMap::PLIC.len(),
+            Self::Aplic => MemMap::AplicM.len(),
+            Self::Imsic => MemMap::ImsicM.len(),
+            Self::Pcie => pci::PcieHost::size(),
+            Self::Rtc => 0x1000,
+            Self::FwCfg => fw_cfg::SIZE,
+            Self::Flash => pflash::BANK_SIZE,
+            Self::Clint => MemMap::CLINT.len(),
+            Self::VirtioMmio => MemMap::VIRTIO.len(),
+        }
+    }
+}
+
+/// Interrupt controllers of QEMU virt's aia option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aia {
+    /// The PLIC, QEMU virt's default
+    None,
+    /// APLIC domains in direct mode
+    Aplic,
+    /// APLIC domains forwarding interrupts as MSIs to IMSICs
+    AplicImsic,
+}
+impl std::str::FromStr for Aia {
+    type Err = color_eyre::Report;
+    fn from_str(aia: &str) -> Result<Self> {
+        Ok(match aia {
+            "none" => Self::None,
+            "aplic" => Self::Aplic,
+            "aplic-imsic" => Self::AplicImsic,
+            _ => bail!("Unknown aia {aia:?}, it is none, aplic or aplic-imsic"),
+        })
+    }
+}
+
+/// Value of an on/off machine option
+fn switch(value: &str) -> Result<bool> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => bail!("Machine options are on or off, not {value:?}"),
+    }
+}
+
+/// Sizes are either a number of bytes or a string parsed like `-m`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(uguest),
+    Text(String),
+}
+fn size<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<uguest, D::Error> {
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Text(text) => crate::args::parse_size(&text).map_err(serde::de::Error::custom),
+    }
+}
+fn optional_size<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<uguest>, D::Error> {
+    size(deserializer).map(Some)
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self::virt()
+    }
+}
+impl Board {
+    /// QEMU virt with 128MiB of RAM and a UART
+    pub fn virt() -> Self {
+        Self::preset("virt").expect("virt is a preset")
+    }
+    pub fn preset(name: &str) -> Option<Self> {
+        let (_, description) = PRESETS.iter().find(|(preset, _)| *preset == name)?;
+        Some(Self::from_toml(description).expect("Presets are valid"))
+    }
+    /// A preset name, or the path of a .toml or .ron description
+    /// Options can follow like with QEMU's -machine: virt,aia=aplic-imsic,pcie=on,rtc=on,fw-cfg=on,clint=on,virtio=on
+    pub fn load(machine: &str) -> Result<Self> {
+        let mut options = machine.split(',');
+        let machine = options.next().unwrap_or_default();
+        let mut board = Self::load_description(machine)?;
+        for option in options {
+            match option.split_once('=') {
+                Some(("aia", aia)) => board = board.with_aia(aia.parse()?)?,
+                Some(("pcie", pcie)) => board = board.with_pcie(switch(pcie)?),
+                Some(("rtc", rtc)) => board = board.with_rtc(switch(rtc)?),
+                Some(("fw-cfg", fw_cfg)) => board = board.with_fw_cfg(switch(fw_cfg)?),
+                Some(("clint", clint)) => board = board.with_clint(switch(clint)?),
+                Some(("virtio", virtio)) => board = board.with_virtio_mmio(switch(virtio)?),
+                _ => bail!("Unknown machine option {option:?}"),
+            }
+        }
+        Ok(board)
+    }
+    fn load_description(machine: &str) -> Result<Self> {
+        if let Some(board) = Self::preset(machine) {return Ok(board)}
+        let path = Path::new(machine);
+        let text = std::fs::read_to_string(path).with_context(|| {
+            let presets: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+            format!("{machine} is neither a preset ({}) nor a readable machine description", presets.join(", "))
+        })?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml(&text),
+            Some("ron") => Self::from_ron(&text),
+            _ => bail!("Can't tell the format of {machine}, descriptions are .toml or .ron files"),
+        }.with_context(|| format!("Invalid machine description {machine}"))
+    }
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+    pub fn from_ron(text: &str) -> Result<Self> {
+        Ok(ron::from_str(text)?)
+    }
+    /// Resizes the first RAM region, like QEMU's -m
+    pub fn with_ram_size(mut self, size: uguest) -> Self {
+        if let Some(ram) = self.ram.first_mut() {
+            ram.size = size;
+        }
+        self
+    }
+    /// Replaces the PLIC by APLIC domains (and IMSICs) at their place in QEMU virt, harts get the AIA CSRs of the levels that have an IMSIC
+    pub fn with_aia(mut self, aia: Aia) -> Result<Self> {
+        self.devices.retain(|device| !matches!(device.kind, DeviceKind::Plic | DeviceKind::Aplic | DeviceKind::Imsic));
+        let mut isa = cpu::isa::parse_isa(&self.isa)?;
+        let controllers: &[(DeviceKind, MemMap, aia::Level)] = match aia {
+            Aia::None => &[(DeviceKind::Plic, MemMap::PLIC, aia::Level::Machine)],
+            Aia::Aplic => &[(DeviceKind::Aplic, MemMap::AplicM, aia::Level::Machine), (DeviceKind::Aplic, MemMap::AplicS, aia::Level::Supervisor)],
+            Aia::AplicImsic => &[
+                (DeviceKind::Aplic, MemMap::AplicM, aia::Level::Machine), (DeviceKind::Aplic, MemMap::AplicS, aia::Level::Supervisor),
+                (DeviceKind::Imsic, MemMap::ImsicM, aia::Level::Machine), (DeviceKind::Imsic, MemMap::ImsicS, aia::Level::Supervisor),
+            ],
+        };
+        for (kind, region, level) in controllers {
+            self.devices.push(Device { kind: *kind, base: region.base(), size: None, irq: None, level: *level });
+        }
+        if aia == Aia::AplicImsic {
+            isa.extensions = isa.extensions.union(cpu::isa::Extensions::SMAIA).union(cpu::isa::Extensions::SSAIA);
+        }
+        self.isa = crate::fdt::isa_string(isa);
+        Ok(self)
+    }
+    /// Adds the ECAM host bridge at its place in QEMU virt, INTA..INTD are irqs 32..=35
+    pub fn with_pcie(self, pcie: bool) -> Self {
+        self.with_virt_device(DeviceKind::Pcie, MemMap::PcieEcam, Some(pci::PCIE_IRQ), pcie)
+    }
+    /// Adds the goldfish RTC at its place in QEMU virt
+    pub fn with_rtc(self, rtc: bool) -> Self {
+        self.with_virt_device(DeviceKind::Rtc, MemMap::RTC, Some(rtc::RTC_IRQ), rtc)
+    }
+    /// Adds fw_cfg at its place in QEMU virt
+    pub fn with_fw_cfg(self, fw_cfg: bool) -> Self {
+        self.with_virt_device(DeviceKind::FwCfg, MemMap::FwCfg, None, fw_cfg)
+    }
+    /// Adds the CLINT at its place in QEMU virt, M-mode software gets mtimecmp and IPIs from it
+    pub fn with_clint(self, clint: bool) -> Self {
+        self.with_virt_device(DeviceKind::Clint, MemMap::CLINT, None, clint)
+    }
+    /// Adds the eight virtio-mmio transports at their place in QEMU virt, on irqs 1..=8
+    pub fn with_virtio_mmio(mut self, virtio: bool) -> Self {
+        self.devices.retain(|device| device.kind != DeviceKind::VirtioMmio);
+        for transport in 0..virtio::mmio::TRANSPORTS * virtio as uguest {
+            let (base, irq) = (MemMap::VIRTIO.base() + transport * MemMap::VIRTIO.len(), virtio::mmio::VIRTIO_IRQ + transport as u32);
+            self.devices.push(Device { kind: DeviceKind::VirtioMmio, base, size: None, irq: Some(irq), level: Default::default() });
+        }
+        self
+    }
+    /// Replaces the flash banks by `banks` erased ones at their place in QEMU virt, which has two
+    pub fn with_pflash(mut self, banks: usize) -> Result<Self> {
+        if banks > 2 {bail!("QEMU virt has two flash banks, not {banks}")}
+        self.devices.retain(|device| device.kind != DeviceKind::Flash);
+        for bank in 0..banks as uguest {
+            let base = MemMap::FLASH.base() + bank * pflash::BANK_SIZE;
+            self.devices.push(Device { kind: DeviceKind::Flash, base, size: None, irq: None, level: Default::default() });
+        }
+        Ok(self)
+    }
+    fn with_virt_device(mut self, kind: DeviceKind, region: MemMap, irq: Option<u32>, present: bool) -> Self {
+        self.devices.retain(|device| device.kind != kind);
+        if present {
+            self.devices.push(Device { kind, base: region.base(), size: None, irq, level: Default::default() });
+        }
+        self
+    }
+    /// First device of `kind`
+    pub fn device(&self, kind: DeviceKind) -> Option<&Device> {
+        self.devices.iter().find(|device| device.kind == kind)
+    }
+    /// Interrupt controller of `kind` at `level`, if the description has one
+    pub fn interrupt_controller(&self, kind: DeviceKind, level: aia::Level) -> Option<&Device> {
+        self.devices.iter().find(|device| device.kind == kind && device.level == level)
+    }
+    /// Where raw binaries are loaded and harts start
+    pub fn ram_base(&self) -> uguest {
+        self.ram.first().map_or(MemMap::DRAM.base(), |ram| ram.base)
+    }
+    pub fn misa(&self) -> Result<uguest> {
+        cpu::isa::parse(&self.isa)
+    }
+    /// UART the console goes to, the first one
+    pub fn stdout(&self) -> Option<&Device> {
+        self.devices.iter().find(|device| device.kind == DeviceKind::Uart)
+    }
+
+    /// Memory bus with the RAM and devices of the description, fails on overlaps or an invalid description
+    pub fn bus(&self) -> Result<Memory> {
+        if self.harts == 0 {bail!("A machine needs at least one hart")}
+        if self.ram.is_empty() {bail!("A machine needs RAM")}
+        self.misa()?;
+        if !self.vlen.is_power_of_two() || !(128..=65536).contains(&self.vlen) {
+            bail!("VLEN must be a power of 2 between 128 and 65536, not {}", self.vlen)
+        }
+        let mut mem = Memory::empty();
+        for ram in &self.ram {
+            mem.register(ram.base, ram.size, None, DRAM::new(ram.size)).context("Invalid RAM region")?;
+        }
+        for device in &self.devices {
+            let (base, size, irq) = (device.base, device.size(), device.irq);
+            match device.kind {
+                DeviceKind::Uart => mem.register(base, size, irq, uart::UART::default()),
+                DeviceKind::Plic => mem.register(base, size, irq, plic::Plic::new(self.harts)),
+                DeviceKind::Aplic => mem.register(base, size, irq, aia::Aplic::new(device.level, self.harts)),
+                DeviceKind::Imsic => mem.register(base, size, irq, aia::Imsic::new(device.level)),
+                DeviceKind::Pcie => mem.register(base, size, irq, pci::PcieHost::new(base)),
+                DeviceKind::Rtc => mem.register(base, size, irq, rtc::GoldfishRtc::default()),
+                DeviceKind::FwCfg => {
+                    let ram = self.ram.iter().map(|ram| ram.size).sum();
+                    mem.register(base, size, irq, fw_cfg::FwCfg::new(ram, self.harts))
+                },
+                DeviceKind::Flash => mem.register(base, size, irq, pflash::Pflash::new(size)),
+                DeviceKind::Clint => mem.register(base, size, irq, clint::Clint::new(self.harts)),
+                DeviceKind::VirtioMmio => mem.register(base, size, irq, virtio::mmio::VirtioMmio::default()),
+            }.with_context(|| format!("Can't map the {:?} at {base:#x}", device.kind))?;
+        }
+        Ok(mem)
+    }
+    /// Hart `hartid` in M-mode at the start of RAM, with the ISA of the description
+    pub fn hart(&self, hartid: uguest) -> Result<CPU> {
+        let isa = cpu::isa::parse_isa(&self.isa)?;
+        let mut cpu = CPU { hartid, pc: self.ram_base(), next_pc: self.ram_base(), extensions: isa.extensions, ..Default::default() };
+        cpu.csrs[cpu::csr::file::MISA as usize].0 = isa.misa;
+        if isa.misa & cpu::isa::bit('v') != 0 {
+            cpu.vector = cpu::vector::Vector::new(self.vlen);
+            // Like after reset, vector instructions need a vset{i}vl{i} first
+            cpu.csrs[cpu::csr::file::VTYPE as usize].0 = cpu::vector::VILL;
+        }
+        Ok(cpu)
+    }
+}
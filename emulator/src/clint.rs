@@ -0,0 +1,126 @@
+// SiFive CLINT (QEMU's hw/intc/riscv_aclint.c in CLINT mode): msip and mtimecmp per hart, and mtime
+// mtime is the VM's `clock::Clock`, `VM::update_timers` copies it in before each step and hands guest writes back,
+// then sets MSIP and MTIP of the hart being stepped, the other harts get theirs when they step
+use crate::board::DeviceKind;
+use crate::cpu::csr::file::{ENVCFG_STCE, HENVCFG, HTIMEDELTA, MENVCFG, MIE, MIP, STIMECMP, VSTIMECMP};
+use crate::mem::MemoryRegion;
+use crate::vm::VM;
+use crate::uguest;
+
+const MSIP: uguest = 0x0000;
+const MTIMECMP: uguest = 0x4000;
+const MTIME: uguest = 0xBFF8;
+
+const MSIP_BIT: u32 = 3;
+const MTIP_BIT: u32 = 7;
+const STIP_BIT: u32 = 5;
+const VSTIP_BIT: u32 = 6;
+
+#[derive(Debug, Clone)]
+pub struct Clint {
+    msip: Vec<u32>,
+    /// Never reached until written, so MTIP isn't pending out of reset
+    mtimecmp: Vec<u64>,
+    mtime: u64,
+    /// The guest wrote mtime since the last `update_timers`
+    mtime_written: bool,
+}
+impl Clint {
+    pub fn new(harts: usize) -> Self {
+        Self { msip: vec![0; harts], mtimecmp: vec![u64::MAX; harts], mtime: 0, mtime_written: false }
+    }
+    pub fn mtimecmp(&self, hart: usize) -> Option<u64> {
+        self.mtimecmp.get(hart).copied()
+    }
+    pub fn msip(&self, hart: usize) -> bool {
+        self.msip.get(hart).is_some_and(|msip| msip & 1 != 0)
+    }
+}
+/// Replaces byte `i` of `value`
+fn set_byte(value: u64, i: uguest, byte: u8) -> u64 {
+    value & !(0xFF << (8 * i)) | (byte as u64) << (8 * i)
+}
+impl MemoryRegion for Clint {
+    fn read(&mut self, offset: uguest) -> u8 {
+        let (value, i) = match offset {
+            MSIP..MTIMECMP => (self.msip.get((offset / 4) as usize).copied().unwrap_or(0) as u64, offset % 4),
+            MTIME.. => (self.mtime, offset - MTIME),
+            _ => (self.mtimecmp.get(((offset - MTIMECMP) / 8) as usize).copied().unwrap_or(0), offset % 8),
+        };
+        (value >> (8 * i)) as u8
+    }
+    fn write(&mut self, offset: uguest, val: u8) {
+        match offset {
+            // Only bit 0 is writable
+            MSIP..MTIMECMP if offset.is_multiple_of(4) => {
+                if let Some(msip) = self.msip.get_mut((offset / 4) as usize) {*msip = (val & 1) as u32}
+            },
+            MSIP..MTIMECMP => {},
+            MTIME.. => {
+                self.mtime = set_byte(self.mtime, offset - MTIME, val);
+                self.mtime_written = true;
+            },
+            _ => if let Some(mtimecmp) = self.mtimecmp.get_mut(((offset - MTIMECMP) / 8) as usize) {
+                *mtimecmp = set_byte(*mtimecmp, offset % 8, val);
+            },
+        }
+    }
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {
+        crate::snapshot::cloned(self)
+    }
+    fn restore(&mut self, state: &dyn std::any::Any) -> color_eyre::Result<()> {
+        crate::snapshot::restore_cloned(self, state)
+    }
+}
+
+impl VM {
+    /// Brings the CLINT's mtime and the hart's time CSR to the clock, and sets MSIP and MTIP of the current hart
+    pub(crate) fn update_timers(&mut self) {
+        let Some(clint) = self.board.device(DeviceKind::Clint).map(|device| device.base) else {
+            self.cpu.time = self.clock.now();
+            return
+        };
+        let Some(clint) = self.mem.device_mut::<Clint>(clint) else {return};
+        if std::mem::take(&mut clint.mtime_written) {
+            self.clock.set(clint.mtime);
+        }
+        clint.mtime = self.clock.now();
+        self.cpu.time = clint.mtime;
+        let hart = self.cpu.hartid as usize;
+        let mip = &mut self.cpu.csrs[MIP as usize].0;
+        *mip = *mip & !(1 << MSIP_BIT | 1 << MTIP_BIT)
+            | (clint.msip(hart) as uguest) << MSIP_BIT
+            | (clint.mtimecmp(hart).is_some_and(|mtimecmp| clint.mtime >= mtimecmp) as uguest) << MTIP_BIT;
+    }
+    /// When the current hart waits in WFI with no interrupt pending, the time it waits for: the earliest deadline of the timers
+    /// whose interrupt is enabled, `uguest::MAX` without any. None when it runs.
+    pub fn idle_deadline(&self) -> Option<uguest> {
+        let cpu = &self.cpu;
+        if !cpu.waiting || cpu.csr_value(MIP) & cpu.csr_value(MIE) != 0 {return None}
+        let enabled = |bit: u32| cpu.csr_value(MIE) & 1 << bit != 0;
+        let raw = |csr: u16| cpu.csrs[csr as usize].0;
+        let mut deadlines = Vec::new();
+        if enabled(MTIP_BIT) {
+            let clint = self.board.device(DeviceKind::Clint).and_then(|device| self.mem.device::<Clint>(device.base));
+            deadlines.extend(clint.and_then(|clint| clint.mtimecmp(cpu.hartid as usize)));
+        }
+        if enabled(STIP_BIT) {
+            if raw(MENVCFG) & ENVCFG_STCE != 0 {
+                deadlines.push(raw(STIMECMP));
+            } else if let Some(sbi) = &self.sbi {
+                deadlines.push(sbi.timer());
+            }
+        }
+        if enabled(VSTIP_BIT) && raw(MENVCFG) & raw(HENVCFG) & ENVCFG_STCE != 0 {
+            deadlines.push(raw(VSTIMECMP).wrapping_sub(raw(HTIMEDELTA)));
+        }
+        Some(deadlines.into_iter().min().unwrap_or(uguest::MAX))
+    }
+    /// Skips time to `deadline`, all harts wait for it
+    pub(crate) fn fast_forward(&mut self, deadline: uguest) {
+        if deadline != uguest::MAX {
+            self.clock.skip_to(deadline);
+            self.update_timers();
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Virtual time: mtime, what the time CSR and the CLINT read, in ticks of the timebase (`fdt::TIMEBASE_FREQUENCY`)
+// It either counts retired instructions, so runs are reproducible, or follows the host clock
+// When every hart waits in WFI for a timer, `VM::step` and `Machine::step` skip time to the earliest deadline
+use std::time::Instant;
+
+use crate::uguest;
+
+/// How mtime advances
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockMode {
+    /// By this many ticks per retired instruction, like QEMU's -icount
+    Icount(uguest),
+    /// With the host clock
+    Realtime,
+}
+impl Default for ClockMode {
+    fn default() -> Self {
+        Self::Icount(1)
+    }
+}
+impl std::str::FromStr for ClockMode {
+    type Err = String;
+    /// icount, icount=TICKS or realtime
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode.split_once('=') {
+            None if mode == "icount" => Ok(Self::default()),
+            None if mode == "realtime" => Ok(Self::Realtime),
+            Some(("icount", ticks)) => match ticks.parse() {
+                Ok(ticks) if ticks > 0 => Ok(Self::Icount(ticks)),
+                _ => Err(format!("Invalid ticks per instruction {ticks:?}")),
+            },
+            _ => Err(format!("Unknown clock {mode:?}, it is icount, icount=TICKS or realtime")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    pub mode: ClockMode,
+    /// Ticks of the retired instructions in icount mode
+    counted: uguest,
+    start: Instant,
+    /// Added to the time of the mode, by skips and by the guest setting mtime
+    offset: uguest,
+}
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(ClockMode::default())
+    }
+}
+impl Clock {
+    pub fn new(mode: ClockMode) -> Self {
+        Self { mode, counted: 0, start: Instant::now(), offset: 0 }
+    }
+    fn base(&self) -> uguest {
+        match self.mode {
+            ClockMode::Icount(_) => self.counted,
+            ClockMode::Realtime => {
+                let ticks = self.start.elapsed().as_nanos() * crate::fdt::TIMEBASE_FREQUENCY as u128 / 1_000_000_000;
+                ticks as uguest
+            },
+        }
+    }
+    /// mtime
+    pub fn now(&self) -> uguest {
+        self.base().wrapping_add(self.offset)
+    }
+    /// Called once per retired instruction
+    pub fn retired(&mut self) {
+        if let ClockMode::Icount(ticks) = self.mode {
+            self.counted = self.counted.wrapping_add(ticks);
+        }
+    }
+    /// Jumps to `time` if it's ahead, time never goes back
+    pub fn skip_to(&mut self, time: uguest) {
+        let now = self.now();
+        if time > now {
+            self.offset = self.offset.wrapping_add(time - now);
+        }
+    }
+    /// The guest wrote mtime
+    pub fn set(&mut self, time: uguest) {
+        self.offset = time.wrapping_sub(self.base());
+    }
+}
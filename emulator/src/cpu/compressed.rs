@@ -0,0 +1,141 @@
+// RVC: every compressed instruction is an alias of a 32 bits one, they're expanded before execution
+// See Chapter 16. "C" Extension for Compressed Instructions, Table 33-35 for the encodings
+use bit_field::BitField;
+
+const LOAD: u32 = 0b0000011;
+const OP_IMM: u32 = 0b0010011;
+const OP_IMM_32: u32 = 0b0011011;
+const STORE: u32 = 0b0100011;
+const OP: u32 = 0b0110011;
+const LUI: u32 = 0b0110111;
+const OP_32: u32 = 0b0111011;
+const BRANCH: u32 = 0b1100011;
+const JALR: u32 = 0b1100111;
+const JAL: u32 = 0b1101111;
+const EBREAK: u32 = 0x0010_0073;
+
+fn i_type(opcode: u32, fun3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+    ((imm as u32) & 0xFFF) << 20 | rs1 << 15 | fun3 << 12 | rd << 7 | opcode
+}
+fn r_type(opcode: u32, fun3: u32, fun7: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    fun7 << 25 | rs2 << 20 | rs1 << 15 | fun3 << 12 | rd << 7 | opcode
+}
+fn s_type(fun3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    imm.get_bits(5..=11) << 25 | rs2 << 20 | rs1 << 15 | fun3 << 12 | imm.get_bits(0..=4) << 7 | STORE
+}
+fn b_type(fun3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    (imm.get_bit(12) as u32) << 31 | imm.get_bits(5..=10) << 25 | rs2 << 20 | rs1 << 15 | fun3 << 12
+        | imm.get_bits(1..=4) << 8 | (imm.get_bit(11) as u32) << 7 | BRANCH
+}
+fn j_type(rd: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    (imm.get_bit(20) as u32) << 31 | imm.get_bits(1..=10) << 21 | (imm.get_bit(11) as u32) << 20
+        | imm.get_bits(12..=19) << 12 | rd << 7 | JAL
+}
+
+/// Sign-extends the `bits` lower bits
+fn sext(val: u32, bits: u32) -> i32 {
+    ((val << (32-bits)) as i32) >> (32-bits)
+}
+/// Gathers scattered immediate bits, `layout` lists (instruction bit, immediate bit) pairs
+fn gather(inst: u16, layout: &[(usize, usize)]) -> u32 {
+    layout.iter().fold(0, |imm, &(from, to)| imm | (inst.get_bit(from) as u32) << to)
+}
+
+/// Expands a 16 bits instruction to the 32 bits one it stands for, None if it's illegal or reserved
+/// Floating point loads and stores are illegal as there is no F/D extension
+pub fn expand(inst: u16) -> Option<u32> {
+    let fun3 = inst.get_bits(13..=15) as u32;
+    let rd = inst.get_bits(7..=11) as u32;
+    let rs2 = inst.get_bits(2..=6) as u32;
+    // x8-x15 registers of the CIW, CL, CS, CA and CB formats
+    let rd_ = inst.get_bits(2..=4) as u32 + 8;
+    let rs1_ = inst.get_bits(7..=9) as u32 + 8;
+    // 6 bits immediate of the CI format, imm[5] is bit 12
+    let ci_imm = sext(gather(inst, &[(2,0),(3,1),(4,2),(5,3),(6,4),(12,5)]), 6);
+    let ci_shamt = ci_imm as u32 & 0x3F;
+    // Offsets of the word and double word loads/stores, scaled
+    let cl_w = gather(inst, &[(6,2),(10,3),(11,4),(12,5),(5,6)]) as i32;
+    let cl_d = gather(inst, &[(10,3),(11,4),(12,5),(5,6),(6,7)]) as i32;
+
+    let expanded = match (inst & 0b11, fun3) {
+        // Quadrant 0
+        (0b00, 0b000) => {
+            let imm = gather(inst, &[(6,2),(5,3),(11,4),(12,5),(7,6),(8,7),(9,8),(10,9)]);
+            if imm == 0 {return None} // Also the all zeros illegal instruction
+            i_type(OP_IMM, 0b000, rd_, 2, imm as i32) // c.addi4spn
+        },
+        (0b00, 0b010) => i_type(LOAD, 0b010, rd_, rs1_, cl_w), // c.lw
+        (0b00, 0b011) => i_type(LOAD, 0b011, rd_, rs1_, cl_d), // c.ld
+        (0b00, 0b110) => s_type(0b010, rs1_, rd_, cl_w), // c.sw
+        (0b00, 0b111) => s_type(0b011, rs1_, rd_, cl_d), // c.sd
+        // Quadrant 1
+        (0b01, 0b000) => i_type(OP_IMM, 0b000, rd, rd, ci_imm), // c.addi, c.nop
+        (0b01, 0b001) => {
+            if rd == 0 {return None}
+            i_type(OP_IMM_32, 0b000, rd, rd, ci_imm) // c.addiw
+        },
+        (0b01, 0b010) => i_type(OP_IMM, 0b000, rd, 0, ci_imm), // c.li
+        (0b01, 0b011) if rd == 2 => {
+            let imm = sext(gather(inst, &[(6,4),(2,5),(5,6),(3,7),(4,8),(12,9)]), 10);
+            if imm == 0 {return None}
+            i_type(OP_IMM, 0b000, 2, 2, imm) // c.addi16sp
+        },
+        (0b01, 0b011) => {
+            if ci_imm == 0 {return None}
+            (ci_imm as u32) << 12 | rd << 7 | LUI // c.lui
+        },
+        (0b01, 0b100) => match (inst.get_bits(10..=11), inst.get_bit(12), inst.get_bits(5..=6)) {
+            (0b00, _, _) => r_type(OP_IMM, 0b101, (ci_shamt >> 5) & 1, rs1_, rs1_, ci_shamt & 0x1F), // c.srli
+            (0b01, _, _) => r_type(OP_IMM, 0b101, 0b0100000 | (ci_shamt >> 5) & 1, rs1_, rs1_, ci_shamt & 0x1F), // c.srai
+            (0b10, _, _) => i_type(OP_IMM, 0b111, rs1_, rs1_, ci_imm), // c.andi
+            (_, false, 0b00) => r_type(OP, 0b000, 0b0100000, rs1_, rs1_, rd_), // c.sub
+            (_, false, 0b01) => r_type(OP, 0b100, 0, rs1_, rs1_, rd_), // c.xor
+            (_, false, 0b10) => r_type(OP, 0b110, 0, rs1_, rs1_, rd_), // c.or
+            (_, false, _) => r_type(OP, 0b111, 0, rs1_, rs1_, rd_), // c.and
+            (_, true, 0b00) => r_type(OP_32, 0b000, 0b0100000, rs1_, rs1_, rd_), // c.subw
+            (_, true, 0b01) => r_type(OP_32, 0b000, 0, rs1_, rs1_, rd_), // c.addw
+            _ => return None,
+        },
+        (0b01, 0b101) => {
+            let imm = sext(gather(inst, &[(3,1),(4,2),(5,3),(11,4),(2,5),(7,6),(6,7),(9,8),(10,9),(8,10),(12,11)]), 12);
+            j_type(0, imm) // c.j
+        },
+        (0b01, 0b110 | 0b111) => {
+            let imm = sext(gather(inst, &[(3,1),(4,2),(10,3),(11,4),(2,5),(5,6),(6,7),(12,8)]), 9);
+            b_type(fun3 & 1, rs1_, 0, imm) // c.beqz, c.bnez
+        },
+        // Quadrant 2
+        (0b10, 0b000) => r_type(OP_IMM, 0b001, (ci_shamt >> 5) & 1, rd, rd, ci_shamt & 0x1F), // c.slli
+        (0b10, 0b010) => {
+            if rd == 0 {return None}
+            let imm = gather(inst, &[(4,2),(5,3),(6,4),(12,5),(2,6),(3,7)]);
+            i_type(LOAD, 0b010, rd, 2, imm as i32) // c.lwsp
+        },
+        (0b10, 0b011) => {
+            if rd == 0 {return None}
+            let imm = gather(inst, &[(5,3),(6,4),(12,5),(2,6),(3,7),(4,8)]);
+            i_type(LOAD, 0b011, rd, 2, imm as i32) // c.ldsp
+        },
+        (0b10, 0b100) => match (inst.get_bit(12), rd, rs2) {
+            (false, 0, 0) => return None,
+            (false, rs1, 0) => i_type(JALR, 0b000, 0, rs1, 0), // c.jr
+            (false, rd, rs2) => r_type(OP, 0b000, 0, rd, 0, rs2), // c.mv
+            (true, 0, 0) => EBREAK, // c.ebreak
+            (true, rs1, 0) => i_type(JALR, 0b000, 1, rs1, 0), // c.jalr
+            (true, rd, rs2) => r_type(OP, 0b000, 0, rd, rd, rs2), // c.add
+        },
+        (0b10, 0b110) => {
+            let imm = gather(inst, &[(9,2),(10,3),(11,4),(12,5),(7,6),(8,7)]);
+            s_type(0b010, 2, rs2, imm as i32) // c.swsp
+        },
+        (0b10, 0b111) => {
+            let imm = gather(inst, &[(10,3),(11,4),(12,5),(7,6),(8,7),(9,8)]);
+            s_type(0b011, 2, rs2, imm as i32) // c.sdsp
+        },
+        _ => return None,
+    };
+    Some(expanded)
+}
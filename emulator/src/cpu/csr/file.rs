@@ -0,0 +1,421 @@
+// Behaviour of the implemented CSRs on top of the raw `CPU::csrs` storage:
+// access checks, WARL masks, read-only registers, aliases and counters
+use bit_field::BitField;
+
+use super::CsrID;
+use crate::cpu::mmu::{ATP_PPN, SV39};
+use crate::cpu::trap::Exception;
+use crate::cpu::{PrivilegeLevel, CPU};
+use crate::uguest;
+
+pub const VSTART: u16 = 0x008;
+pub const VXSAT: u16 = 0x009;
+pub const VXRM: u16 = 0x00A;
+pub const VCSR: u16 = 0x00F;
+pub const VSSTATUS: u16 = 0x200;
+pub const VSIE: u16 = 0x204;
+pub const VSTVEC: u16 = 0x205;
+pub const VSSCRATCH: u16 = 0x240;
+pub const VSEPC: u16 = 0x241;
+pub const VSCAUSE: u16 = 0x242;
+pub const VSTVAL: u16 = 0x243;
+pub const VSIP: u16 = 0x244;
+pub const VSTIMECMP: u16 = 0x24D;
+pub const VSATP: u16 = 0x280;
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SENVCFG: u16 = 0x10A;
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+pub const STIMECMP: u16 = 0x14D;
+pub const SISELECT: u16 = 0x150;
+pub const SIREG: u16 = 0x151;
+pub const STOPEI: u16 = 0x15C;
+pub const SATP: u16 = 0x180;
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MENVCFG: u16 = 0x30A;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MTINST: u16 = 0x34A;
+pub const MTVAL2: u16 = 0x34B;
+pub const MISELECT: u16 = 0x350;
+pub const MIREG: u16 = 0x351;
+pub const MTOPEI: u16 = 0x35C;
+pub const HSTATUS: u16 = 0x600;
+pub const HEDELEG: u16 = 0x602;
+pub const HIDELEG: u16 = 0x603;
+pub const HIE: u16 = 0x604;
+pub const HTIMEDELTA: u16 = 0x605;
+pub const HCOUNTEREN: u16 = 0x606;
+pub const HGEIE: u16 = 0x607;
+pub const HENVCFG: u16 = 0x60A;
+pub const HTVAL: u16 = 0x643;
+pub const HIP: u16 = 0x644;
+pub const HVIP: u16 = 0x645;
+pub const HTINST: u16 = 0x64A;
+pub const HGATP: u16 = 0x680;
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const VL: u16 = 0xC20;
+pub const VTYPE: u16 = 0xC21;
+pub const VLENB: u16 = 0xC22;
+pub const HGEIP: u16 = 0xE12;
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+pub const MCONFIGPTR: u16 = 0xF15;
+
+/// SIE, MIE, SPIE, MPIE, SPP, MPP, MPRV, SUM, MXR, TVM, TW, TSR
+pub const MSTATUS_WRITABLE: uguest = 0x7E_19AA;
+/// Vector state (Off, Initial, Clean, Dirty), writable when V is enabled
+pub const MSTATUS_VS: uguest = 0x600;
+/// State Dirty, set on reads when a state (only VS here) is dirty
+pub const MSTATUS_SD: uguest = 1 << 63;
+/// UXL = SXL = 2 (64 bits), read-only
+pub const MSTATUS_XLEN: uguest = 0xA_0000_0000;
+/// Previous virtualization mode and Guest Virtual Address, writable with H
+pub const MSTATUS_MPV: uguest = 1 << 39;
+pub const MSTATUS_GVA: uguest = 1 << 38;
+/// vsstatus.UXL = 2 (64 bits), read-only
+pub const VSSTATUS_UXL: uguest = 0x2_0000_0000;
+/// GVA, SPV, SPVP, HU, VTVM, VTW, VTSR (no guest external interrupts, VGEIN stays 0)
+pub const HSTATUS_WRITABLE: uguest = 0x70_03C0;
+/// VSXL = 2 (64 bits), read-only
+pub const HSTATUS_VSXL: uguest = 0x2_0000_0000;
+/// Bits of mstatus visible in sstatus: SIE, SPIE, UBE, SPP, VS, FS, XS, SUM, MXR, UXL, SD
+pub const SSTATUS_MASK: uguest = 0x8000_0003_000D_E762;
+/// SIE, SPIE, SPP, SUM, MXR
+pub const SSTATUS_WRITABLE: uguest = 0xC_0122;
+/// Sstc, stimecmp is usable below M-mode and drives STIP (menvcfg), same for vstimecmp and VSTIP (henvcfg)
+pub const ENVCFG_STCE: uguest = 1 << 63;
+/// Supervisor software/timer/external interrupts, the ones that can be delegated
+pub const S_INTERRUPTS: uguest = 0x222;
+/// Software, timer and external interrupts of both levels
+pub const ALL_INTERRUPTS: uguest = 0xAAA;
+/// VS software/timer/external interrupts, always delegated to HS-mode with H
+pub const VS_INTERRUPTS: uguest = 0x444;
+/// Every exception except environment calls from M-mode can be delegated
+pub const DELEGABLE_EXCEPTIONS: uguest = 0xB3FF;
+/// Environment calls from VS-mode, guest-page faults and virtual instructions, delegable with H
+pub const HYPERVISOR_EXCEPTIONS: uguest = 0xF0_0400;
+/// Exceptions HS-mode can leave to guests, not its own environment calls nor the ones meant for the hypervisor
+pub const GUEST_EXCEPTIONS: uguest = 0xB1FF;
+/// Supervisor CSRs guests access in their place when V=1
+const VIRTUAL_ALIASES: [(u16, u16); 10] = [
+    (SSTATUS, VSSTATUS), (SIE, VSIE), (STVEC, VSTVEC), (SSCRATCH, VSSCRATCH), (SEPC, VSEPC),
+    (SCAUSE, VSCAUSE), (STVAL, VSTVAL), (SIP, VSIP), (SATP, VSATP), (STIMECMP, VSTIMECMP),
+];
+/// RV64 with A, C, I, M, S and U, what is implemented by default (see `cpu::isa` to leave some out or enable V and H)
+pub const MISA_VALUE: uguest = (2 << 62) | (1 << 0) | (1 << 2) | (1 << 8) | (1 << 12) | (1 << 18) | (1 << 20);
+
+impl CPU {
+    /// Checks done before a Zicsr instruction touches `csr`, failures raise an illegal instruction,
+    /// or a virtual instruction for the ones HS-mode could do (21.6.1)
+    fn check_csr(&self, csr: CsrID, write: bool) -> Result<(), Exception> {
+        let id = csr.get();
+        let (level, writable) = csr.access().ok_or(Exception::IllegalInstruction)?;
+        if !self.csr_implemented(id) || (write && !writable) {
+            return Err(Exception::IllegalInstruction)
+        }
+        // Guests can't reach the H and VS CSRs, nor VU-mode the supervisor ones
+        if self.privilege_level < level || (self.virt && csr.hypervisor()) {
+            return Err(if self.virt && level < PrivilegeLevel::Machine {Exception::VirtualInstruction} else {Exception::IllegalInstruction})
+        }
+        // stimecmp needs time to be readable and STCE, guests are stopped by the hypervisor's
+        if id == STIMECMP && self.privilege_level < PrivilegeLevel::Machine {
+            let enabled = |counteren: u16, envcfg: u16| {
+                self.csrs[counteren as usize].0.get_bit(1) && self.csr_value(envcfg) & ENVCFG_STCE != 0
+            };
+            if !enabled(MCOUNTEREN, MENVCFG) {return Err(Exception::IllegalInstruction)}
+            if self.virt && !enabled(HCOUNTEREN, HENVCFG) {return Err(Exception::VirtualInstruction)}
+        }
+        // No guest interrupt files, the hypervisor has to emulate the S-level AIA CSRs of guests
+        if self.virt && matches!(id, SISELECT | SIREG | STOPEI) {return Err(Exception::VirtualInstruction)}
+        let select = match id {
+            MIREG => Some(MISELECT),
+            SIREG => Some(SISELECT),
+            _ => None,
+        };
+        if select.is_some_and(|select| !crate::aia::InterruptFile::valid_select(self.csrs[select as usize].0)) {
+            return Err(Exception::IllegalInstruction)
+        }
+        if let CYCLE..=INSTRET = id {
+            let enabled = |counteren: u16| self.csrs[counteren as usize].0.get_bit((id-CYCLE) as usize);
+            if self.privilege_level < PrivilegeLevel::Machine && !enabled(MCOUNTEREN) {
+                return Err(Exception::IllegalInstruction)
+            }
+            if (self.virt && !enabled(HCOUNTEREN)) || (self.privilege_level == PrivilegeLevel::User && !enabled(SCOUNTEREN)) {
+                return Err(self.privileged_instruction())
+            }
+        }
+        // Vector CSRs are off with the vector unit
+        if matches!(id, VSTART..=VCSR | VL..=VLENB) && !self.vector_enabled() {
+            return Err(Exception::IllegalInstruction)
+        }
+        // Trap Virtual Memory, hstatus.VTVM for the satp of guests
+        if self.privilege_level == PrivilegeLevel::Supervisor {
+            if id == SATP && self.virt && self.csrs[HSTATUS as usize].0.get_bit(20) {
+                return Err(Exception::VirtualInstruction)
+            }
+            if matches!(id, SATP | HGATP) && !self.virt && self.csrs[MSTATUS as usize].0.get_bit(20) {
+                return Err(Exception::IllegalInstruction)
+            }
+        }
+        Ok(())
+    }
+    fn csr_implemented(&self, id: u16) -> bool {
+        match id {
+            STIMECMP => return self.sstc_enabled(),
+            VSTIMECMP => return self.sstc_enabled() && self.hypervisor_enabled(),
+            MISELECT | MIREG | MTOPEI => return self.extensions.contains(crate::cpu::isa::Extensions::SMAIA),
+            SISELECT | SIREG | STOPEI => return self.extensions.contains(crate::cpu::isa::Extensions::SSAIA),
+            _ => {},
+        }
+        let hypervisor = matches!(id,
+            HSTATUS | HEDELEG | HIDELEG | HIE | HTIMEDELTA | HCOUNTEREN | HGEIE | HENVCFG
+            | HTVAL | HIP | HVIP | HTINST | HGATP | HGEIP | MTINST | MTVAL2
+            | VSSTATUS | VSIE | VSTVEC | VSSCRATCH | VSEPC | VSCAUSE | VSTVAL | VSIP | VSATP
+        );
+        if hypervisor {return self.hypervisor_enabled()}
+        matches!(id,
+            SSTATUS | SIE | STVEC | SCOUNTEREN | SENVCFG | SSCRATCH | SEPC | SCAUSE | STVAL | SIP | SATP
+            | MSTATUS | MISA | MEDELEG | MIDELEG | MIE | MTVEC | MCOUNTEREN | MENVCFG
+            | MSCRATCH | MEPC | MCAUSE | MTVAL | MIP
+            | MCYCLE | MINSTRET | CYCLE | TIME | INSTRET
+            | VSTART | VXSAT | VXRM | VCSR | VL | VTYPE | VLENB
+            | MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR
+            | 0x3B0..=0x3EF // pmpaddr
+        ) || (matches!(id, 0x3A0..=0x3AF) && id.is_multiple_of(2)) // Odd pmpcfg are RV32 only
+    }
+    pub fn hypervisor_enabled(&self) -> bool {
+        self.csrs[MISA as usize].0 & crate::cpu::isa::bit('h') != 0
+    }
+    pub fn sstc_enabled(&self) -> bool {
+        self.extensions.contains(crate::cpu::isa::Extensions::SSTC)
+    }
+    pub fn time(&self) -> uguest {
+        self.time
+    }
+    /// mip with the interrupts devices drive: the timer ones of Sstc, STIP follows stimecmp and VSTIP is hvip's or'ed with vstimecmp's,
+    /// and the external ones of the APLICs and interrupt files (see `aia`)
+    fn mip(&self) -> uguest {
+        let raw = |id: u16| self.csrs[id as usize].0;
+        let mut mip = raw(MIP) | self.external_interrupts;
+        for level in [crate::aia::Level::Machine, crate::aia::Level::Supervisor] {
+            if self.interrupt_files[level as usize].interrupt() {mip |= level.eip()}
+        }
+        if raw(MENVCFG) & ENVCFG_STCE != 0 {
+            mip.set_bit(5, self.time() >= raw(STIMECMP));
+        }
+        if raw(MENVCFG) & raw(HENVCFG) & ENVCFG_STCE != 0 && self.time().wrapping_add(raw(HTIMEDELTA)) >= raw(VSTIMECMP) {
+            mip |= 1 << 6;
+        }
+        mip
+    }
+    /// CSR a Zicsr instruction really accesses, guests get the VS CSRs in place of the supervisor ones
+    fn virtual_alias(&self, id: u16) -> u16 {
+        if !self.virt {return id}
+        VIRTUAL_ALIASES.iter().find(|(csr, _)| *csr == id).map_or(id, |(_, alias)| *alias)
+    }
+
+    /// csrr, with the access checks of the current privilege level
+    pub fn read_csr(&mut self, csr: CsrID) -> Result<uguest, Exception> {
+        self.check_csr(csr, false)?;
+        Ok(self.csr_value(self.virtual_alias(csr.get())))
+    }
+    /// Value csrrs/csrrc set or clear bits of, `old` is what they read
+    /// mip.SEIP reads or'ed with the external interrupt, only the software-writable bit is written back
+    pub fn modified_csr(&self, csr: CsrID, old: uguest) -> uguest {
+        match self.virtual_alias(csr.get()) {
+            MIP => self.csrs[MIP as usize].0,
+            _ => old,
+        }
+    }
+    /// csrw, with the access checks of the current privilege level
+    pub fn write_csr(&mut self, csr: CsrID, value: uguest) -> Result<(), Exception> {
+        self.check_csr(csr, true)?;
+        self.set_csr_value(self.virtual_alias(csr.get()), value);
+        Ok(())
+    }
+
+    /// Value software reads, without access checks
+    pub fn csr_value(&self, id: u16) -> uguest {
+        let raw = |id: u16| self.csrs[id as usize].0;
+        match id {
+            MSTATUS => self.mstatus(),
+            SSTATUS => self.mstatus() & SSTATUS_MASK,
+            SIE => raw(MIE) & raw(MIDELEG),
+            MIP => self.mip(),
+            SIP => self.mip() & raw(MIDELEG),
+            // VS interrupts are always delegated, HS-mode picks which ones go to guests in hideleg
+            MIDELEG if self.hypervisor_enabled() => raw(MIDELEG) | VS_INTERRUPTS,
+            HSTATUS => raw(HSTATUS) | HSTATUS_VSXL,
+            VSSTATUS => {
+                let vsstatus = raw(VSSTATUS) | VSSTATUS_UXL;
+                if vsstatus & MSTATUS_VS == MSTATUS_VS {vsstatus | MSTATUS_SD} else {vsstatus}
+            },
+            // The VS bits of mip are hvip's, no guest external interrupt is implemented (SGEIP, hgeip)
+            HVIP => raw(MIP) & VS_INTERRUPTS,
+            HIP => self.mip() & VS_INTERRUPTS,
+            HIE => raw(MIE) & VS_INTERRUPTS,
+            // Guests see their interrupts at the S bits
+            VSIP => (self.mip() & raw(HIDELEG) & VS_INTERRUPTS) >> 1,
+            VSIE => (raw(MIE) & raw(HIDELEG) & VS_INTERRUPTS) >> 1,
+            HGEIE | HGEIP => 0,
+            MIREG => self.interrupt_files[0].read(raw(MISELECT)),
+            SIREG => self.interrupt_files[1].read(raw(SISELECT)),
+            MTOPEI => self.interrupt_files[0].topei(),
+            STOPEI => self.interrupt_files[1].topei(),
+            // henvcfg.STCE is read-only 0 while menvcfg.STCE is
+            HENVCFG => raw(HENVCFG) & (raw(MENVCFG) | !ENVCFG_STCE),
+            MHARTID => self.hartid,
+            MVENDORID | MARCHID | MIMPID | MCONFIGPTR => 0,
+            MCYCLE | CYCLE => self.cycle,
+            MINSTRET | INSTRET => self.instret,
+            TIME if self.virt => self.time().wrapping_add(raw(HTIMEDELTA)),
+            TIME => self.time(),
+            VCSR => raw(VXRM) << 1 | raw(VXSAT),
+            VLENB => self.vector.vlenb() as uguest,
+            _ => raw(id),
+        }
+    }
+    fn mstatus(&self) -> uguest {
+        let mstatus = self.csrs[MSTATUS as usize].0 | MSTATUS_XLEN;
+        if mstatus & MSTATUS_VS == MSTATUS_VS {mstatus | MSTATUS_SD} else {mstatus}
+    }
+    /// mstatus.VS can only be changed with V
+    fn status_writable(&self, writable: uguest) -> uguest {
+        if self.csrs[MISA as usize].0 & crate::cpu::isa::bit('v') != 0 {writable | MSTATUS_VS} else {writable}
+    }
+    /// Writes like a csrw instruction would without access checks, only legal values are kept (WARL)
+    pub fn set_csr_value(&mut self, id: u16, value: uguest) {
+        let masked = |cpu: &mut Self, id: u16, mask: uguest, value: uguest| {
+            let old = cpu.csrs[id as usize].0;
+            cpu.csrs[id as usize].0 = (old & !mask) | (value & mask);
+        };
+        match id {
+            MSTATUS => {
+                let mut value = value;
+                // MPP can't hold the reserved encoding, keep the old mode
+                if value.get_bits(11..=12) == PrivilegeLevel::Reserved as uguest {
+                    value.set_bits(11..=12, self.csrs[MSTATUS as usize].0.get_bits(11..=12));
+                }
+                let hypervisor = if self.hypervisor_enabled() {MSTATUS_MPV | MSTATUS_GVA} else {0};
+                masked(self, MSTATUS, self.status_writable(MSTATUS_WRITABLE) | hypervisor, value)
+            },
+            SSTATUS => masked(self, MSTATUS, self.status_writable(SSTATUS_WRITABLE), value),
+            VSSTATUS => masked(self, VSSTATUS, self.status_writable(SSTATUS_WRITABLE), value),
+            HSTATUS => masked(self, HSTATUS, HSTATUS_WRITABLE, value),
+            MEDELEG => {
+                let hypervisor = if self.hypervisor_enabled() {HYPERVISOR_EXCEPTIONS} else {0};
+                masked(self, MEDELEG, DELEGABLE_EXCEPTIONS | hypervisor, value)
+            },
+            HEDELEG => masked(self, HEDELEG, GUEST_EXCEPTIONS, value),
+            MIDELEG => masked(self, MIDELEG, S_INTERRUPTS, value),
+            HIDELEG => masked(self, HIDELEG, VS_INTERRUPTS, value),
+            MIE => {
+                let hypervisor = if self.hypervisor_enabled() {VS_INTERRUPTS} else {0};
+                masked(self, MIE, ALL_INTERRUPTS | hypervisor, value)
+            },
+            HIE => masked(self, MIE, VS_INTERRUPTS, value),
+            // M-level pending bits are driven by devices, VS ones by the hypervisor through hvip
+            // STIP is read-only with Sstc
+            MIP | HIP => {
+                let hypervisor = if self.hypervisor_enabled() {1 << 2} else {0};
+                let supervisor = if self.csrs[MENVCFG as usize].0 & ENVCFG_STCE != 0 {S_INTERRUPTS & !(1 << 5)} else {S_INTERRUPTS};
+                masked(self, MIP, if id == HIP {hypervisor} else {supervisor | hypervisor}, value)
+            },
+            HVIP => masked(self, MIP, VS_INTERRUPTS, value),
+            VSIE => {
+                let mask = self.csrs[HIDELEG as usize].0 & VS_INTERRUPTS;
+                masked(self, MIE, mask, value << 1)
+            },
+            VSIP => {
+                // Only the software interrupt can be cleared by guests
+                let mask = self.csrs[HIDELEG as usize].0 & (1 << 2);
+                masked(self, MIP, mask, value << 1)
+            },
+            SIE => {
+                let mask = self.csrs[MIDELEG as usize].0 & S_INTERRUPTS;
+                masked(self, MIE, mask, value)
+            },
+            SIP => {
+                // Only the software interrupt can be cleared from S-mode
+                let mask = self.csrs[MIDELEG as usize].0 & (1 << 1);
+                masked(self, MIP, mask, value)
+            },
+            // Direct or vectored
+            MTVEC | STVEC | VSTVEC => {
+                if value & 0b11 < 2 {self.csrs[id as usize].0 = value}
+            },
+            // 2 bytes aligned with C, 4 without
+            MEPC | SEPC | VSEPC => {
+                let c = self.csrs[MISA as usize].0 & crate::cpu::isa::bit('c') != 0;
+                self.csrs[id as usize].0 = value & if c {!1} else {!0b11};
+            },
+            MCOUNTEREN | SCOUNTEREN | HCOUNTEREN => self.csrs[id as usize].0 = value & 0b111,
+            // FIOM, and STCE with Sstc (henvcfg's only once menvcfg's is set)
+            MENVCFG => self.csrs[MENVCFG as usize].0 = value & if self.sstc_enabled() {ENVCFG_STCE | 1} else {1},
+            HENVCFG => self.csrs[HENVCFG as usize].0 = value & (self.csrs[MENVCFG as usize].0 & ENVCFG_STCE | 1),
+            SENVCFG => self.csrs[SENVCFG as usize].0 = value & 1,
+            // Selects registers up to 0xFFF, the rest are custom
+            MISELECT | SISELECT => self.csrs[id as usize].0 = value & 0xFFF,
+            MIREG => self.interrupt_files[0].write(self.csrs[MISELECT as usize].0, value),
+            SIREG => self.interrupt_files[1].write(self.csrs[SISELECT as usize].0, value),
+            // Any write claims the top interrupt
+            MTOPEI => self.interrupt_files[0].claim(),
+            STOPEI => self.interrupt_files[1].claim(),
+            // Bare or Sv39, writes with another mode are ignored
+            SATP | VSATP => {
+                if matches!(value >> 60, 0 | SV39) {self.csrs[id as usize].0 = value}
+            },
+            // Bare or Sv39x4, without VMIDs, the root page table is 16KiB aligned
+            HGATP => {
+                if matches!(value >> 60, 0 | SV39) {self.csrs[HGATP as usize].0 = value & (0xF << 60 | ATP_PPN & !0b11)}
+            },
+            // vstart holds element indices, up to VLEN - 1 for 8 bits elements
+            VSTART => {
+                self.csrs[VSTART as usize].0 = value & (self.vector.vlen as uguest - 1);
+                self.vector_dirty()
+            },
+            VXSAT => {
+                self.csrs[VXSAT as usize].0 = value & 1;
+                self.vector_dirty()
+            },
+            VXRM => {
+                self.csrs[VXRM as usize].0 = value & 0b11;
+                self.vector_dirty()
+            },
+            VCSR => {
+                self.csrs[VXSAT as usize].0 = value & 1;
+                self.csrs[VXRM as usize].0 = (value >> 1) & 0b11;
+                self.vector_dirty()
+            },
+            MCYCLE => self.cycle = value,
+            MINSTRET => self.instret = value,
+            // misa is set when building the hart, the extensions can't be changed at runtime
+            MISA | MHARTID | MVENDORID | MARCHID | MIMPID | MCONFIGPTR | CYCLE | TIME | INSTRET | VL | VTYPE | VLENB | HGEIE | HGEIP => {},
+            0x3A0..=0x3AF => self.write_pmpcfg(id, value),
+            0x3B0..=0x3EF => self.write_pmpaddr(id, value),
+            _ => self.csrs[id as usize].0 = value,
+        }
+    }
+}
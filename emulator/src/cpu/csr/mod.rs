@@ -1,13 +1,15 @@
 use std::fmt::Display;
 
-use super::uguest;
+use super::{uguest, PrivilegeLevel};
+
+pub mod file;
 
 fn todo_write(id: CsrID, csr: uguest) -> uguest {
     println!("WARN: writing to unsupported csr {id}");
     csr
 }
 pub type CsrWriteHandler = fn(id: CsrID, uguest) -> uguest;
-pub const CSRS: [CsrWriteHandler; 4096] = [todo_write; 4096];
+pub static CSRS: [CsrWriteHandler; 4096] = [todo_write; 4096];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
@@ -40,6 +42,9 @@ pub enum SupportedCsrID {
     mip = 0x344, // "MRW", "Machine interrupt pending."),
     mtinst = 0x34A, // "MRW", "Machine trap instruction (transformed)."),
     mtval2 = 0x34B, // "MRW", "Machine bad guest physical address."),
+    miselect = 0x350, // "MRW", "Machine indirect register select."),
+    mireg = 0x351, // "MRW", "Machine indirect register alias."),
+    mtopei = 0x35C, // "MRW", "Machine top external interrupt."),
 
     // Machine Configuration
     menvcfg = 0x30A, // "MRW", "Machine environment configuration register."),
@@ -58,6 +63,10 @@ pub enum SupportedCsrID {
     pmpaddr1 = 0x3B1, // "MRW", "Physical memory protection address register."),
     pmpaddr63 = 0x3EF, // "MRW", "Physical memory protection address register."),
 
+    // Machine Counter/Timers
+    mcycle = 0xB00, // "MRW", "Machine cycle counter."),
+    minstret = 0xB02, // "MRW", "Machine instructions-retired counter."),
+
     // Machine State Enable Registers
     mstateen0 = 0x30C, // "MRW", "Machine State Enable 0 Register."),
     mstateen1 = 0x30D, // "MRW", "Machine State Enable 1 Register."),
@@ -70,6 +79,10 @@ pub enum SupportedCsrID {
     fflags = 0x001, // "URW", "Floating-Point Accrued Exceptions."),
     frm = 0x002, // "URW", "Floating-Point Dynamic Rounding Mode."),
     fcsr = 0x003, // "URW", "Floating-Point Control and Status Register (frm +fflags)."),
+    vstart = 0x008, // "URW", "Vector start position."),
+    vxsat = 0x009, // "URW", "Fixed-point accrued saturation flag."),
+    vxrm = 0x00A, // "URW", "Fixed-point rounding mode."),
+    vcsr = 0x00F, // "URW", "Vector control and status register (vxrm + vxsat)."),
             
     // Unprivileged Counter/Timers
     cycle = 0xC00, // "URO", "Cycle counter for RDCYCLE instruction."),
@@ -77,6 +90,9 @@ pub enum SupportedCsrID {
     instret = 0xC02, // "URO", "Instructions-retired counter for RDINSTRET instruction."),
     hpmcounter3 = 0xC03, // "URO", "Performance-monitoring counter."),
     hpmcounter4 = 0xC04, // "URO", "Performance-monitoring counter."),
+    vl = 0xC20, // "URO", "Vector length."),
+    vtype = 0xC21, // "URO", "Vector data type register."),
+    vlenb = 0xC22, // "URO", "VLEN/8 (vector register length in bytes)."),
     // ... and so on for other unprivileged CSRs
             
     // Supervisor-level CSRs
@@ -91,6 +107,10 @@ pub enum SupportedCsrID {
     scause = 0x142, // "SRW", "Supervisor trap cause."),
     stval = 0x143, // "SRW", "Supervisor bad address or instruction."),
     sip = 0x144, // "SRW", "Supervisor interrupt pending."),
+    stimecmp = 0x14D, // "SRW", "Supervisor timer compare."),
+    siselect = 0x150, // "SRW", "Supervisor indirect register select."),
+    sireg = 0x151, // "SRW", "Supervisor indirect register alias."),
+    stopei = 0x15C, // "SRW", "Supervisor top external interrupt."),
     scountovf = 0xDA0, // "SRO", "Supervisor count overflow."),
     satp = 0x180, // "SRW", "Supervisor address translation and protection."),
     scontext = 0x5A8, // "SRW", "Supervisor-mode context register."),
@@ -136,6 +156,7 @@ pub enum SupportedCsrID {
     vscause = 0x242, // "HRW", "Virtual supervisor trap cause."),
     vstval = 0x243, // "HRW", "Virtual supervisor bad address or instruction."),
     vsip = 0x244, // "HRW", "Virtual supervisor interrupt pending."),
+    vstimecmp = 0x24D, // "HRW", "Virtual supervisor timer compare."),
     vsatp = 0x280, // "HRW", "Virtual supervisor address translation and protection."),
 
     // Unsupported(u16),
@@ -182,6 +203,9 @@ const CSR_TABLE: [Option<SupportedCsrID>; 0x1000] = {
     table[0x3B1] = Some(SupportedCsrID::pmpaddr1);
     table[0x3EF] = Some(SupportedCsrID::pmpaddr63);
 
+    table[0xB00] = Some(SupportedCsrID::mcycle);
+    table[0xB02] = Some(SupportedCsrID::minstret);
+
     table[0x30C] = Some(SupportedCsrID::mstateen0);
     table[0x30D] = Some(SupportedCsrID::mstateen1);
     table[0x30E] = Some(SupportedCsrID::mstateen2);
@@ -227,9 +251,11 @@ impl CsrID {
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct CsrValue(pub uguest);
 
-impl Display for CsrID {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let (name, privilege, description) = match self.get() {
+impl CsrID {
+    /// (name, privilege, description) of the CSR, privilege is the lowest mode that can access it
+    /// followed by RW or RO, e.g. "MRW", "URO"
+    pub fn info(self) -> Option<(&'static str, &'static str, &'static str)> {
+        Some(match self.get() {
             // Machine Information Registers
             0xF11 => ("mvendorid", "MRO", "Vendor ID."),
             0xF12 => ("marchid", "MRO", "Architecture ID."),
@@ -256,6 +282,9 @@ impl Display for CsrID {
             0x344 => ("mip", "MRW", "Machine interrupt pending."),
             0x34A => ("mtinst", "MRW", "Machine trap instruction (transformed)."),
             0x34B => ("mtval2", "MRW", "Machine bad guest physical address."),
+            0x350 => ("miselect", "MRW", "Machine indirect register select."),
+            0x351 => ("mireg", "MRW", "Machine indirect register alias."),
+            0x35C => ("mtopei", "MRW", "Machine top external interrupt."),
 
             // Machine Configuration
             0x30A => ("menvcfg", "MRW", "Machine environment configuration register."),
@@ -273,6 +302,12 @@ impl Display for CsrID {
             0x3B0 => ("pmpaddr0", "MRW", "Physical memory protection address register."),
             0x3B1 => ("pmpaddr1", "MRW", "Physical memory protection address register."),
             0x3EF => ("pmpaddr63", "MRW", "Physical memory protection address register."),
+            0x3A4..=0x3AD => ("pmpcfgN", "MRW", "Physical memory protection configuration."),
+            0x3B2..=0x3EE => ("pmpaddrN", "MRW", "Physical memory protection address register."),
+
+            // Machine Counter/Timers
+            0xB00 => ("mcycle", "MRW", "Machine cycle counter."),
+            0xB02 => ("minstret", "MRW", "Machine instructions-retired counter."),
 
             // Machine State Enable Registers
             0x30C => ("mstateen0", "MRW", "Machine State Enable 0 Register."),
@@ -286,6 +321,10 @@ impl Display for CsrID {
             0x001 => ("fflags", "URW", "Floating-Point Accrued Exceptions."),
             0x002 => ("frm", "URW", "Floating-Point Dynamic Rounding Mode."),
             0x003 => ("fcsr", "URW", "Floating-Point Control and Status Register (frm +fflags)."),
+            0x008 => ("vstart", "URW", "Vector start position."),
+            0x009 => ("vxsat", "URW", "Fixed-point accrued saturation flag."),
+            0x00A => ("vxrm", "URW", "Fixed-point rounding mode."),
+            0x00F => ("vcsr", "URW", "Vector control and status register (vxrm + vxsat)."),
             
             // Unprivileged Counter/Timers
             0xC00 => ("cycle", "URO", "Cycle counter for RDCYCLE instruction."),
@@ -293,6 +332,9 @@ impl Display for CsrID {
             0xC02 => ("instret", "URO", "Instructions-retired counter for RDINSTRET instruction."),
             0xC03 => ("hpmcounter3", "URO", "Performance-monitoring counter."),
             0xC04 => ("hpmcounter4", "URO", "Performance-monitoring counter."),
+            0xC20 => ("vl", "URO", "Vector length."),
+            0xC21 => ("vtype", "URO", "Vector data type register."),
+            0xC22 => ("vlenb", "URO", "VLEN/8 (vector register length in bytes)."),
             // ... and so on for other unprivileged CSRs
             
             // Supervisor-level CSRs
@@ -307,6 +349,10 @@ impl Display for CsrID {
             0x142 => ("scause", "SRW", "Supervisor trap cause."),
             0x143 => ("stval", "SRW", "Supervisor bad address or instruction."),
             0x144 => ("sip", "SRW", "Supervisor interrupt pending."),
+            0x14D => ("stimecmp", "SRW", "Supervisor timer compare."),
+            0x150 => ("siselect", "SRW", "Supervisor indirect register select."),
+            0x151 => ("sireg", "SRW", "Supervisor indirect register alias."),
+            0x15C => ("stopei", "SRW", "Supervisor top external interrupt."),
             0xDA0 => ("scountovf", "SRO", "Supervisor count overflow."),
             0x180 => ("satp", "SRW", "Supervisor address translation and protection."),
             0x5A8 => ("scontext", "SRW", "Supervisor-mode context register."),
@@ -352,12 +398,33 @@ impl Display for CsrID {
             0x242 => ("vscause", "HRW", "Virtual supervisor trap cause."),
             0x243 => ("vstval", "HRW", "Virtual supervisor bad address or instruction."),
             0x244 => ("vsip", "HRW", "Virtual supervisor interrupt pending."),
+            0x24D => ("vstimecmp", "HRW", "Virtual supervisor timer compare."),
             0x280 => ("vsatp", "HRW", "Virtual supervisor address translation and protection."),
             
-            // Default case for unknown CSR ID
-            _ => ("unknown", "unknown", "Unknown CSR ID."),
+            _ => return None,
+        })
+    }
+    /// Lowest privilege level that can access the CSR and whether it can be written, from the privilege in `info`
+    /// Hypervisor CSRs are accessible from HS-mode, which is the supervisor level
+    pub fn access(self) -> Option<(PrivilegeLevel, bool)> {
+        let (_name, privilege, _description) = self.info()?;
+        let level = match privilege.as_bytes()[0] {
+            b'U' => PrivilegeLevel::User,
+            b'S' | b'H' => PrivilegeLevel::Supervisor,
+            b'M' => PrivilegeLevel::Machine,
+            _ => return None,
         };
+        Some((level, !privilege.ends_with("RO")))
+    }
+    /// H and VS CSRs, the supervisor level only reaches them in HS-mode
+    pub fn hypervisor(self) -> bool {
+        (self.get() >> 8) & 0b11 == 0b10
+    }
+}
 
+impl Display for CsrID {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (name, privilege, description) = self.info().unwrap_or(("unknown", "unknown", "Unknown CSR ID."));
         write!(f, "{} ({}) - {}", name, privilege, description)
     }
 }
\ No newline at end of file
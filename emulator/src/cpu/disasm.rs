@@ -0,0 +1,203 @@
+// Disassembler, instructions are written like objdump does but without commas: `addi t3 t1 2`, `ld a0 8(sp)`, `j 0x80000020`
+// The assembler (asm.rs) reads the same syntax back, both take the operands of an instruction from `operands`
+use super::csr::CsrID;
+use super::instructions::Instruction32;
+use super::raw_instructions::{sext, try_find_instruction32_desc, Instruction32Format};
+use super::reg::REGS;
+use super::vector::{self, VType};
+use crate::{iguest, uguest};
+
+/// How the operands of an instruction are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+    /// rd rs1 rs2
+    Register,
+    /// rd rs1
+    Unary,
+    /// rd rs1 imm
+    Immediate,
+    /// rd rs1 shamt
+    Shift,
+    /// rd imm(rs1), loads and jalr
+    Load,
+    /// rs2 imm(rs1)
+    Store,
+    /// rs1 rs2 target
+    Branch,
+    /// rd target
+    Jump,
+    /// rd imm, the upper 20 bits
+    Upper,
+    /// rd csr rs1
+    Csr,
+    /// rd csr uimm
+    CsrImmediate,
+    /// rd (rs1)
+    LoadReserved,
+    /// rd rs2 (rs1)
+    Atomic,
+    /// rd (rs1), hlv and hlvx
+    HypervisorLoad,
+    /// rs2 (rs1), hsv
+    HypervisorStore,
+    /// pred succ, left out when both are iorw
+    Fence,
+    /// Told apart by their immediate, see `SYSTEM`
+    System,
+    /// Decoded further, see `vector::forms`
+    Vector,
+    None,
+}
+
+/// Instructions of the SYSTEM opcode without operands and their immediate, the fences of `VMA_FENCES` have their own operands
+pub const SYSTEM: [(&str, u32); 5] = [("ecall", 0), ("ebreak", 1), ("sret", 0x102), ("wfi", 0x105), ("mret", 0x302)];
+/// Address translation fences and their fun7, they take an address and an ASID/VMID: `sfence.vma rs1 rs2`
+pub const VMA_FENCES: [(&str, u32); 3] = [("sfence.vma", 0b0001001), ("hfence.vvma", 0b0010001), ("hfence.gvma", 0b0110001)];
+/// Suffixes of atomics, by the value of their aq and rl bits
+pub const ORDERINGS: [&str; 4] = ["", ".rl", ".aq", ".aqrl"];
+/// Instructions with a single source, the rs2 or immediate field is part of the encoding
+const UNARY: [&str; 11] = ["clz", "clzw", "ctz", "ctzw", "cpop", "cpopw", "sextb", "sexth", "zexth", "orcb", "rev8"];
+
+/// Operands of an instruction of `INSTRUCTIONS32`, from its name, format and mask
+pub fn operands(name: &str, format: Instruction32Format, mask: Instruction32) -> Operands {
+    match (format, mask.opcode()) {
+        (_, 0b1010111 | 0b0000111 | 0b0100111) => Operands::Vector,
+        _ if UNARY.contains(&name) => Operands::Unary,
+        (_, 0b0000011 | 0b1100111) => Operands::Load,
+        (_, 0b0101111) if name.starts_with("lr") => Operands::LoadReserved,
+        (_, 0b0101111) => Operands::Atomic,
+        (_, 0b0001111) if name == "fence" => Operands::Fence,
+        (_, 0b0001111) => Operands::None,
+        (_, 0b1110011) if name == "ecall" => Operands::System,
+        (_, 0b1110011) if name.starts_with("hlv") => Operands::HypervisorLoad,
+        (_, 0b1110011) if name.starts_with("hsv") => Operands::HypervisorStore,
+        (_, 0b1110011) if name.ends_with('i') => Operands::CsrImmediate,
+        (_, 0b1110011) => Operands::Csr,
+        (Instruction32Format::I, 0b0010011 | 0b0011011) if mask.fun3() & 0b11 == 0b01 => Operands::Shift,
+        (Instruction32Format::I, _) => Operands::Immediate,
+        (Instruction32Format::R, _) => Operands::Register,
+        (Instruction32Format::S, _) => Operands::Store,
+        (Instruction32Format::B, _) => Operands::Branch,
+        (Instruction32Format::U, _) => Operands::Upper,
+        (Instruction32Format::J, _) => Operands::Jump,
+    }
+}
+/// Bits of the shift amount of a shift (or Zbs/rotate) by immediate
+pub fn shamt_bits(name: &str, opcode: u8) -> u32 {
+    if opcode == 0b0011011 && name != "slliuw" {5} else {6}
+}
+
+/// Assembly name of an instruction of `INSTRUCTIONS32`, their names can't have dots
+pub fn mnemonic(name: &str) -> String {
+    match name {
+        "fencei" => "fence.i".into(),
+        "sextb" => "sext.b".into(),
+        "sexth" => "sext.h".into(),
+        "zexth" => "zext.h".into(),
+        "orcb" => "orc.b".into(),
+        "adduw" | "sh1adduw" | "sh2adduw" | "sh3adduw" | "slliuw" => format!("{}.uw", &name[..name.len()-2]),
+        // lr.w, sc.d, amoadd.w...
+        // hlv.b, hlvx.hu, hsv.d...
+        _ if name.starts_with("hlv") || name.starts_with("hsv") => {
+            let (op, size) = name.split_at(if name.starts_with("hlvx") {4} else {3});
+            format!("{op}.{size}")
+        },
+        _ if name.starts_with("amo") || name.starts_with("lr") || name.starts_with("sc") => {
+            let (op, size) = name.split_at(name.len()-1);
+            format!("{op}.{size}")
+        },
+        _ => name.into(),
+    }
+}
+
+pub fn reg(reg: u8) -> &'static str {
+    REGS[reg as usize]
+}
+/// Name of a CSR, or its number when it doesn't have its own
+pub fn csr(csr: u16) -> String {
+    match CsrID::new(csr).info() {
+        Some((name, _, _)) if !name.contains(char::is_uppercase) => name.into(),
+        _ => format!("{csr:#x}"),
+    }
+}
+/// Predecessor or successor set of a fence
+pub fn fence_set(set: u32) -> String {
+    let set: String = "iorw".chars().enumerate().filter(|(i, _)| set & (0b1000 >> i) != 0).map(|(_, c)| c).collect();
+    if set.is_empty() {"0".into()} else {set}
+}
+
+/// Branch and jump target, `.+offset` when the address of the instruction isn't known
+fn target(pc: Option<uguest>, offset: iguest) -> String {
+    match pc {
+        Some(pc) => format!("{:#x}", pc.wrapping_add(offset as uguest)),
+        None if offset < 0 => format!(".-{:#x}", offset.unsigned_abs()),
+        None => format!(".+{offset:#x}"),
+    }
+}
+
+/// Text of an instruction at `pc`, `.word` for encodings the emulator doesn't implement
+pub fn disassemble(instruction: Instruction32, pc: Option<uguest>) -> String {
+    let Ok((name, format, mask, _)) = try_find_instruction32_desc(instruction) else {return format!(".word {:#010x}", instruction.0)};
+    let raw = instruction.0;
+    let (rd, rs1, rs2) = (reg(instruction._raw_rd()), reg(instruction._raw_rs1()), reg(instruction._raw_rs2()));
+    let imm = || sext(instruction.parse_i().0 as _, 12) as iguest;
+    let mnemonic = mnemonic(name);
+    match operands(name, format, Instruction32(mask.0)) {
+        Operands::Register => format!("{mnemonic} {rd} {rs1} {rs2}"),
+        Operands::Unary => format!("{mnemonic} {rd} {rs1}"),
+        Operands::Immediate => format!("{mnemonic} {rd} {rs1} {}", imm()),
+        Operands::Shift => {
+            let shamt = (raw >> 20) & ((1 << shamt_bits(name, instruction.opcode())) - 1);
+            format!("{mnemonic} {rd} {rs1} {shamt}")
+        },
+        Operands::Load => format!("{mnemonic} {rd} {}({rs1})", imm()),
+        Operands::Store => format!("{mnemonic} {rs2} {}({rs1})", sext(instruction.parse_s().0 as _, 12) as iguest),
+        Operands::Branch => format!("{mnemonic} {rs1} {rs2} {}", target(pc, sext(instruction.parse_b().0 as _, 13) as iguest)),
+        Operands::Jump => {
+            let offset = sext(instruction.parse_j().0 as _, 21) as iguest;
+            if rd == "zero" {format!("j {}", target(pc, offset))} else {format!("{mnemonic} {rd} {}", target(pc, offset))}
+        },
+        Operands::Upper => format!("{mnemonic} {rd} {:#x}", raw >> 12),
+        Operands::Csr => format!("{mnemonic} {rd} {} {rs1}", csr((raw >> 20) as u16)),
+        Operands::CsrImmediate => format!("{mnemonic} {rd} {} {}", csr((raw >> 20) as u16), instruction._raw_rs1()),
+        Operands::LoadReserved => format!("{mnemonic}{} {rd} ({rs1})", ORDERINGS[(instruction.fun7() & 0b11) as usize]),
+        Operands::Atomic => format!("{mnemonic}{} {rd} {rs2} ({rs1})", ORDERINGS[(instruction.fun7() & 0b11) as usize]),
+        Operands::Fence => match ((raw >> 24) & 0xF, (raw >> 20) & 0xF) {
+            (0b1111, 0b1111) => mnemonic,
+            (pred, succ) => format!("{mnemonic} {} {}", fence_set(pred), fence_set(succ)),
+        },
+        Operands::HypervisorLoad => format!("{mnemonic} {rd} ({rs1})"),
+        Operands::HypervisorStore => format!("{mnemonic} {rs2} ({rs1})"),
+        Operands::System => match (VMA_FENCES.iter().find(|(_, fun7)| *fun7 == instruction.fun7()), SYSTEM.iter().find(|(_, imm)| *imm == raw >> 20)) {
+            (Some((name, _)), _) => format!("{name} {rs1} {rs2}"),
+            (None, Some((name, _))) => name.to_string(),
+            (None, None) => format!(".word {raw:#010x}"),
+        },
+        Operands::None => mnemonic,
+        Operands::Vector => vector_instruction(raw).unwrap_or_else(|| format!(".word {raw:#010x}")),
+    }
+}
+
+/// `vadd.vv v1 v2 v3 v0.t`, `vle32.v v8 (a0)`, `vsetvli t0 a2 e32 m1 ta ma`
+fn vector_instruction(raw: u32) -> Option<String> {
+    let form = vector::forms().iter().find(|form| form.matches(raw))?;
+    let mut text = form.mnemonic.clone();
+    for operand in form.operands.split(' ') {
+        let value = vector::field(operand).map_or(0, |(shift, bits)| (raw >> shift) & ((1 << bits) - 1));
+        let vtype = |vtype: u32| VType::decode(vtype as uguest).map_or(vtype.to_string(), VType::text);
+        let operand = match operand {
+            "vd" | "vs1" | "vs2" | "vs3" => format!("v{value}"),
+            "rd" | "rs1" | "rs2" => reg(value as u8).into(),
+            "(rs1)" => format!("({})", reg(value as u8)),
+            "simm" => (sext(value as uguest, 5) as iguest).to_string(),
+            "uimm" => value.to_string(),
+            "vtypei" | "vtypei10" => vtype(value),
+            "vm" if value == 0 => "v0.t".into(),
+            "vm" => continue,
+            _ => operand.into(),
+        };
+        text.push(' ');
+        text.push_str(&operand);
+    }
+    Some(text)
+}
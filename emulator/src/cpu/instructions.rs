@@ -13,9 +13,9 @@ impl Instruction {
     /// Returns a Instruction32 or two compressed 16's instructions
     pub fn new(instruction: u32) -> Result<(Self, Option<Instruction16>)> {
         if instruction & 0b11 == 0b11 {
-            return Ok((Self::Base(Instruction32::new(instruction)?), None))
+            Ok((Self::Base(Instruction32::new(instruction)?), None))
         } else {
-            return Ok((Self::Compressed(Instruction16::new((instruction & 0xFFFF) as _)), Some(Instruction16::new((instruction>>16) as _))))
+            Ok((Self::Compressed(Instruction16::new((instruction & 0xFFFF) as _)), Some(Instruction16::new((instruction>>16) as _))))
         }
     }
 }
@@ -39,15 +39,15 @@ impl Instruction32 {
         ((self.0.get_bits(7..=11)|(self.0.get_bits(25..=31)<<5)) as _,self.rs1() as _,self.rs2() as _)
     }
     pub fn parse_b(self) -> (Imm, Rs1, Rs2) {
-        (((self.0.get_bits(8..=11)<<1) | (self.0.get_bits(25..=30) << 4) | ((self.0 & (1<<7))<<11) | ((self.0 & (1<<31))<<12)) as _,
+        (((self.0.get_bits(8..=11)<<1) | (self.0.get_bits(25..=30) << 5) | (self.0.get_bits(7..=7)<<11) | (self.0.get_bits(31..=31)<<12)) as _,
         self.rs1() as _,self.rs2() as _)
     }
     pub fn parse_u(self) -> (UImm, Rd) { // 19 bits Imm
         ((self.0 & 0xFFFFF000) as _,
         self.rd() as _,)
     }
-    pub fn parse_j(self) -> (Imm, Rd) {
-        (((self.0.get_bits(21..=30)<<1) | (self.0.get_bits(20..=20)<<11) | (self.0 & 0x7F000) | (self.0.get_bits(31..=31)<<20)) as _,
+    pub fn parse_j(self) -> (UImm, Rd) { // 21 bits Imm
+        ((self.0.get_bits(21..=30)<<1) | (self.0.get_bits(20..=20)<<11) | (self.0 & 0xFF000) | (self.0.get_bits(31..=31)<<20),
         self.rd() as _,)
     }
 
@@ -145,11 +145,10 @@ impl std::fmt::Debug for Instruction32 {
         fmt.write_str(&format!("{:b} {:b} {:b} {:b}", self.opcode(), self.fun3(), self.fun7(), self.0))
     }
 }
+/// Branch targets are relative (`.+0x10`), see `disasm::disassemble` to have them absolute
 impl std::fmt::Display for Instruction32 {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        let (s1, has_s2) = self.s1();
-        let s2 = if has_s2 {self.s2()} else {Destination::Immediate(0)};
-        fmt.write_str(&format!("{} {} {} {}", self._opcode_name(), self.destination(), s1, s2))
+        fmt.write_str(&super::disasm::disassemble(*self, None))
     }
 }
 
@@ -158,9 +157,7 @@ impl std::fmt::Display for Instruction32 {
 pub struct Instruction16(pub u16);
 impl Instruction16 {
     pub fn new(instruction: u16) -> Self {
-        let _s = Self(instruction);
-        
-        _s
+        Self(instruction)
     }
     pub fn opcode(self) -> u8 {
         (self.0 & 0b11) as _
@@ -0,0 +1,151 @@
+// ISA strings (e.g. rv64imac_zicsr_zifencei) of machine descriptions, and the misa value they stand for
+// Extensions left out of misa are really disabled: their instructions raise illegal instruction exceptions
+// V and H have a misa bit but aren't in the default one, they have to be asked for
+// The bit-manipulation ones (Zba, Zbb, Zbc, Zbs), Smaia, Ssaia and Sstc have no misa bit of their own, harts keep them in `CPU::extensions`
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+
+use super::csr::file::MISA_VALUE;
+use super::instructions::Instruction32;
+use super::raw_instructions::find_instruction32_desc;
+use crate::uguest;
+
+/// MXL = 2, 64 bits
+const MXL_64: uguest = 2 << 62;
+/// Order single letter extensions have to be given in
+const CANONICAL_ORDER: &str = "imafdqlcbkjtpvh";
+/// Multi-letter extensions that are always implemented
+pub const Z_EXTENSIONS: [&str; 3] = ["zicntr", "zicsr", "zifencei"];
+
+/// Multi-letter extensions that can be left out, in canonical order
+pub const OPTIONAL_EXTENSIONS: [&str; 7] = ["zba", "zbb", "zbc", "zbs", "smaia", "ssaia", "sstc"];
+/// Instructions of each optional extension, by name in `INSTRUCTIONS32`
+const OPTIONAL_INSTRUCTIONS: [&[&str]; 7] = [
+    &["adduw", "sh1add", "sh2add", "sh3add", "sh1adduw", "sh2adduw", "sh3adduw", "slliuw"],
+    &["andn", "orn", "xnor", "clz", "clzw", "ctz", "ctzw", "cpop", "cpopw", "max", "maxu", "min", "minu",
+        "sextb", "sexth", "zexth", "rol", "rolw", "ror", "rori", "roriw", "rorw", "orcb", "rev8"],
+    &["clmul", "clmulh", "clmulr"],
+    &["bclr", "bclri", "bext", "bexti", "binv", "binvi", "bset", "bseti"],
+    // Smaia, Ssaia and Sstc only add CSRs
+    &[],
+    &[],
+    &[],
+];
+
+/// Optional extensions of a hart, a bit per entry of `OPTIONAL_EXTENSIONS`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions(u8);
+impl Extensions {
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self((1 << OPTIONAL_EXTENSIONS.len()) - 1);
+    /// Zba, Zbb, Zbc and Zbs
+    pub const BITMANIP: Self = Self(0b1111);
+    /// B is Zba, Zbb and Zbs
+    pub const B: Self = Self(0b1011);
+    /// AIA CSRs of M-mode and S-mode, for IMSICs
+    pub const SMAIA: Self = Self(0b1_0000);
+    pub const SSAIA: Self = Self(0b10_0000);
+    pub const SSTC: Self = Self(0b100_0000);
+    pub fn named(name: &str) -> Option<Self> {
+        OPTIONAL_EXTENSIONS.iter().position(|extension| *extension == name).map(|i| Self(1 << i))
+    }
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        OPTIONAL_EXTENSIONS.into_iter().enumerate().filter(move |(i, _)| self.0 & 1 << i != 0).map(|(_, name)| name)
+    }
+}
+
+/// What an ISA string enables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Isa {
+    pub misa: uguest,
+    pub extensions: Extensions,
+}
+/// Only what misa tells
+impl From<uguest> for Isa {
+    fn from(misa: uguest) -> Self {
+        Self { misa, extensions: Extensions::NONE }
+    }
+}
+
+/// Bit of a single letter extension in misa
+pub const fn bit(extension: char) -> uguest {
+    1 << (extension as u8 - b'a')
+}
+/// Single letter extensions implemented but left out of `MISA_VALUE`
+const OPTIONAL_LETTERS: uguest = bit('v') | bit('h');
+/// S and U are privilege modes, they are always there
+const MODES: uguest = bit('s') | bit('u');
+
+/// misa of an ISA string, see `parse_isa`
+pub fn parse(isa: &str) -> Result<uguest> {
+    Ok(parse_isa(isa)?.misa)
+}
+/// misa and optional extensions of an ISA string, fails on extensions the emulator doesn't implement
+/// Single letter extensions come first in canonical order, multi-letter ones follow separated by underscores, also in canonical order
+pub fn parse_isa(isa: &str) -> Result<Isa> {
+    let lower = isa.to_ascii_lowercase();
+    let Some(extensions) = lower.strip_prefix("rv64") else {bail!("{isa:?} isn't an RV64 ISA string")};
+    let mut parts = extensions.split('_');
+    let letters = parts.next().unwrap_or_default();
+    let letters = match letters.strip_prefix('g') {
+        // imafd_zicsr_zifencei
+        Some(rest) => format!("imafd{rest}"),
+        None => letters.to_string(),
+    };
+    if !letters.starts_with('i') {bail!("{isa:?} doesn't start with the base integer ISA")}
+    let mut misa = MXL_64 | MODES;
+    let mut optional = Extensions::NONE;
+    let mut last = 0;
+    for extension in letters.chars() {
+        let Some(position) = CANONICAL_ORDER.find(extension) else {bail!("Unknown extension {extension:?} in {isa:?}")};
+        if position < last || misa & bit(extension) != 0 {
+            bail!("Extension {extension:?} of {isa:?} is repeated or isn't in canonical order ({CANONICAL_ORDER})")
+        }
+        if extension == 'b' {
+            optional = optional.union(Extensions::B);
+        } else if (MISA_VALUE | OPTIONAL_LETTERS) & bit(extension) == 0 {
+            bail!("Extension {extension:?} of {isa:?} isn't implemented")
+        }
+        misa |= bit(extension);
+        last = position;
+    }
+    // Zi* come before Zb*, like i before b, and S* after Z*
+    let known: Vec<&str> = Z_EXTENSIONS.iter().chain(OPTIONAL_EXTENSIONS.iter()).copied().collect();
+    let mut last = None;
+    for extension in parts {
+        let Some(position) = known.iter().position(|known| *known == extension) else {bail!("Extension {extension:?} of {isa:?} isn't implemented")};
+        if last.is_some_and(|last| position <= last) {
+            bail!("Extension {extension:?} of {isa:?} is repeated or isn't in canonical order ({})", known.join("_"))
+        }
+        optional = optional.union(Extensions::named(extension).unwrap_or_default());
+        last = Some(position);
+    }
+    if optional.contains(Extensions::B) {misa |= bit('b')}
+    Ok(Isa { misa, extensions: optional })
+}
+
+/// Whether the extension of `instruction` is enabled, for the ones that can be left out (compressed ones are checked when expanded)
+pub fn enabled(misa: uguest, extensions: Extensions, instruction: Instruction32) -> bool {
+    match instruction.opcode() {
+        // OP and OP-32 with funct7 = 1
+        0b0110011 | 0b0111011 if instruction.fun7() == 1 => misa & bit('m') != 0,
+        0b0101111 => misa & bit('a') != 0,
+        // OP-V, and LOAD-FP/STORE-FP which only hold vector loads and stores
+        0b1010111 | 0b0000111 | 0b0100111 => misa & bit('v') != 0,
+        // Hypervisor loads and stores, the fences are checked when executed
+        0b1110011 if instruction.fun3() == 0b100 => misa & bit('h') != 0,
+        // OP-IMM, OP-IMM-32, OP and OP-32 hold the bit-manipulation instructions
+        0b0010011 | 0b0011011 | 0b0110011 | 0b0111011 if !extensions.contains(Extensions::BITMANIP) => {
+            let name = find_instruction32_desc(instruction).0;
+            OPTIONAL_INSTRUCTIONS.iter().enumerate()
+                .all(|(i, names)| !names.contains(&name) || extensions.contains(Extensions(1 << i)))
+        },
+        _ => true,
+    }
+}
@@ -0,0 +1,249 @@
+// 10.3. Sv39 page-based virtual memory, for satp, and the two stages of guests (21.5. Two-Stage Address Translation):
+// guest virtual addresses go through vsatp (Sv39), then the guest physical addresses, page tables included, through hgatp (Sv39x4)
+// A and D are updated by the walker, TLBs aren't modelled so the fences have nothing to flush
+use bit_field::BitField;
+
+use super::csr::file::{HGATP, HSTATUS, MSTATUS, SATP, VSATP, VSSTATUS};
+use super::trap::Exception;
+use super::{PrivilegeLevel, CPU};
+use crate::mem::{AccessFault, AccessType, PAGE_SIZE};
+use crate::vm::VM;
+use crate::{iguest, uguest};
+
+/// MODE of satp and vsatp for Sv39, and of hgatp for Sv39x4
+pub const SV39: uguest = 8;
+/// PPN field of satp, vsatp and hgatp
+pub const ATP_PPN: uguest = (1 << 44) - 1;
+const LEVELS: usize = 3;
+const PTE_SIZE: uguest = 8;
+// Page table entry bits
+const V: usize = 0;
+const R: usize = 1;
+const W: usize = 2;
+const X: usize = 3;
+const U: usize = 4;
+const A: usize = 6;
+const D: usize = 7;
+
+/// Exception raised by a memory access and its tval, the virtual address the program used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub cause: Exception,
+    pub addr: uguest,
+}
+impl Fault {
+    pub fn exception(self) -> Exception {
+        self.cause
+    }
+}
+impl From<AccessFault> for Fault {
+    fn from(fault: AccessFault) -> Self {
+        Self { cause: fault.exception(), addr: fault.addr }
+    }
+}
+
+/// Privilege and virtualization mode memory is accessed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub privilege: PrivilegeLevel,
+    /// Through vsatp and hgatp instead of satp
+    pub virt: bool,
+    /// hlvx, reads need execute permission instead of read permission
+    pub hlvx: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// satp
+    Single,
+    /// vsatp, its page tables are at guest physical addresses
+    VirtualSupervisor,
+    /// hgatp, guest physical to physical
+    Guest,
+}
+
+fn page_fault(access: AccessType) -> Exception {
+    match access {
+        AccessType::Read => Exception::LoadPageFault,
+        AccessType::Write => Exception::StorePageFault,
+        AccessType::Execute => Exception::InstructionPageFault,
+    }
+}
+fn guest_page_fault(access: AccessType) -> Exception {
+    match access {
+        AccessType::Read => Exception::LoadGuestPageFault,
+        AccessType::Write => Exception::StoreGuestPageFault,
+        AccessType::Execute => Exception::InstructionGuestPageFault,
+    }
+}
+
+impl CPU {
+    /// Mode instructions are fetched in
+    pub fn fetch_mode(&self) -> Mode {
+        Mode { privilege: self.privilege_level, virt: self.virt, hlvx: false }
+    }
+    /// Mode of loads and stores, M-mode uses MPP (and MPV) when mstatus.MPRV is set
+    pub fn data_mode(&self) -> Mode {
+        let mstatus = self.csrs[MSTATUS as usize].0;
+        if self.privilege_level == PrivilegeLevel::Machine && mstatus.get_bit(17) {
+            let privilege = PrivilegeLevel::from_bits(mstatus.get_bits(11..=12));
+            Mode { privilege, virt: privilege != PrivilegeLevel::Machine && mstatus.get_bit(39), hlvx: false }
+        } else {
+            self.fetch_mode()
+        }
+    }
+    /// Mode of the hypervisor loads and stores (hlv, hlvx, hsv): V=1 at the privilege in hstatus.SPVP
+    pub fn hypervisor_mode(&self, hlvx: bool) -> Mode {
+        let spvp = self.csrs[HSTATUS as usize].0.get_bit(8);
+        let privilege = if spvp {PrivilegeLevel::Supervisor} else {PrivilegeLevel::User};
+        Mode { privilege, virt: true, hlvx }
+    }
+    /// Whether addresses of `mode` go through a page table, accesses crossing a page are then split
+    fn translated(&self, mode: Mode) -> bool {
+        let enabled = |atp: u16| self.csrs[atp as usize].0 >> 60 == SV39;
+        mode.privilege != PrivilegeLevel::Machine && if mode.virt {enabled(VSATP) || enabled(HGATP)} else {enabled(SATP)}
+    }
+}
+
+impl VM {
+    /// Physical address of `addr` for an access of `mode`, or the page fault, guest-page fault or access fault to raise
+    pub fn translate(&mut self, addr: uguest, access: AccessType, mode: Mode) -> Result<uguest, Fault> {
+        if mode.privilege == PrivilegeLevel::Machine {return Ok(addr)}
+        if !mode.virt {
+            let satp = self.cpu.csrs[SATP as usize].0;
+            return self.walk(Stage::Single, satp, addr, addr, access, mode)
+        }
+        let (vsatp, hgatp) = (self.cpu.csrs[VSATP as usize].0, self.cpu.csrs[HGATP as usize].0);
+        let gpa = self.walk(Stage::VirtualSupervisor, vsatp, addr, addr, access, mode)?;
+        self.walk(Stage::Guest, hgatp, gpa, addr, access, mode)
+    }
+
+    /// Translates `addr` with the page table `atp` (satp, vsatp or hgatp) points to, `va` is the address faults report
+    fn walk(&mut self, stage: Stage, atp: uguest, addr: uguest, va: uguest, access: AccessType, mode: Mode) -> Result<uguest, Fault> {
+        if atp >> 60 != SV39 {return Ok(addr)} // Bare
+        let fault = |cpu: &mut CPU| match stage {
+            Stage::Guest => {
+                cpu.guest_fault = Some(addr >> 2);
+                Fault { cause: guest_page_fault(access), addr: va }
+            },
+            _ => Fault { cause: page_fault(access), addr: va },
+        };
+        // Sv39 addresses are sign extended from bit 38, Sv39x4 ones are 41 bits
+        let valid = match stage {
+            Stage::Guest => addr >> 41 == 0,
+            _ => ((addr << 25) as iguest >> 25) as uguest == addr,
+        };
+        if !valid {return Err(fault(&mut self.cpu))}
+        let mstatus = self.cpu.csrs[MSTATUS as usize].0;
+        let vsstatus = self.cpu.csrs[VSSTATUS as usize].0;
+        let (sum, mxr) = match stage {
+            Stage::Single => (mstatus.get_bit(18), mstatus.get_bit(19)),
+            Stage::VirtualSupervisor => (vsstatus.get_bit(18), mstatus.get_bit(19) || vsstatus.get_bit(19)),
+            Stage::Guest => (false, mstatus.get_bit(19)),
+        };
+        let mut table = (atp & ATP_PPN) * PAGE_SIZE;
+        for level in (0..LEVELS).rev() {
+            let shift = 12 + 9*level;
+            // The root of Sv39x4 is 4 pages, its index has 2 more bits
+            let index_bits = if stage == Stage::Guest && level == LEVELS-1 {11} else {9};
+            let mut pte_addr = table + (addr >> shift & ((1 << index_bits) - 1)) * PTE_SIZE;
+            if stage == Stage::VirtualSupervisor {
+                // Implicit reads, their guest-page faults are reported as the original access
+                let hgatp = self.cpu.csrs[HGATP as usize].0;
+                pte_addr = self.walk(Stage::Guest, hgatp, pte_addr, va, access, Mode { hlvx: false, ..mode })?;
+            }
+            let access_fault = Fault { cause: AccessFault { addr: va, len: PTE_SIZE, access }.exception(), addr: va };
+            self.cpu.pmp_check_as(pte_addr, PTE_SIZE, AccessType::Read, PrivilegeLevel::Supervisor).map_err(|_| access_fault)?;
+            let mut pte: uguest = self.mem.get(pte_addr).map_err(|_| access_fault)?;
+            // Reserved bits, Svnapot and Svpbmt aren't implemented
+            if !pte.get_bit(V) || (!pte.get_bit(R) && pte.get_bit(W)) || pte >> 54 != 0 {
+                return Err(fault(&mut self.cpu))
+            }
+            let ppn = pte.get_bits(10..54);
+            if !pte.get_bit(R) && !pte.get_bit(X) {
+                // Pointer to the next level, which has to have A, D and U clear
+                if level == 0 || pte.get_bit(A) || pte.get_bit(D) || pte.get_bit(U) {return Err(fault(&mut self.cpu))}
+                table = ppn * PAGE_SIZE;
+                continue
+            }
+            let permitted = match access {
+                AccessType::Read if mode.hlvx => pte.get_bit(X),
+                AccessType::Read => pte.get_bit(R) || (mxr && pte.get_bit(X)),
+                AccessType::Write => pte.get_bit(W),
+                AccessType::Execute => pte.get_bit(X),
+            };
+            // Guest physical pages are all user pages, S-mode reaches user pages with SUM and never executes them
+            let privileged = match stage {
+                Stage::Guest => pte.get_bit(U),
+                _ if mode.privilege == PrivilegeLevel::User => pte.get_bit(U),
+                _ => !pte.get_bit(U) || (sum && access != AccessType::Execute),
+            };
+            // Superpages are aligned to their size
+            let misaligned = ppn & ((1 << (9*level)) - 1) != 0;
+            if !permitted || !privileged || misaligned {return Err(fault(&mut self.cpu))}
+            if !pte.get_bit(A) || (access == AccessType::Write && !pte.get_bit(D)) {
+                pte.set_bit(A, true);
+                if access == AccessType::Write {pte.set_bit(D, true);}
+                self.cpu.pmp_check_as(pte_addr, PTE_SIZE, AccessType::Write, PrivilegeLevel::Supervisor).map_err(|_| access_fault)?;
+                self.mem.set(pte_addr, pte).map_err(|_| access_fault)?;
+            }
+            let offset = (1 << shift) - 1;
+            return Ok((ppn * PAGE_SIZE) & !offset | addr & offset)
+        }
+        unreachable!("The last level is always a leaf or a fault")
+    }
+
+    /// Accesses of `len` bytes that can't be translated in one go, each byte is then accessed on its own
+    fn crosses_page(&self, addr: uguest, len: uguest, mode: Mode) -> bool {
+        addr % PAGE_SIZE + len > PAGE_SIZE && self.cpu.translated(mode)
+    }
+    /// Load of `mode`, translated then checked against PMP before reaching the bus
+    pub fn load_as<T: Copy>(&mut self, addr: uguest, access: AccessType, mode: Mode) -> Result<T, Fault> {
+        let len = core::mem::size_of::<T>();
+        let result = if self.crosses_page(addr, len as _, mode) {
+            let mut val = core::mem::MaybeUninit::<T>::zeroed();
+            // SAFETY: T is Copy and the slice covers exactly the value
+            let bytes = unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, len) };
+            (0..len).try_for_each(|i| {
+                bytes[i] = self.load_as(addr.wrapping_add(i as _), access, mode)?;
+                Ok(())
+            // SAFETY: every byte was written
+            }).map(|()| unsafe { val.assume_init() })
+        } else {
+            self.translate(addr, access, mode).and_then(|physical| {
+                self.cpu.pmp_check_as(physical, len as _, access, mode.privilege).map_err(|_| self.access_fault(addr, access))?;
+                let val = match access {
+                    AccessType::Execute => self.mem.fetch(physical),
+                    _ => self.mem.get(physical),
+                };
+                val.map_err(|_| self.access_fault(addr, access))
+            })
+        };
+        self.guest_access(result, mode)
+    }
+    /// Store of `mode`, translated then checked against PMP before reaching the bus
+    pub fn store_as<T: Copy>(&mut self, addr: uguest, val: T, mode: Mode) -> Result<(), Fault> {
+        let len = core::mem::size_of::<T>();
+        let result = if self.crosses_page(addr, len as _, mode) {
+            // SAFETY: T is Copy and the slice covers exactly the value
+            let bytes = unsafe { core::slice::from_raw_parts(&val as *const T as *const u8, len) }.to_vec();
+            bytes.into_iter().enumerate().try_for_each(|(i, byte)| self.store_as(addr.wrapping_add(i as _), byte, mode))
+        } else {
+            self.translate(addr, AccessType::Write, mode).and_then(|physical| {
+                self.cpu.pmp_check_as(physical, len as _, AccessType::Write, mode.privilege).map_err(|_| self.access_fault(addr, AccessType::Write))?;
+                self.mem.set(physical, val).map_err(|_| self.access_fault(addr, AccessType::Write))
+            })
+        };
+        self.guest_access(result, mode)
+    }
+    fn access_fault(&self, addr: uguest, access: AccessType) -> Fault {
+        AccessFault { addr, len: 0, access }.into()
+    }
+    /// Faults of guest accesses report a guest virtual address in tval (hstatus.GVA)
+    fn guest_access<T>(&mut self, result: Result<T, Fault>, mode: Mode) -> Result<T, Fault> {
+        if result.is_err() && mode.virt {
+            self.cpu.guest_fault.get_or_insert(0);
+        }
+        result
+    }
+}
@@ -6,15 +6,51 @@ use mem::MemoryMap;
 use crate::*;
 
 pub mod reg;
+pub mod compressed;
 pub mod csr;
+pub mod disasm;
 pub mod instructions;
+pub mod isa;
+pub mod mmu;
+pub mod pmp;
 pub mod raw_instructions;
+pub mod trap;
+pub mod vector;
 
+#[derive(Clone)]
 pub struct CPU {
     pub regs: [uguest; 32],
     pub csrs: [CsrValue; 4096],
     pub privilege_level: PrivilegeLevel,
+    /// Virtualization mode (H extension), S and U are then VS and VU
+    pub virt: bool,
     pub pc: uguest,
+    /// Where execution continues after the current instruction, jumps and traps write to it
+    pub next_pc: uguest,
+    /// Implemented PMP entries (0, 16 or 64)
+    pub pmp_entries: usize,
+    pub hartid: uguest,
+    pub cycle: u64,
+    pub instret: u64,
+    /// Set when the current instruction raised an exception instead of retiring
+    pub trapped: bool,
+    /// Address reserved by the last lr
+    pub reservation: Option<uguest>,
+    /// Enabled extensions misa has no bit for
+    pub extensions: isa::Extensions,
+    /// Vector registers, used when misa has V
+    pub vector: vector::Vector,
+    /// Set by a faulting guest access for the trap it raises: tval is then a guest virtual address (GVA),
+    /// and htval/mtval2 get the guest physical address of guest-page faults shifted by 2 (0 for other faults)
+    pub guest_fault: Option<uguest>,
+    /// IMSIC interrupt files of M-mode and S-mode, see `aia::Level`
+    pub interrupt_files: [crate::aia::InterruptFile; 2],
+    /// MEIP and SEIP the PLIC or the APLIC domains in direct mode drive, set by `VM::route_plic_interrupts` and `VM::route_interrupts`
+    pub external_interrupts: uguest,
+    /// mtime, from the VM's clock (see `VM::update_timers`)
+    pub time: uguest,
+    /// Stopped in WFI until an interrupt enabled in mie is pending
+    pub waiting: bool,
 }
 impl CPU {
     pub fn reg(&mut self, reg: reg::Reg) -> &mut uguest {
@@ -23,6 +59,9 @@ impl CPU {
     pub fn csr(&mut self, csr: CsrID) -> &mut CsrValue {
         &mut self.csrs[csr.get() as usize]
     }
+    pub fn isa(&self) -> isa::Isa {
+        isa::Isa { misa: self.csrs[csr::file::MISA as usize].0, extensions: self.extensions }
+    }
 }
 impl Debug for CPU {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -35,12 +74,29 @@ impl Debug for CPU {
 }
 impl Default for CPU {
     fn default() -> Self {
-        Self { regs: Default::default(), pc: mem::MemMap::DRAM.base(), csrs: [CsrValue(0); 4096], privilege_level: PrivilegeLevel::Machine }
+        let mut cpu = Self { regs: Default::default(), pc: mem::MemMap::DRAM.base(), next_pc: mem::MemMap::DRAM.base(), csrs: [CsrValue(0); 4096], privilege_level: PrivilegeLevel::Machine, virt: false, pmp_entries: 16,
+            hartid: 0, cycle: 0, instret: 0, trapped: false, reservation: None, extensions: isa::Extensions::NONE,
+            vector: vector::Vector::default(), guest_fault: None,
+            interrupt_files: Default::default(), external_interrupts: 0, time: 0, waiting: false };
+        cpu.csrs[csr::file::MISA as usize] = CsrValue(csr::file::MISA_VALUE);
+        cpu
     }
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum PrivilegeLevel {
     User = 0,
     Supervisor = 1,
     Reserved = 2,
     Machine = 3,
+}
+impl PrivilegeLevel {
+    /// From the encoding used in mstatus.MPP
+    pub fn from_bits(bits: uguest) -> Self {
+        match bits & 0b11 {
+            0 => Self::User,
+            1 => Self::Supervisor,
+            2 => Self::Reserved,
+            _ => Self::Machine,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use bit_field::BitField;
+
+use crate::mem::{AccessFault, AccessType};
+use crate::uguest;
+use super::{PrivilegeLevel, CPU};
+
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPADDR0: u16 = 0x3B0;
+/// Entries the spec allows, implementations choose 0, 16 or 64
+pub const PMP_MAX_ENTRIES: usize = 64;
+/// On RV64 pmpaddr holds bits 55:2 of the physical address
+const PMPADDR_MASK: uguest = (1<<54)-1;
+
+/// 3.7.1.1. Address Matching (the A field of pmpcfg)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressMatching {
+    Off = 0,
+    /// Top of range, the previous pmpaddr is the bottom
+    TOR = 1,
+    /// Naturally aligned four-byte region
+    NA4 = 2,
+    /// Naturally aligned power-of-two region, >= 8 bytes
+    NAPOT = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmpConfig(pub u8);
+impl PmpConfig {
+    pub fn r(self) -> bool {self.0.get_bit(0)}
+    pub fn w(self) -> bool {self.0.get_bit(1)}
+    pub fn x(self) -> bool {self.0.get_bit(2)}
+    pub fn a(self) -> AddressMatching {
+        match self.0.get_bits(3..=4) {
+            0 => AddressMatching::Off,
+            1 => AddressMatching::TOR,
+            2 => AddressMatching::NA4,
+            _ => AddressMatching::NAPOT,
+        }
+    }
+    /// Locked entries ignore writes and also apply to M-mode, until reset
+    pub fn locked(self) -> bool {self.0.get_bit(7)}
+    fn allows(self, access: AccessType) -> bool {
+        match access {
+            AccessType::Read => self.r(),
+            AccessType::Write => self.w(),
+            AccessType::Execute => self.x(),
+        }
+    }
+    /// WARL: bits 5 and 6 are reserved and R=0 W=1 is a reserved combination
+    fn legalize(mut self) -> Self {
+        self.0 &= 0b1001_1111;
+        if !self.r() {self.0.set_bit(1, false);}
+        self
+    }
+}
+
+impl CPU {
+    pub fn pmp_cfg(&self, i: usize) -> PmpConfig {
+        if i >= self.pmp_entries {return PmpConfig(0)}
+        let reg = self.csrs[(PMPCFG0 as usize)+(i/8)*2].0;
+        PmpConfig(reg.get_bits((i%8)*8..(i%8)*8+8) as u8)
+    }
+    pub fn pmp_addr(&self, i: usize) -> uguest {
+        if i >= self.pmp_entries {return 0}
+        self.csrs[PMPADDR0 as usize+i].0
+    }
+    fn pmp_addr_locked(&self, i: usize) -> bool {
+        self.pmp_cfg(i).locked() || (i+1<self.pmp_entries && self.pmp_cfg(i+1).locked() && self.pmp_cfg(i+1).a() == AddressMatching::TOR)
+    }
+    /// `csr` is one of pmpcfg0..pmpcfg15, the odd ones don't exist on RV64 and stay zero
+    pub(crate) fn write_pmpcfg(&mut self, csr: u16, value: uguest) {
+        let reg = (csr-PMPCFG0) as usize;
+        if !reg.is_multiple_of(2) {return}
+        let mut new = 0;
+        for byte in 0..8 {
+            let i = reg/2*8+byte;
+            let old = self.pmp_cfg(i);
+            let cfg = if i >= self.pmp_entries {
+                PmpConfig(0)
+            } else if old.locked() {
+                old
+            } else {
+                PmpConfig(value.get_bits(byte*8..byte*8+8) as u8).legalize()
+            };
+            new.set_bits(byte*8..byte*8+8, cfg.0 as uguest);
+        }
+        self.csrs[csr as usize].0 = new;
+    }
+    pub(crate) fn write_pmpaddr(&mut self, csr: u16, value: uguest) {
+        let i = (csr-PMPADDR0) as usize;
+        if i >= self.pmp_entries || self.pmp_addr_locked(i) {return}
+        self.csrs[csr as usize].0 = value & PMPADDR_MASK;
+    }
+    /// What firmwares do before dropping to a lower privilege level: entry 0 gives access to the whole address space
+    pub fn pmp_allow_all(&mut self) {
+        self.write_pmpaddr(PMPADDR0, uguest::MAX);
+        self.write_pmpcfg(PMPCFG0, 0b11111); // NAPOT, RWX
+    }
+    /// Range matched by entry `i`, end excluded
+    fn pmp_range(&self, i: usize) -> Option<(uguest, uguest)> {
+        let addr = self.pmp_addr(i);
+        match self.pmp_cfg(i).a() {
+            AddressMatching::Off => None,
+            AddressMatching::TOR => {
+                let bottom = if i == 0 {0} else {self.pmp_addr(i-1)};
+                Some((bottom << 2, addr << 2))
+            },
+            AddressMatching::NA4 => Some((addr << 2, (addr << 2)+4)),
+            AddressMatching::NAPOT => {
+                let trailing = addr.trailing_ones();
+                let size = 1u128 << (trailing+3);
+                let base = ((addr & !((1u64 << trailing)-1)) as u128) << 2;
+                Some((base as uguest, (base+size).min(uguest::MAX as u128) as uguest))
+            },
+        }
+    }
+    /// Privilege used for PMP checks, loads and stores use MPP when mstatus.MPRV is set
+    fn pmp_privilege(&self, access: AccessType) -> PrivilegeLevel {
+        let mstatus = self.csrs[0x300].0;
+        if access != AccessType::Execute && mstatus.get_bit(17) {
+            PrivilegeLevel::from_bits(mstatus.get_bits(11..=12))
+        } else {
+            self.privilege_level
+        }
+    }
+    /// 3.7.1. Physical Memory Protection CSRs, the lowest-numbered matching entry decides
+    /// Accesses only partially covered by the matching entry fail
+    pub fn pmp_check(&self, addr: uguest, len: uguest, access: AccessType) -> Result<(), AccessFault> {
+        self.pmp_check_as(addr, len, access, self.pmp_privilege(access))
+    }
+    /// Same as `pmp_check` for an access of `privilege`, translated accesses know it from their `mmu::Mode`
+    pub fn pmp_check_as(&self, addr: uguest, len: uguest, access: AccessType, privilege: PrivilegeLevel) -> Result<(), AccessFault> {
+        let fault = AccessFault { addr, len, access };
+        let end = addr.saturating_add(len);
+        for i in 0..self.pmp_entries {
+            let Some((bottom, top)) = self.pmp_range(i) else {continue};
+            if addr >= top || end <= bottom {continue}
+            if addr < bottom || end > top {return Err(fault)}
+            let cfg = self.pmp_cfg(i);
+            if privilege == PrivilegeLevel::Machine && !cfg.locked() {return Ok(())}
+            return if cfg.allows(access) {Ok(())} else {Err(fault)}
+        }
+        // No entry matched
+        if privilege == PrivilegeLevel::Machine || self.pmp_entries == 0 {
+            Ok(())
+        } else {
+            Err(fault)
+        }
+    }
+}
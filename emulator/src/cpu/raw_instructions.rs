@@ -1,7 +1,7 @@
 // Big thanks to https://www.eg.bucknell.edu/~csci206/riscv-converter/Annotated_RISCV_Card.pdf
 // For more info about instructions https://projectf.io/posts/riscv-cheat-sheet/
 
-use std::cell::OnceCell;
+use std::sync::OnceLock;
 
 use color_eyre::eyre::ContextCompat;
 use color_eyre::Report;
@@ -21,16 +21,23 @@ pub type Imm = u16;
 pub type UImm = u32;
 
 use crate::cpu::reg::Reg;
-use crate::uguest;
+use crate::{iguest, uguest};
 use crate::cpu::CsrID;
+use crate::cpu::PrivilegeLevel;
+use crate::cpu::trap::Exception;
 
 use super::instructions::Instruction32;
+use super::vector;
 
 use color_eyre::Result;
 
 const fn _mask(opcode: u32, fun3: u32, fun7: u32) -> Instruction32Mask {
     Instruction32Mask(opcode | fun3 << 12 | fun7 << 25)
 }
+/// Zbb's unary operations take their operation from the whole immediate
+const fn _unary(opcode: u32, fun3: u32, imm: u32) -> Instruction32Mask {
+    Instruction32Mask(opcode | fun3 << 12 | imm << 20)
+}
 const fn desc(macro_out: (&'static str, Instruction32Format, InstructionFunction32), mask: Instruction32Mask) -> InstructionDescription32 {
     (macro_out.0, macro_out.1, mask, macro_out.2)
 }
@@ -38,7 +45,11 @@ const fn desc(macro_out: (&'static str, Instruction32Format, InstructionFunction
 macro_rules! load {
     ($size: ty,$name: ident,$func3: expr) => {
         desc(i!($name, {
-            vm.mem.get::<$size>((vs1+imm as uguest)).unwrap() as _
+            let addr = vs1.wrapping_add(sext(imm as _, 12));
+            match vm.load::<$size>(addr) {
+                Ok(val) => val as _,
+                Err(fault) => {vm.cpu.exception(fault.exception(), fault.addr); return}
+            }
         }), _mask(0b0000011, $func3, 0b0))
         // pub fn $name(vm: &mut crate::vm::VM, instruction: Instruction) {
         //     let (vs1, imm, dest) = Instruction::parse_i(instruction);
@@ -48,43 +59,235 @@ macro_rules! load {
 macro_rules! store {
     ($size: ty,$name: ident,$func3: expr) => {
         desc(s!($name, {
-            vm.mem.set::<$size>((vs1+imm as uguest), (vs2&($size::MAX as uguest)) as _).unwrap();
+            let addr = vs1.wrapping_add(sext(imm as _, 12));
+            if let Err(fault) = vm.store::<$size>(addr, vs2 as $size) {
+                vm.cpu.exception(fault.exception(), fault.addr);
+            }
         }), _mask(0b0100011, $func3, 0b0))
     };
 }
 
 
+/// Hypervisor virtual-machine load, the access is done as a guest (see `hypervisor_mode`)
+macro_rules! hlv {
+    ($size: ty, $name: ident, $fun7: expr, $rs2: expr) => {
+        desc(i!($name, {
+            match hypervisor_mode(vm, instruction, $rs2 == 0b11).and_then(|mode| {
+                vm.load_as::<$size>(vs1, crate::mem::AccessType::Read, mode).map_err(|fault| (fault.exception(), fault.addr))
+            }) {
+                Ok(val) => val as _,
+                Err(fault) => {vm.cpu.exception(fault.0, fault.1); return}
+            }
+        }), _unary(0b1110011, 0b100, $fun7 << 5 | $rs2))
+    };
+}
+/// Hypervisor virtual-machine store, rs2 is the value
+macro_rules! hsv {
+    ($size: ty, $name: ident, $fun7: expr) => {
+        desc(i!($name, {
+            let val = vm.cpu.regs[instruction._raw_rs2() as usize] as $size;
+            if let Err(fault) = hypervisor_mode(vm, instruction, false).and_then(|mode| {
+                vm.store_as::<$size>(vs1, val, mode).map_err(|fault| (fault.exception(), fault.addr))
+            }) {
+                vm.cpu.exception(fault.0, fault.1);
+                return
+            }
+            0 // rd is zero
+        }), _unary(0b1110011, 0b100, $fun7 << 5))
+    };
+}
+
 macro_rules! op_i {
     ($name: ident, $operator: expr) => {
         i!($name, {
-            let res = $operator(vs1, imm as uguest);
+            let res = $operator(vs1, sext(imm as _, 12));
             res
         })
     };
 }
 macro_rules! op_r {
     ($name: ident, $func3: expr, $func7:expr, $operator: expr) => {
+        op_r!($name, 0b0110011, $func3, $func7, $operator)
+    };
+    ($name: ident, $opcode: expr, $func3: expr, $func7:expr, $operator: expr) => {
         desc(r!($name, {
             let res = $operator(vs1, vs2);
             res
-        }), _mask(0b0110011, $func3, $func7))
+        }), _mask($opcode, $func3, $func7))
+    };
+}
+/// Read-modify-write of $size bits at vs1, rd gets the old value
+macro_rules! amo {
+    ($name: ident, $size: ty, $func5: expr, $operator: expr) => {
+        desc(r!($name, {
+            let old = match atomic_load::<$size>(vm, vs1) {
+                Ok(old) => old as uguest,
+                Err(fault) => {vm.cpu.exception(fault.0, fault.1); return}
+            };
+            if let Err(fault) = vm.store::<$size>(vs1, $operator(old, vs2) as $size) {
+                vm.cpu.exception(fault.exception(), fault.addr);
+                return
+            }
+            old
+        }), _mask(0b0101111, if core::mem::size_of::<$size>() == 4 {0b010} else {0b011}, $func5 << 2))
     };
 }
 
 macro_rules! branch {
     ($name: ident, $func3: expr, $op: tt) => {
-        desc(s!($name, {
-            dbg!(vs1,vs2, imm);
+        desc(b!($name, {
             if $op(vs1,vs2) {
-                vm.cpu.pc += vs2
+                vm.cpu.next_pc = vm.cpu.pc.wrapping_add(sext(imm as _, 13))
             };
         }), _mask(0b1100011, $func3, 0b0))
     };
 }
 
-#[track_caller]
-fn panic(a: uguest, b: uguest) -> uguest {
-    todo!("{} {}", a,b)
+macro_rules! zicsr {
+    ($name: ident, $func3: expr, $op: expr, $immediate: expr) => {
+        desc(i!($name, {
+            let source = if $immediate {instruction._raw_rs1() as uguest} else {vs1};
+            match zicsr(vm, instruction, $op, source) {
+                Ok(old) => old,
+                Err(cause) => {vm.cpu.exception(cause, instruction.0 as uguest); return}
+            }
+        }), _mask(0b1110011, $func3, 0b0))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrOp {
+    Write,
+    Set,
+    Clear,
+}
+/// Shared by the six Zicsr instructions, returns the old value of the CSR
+/// csrrw(i) doesn't read when rd is x0 and csrrs(i)/csrrc(i) don't write when rs1 is x0 (or uimm is 0)
+pub fn zicsr(vm: &mut crate::vm::VM, instruction: Instruction32, op: CsrOp, source: uguest) -> Result<uguest, Exception> {
+    let csr = CsrID::new((instruction.0 >> 20) as u16);
+    let reads = op != CsrOp::Write || instruction.rd() != Reg::zero;
+    let writes = op == CsrOp::Write || instruction._raw_rs1() != 0;
+    let old = if reads {vm.cpu.read_csr(csr)?} else {0};
+    if writes {
+        let new = match op {
+            CsrOp::Write => source,
+            CsrOp::Set => vm.cpu.modified_csr(csr, old) | source,
+            CsrOp::Clear => vm.cpu.modified_csr(csr, old) & !source,
+        };
+        vm.cpu.write_csr(csr, new)?;
+    }
+    Ok(old)
+}
+
+/// Sign-extends the `bits` lower bits of `val`
+pub fn sext(val: uguest, bits: u32) -> uguest {
+    let shift = uguest::BITS-bits;
+    (((val << shift) as crate::iguest) >> shift) as uguest
+}
+
+fn slt(a: uguest, b: uguest) -> uguest {
+    ((a as iguest) < (b as iguest)) as uguest
+}
+fn sltu(a: uguest, b: uguest) -> uguest {
+    (a < b) as uguest
+}
+// Only the lower 6 bits of the shift amount are used
+fn sll(a: uguest, b: uguest) -> uguest {
+    a << (b & 0x3F)
+}
+fn srl(a: uguest, b: uguest) -> uguest {
+    a >> (b & 0x3F)
+}
+fn sra(a: uguest, b: uguest) -> uguest {
+    ((a as iguest) >> (b & 0x3F)) as uguest
+}
+// *W instructions work on the lower 32 bits and sign-extend the result
+fn addw(a: uguest, b: uguest) -> uguest {
+    (a as i32).wrapping_add(b as i32) as iguest as uguest
+}
+fn sllw(a: uguest, b: uguest) -> uguest {
+    ((a as u32) << (b & 0x1F)) as i32 as iguest as uguest
+}
+fn srlw(a: uguest, b: uguest) -> uguest {
+    ((a as u32) >> (b & 0x1F)) as i32 as iguest as uguest
+}
+fn sraw(a: uguest, b: uguest) -> uguest {
+    ((a as i32) >> (b & 0x1F)) as iguest as uguest
+}
+/// Division by zero gives -1 and the overflow (MIN/-1) gives MIN, no exception
+fn div(a: uguest, b: uguest) -> uguest {
+    if b == 0 {return uguest::MAX}
+    (a as iguest).wrapping_div(b as iguest) as uguest
+}
+/// Remainder of a division by zero is the dividend, the overflow gives 0
+fn rem(a: uguest, b: uguest) -> uguest {
+    if b == 0 {return a}
+    (a as iguest).wrapping_rem(b as iguest) as uguest
+}
+
+// Zba, address generation: the index is shifted and added to the base in vs2
+fn sh_add(a: uguest, b: uguest, shift: u32) -> uguest {
+    b.wrapping_add(a << shift)
+}
+/// *.uw instructions take the index as an unsigned word
+fn uw(a: uguest) -> uguest {
+    a as u32 as uguest
+}
+fn rolw(a: uguest, b: uguest) -> uguest {
+    (a as u32).rotate_left(b as u32 & 0x1F) as i32 as iguest as uguest
+}
+fn rorw(a: uguest, b: uguest) -> uguest {
+    (a as u32).rotate_right(b as u32 & 0x1F) as i32 as iguest as uguest
+}
+/// Each byte becomes 0xFF if any of its bits is set
+fn orc_b(a: uguest) -> uguest {
+    (0..8).map(|byte| if (a >> (byte*8)) & 0xFF != 0 {0xFF << (byte*8)} else {0}).fold(0, core::ops::BitOr::bitor)
+}
+/// Carry-less product of a and b, 128 bits
+fn clmul(a: uguest, b: uguest) -> u128 {
+    (0..uguest::BITS).filter(|i| (b >> i) & 1 != 0).map(|i| (a as u128) << i).fold(0, core::ops::BitXor::bitxor)
+}
+// Zbs, single bit instructions, the index is taken modulo XLEN
+fn bit(b: uguest) -> uguest {
+    1 << (b & 0x3F)
+}
+
+/// Hypervisor loads and stores are for HS-mode and M-mode, U-mode when hstatus.HU allows it
+fn hypervisor_mode(vm: &mut crate::vm::VM, instruction: Instruction32, hlvx: bool) -> Result<super::mmu::Mode, (Exception, uguest)> {
+    let cpu = &vm.cpu;
+    if cpu.virt {return Err((Exception::VirtualInstruction, instruction.0 as uguest))}
+    if cpu.privilege_level == PrivilegeLevel::User && cpu.csr_value(super::csr::file::HSTATUS) & 1 << 9 == 0 { // HU
+        return Err((Exception::IllegalInstruction, instruction.0 as uguest))
+    }
+    Ok(cpu.hypervisor_mode(hlvx))
+}
+
+/// Atomics must be naturally aligned, their faults are reported as store faults
+fn atomic_check<T>(vm: &mut crate::vm::VM, addr: uguest, misaligned: Exception) -> Result<(), (Exception, uguest)> {
+    if !addr.is_multiple_of(core::mem::size_of::<T>() as uguest) {
+        return Err((misaligned, addr))
+    }
+    Ok(())
+}
+/// First half of an AMO, the value is loaded with write permission checks as it will be written back
+fn atomic_load<T: Copy>(vm: &mut crate::vm::VM, addr: uguest) -> Result<T, (Exception, uguest)> {
+    atomic_check::<T>(vm, addr, Exception::StoreAddressMisaligned)?;
+    vm.load_writable::<T>(addr).map_err(|fault| (fault.exception(), fault.addr))
+}
+fn load_reserved<T: Copy + Into<iguest>>(vm: &mut crate::vm::VM, addr: uguest) -> Result<uguest, (Exception, uguest)> {
+    atomic_check::<T>(vm, addr, Exception::LoadAddressMisaligned)?;
+    let val = vm.load::<T>(addr).map_err(|fault| (fault.exception(), fault.addr))?;
+    vm.cpu.reservation = Some(addr);
+    Ok(val.into() as uguest)
+}
+/// Returns 0 on success, 1 when the reservation was lost
+fn store_conditional<T: Copy>(vm: &mut crate::vm::VM, addr: uguest, val: T) -> Result<uguest, (Exception, uguest)> {
+    atomic_check::<T>(vm, addr, Exception::StoreAddressMisaligned)?;
+    if vm.cpu.reservation.take() != Some(addr) {
+        return Ok(1)
+    }
+    vm.store::<T>(addr, val).map_err(|fault| (fault.exception(), fault.addr))?;
+    Ok(0)
 }
 
 
@@ -117,127 +320,253 @@ pub type InstructionDescription16 = (&'static str, Instruction16Format, Instruct
 /// Based on
 /// Chapter 34. RV32/64G Instruction Set Listings
 /// And https://www.eg.bucknell.edu/~csci206/riscv-converter/Annotated_RISCV_Card.pdf at beginning
-pub const INSTRUCTIONS32: [InstructionDescription32; 60] = [
-    load!(u8,  lb, 0),
-    load!(u16, lh, 1),
-    load!(u32, lw, 2),
+pub const INSTRUCTIONS32: [InstructionDescription32; 157] = [
+    load!(i8,  lb, 0),
+    load!(i16, lh, 1),
+    load!(i32, lw, 2),
     load!(u64, ld, 3),
-    load!(u8,  lbu, 0+4),
+    load!(u8,  lbu, 4),
     load!(u16, lhu, 1+4),
     load!(u32, lwu, 2+4),
-    load!(u64, ldu, 3+4),
-    // ("lbu",     InstructionFormat::I, _mask(0b0000011, 0b100, 0b0), lbu),
-    // ("lhu",     InstructionFormat::I, _mask(0b0000011, 0b110, 0b0), lhu),
-    // ("lwu",     InstructionFormat::I, _mask(0b0000011, 0b111, 0b0), lwu),
     
-    desc(i!(fence, {
-        todo!()
-    }), _mask(0b0001111, 0b000, 0b0)),
-    desc(i!(fencei, {
-        todo!()
-    }), _mask(0b0001111, 0b001, 0b0)),
+    // Single hart without caches, memory is always coherent
+    desc(i!(fence, {return}), _mask(0b0001111, 0b000, 0b0)),
+    desc(i!(fencei, {return}), _mask(0b0001111, 0b001, 0b0)),
     
-    desc(op_i!(addi,core::ops::Add::add),    _mask(0b0010011, 0b000, 0b0)),
-    desc(op_i!(slli,core::ops::Shl::shl),    _mask(0b0010011, 0b001, 0b0)),
-    desc(op_i!(slti,panic),    _mask(0b0010011, 0b010, 0b0)),
-    desc(op_i!(sltiu,panic), _mask(0b0010011, 0b011, 0b0)),
+    desc(op_i!(addi,uguest::wrapping_add),    _mask(0b0010011, 0b000, 0b0)),
+    desc(op_i!(slli,sll),    _mask(0b0010011, 0b001, 0b0)),
+    desc(op_i!(slti,slt),    _mask(0b0010011, 0b010, 0b0)),
+    desc(op_i!(sltiu,sltu), _mask(0b0010011, 0b011, 0b0)),
     desc(op_i!(xori,core::ops::BitXor::bitxor),    _mask(0b0010011, 0b100, 0b0)),
-    desc(op_i!(srli,panic),    _mask(0b0010011, 0b101, 0b0000000)),
-    desc(op_i!(srai,panic),    _mask(0b0010011, 0b101, 0b0100000)),
+    desc(op_i!(srli,srl),    _mask(0b0010011, 0b101, 0b0000000)),
+    desc(op_i!(srai,sra),    _mask(0b0010011, 0b101, 0b0100000)),
     desc(op_i!(ori,core::ops::BitOr::bitor),       _mask(0b0010011, 0b110, 0b0)),
     desc(op_i!(andi,core::ops::BitAnd::bitand),    _mask(0b0010011, 0b111, 0b0)),
     
-    desc(u!(auipc, {vm.cpu.pc+(imm as uguest)}), _mask(0b0010111, 0b000, 0b0)),
+    desc(u!(auipc, {vm.cpu.pc.wrapping_add(sext(imm as _, 32))}), _mask(0b0010111, 0b000, 0b0)),
     
-    desc(i!(addiw, {vs1+imm as uguest}), _mask(0b0011011, 0b000, 0b0)),
-    desc(i!(slliw, {(vs1)<<(imm as uguest)}), _mask(0b0011011, 0b001, 0b0000000)),
-    desc(i!(srliw, {vs1>>imm as uguest}), _mask(0b0011011, 0b101, 0b0000000)),
-    desc(i!(sraiw, {todo!("vs1>>>imm as uguest")}), _mask(0b0011011, 0b101, 0b0100000)),
+    desc(op_i!(addiw, addw), _mask(0b0011011, 0b000, 0b0)),
+    desc(op_i!(slliw, sllw), _mask(0b0011011, 0b001, 0b0000000)),
+    desc(op_i!(srliw, srlw), _mask(0b0011011, 0b101, 0b0000000)),
+    desc(op_i!(sraiw, sraw), _mask(0b0011011, 0b101, 0b0100000)),
     
     store!(u8,  sb, 0b000),
     store!(u16, sh, 0b001),
     store!(u32, sw, 0b010),
     store!(u64, sd, 0b011),
     
-    op_r!(add,  0b000, 0b0000000, core::ops::Add::add),
-    op_r!(sub,  0b000, 0b0100000, core::ops::Sub::sub),
-    op_r!(sll,  0b001, 0b0000000, core::ops::Shl::shl),
-    op_r!(slt,  0b010, 0b0000000, panic), // core::ops::Slt::slt
-    op_r!(sltu, 0b011, 0b0000000, panic), // core::ops::Sltu::sltu
+    op_r!(add,  0b000, 0b0000000, uguest::wrapping_add),
+    op_r!(sub,  0b000, 0b0100000, uguest::wrapping_sub),
+    op_r!(sll,  0b001, 0b0000000, sll),
+    op_r!(slt,  0b010, 0b0000000, slt),
+    op_r!(sltu, 0b011, 0b0000000, sltu),
     op_r!(xor,  0b100, 0b0000000, core::ops::BitXor::bitxor),
-    op_r!(srl,  0b101, 0b0000000, panic), // core::ops::srl::srl
-    op_r!(sra,  0b101, 0b0100000, panic), // core::ops::sra::sra
+    op_r!(srl,  0b101, 0b0000000, srl),
+    op_r!(sra,  0b101, 0b0100000, sra),
     op_r!(or,   0b110, 0b0000000, core::ops::BitOr::bitor),
     op_r!(and,  0b111, 0b0000000, core::ops::BitAnd::bitand),
     
-    desc(u!(lui, {imm as uguest}),   _mask(0b0110111, 0b0, 0b0)),
+    desc(u!(lui, {sext(imm as _, 32)}),   _mask(0b0110111, 0b0, 0b0)),
+    
+    op_r!(addw, 0b0111011, 0b000, 0b0000000, addw),
+    op_r!(subw, 0b0111011, 0b000, 0b0100000, (|a, b: uguest| addw(a, b.wrapping_neg()))),
+    op_r!(sllw, 0b0111011, 0b001, 0b0000000, sllw),
+    op_r!(srlw, 0b0111011, 0b101, 0b0000000, srlw),
+    op_r!(sraw, 0b0111011, 0b101, 0b0100000, sraw),
     
-    desc(r!(addw, {todo!()}),  _mask(0b0111011, 0b000, 0b0000000)),
-    desc(r!(subw, {todo!()}),  _mask(0b0111011, 0b000, 0b0100000)),
-    desc(r!(sllw, {todo!()}),  _mask(0b0111011, 0b001, 0b0000000)),
-    desc(r!(srllw, {todo!()}), _mask(0b0111011, 0b101, 0b0000000)),
-    desc(r!(sraw, {todo!()}),  _mask(0b0111011, 0b101, 0b0100000)),
+    // M extension
+    op_r!(mul,    0b000, 0b0000001, uguest::wrapping_mul),
+    op_r!(mulh,   0b001, 0b0000001, (|a, b| ((a as iguest as i128 * b as iguest as i128) >> 64) as uguest)),
+    op_r!(mulhsu, 0b010, 0b0000001, (|a, b| ((a as iguest as i128).wrapping_mul(b as i128) >> 64) as uguest)),
+    op_r!(mulhu,  0b011, 0b0000001, (|a, b| ((a as u128 * b as u128) >> 64) as uguest)),
+    op_r!(div,    0b100, 0b0000001, div),
+    op_r!(divu,   0b101, 0b0000001, (|a: uguest, b| a.checked_div(b).unwrap_or(uguest::MAX))),
+    op_r!(rem,    0b110, 0b0000001, rem),
+    op_r!(remu,   0b111, 0b0000001, (|a: uguest, b| a.checked_rem(b).unwrap_or(a))),
+    op_r!(mulw,  0b0111011, 0b000, 0b0000001, (|a, b| (a as i32).wrapping_mul(b as i32) as iguest as uguest)),
+    op_r!(divw,  0b0111011, 0b100, 0b0000001, (|a, b| div(a as i32 as iguest as uguest, b as i32 as iguest as uguest) as i32 as iguest as uguest)),
+    op_r!(divuw, 0b0111011, 0b101, 0b0000001, (|a, b| (a as u32).checked_div(b as u32).unwrap_or(u32::MAX) as i32 as iguest as uguest)),
+    op_r!(remw,  0b0111011, 0b110, 0b0000001, (|a, b| rem(a as i32 as iguest as uguest, b as i32 as iguest as uguest) as i32 as iguest as uguest)),
+    op_r!(remuw, 0b0111011, 0b111, 0b0000001, (|a, b| (a as u32).checked_rem(b as u32).unwrap_or(a as u32) as i32 as iguest as uguest)),
+    
+    // A extension, aq and rl don't matter with a single hart
+    desc(r!(lrw, {
+        match load_reserved::<i32>(vm, vs1) {
+            Ok(val) => val,
+            Err(fault) => {vm.cpu.exception(fault.0, fault.1); return}
+        }
+    }), _mask(0b0101111, 0b010, 0b00010 << 2)),
+    desc(r!(lrd, {
+        match load_reserved::<i64>(vm, vs1) {
+            Ok(val) => val,
+            Err(fault) => {vm.cpu.exception(fault.0, fault.1); return}
+        }
+    }), _mask(0b0101111, 0b011, 0b00010 << 2)),
+    desc(r!(scw, {
+        match store_conditional::<u32>(vm, vs1, vs2 as u32) {
+            Ok(val) => val,
+            Err(fault) => {vm.cpu.exception(fault.0, fault.1); return}
+        }
+    }), _mask(0b0101111, 0b010, 0b00011 << 2)),
+    desc(r!(scd, {
+        match store_conditional::<u64>(vm, vs1, vs2) {
+            Ok(val) => val,
+            Err(fault) => {vm.cpu.exception(fault.0, fault.1); return}
+        }
+    }), _mask(0b0101111, 0b011, 0b00011 << 2)),
+    amo!(amoswapw, i32, 0b00001, (|_, b| b)),
+    amo!(amoaddw,  i32, 0b00000, uguest::wrapping_add),
+    amo!(amoxorw,  i32, 0b00100, core::ops::BitXor::bitxor),
+    amo!(amoandw,  i32, 0b01100, core::ops::BitAnd::bitand),
+    amo!(amoorw,   i32, 0b01000, core::ops::BitOr::bitor),
+    amo!(amominw,  i32, 0b10000, (|a, b| (a as iguest).min(b as i32 as iguest) as uguest)),
+    amo!(amomaxw,  i32, 0b10100, (|a, b| (a as iguest).max(b as i32 as iguest) as uguest)),
+    amo!(amominuw, i32, 0b11000, (|a: uguest, b| (a as u32).min(b as u32) as uguest)),
+    amo!(amomaxuw, i32, 0b11100, (|a: uguest, b| (a as u32).max(b as u32) as uguest)),
+    amo!(amoswapd, i64, 0b00001, (|_, b| b)),
+    amo!(amoaddd,  i64, 0b00000, uguest::wrapping_add),
+    amo!(amoxord,  i64, 0b00100, core::ops::BitXor::bitxor),
+    amo!(amoandd,  i64, 0b01100, core::ops::BitAnd::bitand),
+    amo!(amoord,   i64, 0b01000, core::ops::BitOr::bitor),
+    amo!(amomind,  i64, 0b10000, (|a, b| (a as iguest).min(b as iguest) as uguest)),
+    amo!(amomaxd,  i64, 0b10100, (|a, b| (a as iguest).max(b as iguest) as uguest)),
+    amo!(amominud, i64, 0b11000, uguest::min),
+    amo!(amomaxud, i64, 0b11100, uguest::max),
     
     branch!(beq,  0b000, (|vs1,vs2| vs1==vs2)), // Branch equal
     branch!(bne,  0b001, (|vs1,vs2| vs1!=vs2)), // Branch not equal
-    branch!(blt,  0b100, (|vs1,vs2| vs1< vs2)), // Branch less than
-    branch!(bge,  0b101, (|vs1,vs2| vs1< vs2)), // Branch less than unsigned
-    branch!(bltu, 0b110, (|vs1,vs2| vs1>=vs2)), // Branch greater or equal
-    branch!(bgeu, 0b111, (|vs1,vs2| vs1>=vs2)), // Branch greater or equal
+    branch!(blt,  0b100, (|vs1,vs2| (vs1 as crate::iguest)< (vs2 as crate::iguest))), // Branch less than
+    branch!(bge,  0b101, (|vs1,vs2| (vs1 as crate::iguest)>=(vs2 as crate::iguest))), // Branch greater or equal
+    branch!(bltu, 0b110, (|vs1,vs2| vs1< vs2)), // Branch less than unsigned
+    branch!(bgeu, 0b111, (|vs1,vs2| vs1>=vs2)), // Branch greater or equal unsigned
     
     desc(i!(jalr, {
-        let prev_pc = vm.cpu.pc+4;
-        let imm: i16 = unsafe {core::mem::transmute(imm)};
-        let (add,overflowed) = vm.cpu.pc.overflowing_add_signed((imm as i64+vs1 as i64)-4);
-        if overflowed {todo!();}
+        let prev_pc = vm.cpu.next_pc;
+        vm.cpu.next_pc = vs1.wrapping_add(sext(imm as _, 12)) & !1;
         prev_pc
     }),  _mask(0b1100111, 0b000, 0b0)),
     desc(j!(jal, {
-        let prev_pc = vm.cpu.pc+4;
-        let imm: i16 = unsafe {core::mem::transmute(imm)};
-        let (add,overflowed) = vm.cpu.pc.overflowing_add_signed(imm as i64-4);
-        if overflowed {todo!();}
-        vm.cpu.pc = add;
+        let prev_pc = vm.cpu.next_pc;
+        vm.cpu.next_pc = vm.cpu.pc.wrapping_add(sext(imm as _, 21));
         prev_pc
     }),   _mask(0b1101111, 0b0, 0b0)),
     
     desc(i!(ecall,  {
-        match imm {
-            0 => {
-                todo!("ECALL")
+        let res = match imm {
+            0 if vm.linux_syscall() || vm.sbi_call() => Ok(()),
+            0 => Err(match (vm.cpu.privilege_level, vm.cpu.virt) {
+                (PrivilegeLevel::Machine, _) => Exception::MachineEcall,
+                (PrivilegeLevel::Supervisor, false) => Exception::SupervisorEcall,
+                (PrivilegeLevel::Supervisor, true) => Exception::VirtualSupervisorEcall,
+                _ => Exception::UserEcall,
+            }),
+            1 if vm.semihosting_call() => Ok(()),
+            1 => Err(Exception::Breakpoint),
+            0x302 => vm.cpu.mret(),
+            0x102 => vm.cpu.sret(),
+            0x105 => vm.cpu.wfi(),
+            imm if imm >> 5 == 0b0001001 => vm.cpu.sfence_vma(),
+            imm if imm >> 5 == 0b0010001 => vm.cpu.hfence(false), // HFENCE.VVMA
+            imm if imm >> 5 == 0b0110001 => vm.cpu.hfence(true), // HFENCE.GVMA
+            _ => Err(Exception::IllegalInstruction),
+        };
+        match res {
+            Ok(()) => 0,
+            // Only illegal and virtual instructions report the instruction bits, ecall/ebreak have no tval
+            Err(cause) => {
+                let tval = if matches!(cause, Exception::IllegalInstruction | Exception::VirtualInstruction) {instruction.0 as uguest} else {0};
+                vm.cpu.exception(cause, tval);
+                return
             },
-            1 => {
-                todo!("EBREAK")
-            },
-            0x302 => { // MRET
-                let mepc = crate::csr!(vm, mepc);
-                println!("MRET");
-                vm.cpu.pc = mepc.0;
-                0
-            },
-            _ => {todo!("Invalid instruction {:?}", instruction.0)}
         }
     }), _mask(0b1110011, 0b0, 0b0)), // Immediates (fun7)
-    desc(i!(ebreak, {todo!()}), _mask(0b1110011, 0b0, 0b1)),
     
     // Atomic Read/Write CSR
-    desc(i!(csrrw, {
-        let old = *vm.cpu.csr(CsrID::new(imm));
-        println!("{}", CsrID::new(imm));
-        vm.cpu.csr(CsrID::new(imm)).0 = vs1;
-        old.0
-    }), _mask(0b1110011, 0b001, 0b0)),
+    zicsr!(csrrw, 0b001, CsrOp::Write, false),
     // Atomic Read and Set Bits in CSR
-    desc(i!(csrrs, {
-        assert_eq!(rs1, Reg::zero); // Not supported if non-zero
-        vm.cpu.csr(CsrID::new(imm)).0
-    }), _mask(0b1110011, 0b010, 0b0)),
+    zicsr!(csrrs, 0b010, CsrOp::Set, false),
     // Atomic Read and Clear Bits in CSR
-    desc(i!(csrrc, {todo!()}), _mask(0b1110011, 0b011, 0b0)),
+    zicsr!(csrrc, 0b011, CsrOp::Clear, false),
     // Same but with immediates
-    desc(i!(csrrwi,{todo!()}), _mask(0b1110011, 0b101, 0b0)),
-    desc(i!(csrrsi,{todo!()}), _mask(0b1110011, 0b110, 0b0)),
-    desc(i!(csrrci,{todo!()}), _mask(0b1110011, 0b111, 0b0)),
+    zicsr!(csrrwi, 0b101, CsrOp::Write, true),
+    zicsr!(csrrsi, 0b110, CsrOp::Set, true),
+    zicsr!(csrrci, 0b111, CsrOp::Clear, true),
+    
+    // Zba
+    op_r!(adduw,    0b0111011, 0b000, 0b0000100, (|a, b: uguest| b.wrapping_add(uw(a)))),
+    op_r!(sh1add,   0b010, 0b0010000, (|a, b| sh_add(a, b, 1))),
+    op_r!(sh2add,   0b100, 0b0010000, (|a, b| sh_add(a, b, 2))),
+    op_r!(sh3add,   0b110, 0b0010000, (|a, b| sh_add(a, b, 3))),
+    op_r!(sh1adduw, 0b0111011, 0b010, 0b0010000, (|a, b| sh_add(uw(a), b, 1))),
+    op_r!(sh2adduw, 0b0111011, 0b100, 0b0010000, (|a, b| sh_add(uw(a), b, 2))),
+    op_r!(sh3adduw, 0b0111011, 0b110, 0b0010000, (|a, b| sh_add(uw(a), b, 3))),
+    desc(op_i!(slliuw, (|a, b| sll(uw(a), b))), _mask(0b0011011, 0b001, 0b0000100)),
+    
+    // Zbb
+    op_r!(andn, 0b111, 0b0100000, (|a, b: uguest| a & !b)),
+    op_r!(orn,  0b110, 0b0100000, (|a, b: uguest| a | !b)),
+    op_r!(xnor, 0b100, 0b0100000, (|a: uguest, b: uguest| !(a ^ b))),
+    desc(op_i!(clz,   (|a: uguest, _| a.leading_zeros() as uguest)),         _unary(0b0010011, 0b001, 0x600)),
+    desc(op_i!(clzw,  (|a: uguest, _| (a as u32).leading_zeros() as uguest)), _unary(0b0011011, 0b001, 0x600)),
+    desc(op_i!(ctz,   (|a: uguest, _| a.trailing_zeros() as uguest)),        _unary(0b0010011, 0b001, 0x601)),
+    desc(op_i!(ctzw,  (|a: uguest, _| (a as u32).trailing_zeros() as uguest)), _unary(0b0011011, 0b001, 0x601)),
+    desc(op_i!(cpop,  (|a: uguest, _| a.count_ones() as uguest)),            _unary(0b0010011, 0b001, 0x602)),
+    desc(op_i!(cpopw, (|a: uguest, _| (a as u32).count_ones() as uguest)),   _unary(0b0011011, 0b001, 0x602)),
+    op_r!(max,  0b110, 0b0000101, (|a, b| (a as iguest).max(b as iguest) as uguest)),
+    op_r!(maxu, 0b111, 0b0000101, uguest::max),
+    op_r!(min,  0b100, 0b0000101, (|a, b| (a as iguest).min(b as iguest) as uguest)),
+    op_r!(minu, 0b101, 0b0000101, uguest::min),
+    desc(op_i!(sextb, (|a, _| a as i8 as iguest as uguest)),  _unary(0b0010011, 0b001, 0x604)),
+    desc(op_i!(sexth, (|a, _| a as i16 as iguest as uguest)), _unary(0b0010011, 0b001, 0x605)),
+    op_r!(zexth, 0b0111011, 0b100, 0b0000100, (|a, _| a as u16 as uguest)),
+    op_r!(rol,  0b001, 0b0110000, (|a: uguest, b| a.rotate_left(b as u32 & 0x3F))),
+    op_r!(rolw, 0b0111011, 0b001, 0b0110000, rolw),
+    op_r!(ror,  0b101, 0b0110000, (|a: uguest, b| a.rotate_right(b as u32 & 0x3F))),
+    desc(op_i!(rori, (|a: uguest, b| a.rotate_right(b as u32 & 0x3F))), _mask(0b0010011, 0b101, 0b0110000)),
+    desc(op_i!(roriw, rorw), _mask(0b0011011, 0b101, 0b0110000)),
+    op_r!(rorw, 0b0111011, 0b101, 0b0110000, rorw),
+    desc(op_i!(orcb, (|a, _| orc_b(a))),                 _unary(0b0010011, 0b101, 0x287)),
+    desc(op_i!(rev8, (|a: uguest, _| a.swap_bytes())),  _unary(0b0010011, 0b101, 0x6B8)),
+    
+    // Zbc
+    op_r!(clmul,  0b001, 0b0000101, (|a, b| clmul(a, b) as uguest)),
+    op_r!(clmulh, 0b011, 0b0000101, (|a, b| (clmul(a, b) >> 64) as uguest)),
+    op_r!(clmulr, 0b010, 0b0000101, (|a, b| (clmul(a, b) >> 63) as uguest)),
+    
+    // Zbs
+    op_r!(bclr, 0b001, 0b0100100, (|a, b| a & !bit(b))),
+    desc(op_i!(bclri, (|a, b| a & !bit(b))), _mask(0b0010011, 0b001, 0b0100100)),
+    op_r!(bext, 0b101, 0b0100100, (|a, b| (a >> (b & 0x3F)) & 1)),
+    desc(op_i!(bexti, (|a, b| (a >> (b & 0x3F)) & 1)), _mask(0b0010011, 0b101, 0b0100100)),
+    op_r!(binv, 0b001, 0b0110100, (|a, b| a ^ bit(b))),
+    desc(op_i!(binvi, (|a, b| a ^ bit(b))), _mask(0b0010011, 0b001, 0b0110100)),
+    op_r!(bset, 0b001, 0b0010100, (|a, b| a | bit(b))),
+    desc(op_i!(bseti, (|a, b| a | bit(b))), _mask(0b0010011, 0b001, 0b0010100)),
+    
+    // H, the hypervisor loads and stores take their size from funct7, and hlv/hlvx/unsigned from rs2
+    hlv!(i8,  hlvb,   0b0110000, 0b00),
+    hlv!(u8,  hlvbu,  0b0110000, 0b01),
+    hlv!(i16, hlvh,   0b0110010, 0b00),
+    hlv!(u16, hlvhu,  0b0110010, 0b01),
+    hlv!(u16, hlvxhu, 0b0110010, 0b11),
+    hlv!(i32, hlvw,   0b0110100, 0b00),
+    hlv!(u32, hlvwu,  0b0110100, 0b01),
+    hlv!(u32, hlvxwu, 0b0110100, 0b11),
+    hlv!(u64, hlvd,   0b0110110, 0b00),
+    hsv!(u8,  hsvb,   0b0110001),
+    hsv!(u16, hsvh,   0b0110011),
+    hsv!(u32, hsvw,   0b0110101),
+    hsv!(u64, hsvd,   0b0110111),
+    
+    // V, decoded further in `vector`
+    ("opivv", Instruction32Format::R, _mask(0b1010111, vector::OPIVV, 0), vector::op_v),
+    ("opmvv", Instruction32Format::R, _mask(0b1010111, vector::OPMVV, 0), vector::op_v),
+    ("opivi", Instruction32Format::R, _mask(0b1010111, vector::OPIVI, 0), vector::op_v),
+    ("opivx", Instruction32Format::R, _mask(0b1010111, vector::OPIVX, 0), vector::op_v),
+    ("opmvx", Instruction32Format::R, _mask(0b1010111, vector::OPMVX, 0), vector::op_v),
+    ("opcfg", Instruction32Format::R, _mask(0b1010111, vector::OPCFG, 0), vector::op_v),
+    ("vload", Instruction32Format::I, _mask(0b0000111, 0, 0), vector::load),
+    ("vstore", Instruction32Format::S, _mask(0b0100111, 0, 0), vector::store),
 ];
 
 pub enum InstructionDescription {
@@ -246,7 +575,7 @@ pub enum InstructionDescription {
 }
 
 pub fn get_from_opcode(opcode:u8) -> Option<&'static Vec<InstructionDescription32>> {
-    unsafe{REVERSE_INSTRUCTIONS_MASKS.get()?.get(opcode as usize)}
+    REVERSE_INSTRUCTIONS_MASKS.get_or_init(build_instructions_funcs).get(opcode as usize)
 }
 pub fn try_find_instruction32_desc(inst: Instruction32) -> Result<InstructionDescription32> {
     let opcode = inst.opcode();
@@ -256,7 +585,27 @@ pub fn try_find_instruction32_desc(inst: Instruction32) -> Result<InstructionDes
     for (_name, _fmt, mask, fun) in neighbors {
         let mi = Instruction32(mask.0);
         if match fmt {
+            // aq and rl are the lower bits of fun7 in atomics
+            Instruction32Format::R if opcode == 0b0101111 => {mi.fun3() == inst.fun3() && mi.fun7() >> 2 == inst.fun7() >> 2},
+            // OP-V takes the operation from funct6 and the operands from funct3
+            Instruction32Format::R if opcode == 0b1010111 => {mi.fun3() == inst.fun3()},
             Instruction32Format::R => {mi.fun3() == inst.fun3() && mi.fun7() == inst.fun7()},
+            // Vector loads and stores share their opcodes with the F ones, the width tells them apart
+            Instruction32Format::I | Instruction32Format::S if matches!(opcode, 0b0000111 | 0b0100111) => {vector::eew(inst.fun3()).is_some()},
+            // Zbb's unary operations (clz, ctz, cpop, sext.*, their *W) are told apart by rs2
+            Instruction32Format::I if matches!(opcode, 0b0010011 | 0b0011011) && inst.fun3() == 0b001 && inst.fun7() == 0b0110000 => {
+                mi.fun3() == inst.fun3() && mi.fun7() == inst.fun7() && mi._raw_rs2() == inst._raw_rs2()
+            },
+            // Hypervisor loads are told apart by rs2, stores have rs2 as their source
+            Instruction32Format::I if opcode == 0b1110011 && inst.fun3() == 0b100 => {
+                mi.fun3() == inst.fun3() && mi.fun7() == inst.fun7() && (inst.fun7() & 1 == 1 || mi._raw_rs2() == inst._raw_rs2())
+            },
+            // Shifts by immediate have a fun6 (RV64) or fun7 (*W) next to the shift amount
+            Instruction32Format::I if opcode == 0b0010011 && inst.fun3() & 0b11 == 0b01 => {mi.fun3() == inst.fun3() && mi.fun7() >> 1 == inst.fun7() >> 1},
+            // slli.uw has the 6 bits shift amount of RV64
+            Instruction32Format::I if opcode == 0b0011011 && inst.fun3() & 0b11 == 0b01 => {
+                mi.fun3() == inst.fun3() && (mi.fun7() == inst.fun7() || mi.fun7() == 0b0000100 && inst.fun7() >> 1 == 0b0000010)
+            },
             Instruction32Format::I => {mi.fun3() == inst.fun3()},
             Instruction32Format::S => {mi.fun3() == inst.fun3()},
             Instruction32Format::B => {mi.fun3() == inst.fun3()},
@@ -273,12 +622,15 @@ pub fn find_instruction32_desc(inst: Instruction32) -> InstructionDescription32
 }
 
 type _ReverseInstructionsMasks = [Vec<InstructionDescription32>; 127];
-pub static mut REVERSE_INSTRUCTIONS_MASKS: OnceCell<_ReverseInstructionsMasks> = OnceCell::new();
+pub static REVERSE_INSTRUCTIONS_MASKS: OnceLock<_ReverseInstructionsMasks> = OnceLock::new();
 pub fn set_instructions_funcs() {
+    REVERSE_INSTRUCTIONS_MASKS.get_or_init(build_instructions_funcs);
+}
+fn build_instructions_funcs() -> _ReverseInstructionsMasks {
     let mut instru_funcs: _ReverseInstructionsMasks = std::array::from_fn(|_| Vec::new());
     for (name, format, mask, fun) in INSTRUCTIONS32.iter() {
         let opcode = Instruction32(mask.0).opcode();
         instru_funcs[opcode as usize].push((name, *format, *mask, *fun));
     }
-    unsafe{REVERSE_INSTRUCTIONS_MASKS.set(instru_funcs).unwrap()}
+    instru_funcs
 }
\ No newline at end of file
@@ -5,7 +5,7 @@ pub enum SavedBy {
     Callee,
     None
 }
-pub const REGS: [&'static str; 32] = [
+pub const REGS: [&str; 32] = [
     "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", 
     "s0", "s1", // or "fp"
     "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", 
@@ -56,13 +56,10 @@ pub enum Reg {
 }
 
 impl Reg {
+    /// The register of a 5 bits register field, the bits above it are ignored
     pub fn new(reg: u8) -> Self {
-        if reg <= 31 {
-            // SAFETY: We checked the range, so it's safe to cast to Reg
-            unsafe { std::mem::transmute(reg) }
-        } else {
-            todo!()
-        }
+        // SAFETY: The mask keeps the value in the range of Reg, so it's safe to cast to Reg
+        unsafe { std::mem::transmute::<u8, Reg>(reg & 0b11111) }
     }   
 }
 
@@ -0,0 +1,260 @@
+use bit_field::BitField;
+
+use crate::uguest;
+use super::csr::file::{
+    HEDELEG, HIDELEG, HSTATUS, HTINST, HTVAL, MCAUSE, MEDELEG, MEPC, MIDELEG, MIE, MIP, MISA, MSTATUS, MTINST, MTVAL, MTVAL2, MTVEC,
+    SCAUSE, SEPC, STVAL, STVEC, VSCAUSE, VSEPC, VSSTATUS, VSTVAL, VSTVEC,
+};
+use super::{PrivilegeLevel, CPU};
+
+/// Table 14. Machine cause register (mcause) values after trap (Interrupt = 0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6, // Also AMO
+    StoreAccessFault = 7, // Also AMO
+    UserEcall = 8,
+    SupervisorEcall = 9,
+    VirtualSupervisorEcall = 10,
+    MachineEcall = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15, // Also AMO
+    InstructionGuestPageFault = 20,
+    LoadGuestPageFault = 21,
+    VirtualInstruction = 22,
+    StoreGuestPageFault = 23, // Also AMO
+}
+
+/// Table 14. Machine cause register (mcause) values after trap (Interrupt = 1), the bit of each in mip/mie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    VirtualSupervisorSoftware = 2,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    VirtualSupervisorTimer = 6,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    VirtualSupervisorExternal = 10,
+    MachineExternal = 11,
+}
+impl Interrupt {
+    /// 3.1.9. Simultaneous interrupts are taken in this order, VS ones come after the S ones (21.2.3)
+    const PRIORITY: [Self; 9] = [
+        Self::MachineExternal, Self::MachineSoftware, Self::MachineTimer,
+        Self::SupervisorExternal, Self::SupervisorSoftware, Self::SupervisorTimer,
+        Self::VirtualSupervisorExternal, Self::VirtualSupervisorSoftware, Self::VirtualSupervisorTimer,
+    ];
+}
+
+/// Mode a trap is handled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handler {
+    Machine,
+    /// HS-mode, or S-mode without the H extension
+    Supervisor,
+    VirtualSupervisor,
+}
+
+impl CPU {
+    /// Takes a synchronous trap, the instruction that raised it doesn't retire
+    /// `tval` is the faulting address, or 0 when the exception has none
+    /// Exceptions delegated in medeleg go to S-mode, unless they happen in M-mode, guests handle the ones also delegated in hedeleg
+    pub fn exception(&mut self, cause: Exception, tval: uguest) {
+        self.trapped = true;
+        let delegated = |csr: u16| self.csrs[csr as usize].0.get_bit(cause as usize);
+        let handler = if self.privilege_level == PrivilegeLevel::Machine || !delegated(MEDELEG) {
+            Handler::Machine
+        } else if self.virt && delegated(HEDELEG) {
+            Handler::VirtualSupervisor
+        } else {
+            Handler::Supervisor
+        };
+        self.trap(cause as uguest, tval, handler);
+    }
+    /// Highest priority interrupt that is pending, enabled and not masked at the current privilege level
+    /// Interrupts delegated in mideleg are never taken in M-mode, the others are always taken below M-mode
+    /// The ones also delegated in hideleg only interrupt guests, which they always do from HS-mode
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.csr_value(MIP) & self.csrs[MIE as usize].0;
+        if pending == 0 {return None}
+        let mstatus = self.csrs[MSTATUS as usize].0;
+        Interrupt::PRIORITY.into_iter().filter(|irq| pending.get_bit(*irq as usize)).find(|irq| match self.interrupt_handler(*irq) {
+            Handler::Machine => self.privilege_level < PrivilegeLevel::Machine || mstatus.get_bit(3),
+            Handler::Supervisor => {
+                self.virt || self.privilege_level < PrivilegeLevel::Supervisor
+                    || (self.privilege_level == PrivilegeLevel::Supervisor && mstatus.get_bit(1))
+            },
+            Handler::VirtualSupervisor => {
+                self.virt && (self.privilege_level == PrivilegeLevel::User || self.csrs[VSSTATUS as usize].0.get_bit(1))
+            },
+        })
+    }
+    fn interrupt_handler(&self, irq: Interrupt) -> Handler {
+        if !self.csr_value(MIDELEG).get_bit(irq as usize) {
+            Handler::Machine
+        } else if self.csrs[HIDELEG as usize].0.get_bit(irq as usize) {
+            Handler::VirtualSupervisor
+        } else {
+            Handler::Supervisor
+        }
+    }
+    /// Takes an interrupt before the instruction at pc, which will be executed after the handler returns
+    pub fn interrupt(&mut self, irq: Interrupt) {
+        let handler = self.interrupt_handler(irq);
+        // Guests see VS interrupts as their S ones
+        let code = if handler == Handler::VirtualSupervisor {irq as uguest - 1} else {irq as uguest};
+        self.trap(1 << 63 | code, 0, handler);
+    }
+    fn trap(&mut self, cause: uguest, tval: uguest, handler: Handler) {
+        // tval of the memory exceptions of guests is a guest virtual address, misaligned ones don't fault in `mmu`
+        let guest_fault = self.guest_fault.take();
+        let misaligned = !cause.get_bit(63) && matches!(cause, 0 | 4 | 6);
+        let gva = guest_fault.is_some() || (self.virt && misaligned);
+        let gpa = guest_fault.unwrap_or(0);
+        let mut mstatus = self.csrs[MSTATUS as usize].0;
+        let (tvec, epc, cause_csr, tval_csr) = match handler {
+            Handler::VirtualSupervisor => {
+                let mut vsstatus = self.csrs[VSSTATUS as usize].0;
+                vsstatus.set_bit(5, vsstatus.get_bit(1)); // SPIE = SIE
+                vsstatus.set_bit(1, false);
+                vsstatus.set_bit(8, self.privilege_level == PrivilegeLevel::Supervisor); // SPP
+                self.csrs[VSSTATUS as usize].0 = vsstatus;
+                self.privilege_level = PrivilegeLevel::Supervisor;
+                (VSTVEC, VSEPC, VSCAUSE, VSTVAL)
+            },
+            Handler::Supervisor => {
+                mstatus.set_bit(5, mstatus.get_bit(1)); // SPIE = SIE
+                mstatus.set_bit(1, false);
+                mstatus.set_bit(8, self.privilege_level == PrivilegeLevel::Supervisor); // SPP
+                let hstatus = &mut self.csrs[HSTATUS as usize].0;
+                hstatus.set_bit(7, self.virt); // SPV
+                if self.virt {hstatus.set_bit(8, self.privilege_level == PrivilegeLevel::Supervisor);} // SPVP
+                hstatus.set_bit(6, gva);
+                self.csrs[HTVAL as usize].0 = gpa;
+                self.csrs[HTINST as usize].0 = 0;
+                self.privilege_level = PrivilegeLevel::Supervisor;
+                self.virt = false;
+                (STVEC, SEPC, SCAUSE, STVAL)
+            },
+            Handler::Machine => {
+                mstatus.set_bit(7, mstatus.get_bit(3)); // MPIE = MIE
+                mstatus.set_bit(3, false);
+                mstatus.set_bits(11..=12, self.privilege_level as uguest); // MPP
+                mstatus.set_bit(39, self.virt); // MPV
+                mstatus.set_bit(38, gva); // GVA
+                self.csrs[MTVAL2 as usize].0 = gpa;
+                self.csrs[MTINST as usize].0 = 0;
+                self.privilege_level = PrivilegeLevel::Machine;
+                self.virt = false;
+                (MTVEC, MEPC, MCAUSE, MTVAL)
+            },
+        };
+        self.csrs[MSTATUS as usize].0 = mstatus;
+        self.csrs[epc as usize].0 = self.pc;
+        self.csrs[cause_csr as usize].0 = cause;
+        self.csrs[tval_csr as usize].0 = tval;
+        let tvec = self.csrs[tvec as usize].0;
+        let base = tvec & !0b11;
+        // Exceptions always go to BASE, even in vectored mode
+        self.next_pc = if tvec & 0b11 == 1 && cause.get_bit(63) {
+            base.wrapping_add(4 * (cause & 0xFF))
+        } else {
+            base
+        };
+    }
+    /// 3.3.2. Trap-Return Instructions
+    pub fn mret(&mut self) -> Result<(), Exception> {
+        if self.privilege_level < PrivilegeLevel::Machine {return Err(Exception::IllegalInstruction)}
+        let mut mstatus = self.csrs[MSTATUS as usize].0;
+        let mpp = PrivilegeLevel::from_bits(mstatus.get_bits(11..=12));
+        mstatus.set_bit(3, mstatus.get_bit(7)); // MIE = MPIE
+        mstatus.set_bit(7, true);
+        mstatus.set_bits(11..=12, PrivilegeLevel::User as uguest);
+        if mpp != PrivilegeLevel::Machine {mstatus.set_bit(17, false);} // MPRV
+        self.virt = mpp != PrivilegeLevel::Machine && mstatus.get_bit(39); // MPV
+        mstatus.set_bit(39, false);
+        self.csrs[MSTATUS as usize].0 = mstatus;
+        self.privilege_level = mpp;
+        self.next_pc = self.csrs[MEPC as usize].0;
+        Ok(())
+    }
+    /// Returns to the mode in sstatus.SPP (and hstatus.SPV), guests return with vsstatus
+    pub fn sret(&mut self) -> Result<(), Exception> {
+        if self.virt {
+            // VTSR traps the guest's SRET to HS-mode
+            if self.privilege_level == PrivilegeLevel::User || self.csrs[HSTATUS as usize].0.get_bit(22) {
+                return Err(Exception::VirtualInstruction)
+            }
+            let mut vsstatus = self.csrs[VSSTATUS as usize].0;
+            let spp = if vsstatus.get_bit(8) {PrivilegeLevel::Supervisor} else {PrivilegeLevel::User};
+            vsstatus.set_bit(1, vsstatus.get_bit(5)); // SIE = SPIE
+            vsstatus.set_bit(5, true);
+            vsstatus.set_bit(8, false);
+            self.csrs[VSSTATUS as usize].0 = vsstatus;
+            self.privilege_level = spp;
+            self.next_pc = self.csrs[VSEPC as usize].0;
+            return Ok(())
+        }
+        let mut mstatus = self.csrs[MSTATUS as usize].0;
+        // Trap SRET
+        if self.privilege_level < PrivilegeLevel::Supervisor || (self.privilege_level == PrivilegeLevel::Supervisor && mstatus.get_bit(22)) {
+            return Err(Exception::IllegalInstruction)
+        }
+        let spp = if mstatus.get_bit(8) {PrivilegeLevel::Supervisor} else {PrivilegeLevel::User};
+        mstatus.set_bit(1, mstatus.get_bit(5)); // SIE = SPIE
+        mstatus.set_bit(5, true);
+        mstatus.set_bit(8, false);
+        mstatus.set_bit(17, false); // MPRV
+        self.csrs[MSTATUS as usize].0 = mstatus;
+        let hstatus = &mut self.csrs[HSTATUS as usize].0;
+        self.virt = hstatus.get_bit(7); // SPV
+        hstatus.set_bit(7, false);
+        self.privilege_level = spp;
+        self.next_pc = self.csrs[SEPC as usize].0;
+        Ok(())
+    }
+
+    /// Exception of instructions guests can't execute that HS-mode could, the hypervisor emulates them
+    pub fn privileged_instruction(&self) -> Exception {
+        if self.virt {Exception::VirtualInstruction} else {Exception::IllegalInstruction}
+    }
+    /// WFI stops the hart until an interrupt is pending (see `VM::step`), but it traps right away when it is made to trap
+    /// after a time limit (mstatus.TW below M-mode, hstatus.VTW in VS-mode and always in VU-mode)
+    pub fn wfi(&mut self) -> Result<(), Exception> {
+        if self.privilege_level < PrivilegeLevel::Machine && self.csrs[MSTATUS as usize].0.get_bit(21) {
+            return Err(Exception::IllegalInstruction)
+        }
+        if self.virt && (self.privilege_level == PrivilegeLevel::User || self.csrs[HSTATUS as usize].0.get_bit(21)) {
+            return Err(Exception::VirtualInstruction)
+        }
+        self.waiting = true;
+        Ok(())
+    }
+    /// SFENCE.VMA, nothing is cached so only its traps are checked (mstatus.TVM in S-mode, hstatus.VTVM in VS-mode)
+    pub fn sfence_vma(&self) -> Result<(), Exception> {
+        let tvm = if self.virt {self.csrs[HSTATUS as usize].0.get_bit(20)} else {self.csrs[MSTATUS as usize].0.get_bit(20)};
+        if self.privilege_level == PrivilegeLevel::User || (self.privilege_level == PrivilegeLevel::Supervisor && tvm) {
+            return Err(self.privileged_instruction())
+        }
+        Ok(())
+    }
+    /// HFENCE.VVMA and HFENCE.GVMA, HS-mode and M-mode only, mstatus.TVM also traps the G-stage one
+    pub fn hfence(&self, gvma: bool) -> Result<(), Exception> {
+        if self.csrs[MISA as usize].0 & super::isa::bit('h') == 0 {return Err(Exception::IllegalInstruction)}
+        if self.virt {return Err(Exception::VirtualInstruction)}
+        let tvm = self.csrs[MSTATUS as usize].0.get_bit(20);
+        if self.privilege_level == PrivilegeLevel::User || (gvma && self.privilege_level == PrivilegeLevel::Supervisor && tvm) {
+            return Err(Exception::IllegalInstruction)
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,735 @@
+// V extension (RVV 1.0): 32 vector registers of VLEN bits, vtype/vl set by vsetvli/vsetivli/vsetvl,
+// vector loads and stores and the integer OP-V instructions (floating-point, widening and narrowing ones aren't implemented)
+// Inactive and tail elements are left undisturbed, which is a legal implementation of the agnostic policies too
+use bit_field::BitField;
+
+use super::csr::file::*;
+use super::instructions::Instruction32;
+use super::isa::bit;
+use super::trap::Exception;
+use super::CPU;
+use crate::{iguest, uguest};
+
+/// vtype of an illegal configuration, the other bits are 0
+pub const VILL: uguest = 1 << 63;
+/// ELEN, widest element
+const ELEN: usize = 64;
+pub const DEFAULT_VLEN: usize = 128;
+
+// funct3 of OP-V, the category of the operands
+pub const OPIVV: u32 = 0b000;
+pub const OPMVV: u32 = 0b010;
+pub const OPIVI: u32 = 0b011;
+pub const OPIVX: u32 = 0b100;
+pub const OPMVX: u32 = 0b110;
+pub const OPCFG: u32 = 0b111;
+
+/// Instructions with a funct6 of their own: name, funct6 and the funct3 they exist with
+pub const ARITHMETIC: [(&str, u32, &[u32]); 53] = [
+    ("vadd",    0b000000, &[OPIVV, OPIVX, OPIVI]),
+    ("vsub",    0b000010, &[OPIVV, OPIVX]),
+    ("vrsub",   0b000011, &[OPIVX, OPIVI]),
+    ("vminu",   0b000100, &[OPIVV, OPIVX]),
+    ("vmin",    0b000101, &[OPIVV, OPIVX]),
+    ("vmaxu",   0b000110, &[OPIVV, OPIVX]),
+    ("vmax",    0b000111, &[OPIVV, OPIVX]),
+    ("vand",    0b001001, &[OPIVV, OPIVX, OPIVI]),
+    ("vor",     0b001010, &[OPIVV, OPIVX, OPIVI]),
+    ("vxor",    0b001011, &[OPIVV, OPIVX, OPIVI]),
+    ("vmseq",   0b011000, &[OPIVV, OPIVX, OPIVI]),
+    ("vmsne",   0b011001, &[OPIVV, OPIVX, OPIVI]),
+    ("vmsltu",  0b011010, &[OPIVV, OPIVX]),
+    ("vmslt",   0b011011, &[OPIVV, OPIVX]),
+    ("vmsleu",  0b011100, &[OPIVV, OPIVX, OPIVI]),
+    ("vmsle",   0b011101, &[OPIVV, OPIVX, OPIVI]),
+    ("vmsgtu",  0b011110, &[OPIVX, OPIVI]),
+    ("vmsgt",   0b011111, &[OPIVX, OPIVI]),
+    ("vsaddu",  0b100000, &[OPIVV, OPIVX, OPIVI]),
+    ("vsadd",   0b100001, &[OPIVV, OPIVX, OPIVI]),
+    ("vssubu",  0b100010, &[OPIVV, OPIVX]),
+    ("vssub",   0b100011, &[OPIVV, OPIVX]),
+    ("vsll",    0b100101, &[OPIVV, OPIVX, OPIVI]),
+    ("vsrl",    0b101000, &[OPIVV, OPIVX, OPIVI]),
+    ("vsra",    0b101001, &[OPIVV, OPIVX, OPIVI]),
+    ("vredsum", 0b000000, &[OPMVV]),
+    ("vredand", 0b000001, &[OPMVV]),
+    ("vredor",  0b000010, &[OPMVV]),
+    ("vredxor", 0b000011, &[OPMVV]),
+    ("vredminu", 0b000100, &[OPMVV]),
+    ("vredmin", 0b000101, &[OPMVV]),
+    ("vredmaxu", 0b000110, &[OPMVV]),
+    ("vredmax", 0b000111, &[OPMVV]),
+    ("vmandn",  0b011000, &[OPMVV]),
+    ("vmand",   0b011001, &[OPMVV]),
+    ("vmor",    0b011010, &[OPMVV]),
+    ("vmxor",   0b011011, &[OPMVV]),
+    ("vmorn",   0b011100, &[OPMVV]),
+    ("vmnand",  0b011101, &[OPMVV]),
+    ("vmnor",   0b011110, &[OPMVV]),
+    ("vmxnor",  0b011111, &[OPMVV]),
+    ("vdivu",   0b100000, &[OPMVV, OPMVX]),
+    ("vdiv",    0b100001, &[OPMVV, OPMVX]),
+    ("vremu",   0b100010, &[OPMVV, OPMVX]),
+    ("vrem",    0b100011, &[OPMVV, OPMVX]),
+    ("vmulhu",  0b100100, &[OPMVV, OPMVX]),
+    ("vmul",    0b100101, &[OPMVV, OPMVX]),
+    ("vmulhsu", 0b100110, &[OPMVV, OPMVX]),
+    ("vmulh",   0b100111, &[OPMVV, OPMVX]),
+    ("vmadd",   0b101001, &[OPMVV, OPMVX]),
+    ("vnmsub",  0b101011, &[OPMVV, OPMVX]),
+    ("vmacc",   0b101101, &[OPMVV, OPMVX]),
+    ("vnmsac",  0b101111, &[OPMVV, OPMVX]),
+];
+/// vmerge.v*m and vmv.v.*, told apart by vm
+pub const MERGE: u32 = 0b010111;
+/// vmv<nr>r.v, OPIVI
+pub const MOVE_WHOLE: u32 = 0b100111;
+/// vmv.x.s, vcpop.m and vfirst.m with OPMVV, vmv.s.x with OPMVX
+pub const UNARY_X: u32 = 0b010000;
+/// vzext.vf* and vsext.vf*, the source is told by vs1
+pub const EXTEND: u32 = 0b010010;
+pub const EXTENSIONS: [(&str, u32); 6] = [("vzext.vf8", 0b00010), ("vsext.vf8", 0b00011), ("vzext.vf4", 0b00100), ("vsext.vf4", 0b00101), ("vzext.vf2", 0b00110), ("vsext.vf2", 0b00111)];
+/// vmsbf.m, vmsof.m, vmsif.m, viota.m and vid.v, told by vs1
+pub const UNARY_MASK: u32 = 0b010100;
+pub const MASK_UNARIES: [(&str, u32); 5] = [("vmsbf.m", 0b00001), ("vmsof.m", 0b00010), ("vmsif.m", 0b00011), ("viota.m", 0b10000), ("vid.v", 0b10001)];
+
+pub fn find(funct6: u32, funct3: u32) -> Option<&'static str> {
+    ARITHMETIC.iter().find(|(_, f6, forms)| *f6 == funct6 && forms.contains(&funct3)).map(|(name, ..)| *name)
+}
+/// Multiply-adds take the multiplier first, vd += vs1 * vs2
+pub fn multiply_add(name: &str) -> bool {
+    matches!(name, "vmadd" | "vnmsub" | "vmacc" | "vnmsac")
+}
+/// vmand.mm and the other instructions between masks
+pub fn mask_logical(name: &str) -> bool {
+    matches!(name, "vmandn" | "vmand" | "vmor" | "vmxor" | "vmorn" | "vmnand" | "vmnor" | "vmxnor")
+}
+/// Suffix of an instruction for its funct3 (vv, vx, vi) or kind (vs, mm)
+pub fn suffix(name: &str, funct3: u32) -> &'static str {
+    match funct3 {
+        _ if name.starts_with("vred") => "vs",
+        _ if mask_logical(name) => "mm",
+        OPIVV | OPMVV => "vv",
+        OPIVI => "vi",
+        _ => "vx",
+    }
+}
+
+/// Assembly syntax of a vector instruction: the instructions with `bits` outside of the fields of `operands`
+/// Operands are separated by spaces: vd, vs1, vs2, vs3 (vd of stores), rd, rs1, rs2, simm and uimm (5 bits in vs1),
+/// (rs1) for addresses, vtypei (11 bits) and vtypei10 (vsetivli), v0 (vmerge) and vm (an optional v0.t)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Form {
+    pub mnemonic: String,
+    pub bits: u32,
+    pub operands: &'static str,
+}
+impl Form {
+    fn new(mnemonic: impl Into<String>, bits: u32, operands: &'static str) -> Self {
+        Self { mnemonic: mnemonic.into(), bits, operands }
+    }
+    /// Bits of the instruction the operands are written to
+    pub fn fields(&self) -> u32 {
+        self.operands.split(' ').filter_map(field).fold(0, |fields, (shift, bits)| fields | ((1 << bits) - 1) << shift)
+    }
+    pub fn matches(&self, raw: u32) -> bool {
+        raw & !self.fields() == self.bits
+    }
+}
+/// First bit and length of an operand in the encoding
+pub fn field(operand: &str) -> Option<(u32, u32)> {
+    match operand {
+        "vd" | "vs3" | "rd" => Some((7, 5)),
+        "vs1" | "rs1" | "simm" | "uimm" | "(rs1)" => Some((15, 5)),
+        "vs2" | "rs2" => Some((20, 5)),
+        "vm" => Some((25, 1)),
+        "vtypei" => Some((20, 11)),
+        "vtypei10" => Some((20, 10)),
+        _ => None,
+    }
+}
+
+static FORMS: std::sync::OnceLock<Vec<Form>> = std::sync::OnceLock::new();
+/// Every vector instruction the emulator implements
+pub fn forms() -> &'static [Form] {
+    FORMS.get_or_init(build_forms)
+}
+fn build_forms() -> Vec<Form> {
+    const OP_V: u32 = 0b1010111;
+    const VM: u32 = 1 << 25;
+    let op = |funct6: u32, funct3: u32| funct6 << 26 | funct3 << 12 | OP_V;
+    let mut forms = Vec::new();
+    for (name, funct6, funct3s) in ARITHMETIC {
+        for &funct3 in funct3s {
+            let mnemonic = format!("{name}.{}", suffix(name, funct3));
+            let bits = op(funct6, funct3);
+            forms.push(match funct3 {
+                _ if multiply_add(name) && funct3 == OPMVV => Form::new(mnemonic, bits, "vd vs1 vs2 vm"),
+                _ if multiply_add(name) => Form::new(mnemonic, bits, "vd rs1 vs2 vm"),
+                // Mask logical instructions are always unmasked
+                _ if mask_logical(name) => Form::new(mnemonic, bits | VM, "vd vs2 vs1"),
+                OPIVV | OPMVV => Form::new(mnemonic, bits, "vd vs2 vs1 vm"),
+                OPIVI if matches!(name, "vsll" | "vsrl" | "vsra") => Form::new(mnemonic, bits, "vd vs2 uimm vm"),
+                OPIVI => Form::new(mnemonic, bits, "vd vs2 simm vm"),
+                _ => Form::new(mnemonic, bits, "vd vs2 rs1 vm"),
+            });
+        }
+    }
+    forms.extend([
+        Form::new("vmerge.vvm", op(MERGE, OPIVV), "vd vs2 vs1 v0"),
+        Form::new("vmerge.vxm", op(MERGE, OPIVX), "vd vs2 rs1 v0"),
+        Form::new("vmerge.vim", op(MERGE, OPIVI), "vd vs2 simm v0"),
+        Form::new("vmv.v.v", op(MERGE, OPIVV) | VM, "vd vs1"),
+        Form::new("vmv.v.x", op(MERGE, OPIVX) | VM, "vd rs1"),
+        Form::new("vmv.v.i", op(MERGE, OPIVI) | VM, "vd simm"),
+        Form::new("vmv.x.s", op(UNARY_X, OPMVV) | VM, "rd vs2"),
+        Form::new("vcpop.m", op(UNARY_X, OPMVV) | 0b10000 << 15, "rd vs2 vm"),
+        Form::new("vfirst.m", op(UNARY_X, OPMVV) | 0b10001 << 15, "rd vs2 vm"),
+        Form::new("vmv.s.x", op(UNARY_X, OPMVX) | VM, "vd rs1"),
+        Form::new("vsetvli", OPCFG << 12 | OP_V, "rd rs1 vtypei"),
+        Form::new("vsetivli", 0b11 << 30 | OPCFG << 12 | OP_V, "rd uimm vtypei10"),
+        Form::new("vsetvl", 0b1000000 << 25 | OPCFG << 12 | OP_V, "rd rs1 rs2"),
+    ]);
+    for registers in [1, 2, 4, 8] {
+        forms.push(Form::new(format!("vmv{registers}r.v"), op(MOVE_WHOLE, OPIVI) | VM | (registers - 1) << 15, "vd vs2"));
+    }
+    for (mnemonic, vs1) in EXTENSIONS {
+        forms.push(Form::new(mnemonic, op(EXTEND, OPMVV) | vs1 << 15, "vd vs2 vm"));
+    }
+    for (mnemonic, vs1) in MASK_UNARIES {
+        forms.push(Form::new(mnemonic, op(UNARY_MASK, OPMVV) | vs1 << 15, if mnemonic == "vid.v" {"vd vm"} else {"vd vs2 vm"}));
+    }
+    // Loads and stores
+    const LOAD: u32 = 0b0000111;
+    const STORE: u32 = 0b0100111;
+    let access = |nf: u32, mop: u32, umop: u32, width: u32| (nf - 1) << 29 | mop << 26 | umop << 20 | width << 12;
+    for (width, eew) in [(0b000, 8), (0b101, 16), (0b110, 32), (0b111, 64)] {
+        for nf in 1..=8 {
+            let seg = if nf == 1 {String::new()} else {format!("seg{nf}")};
+            forms.extend([
+                Form::new(format!("vl{seg}e{eew}.v"), access(nf, 0, 0, width) | LOAD, "vd (rs1) vm"),
+                Form::new(format!("vl{seg}e{eew}ff.v"), access(nf, 0, FAULT_ONLY_FIRST, width) | LOAD, "vd (rs1) vm"),
+                Form::new(format!("vls{seg}e{eew}.v"), access(nf, 0b10, 0, width) | LOAD, "vd (rs1) rs2 vm"),
+                Form::new(format!("vlux{seg}ei{eew}.v"), access(nf, 0b01, 0, width) | LOAD, "vd (rs1) vs2 vm"),
+                Form::new(format!("vlox{seg}ei{eew}.v"), access(nf, 0b11, 0, width) | LOAD, "vd (rs1) vs2 vm"),
+                Form::new(format!("vs{seg}e{eew}.v"), access(nf, 0, 0, width) | STORE, "vs3 (rs1) vm"),
+                Form::new(format!("vss{seg}e{eew}.v"), access(nf, 0b10, 0, width) | STORE, "vs3 (rs1) rs2 vm"),
+                Form::new(format!("vsux{seg}ei{eew}.v"), access(nf, 0b01, 0, width) | STORE, "vs3 (rs1) vs2 vm"),
+                Form::new(format!("vsox{seg}ei{eew}.v"), access(nf, 0b11, 0, width) | STORE, "vs3 (rs1) vs2 vm"),
+            ]);
+        }
+        for nf in [1, 2, 4, 8] {
+            forms.push(Form::new(format!("vl{nf}re{eew}.v"), access(nf, 0, WHOLE_REGISTERS, width) | VM | LOAD, "vd (rs1)"));
+            if eew == 8 {
+                forms.push(Form::new(format!("vs{nf}r.v"), access(nf, 0, WHOLE_REGISTERS, width) | VM | STORE, "vs3 (rs1)"));
+            }
+        }
+    }
+    forms.push(Form::new("vlm.v", access(1, 0, MASK, 0) | VM | LOAD, "vd (rs1)"));
+    forms.push(Form::new("vsm.v", access(1, 0, MASK, 0) | VM | STORE, "vs3 (rs1)"));
+    forms
+}
+
+/// Vector register file, register `r` is `regs[r*VLEN/8..(r+1)*VLEN/8]` so register groups are contiguous
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    pub vlen: usize,
+    pub regs: Vec<u8>,
+}
+impl Default for Vector {
+    fn default() -> Self {
+        Self::new(DEFAULT_VLEN)
+    }
+}
+impl Vector {
+    /// `vlen` bits per register, a power of 2 between 128 and 65536
+    pub fn new(vlen: usize) -> Self {
+        Self { vlen, regs: vec![0; 32 * vlen / 8] }
+    }
+    pub fn vlenb(&self) -> usize {
+        self.vlen / 8
+    }
+    fn offset(&self, reg: usize, index: usize, eew: usize) -> usize {
+        reg * self.vlenb() + index * eew / 8
+    }
+    /// Element `index` of `eew` bits of the group starting at `reg`, zero-extended
+    pub fn get(&self, reg: usize, index: usize, eew: usize) -> uguest {
+        let offset = self.offset(reg, index, eew);
+        let mut bytes = [0; 8];
+        bytes[..eew/8].copy_from_slice(&self.regs[offset..offset + eew/8]);
+        uguest::from_le_bytes(bytes)
+    }
+    pub fn set(&mut self, reg: usize, index: usize, eew: usize, value: uguest) {
+        let offset = self.offset(reg, index, eew);
+        self.regs[offset..offset + eew/8].copy_from_slice(&value.to_le_bytes()[..eew/8]);
+    }
+    /// Bit `index` of mask register `reg`
+    pub fn mask(&self, reg: usize, index: usize) -> bool {
+        self.regs[reg * self.vlenb() + index / 8].get_bit(index % 8)
+    }
+    pub fn set_mask(&mut self, reg: usize, index: usize, value: bool) {
+        let vlenb = self.vlenb();
+        self.regs[reg * vlenb + index / 8].set_bit(index % 8, value);
+    }
+}
+
+/// Legal vtype
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VType {
+    /// Bits per element
+    pub sew: usize,
+    /// LMUL times 8, 1 for mf8 to 64 for m8
+    pub lmul8: usize,
+    pub tail_agnostic: bool,
+    pub mask_agnostic: bool,
+}
+impl VType {
+    /// None for illegal or reserved values, which set vill
+    pub fn decode(vtype: uguest) -> Option<Self> {
+        if vtype >> 8 != 0 {return None}
+        let lmul8 = match vtype & 0b111 {
+            vlmul @ 0..=3 => 8 << vlmul,
+            4 => return None,
+            vlmul => 1 << (vlmul - 5),
+        };
+        let sew = match (vtype >> 3) & 0b111 {
+            vsew @ 0..=3 => 8 << vsew,
+            _ => return None,
+        };
+        // Fractional LMUL needs SEW <= LMUL * ELEN
+        if sew * 8 > lmul8 * ELEN {return None}
+        Some(Self { sew, lmul8, tail_agnostic: vtype.get_bit(6), mask_agnostic: vtype.get_bit(7) })
+    }
+    pub fn encode(self) -> uguest {
+        let vlmul = match self.lmul8 {
+            8.. => self.lmul8.trailing_zeros() - 3,
+            _ => self.lmul8.trailing_zeros() + 5,
+        };
+        vlmul as uguest | ((self.sew.trailing_zeros() - 3) as uguest) << 3 | (self.tail_agnostic as uguest) << 6 | (self.mask_agnostic as uguest) << 7
+    }
+    pub fn vlmax(self, vlen: usize) -> usize {
+        vlen * self.lmul8 / 8 / self.sew
+    }
+    /// Assembly form, e.g. e32 m1 ta ma
+    pub fn text(self) -> String {
+        let lmul = if self.lmul8 >= 8 {format!("m{}", self.lmul8 / 8)} else {format!("mf{}", 8 / self.lmul8)};
+        let policy = |agnostic| if agnostic {"a"} else {"u"};
+        format!("e{} {lmul} t{} m{}", self.sew, policy(self.tail_agnostic), policy(self.mask_agnostic))
+    }
+    /// Parses the operands `text` writes
+    pub fn parse(operands: &[&str]) -> Option<Self> {
+        let [sew, lmul, tail, mask] = operands else {return None};
+        let sew = sew.strip_prefix('e')?.parse().ok()?;
+        let lmul8 = match lmul.strip_prefix("mf") {
+            Some(fraction) => 8 / fraction.parse::<usize>().ok().filter(|fraction| matches!(fraction, 2 | 4 | 8))?,
+            None => 8 * lmul.strip_prefix('m')?.parse::<usize>().ok().filter(|lmul| matches!(lmul, 1 | 2 | 4 | 8))?,
+        };
+        let agnostic = |policy: &str, prefix| match policy.strip_prefix(prefix)? {"a" => Some(true), "u" => Some(false), _ => None};
+        let vtype = Self { sew, lmul8, tail_agnostic: agnostic(tail, 't')?, mask_agnostic: agnostic(mask, 'm')? };
+        matches!(sew, 8 | 16 | 32 | 64).then_some(vtype)
+    }
+}
+
+/// Registers of a group of EMUL (times 8) `emul8`, fractional ones use a register
+fn group(emul8: usize) -> usize {
+    (emul8 / 8).max(1)
+}
+/// Register groups have to be aligned on their size
+fn aligned(reg: usize, emul8: usize) -> Result<(), Exception> {
+    if !reg.is_multiple_of(group(emul8)) {return Err(Exception::IllegalInstruction)}
+    Ok(())
+}
+fn mask(sew: usize) -> uguest {
+    if sew == 64 {uguest::MAX} else {(1 << sew) - 1}
+}
+fn sext(value: uguest, sew: usize) -> iguest {
+    ((value << (64 - sew)) as iguest) >> (64 - sew)
+}
+
+impl CPU {
+    /// Vector instructions and CSRs need V in misa and mstatus.VS on
+    pub fn vector_enabled(&self) -> bool {
+        self.csrs[MISA as usize].0 & bit('v') != 0 && self.csrs[MSTATUS as usize].0.get_bits(9..=10) != 0
+            && (!self.virt || self.csrs[VSSTATUS as usize].0.get_bits(9..=10) != 0)
+    }
+    /// The vector state changed, mstatus.VS (and vsstatus.VS for guests) becomes dirty
+    pub fn vector_dirty(&mut self) {
+        self.csrs[MSTATUS as usize].0.set_bits(9..=10, 0b11);
+        if self.virt {self.csrs[VSSTATUS as usize].0.set_bits(9..=10, 0b11);}
+    }
+    pub fn vtype(&self) -> Option<VType> {
+        VType::decode(self.csrs[VTYPE as usize].0)
+    }
+    fn vl(&self) -> usize {
+        self.csrs[VL as usize].0 as usize
+    }
+    fn vstart(&self) -> usize {
+        self.csrs[VSTART as usize].0 as usize
+    }
+    /// Configuration of an instruction that works on elements, illegal when vtype is
+    fn vector_config(&self) -> Result<VType, Exception> {
+        if !self.vector_enabled() {return Err(Exception::IllegalInstruction)}
+        self.vtype().ok_or(Exception::IllegalInstruction)
+    }
+    /// Sets vtype and vl from the requested vector length, like vset{i}vl{i}, returns the new vl
+    pub fn set_vl(&mut self, avl: uguest, vtype: uguest) -> uguest {
+        let (vtype, vl) = match VType::decode(vtype) {
+            Some(config) => (vtype, avl.min(config.vlmax(self.vector.vlen) as uguest)),
+            None => (VILL, 0),
+        };
+        self.csrs[VTYPE as usize].0 = vtype;
+        self.csrs[VL as usize].0 = vl;
+        self.csrs[VSTART as usize].0 = 0;
+        self.vector_dirty();
+        vl
+    }
+}
+
+/// OP-V: vset*, arithmetic, masks and reductions
+pub fn op_v(vm: &mut crate::vm::VM, instruction: Instruction32) {
+    if let Err(cause) = execute(&mut vm.cpu, instruction) {
+        vm.cpu.exception(cause, instruction.0 as uguest);
+    }
+}
+
+fn execute(cpu: &mut CPU, instruction: Instruction32) -> Result<(), Exception> {
+    if !cpu.vector_enabled() {return Err(Exception::IllegalInstruction)}
+    let raw = instruction.0;
+    let funct3 = instruction.fun3();
+    let (vd, rs1, vs2) = (instruction._raw_rd() as usize, instruction._raw_rs1() as usize, instruction._raw_rs2() as usize);
+    if funct3 == OPCFG {
+        let (avl, vtype) = match raw >> 30 {
+            // vsetivli
+            0b11 => (rs1 as uguest, raw.get_bits(20..=29) as uguest),
+            // vsetvl
+            0b10 if raw >> 25 == 0b1000000 => (cpu.regs[rs1], cpu.regs[vs2]),
+            0b10 => return Err(Exception::IllegalInstruction),
+            // vsetvli
+            _ => (cpu.regs[rs1], raw.get_bits(20..=30) as uguest),
+        };
+        // x0 as AVL asks for VLMAX, or to keep vl when rd is x0 too
+        let avl = match (raw >> 30 == 0b11, rs1, vd) {
+            (false, 0, 0) => cpu.vl() as uguest,
+            (false, 0, _) => uguest::MAX,
+            _ => avl,
+        };
+        cpu.regs[vd] = cpu.set_vl(avl, vtype);
+        return Ok(())
+    }
+    let config = cpu.vector_config()?;
+    let (funct6, masked) = (raw >> 26, !raw.get_bit(25));
+    let (sew, vl, vstart) = (config.sew, cpu.vl(), cpu.vstart());
+    let name = find(funct6, funct3);
+    let scalar = match funct3 {
+        OPIVX | OPMVX => cpu.regs[rs1] & mask(sew),
+        // Shifts take an unsigned immediate
+        OPIVI if matches!(name, Some("vsll" | "vsrl" | "vsra")) => rs1 as uguest,
+        OPIVI => sext(rs1 as uguest, 5) as uguest & mask(sew),
+        _ => 0,
+    };
+    let vector = &mut cpu.vector;
+    let operand = |vector: &Vector, i: usize| if matches!(funct3, OPIVV | OPMVV) {vector.get(rs1, i, sew)} else {scalar};
+    // Active elements, taken before v0 can be written
+    let active: Vec<usize> = (vstart..vl).filter(|&i| !masked || vector.mask(0, i)).collect();
+    // Results that aren't masks can't overwrite the mask they're computed with
+    let check_vd = || if masked && vd == 0 {Err(Exception::IllegalInstruction)} else {aligned(vd, config.lmul8)};
+    match (funct6, funct3, name) {
+        (MERGE, OPIVV | OPIVX | OPIVI, _) => {
+            // vmv.v.* has no vs2
+            if !masked && vs2 != 0 {return Err(Exception::IllegalInstruction)}
+            aligned(vd, config.lmul8)?;
+            aligned(vs2, config.lmul8)?;
+            if funct3 == OPIVV {aligned(rs1, config.lmul8)?}
+            for i in vstart..vl {
+                let value = if vector.mask(0, i) || !masked {operand(vector, i)} else {vector.get(vs2, i, sew)};
+                vector.set(vd, i, sew, value);
+            }
+        },
+        (MOVE_WHOLE, OPIVI, _) => {
+            let registers = rs1 + 1;
+            if !matches!(registers, 1 | 2 | 4 | 8) || vd % registers != 0 || vs2 % registers != 0 {return Err(Exception::IllegalInstruction)}
+            let vlenb = vector.vlenb();
+            vector.regs.copy_within(vs2 * vlenb..(vs2 + registers) * vlenb, vd * vlenb);
+        },
+        (UNARY_X, OPMVV, _) => {
+            let mut set = active.iter().copied().filter(|&i| vector.mask(vs2, i));
+            cpu.regs[vd] = match rs1 {
+                // vmv.x.s
+                0b00000 => sext(vector.get(vs2, 0, sew), sew) as uguest,
+                0b10000 => set.count() as uguest,
+                0b10001 => set.next().map_or(uguest::MAX, |i| i as uguest),
+                _ => return Err(Exception::IllegalInstruction),
+            };
+        },
+        (UNARY_X, OPMVX, _) => {
+            // vmv.s.x
+            if vs2 != 0 {return Err(Exception::IllegalInstruction)}
+            if vstart < vl {vector.set(vd, 0, sew, scalar)}
+        },
+        (EXTEND, OPMVV, _) => {
+            let factor = match rs1 >> 1 {0b001 => 8, 0b010 => 4, 0b011 => 2, _ => return Err(Exception::IllegalInstruction)};
+            let signed = rs1 & 1 != 0;
+            let (eew, emul8) = (sew / factor, config.lmul8 / factor);
+            if eew < 8 || emul8 == 0 {return Err(Exception::IllegalInstruction)}
+            check_vd()?;
+            aligned(vs2, emul8)?;
+            for i in active.iter().copied() {
+                let value = vector.get(vs2, i, eew);
+                vector.set(vd, i, sew, if signed {sext(value, eew) as uguest & mask(sew)} else {value});
+            }
+        },
+        (UNARY_MASK, OPMVV, _) => {
+            if vd == vs2 || (masked && vd == 0) {return Err(Exception::IllegalInstruction)}
+            match rs1 {
+                0b00001..=0b00011 => {
+                    let mut seen = false;
+                    for i in active.iter().copied() {
+                        let set = vector.mask(vs2, i);
+                        let value = match rs1 {
+                            0b00001 => !seen && !set, // vmsbf
+                            0b00010 => !seen && set, // vmsof
+                            _ => !seen, // vmsif
+                        };
+                        vector.set_mask(vd, i, value);
+                        seen |= set;
+                    }
+                },
+                0b10000 | 0b10001 => {
+                    aligned(vd, config.lmul8)?;
+                    let mut count = 0;
+                    for i in active.iter().copied() {
+                        // viota counts the set bits before, vid is the index
+                        vector.set(vd, i, sew, if rs1 == 0b10001 {i as uguest} else {count} & mask(sew));
+                        count += vector.mask(vs2, i) as uguest;
+                    }
+                },
+                _ => return Err(Exception::IllegalInstruction),
+            }
+        },
+        (_, _, Some(name)) if name.starts_with("vred") => {
+            // Reductions start from vs1[0], they can't resume from a vstart
+            if vstart != 0 {return Err(Exception::IllegalInstruction)}
+            aligned(vs2, config.lmul8)?;
+            if vl > 0 {
+                let op = |a: uguest, b: uguest| match name {
+                    "vredsum" => a.wrapping_add(b),
+                    "vredand" => a & b,
+                    "vredor" => a | b,
+                    "vredxor" => a ^ b,
+                    "vredminu" => a.min(b),
+                    "vredmaxu" => a.max(b),
+                    "vredmin" => sext(a, sew).min(sext(b, sew)) as uguest,
+                    _ => sext(a, sew).max(sext(b, sew)) as uguest,
+                };
+                let result = active.iter().copied().fold(vector.get(rs1, 0, sew), |acc, i| op(acc, vector.get(vs2, i, sew)));
+                vector.set(vd, 0, sew, result & mask(sew));
+            }
+        },
+        (_, OPMVV, Some(name)) if mask_logical(name) => {
+            if masked {return Err(Exception::IllegalInstruction)}
+            for i in vstart..vl {
+                let (a, b) = (vector.mask(vs2, i), vector.mask(rs1, i));
+                let value = match name {
+                    "vmandn" => a && !b,
+                    "vmand" => a && b,
+                    "vmor" => a || b,
+                    "vmxor" => a ^ b,
+                    "vmorn" => a || !b,
+                    "vmnand" => !(a && b),
+                    "vmnor" => !(a || b),
+                    _ => a == b, // vmxnor
+                };
+                vector.set_mask(vd, i, value);
+            }
+        },
+        (_, _, Some(name)) if name.starts_with("vms") => {
+            // Comparisons write a mask
+            aligned(vs2, config.lmul8)?;
+            if funct3 == OPIVV {aligned(rs1, config.lmul8)?}
+            for i in active.iter().copied() {
+                let (a, b) = (vector.get(vs2, i, sew), operand(vector, i));
+                let (sa, sb) = (sext(a, sew), sext(b, sew));
+                let value = match name {
+                    "vmseq" => a == b,
+                    "vmsne" => a != b,
+                    "vmsltu" => a < b,
+                    "vmslt" => sa < sb,
+                    "vmsleu" => a <= b,
+                    "vmsle" => sa <= sb,
+                    "vmsgtu" => a > b,
+                    _ => sa > sb, // vmsgt
+                };
+                vector.set_mask(vd, i, value);
+            }
+        },
+        (_, _, Some(name)) => {
+            check_vd()?;
+            aligned(vs2, config.lmul8)?;
+            if matches!(funct3, OPIVV | OPMVV) {aligned(rs1, config.lmul8)?}
+            let shift = |b: uguest| (b & (sew as uguest - 1)) as u32;
+            let (min, max) = (-1 << (sew - 1), (1 << (sew - 1)) - 1);
+            let mut saturated = false;
+            for i in active.iter().copied() {
+                let (a, b, d) = (vector.get(vs2, i, sew), operand(vector, i), vector.get(vd, i, sew));
+                let (sa, sb) = (sext(a, sew) as i128, sext(b, sew) as i128);
+                let mut saturate = |value: i128, min: i128, max: i128| {
+                    saturated |= value < min || value > max;
+                    value.clamp(min, max) as uguest
+                };
+                let value = match name {
+                    "vadd" => a.wrapping_add(b),
+                    "vsub" => a.wrapping_sub(b),
+                    "vrsub" => b.wrapping_sub(a),
+                    "vminu" => a.min(b),
+                    "vmin" => sa.min(sb) as uguest,
+                    "vmaxu" => a.max(b),
+                    "vmax" => sa.max(sb) as uguest,
+                    "vand" => a & b,
+                    "vor" => a | b,
+                    "vxor" => a ^ b,
+                    "vsaddu" => saturate(a as i128 + b as i128, 0, mask(sew) as i128),
+                    "vsadd" => saturate(sa + sb, min, max),
+                    "vssubu" => saturate(a as i128 - b as i128, 0, mask(sew) as i128),
+                    "vssub" => saturate(sa - sb, min, max),
+                    "vsll" => a << shift(b),
+                    "vsrl" => a >> shift(b),
+                    "vsra" => (sext(a, sew) >> shift(b)) as uguest,
+                    "vmul" => a.wrapping_mul(b),
+                    "vmulh" => ((sa * sb) >> sew) as uguest,
+                    "vmulhu" => ((a as u128 * b as u128) >> sew) as uguest,
+                    "vmulhsu" => ((sa * b as i128) >> sew) as uguest,
+                    "vdivu" => a.checked_div(b).unwrap_or(uguest::MAX),
+                    "vremu" => a.checked_rem(b).unwrap_or(a),
+                    // Division by zero gives -1 and the remainder the dividend, the overflow wraps like the scalar ones
+                    "vdiv" if b == 0 => uguest::MAX,
+                    "vdiv" => (sa / sb) as uguest,
+                    "vrem" if b == 0 => a,
+                    "vrem" => (sa % sb) as uguest,
+                    "vmacc" => b.wrapping_mul(a).wrapping_add(d),
+                    "vnmsac" => d.wrapping_sub(b.wrapping_mul(a)),
+                    "vmadd" => b.wrapping_mul(d).wrapping_add(a),
+                    "vnmsub" => a.wrapping_sub(b.wrapping_mul(d)),
+                    _ => return Err(Exception::IllegalInstruction),
+                };
+                vector.set(vd, i, sew, value & mask(sew));
+            }
+            if saturated {cpu.csrs[VXSAT as usize].0 = 1}
+        },
+        _ => return Err(Exception::IllegalInstruction),
+    }
+    cpu.csrs[VSTART as usize].0 = 0;
+    cpu.vector_dirty();
+    Ok(())
+}
+
+/// Element width of the width field of loads and stores
+pub fn eew(width: u32) -> Option<usize> {
+    match width {
+        0b000 => Some(8),
+        0b101 => Some(16),
+        0b110 => Some(32),
+        0b111 => Some(64),
+        _ => None,
+    }
+}
+// lumop/sumop of unit-stride accesses
+pub const WHOLE_REGISTERS: u32 = 0b01000;
+pub const MASK: u32 = 0b01011;
+pub const FAULT_ONLY_FIRST: u32 = 0b10000;
+
+/// Vector loads, in the LOAD-FP opcode
+pub fn load(vm: &mut crate::vm::VM, instruction: Instruction32) {
+    if let Err((cause, tval)) = transfer(vm, instruction, false) {
+        vm.cpu.exception(cause, tval);
+    }
+}
+/// Vector stores, in the STORE-FP opcode
+pub fn store(vm: &mut crate::vm::VM, instruction: Instruction32) {
+    if let Err((cause, tval)) = transfer(vm, instruction, true) {
+        vm.cpu.exception(cause, tval);
+    }
+}
+
+/// Loads or stores the elements, faults leave the index of the element in vstart
+fn transfer(vm: &mut crate::vm::VM, instruction: Instruction32, store: bool) -> Result<(), (Exception, uguest)> {
+    let raw = instruction.0;
+    let illegal = (Exception::IllegalInstruction, raw as uguest);
+    if !vm.cpu.vector_enabled() {return Err(illegal)}
+    let fields = raw.get_bits(29..=31) as usize + 1;
+    let (mop, masked, umop) = (raw.get_bits(26..=27), !raw.get_bit(25), instruction._raw_rs2() as u32);
+    let (vd, base, vs2) = (instruction._raw_rd() as usize, vm.cpu.regs[instruction._raw_rs1() as usize], instruction._raw_rs2() as usize);
+    let eew = eew(instruction.fun3()).ok_or(illegal)?;
+    // mew, for EEW above 64
+    if raw.get_bit(28) {return Err(illegal)}
+    let vlenb = vm.cpu.vector.vlenb();
+    // Elements, their width and the register group of a field
+    let (count, data_eew, emul8) = if mop == 0 && umop == WHOLE_REGISTERS {
+        // vl<nf>r / vs<nf>r ignore vtype and vl, stores only encode EEW = 8
+        if !matches!(fields, 1 | 2 | 4 | 8) || vd % fields != 0 || masked || (store && eew != 8) {return Err(illegal)}
+        (fields * vlenb * 8 / eew, eew, 8 * fields)
+    } else {
+        let config = vm.cpu.vector_config().map_err(|_| illegal)?;
+        let vl = vm.cpu.vl();
+        match (mop, umop) {
+            // vlm.v / vsm.v, a byte per 8 elements
+            (0, MASK) if eew == 8 && !masked && fields == 1 => (vl.div_ceil(8), 8, 8),
+            (0, 0) | (0, FAULT_ONLY_FIRST) | (2, _) => (vl, eew, eew * config.lmul8 / config.sew),
+            // Indexed, the data has SEW and the index EEW
+            (1 | 3, _) => {
+                let index_emul8 = eew * config.lmul8 / config.sew;
+                if !(1..=64).contains(&index_emul8) {return Err(illegal)}
+                aligned(vs2, index_emul8).map_err(|_| illegal)?;
+                (vl, config.sew, config.lmul8)
+            },
+            _ => return Err(illegal),
+        }
+    };
+    let fault_only_first = mop == 0 && umop == FAULT_ONLY_FIRST;
+    if !(1..=64).contains(&emul8) || fields * group(emul8) > 8 || vd % group(emul8) != 0 || vd + fields * group(emul8) > 32
+        || (masked && vd == 0 && !store) || (fault_only_first && store) {
+        return Err(illegal)
+    }
+    let stride = if mop == 2 {vm.cpu.regs[vs2]} else {(fields * data_eew / 8) as uguest};
+    for i in vm.cpu.vstart()..count {
+        if masked && !vm.cpu.vector.mask(0, i) {continue}
+        let offset = match mop {
+            1 | 3 => vm.cpu.vector.get(vs2, i, eew),
+            _ => stride.wrapping_mul(i as uguest),
+        };
+        for field in 0..fields {
+            let addr = base.wrapping_add(offset).wrapping_add((field * data_eew / 8) as uguest);
+            let reg = vd + field * group(emul8);
+            let result = if store {
+                let value = vm.cpu.vector.get(reg, i, data_eew);
+                match data_eew {
+                    8 => vm.store(addr, value as u8),
+                    16 => vm.store(addr, value as u16),
+                    32 => vm.store(addr, value as u32),
+                    _ => vm.store(addr, value),
+                }
+            } else {
+                match data_eew {
+                    8 => vm.load::<u8>(addr).map(uguest::from),
+                    16 => vm.load::<u16>(addr).map(uguest::from),
+                    32 => vm.load::<u32>(addr).map(uguest::from),
+                    _ => vm.load::<u64>(addr),
+                }.map(|value| vm.cpu.vector.set(reg, i, data_eew, value))
+            };
+            if let Err(fault) = result {
+                // Fault-only-first loads only trap on the first element, the others shorten vl
+                if fault_only_first && i > 0 {
+                    vm.cpu.csrs[VL as usize].0 = i as uguest;
+                    vm.cpu.csrs[VSTART as usize].0 = 0;
+                    vm.cpu.vector_dirty();
+                    return Ok(())
+                }
+                vm.cpu.csrs[VSTART as usize].0 = i as uguest;
+                return Err((fault.exception(), fault.addr))
+            }
+        }
+    }
+    vm.cpu.csrs[VSTART as usize].0 = 0;
+    // Stores don't change the vector state
+    if !store {vm.cpu.vector_dirty()}
+    Ok(())
+}
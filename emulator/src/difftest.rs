@@ -0,0 +1,296 @@
+// Differential testing against QEMU: runs the same ELF in qemu-system-riscv64 with
+// `-d in_asm,cpu,nochain` and one instruction per translation block (`-singlestep`), so that the log has the state
+// of the hart before every instruction, then steps our emulator along the log and stops at the first instruction
+// where the pc, a general purpose register or a CSR differs.
+// QEMU starts in its reset vector (0x1000) which sets a0, a1 and t0 before jumping to the ELF's entry point,
+// the comparison starts there with the registers QEMU had. Both machines run without firmware (-bios none).
+// Timer and external interrupts depend on timing and aren't expected to line up.
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{bail, Context, ContextCompat};
+use color_eyre::Result;
+
+use crate::cpu::csr::CsrID;
+use crate::cpu::reg::REGS;
+use crate::uguest;
+use crate::vm::VM;
+
+/// CSRs compared by default, the ones QEMU dumps that don't depend on timing
+pub const DEFAULT_CSRS: &[&str] = &[
+    "mstatus", "mtvec", "mepc", "mcause", "mtval", "mie", "medeleg", "mideleg", "mscratch",
+    "stvec", "sepc", "scause", "stval", "sscratch", "satp",
+];
+/// Counters can't match between the two, their values are taken from QEMU
+const COUNTERS: &[u16] = &[0xC00, 0xC01, 0xC02, 0xB00, 0xB02];
+
+/// State of the hart before an instruction, from a `-d cpu` dump
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QemuState {
+    pub pc: uguest,
+    pub regs: [uguest; 32],
+    /// CSRs of the dump with QEMU's names, in order
+    pub csrs: Vec<(String, uguest)>,
+    /// Disassembly of the instruction at pc, from the `-d in_asm` part of the log
+    pub disasm: Option<String>,
+}
+impl QemuState {
+    pub fn csr(&self, name: &str) -> Option<uguest> {
+        self.csrs.iter().find(|(csr, _)| csr == name).map(|&(_, value)| value)
+    }
+}
+
+/// Streams the states of a QEMU log, lines that aren't part of a dump or a disassembly are skipped
+pub struct QemuLog<R> {
+    lines: std::io::Lines<R>,
+    line: usize,
+    /// in_asm only shows instructions the first time they're translated
+    disasm: HashMap<uguest, String>,
+    /// A state ends when the next one starts
+    current: Option<QemuState>,
+}
+impl<R: BufRead> QemuLog<R> {
+    pub fn new(reader: R) -> Self {
+        Self { lines: reader.lines(), line: 0, disasm: HashMap::new(), current: None }
+    }
+    /// Returns the previous state when `line` starts a new one
+    fn parse_line(&mut self, line: &str) -> Result<Option<QemuState>> {
+        // 0x0000000080000000:  00000297          auipc                   t0,0                    # 0x80000000
+        if let Some((addr, rest)) = line.strip_prefix("0x").and_then(|line| line.split_once(':')) {
+            let pc = uguest::from_str_radix(addr, 16).context("Invalid address")?;
+            let disasm = rest.split_whitespace().skip(1).collect::<Vec<_>>().join(" ");
+            self.disasm.insert(pc, disasm);
+            return Ok(None)
+        }
+        // " pc       0000000080000000", GPRs are 4 per line: " x0/zero  0000000000000000 x1/ra    0000000080000000..."
+        if !line.starts_with(' ') {return Ok(None)}
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if !tokens.len().is_multiple_of(2) {return Ok(None)}
+        let mut finished = None;
+        for pair in tokens.chunks(2) {
+            let (name, value) = (pair[0], pair[1]);
+            let Ok(value) = uguest::from_str_radix(value, 16) else {return Ok(None)};
+            if name == "pc" {
+                finished = self.current.replace(QemuState { pc: value, disasm: self.disasm.get(&value).cloned(), ..Default::default() });
+                continue
+            }
+            let Some(state) = &mut self.current else {continue};
+            if let Some((reg, _abi_name)) = name.strip_prefix('x').and_then(|name| name.split_once('/')) {
+                let reg: usize = reg.parse().context("Invalid register")?;
+                *state.regs.get_mut(reg).context("Invalid register")? = value;
+            } else if !name.contains('/') {
+                // Floating point and vector registers have an ABI name too, CSRs don't
+                state.csrs.push((name.to_string(), value));
+            }
+        }
+        Ok(finished)
+    }
+}
+impl<R: BufRead> Iterator for QemuLog<R> {
+    type Item = Result<QemuState>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next() {
+                None => return self.current.take().map(Ok),
+                Some(line) => line,
+            };
+            self.line += 1;
+            let parsed = line.map_err(Into::into).and_then(|line| self.parse_line(&line));
+            match parsed {
+                Ok(None) => {},
+                Ok(Some(state)) => return Some(Ok(state)),
+                Err(err) => return Some(Err(err.wrap_err(format!("Line {} of the QEMU log", self.line)))),
+            }
+        }
+    }
+}
+
+/// What made the comparison stop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Pc { qemu: uguest, ours: uguest },
+    Reg { reg: usize, qemu: uguest, ours: uguest },
+    Csr { name: String, qemu: uguest, ours: uguest },
+    /// Our emulator stopped (exited or fetched a zero instruction with no trap handler) while QEMU went on
+    Stopped { pc: uguest },
+}
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pc { qemu, ours } => write!(f, "pc: QEMU {qemu:#x}, emulator {ours:#x}"),
+            Self::Reg { reg, qemu, ours } => write!(f, "x{reg}/{}: QEMU {qemu:#x}, emulator {ours:#x}", REGS[*reg]),
+            Self::Csr { name, qemu, ours } => write!(f, "{name}: QEMU {qemu:#x}, emulator {ours:#x}"),
+            Self::Stopped { pc } => write!(f, "the emulator stopped at {pc:#x}, QEMU didn't"),
+        }
+    }
+}
+
+/// An instruction both executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executed {
+    pub pc: uguest,
+    pub ours: String,
+    pub qemu: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Instructions executed before it was found
+    pub instructions: u64,
+    pub mismatch: Mismatch,
+    /// Last instructions executed, the culprit is usually the last one
+    pub context: Vec<Executed>,
+    pub qemu: QemuState,
+    /// Our registers when the mismatch was found
+    pub regs: [uguest; 32],
+}
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Divergence after {} instructions, {}", self.instructions, self.mismatch)?;
+        writeln!(f, "Last instructions (emulator | QEMU):")?;
+        for executed in &self.context {
+            writeln!(f, "  {:#018x}  {:<40} | {}", executed.pc, executed.ours, executed.qemu.as_deref().unwrap_or("?"))?;
+        }
+        writeln!(f, "Registers (* differ):")?;
+        for (reg, (qemu, ours)) in self.qemu.regs.iter().zip(self.regs).enumerate() {
+            let mark = if *qemu != ours {'*'} else {' '};
+            writeln!(f, " {mark}x{reg:<2} {:<5} QEMU {qemu:#018x}  emulator {ours:#018x}", REGS[reg])?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    /// The whole log matched (or the first `max_instructions` did)
+    Match { instructions: u64 },
+    Diverged(Box<Divergence>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// QEMU names of the CSRs to compare, the ones QEMU doesn't dump or we don't have are skipped
+    pub csrs: Vec<String>,
+    /// Instructions shown before a divergence
+    pub context: usize,
+    pub max_instructions: Option<u64>,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self { csrs: DEFAULT_CSRS.iter().map(|csr| csr.to_string()).collect(), context: 10, max_instructions: None }
+    }
+}
+
+fn csr_id(name: &str) -> Option<u16> {
+    (0..0x1000).find(|&id| CsrID::Unsupported(id).info().is_some_and(|(csr, _, _)| csr == name))
+}
+
+/// Steps `vm` along the states of `log`, starting at the first one with the pc of `vm`
+pub fn compare(vm: &mut VM, log: impl IntoIterator<Item = Result<QemuState>>, options: &Options) -> Result<Outcome> {
+    let csrs: Vec<(&str, u16)> = options.csrs.iter().filter_map(|name| Some((name.as_str(), csr_id(name)?))).collect();
+    let mut log = log.into_iter();
+    let start = vm.cpu.pc;
+    let mut qemu = loop {
+        match log.next().transpose()? {
+            Some(state) if state.pc == start => break state,
+            Some(_) => {},
+            None => bail!("QEMU never reached {start:#x}"),
+        }
+    };
+    vm.cpu.regs = qemu.regs;
+    vm.trace = false;
+
+    let mut context: Vec<Executed> = Vec::new();
+    let mut counter_read = None;
+    let mut instructions = 0;
+    loop {
+        if let Some(rd) = counter_read.take() {
+            vm.cpu.regs[rd] = qemu.regs[rd];
+        }
+        let mismatch = if vm.cpu.pc != qemu.pc {
+            Some(Mismatch::Pc { qemu: qemu.pc, ours: vm.cpu.pc })
+        } else if let Some(reg) = (1..32).find(|&reg| vm.cpu.regs[reg] != qemu.regs[reg]) {
+            Some(Mismatch::Reg { reg, qemu: qemu.regs[reg], ours: vm.cpu.regs[reg] })
+        } else {
+            csrs.iter().find_map(|&(name, id)| {
+                let (theirs, ours) = (qemu.csr(name)?, vm.cpu.csr_value(id));
+                (theirs != ours).then(|| Mismatch::Csr { name: name.to_string(), qemu: theirs, ours })
+            })
+        };
+        if let Some(mismatch) = mismatch {
+            return Ok(Outcome::Diverged(Box::new(Divergence { instructions, mismatch, context, qemu, regs: vm.cpu.regs })))
+        }
+        if options.max_instructions.is_some_and(|max| instructions >= max) {break}
+
+        let pc = vm.cpu.pc;
+        let ours = match vm.fetch_instruction() {
+            Ok((instruction, size)) => {
+                let raw = instruction.0;
+                let csr = (raw >> 20) as u16;
+                if size == 4 && raw & 0x7F == 0x73 && (raw >> 12) & 0b111 != 0 && COUNTERS.contains(&csr) {
+                    counter_read = Some(((raw >> 7) & 0x1F) as usize);
+                }
+                instruction.to_string()
+            },
+            Err((cause, _)) => format!("{cause:?}"),
+        };
+        context.push(Executed { pc, ours, qemu: qemu.disasm.clone() });
+        if context.len() > options.context {
+            context.remove(0);
+        }
+        let running = vm.step()?;
+        let Some(next) = log.next().transpose()? else {break};
+        qemu = next;
+        if !running {
+            return Ok(Outcome::Diverged(Box::new(Divergence {
+                instructions, mismatch: Mismatch::Stopped { pc }, context, qemu, regs: vm.cpu.regs,
+            })))
+        }
+        instructions += 1;
+    }
+    Ok(Outcome::Match { instructions })
+}
+
+/// `-singlestep` was replaced by `-accel tcg,one-insn-per-tb=on` in QEMU 8.1
+fn singlestep_args(qemu: &Path) -> Result<Vec<&'static str>> {
+    let output = Command::new(qemu).arg("--version").output().with_context(|| format!("Can't run {}", qemu.display()))?;
+    let version = String::from_utf8_lossy(&output.stdout);
+    let (major, minor) = version.split_whitespace()
+        .skip_while(|word| *word != "version").nth(1)
+        .and_then(|version| {
+            let mut numbers = version.split('.').map(|number| number.parse::<u32>().ok());
+            Some((numbers.next()??, numbers.next()??))
+        })
+        .with_context(|| format!("Unknown QEMU version {version:?}"))?;
+    Ok(if (major, minor) >= (8, 1) {vec!["-accel", "tcg,one-insn-per-tb=on"]} else {vec!["-singlestep"]})
+}
+
+/// Runs `elf` in QEMU until it exits or `timeout` passes, returns the path of the log
+pub fn run_qemu(qemu: &Path, elf: &Path, mem_size: uguest, extra_args: &[String], timeout: Duration) -> Result<PathBuf> {
+    let log = std::env::temp_dir().join(format!("difftest-{}.log", std::process::id()));
+    let mut child = Command::new(qemu)
+        .args(["-machine", "virt", "-bios", "none", "-display", "none", "-serial", "null", "-monitor", "none"])
+        .arg("-m").arg(format!("{}M", mem_size >> 20))
+        .arg("-kernel").arg(elf)
+        .args(["-d", "in_asm,cpu,nochain"])
+        .args(singlestep_args(qemu)?)
+        .arg("-D").arg(&log)
+        .args(extra_args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Can't run {}", qemu.display()))?;
+    let deadline = Instant::now() + timeout;
+    while child.try_wait()?.is_none() {
+        if Instant::now() > deadline {
+            child.kill()?;
+            child.wait()?;
+            break
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Ok(log)
+}
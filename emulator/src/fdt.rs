@@ -0,0 +1,357 @@
+// Flattened device tree (https://devicetree-specification.readthedocs.io, chapter 5) given to the guest in a1
+// The tree describes the machine from its description (see board.rs)
+use crate::board::{Board, Device, DeviceKind};
+use crate::cpu::isa::Isa;
+use crate::mem::{MemMap, MemoryMap};
+use crate::{aia, uguest};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+/// Header size, it's followed by the (empty) memory reservation map
+const HEADER_SIZE: usize = 40;
+
+/// mtime ticks per second advertised to the guest, like QEMU virt's
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+/// Phandle of the interrupt controller of hart 0, the next harts' follow
+const CPU_INTC_PHANDLE: u32 = 1;
+/// Phandles of the AIA controllers, after the ones of the harts
+const CONTROLLER_PHANDLE: u32 = 0x1000;
+/// Phandle of the PLIC, after the ones of the AIA controllers
+const PLIC_PHANDLE: u32 = CONTROLLER_PHANDLE + 4;
+
+/// Phandle of an APLIC domain or IMSIC, the ones of both levels follow each other
+fn controller_phandle(kind: DeviceKind, level: aia::Level) -> u32 {
+    CONTROLLER_PHANDLE + 2 * (kind == DeviceKind::Aplic) as u32 + level as u32
+}
+
+/// Builds the blob node by node, `begin_node`s have to be matched by `end_node`s
+#[derive(Debug, Default)]
+pub struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+impl FdtBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+    fn pad(&mut self) {
+        self.structure.resize(self.structure.len().next_multiple_of(4), 0);
+    }
+    /// Offset of `name` in the strings block, names are only stored once
+    fn string(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for string in self.strings.split(|&c| c == 0) {
+            if string == name.as_bytes() && offset < self.strings.len() {return offset as u32}
+            offset += string.len()+1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    /// The root node has an empty name
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+        self.depth += 1;
+    }
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "end_node without a matching begin_node");
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name = self.string(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(name);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+    /// Boolean property
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+    /// 64 bits values, two cells each (e.g. `reg` with #address-cells = #size-cells = 2)
+    pub fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let value: Vec<u8> = values.iter().flat_map(|value| value.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let value: Vec<u8> = values.iter().flat_map(|value| value.bytes().chain([0])).collect();
+        self.property(name, &value);
+    }
+
+    pub fn finish(mut self, boot_cpuid: u32) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "Unclosed node");
+        self.token(FDT_END);
+        let struct_offset = HEADER_SIZE + 16;
+        let strings_offset = struct_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            struct_offset as u32,
+            strings_offset as u32,
+            HEADER_SIZE as u32, // Memory reservation map
+            17, // Version
+            16, // Last compatible version
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]); // No reserved memory
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// ISA extensions of misa in canonical order, followed by the Z extensions that are always there and the optional ones
+pub fn isa_extensions(isa: impl Into<Isa>) -> Vec<String> {
+    let Isa { misa, extensions: optional } = isa.into();
+    let mut extensions: Vec<String> = "imafdqcbv".chars()
+        .filter(|c| misa & (1 << (*c as u8 - b'a')) != 0)
+        .map(String::from)
+        .collect();
+    extensions.extend(crate::cpu::isa::Z_EXTENSIONS.map(String::from));
+    extensions.extend(optional.names().map(String::from));
+    extensions
+}
+/// riscv,isa string, e.g. rv64imac_zicntr_zicsr_zifencei
+pub fn isa_string(isa: impl Into<Isa>) -> String {
+    let (single, multi): (Vec<_>, Vec<_>) = isa_extensions(isa).into_iter().partition(|ext| ext.len() == 1);
+    let mut isa = format!("rv64{}", single.concat());
+    for ext in multi {
+        isa.push('_');
+        isa.push_str(&ext);
+    }
+    isa
+}
+
+/// External interrupt of `level` of every hart, as interrupts-extended cells
+fn hart_interrupts(board: &Board, level: aia::Level) -> Vec<u32> {
+    let interrupt = level.eip().trailing_zeros();
+    (0..board.harts as u32).flat_map(|hartid| [CPU_INTC_PHANDLE + hartid, interrupt]).collect()
+}
+
+/// Phandle of the controller the irqs of devices are described on and the cells after an irq, None without one
+/// The S-level APLIC domain when the root delegates to it, like QEMU, level high
+fn irq_parent(board: &Board) -> Option<(u32, &'static [u32])> {
+    if let Some(aplic) = board.interrupt_controller(DeviceKind::Aplic, aia::Level::Machine) {
+        let child = board.interrupt_controller(DeviceKind::Aplic, aia::Level::Supervisor).unwrap_or(aplic);
+        return Some((controller_phandle(DeviceKind::Aplic, child.level), &[4]))
+    }
+    board.device(DeviceKind::Plic).map(|_| (PLIC_PHANDLE, [].as_slice()))
+}
+
+/// interrupt-parent and interrupts of a device wired to the APLIC or the PLIC
+fn device_interrupt(fdt: &mut FdtBuilder, board: &Board, device: &Device) {
+    let (Some((parent, cells)), Some(irq)) = (irq_parent(board), device.irq) else {return};
+    fdt.property_u32("interrupt-parent", parent);
+    fdt.property_cells("interrupts", &[[irq].as_slice(), cells].concat());
+}
+
+/// Device tree of a machine built from `board`, whose harts have the extensions of `isa`
+pub fn generate(board: &Board, isa: Isa, boot_hart: uguest, bootargs: &str) -> Vec<u8> {
+    let mut fdt = FdtBuilder::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", &board.compatible);
+    fdt.property_string("model", &board.model);
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", bootargs);
+    if let Some(uart) = board.stdout() {
+        fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", uart.base));
+    }
+    fdt.end_node();
+
+    for ram in &board.ram {
+        fdt.begin_node(&format!("memory@{:x}", ram.base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_u64s("reg", &[ram.base, ram.size]);
+        fdt.end_node();
+    }
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+    let extensions = isa_extensions(isa);
+    for hartid in 0..board.harts as u32 {
+        fdt.begin_node(&format!("cpu@{hartid:x}"));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hartid);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa_string(isa));
+        fdt.property_string("riscv,isa-base", "rv64i");
+        fdt.property_strings("riscv,isa-extensions", &extensions.iter().map(String::as_str).collect::<Vec<_>>());
+        fdt.property_string("mmu-type", "riscv,sv39");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU_INTC_PHANDLE + hartid);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+    // Without an APLIC or a PLIC, irqs aren't described, there is no interrupt controller to route them to
+    let parent = irq_parent(board);
+    for device in &board.devices {
+        match device.kind {
+            DeviceKind::Uart => {
+                fdt.begin_node(&format!("serial@{:x}", device.base));
+                fdt.property_string("compatible", "ns16550a");
+                fdt.property_u64s("reg", &[device.base, device.size()]);
+                fdt.property_u32("clock-frequency", 0x384000);
+                device_interrupt(&mut fdt, board, device);
+                fdt.end_node();
+            },
+            DeviceKind::Plic => {
+                fdt.begin_node(&format!("plic@{:x}", device.base));
+                fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+                fdt.property_u64s("reg", &[device.base, device.size()]);
+                fdt.property_u32("#address-cells", 0);
+                fdt.property_u32("#interrupt-cells", 1);
+                fdt.property_empty("interrupt-controller");
+                fdt.property_u32("riscv,ndev", crate::plic::PLIC_SOURCES as u32 - 1);
+                // The M-mode and S-mode contexts of each hart
+                let interrupts: Vec<u32> = (0..board.harts as u32).flat_map(|hartid| [CPU_INTC_PHANDLE + hartid, 11, CPU_INTC_PHANDLE + hartid, 9]).collect();
+                fdt.property_cells("interrupts-extended", &interrupts);
+                fdt.property_u32("phandle", PLIC_PHANDLE);
+                fdt.end_node();
+            },
+            DeviceKind::Imsic => {
+                fdt.begin_node(&format!("interrupt-controller@{:x}", device.base));
+                fdt.property_string("compatible", "riscv,imsics");
+                fdt.property_u64s("reg", &[device.base, aia::IMSIC_HART_STRIDE * board.harts as uguest]);
+                fdt.property_u32("#interrupt-cells", 0);
+                fdt.property_empty("interrupt-controller");
+                fdt.property_empty("msi-controller");
+                fdt.property_u32("riscv,num-ids", aia::IMSIC_IDS as u32);
+                fdt.property_cells("interrupts-extended", &hart_interrupts(board, device.level));
+                fdt.property_u32("phandle", controller_phandle(device.kind, device.level));
+                fdt.end_node();
+            },
+            DeviceKind::Aplic => {
+                fdt.begin_node(&format!("interrupt-controller@{:x}", device.base));
+                fdt.property_string("compatible", "riscv,aplic");
+                fdt.property_u64s("reg", &[device.base, device.size()]);
+                fdt.property_u32("#interrupt-cells", 2);
+                fdt.property_empty("interrupt-controller");
+                fdt.property_u32("riscv,num-sources", aia::APLIC_SOURCES as u32);
+                if board.interrupt_controller(DeviceKind::Imsic, device.level).is_some() {
+                    fdt.property_u32("msi-parent", controller_phandle(DeviceKind::Imsic, device.level));
+                } else {
+                    fdt.property_cells("interrupts-extended", &hart_interrupts(board, device.level));
+                }
+                if device.level == aia::Level::Machine && board.interrupt_controller(DeviceKind::Aplic, aia::Level::Supervisor).is_some() {
+                    fdt.property_u32("riscv,children", controller_phandle(DeviceKind::Aplic, aia::Level::Supervisor));
+                    fdt.property_cells("riscv,delegation", &[controller_phandle(DeviceKind::Aplic, aia::Level::Supervisor), 1, aia::APLIC_SOURCES as u32]);
+                }
+                fdt.property_u32("phandle", controller_phandle(device.kind, device.level));
+                fdt.end_node();
+            },
+            DeviceKind::Pcie => {
+                let (ecam, window) = (MemMap::PcieEcam.len(), MemMap::PcieMmio.len());
+                fdt.begin_node(&format!("pci@{:x}", device.base));
+                fdt.property_string("compatible", "pci-host-ecam-generic");
+                fdt.property_string("device_type", "pci");
+                fdt.property_u32("#address-cells", 3);
+                fdt.property_u32("#size-cells", 2);
+                fdt.property_u32("#interrupt-cells", 1);
+                fdt.property_cells("bus-range", &[0, (ecam >> 20) as u32 - 1]);
+                fdt.property_u32("linux,pci-domain", 0);
+                fdt.property_empty("dma-coherent");
+                fdt.property_u64s("reg", &[device.base, ecam]);
+                // 32 bits memory space, PCI addresses are CPU ones
+                let window = device.base + ecam;
+                let split = |value: uguest| [(value >> 32) as u32, value as u32];
+                fdt.property_cells("ranges", &[[0x0200_0000].as_slice(), &split(window), &split(window), &split(MemMap::PcieMmio.len())].concat());
+                if let (Some((parent, cells)), Some(irq)) = (parent, device.irq) {
+                    // Pin P of slot S raises INTA + (P - 1 + S) % 4
+                    let map: Vec<u32> = (0..4).flat_map(|slot| (1..=4).flat_map(move |pin| {
+                        [[slot << 11, 0, 0, pin, parent, irq + (pin - 1 + slot) % 4].as_slice(), cells].concat()
+                    })).collect();
+                    fdt.property_cells("interrupt-map", &map);
+                    fdt.property_cells("interrupt-map-mask", &[0x1800, 0, 0, 7]);
+                }
+                fdt.end_node();
+            },
+            DeviceKind::Rtc => {
+                fdt.begin_node(&format!("rtc@{:x}", device.base));
+                fdt.property_string("compatible", "google,goldfish-rtc");
+                fdt.property_u64s("reg", &[device.base, device.size()]);
+                device_interrupt(&mut fdt, board, device);
+                fdt.end_node();
+            },
+            DeviceKind::FwCfg => {
+                fdt.begin_node(&format!("fw-cfg@{:x}", device.base));
+                fdt.property_string("compatible", "qemu,fw-cfg-mmio");
+                fdt.property_u64s("reg", &[device.base, device.size()]);
+                fdt.property_empty("dma-coherent");
+                fdt.end_node();
+            },
+            DeviceKind::Flash => {
+                fdt.begin_node(&format!("flash@{:x}", device.base));
+                fdt.property_string("compatible", "cfi-flash");
+                fdt.property_u64s("reg", &[device.base, device.size()]);
+                fdt.property_u32("bank-width", crate::pflash::WIDTH);
+                fdt.end_node();
+            },
+            DeviceKind::Clint => {
+                fdt.begin_node(&format!("clint@{:x}", device.base));
+                fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+                fdt.property_u64s("reg", &[device.base, device.size()]);
+                // Software and timer interrupts of M-mode
+                let interrupts: Vec<u32> = (0..board.harts as u32).flat_map(|hartid| [CPU_INTC_PHANDLE + hartid, 3, CPU_INTC_PHANDLE + hartid, 7]).collect();
+                fdt.property_cells("interrupts-extended", &interrupts);
+                fdt.end_node();
+            },
+            DeviceKind::VirtioMmio => {
+                fdt.begin_node(&format!("virtio_mmio@{:x}", device.base));
+                fdt.property_string("compatible", "virtio,mmio");
+                fdt.property_u64s("reg", &[device.base, device.size()]);
+                device_interrupt(&mut fdt, board, device);
+                fdt.end_node();
+            },
+        }
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish(boot_hart as u32)
+}
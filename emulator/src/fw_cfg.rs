@@ -0,0 +1,182 @@
+// QEMU's firmware configuration device, MMIO flavour (docs/specs/fw_cfg.rst)
+// Items are selected by key and read as a byte stream from the data register, or copied to guest memory by DMA
+// Files are items from FILE_FIRST on, listed in the FILE_DIR item, names are like "opt/org.example/file"
+// Integers of the items are little endian, the registers and the file directory are big endian
+use std::collections::BTreeMap;
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+
+use crate::mem::{Memory, MemoryRegion};
+use crate::uguest;
+
+pub const SIZE: uguest = 0x18;
+
+// Registers
+const DATA: uguest = 0x00;
+const SELECTOR: uguest = 0x08;
+const DMA: uguest = 0x10;
+/// Read from the DMA register, so the guest knows it's there
+const DMA_SIGNATURE: &[u8; 8] = b"QEMU CFG";
+
+// Keys of the items
+pub const SIGNATURE: u16 = 0x00;
+pub const ID: u16 = 0x01;
+pub const RAM_SIZE: u16 = 0x03;
+pub const NB_CPUS: u16 = 0x05;
+pub const KERNEL_SIZE: u16 = 0x08;
+pub const INITRD_SIZE: u16 = 0x0B;
+pub const KERNEL_DATA: u16 = 0x11;
+pub const INITRD_DATA: u16 = 0x12;
+pub const CMDLINE_SIZE: u16 = 0x14;
+pub const CMDLINE_DATA: u16 = 0x15;
+pub const FILE_DIR: u16 = 0x19;
+pub const FILE_FIRST: u16 = 0x20;
+/// Bytes of a file name, with its NUL terminator
+pub const FILE_NAME_LEN: usize = 56;
+/// ID bits: the data register and DMA are both there
+const ID_TRADITIONAL: u32 = 1;
+const ID_DMA: u32 = 2;
+
+// Control bits of a DMA access
+pub const DMA_ERROR: u32 = 1;
+pub const DMA_READ: u32 = 2;
+pub const DMA_SKIP: u32 = 4;
+pub const DMA_SELECT: u32 = 8;
+pub const DMA_WRITE: u32 = 0x10;
+
+#[derive(Debug)]
+pub struct FwCfg {
+    items: BTreeMap<u16, Vec<u8>>,
+    files: Vec<(String, u16)>,
+    selected: u16,
+    /// Position in the selected item, shared by the data register and DMA
+    offset: usize,
+    /// High half of the DMA address, when written 32 bits at a time
+    dma_high: u32,
+    /// Address of the DMA access the guest asked for
+    dma_access: Option<uguest>,
+}
+impl FwCfg {
+    pub fn new(ram_size: uguest, harts: usize) -> Self {
+        let mut fw_cfg = Self { items: BTreeMap::new(), files: Vec::new(), selected: 0, offset: 0, dma_high: 0, dma_access: None };
+        fw_cfg.set_item(SIGNATURE, b"QEMU".to_vec());
+        fw_cfg.set_item(ID, (ID_TRADITIONAL | ID_DMA).to_le_bytes().to_vec());
+        fw_cfg.set_item(RAM_SIZE, ram_size.to_le_bytes().to_vec());
+        fw_cfg.set_item(NB_CPUS, (harts as u16).to_le_bytes().to_vec());
+        fw_cfg.set_item(FILE_DIR, 0u32.to_be_bytes().to_vec());
+        fw_cfg
+    }
+    pub fn set_item(&mut self, key: u16, data: Vec<u8>) {
+        self.items.insert(key, data);
+    }
+    pub fn item(&self, key: u16) -> Option<&[u8]> {
+        self.items.get(&key).map(Vec::as_slice)
+    }
+    /// Kernel command line, like QEMU's -append
+    pub fn set_cmdline(&mut self, cmdline: &str) {
+        let mut data = cmdline.as_bytes().to_vec();
+        data.push(0);
+        self.set_item(CMDLINE_SIZE, (data.len() as u32).to_le_bytes().to_vec());
+        self.set_item(CMDLINE_DATA, data);
+    }
+    /// Like QEMU's -initrd
+    pub fn set_initrd(&mut self, initrd: Vec<u8>) {
+        self.set_item(INITRD_SIZE, (initrd.len() as u32).to_le_bytes().to_vec());
+        self.set_item(INITRD_DATA, initrd);
+    }
+    /// Adds a file to the directory, or replaces it, returns its key
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) -> Result<u16> {
+        if name.is_empty() || name.len() >= FILE_NAME_LEN {bail!("fw_cfg file names are 1 to {} bytes long, not {name:?}", FILE_NAME_LEN - 1)}
+        let key = match self.files.iter().find(|(file, _)| file == name) {
+            Some((_, key)) => *key,
+            None => {
+                let key = FILE_FIRST + self.files.len() as u16;
+                self.files.push((name.into(), key));
+                key
+            },
+        };
+        self.set_item(key, data);
+        self.update_directory();
+        Ok(key)
+    }
+    /// Key of the file `name`
+    pub fn file(&self, name: &str) -> Option<u16> {
+        self.files.iter().find(|(file, _)| file == name).map(|(_, key)| *key)
+    }
+    /// Sorted by name like QEMU: count, then size, key, reserved and name of each file
+    fn update_directory(&mut self) {
+        let mut files = self.files.clone();
+        files.sort();
+        let mut directory = (files.len() as u32).to_be_bytes().to_vec();
+        for (name, key) in files {
+            directory.extend((self.items[&key].len() as u32).to_be_bytes());
+            directory.extend(key.to_be_bytes());
+            directory.extend([0; 2]);
+            let mut padded = [0; FILE_NAME_LEN];
+            padded[..name.len()].copy_from_slice(name.as_bytes());
+            directory.extend(padded);
+        }
+        self.set_item(FILE_DIR, directory);
+    }
+    fn select(&mut self, key: u16) {
+        (self.selected, self.offset) = (key, 0);
+    }
+    /// Next `len` bytes of the selected item, zeros past its end
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        let item = self.items.get(&self.selected).map_or(&[][..], Vec::as_slice);
+        let data = (0..len).map(|i| item.get(self.offset + i).copied().unwrap_or(0)).collect();
+        self.offset = self.offset.saturating_add(len);
+        data
+    }
+    /// Does the access described at `access`, returns the control word to write back
+    fn dma_access(&mut self, bus: &mut Memory, access: uguest) -> u32 {
+        // The guest writes `access`, it can be anywhere
+        let Some(fields) = access.checked_add(8) else {return DMA_ERROR};
+        let (Ok(control), Ok(length), Ok(address)) = (bus.get::<u32>(access), bus.get::<u32>(access + 4), bus.get::<u64>(fields)) else {
+            return DMA_ERROR
+        };
+        let (control, length, address) = (u32::from_be(control), u32::from_be(length), u64::from_be(address));
+        if control & DMA_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+        if control & DMA_READ != 0 {
+            let data = self.take(length as usize);
+            if bus.write(address, &data).is_err() {return DMA_ERROR}
+        } else if control & DMA_SKIP != 0 {
+            self.offset = self.offset.saturating_add(length as usize);
+        } else if control & DMA_WRITE != 0 {
+            // Items are read-only
+            return DMA_ERROR
+        }
+        0
+    }
+}
+impl MemoryRegion for FwCfg {
+    fn read(&mut self, offset: uguest) -> u8 {
+        match offset {
+            DATA..SELECTOR => self.take(1)[0],
+            DMA.. => DMA_SIGNATURE[(offset - DMA) as usize % 8],
+            _ => 0,
+        }
+    }
+    fn write(&mut self, _offset: uguest, _val: u8) {}
+    /// The selector is written 16 bits at a time, the DMA address 64 or twice 32 (the low half starts the access)
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        match (offset, buffer.len()) {
+            (SELECTOR, 2) => self.select(u16::from_be_bytes([buffer[0], buffer[1]])),
+            (DMA, 8) => self.dma_access = Some(u64::from_be_bytes(buffer.try_into().unwrap())),
+            (DMA, 4) => self.dma_high = u32::from_be_bytes(buffer.try_into().unwrap()),
+            (0x14, 4) => self.dma_access = Some((self.dma_high as u64) << 32 | u32::from_be_bytes(buffer.try_into().unwrap()) as u64),
+            _ => {},
+        }
+    }
+    fn dma_pending(&self) -> bool {
+        self.dma_access.is_some()
+    }
+    fn dma(&mut self, bus: &mut Memory) {
+        let Some(access) = self.dma_access.take() else {return};
+        let control = self.dma_access(bus, access);
+        let _ = bus.set(access, control.to_be());
+    }
+}
@@ -0,0 +1,109 @@
+// Frames of the display, saved as binary PPM (P6) or PNG (8 bits RGB, unfiltered rows, zlib through miniz_oxide)
+use std::path::Path;
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Png,
+    Ppm,
+}
+impl std::str::FromStr for Format {
+    type Err = String;
+    fn from_str(format: &str) -> Result<Self, String> {
+        match format {
+            "png" => Ok(Self::Png),
+            "ppm" => Ok(Self::Ppm),
+            _ => Err(format!("Unknown image format {format:?}, it is png or ppm")),
+        }
+    }
+}
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Ppm => "ppm",
+        }
+    }
+}
+
+/// RGB pixels, row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub rgb: Vec<u8>,
+}
+impl Frame {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, rgb: vec![0; width as usize * height as usize * 3] }
+    }
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        [self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]]
+    }
+    pub fn set_pixel(&mut self, x: u32, y: u32, rgb: [u8; 3]) {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        self.rgb[i..i + 3].copy_from_slice(&rgb);
+    }
+    pub fn ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend(&self.rgb);
+        ppm
+    }
+    pub fn png(&self) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut header = self.width.to_be_bytes().to_vec();
+        header.extend(self.height.to_be_bytes());
+        // 8 bits, truecolour, deflate, no filter method, no interlacing
+        header.extend([8, 2, 0, 0, 0]);
+        chunk(&mut png, b"IHDR", &header);
+        let row = self.width as usize * 3;
+        let mut raw = Vec::with_capacity((row + 1) * self.height as usize);
+        for line in self.rgb.chunks(row.max(1)) {
+            // Filter type None
+            raw.push(0);
+            raw.extend(line);
+        }
+        chunk(&mut png, b"IDAT", &miniz_oxide::deflate::compress_to_vec_zlib(&raw, 6));
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+    pub fn encode(&self, format: Format) -> Vec<u8> {
+        match format {
+            Format::Png => self.png(),
+            Format::Ppm => self.ppm(),
+        }
+    }
+    /// Writes the frame, in the format of the extension of `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let format = match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => extension.parse().map_err(color_eyre::Report::msg)?,
+            None => bail!("Can't tell the format of {}, frames are .png or .ppm files", path.display()),
+        };
+        std::fs::write(path, self.encode(format)).with_context(|| format!("Can't write the frame {}", path.display()))
+    }
+}
+
+/// Appends a chunk: length, type, data and the CRC of the type and data
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+/// CRC-32 of ISO 3309, the one of PNG and zip
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {crc >> 1 ^ 0xEDB8_8320} else {crc >> 1};
+        }
+    }
+    !crc
+}
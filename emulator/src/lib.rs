@@ -1,11 +1,34 @@
-#![feature(const_mut_refs)] // For raw instructions
-#![feature(vec_push_within_capacity)]
-#![feature(pointer_byte_offsets)]
 #![allow(dead_code, unused)]
 
+pub mod aia;
 pub mod args;
+pub mod asm;
+pub mod block;
+pub mod board;
+pub mod clint;
+pub mod clock;
 pub mod cpu;
+pub mod difftest;
+pub mod fdt;
+pub mod fw_cfg;
+pub mod image;
+pub mod linux;
+pub mod loader;
+pub mod machine;
 pub mod mem;
+pub mod monitor;
+pub mod net;
+pub mod pci;
+pub mod pflash;
+pub mod plic;
+pub mod profiler;
+pub mod rtc;
+pub mod sbi;
+pub mod semihosting;
+pub mod snapshot;
+pub mod testsuite;
+pub mod uart;
+pub mod virtio;
 pub mod vm;
 
 #[allow(non_camel_case_types)]
@@ -0,0 +1,553 @@
+// Linux user-mode emulation: runs a static riscv64 Linux program directly, its ecalls become host system calls.
+// The program gets the process Linux would give it: segments at their virtual addresses, a brk heap after them,
+// an mmap area and a stack holding argc, argv, envp and the auxiliary vector.
+// Guest pointers are checked against the regions mapped for the program before the host touches anything,
+// bad ones fail with EFAULT. Faults that Linux would turn into a signal kill the program.
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+
+use crate::cpu::csr::file::{MCAUSE, MEPC, MISA, MTVAL};
+use crate::cpu::PrivilegeLevel;
+use crate::mem::{Memory, DRAM, PAGE_SIZE};
+use crate::vm::VM;
+use crate::{iguest, loader, uguest};
+
+// riscv64 uses the generic syscall table, include/uapi/asm-generic/unistd.h
+pub const SYS_OPENAT: uguest = 56;
+pub const SYS_CLOSE: uguest = 57;
+pub const SYS_READ: uguest = 63;
+pub const SYS_WRITE: uguest = 64;
+pub const SYS_WRITEV: uguest = 66;
+pub const SYS_FSTAT: uguest = 80;
+pub const SYS_EXIT: uguest = 93;
+pub const SYS_EXIT_GROUP: uguest = 94;
+pub const SYS_SET_TID_ADDRESS: uguest = 96;
+pub const SYS_CLOCK_GETTIME: uguest = 113;
+pub const SYS_BRK: uguest = 214;
+pub const SYS_MUNMAP: uguest = 215;
+pub const SYS_MMAP: uguest = 222;
+pub const SYS_MPROTECT: uguest = 226;
+pub const SYS_GETRANDOM: uguest = 278;
+
+pub const EBADF: uguest = 9;
+pub const ENOMEM: uguest = 12;
+pub const EFAULT: uguest = 14;
+pub const ENODEV: uguest = 19;
+pub const EINVAL: uguest = 22;
+pub const ENAMETOOLONG: uguest = 36;
+pub const ENOSYS: uguest = 38;
+const EIO: uguest = 5;
+
+/// Layout of the process, the stack is at the top of the Sv39 user address space
+pub const STACK_TOP: uguest = 0x3F_FFFF_F000;
+pub const STACK_SIZE: uguest = 8 << 20;
+pub const MMAP_BASE: uguest = 0x30_0000_0000;
+pub const MMAP_SIZE: uguest = 1 << 30;
+/// How far brk can grow the heap
+pub const HEAP_SIZE: uguest = 1 << 30;
+
+/// Reads and writes are cut to this, programs have to handle short counts anyway
+const MAX_IO: uguest = 1 << 20;
+const PATH_MAX: uguest = 4096;
+const IOV_MAX: uguest = 1024;
+/// Process and thread id of the program
+const PID: uguest = 1;
+
+const AT_FDCWD: iguest = -100;
+const O_ACCMODE: uguest = 0b11;
+const O_CREAT: uguest = 0o100;
+const O_EXCL: uguest = 0o200;
+const O_TRUNC: uguest = 0o1000;
+const O_APPEND: uguest = 0o2000;
+
+const MAP_TYPE: uguest = 0b11; // MAP_SHARED or MAP_PRIVATE
+const MAP_FIXED: uguest = 0x10;
+const MAP_ANONYMOUS: uguest = 0x20;
+
+const CLOCK_REALTIME: uguest = 0;
+const CLOCK_REALTIME_COARSE: uguest = 5;
+const CLOCK_BOOTTIME: uguest = 7;
+
+// Auxiliary vector entries
+const AT_NULL: uguest = 0;
+const AT_PHDR: uguest = 3;
+const AT_PHENT: uguest = 4;
+const AT_PHNUM: uguest = 5;
+const AT_PAGESZ: uguest = 6;
+const AT_BASE: uguest = 7;
+const AT_FLAGS: uguest = 8;
+const AT_ENTRY: uguest = 9;
+const AT_UID: uguest = 11;
+const AT_EUID: uguest = 12;
+const AT_GID: uguest = 13;
+const AT_EGID: uguest = 14;
+const AT_HWCAP: uguest = 16;
+const AT_CLKTCK: uguest = 17;
+const AT_SECURE: uguest = 23;
+const AT_RANDOM: uguest = 25;
+const AT_EXECFN: uguest = 31;
+const HWCAP_EXTENSIONS: uguest = 1 << 0 | 1 << 2 | 1 << 3 | 1 << 5 | 1 << 8 | 1 << 12 | 1 << 21;
+
+const SIGILL: i32 = 4;
+const SIGTRAP: i32 = 5;
+const SIGBUS: i32 = 7;
+const SIGSEGV: i32 = 11;
+
+#[derive(Debug)]
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// The guest gets -errno in a0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Errno(uguest);
+impl From<std::io::Error> for Errno {
+    fn from(err: std::io::Error) -> Self {
+        // The host is Linux too, the numbers are the same
+        Self(err.raw_os_error().map_or(EIO, |errno| errno as uguest))
+    }
+}
+type SysResult = Result<uguest, Errno>;
+
+pub struct Linux {
+    /// Indexed by file descriptor
+    fds: Vec<Option<Fd>>,
+    brk_start: uguest,
+    brk: uguest,
+    /// Pages of the mmap area given to the program
+    mapped: Vec<bool>,
+    start: Instant,
+    /// Where fd 1 goes
+    stdout: Box<dyn Write>,
+    /// Set by exit, exit_group or a fatal signal
+    pub exit_status: Option<i32>,
+}
+impl std::fmt::Debug for Linux {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Linux").field("fds", &self.fds).field("brk", &self.brk).field("exit_status", &self.exit_status).finish()
+    }
+}
+impl Default for Linux {
+    fn default() -> Self {
+        Self::with_stdout(Box::new(std::io::stdout()))
+    }
+}
+
+impl Linux {
+    pub fn with_stdout(stdout: Box<dyn Write>) -> Self {
+        Self {
+            fds: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            brk_start: 0,
+            brk: 0,
+            mapped: vec![false; (MMAP_SIZE / PAGE_SIZE) as usize],
+            start: Instant::now(),
+            stdout,
+            exit_status: None,
+        }
+    }
+    /// Current end of the heap
+    pub fn brk(&self) -> uguest {
+        self.brk
+    }
+
+    fn syscall(&mut self, mem: &mut Memory, nr: uguest, args: [uguest; 6]) -> SysResult {
+        match nr {
+            SYS_READ => self.read(mem, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(mem, args[0], args[1], args[2]),
+            SYS_WRITEV => {
+                if args[2] > IOV_MAX {return Err(Errno(EINVAL))}
+                let mut written = 0;
+                for i in 0..args[2] {
+                    let iov = args[1].checked_add(i*16).ok_or(Errno(EFAULT))?;
+                    let (base, len) = (get::<uguest>(mem, iov)?, get::<uguest>(mem, iov.checked_add(8).ok_or(Errno(EFAULT))?)?);
+                    let count = self.write(mem, args[0], base, len)?;
+                    written += count;
+                    if count < len {break}
+                }
+                Ok(written)
+            },
+            SYS_OPENAT => self.openat(mem, args[0] as iguest, args[1], args[2], args[3]),
+            SYS_CLOSE => match self.fds.get_mut(args[0] as usize).and_then(Option::take) {
+                Some(_) => Ok(0),
+                None => Err(Errno(EBADF)),
+            },
+            SYS_FSTAT => {
+                let stat = match self.fd(args[0])? {
+                    Fd::File(file) => Stat::from_metadata(&file.metadata()?),
+                    _ => Stat::console(),
+                };
+                write(mem, args[1], &stat.to_bytes())?;
+                Ok(0)
+            },
+            SYS_BRK => Ok(self.set_brk(mem, args[0])),
+            SYS_MMAP => self.mmap(mem, args[0], args[1], args[3], args[4] as iguest, args[5]),
+            SYS_MUNMAP => self.munmap(mem, args[0], args[1]),
+            // Pages don't have permissions
+            SYS_MPROTECT => Ok(0),
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_status = Some((args[0] & 0xFF) as i32);
+                Ok(0)
+            },
+            SYS_SET_TID_ADDRESS => Ok(PID),
+            SYS_CLOCK_GETTIME => {
+                let time = match args[0] {
+                    CLOCK_REALTIME | CLOCK_REALTIME_COARSE => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+                    // Monotonic, CPU time and boot time clocks all count from the start of the program
+                    ..=CLOCK_BOOTTIME => self.start.elapsed(),
+                    _ => return Err(Errno(EINVAL)),
+                };
+                let timespec = [time.as_secs().to_le_bytes(), (time.subsec_nanos() as u64).to_le_bytes()].concat();
+                write(mem, args[1], &timespec)?;
+                Ok(0)
+            },
+            SYS_GETRANDOM => {
+                let len = args[1].min(MAX_IO);
+                check(mem, args[0], len)?;
+                let mut data = vec![0; len as usize];
+                host_random(&mut data)?;
+                write(mem, args[0], &data)?;
+                Ok(len)
+            },
+            _ => {
+                log::warn!("Unsupported syscall {nr}");
+                Err(Errno(ENOSYS))
+            },
+        }
+    }
+
+    fn fd(&mut self, fd: uguest) -> Result<&mut Fd, Errno> {
+        self.fds.get_mut(fd as usize).and_then(Option::as_mut).ok_or(Errno(EBADF))
+    }
+    fn read(&mut self, mem: &mut Memory, fd: uguest, buf: uguest, len: uguest) -> SysResult {
+        let len = len.min(MAX_IO);
+        check(mem, buf, len)?;
+        let mut data = vec![0; len as usize];
+        let read = match self.fd(fd)? {
+            Fd::Stdin => std::io::stdin().read(&mut data)?,
+            Fd::File(file) => file.read(&mut data)?,
+            Fd::Stdout | Fd::Stderr => return Err(Errno(EBADF)),
+        };
+        write(mem, buf, &data[..read])?;
+        Ok(read as uguest)
+    }
+    fn write(&mut self, mem: &mut Memory, fd: uguest, buf: uguest, len: uguest) -> SysResult {
+        let data = read(mem, buf, len.min(MAX_IO))?;
+        match self.fd(fd)? {
+            Fd::Stdin => return Err(Errno(EBADF)),
+            Fd::Stdout => {
+                self.stdout.write_all(&data)?;
+                self.stdout.flush()?;
+            },
+            Fd::Stderr => std::io::stderr().write_all(&data)?,
+            Fd::File(file) => return Ok(file.write(&data)? as uguest),
+        }
+        Ok(data.len() as uguest)
+    }
+    /// Paths are the host's, relative ones only from the current directory
+    fn openat(&mut self, mem: &mut Memory, dirfd: iguest, path: uguest, flags: uguest, mode: uguest) -> SysResult {
+        let path = read_string(mem, path)?;
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            log::warn!("openat relative to a directory file descriptor isn't supported");
+            return Err(Errno(EINVAL))
+        }
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            0 => options.read(true),
+            1 => options.write(true),
+            2 => options.read(true).write(true),
+            _ => return Err(Errno(EINVAL)),
+        };
+        if flags & O_APPEND != 0 {options.append(true);}
+        if flags & O_TRUNC != 0 {options.truncate(true);}
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {options.create_new(true);} else {options.create(true);}
+        }
+        options.mode((mode & 0o7777) as u32);
+        let file = options.open(path)?;
+        let fd = match self.fds.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None => {self.fds.push(None); self.fds.len()-1},
+        };
+        self.fds[fd] = Some(Fd::File(file));
+        Ok(fd as uguest)
+    }
+
+    /// Returns the new end of the heap, the current one if `addr` is out of the heap
+    fn set_brk(&mut self, mem: &mut Memory, addr: uguest) -> uguest {
+        if (self.brk_start..=self.brk_start + HEAP_SIZE).contains(&addr) {
+            if addr < self.brk {
+                // Shrinking frees the pages, growing again gives zeroed memory
+                let heap = mem.device_mut::<DRAM>(self.brk_start).expect("The heap is mapped");
+                heap.discard(addr - self.brk_start, self.brk - addr);
+            }
+            self.brk = addr;
+        }
+        self.brk
+    }
+    /// Only the mmap area can be mapped, at a fixed address or not
+    fn mmap(&mut self, mem: &mut Memory, addr: uguest, len: uguest, flags: uguest, fd: iguest, offset: uguest) -> SysResult {
+        if len == 0 || !offset.is_multiple_of(PAGE_SIZE) || flags & MAP_TYPE == 0 {return Err(Errno(EINVAL))}
+        let len = len.checked_next_multiple_of(PAGE_SIZE).ok_or(Errno(ENOMEM))?;
+        let pages = (len / PAGE_SIZE) as usize;
+        let first = if flags & MAP_FIXED != 0 {
+            if !addr.is_multiple_of(PAGE_SIZE) {return Err(Errno(EINVAL))}
+            if addr < MMAP_BASE || addr.saturating_add(len) > MMAP_BASE + MMAP_SIZE {return Err(Errno(ENOMEM))}
+            ((addr - MMAP_BASE) / PAGE_SIZE) as usize
+        } else {
+            // First fit, the hint is ignored
+            let mut free = 0;
+            let end = self.mapped.iter().position(|&mapped| {
+                free = if mapped {0} else {free + 1};
+                free == pages
+            }).ok_or(Errno(ENOMEM))?;
+            end + 1 - pages
+        };
+        let start = MMAP_BASE + first as uguest * PAGE_SIZE;
+        let file = if flags & MAP_ANONYMOUS == 0 {
+            match self.fd(fd as uguest)? {
+                Fd::File(file) => Some(file),
+                _ => return Err(Errno(ENODEV)),
+            }
+        } else {None};
+        let area = mem.device_mut::<DRAM>(MMAP_BASE).expect("The mmap area is mapped");
+        // Replaced by a fixed mapping
+        area.discard(start - MMAP_BASE, len);
+        // Private copy of the file, writes to shared mappings aren't written back
+        if let Some(file) = file {
+            let mut data = vec![0; len as usize];
+            let mut read = 0;
+            while read < data.len() {
+                match file.read_at(&mut data[read..], offset + read as uguest)? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            mem.write(start, &data[..read]).map_err(|_| Errno(EFAULT))?;
+        }
+        self.mapped[first..first+pages].fill(true);
+        Ok(start)
+    }
+    fn munmap(&mut self, mem: &mut Memory, addr: uguest, len: uguest) -> SysResult {
+        if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {return Err(Errno(EINVAL))}
+        let start = addr.max(MMAP_BASE);
+        // Like Linux, a range past the end of the address space is invalid
+        let end = addr.checked_add(len).and_then(|end| end.checked_next_multiple_of(PAGE_SIZE)).ok_or(Errno(EINVAL))?
+            .min(MMAP_BASE + MMAP_SIZE);
+        // Unmapping what isn't mapped is fine
+        if start >= end {return Ok(0)}
+        mem.device_mut::<DRAM>(MMAP_BASE).expect("The mmap area is mapped").discard(start - MMAP_BASE, end - start);
+        self.mapped[((start - MMAP_BASE) / PAGE_SIZE) as usize..((end - MMAP_BASE) / PAGE_SIZE) as usize].fill(false);
+        Ok(0)
+    }
+}
+
+/// Guest buffers have to be entirely inside one region mapped for the program, checked before allocating
+/// anything for them as the length comes from the guest
+fn check(mem: &mut Memory, addr: uguest, len: uguest) -> Result<(), Errno> {
+    match mem.get_region(addr, len) {
+        Some(_) => Ok(()),
+        None => Err(Errno(EFAULT)),
+    }
+}
+fn read(mem: &mut Memory, addr: uguest, len: uguest) -> Result<Vec<u8>, Errno> {
+    check(mem, addr, len)?;
+    let mut data = vec![0; len as usize];
+    mem.read(addr, &mut data).map_err(|_| Errno(EFAULT))?;
+    Ok(data)
+}
+fn write(mem: &mut Memory, addr: uguest, data: &[u8]) -> Result<(), Errno> {
+    mem.write(addr, data).map_err(|_| Errno(EFAULT))
+}
+fn get<T: Copy>(mem: &mut Memory, addr: uguest) -> Result<T, Errno> {
+    mem.get(addr).map_err(|_| Errno(EFAULT))
+}
+/// NUL terminated string, at most PATH_MAX bytes
+fn read_string(mem: &mut Memory, addr: uguest) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = get::<u8>(mem, addr.wrapping_add(bytes.len() as uguest))?;
+        if byte == 0 {break}
+        bytes.push(byte);
+        if bytes.len() as uguest >= PATH_MAX {return Err(Errno(ENAMETOOLONG))}
+    }
+    String::from_utf8(bytes).map_err(|_| Errno(EINVAL))
+}
+fn host_random(buffer: &mut [u8]) -> std::io::Result<()> {
+    File::open("/dev/urandom")?.read_exact(buffer)
+}
+
+/// struct stat of the generic syscall ABI (128 bytes)
+#[derive(Debug, Default)]
+struct Stat {
+    dev: u64,
+    ino: u64,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    size: i64,
+    blksize: i32,
+    blocks: i64,
+    /// Seconds and nanoseconds of the last access, modification and status change
+    times: [(i64, i64); 3],
+}
+impl Stat {
+    fn from_metadata(metadata: &Metadata) -> Self {
+        Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            mode: metadata.mode(),
+            nlink: metadata.nlink() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: metadata.rdev(),
+            size: metadata.size() as i64,
+            blksize: metadata.blksize() as i32,
+            blocks: metadata.blocks() as i64,
+            times: [
+                (metadata.atime(), metadata.atime_nsec()),
+                (metadata.mtime(), metadata.mtime_nsec()),
+                (metadata.ctime(), metadata.ctime_nsec()),
+            ],
+        }
+    }
+    /// The standard streams look like a terminal
+    fn console() -> Self {
+        Self { mode: 0o20620, nlink: 1, rdev: 0x8800, blksize: 1024, ..Default::default() }
+    }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(128);
+        bytes.extend_from_slice(&self.dev.to_le_bytes());
+        bytes.extend_from_slice(&self.ino.to_le_bytes());
+        bytes.extend_from_slice(&self.mode.to_le_bytes());
+        bytes.extend_from_slice(&self.nlink.to_le_bytes());
+        bytes.extend_from_slice(&self.uid.to_le_bytes());
+        bytes.extend_from_slice(&self.gid.to_le_bytes());
+        bytes.extend_from_slice(&self.rdev.to_le_bytes());
+        bytes.extend_from_slice(&[0; 8]); // __pad1
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes.extend_from_slice(&self.blksize.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]); // __pad2
+        bytes.extend_from_slice(&self.blocks.to_le_bytes());
+        for (secs, nsecs) in self.times {
+            bytes.extend_from_slice(&secs.to_le_bytes());
+            bytes.extend_from_slice(&nsecs.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[0; 8]); // __unused4, __unused5
+        bytes
+    }
+}
+
+impl VM {
+    /// Loads a static Linux executable and its stack, execution starts at its entry point in U-mode
+    /// `argv[0]` is the program name it sees
+    pub fn new_linux_user(program: &[u8], argv: &[String], envp: &[String], mut linux: Linux) -> Result<Self> {
+        if argv.is_empty() {bail!("argv needs at least the program name")}
+        crate::cpu::raw_instructions::set_instructions_funcs();
+        let mut mem = Memory::empty();
+        let image = loader::load_user_elf(&mut mem, program)?;
+        mem.register(image.end, HEAP_SIZE, None, DRAM::new(HEAP_SIZE)).context("No room for the heap")?;
+        mem.register(MMAP_BASE, MMAP_SIZE, None, DRAM::new(MMAP_SIZE)).context("No room for the mmap area")?;
+        mem.register(STACK_TOP - STACK_SIZE, STACK_SIZE, None, DRAM::new(STACK_SIZE)).context("No room for the stack")?;
+        linux.brk_start = image.end;
+        linux.brk = image.end;
+
+        let mut cpu = crate::cpu::CPU::default();
+        let sp = setup_stack(&mut mem, &image, argv, envp, cpu.csr_value(MISA))?;
+        cpu.pmp_allow_all();
+        cpu.privilege_level = PrivilegeLevel::User;
+        cpu.pc = image.entry;
+        cpu.regs[2] = sp;
+        let mut vm = Self::from_parts(mem, cpu, image.symbols);
+        vm.linux = Some(linux);
+        vm.trace = false;
+        Ok(vm)
+    }
+
+    /// Called on ecall, returns false if it isn't a system call (not a Linux program or not from U-mode)
+    pub fn linux_syscall(&mut self) -> bool {
+        let Some(linux) = &mut self.linux else {return false};
+        if self.cpu.privilege_level != PrivilegeLevel::User {return false}
+        let regs = self.cpu.regs;
+        let args = [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]];
+        self.cpu.regs[10] = match linux.syscall(&mut self.mem, regs[17], args) {
+            Ok(value) => value,
+            Err(Errno(errno)) => errno.wrapping_neg(),
+        };
+        true
+    }
+    /// The exception that was just raised kills the program, with the signal Linux would send
+    pub(crate) fn linux_fault(&mut self) {
+        let Some(linux) = &mut self.linux else {return};
+        let cause = self.cpu.csr_value(MCAUSE);
+        let (signal, name) = match cause {
+            2 => (SIGILL, "Illegal instruction"),
+            3 => (SIGTRAP, "Trace/breakpoint trap"),
+            0 | 4 | 6 => (SIGBUS, "Bus error"),
+            _ => (SIGSEGV, "Segmentation fault"),
+        };
+        eprintln!("{name} at pc {:#x} (tval {:#x})", self.cpu.csr_value(MEPC), self.cpu.csr_value(MTVAL));
+        linux.exit_status = Some(128 + signal);
+    }
+}
+
+/// Writes the strings, argc, argv, envp and auxiliary vector like Linux does, returns the stack pointer
+fn setup_stack(mem: &mut Memory, image: &loader::UserImage, argv: &[String], envp: &[String], misa: uguest) -> Result<uguest> {
+    let mut sp = STACK_TOP;
+    let mut push = |mem: &mut Memory, bytes: &[u8]| -> Result<uguest> {
+        sp = sp.checked_sub(bytes.len() as uguest).filter(|sp| *sp >= STACK_TOP - STACK_SIZE)
+            .ok_or_else(|| color_eyre::Report::msg("The arguments don't fit on the stack"))?;
+        mem.write(sp, bytes)?;
+        Ok(sp)
+    };
+    let cstring = |string: &String| [string.as_bytes(), &[0]].concat();
+    let execfn = push(mem, &cstring(&argv[0]))?;
+    let argv_ptrs = argv.iter().map(|arg| push(mem, &cstring(arg))).collect::<Result<Vec<_>>>()?;
+    let envp_ptrs = envp.iter().map(|var| push(mem, &cstring(var))).collect::<Result<Vec<_>>>()?;
+    let mut random = [0; 16];
+    host_random(&mut random)?;
+    let random = push(mem, &random)?;
+
+    let auxv = [
+        (AT_PHDR, image.phdr),
+        (AT_PHENT, image.phent),
+        (AT_PHNUM, image.phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0), // No interpreter
+        (AT_FLAGS, 0),
+        (AT_ENTRY, image.entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        // Bits of misa, only for the extensions Linux reports (IMAFDCV)
+        (AT_HWCAP, misa & HWCAP_EXTENSIONS),
+        (AT_CLKTCK, 100),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0),
+    ];
+    let mut words = vec![argv.len() as uguest];
+    words.extend(&argv_ptrs);
+    words.push(0);
+    words.extend(&envp_ptrs);
+    words.push(0);
+    words.extend(auxv.iter().flat_map(|&(key, value)| [key, value]));
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    // The ABI wants sp 16 bytes aligned at the entry point
+    let table = sp.checked_sub(bytes.len() as uguest).map(|sp| sp & !0xF)
+        .filter(|sp| *sp >= STACK_TOP - STACK_SIZE)
+        .ok_or_else(|| color_eyre::Report::msg("The arguments don't fit on the stack"))?;
+    mem.write(table, &bytes)?;
+    Ok(table)
+}
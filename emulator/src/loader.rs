@@ -0,0 +1,181 @@
+// Loading of guest images: raw binaries at the start of RAM, or ELF files with their symbols
+// Linux programs are loaded at their virtual addresses instead, see `load_user_elf`
+use color_eyre::eyre::{bail, Context, ContextCompat};
+use color_eyre::Result;
+use elf::abi::{ET_DYN, ET_EXEC, PT_INTERP, PT_LOAD, STT_FUNC, STT_NOTYPE, STT_OBJECT};
+use elf::endian::LittleEndian;
+use elf::ElfBytes;
+
+use crate::mem::{Memory, DRAM, PAGE_SIZE};
+use crate::uguest;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub addr: uguest,
+    pub size: uguest,
+    /// Demangled, without the Rust hash suffix
+    pub name: String,
+    /// Functions and assembly labels, as opposed to data objects
+    pub code: bool,
+}
+
+/// Symbol table of the guest image, sorted by address
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    sorted: Vec<Symbol>,
+}
+impl Symbols {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|sym| sym.addr);
+        Self { sorted: symbols }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.sorted.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.sorted.iter()
+    }
+    /// Code symbol containing `addr`, sized symbols must contain it, unsized ones (assembly labels) extend to the next symbol
+    pub fn lookup(&self, addr: uguest) -> Option<&Symbol> {
+        let idx = self.sorted.partition_point(|sym| sym.addr <= addr);
+        self.sorted[..idx].iter().rev()
+            .find(|sym| sym.code && (sym.size == 0 || addr < sym.addr + sym.size))
+    }
+    pub fn by_name(&self, name: &str) -> Option<&Symbol> {
+        self.sorted.iter().find(|sym| sym.name == name)
+    }
+    /// Name of the code at `addr`, or the address itself when there is no symbol for it
+    pub fn name(&self, addr: uguest) -> String {
+        match self.lookup(addr) {
+            Some(sym) => sym.name.clone(),
+            None => format!("{addr:#x}"),
+        }
+    }
+}
+
+/// What was loaded, where execution should start
+#[derive(Debug, Default)]
+pub struct Image {
+    pub entry: uguest,
+    pub symbols: Symbols,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(elf::abi::ELFMAGIC.as_slice())
+}
+
+fn parse_elf(bytes: &[u8]) -> Result<ElfBytes<'_, LittleEndian>> {
+    let file = ElfBytes::<LittleEndian>::minimal_parse(bytes).context("Invalid ELF file")?;
+    if file.ehdr.class != elf::file::Class::ELF64 || file.ehdr.e_machine != elf::abi::EM_RISCV {
+        bail!("Not a RISC-V 64 bits ELF");
+    }
+    Ok(file)
+}
+
+/// Copies the PT_LOAD segments of a RISC-V 64 ELF at their physical addresses
+pub fn load_elf(mem: &mut Memory, bytes: &[u8]) -> Result<Image> {
+    let file = parse_elf(bytes)?;
+    for segment in file.segments().into_iter().flatten().filter(|seg| seg.p_type == PT_LOAD) {
+        let data = file.segment_data(&segment).context("Segment out of the ELF file")?;
+        // RAM starts zeroed, no need to clear the rest of p_memsz (.bss)
+        mem.write(segment.p_paddr, data)
+            .with_context(|| format!("Segment at {:#x} doesn't fit in guest memory", segment.p_paddr))?;
+    }
+    Ok(Image {
+        entry: file.ehdr.e_entry,
+        symbols: elf_symbols(&file)?,
+    })
+}
+
+/// Load address of position independent executables, where Linux puts them on Sv39 (2/3 of the user address space)
+pub const PIE_BASE: uguest = 0x2A_AAAA_A000;
+
+/// A Linux program in memory, with what the auxiliary vector tells it about itself
+#[derive(Debug, Default)]
+pub struct UserImage {
+    pub entry: uguest,
+    /// Address of the program headers in memory, 0 if no segment contains them
+    pub phdr: uguest,
+    pub phent: uguest,
+    pub phnum: uguest,
+    /// Page aligned end of the highest segment, where the heap starts
+    pub end: uguest,
+    pub symbols: Symbols,
+}
+
+/// Maps the PT_LOAD segments of a static RISC-V 64 Linux executable at their virtual addresses,
+/// each page range gets its own RAM region on the bus (segments sharing a page share the region)
+/// Static PIEs are loaded at PIE_BASE, dynamically linked executables are refused
+pub fn load_user_elf(mem: &mut Memory, bytes: &[u8]) -> Result<UserImage> {
+    let file = parse_elf(bytes)?;
+    let segments: Vec<_> = file.segments().into_iter().flatten().collect();
+    if segments.iter().any(|seg| seg.p_type == PT_INTERP) {
+        bail!("Dynamically linked executables aren't supported, link it statically");
+    }
+    let bias = match file.ehdr.e_type {
+        ET_EXEC => 0,
+        ET_DYN => PIE_BASE,
+        _ => bail!("Not an executable"),
+    };
+    let loads: Vec<_> = segments.iter().filter(|seg| seg.p_type == PT_LOAD && seg.p_memsz != 0).collect();
+    if loads.is_empty() {bail!("No segment to load")}
+
+    // Page ranges of the segments, merged when they overlap
+    let mut ranges: Vec<(uguest, uguest)> = Vec::new();
+    for segment in &loads {
+        let start = (segment.p_vaddr + bias) & !(PAGE_SIZE-1);
+        let end = (segment.p_vaddr + bias).checked_add(segment.p_memsz).context("Segment past the end of the address space")?
+            .next_multiple_of(PAGE_SIZE);
+        ranges.push((start, end));
+    }
+    ranges.sort();
+    let mut merged: Vec<(uguest, uguest)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    for &(start, end) in &merged {
+        mem.register(start, end-start, None, DRAM::new(end-start))?;
+    }
+    let mut phdr = 0;
+    for segment in &loads {
+        let data = file.segment_data(segment).context("Segment out of the ELF file")?;
+        mem.write(segment.p_vaddr + bias, data)?;
+        // The program headers are usually in the first segment
+        let phoff = file.ehdr.e_phoff;
+        if (segment.p_offset..segment.p_offset + segment.p_filesz).contains(&phoff) {
+            phdr = segment.p_vaddr + bias + (phoff - segment.p_offset);
+        }
+    }
+    Ok(UserImage {
+        entry: file.ehdr.e_entry + bias,
+        phdr,
+        phent: file.ehdr.e_phentsize as uguest,
+        phnum: file.ehdr.e_phnum as uguest,
+        end: merged.last().unwrap().1,
+        symbols: if bias == 0 {elf_symbols(&file)?} else {Symbols::default()},
+    })
+}
+
+fn elf_symbols(file: &ElfBytes<LittleEndian>) -> Result<Symbols> {
+    let Some((table, strings)) = file.symbol_table().context("Invalid symbol table")? else {
+        return Ok(Symbols::default())
+    };
+    let mut symbols = Vec::new();
+    for sym in table.iter() {
+        let kind = sym.st_symtype();
+        if sym.is_undefined() || !matches!(kind, STT_FUNC | STT_NOTYPE | STT_OBJECT) {continue}
+        let name = strings.get(sym.st_name as usize).context("Invalid symbol name")?;
+        // Local labels of the assembler and mapping symbols
+        if name.is_empty() || name.starts_with(".L") || name.starts_with('$') {continue}
+        symbols.push(Symbol {
+            addr: sym.st_value,
+            size: sym.st_size,
+            name: format!("{:#}", rustc_demangle::demangle(name)),
+            code: kind != STT_OBJECT,
+        });
+    }
+    Ok(Symbols::new(symbols))
+}
@@ -0,0 +1,384 @@
+// Library API to embed the emulator (e.g. in test harnesses): build a machine, run it until a condition holds or
+// for a number of steps, look at its registers and memory, and get called back on what happens inside.
+// Nothing is printed, running returns why it stopped.
+//
+// All harts share the VM's bus, the one being stepped is the VM's `cpu` and the others wait in `harts`.
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+
+use crate::board::{Board, Device, DeviceKind, Ram};
+use crate::clock::{Clock, ClockMode};
+use crate::cpu::reg::Reg;
+use crate::cpu::{PrivilegeLevel, CPU};
+use crate::cpu::csr::file::{MCAUSE, MEPC, MTVAL, SCAUSE, SEPC, STVAL, VSCAUSE, VSEPC, VSTVAL};
+use crate::mem::{AccessFault, AccessType, MemMap, Memory, MemoryMap, MemoryRegion};
+use crate::vm::VM;
+use crate::{loader, uguest};
+
+/// An instruction that completed, without raising an exception
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retired {
+    pub hart: uguest,
+    pub pc: uguest,
+    /// Compressed instructions are given as their 32 bits expansion
+    pub instruction: u32,
+    pub size: uguest,
+}
+/// A load or store done by an instruction, after it succeeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub hart: uguest,
+    pub addr: uguest,
+    pub size: uguest,
+    pub access: AccessType,
+    /// Value loaded or stored, zero extended
+    pub value: u64,
+}
+/// An exception or interrupt taken by a hart, with the CSRs of the mode it went to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub hart: uguest,
+    /// mcause/scause, bit 63 is set for interrupts
+    pub cause: uguest,
+    pub tval: uguest,
+    pub epc: uguest,
+    pub privilege: PrivilegeLevel,
+}
+impl Trap {
+    pub fn is_interrupt(&self) -> bool {
+        self.cause >> 63 == 1
+    }
+}
+/// A device raised its interrupt line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceInterrupt {
+    pub irq: u32,
+}
+
+type Hook<T> = Option<Box<dyn FnMut(&T)>>;
+
+/// Callbacks run by `VM::step`, set them with the `on_*` methods of `Machine`
+#[derive(Default)]
+pub struct Hooks {
+    retire: Hook<Retired>,
+    memory: Hook<MemoryAccess>,
+    trap: Hook<Trap>,
+    interrupt: Hook<DeviceInterrupt>,
+    /// Interrupt lines up after the last step, only callbacks on the rising edge
+    raised: Vec<u32>,
+}
+impl Hooks {
+    pub(crate) fn retired(&mut self, cpu: &CPU, instruction: u32, size: uguest) {
+        if let Some(hook) = &mut self.retire {
+            hook(&Retired { hart: cpu.hartid, pc: cpu.pc, instruction, size });
+        }
+    }
+    pub(crate) fn memory_access<T: Copy>(&mut self, cpu: &CPU, addr: uguest, access: AccessType, value: T) {
+        let Some(hook) = &mut self.memory else {return};
+        let size = core::mem::size_of::<T>().min(8);
+        let mut bytes = [0; 8];
+        // Loads and stores are at most 8 bytes, the value is copied as is
+        bytes[..size].copy_from_slice(unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size) });
+        hook(&MemoryAccess { hart: cpu.hartid, addr, size: size as uguest, access, value: u64::from_le_bytes(bytes) });
+    }
+    /// Called after the cpu trapped, it is now in the mode that handles the trap
+    pub(crate) fn trapped(&mut self, cpu: &CPU) {
+        let Some(hook) = &mut self.trap else {return};
+        let (cause, tval, epc) = match (cpu.privilege_level, cpu.virt) {
+            (PrivilegeLevel::Supervisor, true) => (VSCAUSE, VSTVAL, VSEPC),
+            (PrivilegeLevel::Supervisor, false) => (SCAUSE, STVAL, SEPC),
+            _ => (MCAUSE, MTVAL, MEPC),
+        };
+        hook(&Trap {
+            hart: cpu.hartid,
+            cause: cpu.csr_value(cause),
+            tval: cpu.csr_value(tval),
+            epc: cpu.csr_value(epc),
+            privilege: cpu.privilege_level,
+        });
+    }
+    pub(crate) fn devices_ticked(&mut self, mem: &Memory) {
+        let Some(hook) = &mut self.interrupt else {return};
+        let raised: Vec<u32> = mem.pending_irqs().collect();
+        for &irq in raised.iter().filter(|irq| !self.raised.contains(irq)) {
+            hook(&DeviceInterrupt { irq });
+        }
+        self.raised = raised;
+    }
+}
+
+/// Why `Machine::run_until` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The guest asked to stop (semihosting, SBI system reset, Linux exit) with this status
+    Exited(i32),
+    /// A hart fetched a zero instruction without a trap handler to go to
+    Halted { hart: usize, pc: uguest },
+    /// The number of steps given ran out
+    InstructionLimit,
+    /// The condition given held
+    Condition,
+}
+
+/// Registers a device on the bus being built
+type AddDevice = Box<dyn FnOnce(&mut Memory) -> Result<()>>;
+
+enum Image {
+    Elf(Vec<u8>),
+    Raw(uguest, Vec<u8>),
+}
+
+/// Describes the machine to build, by default the `virt` preset: a single hart with 128MiB of RAM and a UART
+#[derive(Default)]
+pub struct MachineBuilder {
+    board: Board,
+    devices: Vec<AddDevice>,
+    images: Vec<Image>,
+    entry: Option<uguest>,
+    clock: ClockMode,
+}
+impl MachineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Machine description to start from, the other settings change it
+    pub fn board(mut self, board: Board) -> Self {
+        self.board = board;
+        self
+    }
+    /// A single RAM region
+    pub fn ram(mut self, base: uguest, size: uguest) -> Self {
+        self.board.ram = vec![Ram { base, size }];
+        self
+    }
+    pub fn ram_size(mut self, size: uguest) -> Self {
+        self.board = self.board.with_ram_size(size);
+        self
+    }
+    pub fn harts(mut self, harts: usize) -> Self {
+        self.board.harts = harts;
+        self
+    }
+    /// ISA string of the harts, see `cpu::isa::parse`
+    pub fn isa(mut self, isa: &str) -> Self {
+        self.board.isa = isa.into();
+        self
+    }
+    /// Bits of the vector registers, used when the ISA has V
+    pub fn vlen(mut self, vlen: usize) -> Self {
+        self.board.vlen = vlen;
+        self
+    }
+    /// Without UARTs, or with virt's if there isn't one
+    pub fn uart(mut self, uart: bool) -> Self {
+        if !uart {
+            self.board.devices.retain(|device| device.kind != DeviceKind::Uart);
+        } else if self.board.stdout().is_none() {
+            let uart = Device { kind: DeviceKind::Uart, base: MemMap::UART0.base(), size: None, irq: Some(crate::uart::UART_IRQ), level: Default::default() };
+            self.board.devices.push(uart);
+        }
+        self
+    }
+    /// Maps `device` at `base..base+len`, `irq` is the interrupt line it raises
+    /// It isn't part of the description, so it's left out of the device tree
+    pub fn device(mut self, base: uguest, len: uguest, irq: Option<u32>, device: impl MemoryRegion) -> Self {
+        self.devices.push(Box::new(move |mem| mem.register(base, len, irq, device)));
+        self
+    }
+    /// ELF loaded at its physical addresses, the first one gives the entry point and the symbols
+    pub fn elf(mut self, elf: Vec<u8>) -> Self {
+        self.images.push(Image::Elf(elf));
+        self
+    }
+    /// Raw binary copied at `addr`
+    pub fn image(mut self, addr: uguest, bytes: Vec<u8>) -> Self {
+        self.images.push(Image::Raw(addr, bytes));
+        self
+    }
+    /// How mtime advances, one tick per retired instruction by default
+    pub fn clock(mut self, clock: ClockMode) -> Self {
+        self.clock = clock;
+        self
+    }
+    /// Where all harts start, the entry point of the first ELF or the start of RAM otherwise
+    pub fn entry(mut self, entry: uguest) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+
+    pub fn build(self) -> Result<Machine> {
+        crate::cpu::raw_instructions::set_instructions_funcs();
+        let mut mem = self.board.bus()?;
+        for device in self.devices {
+            device(&mut mem)?;
+        }
+        let mut elf_image = None;
+        for image in self.images {
+            match image {
+                Image::Elf(elf) => {
+                    let image = loader::load_elf(&mut mem, &elf)?;
+                    elf_image.get_or_insert(image);
+                },
+                Image::Raw(addr, bytes) => mem.write(addr, &bytes).with_context(|| format!("Image at {addr:#x} doesn't fit in guest memory"))?,
+            }
+        }
+        let entry = self.entry.or(elf_image.as_ref().map(|image| image.entry)).unwrap_or(self.board.ram_base());
+        // Like after QEMU's reset vector, a0 is the hart id
+        let mut harts = Vec::with_capacity(self.board.harts);
+        for hartid in 0..self.board.harts as uguest {
+            let mut cpu = self.board.hart(hartid)?;
+            (cpu.pc, cpu.next_pc) = (entry, entry);
+            cpu.regs[Reg::a0 as usize] = hartid;
+            harts.push(cpu);
+        }
+        let cpu = std::mem::take(&mut harts[0]);
+        let mut vm = VM::from_parts(mem, cpu, elf_image.map(|image| image.symbols).unwrap_or_default());
+        vm.board = self.board;
+        vm.clock = Clock::new(self.clock);
+        vm.trace = false;
+        Ok(Machine { vm, harts, current: 0 })
+    }
+}
+
+pub struct Machine {
+    /// Bus, devices and firmware, its `cpu` is the last hart stepped
+    pub vm: VM,
+    /// Harts by id, the slot of the one in `vm.cpu` holds a placeholder
+    harts: Vec<CPU>,
+    current: usize,
+}
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+    pub fn harts(&self) -> usize {
+        self.harts.len()
+    }
+    pub fn hart(&self, hart: usize) -> &CPU {
+        if hart == self.current {&self.vm.cpu} else {&self.harts[hart]}
+    }
+    pub fn hart_mut(&mut self, hart: usize) -> &mut CPU {
+        if hart == self.current {&mut self.vm.cpu} else {&mut self.harts[hart]}
+    }
+    /// Makes `hart` the VM's cpu
+    fn select(&mut self, hart: usize) {
+        if hart != self.current {
+            std::mem::swap(&mut self.vm.cpu, &mut self.harts[self.current]);
+            std::mem::swap(&mut self.vm.cpu, &mut self.harts[hart]);
+            self.current = hart;
+        }
+    }
+
+    pub fn reg(&self, hart: usize, reg: Reg) -> uguest {
+        self.hart(hart).regs[reg as usize]
+    }
+    /// Writes to zero are ignored
+    pub fn set_reg(&mut self, hart: usize, reg: Reg, value: uguest) {
+        if reg != Reg::zero {
+            self.hart_mut(hart).regs[reg as usize] = value;
+        }
+    }
+    pub fn pc(&self, hart: usize) -> uguest {
+        self.hart(hart).pc
+    }
+    pub fn set_pc(&mut self, hart: usize, pc: uguest) {
+        let cpu = self.hart_mut(hart);
+        (cpu.pc, cpu.next_pc) = (pc, pc);
+    }
+    /// Value a csr instruction would read, without the access checks
+    pub fn csr(&self, hart: usize, csr: u16) -> uguest {
+        self.hart(hart).csr_value(csr)
+    }
+    /// Goes through the WARL masks like a csr instruction, without the access checks
+    pub fn set_csr(&mut self, hart: usize, csr: u16, value: uguest) {
+        self.hart_mut(hart).set_csr_value(csr, value);
+    }
+    pub fn privilege(&self, hart: usize) -> PrivilegeLevel {
+        self.hart(hart).privilege_level
+    }
+
+    /// Physical memory accesses, from the bus (no PMP and no callbacks)
+    pub fn read<T: Copy>(&mut self, addr: uguest) -> Result<T, AccessFault> {
+        self.vm.mem.get(addr)
+    }
+    pub fn write<T: Copy>(&mut self, addr: uguest, value: T) -> Result<(), AccessFault> {
+        self.vm.mem.set(addr, value)
+    }
+    pub fn read_bytes(&mut self, addr: uguest, buffer: &mut [u8]) -> Result<(), AccessFault> {
+        self.vm.mem.read(addr, buffer)
+    }
+    pub fn write_bytes(&mut self, addr: uguest, bytes: &[u8]) -> Result<(), AccessFault> {
+        self.vm.mem.write(addr, bytes)
+    }
+    /// Device tree of the machine, with a cpu node per hart
+    pub fn device_tree(&self, bootargs: &str) -> Vec<u8> {
+        // Hart 0 boots, like the builder sets up
+        crate::fdt::generate(&self.vm.board, self.hart(0).isa(), 0, bootargs)
+    }
+    /// Address of a symbol of the ELF
+    pub fn symbol(&self, name: &str) -> Option<uguest> {
+        Some(self.vm.symbols.by_name(name)?.addr)
+    }
+
+    pub fn on_retire(&mut self, hook: impl FnMut(&Retired) + 'static) {
+        self.vm.hooks.retire = Some(Box::new(hook));
+    }
+    pub fn on_memory_access(&mut self, hook: impl FnMut(&MemoryAccess) + 'static) {
+        self.vm.hooks.memory = Some(Box::new(hook));
+    }
+    pub fn on_trap(&mut self, hook: impl FnMut(&Trap) + 'static) {
+        self.vm.hooks.trap = Some(Box::new(hook));
+    }
+    pub fn on_device_interrupt(&mut self, hook: impl FnMut(&DeviceInterrupt) + 'static) {
+        self.vm.hooks.interrupt = Some(Box::new(hook));
+    }
+
+    /// Executes one instruction on every hart, in hart id order, then ticks the devices once
+    /// Returns why the machine stopped if it did
+    pub fn step(&mut self) -> Result<Option<StopReason>> {
+        let stopped = |vm: &VM, hart| match vm.exit_status() {
+            Some(status) => StopReason::Exited(status),
+            None => StopReason::Halted { hart, pc: vm.cpu.pc },
+        };
+        // A single hart does it all in `VM::step`
+        if self.harts.len() == 1 {
+            return Ok((!self.vm.step()?).then(|| stopped(&self.vm, 0)))
+        }
+        for hart in 0..self.harts.len() {
+            self.select(hart);
+            if !self.vm.execute()? || self.vm.exit_status().is_some() {return Ok(Some(stopped(&self.vm, hart)))}
+        }
+        self.vm.mem.tick();
+        self.vm.hooks.devices_ticked(&self.vm.mem);
+        for hart in 0..self.harts.len() {
+            self.select(hart);
+            self.vm.end_step();
+        }
+        // When every hart waits, the time goes to the earliest deadline
+        let mut deadline = uguest::MAX;
+        for hart in 0..self.harts.len() {
+            self.select(hart);
+            let Some(waits_for) = self.vm.idle_deadline() else {return Ok(None)};
+            deadline = deadline.min(waits_for);
+        }
+        self.vm.fast_forward(deadline);
+        for hart in 0..self.harts.len() {
+            self.select(hart);
+            self.vm.update_timers();
+        }
+        Ok(None)
+    }
+    /// Steps until the machine stops, `condition` holds (checked before every step) or `max_steps` steps were done
+    pub fn run_until(&mut self, mut condition: impl FnMut(&Machine) -> bool, max_steps: Option<u64>) -> Result<StopReason> {
+        let mut steps = 0;
+        loop {
+            if condition(self) {return Ok(StopReason::Condition)}
+            if max_steps.is_some_and(|max| steps >= max) {return Ok(StopReason::InstructionLimit)}
+            if let Some(reason) = self.step()? {return Ok(reason)}
+            steps += 1;
+        }
+    }
+    pub fn run(&mut self, max_steps: Option<u64>) -> Result<StopReason> {
+        self.run_until(|_| false, max_steps)
+    }
+}
@@ -1,22 +1,93 @@
 use clap::Parser;
 #[derive(Parser)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Cli {
-    kernel_file: String,
+    /// Kernel to boot, not needed by subcommands
+    #[arg(required = true)]
+    kernel_file: Option<String>,
 
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
 
-    // #[command(subcommand)]
-    // command: Option<Commands>,
+    #[command(flatten)]
+    run: emulator::args::RunArgs,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(clap::Subcommand)]
+enum Commands {
+    /// Runs a static riscv64 Linux program, its system calls are made on the host
+    User {
+        /// Environment variable of the program (KEY=VALUE), the host's environment isn't passed
+        #[arg(short = 'E', long = "env")]
+        env: Vec<String>,
+        binary: String,
+        /// Arguments of the program
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Runs an ELF in QEMU and in the emulator, reports the first instruction where they differ
+    Difftest {
+        elf: std::path::PathBuf,
+        #[arg(long, default_value = "qemu-system-riscv64")]
+        qemu: std::path::PathBuf,
+        /// Compare against this QEMU log (-d in_asm,cpu,nochain -singlestep) instead of running QEMU
+        #[arg(long)]
+        log: Option<std::path::PathBuf>,
+        /// Seconds QEMU runs before being stopped
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+        /// Instructions shown before the divergence
+        #[arg(long, default_value_t = 10)]
+        context: usize,
+        /// CSR to compare (QEMU's name), defaults to the machine and supervisor trap CSRs
+        #[arg(long = "csr")]
+        csrs: Vec<String>,
+        /// Extra argument given to QEMU
+        #[arg(long = "qemu-arg", allow_hyphen_values = true)]
+        qemu_args: Vec<String>,
+    },
 }
 
 
 fn main() -> color_eyre::eyre::Result<()> {
     color_eyre::install()?;
-    let args = Cli::parse();
-    let program = std::fs::read(args.kernel_file)?;
-    assert!(program.len()%2==0);
-    emulator::vm::run(program)?;
+    let mut args = Cli::parse();
+    let status = match args.command {
+        Some(Commands::User { env, binary, args: program_args }) => {
+            let program = std::fs::read(&binary)?;
+            let argv: Vec<String> = std::iter::once(binary).chain(program_args).collect();
+            emulator::vm::run_user(&program, &argv, &env, &args.run)?
+        },
+        Some(Commands::Difftest { elf, qemu, log, timeout, context, csrs, qemu_args }) => {
+            use emulator::difftest;
+            let mut vm = emulator::vm::VM::with_board(std::fs::read(&elf)?, args.run.board()?)?;
+            let log = match log {
+                Some(log) => log,
+                None => difftest::run_qemu(&qemu, &elf, vm.board.ram[0].size, &qemu_args, std::time::Duration::from_secs(timeout))?,
+            };
+            let mut options = difftest::Options { context, max_instructions: args.run.max_instructions, ..Default::default() };
+            if !csrs.is_empty() {
+                options.csrs = csrs;
+            }
+            let reader = std::io::BufReader::new(std::fs::File::open(&log)?);
+            match difftest::compare(&mut vm, difftest::QemuLog::new(reader), &options)? {
+                difftest::Outcome::Match { instructions } => {println!("No divergence in {instructions} instructions"); None},
+                difftest::Outcome::Diverged(divergence) => {println!("{divergence}"); Some(1)},
+            }
+        },
+        None => {
+            let kernel_file = args.kernel_file.expect("Required by clap");
+            let program = std::fs::read(&kernel_file)?;
+            assert!(emulator::loader::is_elf(&program) || program.len()%2==0);
+            args.run.semihosting_cmdline.get_or_insert(kernel_file);
+            emulator::vm::run(program, &args.run)?
+        },
+    };
+    if let Some(status) = status {
+        std::process::exit(status);
+    }
     Ok(())
 }
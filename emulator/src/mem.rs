@@ -1,10 +1,7 @@
-use std::cell::RefCell;
+use crate::cpu::trap::Exception;
+use crate::uguest;
 
-use color_eyre::eyre::{ContextCompat, Error, Result};
-use color_eyre::Report;
-use crate::{iguest, uguest};
-
-// Inspired by QEMU
+// QEMU virt's memory map (hw/riscv/virt.c), machines are described in board.rs, the `virt` preset follows it
 pub enum MemMap {
     DEBUG,
     MROM,
@@ -35,22 +32,21 @@ impl MemoryMap for MemMap {
             Self::TEST => 0x100000,
             Self::RTC => 0x101000,
             Self::CLINT => 0x2000000,
-            // Self::AclintSswi => 0x2F00000,
-            // Self::PciePio => 0x3000000,
-            // Self::PlatformBus => 0x4000000,
+            Self::AclintSswi => 0x2F00000,
+            Self::PciePio => 0x3000000,
+            Self::PlatformBus => 0x4000000,
             Self::PLIC => 0xc000000,
-            // Self::AplicM => 0xc000000,
-            // Self::AplicS => 0xd000000,
+            Self::AplicM => 0xc000000,
+            Self::AplicS => 0xd000000,
             Self::UART0 => 0x10000000,
             Self::VIRTIO => 0x10001000,
-            // Self::FwCfg => 0x10100000,
+            Self::FwCfg => 0x10100000,
             Self::FLASH => 0x20000000,
-            // Self::ImsicM => 0x24000000,
-            // Self::ImsicS => 0x28000000,
-            // Self::PcieEcam => 0x30000000,
-            // Self::PcieMmio => 0x40000000,
+            Self::ImsicM => 0x24000000,
+            Self::ImsicS => 0x28000000,
+            Self::PcieEcam => 0x30000000,
+            Self::PcieMmio => 0x40000000,
             Self::DRAM => 0x8000_0000,
-            _ => {todo!()}
         }
     }
     fn len(&self) -> uguest {
@@ -60,22 +56,23 @@ impl MemoryMap for MemMap {
             Self::TEST => 0x1000,
             Self::RTC => 0x1000,
             Self::CLINT => 0x10000,
-            // Self::AclintSswi => 0x4000,
-            // Self::PciePio => 0x10000,
-            // Self::PlatformBus => 0x2000000,
-            Self::PLIC => 0x0C20_0000-Self::PLIC.base(),
-            // Self::AplicM => APLIC_SIZE(VIRT_CPUS_MAX),
-            // Self::AplicS => APLIC_SIZE(VIRT_CPUS_MAX),
+            Self::AclintSswi => 0x4000,
+            Self::PciePio => 0x10000,
+            Self::PlatformBus => 0x2000000,
+            // VIRT_PLIC_SIZE(VIRT_CPUS_MAX * 2), the source registers and two contexts per hart
+            Self::PLIC => 0x20_0000 + 0x1000*2*512,
+            // APLIC_SIZE(VIRT_CPUS_MAX), the domain registers and 512 interrupt delivery controls
+            Self::AplicM | Self::AplicS => 0x4000 + 32*512,
             Self::UART0 => 0x100,
             Self::VIRTIO => 0x1000,
-            // Self::FwCfg => 0x18,
-            // Self::FLASH => 0x4000000,
-            // Self::ImsicM => VIRT_IMSIC_MAX_SIZE,
-            // Self::ImsicS => VIRT_IMSIC_MAX_SIZE,
-            // Self::PcieEcam => 0x10000000,
-            // Self::PcieMmio => 0x40000000,
-            Self::DRAM => uguest::MAX-Self::DRAM.base(), // Max size
-            _ => todo!(),
+            Self::FwCfg => 0x18,
+            // Two 32MiB banks
+            Self::FLASH => 0x4000000,
+            // Up to the next one
+            Self::ImsicM | Self::ImsicS => 0x4000000,
+            Self::PcieEcam => 0x10000000,
+            Self::PcieMmio => 0x40000000,
+            Self::DRAM => uguest::MAX-Self::DRAM.base(), // Upper bound, the actual size is `DRAM::len`
         }
     }
 }
@@ -84,169 +81,293 @@ pub trait MemoryMap {
     fn base(&self) -> uguest;
     fn len(&self) -> uguest;
     fn end(&self) -> uguest {self.base()+self.len()}
+    fn is_empty(&self) -> bool {self.len()==0}
+    // Checks the whole access, not only the first byte
     fn in_bounds(&self, offset: uguest, len:uguest) -> bool {
-        offset>=self.base() && offset<self.end()
+        offset>=self.base() && offset.checked_add(len).is_some_and(|end| end<=self.end())
     }
 }
 
-pub trait MemoryRegion: MemoryMap {
-    // Bounds checks must be done by caller
-    unsafe fn read(&self, offset: uguest) -> u8;
-    unsafe fn read_bytes<'a>(&'a self, offset: uguest, len: uguest) -> Vec<u8> {
-        let mut out = Vec::with_capacity(len as _);
-        for i in offset..offset+len {
-            out.push_within_capacity(unsafe {self.read(offset+i)}).unwrap()
+/// Anything that can be mapped on the bus, the base and size are given when registering it (see `Memory::register`)
+pub trait MemoryRegion: std::any::Any {
+    // Offsets are relative to the base of the mapping, bounds checks are done by the bus
+    fn read(&mut self, offset: uguest) -> u8;
+    fn read_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
+        for (i,byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read(offset+i as uguest)
         }
-        out
     }
-    unsafe fn write(&mut self, offset: uguest, val: u8);
-    unsafe fn write_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
-        for (i,byte) in buffer.into_iter().enumerate() {
-            unsafe {self.write(offset+i as uguest, *byte)}
+    fn write(&mut self, offset: uguest, val: u8);
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        for (i,byte) in buffer.iter().enumerate() {
+            self.write(offset+i as uguest, *byte)
+        }
+    }
+    /// Called once per executed instruction
+    fn tick(&mut self) {}
+    /// Level of the interrupt line, it is routed to the irq given when registering the device
+    fn interrupt_pending(&self) -> bool {false}
+    /// Levels of the interrupt lines of devices that have several, bit i is irq + i
+    fn interrupt_lines(&self) -> u32 {
+        self.interrupt_pending() as u32
+    }
+    /// Whether `dma` has work to do, checked after every tick
+    fn dma_pending(&self) -> bool {false}
+    /// Accesses guest memory through the bus, the device's own mapping is unmapped meanwhile
+    fn dma(&mut self, _bus: &mut Memory) {}
+    /// Copy of the state `restore` puts back, for the snapshots of the VM (see `snapshot`)
+    /// None for devices that keep their state when one is restored
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {Ok(None)}
+    fn restore(&mut self, _state: &dyn std::any::Any) -> color_eyre::Result<()> {Ok(())}
+}
+
+/// Stands in for a device while it does DMA
+struct Detached;
+impl MemoryRegion for Detached {
+    fn read(&mut self, _offset: uguest) -> u8 {0}
+    fn write(&mut self, _offset: uguest, _val: u8) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute,
+}
+
+/// An access that didn't land in any region (or crossed the end of one)
+/// The cpu turns it into a load/store/instruction access fault, see `AccessFault::exception`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessFault {
+    pub addr: uguest,
+    pub len: uguest,
+    pub access: AccessType,
+}
+impl AccessFault {
+    pub fn exception(self) -> Exception {
+        match self.access {
+            AccessType::Read => Exception::LoadAccessFault,
+            AccessType::Write => Exception::StoreAccessFault,
+            AccessType::Execute => Exception::InstructionAccessFault,
         }
     }
-    
-    // unsafe fn read<'a>    (&mut self, offset: uguest) -> &'a u8;
-    // unsafe fn read_mut<'a>(&mut self, offset: uguest) -> &'a mut u8;
 }
+impl std::fmt::Display for AccessFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} access fault at {:#x}-{:#x}", self.access, self.addr, self.addr.wrapping_add(self.len))
+    }
+}
+impl std::error::Error for AccessFault {}
 
-#[repr(transparent)]
+pub const PAGE_SIZE: uguest = 4096;
+type Page = Box<[u8; PAGE_SIZE as usize]>;
+
+/// Guest RAM, host pages are only allocated on first write
+/// Reading a page that was never written returns zeros without allocating
 pub struct DRAM {
-    inner: RefCell<Vec<u8>>,
+    size: uguest,
+    pages: Vec<Option<Page>>,
 }
-impl MemoryMap for DRAM {
-    fn base(&self) -> uguest {MemMap::DRAM.base()}
-    fn len(&self) -> uguest {MemMap::DRAM.len()}
+impl DRAM {
+    pub fn new(size: uguest) -> Self {
+        let page_count = size.div_ceil(PAGE_SIZE);
+        Self {
+            size,
+            pages: std::iter::repeat_with(|| None).take(page_count as usize).collect(),
+        }
+    }
+    pub fn size(&self) -> uguest {
+        self.size
+    }
+    /// Amount of host pages that were allocated, mostly for debugging
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+    /// Frees the pages fully inside `offset..offset+len`, they read as zeros again
+    pub fn discard(&mut self, offset: uguest, len: uguest) {
+        let first = offset.div_ceil(PAGE_SIZE) as usize;
+        let last = (offset.saturating_add(len).min(self.size) / PAGE_SIZE) as usize;
+        for page in self.pages.iter_mut().take(last).skip(first) {
+            *page = None;
+        }
+    }
+    fn page_mut(&mut self, page: usize) -> &mut Page {
+        self.pages[page].get_or_insert_with(|| vec![0u8; PAGE_SIZE as usize].into_boxed_slice().try_into().unwrap())
+    }
+    // Calls `f` on every (page index, offset in page, range in buffer) the access touches
+    fn split_pages(offset: uguest, len: usize, mut f: impl FnMut(usize, usize, core::ops::Range<usize>)) {
+        let mut done = 0;
+        while done < len {
+            let addr = offset+done as uguest;
+            let in_page = (addr % PAGE_SIZE) as usize;
+            let chunk = (PAGE_SIZE as usize-in_page).min(len-done);
+            f((addr/PAGE_SIZE) as usize, in_page, done..done+chunk);
+            done += chunk;
+        }
+    }
 }
 impl MemoryRegion for DRAM {
-    unsafe fn read(&self, offset: uguest) -> u8 {
-        if offset>=self.len() {return 0}
-        self.inner.borrow()[offset as usize]
-    }
-    unsafe fn read_bytes<'a>(&'a self, offset: uguest, len: uguest) -> Vec<u8> {
-        let inner_len = self.inner.borrow().len();
-        if (offset+len)>=inner_len as uguest {
-            self.inner.borrow_mut().append(&mut vec![0; ((offset+len)-inner_len as uguest) as usize]);
+    fn read(&mut self, offset: uguest) -> u8 {
+        match &self.pages[(offset/PAGE_SIZE) as usize] {
+            Some(page) => page[(offset%PAGE_SIZE) as usize],
+            None => 0,
         }
-        self.inner.borrow()[offset as usize..(offset+len) as usize].to_vec()
     }
-    
-    unsafe fn write(&mut self, offset: uguest, val: u8) {
-        self.inner.borrow_mut().append(&mut vec![0; offset as usize+10]);
-        self.inner.borrow_mut()[offset as usize] = val;
+    fn read_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
+        let pages = &self.pages;
+        Self::split_pages(offset, buffer.len(), |page, in_page, range| {
+            let out = &mut buffer[range.clone()];
+            match &pages[page] {
+                Some(page) => out.copy_from_slice(&page[in_page..in_page+range.len()]),
+                None => out.fill(0),
+            }
+        });
     }
     
-    unsafe fn write_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
-        if offset+buffer.len() as uguest>=self.inner.borrow().len() as uguest {
-            self.inner.borrow_mut().append(&mut vec![0u8; buffer.len()]);
-            dbg!(self.inner.borrow().len());
-        }
-        self.inner.borrow_mut()[offset as usize..offset as usize+buffer.len()].copy_from_slice(buffer)
+    fn write(&mut self, offset: uguest, val: u8) {
+        self.page_mut((offset/PAGE_SIZE) as usize)[(offset%PAGE_SIZE) as usize] = val;
     }
-}
-#[derive(Debug, Default)]
-pub struct UART {
     
-}
-impl MemoryMap for UART {
-    fn base(&self) -> uguest {MemMap::UART0.base()}
-    fn len(&self) -> uguest {MemMap::UART0.len()}
-}
-impl MemoryRegion for UART {
-    unsafe fn read(&self, offset: uguest) -> u8 {
-        todo!()
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        Self::split_pages(offset, buffer.len(), |page, in_page, range| {
+            self.page_mut(page)[in_page..in_page+range.len()].copy_from_slice(&buffer[range]);
+        });
     }
-
-    unsafe fn write(&mut self, offset: uguest, val: u8) {
-        if offset == 0 {
-            print!("{}", val as char);
-            std::io::Write::flush(&mut std::io::stdout()).unwrap()
-        }
+    /// The allocated pages, the others still read as zeros
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {
+        Ok(Some(Box::new(self.pages.clone())))
+    }
+    fn restore(&mut self, state: &dyn std::any::Any) -> color_eyre::Result<()> {
+        self.pages.clone_from(crate::snapshot::state(state)?);
+        Ok(())
     }
 }
+/// A device registered on the bus
+pub struct Mapping {
+    pub base: uguest,
+    pub len: uguest,
+    /// Interrupt source number on the interrupt controller, if the device is wired to one
+    pub irq: Option<u32>,
+    pub device: Box<dyn MemoryRegion>,
+}
+impl MemoryMap for Mapping {
+    fn base(&self) -> uguest {self.base}
+    fn len(&self) -> uguest {self.len}
+}
 
+/// The system bus, devices are kept sorted by base address so lookups are a binary search
+#[derive(Default)]
 pub struct Memory {
-    dram: DRAM,
-    uart: UART,
+    regions: Vec<Mapping>,
 }
 impl Memory {
-    pub fn with_program(program: Vec<u8>) -> Self {
-        Self {
-            dram: DRAM{inner:RefCell::new(program)},
-            uart: UART::default(),
+    /// An empty bus, accesses fault until devices are registered
+    pub fn empty() -> Self {
+        Self::default()
+    }
+    /// Bus of the `virt` machine description, with `ram_size` bytes of RAM
+    pub fn new(ram_size: uguest) -> Self {
+        crate::board::Board::virt().with_ram_size(ram_size).bus().unwrap()
+    }
+    /// Creates the memory and copies `program` at the start of DRAM
+    pub fn with_program(program: &[u8], ram_size: uguest) -> Result<Self, AccessFault> {
+        let mut mem = Self::new(ram_size);
+        mem.write(MemMap::DRAM.base(), program)?;
+        Ok(mem)
+    }
+    /// Maps `device` at `base..base+len`, fails if it overlaps an already registered device
+    pub fn register(&mut self, base: uguest, len: uguest, irq: Option<u32>, device: impl MemoryRegion) -> color_eyre::Result<()> {
+        let end = base.checked_add(len).filter(|_| len != 0)
+            .ok_or_else(|| color_eyre::Report::msg(format!("Invalid mapping {base:#x} (len {len:#x})")))?;
+        let idx = self.regions.partition_point(|region| region.base < base);
+        let overlapping = [idx.checked_sub(1), Some(idx)].into_iter().flatten()
+            .filter_map(|i| self.regions.get(i))
+            .find(|region| region.base < end && base < region.end());
+        if let Some(region) = overlapping {
+            return Err(color_eyre::Report::msg(format!("Mapping {base:#x}-{end:#x} overlaps {:#x}-{:#x}", region.base, region.end())))
         }
+        self.regions.insert(idx, Mapping { base, len, irq, device: Box::new(device) });
+        Ok(())
+    }
+    pub fn regions(&self) -> &[Mapping] {
+        &self.regions
+    }
+    /// States of the devices by region, for a snapshot of the VM (see `MemoryRegion::snapshot`)
+    pub fn snapshot(&mut self) -> color_eyre::Result<Vec<Option<Box<dyn std::any::Any>>>> {
+        self.regions.iter_mut().map(|region| region.device.snapshot()).collect()
     }
-    pub fn get_region(&mut self, offset: uguest, len:uguest) -> Result<&mut dyn MemoryRegion> {
-        Ok(
-        if offset>=MemMap::DRAM.base() {
-            &mut self.dram
-        } 
-        else if MemMap::UART0.in_bounds(offset, len) {
-            &mut self.uart
+    pub fn restore(&mut self, states: &[Option<Box<dyn std::any::Any>>]) -> color_eyre::Result<()> {
+        if states.len() != self.regions.len() {
+            color_eyre::eyre::bail!("The snapshot has {} devices, the bus {}", states.len(), self.regions.len())
+        }
+        for (region, state) in self.regions.iter_mut().zip(states) {
+            if let Some(state) = state {region.device.restore(state.as_ref())?}
         }
-        else {
-            return Err(Report::msg(format!("Can't find region: {}-{}",offset, offset+len)))
-        })
-    }
-    pub fn get<T: Copy>(&mut self, offset: uguest) -> Result<T> {
-        let region = self.get_region(offset, core::mem::size_of::<T>() as _)?;
-        let bytes = unsafe { region.read_bytes(offset-region.base(), core::mem::size_of::<T>() as _) };
-        Ok(unsafe { *(bytes.as_ptr() as *const T) })
-    }
-    pub fn set<T>(&mut self, offset: uguest, mut val: T) -> Result<()> {
-        let mut vec_val = unsafe { core::slice::from_raw_parts_mut(&mut val as *mut T as *mut u8, core::mem::size_of::<T>()) };
-        let region = self.get_region(offset, core::mem::size_of::<T>() as _)?;
-        unsafe { region.write_bytes(offset-region.base(), &mut vec_val) }
         Ok(())
     }
+    /// Gets the device of type `T` mapped at `base`
+    pub fn device<T: MemoryRegion>(&self, base: uguest) -> Option<&T> {
+        let device: &dyn std::any::Any = self.regions.iter().find(|region| region.base == base)?.device.as_ref();
+        device.downcast_ref()
+    }
+    pub fn device_mut<T: MemoryRegion>(&mut self, base: uguest) -> Option<&mut T> {
+        let device: &mut dyn std::any::Any = self.regions.iter_mut().find(|region| region.base == base)?.device.as_mut();
+        device.downcast_mut()
+    }
+    pub fn get_region(&mut self, offset: uguest, len:uguest) -> Option<&mut Mapping> {
+        let idx = self.regions.partition_point(|region| region.base <= offset).checked_sub(1)?;
+        let region = &mut self.regions[idx];
+        region.in_bounds(offset, len).then_some(region)
+    }
+    fn access(&mut self, offset: uguest, len: uguest, access: AccessType) -> Result<&mut Mapping, AccessFault> {
+        self.get_region(offset, len).ok_or(AccessFault { addr: offset, len, access })
+    }
+    pub fn tick(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.tick()
+        }
+        for i in 0..self.regions.len() {
+            if !self.regions[i].device.dma_pending() {continue}
+            let mut device = std::mem::replace(&mut self.regions[i].device, Box::new(Detached));
+            device.dma(self);
+            self.regions[i].device = device;
+        }
+    }
+    /// Irqs of the devices that currently have their interrupt line raised
+    pub fn pending_irqs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.regions.iter().filter_map(|region| Some((region.irq?, region.device.interrupt_lines())))
+            .flat_map(|(irq, lines)| (0..32).filter(move |line| lines & 1 << line != 0).map(move |line| irq + line))
+    }
+    pub fn get<T: Copy>(&mut self, offset: uguest) -> Result<T, AccessFault> {
+        self.load(offset, AccessType::Read)
+    }
+    /// Same as `get` but faults are reported as instruction access faults
+    pub fn fetch<T: Copy>(&mut self, offset: uguest) -> Result<T, AccessFault> {
+        self.load(offset, AccessType::Execute)
+    }
+    fn load<T: Copy>(&mut self, offset: uguest, access: AccessType) -> Result<T, AccessFault> {
+        let mut val = core::mem::MaybeUninit::<T>::zeroed();
+        // SAFETY: T is Copy and the buffer covers exactly the value
+        let bytes = unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, core::mem::size_of::<T>()) };
+        let region = self.access(offset, bytes.len() as _, access)?;
+        region.device.read_bytes(offset-region.base, bytes);
+        // SAFETY: every byte was written by the region
+        Ok(unsafe { val.assume_init() })
+    }
+    pub fn set<T: Copy>(&mut self, offset: uguest, val: T) -> Result<(), AccessFault> {
+        // SAFETY: T is Copy and the slice covers exactly the value
+        let bytes = unsafe { core::slice::from_raw_parts(&val as *const T as *const u8, core::mem::size_of::<T>()) };
+        self.write(offset, bytes)
+    }
 
-    // For u16, more efficient than `(program[5] as u16) << 8|program[4] as u16` ?
-    pub fn read(&mut self, offset: uguest, len: uguest) -> Result<Vec<u8>> {
-        // Getting mut self because It's a pain
-        let region = self.get_region(offset, len)?;
-        Ok(unsafe { region.read_bytes(offset-region.base(), len) })
-    }
-    pub fn write(&mut self, offset: uguest, buffer: &mut [u8]) -> Result<()> {
-        let region = self.get_region(offset, buffer.len() as _)?;
-        unsafe { region.write_bytes(offset-region.base(), buffer) }
+    pub fn read(&mut self, offset: uguest, buffer: &mut [u8]) -> Result<(), AccessFault> {
+        let region = self.access(offset, buffer.len() as _, AccessType::Read)?;
+        region.device.read_bytes(offset-region.base, buffer);
+        Ok(())
+    }
+    pub fn write(&mut self, offset: uguest, buffer: &[u8]) -> Result<(), AccessFault> {
+        let region = self.access(offset, buffer.len() as _, AccessType::Write)?;
+        region.device.write_bytes(offset-region.base, buffer);
         Ok(())
     }
 }
-
-
-// pub struct MemRegionsIter<'a> {
-//     regions: &'a Memory,
-//     curr: usize,
-// }
-// impl<'a> Iterator for MemRegionsIter<'a> {
-//     type Item = &'a mut dyn MemoryRegion;
-
-//     fn next(&mut self) -> Option<Self::Item> {
-//         let region = &mut match self.curr { // Sorted from most used to less used
-//             0 => self.regions.dram,
-//             // 1 => self.regions.uart,
-//             // 2 => self.regions.plic,
-//             // 3 => self.regions.dram,
-//             // 4 => self.regions.dram,
-//             // 5 => self.regions.dram,
-//             // 6 => self.regions.dram,
-//             // 7 => self.regions.dram,
-//             // 8 => self.regions.dram,
-//             // 9 => self.regions.dram,
-//             // 10 => self.regions.dram,
-//             // 11 => self.regions.dram,
-//             // 12 => self.regions.dram,
-//             // 13 => self.regions.dram,
-//             // 14 => self.regions.dram,
-//             // 15 => self.regions.dram,
-//             // 16 => self.regions.dram,
-//             // 17 => self.regions.dram,
-//             // 18 => self.regions.dram,
-//             // 19 => self.regions.dram,
-//             _ => todo!(),
-//         };
-//         self.curr += 1;
-//         Some(region)
-//     }
-// }
-
@@ -0,0 +1,177 @@
+// A few commands of QEMU's human monitor, to look at and poke the devices of a running VM
+// `--monitor PATH` serves them on a Unix socket, one command per line (e.g. `socat - UNIX-CONNECT:PATH`),
+// the VM looks for them every `PERIOD` steps
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{Context, ContextCompat};
+use color_eyre::Result;
+
+use crate::virtio::block::VirtioBlk;
+use crate::virtio::gpu::VirtioGpu;
+use crate::virtio::net::VirtioNet;
+use crate::vm::VM;
+
+/// Steps between two looks at the socket
+pub const PERIOD: u64 = 4096;
+const HELP: &str = "screendump FILE (.png or .ppm), set_link NIC on|off (NIC is net0, net1...), commit DRIVE (drive0, drive1...), \
+savevm NAME, loadvm NAME, delvm NAME, quit";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Saves what the display shows
+    Screendump(PathBuf),
+    /// Plugs or unplugs the cable of a network card, by index
+    SetLink(usize, bool),
+    /// Writes the overlay of a drive to its image, by index
+    Commit(usize),
+    /// Takes a snapshot of the VM (see `snapshot`), replacing the one with the same name
+    SaveVm(String),
+    /// Puts the VM back as it was in a snapshot
+    LoadVm(String),
+    DelVm(String),
+    Quit,
+    Help,
+}
+impl std::str::FromStr for Command {
+    type Err = String;
+    fn from_str(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["screendump", file] => Ok(Self::Screendump(file.into())),
+            ["set_link", nic, state] => {
+                let nic = nic.strip_prefix("net").and_then(|index| index.parse().ok()).ok_or_else(|| format!("Unknown network card {nic:?}"))?;
+                match *state {
+                    "on" => Ok(Self::SetLink(nic, true)),
+                    "off" => Ok(Self::SetLink(nic, false)),
+                    _ => Err(format!("A link is on or off, not {state:?}")),
+                }
+            },
+            ["commit", drive] => drive.strip_prefix("drive").and_then(|index| index.parse().ok()).map(Self::Commit)
+                .ok_or_else(|| format!("Unknown drive {drive:?}")),
+            ["savevm", name] => Ok(Self::SaveVm(name.to_string())),
+            ["loadvm", name] => Ok(Self::LoadVm(name.to_string())),
+            ["delvm", name] => Ok(Self::DelVm(name.to_string())),
+            ["quit" | "q"] => Ok(Self::Quit),
+            ["help" | "?"] => Ok(Self::Help),
+            _ => Err(format!("Unknown command {line:?}, they are: {HELP}")),
+        }
+    }
+}
+impl Command {
+    /// Runs the command, returns what to answer, `Quit` is left to the caller
+    pub fn execute(&self, vm: &mut VM) -> Result<String> {
+        match self {
+            Self::Screendump(path) => {
+                let gpu = vm.virtio_device_mut::<VirtioGpu>(0).context("The machine has no display, see --gpu")?;
+                let frame = gpu.screendump().context("The display is off")?;
+                frame.save(path)?;
+                Ok(format!("{}x{} frame saved to {}", frame.width, frame.height, path.display()))
+            },
+            Self::SetLink(nic, up) => {
+                vm.virtio_device_mut::<VirtioNet>(*nic).with_context(|| format!("No network card net{nic}"))?.set_link(*up);
+                Ok(String::new())
+            },
+            Self::Commit(drive) => {
+                let disk = vm.virtio_device_mut::<VirtioBlk>(*drive).with_context(|| format!("No drive drive{drive}"))?.disk_mut();
+                let sectors = disk.commit()?;
+                Ok(format!("{sectors} sectors written to {}", disk.path().display()))
+            },
+            Self::SaveVm(name) => {
+                let snapshot = vm.snapshot()?;
+                vm.snapshots.insert(name.clone(), snapshot);
+                Ok(String::new())
+            },
+            Self::LoadVm(name) => {
+                let snapshot = vm.snapshots.remove(name).with_context(|| format!("No snapshot {name:?}"))?;
+                let restored = vm.restore(&snapshot);
+                vm.snapshots.insert(name.clone(), snapshot);
+                restored.map(|()| String::new())
+            },
+            Self::DelVm(name) => {
+                vm.snapshots.remove(name).with_context(|| format!("No snapshot {name:?}"))?;
+                Ok(String::new())
+            },
+            Self::Quit => Ok(String::new()),
+            Self::Help => Ok(HELP.into()),
+        }
+    }
+}
+
+/// A client, with the start of a line it didn't finish
+#[derive(Debug)]
+struct Client {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+/// The socket of `--monitor`
+#[derive(Debug)]
+pub struct Monitor {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+}
+impl Monitor {
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.exists() {
+            std::fs::remove_file(path).with_context(|| format!("Can't remove {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path).with_context(|| format!("Can't serve the monitor at {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, path: path.to_path_buf(), clients: Vec::new() })
+    }
+    /// Runs the commands that arrived, false once one asked to quit
+    pub fn poll(&mut self, vm: &mut VM) -> bool {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.clients.push(Client { stream, line: Vec::new() });
+            }
+        }
+        let mut running = true;
+        self.clients.retain_mut(|client| {
+            let mut buffer = [0; 512];
+            let alive = loop {
+                match client.stream.read(&mut buffer) {
+                    Ok(0) => break false,
+                    Ok(read) => client.line.extend(&buffer[..read]),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break true,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                    Err(_) => break false,
+                }
+            };
+            while let Some(end) = client.line.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = client.line.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                if line.trim().is_empty() {continue}
+                let answer = match line.trim().parse::<Command>() {
+                    Ok(Command::Quit) => {
+                        running = false;
+                        String::new()
+                    },
+                    Ok(command) => command.execute(vm).unwrap_or_else(|err| format!("{err:#}")),
+                    Err(err) => err,
+                };
+                let _ = client.stream.write_all(format!("{answer}\n").as_bytes());
+            }
+            alive
+        });
+        running
+    }
+}
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl VM {
+    /// Runs the monitor commands that arrived, false once one asked to quit
+    pub(crate) fn poll_monitor(&mut self) -> bool {
+        let Some(mut monitor) = self.monitor.take() else {return true};
+        let running = monitor.poll(self);
+        self.monitor = Some(monitor);
+        running
+    }
+}
@@ -0,0 +1,304 @@
+// Host sides of the emulated network, none of them touches a real one: Ethernet frames the guest sends go to a `Backend`,
+// which hands back the frames the guest receives
+// - `Loopback` sends every frame back as is
+// - `Echo` plays a host on the link that answers ARP requests and pings, so a guest stack has someone to talk to
+// - `Hub` links emulator instances through a Unix socket, the first one to open the path serves it and forwards frames
+//   between all the others, frames are prefixed by their length in 4 big endian bytes like QEMU's -netdev stream
+// `Pcap` captures frames to a file Wireshark and tcpdump read
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+
+pub type Mac = [u8; 6];
+
+/// QEMU's default MAC for the first NIC
+pub const DEFAULT_MAC: Mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+/// MAC of the `Echo` host, the one of QEMU's user network gateway
+pub const ECHO_MAC: Mac = [0x52, 0x55, 0x0A, 0x00, 0x02, 0x02];
+pub const BROADCAST: Mac = [0xFF; 6];
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERNET_HEADER: usize = 14;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const IP_ICMP: u8 = 1;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+/// Largest frame the hub takes, without the FCS
+const MAX_FRAME: usize = 65536;
+
+/// Parses aa:bb:cc:dd:ee:ff
+pub fn parse_mac(mac: &str) -> Result<Mac, String> {
+    let bytes: Vec<u8> = mac.split(':').map(|byte| u8::from_str_radix(byte, 16)).collect::<Result<_, _>>()
+        .map_err(|_| format!("Invalid MAC address {mac:?}"))?;
+    bytes.try_into().map_err(|_| format!("A MAC address has 6 bytes, not {mac:?}"))
+}
+
+/// Where the frames of the guest go
+pub trait Backend {
+    /// The guest sent `frame`, from the destination MAC to the payload
+    fn send(&mut self, frame: &[u8]);
+    /// Next frame for the guest
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug, Default)]
+pub struct Loopback {
+    frames: VecDeque<Vec<u8>>,
+}
+impl Backend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        self.frames.push_back(frame.to_vec());
+    }
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+/// Internet checksum (RFC 1071) of `data`
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2).map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32).sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A host answering ARP requests for every address but the asker's, and ICMP echo requests to any of them
+#[derive(Debug)]
+pub struct Echo {
+    pub mac: Mac,
+    frames: VecDeque<Vec<u8>>,
+}
+impl Default for Echo {
+    fn default() -> Self {
+        Self { mac: ECHO_MAC, frames: VecDeque::new() }
+    }
+}
+impl Echo {
+    fn answer(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        let ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+        let mut reply = frame.to_vec();
+        reply[0..6].copy_from_slice(&frame[6..12]);
+        reply[6..12].copy_from_slice(&self.mac);
+        let payload = &mut reply[ETHERNET_HEADER..];
+        match ethertype {
+            // Ethernet and IPv4 addresses only
+            ETHERTYPE_ARP => {
+                if payload.len() < 28 || payload[0..6] != [0, 1, 8, 0, 6, 4] || u16::from_be_bytes([payload[6], payload[7]]) != ARP_REQUEST {return None}
+                let (sender, target) = (payload[14..18].to_vec(), payload[24..28].to_vec());
+                // Gratuitous ARP, and probes for an address the guest wants
+                if sender == target || sender == [0; 4] {return None}
+                let asker = payload[8..14].to_vec();
+                payload[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+                payload[8..14].copy_from_slice(&self.mac);
+                payload[14..18].copy_from_slice(&target);
+                payload[18..24].copy_from_slice(&asker);
+                payload[24..28].copy_from_slice(&sender);
+            },
+            ETHERTYPE_IPV4 => {
+                let header = (*payload.first()? as usize & 0xF) * 4;
+                let total = (u16::from_be_bytes(payload.get(2..4)?.try_into().ok()?) as usize).min(payload.len());
+                if header < 20 || total < header + 8 || payload[9] != IP_ICMP || payload[header] != ICMP_ECHO_REQUEST {return None}
+                let source: [u8; 4] = payload[12..16].try_into().ok()?;
+                payload.copy_within(16..20, 12);
+                payload[16..20].copy_from_slice(&source);
+                payload[8] = 64;
+                payload[10..12].fill(0);
+                let sum = checksum(&payload[..header]);
+                payload[10..12].copy_from_slice(&sum.to_be_bytes());
+                let icmp = &mut payload[header..total];
+                icmp[0] = ICMP_ECHO_REPLY;
+                icmp[2..4].fill(0);
+                let sum = checksum(icmp);
+                icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+            },
+            _ => return None,
+        }
+        Some(reply)
+    }
+}
+impl Backend for Echo {
+    fn send(&mut self, frame: &[u8]) {
+        if let Some(reply) = self.answer(frame) {
+            self.frames.push_back(reply);
+        }
+    }
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+/// Another instance on the hub, with what it was sent and what it sent that isn't a whole frame yet
+#[derive(Debug)]
+struct Peer {
+    stream: UnixStream,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+impl Peer {
+    fn new(stream: UnixStream) -> std::io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self { stream, incoming: Vec::new(), outgoing: Vec::new() })
+    }
+    fn queue(&mut self, frame: &[u8]) {
+        self.outgoing.extend((frame.len() as u32).to_be_bytes());
+        self.outgoing.extend(frame);
+    }
+    /// Writes what the socket takes, false once the peer is gone
+    fn flush(&mut self) -> bool {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return false,
+                Ok(written) => {self.outgoing.drain(..written);},
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+    /// Reads the frames that arrived, false once the peer is gone
+    fn read(&mut self, frames: &mut Vec<Vec<u8>>) -> bool {
+        let mut buffer = [0; 4096];
+        let alive = loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => break false,
+                Ok(read) => self.incoming.extend(&buffer[..read]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break true,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(_) => break false,
+            }
+        };
+        while let Some(len) = self.incoming.get(..4).map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize) {
+            if len > MAX_FRAME {
+                log::warn!("Dropping a hub peer that sent a {len} bytes frame");
+                return false
+            }
+            if self.incoming.len() < 4 + len {break}
+            frames.push(self.incoming[4..4 + len].to_vec());
+            self.incoming.drain(..4 + len);
+        }
+        alive
+    }
+}
+
+/// Ethernet hub over a Unix socket, see the top of the file
+#[derive(Debug)]
+pub struct Hub {
+    /// Set when this instance serves the hub
+    listener: Option<(UnixListener, PathBuf)>,
+    peers: Vec<Peer>,
+    frames: VecDeque<Vec<u8>>,
+}
+impl Hub {
+    /// Joins the hub at `path`, or serves it if nobody does
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut hub = Self { listener: None, peers: Vec::new(), frames: VecDeque::new() };
+        match UnixStream::connect(path) {
+            Ok(stream) => hub.peers.push(Peer::new(stream)?),
+            Err(_) => {
+                // Left by an instance that didn't exit cleanly
+                if path.exists() {
+                    std::fs::remove_file(path).with_context(|| format!("Can't remove the stale socket {}", path.display()))?;
+                }
+                let listener = UnixListener::bind(path).with_context(|| format!("Can't serve the network hub at {}", path.display()))?;
+                listener.set_nonblocking(true)?;
+                hub.listener = Some((listener, path.to_path_buf()));
+            },
+        }
+        Ok(hub)
+    }
+    pub fn serving(&self) -> bool {
+        self.listener.is_some()
+    }
+    pub fn peers(&self) -> usize {
+        self.peers.len()
+    }
+    /// Takes new peers, reads what they sent and forwards it when serving
+    pub fn poll(&mut self) {
+        if let Some((listener, _)) = &self.listener {
+            while let Ok((stream, _)) = listener.accept() {
+                match Peer::new(stream) {
+                    Ok(peer) => self.peers.push(peer),
+                    Err(err) => log::warn!("Can't take a hub peer: {err}"),
+                }
+            }
+        }
+        let mut i = 0;
+        while i < self.peers.len() {
+            let mut frames = Vec::new();
+            if !self.peers[i].read(&mut frames) || !self.peers[i].flush() {
+                self.peers.remove(i);
+                continue
+            }
+            for frame in frames {
+                if self.serving() {
+                    for (j, peer) in self.peers.iter_mut().enumerate() {
+                        if j != i {peer.queue(&frame)}
+                    }
+                }
+                self.frames.push_back(frame);
+            }
+            i += 1;
+        }
+    }
+}
+impl Backend for Hub {
+    fn send(&mut self, frame: &[u8]) {
+        for peer in &mut self.peers {
+            peer.queue(frame);
+        }
+        self.peers.retain_mut(Peer::flush);
+    }
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        if self.frames.is_empty() {self.poll()}
+        self.frames.pop_front()
+    }
+}
+impl Drop for Hub {
+    fn drop(&mut self) {
+        if let Some((_, path)) = &self.listener {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Capture file in the pcap format, Ethernet link type
+#[derive(Debug)]
+pub struct Pcap {
+    file: File,
+}
+impl Pcap {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = File::create(path).with_context(|| format!("Can't create the capture {}", path.display()))?;
+        // Magic, version 2.4, UTC, snapshot length and LINKTYPE_ETHERNET
+        let mut header = 0xA1B2_C3D4u32.to_le_bytes().to_vec();
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        header.extend([0; 8]);
+        header.extend((MAX_FRAME as u32).to_le_bytes());
+        header.extend(1u32.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self { file })
+    }
+    /// Appends `frame`, stamped with the host time
+    pub fn capture(&mut self, frame: &[u8]) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = (time.as_secs() as u32).to_le_bytes().to_vec();
+        record.extend(time.subsec_micros().to_le_bytes());
+        record.extend((frame.len() as u32).to_le_bytes());
+        record.extend((frame.len() as u32).to_le_bytes());
+        record.extend(frame);
+        if let Err(err) = self.file.write_all(&record) {
+            log::warn!("Can't write to the capture: {err}");
+        }
+    }
+}
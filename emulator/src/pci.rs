@@ -0,0 +1,302 @@
+// PCI Express host bridge with ECAM configuration space (pci-host-ecam-generic), like QEMU virt's GPEX
+// Functions sit on bus 0, one per slot (device number), the host bridge itself in slot 0. Software sizes and assigns
+// their BARs, memory BARs are decoded in the MMIO window right after the ECAM space (`MemMap::PcieMmio` on virt),
+// with PCI addresses equal to CPU ones. I/O BARs aren't implemented, `MemMap::PciePio` stays unmapped.
+// INTx are swizzled like on QEMU virt: pin P of slot S raises line (P - 1 + S) % 4, the host is registered with
+// the irq of line 0 (INTA, `PCIE_IRQ`) and raises irq + line
+use crate::mem::{MemMap, Memory, MemoryMap, MemoryRegion};
+use crate::uguest;
+
+/// Irq of INTA on QEMU virt, INTB to INTD follow
+pub const PCIE_IRQ: u32 = 32;
+/// Configuration space of a function
+pub const CONFIG_SIZE: usize = 0x1000;
+/// Slots of a bus
+pub const SLOTS: usize = 32;
+
+// Configuration space header (type 0)
+pub const VENDOR_ID: usize = 0x00;
+pub const DEVICE_ID: usize = 0x02;
+pub const COMMAND: usize = 0x04;
+pub const STATUS: usize = 0x06;
+pub const REVISION_ID: usize = 0x08;
+pub const CLASS_CODE: usize = 0x09;
+pub const HEADER_TYPE: usize = 0x0E;
+pub const BAR0: usize = 0x10;
+pub const SUBSYSTEM_VENDOR_ID: usize = 0x2C;
+pub const SUBSYSTEM_ID: usize = 0x2E;
+pub const CAPABILITIES: usize = 0x34;
+pub const INTERRUPT_LINE: usize = 0x3C;
+pub const INTERRUPT_PIN: usize = 0x3D;
+/// Command bits: memory space, bus master, INTx disable
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+/// Status bits: INTx pending, capabilities list
+pub const STATUS_INTERRUPT: u16 = 1 << 3;
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+/// Capabilities start after the header
+const FIRST_CAPABILITY: usize = 0x40;
+
+/// Configuration space of a function, with the bits software can write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciConfig {
+    space: Vec<u8>,
+    writable: Vec<u8>,
+    /// Size of each BAR, 0 when unused (and for the upper half of 64 bits ones)
+    bar_sizes: [uguest; 6],
+    /// Offset of the last capability of the list, 0 when there is none
+    last_capability: usize,
+    /// Where the next capability goes
+    free: usize,
+}
+impl PciConfig {
+    /// `class` is the class code, sub-class and programming interface (e.g. 0x020000 for an Ethernet controller)
+    pub fn new(vendor: u16, device: u16, class: u32, revision: u8) -> Self {
+        let mut config = Self { space: vec![0; CONFIG_SIZE], writable: vec![0; CONFIG_SIZE], bar_sizes: [0; 6], last_capability: 0, free: FIRST_CAPABILITY };
+        config.set_u16(VENDOR_ID, vendor);
+        config.set_u16(DEVICE_ID, device);
+        config.space[REVISION_ID] = revision;
+        config.space[CLASS_CODE..CLASS_CODE + 3].copy_from_slice(&class.to_le_bytes()[..3]);
+        config.set_writable(COMMAND, &(COMMAND_MEMORY | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE).to_le_bytes());
+        config.set_writable(INTERRUPT_LINE, &[0xFF]);
+        config
+    }
+    pub fn u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.space[offset], self.space[offset + 1]])
+    }
+    pub fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.space[offset..offset + 4].try_into().unwrap())
+    }
+    /// Writes as the function, whatever software can write
+    pub fn set_u16(&mut self, offset: usize, value: u16) {
+        self.space[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    pub fn set_u32(&mut self, offset: usize, value: u32) {
+        self.space[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    pub fn set_writable(&mut self, offset: usize, mask: &[u8]) {
+        self.writable[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+    pub fn set_subsystem(&mut self, vendor: u16, id: u16) {
+        self.set_u16(SUBSYSTEM_VENDOR_ID, vendor);
+        self.set_u16(SUBSYSTEM_ID, id);
+    }
+    /// INTA to INTD are 1 to 4, 0 when the function doesn't use INTx
+    pub fn set_interrupt_pin(&mut self, pin: u8) {
+        self.space[INTERRUPT_PIN] = pin;
+    }
+    pub fn interrupt_pin(&self) -> u8 {
+        self.space[INTERRUPT_PIN]
+    }
+    pub fn command(&self) -> u16 {
+        self.u16(COMMAND)
+    }
+    /// Memory BAR `index` of `size` bytes (a power of 2), 64 bits ones also take the next BAR
+    /// Sizing works by itself: the bits under the size aren't writable
+    pub fn add_memory_bar(&mut self, index: usize, size: uguest, bits64: bool) {
+        assert!(size.is_power_of_two() && size >= 16, "BARs are at least 16 bytes, with a power of 2 size");
+        let offset = BAR0 + 4 * index;
+        self.bar_sizes[index] = size;
+        self.set_u32(offset, if bits64 {0b100} else {0});
+        self.set_writable(offset, &(!(size - 1) as u32 & !0xF).to_le_bytes());
+        if bits64 {
+            self.set_writable(offset + 4, &((!(size - 1)) >> 32).to_le_bytes()[..4]);
+        }
+    }
+    /// Address and size of memory BAR `index`, once assigned and with memory decoding on
+    pub fn bar(&self, index: usize) -> Option<(uguest, uguest)> {
+        let size = self.bar_sizes[index];
+        if size == 0 || self.command() & COMMAND_MEMORY == 0 {return None}
+        let low = self.u32(BAR0 + 4 * index);
+        let high = if low & 0b110 == 0b100 {self.u32(BAR0 + 4 * index + 4)} else {0};
+        let addr = (high as uguest) << 32 | (low & !0xF) as uguest;
+        (addr != 0).then_some((addr, size))
+    }
+    /// Appends a capability to the list, `body` follows the ID and next pointer, returns its offset
+    pub fn add_capability(&mut self, id: u8, body: &[u8]) -> usize {
+        let offset = self.free;
+        // Capabilities are dword aligned
+        self.free = (offset + 2 + body.len()).next_multiple_of(4);
+        self.space[offset] = id;
+        self.space[offset + 2..offset + 2 + body.len()].copy_from_slice(body);
+        if self.last_capability == 0 {
+            self.space[CAPABILITIES] = offset as u8;
+            self.set_u16(STATUS, self.u16(STATUS) | STATUS_CAPABILITIES);
+        } else {
+            self.space[self.last_capability + 1] = offset as u8;
+        }
+        self.last_capability = offset;
+        offset
+    }
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.space.get(offset + i).copied().unwrap_or(0);
+        }
+    }
+    /// Only the writable bits change
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let Some(mask) = self.writable.get(offset + i) else {return};
+            self.space[offset + i] = (self.space[offset + i] & !mask) | (byte & mask);
+        }
+    }
+}
+
+/// A function on the bus, its configuration space and what its BARs decode
+pub trait PciFunction: std::any::Any {
+    fn config(&self) -> &PciConfig;
+    fn config_mut(&mut self) -> &mut PciConfig;
+    /// Configuration space accesses, the function can handle some registers itself
+    fn read_config(&mut self, offset: usize, buffer: &mut [u8]) {
+        self.config().read(offset, buffer)
+    }
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        self.config_mut().write(offset, data)
+    }
+    fn read_bar(&mut self, bar: usize, offset: uguest, buffer: &mut [u8]);
+    fn write_bar(&mut self, bar: usize, offset: uguest, data: &[u8]);
+    /// Level of the INTx pin of the function
+    fn interrupt_pending(&self) -> bool {false}
+    fn tick(&mut self) {}
+    fn dma_pending(&self) -> bool {false}
+    fn dma(&mut self, _bus: &mut Memory) {}
+}
+
+/// What sits in slot 0, QEMU's "gpex-root" (Red Hat, Inc. QEMU PCIe Host bridge)
+pub struct HostBridge(PciConfig);
+impl Default for HostBridge {
+    fn default() -> Self {
+        Self(PciConfig::new(0x1B36, 0x0008, 0x06_00_00, 0))
+    }
+}
+impl PciFunction for HostBridge {
+    fn config(&self) -> &PciConfig {
+        &self.0
+    }
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.0
+    }
+    fn read_bar(&mut self, _bar: usize, _offset: uguest, _buffer: &mut [u8]) {}
+    fn write_bar(&mut self, _bar: usize, _offset: uguest, _data: &[u8]) {}
+}
+
+/// The ECAM space followed by the MMIO window, a single mapping
+pub struct PcieHost {
+    /// Where the mapping starts, BAR addresses are absolute
+    base: uguest,
+    ecam_size: uguest,
+    /// By slot
+    functions: Vec<Box<dyn PciFunction>>,
+}
+impl PcieHost {
+    /// Bytes mapped on QEMU virt: the ECAM space and the 32 bits MMIO window
+    pub fn size() -> uguest {
+        MemMap::PcieEcam.len() + MemMap::PcieMmio.len()
+    }
+    /// Host mapped at `base`, with the ECAM space of virt
+    pub fn new(base: uguest) -> Self {
+        Self { base, ecam_size: MemMap::PcieEcam.len(), functions: vec![Box::new(HostBridge::default())] }
+    }
+    /// Plugs `function` in the next free slot, returns the slot
+    pub fn add(&mut self, function: impl PciFunction) -> Option<usize> {
+        if self.functions.len() >= SLOTS {return None}
+        self.functions.push(Box::new(function));
+        Some(self.functions.len() - 1)
+    }
+    pub fn slots(&self) -> usize {
+        self.functions.len()
+    }
+    pub fn function<T: PciFunction>(&self, slot: usize) -> Option<&T> {
+        let function: &dyn std::any::Any = self.functions.get(slot)?.as_ref();
+        function.downcast_ref()
+    }
+    pub fn function_mut<T: PciFunction>(&mut self, slot: usize) -> Option<&mut T> {
+        let function: &mut dyn std::any::Any = self.functions.get_mut(slot)?.as_mut();
+        function.downcast_mut()
+    }
+    /// Function and register of an ECAM offset, only function 0 of the slots of bus 0 exist
+    fn config_function(&mut self, offset: uguest) -> Option<(&mut Box<dyn PciFunction>, usize)> {
+        let (bus, slot, function) = (offset >> 20, (offset >> 15 & 0x1F) as usize, offset >> 12 & 0b111);
+        if bus != 0 || function != 0 {return None}
+        Some((self.functions.get_mut(slot)?, (offset & 0xFFF) as usize))
+    }
+    /// Function, BAR and offset in the BAR of an address of the MMIO window
+    fn bar_function(&mut self, addr: uguest, len: usize) -> Option<(&mut Box<dyn PciFunction>, usize, uguest)> {
+        self.functions.iter_mut().find_map(|function| {
+            let bar = (0..6).find_map(|index| {
+                let (base, size) = function.config().bar(index)?;
+                // A 64 bits BAR can be put at the end of the address space, offsets don't overflow
+                let offset = addr.checked_sub(base)?;
+                (offset.checked_add(len as uguest)? <= size).then_some((index, offset))
+            })?;
+            Some((function, bar.0, bar.1))
+        })
+    }
+}
+impl MemoryRegion for PcieHost {
+    fn read(&mut self, offset: uguest) -> u8 {
+        let mut byte = [0];
+        self.read_bytes(offset, &mut byte);
+        byte[0]
+    }
+    fn read_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
+        if offset < self.ecam_size {
+            let Some((function, register)) = self.config_function(offset) else {
+                // Nothing there, vendor ID 0xFFFF
+                return buffer.fill(0xFF)
+            };
+            function.read_config(register, buffer);
+            // Status.Interrupt follows the pin
+            if (register..register + buffer.len()).contains(&STATUS) && function.interrupt_pending() {
+                buffer[STATUS - register] |= STATUS_INTERRUPT as u8;
+            }
+            return
+        }
+        let addr = self.base + offset;
+        match self.bar_function(addr, buffer.len()) {
+            Some((function, bar, offset)) => function.read_bar(bar, offset, buffer),
+            None => buffer.fill(0),
+        }
+    }
+    fn write(&mut self, offset: uguest, val: u8) {
+        self.write_bytes(offset, &[val])
+    }
+    fn write_bytes(&mut self, offset: uguest, data: &[u8]) {
+        if offset < self.ecam_size {
+            if let Some((function, register)) = self.config_function(offset) {
+                function.write_config(register, data);
+            }
+            return
+        }
+        let addr = self.base + offset;
+        if let Some((function, bar, offset)) = self.bar_function(addr, data.len()) {
+            function.write_bar(bar, offset, data);
+        }
+    }
+    fn tick(&mut self) {
+        for function in self.functions.iter_mut() {
+            function.tick()
+        }
+    }
+    fn interrupt_lines(&self) -> u32 {
+        let mut lines = 0;
+        for (slot, function) in self.functions.iter().enumerate() {
+            let pin = function.config().interrupt_pin();
+            if pin == 0 || function.config().command() & COMMAND_INTX_DISABLE != 0 || !function.interrupt_pending() {continue}
+            lines |= 1 << ((pin as usize - 1 + slot) % 4);
+        }
+        lines
+    }
+    /// Only bus masters can reach memory
+    fn dma_pending(&self) -> bool {
+        self.functions.iter().any(|function| function.dma_pending() && function.config().command() & COMMAND_BUS_MASTER != 0)
+    }
+    fn dma(&mut self, bus: &mut Memory) {
+        for function in self.functions.iter_mut() {
+            if function.dma_pending() && function.config().command() & COMMAND_BUS_MASTER != 0 {
+                function.dma(bus)
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// CFI parallel flash with the Intel command set (QEMU's hw/block/pflash_cfi01.c), one 16 bits wide device per bank
+// Commands are written to any address of the bank, the query and identifier ones read words (offset / 2)
+// Programming can only clear bits and erasing sets a whole block back to 0xFF, like real flash
+// With a backing file, every program and erase is written to it right away so it survives the run
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+
+use crate::mem::MemoryRegion;
+use crate::uguest;
+
+/// Size of a bank on QEMU virt, which has two
+pub const BANK_SIZE: uguest = 32 << 20;
+pub const BLOCK_SIZE: uguest = 256 << 10;
+/// Bytes per access of the device, the bank-width of the device tree
+pub const WIDTH: u32 = 2;
+
+// Commands, the low byte of a write
+const READ_ARRAY: u8 = 0xFF;
+const READ_ID: u8 = 0x90;
+const CFI_QUERY: u8 = 0x98;
+const READ_STATUS: u8 = 0x70;
+const CLEAR_STATUS: u8 = 0x50;
+const PROGRAM: u8 = 0x40;
+const PROGRAM_ALT: u8 = 0x10;
+const ERASE: u8 = 0x20;
+const LOCK_SETUP: u8 = 0x60;
+const CONFIRM: u8 = 0xD0;
+
+// Status register
+pub const STATUS_READY: u8 = 0x80;
+pub const STATUS_ERASE_ERROR: u8 = 0x20;
+pub const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+/// Intel, 28F128J3
+const MANUFACTURER: u16 = 0x89;
+const DEVICE: u16 = 0x18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    ReadArray,
+    ReadId,
+    Query,
+    Status,
+    /// The next write is the data to program
+    Program,
+    /// The next write confirms the erase
+    Erase,
+    /// The next write locks or unlocks a block, blocks are never locked
+    Lock,
+}
+
+pub struct Pflash {
+    data: Vec<u8>,
+    file: Option<File>,
+    mode: Mode,
+    status: u8,
+    /// Words of the CFI query structure
+    query: Vec<u16>,
+}
+impl Pflash {
+    /// Erased flash, lost at exit
+    pub fn new(size: uguest) -> Self {
+        let query = Self::query_table(size);
+        Self { data: vec![0xFF; size as usize], file: None, mode: Mode::ReadArray, status: STATUS_READY, query }
+    }
+    /// Flash with the content of `path`, created if missing and padded with erased blocks if smaller than `size`
+    pub fn open(path: &Path, size: uguest) -> Result<Self> {
+        let mut file = File::options().read(true).write(true).create(true).truncate(false).open(path)
+            .with_context(|| format!("Can't open the flash image {}", path.display()))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if data.len() as uguest > size {
+            bail!("The flash image {} is {} bytes, more than the {size} of the bank", path.display(), data.len())
+        }
+        let image = data.len();
+        data.resize(size as usize, 0xFF);
+        file.write_all(&data[image..])?;
+        Ok(Self { data, file: Some(file), mode: Mode::ReadArray, status: STATUS_READY, query: Self::query_table(size) })
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    /// CFI query structure, from word 0x10, and the Intel extended query table at 0x31
+    fn query_table(size: uguest) -> Vec<u16> {
+        let blocks = (size / BLOCK_SIZE).max(1) - 1;
+        let mut table = vec![0; 0x10];
+        table.extend(b"QRY".map(u16::from));
+        // Intel command set, extended table at 0x31, no alternate one
+        table.extend([0x01, 0x00, 0x31, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        // Vcc 4.5-5.5V, no Vpp
+        table.extend([0x45, 0x55, 0x00, 0x00]);
+        // Typical timeouts: 128us per word, no buffered writes, 1s per block, no chip erase, then their maximums (x16)
+        table.extend([0x07, 0x00, 0x0A, 0x00, 0x04, 0x00, 0x04, 0x00]);
+        table.push(size.max(1).ilog2() as u16);
+        // x16 interface, no write buffer, one region of identical blocks
+        table.extend([0x01, 0x00, 0x00, 0x00, 0x01]);
+        table.extend([blocks as u16 & 0xFF, blocks as u16 >> 8, (BLOCK_SIZE >> 8) as u16 & 0xFF, (BLOCK_SIZE >> 16) as u16]);
+        // "PRI" version 1.0, without optional features
+        table.extend(b"PRI10".map(u16::from));
+        table.extend([0; 11]);
+        table
+    }
+    fn block(offset: uguest) -> std::ops::Range<usize> {
+        let start = offset - offset % BLOCK_SIZE;
+        start as usize..(start + BLOCK_SIZE) as usize
+    }
+    fn word(&self, offset: uguest) -> u16 {
+        let index = offset / WIDTH as uguest;
+        match self.mode {
+            Mode::ReadId => match index % (BLOCK_SIZE / WIDTH as uguest) {
+                0 => MANUFACTURER,
+                1 => DEVICE,
+                // Block lock status
+                _ => 0,
+            },
+            Mode::Query => self.query.get(index as usize).copied().unwrap_or(0),
+            _ => self.status as u16,
+        }
+    }
+    /// Writes `range` to the backing file
+    fn persist(&mut self, range: std::ops::Range<usize>) {
+        let Some(file) = &mut self.file else {return};
+        let written = file.seek(SeekFrom::Start(range.start as u64)).and_then(|_| file.write_all(&self.data[range]));
+        if let Err(err) = written {
+            log::warn!("Can't write to the flash image: {err}");
+        }
+    }
+    fn command(&mut self, offset: uguest, buffer: &[u8]) {
+        let command = buffer[0];
+        self.mode = match (self.mode, command) {
+            (Mode::Program, _) => {
+                let range = offset as usize..offset as usize + buffer.len();
+                for (byte, value) in self.data[range.clone()].iter_mut().zip(buffer) {
+                    *byte &= value;
+                }
+                self.persist(range);
+                Mode::Status
+            },
+            (Mode::Erase, CONFIRM) => {
+                let block = Self::block(offset);
+                self.data[block.clone()].fill(0xFF);
+                self.persist(block);
+                Mode::Status
+            },
+            // Command sequence error
+            (Mode::Erase, _) => {
+                self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                Mode::Status
+            },
+            (Mode::Lock, _) => Mode::Status,
+            (_, READ_ARRAY | 0x00) => Mode::ReadArray,
+            (_, READ_ID) => Mode::ReadId,
+            (_, CFI_QUERY) => Mode::Query,
+            (_, READ_STATUS) => Mode::Status,
+            (mode, CLEAR_STATUS) => {
+                self.status = STATUS_READY;
+                mode
+            },
+            (_, PROGRAM | PROGRAM_ALT) => Mode::Program,
+            (_, ERASE) => Mode::Erase,
+            (_, LOCK_SETUP) => Mode::Lock,
+            _ => {
+                log::warn!("Unknown flash command {command:#x}");
+                Mode::ReadArray
+            },
+        };
+    }
+}
+impl MemoryRegion for Pflash {
+    fn read(&mut self, offset: uguest) -> u8 {
+        match self.mode {
+            Mode::ReadArray => self.data[offset as usize],
+            _ => (self.word(offset) >> (offset % WIDTH as uguest * 8)) as u8,
+        }
+    }
+    fn read_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
+        if self.mode == Mode::ReadArray {
+            buffer.copy_from_slice(&self.data[offset as usize..offset as usize + buffer.len()]);
+        } else {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = self.read(offset + i as uguest)
+            }
+        }
+    }
+    fn write(&mut self, offset: uguest, val: u8) {
+        self.command(offset, &[val]);
+    }
+    /// A write is a single command or the data to program, whatever its width
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        if !buffer.is_empty() {
+            self.command(offset, buffer);
+        }
+    }
+}
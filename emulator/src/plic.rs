@@ -0,0 +1,186 @@
+// Platform-Level Interrupt Controller (https://github.com/riscv/riscv-plic-spec, v1.0.0), QEMU virt's default (aia=none)
+// Like QEMU virt's, each hart has two contexts, 2*hart for M-mode (MEIP) and 2*hart+1 for S-mode (SEIP).
+// Sources are level-triggered: a source is pending while its line is high, until it is claimed, and pending again
+// once completed if its line is still high.
+use crate::aia::Level;
+use crate::board::DeviceKind;
+use crate::mem::MemoryRegion;
+use crate::vm::VM;
+use crate::uguest;
+
+/// Interrupt sources, like QEMU virt's, source 0 doesn't exist
+pub const PLIC_SOURCES: usize = 96;
+/// Priorities are 3 bits, 0 never interrupts
+const PRIORITY_MAX: u32 = 7;
+
+// Registers, the enable bits and the context registers are per context
+const PRIORITY: uguest = 0x0000;
+const PENDING: uguest = 0x1000;
+const ENABLE: uguest = 0x2000;
+const ENABLE_STRIDE: uguest = 0x80;
+const CONTEXT: uguest = 0x20_0000;
+const CONTEXT_STRIDE: uguest = 0x1000;
+const THRESHOLD: uguest = 0x0;
+const CLAIM: uguest = 0x4;
+
+/// Threshold and enable bits of a context
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Context {
+    threshold: u32,
+    enabled: Vec<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Plic {
+    priority: Vec<u32>,
+    /// Line of each source at the last update
+    level: Vec<bool>,
+    /// Claimed and not completed yet, the gateway doesn't forward the source meanwhile
+    claimed: Vec<bool>,
+    contexts: Vec<Context>,
+}
+impl Plic {
+    pub fn new(harts: usize) -> Self {
+        Self {
+            priority: vec![0; PLIC_SOURCES],
+            level: vec![false; PLIC_SOURCES],
+            claimed: vec![false; PLIC_SOURCES],
+            contexts: vec![Context { threshold: 0, enabled: vec![false; PLIC_SOURCES] }; 2 * harts],
+        }
+    }
+    /// Context of `hart` at `level`
+    pub fn context(hart: usize, level: Level) -> usize {
+        2 * hart + level as usize
+    }
+    fn pending(&self, source: usize) -> bool {
+        self.level[source] && !self.claimed[source]
+    }
+    /// Bits of the 32 sources of word `word`
+    fn word(&self, word: usize, bit: impl Fn(usize) -> bool) -> u32 {
+        (0..32).filter(|i| word*32 + i < PLIC_SOURCES && bit(word*32 + i)).fold(0, |word, i| word | 1 << i)
+    }
+
+    /// Samples the interrupt lines, `raised` are the sources whose line is high
+    pub fn update(&mut self, raised: &[u32]) {
+        for source in 1..PLIC_SOURCES {
+            self.level[source] = raised.contains(&(source as u32));
+        }
+    }
+    /// Highest priority source pending and enabled for `context` above its threshold, the lowest one on ties
+    fn top(&self, context: usize) -> Option<usize> {
+        let context = self.contexts.get(context)?;
+        (1..PLIC_SOURCES)
+            .filter(|&source| self.pending(source) && context.enabled[source] && self.priority[source] > context.threshold)
+            .min_by_key(|&source| (PRIORITY_MAX - self.priority[source], source))
+    }
+    /// Level of the external interrupt of `context`
+    pub fn interrupt(&self, context: usize) -> bool {
+        self.top(context).is_some()
+    }
+    /// Claims the top interrupt of `context`, 0 if there is none
+    pub fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.top(context) else {return 0};
+        self.claimed[source] = true;
+        source as u32
+    }
+    /// Completes `source`, ignored if `context` doesn't have it enabled
+    pub fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if self.contexts.get(context).is_some_and(|context| context.enabled.get(source) == Some(&true)) {
+            self.claimed[source] = false;
+        }
+    }
+
+    fn read_register(&mut self, offset: uguest) -> u32 {
+        match offset {
+            PRIORITY..PENDING => self.priority.get(((offset - PRIORITY) / 4) as usize).copied().unwrap_or(0),
+            PENDING..ENABLE => self.word(((offset - PENDING) / 4) as usize, |source| self.pending(source)),
+            ENABLE..CONTEXT => {
+                let (context, word) = (((offset - ENABLE) / ENABLE_STRIDE) as usize, ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize);
+                let Some(context) = self.contexts.get(context) else {return 0};
+                self.word(word, |source| context.enabled[source])
+            },
+            CONTEXT.. => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    THRESHOLD => self.contexts.get(context).map_or(0, |context| context.threshold),
+                    CLAIM => self.claim(context),
+                    _ => 0,
+                }
+            },
+        }
+    }
+    fn write_register(&mut self, offset: uguest, value: u32) {
+        match offset {
+            // Source 0 doesn't exist, its priority stays 0
+            PRIORITY..PENDING => {
+                let source = ((offset - PRIORITY) / 4) as usize;
+                if (1..PLIC_SOURCES).contains(&source) {
+                    self.priority[source] = value & PRIORITY_MAX;
+                }
+            },
+            // Pending bits are read-only
+            PENDING..ENABLE => {},
+            ENABLE..CONTEXT => {
+                let (context, word) = (((offset - ENABLE) / ENABLE_STRIDE) as usize, ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize);
+                let Some(context) = self.contexts.get_mut(context) else {return};
+                for (i, enabled) in context.enabled.iter_mut().enumerate().skip(1).filter(|(i, _)| i / 32 == word) {
+                    *enabled = value & 1 << (i % 32) != 0;
+                }
+            },
+            CONTEXT.. => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    THRESHOLD => if let Some(context) = self.contexts.get_mut(context) {context.threshold = value & PRIORITY_MAX},
+                    CLAIM => self.complete(context, value),
+                    _ => {},
+                }
+            },
+        }
+    }
+}
+impl MemoryRegion for Plic {
+    fn read(&mut self, offset: uguest) -> u8 {
+        (self.read_register(offset & !3) >> (offset % 4 * 8)) as u8
+    }
+    fn read_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
+        // Reading the claim register claims, registers are read once
+        if buffer.len() == 4 && offset.is_multiple_of(4) {
+            buffer.copy_from_slice(&self.read_register(offset).to_le_bytes());
+        } else {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = self.read(offset + i as uguest)
+            }
+        }
+    }
+    /// Registers are written 32 bits at a time
+    fn write(&mut self, _offset: uguest, _val: u8) {}
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        if let (Ok(word), true) = (<[u8; 4]>::try_from(buffer), offset.is_multiple_of(4)) {
+            self.write_register(offset, u32::from_le_bytes(word));
+        }
+    }
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {
+        crate::snapshot::cloned(self)
+    }
+    fn restore(&mut self, state: &dyn std::any::Any) -> color_eyre::Result<()> {
+        crate::snapshot::restore_cloned(self, state)
+    }
+}
+
+impl VM {
+    /// Takes the interrupt lines of the devices through the PLIC to the current hart, called once devices ticked
+    /// A board has either a PLIC or APLIC domains, see `Board::with_aia`
+    pub(crate) fn route_plic_interrupts(&mut self) {
+        let Some(base) = self.board.device(DeviceKind::Plic).map(|device| device.base) else {return};
+        let raised: Vec<u32> = self.mem.pending_irqs().collect();
+        let hart = self.cpu.hartid as usize;
+        let Some(plic) = self.mem.device_mut::<Plic>(base) else {return};
+        plic.update(&raised);
+        let mut external = 0;
+        for level in [Level::Machine, Level::Supervisor] {
+            if plic.interrupt(Plic::context(hart, level)) {external |= level.eip()}
+        }
+        self.cpu.external_interrupts = external;
+    }
+}
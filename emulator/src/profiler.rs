@@ -0,0 +1,211 @@
+// Guest profiler: counts every retired instruction (or one every `period`) against its PC and the
+// call stack it ran in. Stacks come from a shadow call stack fed by jal/jalr, using the calling
+// convention hints of the spec (rd/rs1 being ra or t0), so it doesn't rely on frame pointers.
+use std::io::Write;
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::loader::Symbols;
+use crate::uguest;
+
+/// x1 (ra) and x5 (t0), the link registers of the calling convention
+fn is_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Call,
+    Return,
+    /// Coroutine swap, pops then pushes
+    ReturnCall,
+    Other,
+}
+impl Control {
+    fn new(raw_instruction: u32) -> Self {
+        let rd = (raw_instruction >> 7) & 0x1F;
+        let rs1 = (raw_instruction >> 15) & 0x1F;
+        match raw_instruction & 0x7F {
+            // jal
+            0b1101111 if is_link(rd) => Self::Call,
+            // jalr
+            0b1100111 => match (is_link(rd), is_link(rs1)) {
+                (true, false) => Self::Call,
+                (false, true) => Self::Return,
+                (true, true) if rd == rs1 => Self::Call,
+                (true, true) => Self::ReturnCall,
+                (false, false) => Self::Other,
+            },
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Node of the call tree, identified by its path from the root
+#[derive(Debug)]
+struct Node {
+    parent: usize,
+    /// Address the call jumped to
+    func: uguest,
+}
+#[derive(Debug)]
+struct Frame {
+    /// Node of the caller
+    node: usize,
+    return_addr: uguest,
+}
+
+#[derive(Debug)]
+pub struct Profiler {
+    /// Count one instruction every `period`, 1 is exact profiling
+    period: u64,
+    countdown: u64,
+    /// Node 0 is the root, the function execution started in
+    nodes: Vec<Node>,
+    children: HashMap<(usize, uguest), usize>,
+    stack: Vec<Frame>,
+    current: usize,
+    /// Samples per (call tree node, pc)
+    samples: HashMap<(usize, uguest), u64>,
+    total: u64,
+}
+
+/// Line of the per-function report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: String,
+    /// Samples in the function itself
+    pub self_samples: u64,
+    /// Samples in the function and everything it called
+    pub total_samples: u64,
+}
+
+impl Profiler {
+    /// `entry` is the pc profiling starts at
+    pub fn new(entry: uguest, period: u64) -> Self {
+        let period = period.max(1);
+        Self {
+            period,
+            countdown: period,
+            nodes: vec![Node { parent: 0, func: entry }],
+            children: HashMap::new(),
+            stack: Vec::new(),
+            current: 0,
+            samples: HashMap::new(),
+            total: 0,
+        }
+    }
+    pub fn total_samples(&self) -> u64 {
+        self.total
+    }
+    /// Samples of a single pc, across all call stacks
+    pub fn pc_samples(&self, pc: uguest) -> u64 {
+        self.samples.iter().filter(|((_, sample_pc), _)| *sample_pc == pc).map(|(_, count)| count).sum()
+    }
+    /// Current depth of the shadow call stack
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Called after each executed instruction, `size` is 2 for compressed instructions (which are given expanded)
+    /// and `next_pc` is where execution continues
+    pub fn record(&mut self, pc: uguest, raw_instruction: u32, size: uguest, next_pc: uguest, trapped: bool) {
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.period;
+            *self.samples.entry((self.current, pc)).or_default() += 1;
+            self.total += 1;
+        }
+        // Traps aren't calls, the handler is counted in the stack it interrupted
+        if trapped {return}
+        match Control::new(raw_instruction) {
+            Control::Call => self.push(next_pc, pc + size),
+            Control::Return => self.pop(next_pc),
+            Control::ReturnCall => {
+                self.pop(next_pc);
+                self.push(next_pc, pc + size);
+            },
+            Control::Other => {},
+        }
+    }
+    fn push(&mut self, func: uguest, return_addr: uguest) {
+        let next_node = self.nodes.len();
+        let node = *self.children.entry((self.current, func)).or_insert(next_node);
+        if node == next_node {
+            self.nodes.push(Node { parent: self.current, func });
+        }
+        self.stack.push(Frame { node: self.current, return_addr });
+        self.current = node;
+    }
+    fn pop(&mut self, target: uguest) {
+        // Unwind to the frame we're returning to, returns that skip frames (longjmp) pop them all.
+        // A return matching no frame (context switch, hand-made stack) leaves the stack alone
+        if let Some(idx) = self.stack.iter().rposition(|frame| frame.return_addr == target) {
+            self.current = self.stack[idx].node;
+            self.stack.truncate(idx);
+        }
+    }
+
+    /// Function names from the root to the leaf, the leaf being the function `pc` is in
+    fn stack_names(&self, node: usize, pc: uguest, symbols: &Symbols) -> Vec<String> {
+        let mut names = vec![symbols.name(self.nodes[node].func)];
+        let mut node = node;
+        while node != 0 {
+            node = self.nodes[node].parent;
+            names.push(symbols.name(self.nodes[node].func));
+        }
+        names.reverse();
+        let leaf = symbols.name(pc);
+        // Jumps without link (tail calls, the code before the first call) end up in another function
+        if names.last() != Some(&leaf) {
+            names.push(leaf);
+        }
+        names
+    }
+
+    /// Folded stacks, one `caller;callee count` line per stack, the input format of flamegraph tools
+    pub fn folded_stacks(&self, symbols: &Symbols) -> Vec<(String, u64)> {
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for (&(node, pc), &count) in &self.samples {
+            *folded.entry(self.stack_names(node, pc, symbols).join(";")).or_default() += count;
+        }
+        let mut folded: Vec<_> = folded.into_iter().collect();
+        folded.sort();
+        folded
+    }
+    pub fn write_folded(&self, symbols: &Symbols, mut out: impl Write) -> std::io::Result<()> {
+        for (stack, count) in self.folded_stacks(symbols) {
+            writeln!(out, "{stack} {count}")?;
+        }
+        Ok(())
+    }
+
+    /// Per-function self and inclusive samples, hottest first
+    pub fn functions(&self, symbols: &Symbols) -> Vec<FunctionStats> {
+        let mut stats: HashMap<String, FunctionStats> = HashMap::new();
+        for (&(node, pc), &count) in &self.samples {
+            let names = self.stack_names(node, pc, symbols);
+            // Recursive functions count once in their inclusive time
+            let unique: HashSet<&String> = names.iter().collect();
+            for name in unique {
+                let entry = stats.entry(name.clone()).or_insert_with(|| FunctionStats { name: name.clone(), self_samples: 0, total_samples: 0 });
+                entry.total_samples += count;
+            }
+            if let Some(leaf) = names.last() {
+                stats.get_mut(leaf).unwrap().self_samples += count;
+            }
+        }
+        let mut stats: Vec<_> = stats.into_values().collect();
+        stats.sort_by(|a, b| b.self_samples.cmp(&a.self_samples).then(b.total_samples.cmp(&a.total_samples)).then(a.name.cmp(&b.name)));
+        stats
+    }
+    pub fn write_table(&self, symbols: &Symbols, mut out: impl Write) -> std::io::Result<()> {
+        let percent = |samples: u64| samples as f64 * 100. / self.total.max(1) as f64;
+        writeln!(out, "{:>7} {:>12} {:>7} {:>12}  function", "self%", "self", "total%", "total")?;
+        for func in self.functions(symbols) {
+            writeln!(out, "{:>6.2}% {:>12} {:>6.2}% {:>12}  {}",
+                percent(func.self_samples), func.self_samples, percent(func.total_samples), func.total_samples, func.name)?;
+        }
+        writeln!(out, "{} samples, one every {} instructions", self.total, self.period)
+    }
+}
@@ -0,0 +1,146 @@
+// Goldfish RTC (QEMU's hw/rtc/goldfish_rtc.c), nanoseconds since the Unix epoch and an alarm raising the interrupt line
+// Registers are 32 bits, reading TIME_LOW latches the high half TIME_HIGH then reads, writing ALARM_LOW arms the alarm
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mem::MemoryRegion;
+use crate::uguest;
+
+/// Interrupt source of the RTC on QEMU virt
+pub const RTC_IRQ: u32 = 11;
+
+const TIME_LOW: uguest = 0x00;
+const TIME_HIGH: uguest = 0x04;
+const ALARM_LOW: uguest = 0x08;
+const ALARM_HIGH: uguest = 0x0C;
+const IRQ_ENABLED: uguest = 0x10;
+const CLEAR_ALARM: uguest = 0x14;
+const ALARM_STATUS: uguest = 0x18;
+const CLEAR_INTERRUPT: uguest = 0x1C;
+
+/// Nanoseconds per tick of a fixed clock, ticks happen at the timebase frequency
+const TICK_NS: u64 = 1_000_000_000 / crate::fdt::TIMEBASE_FREQUENCY as u64;
+
+/// Where the time comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Clock {
+    /// Time of the host, like QEMU's -rtc clock=host
+    #[default]
+    Host,
+    /// Starts at this many seconds after the epoch and advances with instructions, so runs are reproducible
+    Fixed(u64),
+}
+impl std::str::FromStr for Clock {
+    type Err = String;
+    fn from_str(clock: &str) -> Result<Self, String> {
+        match clock {
+            "host" => Ok(Self::Host),
+            _ => clock.parse().map(Self::Fixed).map_err(|_| format!("Invalid RTC clock {clock:?}, it is host or seconds since the epoch")),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GoldfishRtc {
+    clock: Clock,
+    ticks: u64,
+    /// Added to the clock, the guest sets the time by changing it
+    offset: i64,
+    time_high: u32,
+    alarm: u64,
+    alarm_high: u32,
+    armed: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+impl GoldfishRtc {
+    pub fn new(clock: Clock) -> Self {
+        Self { clock, ..Default::default() }
+    }
+    /// Changes the clock, forgetting the time the guest set
+    pub fn set_clock(&mut self, clock: Clock) {
+        (self.clock, self.ticks, self.offset) = (clock, 0, 0);
+    }
+    fn clock_ns(&self) -> u64 {
+        match self.clock {
+            Clock::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64),
+            Clock::Fixed(epoch) => epoch.saturating_mul(1_000_000_000).saturating_add(self.ticks * TICK_NS),
+        }
+    }
+    /// Nanoseconds since the epoch, as the guest sees them
+    pub fn now(&self) -> u64 {
+        self.clock_ns().wrapping_add_signed(self.offset)
+    }
+    fn read_register(&mut self, offset: uguest) -> u32 {
+        match offset {
+            TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            },
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm as u32,
+            ALARM_HIGH => (self.alarm >> 32) as u32,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.armed as u32,
+            _ => 0,
+        }
+    }
+    fn write_register(&mut self, offset: uguest, value: u32) {
+        match offset {
+            // The high half is written first, like the alarm
+            TIME_LOW => {
+                let time = (self.time_high as u64) << 32 | value as u64;
+                self.offset = time.wrapping_sub(self.clock_ns()) as i64;
+            },
+            TIME_HIGH => self.time_high = value,
+            ALARM_LOW => {
+                self.alarm = (self.alarm_high as u64) << 32 | value as u64;
+                self.armed = true;
+                self.check_alarm();
+            },
+            ALARM_HIGH => self.alarm_high = value,
+            IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => self.armed = false,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => {},
+        }
+    }
+    fn check_alarm(&mut self) {
+        if self.armed && self.now() >= self.alarm {
+            self.armed = false;
+            self.irq_pending = true;
+        }
+    }
+}
+impl MemoryRegion for GoldfishRtc {
+    fn read(&mut self, offset: uguest) -> u8 {
+        (self.read_register(offset & !3) >> (offset % 4 * 8)) as u8
+    }
+    fn read_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
+        // Reading TIME_LOW latches TIME_HIGH, registers are read once
+        if buffer.len() == 4 && offset.is_multiple_of(4) {
+            buffer.copy_from_slice(&self.read_register(offset).to_le_bytes());
+        } else {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = self.read(offset + i as uguest)
+            }
+        }
+    }
+    /// Registers are written 32 bits at a time
+    fn write(&mut self, _offset: uguest, _val: u8) {}
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        if let (Ok(word), true) = (<[u8; 4]>::try_from(buffer), offset.is_multiple_of(4)) {
+            self.write_register(offset, u32::from_le_bytes(word));
+        }
+    }
+    fn tick(&mut self) {
+        self.ticks += 1;
+        // Looking at the host clock every instruction would be slow, and an alarm doesn't need that precision
+        if self.armed && (self.clock != Clock::Host || self.ticks.is_multiple_of(1024)) {
+            self.check_alarm();
+        }
+    }
+    fn interrupt_pending(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+}
@@ -0,0 +1,282 @@
+// Built-in SBI firmware (https://github.com/riscv-non-isa/riscv-sbi-doc, v2.0), handles the ecalls of S-mode
+// in the emulator instead of running an M-mode firmware like OpenSBI in the guest.
+// a7 is the extension, a6 the function and a0-a5 the arguments, a0 gets the error and a1 the value.
+// There is a single hart, so hart masks can only target the one that is running.
+use std::collections::VecDeque;
+use std::io::Write;
+
+use bit_field::BitField;
+
+use crate::cpu::csr::file::*;
+use crate::cpu::{PrivilegeLevel, CPU};
+use crate::mem::Memory;
+use crate::uguest;
+
+pub const EXT_LEGACY_PUTCHAR: uguest = 0x01;
+pub const EXT_LEGACY_GETCHAR: uguest = 0x02;
+pub const EXT_BASE: uguest = 0x10;
+pub const EXT_TIME: uguest = 0x5449_4D45;
+pub const EXT_IPI: uguest = 0x73_5049;
+pub const EXT_RFENCE: uguest = 0x5246_4E43;
+pub const EXT_HSM: uguest = 0x48_534D;
+pub const EXT_SRST: uguest = 0x5352_5354;
+pub const EXT_DBCN: uguest = 0x4442_434E;
+const EXTENSIONS: [uguest; 9] = [
+    EXT_LEGACY_PUTCHAR, EXT_LEGACY_GETCHAR, EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST, EXT_DBCN,
+];
+
+/// Major in bits 24-30, minor below
+pub const SPEC_VERSION: uguest = 2 << 24;
+/// Not a registered implementation ID, those are only given to actual firmwares
+pub const IMPL_ID: uguest = 0x5256_454D; // "RVEM"
+
+/// Table 1. Standard SBI Errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SbiError {
+    Failed = -1,
+    NotSupported = -2,
+    InvalidParam = -3,
+    Denied = -4,
+    InvalidAddress = -5,
+    AlreadyAvailable = -6,
+    AlreadyStarted = -7,
+    AlreadyStopped = -8,
+}
+
+/// Table 13. HSM Hart States
+pub const HART_STARTED: uguest = 0;
+pub const HART_STOPPED: uguest = 1;
+/// Suspend types of sbi_hart_suspend
+const SUSPEND_RETENTIVE: uguest = 0;
+const SUSPEND_NON_RETENTIVE: uguest = 0x8000_0000;
+
+/// sbi_system_reset types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+    Shutdown,
+    ColdReboot,
+    WarmReboot,
+}
+/// Reset reason of a system failure, 0 is "no reason"
+pub const RESET_REASON_FAILURE: uguest = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reset {
+    pub kind: ResetType,
+    pub reason: uguest,
+}
+
+pub struct Sbi {
+    /// stime_value of the last sbi_set_timer, STIP is pending once `time` reaches it
+    timer: uguest,
+    /// Where the debug console and legacy putchar output goes
+    console: Box<dyn Write>,
+    /// What the debug console and legacy getchar read, nothing fills it from the host yet
+    pub input: VecDeque<u8>,
+    /// Set by sbi_hart_stop, no hart is left to run
+    stopped: bool,
+    /// Set by sbi_system_reset, reboots stop the machine too
+    pub reset: Option<Reset>,
+}
+impl std::fmt::Debug for Sbi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sbi").field("timer", &self.timer).field("stopped", &self.stopped).field("reset", &self.reset).finish()
+    }
+}
+impl Default for Sbi {
+    fn default() -> Self {
+        Self::with_console(Box::new(std::io::stdout()))
+    }
+}
+
+impl Sbi {
+    pub fn with_console(console: Box<dyn Write>) -> Self {
+        Self { timer: uguest::MAX, console, input: VecDeque::new(), stopped: false, reset: None }
+    }
+
+    /// The guest shut down (0, or 1 after a system failure), rebooted or stopped its last hart
+    pub fn exit_status(&self) -> Option<i32> {
+        match self.reset {
+            Some(Reset { kind: ResetType::Shutdown, reason }) => Some((reason == RESET_REASON_FAILURE) as i32),
+            Some(_) => Some(0),
+            None => self.stopped.then_some(0),
+        }
+    }
+    /// stime_value of the last sbi_set_timer
+    pub fn timer(&self) -> uguest {
+        self.timer
+    }
+    /// Called once per instruction, the supervisor timer interrupt is level triggered
+    /// With Sstc enabled stimecmp drives it instead
+    pub fn tick(&mut self, cpu: &mut CPU) {
+        if cpu.csr_value(MENVCFG) & ENVCFG_STCE != 0 {return}
+        let pending = cpu.csr_value(TIME) >= self.timer;
+        cpu.csrs[MIP as usize].0.set_bit(5, pending);
+    }
+
+    fn console_write(&mut self, data: &[u8]) {
+        let _ = self.console.write_all(data);
+        let _ = self.console.flush();
+    }
+
+    fn call(&mut self, cpu: &mut CPU, mem: &mut Memory, ext: uguest, fun: uguest, args: [uguest; 6]) -> Result<uguest, SbiError> {
+        match (ext, fun) {
+            (EXT_BASE, 0) => Ok(SPEC_VERSION),
+            (EXT_BASE, 1) => Ok(IMPL_ID),
+            (EXT_BASE, 2) => Ok(0x1), // 0.1
+            (EXT_BASE, 3) => Ok(EXTENSIONS.contains(&args[0]) as uguest),
+            (EXT_BASE, 4) => Ok(cpu.csr_value(MVENDORID)),
+            (EXT_BASE, 5) => Ok(cpu.csr_value(MARCHID)),
+            (EXT_BASE, 6) => Ok(cpu.csr_value(MIMPID)),
+
+            // Like OpenSBI, stimecmp is the timer when Sstc is enabled
+            (EXT_TIME, 0) if cpu.csr_value(MENVCFG) & ENVCFG_STCE != 0 => {
+                cpu.set_csr_value(STIMECMP, args[0]);
+                Ok(0)
+            },
+            (EXT_TIME, 0) => {
+                self.timer = args[0];
+                self.tick(cpu);
+                Ok(0)
+            },
+
+            (EXT_IPI, 0) => {
+                if targets_hart(cpu, args[0], args[1])? {
+                    cpu.csrs[MIP as usize].0.set_bit(1, true); // SSIP
+                }
+                Ok(0)
+            },
+
+            // remote_fence_i, remote_sfence_vma(_asid): nothing is cached
+            (EXT_RFENCE, 0..=2) => targets_hart(cpu, args[0], args[1]).map(|_| 0),
+
+            (EXT_HSM, 0) => Err(if args[0] == cpu.hartid {SbiError::AlreadyAvailable} else {SbiError::InvalidParam}),
+            (EXT_HSM, 1) => {
+                self.stopped = true;
+                Ok(0)
+            },
+            (EXT_HSM, 2) => if args[0] == cpu.hartid {Ok(HART_STARTED)} else {Err(SbiError::InvalidParam)},
+            // Waking up right away is allowed, like a WFI that returns early
+            (EXT_HSM, 3) if args[0] == SUSPEND_RETENTIVE => Ok(0),
+            (EXT_HSM, 3) if args[0] == SUSPEND_NON_RETENTIVE => {
+                let (resume_addr, opaque) = (args[1], args[2]);
+                if mem.get_region(resume_addr, 4).is_none() {return Err(SbiError::InvalidAddress)}
+                // Resumes like sbi_hart_start would: S-mode at resume_addr, a0 = hartid, a1 = opaque
+                cpu.set_csr_value(SATP, 0);
+                cpu.csrs[MSTATUS as usize].0.set_bit(1, false); // SIE
+                cpu.next_pc = resume_addr;
+                cpu.regs[10] = cpu.hartid;
+                Ok(opaque)
+            },
+            (EXT_HSM, 3) => Err(SbiError::InvalidParam),
+
+            (EXT_SRST, 0) => {
+                let kind = match args[0] {
+                    0 => ResetType::Shutdown,
+                    1 => ResetType::ColdReboot,
+                    2 => ResetType::WarmReboot,
+                    0xF000_0000..=0xFFFF_FFFF => return Err(SbiError::NotSupported),
+                    _ => return Err(SbiError::InvalidParam),
+                };
+                let reason = args[1];
+                if !matches!(reason, 0 | 1 | 0xE000_0000..=0xFFFF_FFFF) {return Err(SbiError::InvalidParam)}
+                self.reset = Some(Reset { kind, reason });
+                Ok(0)
+            },
+
+            // sbi_debug_console_write/read, the buffer is a physical address (num_bytes, base_lo, base_hi)
+            (EXT_DBCN, 0 | 1) => {
+                let (len, addr) = (args[0], args[1]);
+                if args[2] != 0 || mem.get_region(addr, len).is_none() {return Err(SbiError::InvalidParam)}
+                if fun == 0 {
+                    let mut data = vec![0; len as usize];
+                    mem.read(addr, &mut data).map_err(|_| SbiError::InvalidParam)?;
+                    self.console_write(&data);
+                    Ok(len)
+                } else {
+                    let data: Vec<u8> = self.input.drain(..self.input.len().min(len as usize)).collect();
+                    mem.write(addr, &data).map_err(|_| SbiError::InvalidParam)?;
+                    Ok(data.len() as uguest)
+                }
+            },
+            (EXT_DBCN, 2) => {
+                self.console_write(&[args[0] as u8]);
+                Ok(0)
+            },
+
+            _ => Err(SbiError::NotSupported),
+        }
+    }
+}
+
+/// Whether the running hart is in the hart mask, which can't name any other (a base of -1 means all harts)
+fn targets_hart(cpu: &CPU, mask: uguest, base: uguest) -> Result<bool, SbiError> {
+    if base == uguest::MAX {return Ok(true)}
+    let mut targeted = false;
+    for bit in (0..64).filter(|bit| mask.get_bit(*bit)) {
+        if base.checked_add(bit as uguest) != Some(cpu.hartid) {return Err(SbiError::InvalidParam)}
+        targeted = true;
+    }
+    Ok(targeted)
+}
+
+impl crate::vm::VM {
+    /// Called on ecall, returns false if it isn't an SBI call (SBI disabled or not from S-mode)
+    /// and should raise the environment call exception
+    pub fn sbi_call(&mut self) -> bool {
+        let Some(sbi) = &mut self.sbi else {return false};
+        // Guests' ecalls go to their hypervisor
+        if self.cpu.privilege_level != PrivilegeLevel::Supervisor || self.cpu.virt {return false}
+        let regs = self.cpu.regs;
+        let (ext, fun) = (regs[17], regs[16]);
+        match ext {
+            // Legacy extensions only return a value in a0
+            EXT_LEGACY_PUTCHAR => {
+                sbi.console_write(&[regs[10] as u8]);
+                self.cpu.regs[10] = 0;
+            },
+            EXT_LEGACY_GETCHAR => {
+                self.cpu.regs[10] = sbi.input.pop_front().map_or(uguest::MAX, uguest::from);
+            },
+            _ => {
+                let args = [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]];
+                // SBI_SUCCESS, unless the call puts something else there (the hartid when resuming)
+                self.cpu.regs[10] = 0;
+                match sbi.call(&mut self.cpu, &mut self.mem, ext, fun, args) {
+                    Ok(value) => self.cpu.regs[11] = value,
+                    Err(err) => self.cpu.regs[10] = err as i64 as uguest,
+                }
+            },
+        }
+        true
+    }
+
+    /// Starts the guest in S-mode at the current pc like QEMU's -bios firmware would, ecalls are handled by `sbi`
+    /// a0 is the hartid and a1 the address of the device tree of `board`, it's copied at the end of its first RAM region
+    pub fn boot_supervisor(&mut self, sbi: Sbi, bootargs: &str) -> color_eyre::Result<()> {
+        let ram = *self.board.ram.first().ok_or_else(|| color_eyre::Report::msg("No RAM to put the device tree in"))?;
+        let dtb = crate::fdt::generate(&self.board, self.cpu.isa(), self.cpu.hartid, bootargs);
+        let ram_end = ram.base + ram.size;
+        // Last 2MiB aligned address it fits at, like QEMU, but it has to stay in RAM
+        let last = ram_end.checked_sub(dtb.len() as uguest).filter(|&addr| addr >= ram.base)
+            .ok_or_else(|| color_eyre::Report::msg(format!("The device tree ({} bytes) doesn't fit in RAM", dtb.len())))?;
+        let dtb_addr = match last & !0x1F_FFFF {
+            addr if addr >= ram.base => addr,
+            _ => (last & !0x7).max(ram.base),
+        };
+        self.mem.write(dtb_addr, &dtb)?;
+
+        // What the firmware would have done before jumping to the kernel
+        self.cpu.set_csr_value(MEDELEG, DELEGABLE_EXCEPTIONS & !(1 << 9)); // Except ecalls from S-mode
+        self.cpu.set_csr_value(MIDELEG, S_INTERRUPTS);
+        self.cpu.set_csr_value(MCOUNTEREN, 0b111);
+        self.cpu.set_csr_value(MENVCFG, ENVCFG_STCE); // Kept only with Sstc
+        self.cpu.pmp_allow_all();
+        self.cpu.privilege_level = PrivilegeLevel::Supervisor;
+        self.cpu.regs[10] = self.cpu.hartid;
+        self.cpu.regs[11] = dtb_addr;
+        self.sbi = Some(sbi);
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+// RISC-V semihosting (https://github.com/riscv-non-isa/riscv-semihosting), host services requested with
+//     slli x0, x0, 0x1f
+//     ebreak
+//     srai x0, x0, 7
+// a0 is the operation, a1 its parameter (usually a pointer to a block of 64 bits fields), the result goes in a0.
+// Operations and their semantics are the ones of Arm semihosting.
+// Files are only opened inside the sandbox directory, paths leaving it (even through a symbolic link) fail.
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+use crate::mem::{AccessFault, AccessType, Memory};
+use crate::uguest;
+
+pub const SYS_OPEN: uguest = 0x01;
+pub const SYS_CLOSE: uguest = 0x02;
+pub const SYS_WRITEC: uguest = 0x03;
+pub const SYS_WRITE0: uguest = 0x04;
+pub const SYS_WRITE: uguest = 0x05;
+pub const SYS_READ: uguest = 0x06;
+pub const SYS_READC: uguest = 0x07;
+pub const SYS_ISERROR: uguest = 0x08;
+pub const SYS_ISTTY: uguest = 0x09;
+pub const SYS_SEEK: uguest = 0x0A;
+pub const SYS_FLEN: uguest = 0x0C;
+pub const SYS_CLOCK: uguest = 0x10;
+pub const SYS_TIME: uguest = 0x11;
+pub const SYS_ERRNO: uguest = 0x13;
+pub const SYS_GET_CMDLINE: uguest = 0x15;
+pub const SYS_EXIT: uguest = 0x18;
+pub const SYS_EXIT_EXTENDED: uguest = 0x20;
+
+/// Reason of SYS_EXIT for a normal exit, the subcode is the exit status
+pub const ADP_STOPPED_APPLICATION_EXIT: uguest = 0x20026;
+
+/// slli x0, x0, 0x1f
+const ENTRY_NOP: u32 = 0x01f01013;
+/// srai x0, x0, 7
+const EXIT_NOP: u32 = 0x40705013;
+
+// errno values reported by SYS_ERRNO
+const ENOENT: uguest = 2;
+const EIO: uguest = 5;
+const EBADF: uguest = 9;
+const EACCES: uguest = 13;
+const EINVAL: uguest = 22;
+const ENOSYS: uguest = 38;
+
+const ERROR: uguest = uguest::MAX; // -1
+
+#[derive(Debug)]
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct Semihosting {
+    /// Directory guest paths are relative to
+    root: PathBuf,
+    cmdline: String,
+    /// SYS_OPEN handles are the index + 1
+    handles: Vec<Option<Handle>>,
+    errno: uguest,
+    start: Instant,
+    /// Where :tt output goes
+    console: Box<dyn Write>,
+    /// Set by SYS_EXIT
+    pub exit_status: Option<i32>,
+}
+impl std::fmt::Debug for Semihosting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Semihosting").field("root", &self.root).field("cmdline", &self.cmdline).field("handles", &self.handles).finish()
+    }
+}
+
+impl Semihosting {
+    pub fn new(root: impl Into<PathBuf>, cmdline: impl Into<String>) -> Self {
+        Self::with_console(root, cmdline, Box::new(std::io::stdout()))
+    }
+    pub fn with_console(root: impl Into<PathBuf>, cmdline: impl Into<String>, console: Box<dyn Write>) -> Self {
+        Self {
+            root: root.into(),
+            cmdline: cmdline.into(),
+            handles: Vec::new(),
+            errno: 0,
+            start: Instant::now(),
+            console,
+            exit_status: None,
+        }
+    }
+
+    /// The ebreak at `pc` is a semihosting call if it's surrounded by the two magic nops
+    pub fn is_call(mem: &mut Memory, pc: uguest) -> bool {
+        matches!(mem.fetch::<u32>(pc.wrapping_sub(4)), Ok(ENTRY_NOP))
+            && matches!(mem.fetch::<u32>(pc.wrapping_add(4)), Ok(EXIT_NOP))
+    }
+
+    /// Handles the operation, returns the value of a0
+    pub fn call(&mut self, mem: &mut Memory, op: uguest, param: uguest) -> uguest {
+        match self.dispatch(mem, op, param) {
+            Ok(ret) => ret,
+            // The guest gave us a bad pointer
+            Err(_) => self.fail(EINVAL),
+        }
+    }
+    fn fail(&mut self, errno: uguest) -> uguest {
+        self.errno = errno;
+        ERROR
+    }
+    fn io_fail(&mut self, err: std::io::Error) -> uguest {
+        self.fail(match err.kind() {
+            std::io::ErrorKind::NotFound => ENOENT,
+            std::io::ErrorKind::PermissionDenied => EACCES,
+            _ => EIO,
+        })
+    }
+
+    fn dispatch(&mut self, mem: &mut Memory, op: uguest, param: uguest) -> Result<uguest, AccessFault> {
+        let arg = |mem: &mut Memory, idx: uguest| mem.get::<uguest>(offset(param, idx*8)?);
+        Ok(match op {
+            SYS_OPEN => {
+                let (addr, len) = (arg(mem, 0)?, arg(mem, 2)?);
+                let name = read_bytes(mem, addr, len)?;
+                let mode = arg(mem, 1)?;
+                self.open(&String::from_utf8_lossy(&name), mode)
+            },
+            SYS_CLOSE => {
+                let handle = arg(mem, 0)?;
+                match self.handle(handle) {
+                    Some(_) => {self.handles[handle as usize - 1] = None; 0},
+                    None => self.fail(EBADF),
+                }
+            },
+            SYS_WRITEC => {
+                let c = mem.get::<u8>(param)?;
+                self.console_write(&[c]);
+                0
+            },
+            SYS_WRITE0 => {
+                let mut string = Vec::new();
+                let mut addr = param;
+                loop {
+                    let c = mem.get::<u8>(addr)?;
+                    if c == 0 {break}
+                    string.push(c);
+                    addr = offset(addr, 1)?;
+                }
+                self.console_write(&string);
+                0
+            },
+            SYS_WRITE => {
+                let (handle, len) = (arg(mem, 0)?, arg(mem, 2)?);
+                let addr = arg(mem, 1)?;
+                let data = read_bytes(mem, addr, len)?;
+                // Returns how many bytes were NOT written
+                match self.handle(handle) {
+                    Some(Handle::Stdout | Handle::Stderr) => {self.console_write(&data); 0},
+                    Some(Handle::File(file)) => match file.write_all(&data) {
+                        Ok(()) => 0,
+                        Err(err) => {self.io_fail(err); len},
+                    },
+                    Some(Handle::Stdin) => {self.fail(EBADF); len},
+                    None => self.fail(EBADF),
+                }
+            },
+            SYS_READ => {
+                let (handle, buffer, len) = (arg(mem, 0)?, arg(mem, 1)?, arg(mem, 2)?);
+                check_buffer(mem, buffer, len, AccessType::Write)?;
+                let mut data = vec![0; len as usize];
+                // Returns how many bytes were NOT read, len at end of file
+                let read = match self.handle(handle) {
+                    Some(Handle::Stdin) => std::io::stdin().read(&mut data),
+                    Some(Handle::File(file)) => read_full(file, &mut data),
+                    Some(_) => {self.fail(EBADF); return Ok(len)},
+                    None => return Ok(self.fail(EBADF)),
+                };
+                match read {
+                    Ok(read) => {
+                        mem.write(buffer, &data[..read])?;
+                        len - read as uguest
+                    },
+                    Err(err) => {self.io_fail(err); len},
+                }
+            },
+            SYS_READC => {
+                let mut c = [0];
+                match std::io::stdin().read_exact(&mut c) {
+                    Ok(()) => c[0] as uguest,
+                    Err(err) => self.io_fail(err),
+                }
+            },
+            SYS_ISERROR => ((arg(mem, 0)? as crate::iguest) < 0) as uguest,
+            SYS_ISTTY => match self.handle(arg(mem, 0)?) {
+                Some(Handle::File(_)) => 0,
+                Some(_) => 1,
+                None => self.fail(EBADF),
+            },
+            SYS_SEEK => {
+                let pos = arg(mem, 1)?;
+                match self.handle(arg(mem, 0)?) {
+                    Some(Handle::File(file)) => match file.seek(SeekFrom::Start(pos)) {
+                        Ok(_) => 0,
+                        Err(err) => self.io_fail(err),
+                    },
+                    _ => self.fail(EBADF),
+                }
+            },
+            SYS_FLEN => match self.handle(arg(mem, 0)?) {
+                Some(Handle::File(file)) => match file.metadata() {
+                    Ok(metadata) => metadata.len(),
+                    Err(err) => self.io_fail(err),
+                },
+                _ => self.fail(EBADF),
+            },
+            // Centiseconds since the start
+            SYS_CLOCK => (self.start.elapsed().as_millis() / 10) as uguest,
+            SYS_TIME => std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs()),
+            SYS_ERRNO => self.errno,
+            SYS_GET_CMDLINE => {
+                let (buffer, size) = (arg(mem, 0)?, arg(mem, 1)?);
+                let cmdline = self.cmdline.as_bytes();
+                // The null terminator has to fit
+                if cmdline.len() as uguest >= size {return Ok(self.fail(EINVAL))}
+                mem.write(buffer, cmdline)?;
+                mem.set::<u8>(offset(buffer, cmdline.len() as uguest)?, 0)?;
+                mem.set::<uguest>(offset(param, 8)?, cmdline.len() as uguest)?;
+                0
+            },
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                let (reason, subcode) = (arg(mem, 0)?, arg(mem, 1)?);
+                self.exit_status = Some(if reason == ADP_STOPPED_APPLICATION_EXIT {subcode as i32} else {1});
+                0
+            },
+            _ => {
+                log::warn!("Unsupported semihosting operation {op:#x}");
+                self.fail(ENOSYS)
+            },
+        })
+    }
+
+    fn handle(&mut self, handle: uguest) -> Option<&mut Handle> {
+        self.handles.get_mut((handle as usize).checked_sub(1)?)?.as_mut()
+    }
+    fn console_write(&mut self, data: &[u8]) {
+        let _ = self.console.write_all(data);
+        let _ = self.console.flush();
+    }
+
+    /// Path inside the sandbox, None if it would escape it, through `..` or a symbolic link
+    fn sandboxed(&self, name: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {},
+                _ => return None,
+            }
+        }
+        // A file that doesn't exist yet is created in its directory, a dangling link fails to canonicalize
+        let resolved = if path.symlink_metadata().is_ok() {
+            path.canonicalize().ok()?
+        } else {
+            path.parent()?.canonicalize().ok()?.join(path.file_name()?)
+        };
+        resolved.starts_with(self.root.canonicalize().ok()?).then_some(resolved)
+    }
+    /// Modes are fopen's r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+    fn open(&mut self, name: &str, mode: uguest) -> uguest {
+        let handle = if name == ":tt" {
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            let Some(path) = self.sandboxed(name) else {
+                return self.fail(EACCES)
+            };
+            let mut options = OpenOptions::new();
+            match mode {
+                0 | 1 => options.read(true),
+                2 | 3 => options.read(true).write(true),
+                4 | 5 => options.write(true).create(true).truncate(true),
+                6 | 7 => options.read(true).write(true).create(true).truncate(true),
+                8 | 9 => options.append(true).create(true),
+                10 | 11 => options.read(true).append(true).create(true),
+                _ => return self.fail(EINVAL),
+            };
+            match options.open(path) {
+                Ok(file) => Handle::File(file),
+                Err(err) => return self.io_fail(err),
+            }
+        };
+        let idx = match self.handles.iter().position(Option::is_none) {
+            Some(idx) => idx,
+            None => {self.handles.push(None); self.handles.len()-1},
+        };
+        self.handles[idx] = Some(handle);
+        idx as uguest + 1
+    }
+}
+
+impl crate::vm::VM {
+    /// Called on ebreak, returns false if it isn't a semihosting call (or semihosting is disabled) and
+    /// should raise a breakpoint exception. Like QEMU, U-mode can't use it
+    pub fn semihosting_call(&mut self) -> bool {
+        let Some(semihosting) = &mut self.semihosting else {return false};
+        let uncompressed = self.cpu.next_pc == self.cpu.pc.wrapping_add(4);
+        if !uncompressed || self.cpu.privilege_level == crate::cpu::PrivilegeLevel::User || !Semihosting::is_call(&mut self.mem, self.cpu.pc) {
+            return false
+        }
+        let (op, param) = (self.cpu.regs[10], self.cpu.regs[11]);
+        self.cpu.regs[10] = semihosting.call(&mut self.mem, op, param);
+        true
+    }
+}
+
+/// `addr + by`, a fault instead of wrapping around as both come from the guest
+fn offset(addr: uguest, by: uguest) -> Result<uguest, AccessFault> {
+    addr.checked_add(by).ok_or(AccessFault { addr, len: by, access: AccessType::Read })
+}
+/// Guest buffer, checked before allocating anything as `len` comes from the guest
+fn read_bytes(mem: &mut Memory, addr: uguest, len: uguest) -> Result<Vec<u8>, AccessFault> {
+    check_buffer(mem, addr, len, AccessType::Read)?;
+    let mut data = vec![0; len as usize];
+    mem.read(addr, &mut data)?;
+    Ok(data)
+}
+fn check_buffer(mem: &mut Memory, addr: uguest, len: uguest, access: AccessType) -> Result<(), AccessFault> {
+    match mem.get_region(addr, len) {
+        Some(_) => Ok(()),
+        None => Err(AccessFault { addr, len, access }),
+    }
+}
+/// Reads until the buffer is full or the end of the file
+fn read_full(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,47 @@
+// Snapshots of a running VM, like QEMU's savevm/loadvm (see `monitor`) but kept in memory, taken between two steps
+// A snapshot has the hart, the clock, and the state of the devices that keep one (see `MemoryRegion::snapshot`): RAM,
+// the interrupt controllers and the virtio-mmio transports with their device. A virtio-blk drive keeps its overlay
+// (see `block::OverlaySnapshot`), a drive written in place can't be snapshotted as its writes can't be undone.
+// The rest (the firmware, the UART, the RTC, PCI functions...) keeps its state when a snapshot is restored.
+use std::any::Any;
+
+use color_eyre::eyre::ContextCompat;
+use color_eyre::Result;
+
+use crate::clock::Clock;
+use crate::cpu::CPU;
+use crate::vm::VM;
+
+/// Allocated pages of RAM and overlay sectors are copied, it takes about the memory the guest used
+pub struct Snapshot {
+    cpu: CPU,
+    clock: Clock,
+    /// By region of the bus
+    devices: Vec<Option<Box<dyn Any>>>,
+}
+impl VM {
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        let devices = self.mem.snapshot()?;
+        Ok(Snapshot { cpu: self.cpu.clone(), clock: self.clock.clone(), devices })
+    }
+    /// Puts the VM back as it was when `snapshot` was taken, a snapshot can be restored any number of times
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.mem.restore(&snapshot.devices)?;
+        self.cpu.clone_from(&snapshot.cpu);
+        self.clock.clone_from(&snapshot.clock);
+        Ok(())
+    }
+}
+
+/// What `MemoryRegion::restore` gets back, of the type its `snapshot` made
+pub(crate) fn state<T: 'static>(state: &dyn Any) -> Result<&T> {
+    state.downcast_ref().context("The snapshot was taken on another machine")
+}
+/// `MemoryRegion::snapshot` of a device whose whole state can be cloned
+pub(crate) fn cloned<T: Clone + 'static>(device: &T) -> Result<Option<Box<dyn Any>>> {
+    Ok(Some(Box::new(device.clone())))
+}
+pub(crate) fn restore_cloned<T: Clone + 'static>(device: &mut T, saved: &dyn Any) -> Result<()> {
+    device.clone_from(state(saved)?);
+    Ok(())
+}
@@ -0,0 +1,174 @@
+// Runs the official test suites:
+// - riscv-tests (https://github.com/riscv-software-src/riscv-tests), the rv64ui/um/ua/uc/mi/si "p" environment
+//   ELFs report their result by writing to `tohost`: 1 is a pass, (test number << 1) | 1 a failure
+// - riscv-arch-test (https://github.com/riscv-non-isa/riscv-arch-test), the tests halt the same way and
+//   the memory between `begin_signature` and `end_signature` is compared against a reference signature
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{ContextCompat, Context};
+use color_eyre::Result;
+
+use crate::uguest;
+use crate::vm::VM;
+
+/// Instructions executed before giving up on a test, the biggest riscv-tests retire a few 100k
+pub const DEFAULT_MAX_INSTRUCTIONS: u64 = 5_000_000;
+const RAM_SIZE: uguest = 16 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// riscv-tests number of the failing test case
+    Fail(u64),
+    /// First differing word of the signature
+    SignatureMismatch { index: usize, expected: u32, found: u32 },
+    /// The signature doesn't have the same number of words as the reference
+    SignatureLength { expected: usize, found: usize },
+    /// Never wrote to tohost
+    Timeout,
+    /// Couldn't run the test (invalid ELF, emulator error...)
+    Error(String),
+}
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        *self == Self::Pass
+    }
+}
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pass => write!(f, "PASS"),
+            Self::Fail(test) => write!(f, "FAIL (test {test})"),
+            Self::SignatureMismatch { index, expected, found } => write!(f, "FAIL (signature word {index}: expected {expected:08x}, found {found:08x})"),
+            Self::SignatureLength { expected, found } => write!(f, "FAIL (signature has {found} words, expected {expected})"),
+            Self::Timeout => write!(f, "TIMEOUT"),
+            Self::Error(err) => write!(f, "ERROR ({err})"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestReport {
+    pub name: String,
+    pub outcome: Outcome,
+    pub instructions: u64,
+}
+
+fn symbol(vm: &VM, name: &str) -> Result<uguest> {
+    Ok(vm.symbols.by_name(name).with_context(|| format!("No {name} symbol"))?.addr)
+}
+
+/// Runs until the test writes to `tohost`, returns the value written (0 on timeout)
+fn run_to_host(vm: &mut VM, max_instructions: u64) -> Result<u64> {
+    let tohost = symbol(vm, "tohost")?;
+    for _ in 0..max_instructions {
+        if !vm.step()? {break}
+        let value: u64 = vm.mem.get(tohost)?;
+        if value != 0 {return Ok(value)}
+    }
+    Ok(0)
+}
+
+/// Runs a riscv-tests ELF
+pub fn run_riscv_test(elf: Vec<u8>, max_instructions: u64) -> Result<(Outcome, u64)> {
+    let mut vm = VM::new(elf, RAM_SIZE)?;
+    vm.trace = false;
+    let outcome = match run_to_host(&mut vm, max_instructions)? {
+        0 => Outcome::Timeout,
+        1 => Outcome::Pass,
+        value => Outcome::Fail(value >> 1),
+    };
+    Ok((outcome, vm.cpu.instret))
+}
+
+/// Signature region of an arch test, as 32 bits words
+pub fn signature(vm: &mut VM) -> Result<Vec<u32>> {
+    let (begin, end) = (symbol(vm, "begin_signature")?, symbol(vm, "end_signature")?);
+    (begin..end).step_by(4).map(|addr| Ok(vm.mem.get::<u32>(addr)?)).collect()
+}
+/// Reference signatures have one hex word per line, 32 bits (or 64 bits, lower word second)
+pub fn parse_signature(reference: &str) -> Result<Vec<u32>> {
+    let mut words = Vec::new();
+    for line in reference.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let value = u64::from_str_radix(line, 16).with_context(|| format!("Invalid signature line {line:?}"))?;
+        if line.len() > 8 {
+            words.push(value as u32);
+            words.push((value >> 32) as u32);
+        } else {
+            words.push(value as u32);
+        }
+    }
+    Ok(words)
+}
+pub fn compare_signatures(expected: &[u32], found: &[u32]) -> Outcome {
+    if let Some(index) = expected.iter().zip(found).position(|(expected, found)| expected != found) {
+        return Outcome::SignatureMismatch { index, expected: expected[index], found: found[index] }
+    }
+    if expected.len() != found.len() {
+        return Outcome::SignatureLength { expected: expected.len(), found: found.len() }
+    }
+    Outcome::Pass
+}
+
+/// Runs a riscv-arch-test ELF and compares its signature
+pub fn run_arch_test(elf: Vec<u8>, reference: &str, max_instructions: u64) -> Result<(Outcome, u64)> {
+    let expected = parse_signature(reference)?;
+    let mut vm = VM::new(elf, RAM_SIZE)?;
+    vm.trace = false;
+    let outcome = match run_to_host(&mut vm, max_instructions)? {
+        0 => Outcome::Timeout,
+        _ => compare_signatures(&expected, &signature(&mut vm)?),
+    };
+    Ok((outcome, vm.cpu.instret))
+}
+
+fn report(name: String, result: Result<(Outcome, u64)>) -> TestReport {
+    match result {
+        Ok((outcome, instructions)) => TestReport { name, outcome, instructions },
+        Err(err) => TestReport { name, outcome: Outcome::Error(err.to_string()), instructions: 0 },
+    }
+}
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = std::fs::read_dir(dir).with_context(|| format!("Can't read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// Runs every rv64* riscv-tests ELF of `dir` ("p" environment, dumps and other files are ignored)
+pub fn run_riscv_tests_dir(dir: &Path, max_instructions: u64) -> Result<Vec<TestReport>> {
+    let mut reports = Vec::new();
+    for path in sorted_entries(dir)? {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {continue};
+        if !name.starts_with("rv64") || !name.contains("-p-") || path.extension().is_some() {continue}
+        let result = std::fs::read(&path).map_err(Into::into).and_then(|elf| run_riscv_test(elf, max_instructions));
+        reports.push(report(name.to_string(), result));
+    }
+    Ok(reports)
+}
+/// Runs every `<name>.elf` of `dir` that has a `<name>.reference_output` next to it
+pub fn run_arch_tests_dir(dir: &Path, max_instructions: u64) -> Result<Vec<TestReport>> {
+    let mut reports = Vec::new();
+    for path in sorted_entries(dir)? {
+        if path.extension().is_none_or(|ext| ext != "elf") {continue}
+        let reference = path.with_extension("reference_output");
+        if !reference.exists() {continue}
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let result = (|| {
+            let reference = std::fs::read_to_string(&reference)?;
+            run_arch_test(std::fs::read(&path)?, &reference, max_instructions)
+        })();
+        reports.push(report(name, result));
+    }
+    Ok(reports)
+}
+
+/// One line per test and a summary
+pub fn print_reports(reports: &[TestReport]) {
+    for report in reports {
+        println!("{:<40} {:>10} instructions  {}", report.name, report.instructions, report.outcome);
+    }
+    let passed = reports.iter().filter(|report| report.outcome.passed()).count();
+    println!("{passed}/{} passed", reports.len());
+}
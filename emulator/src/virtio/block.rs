@@ -0,0 +1,141 @@
+// virtio-blk (5.2): one request queue, each request is a header (type, reserved, sector), the data, and a status byte
+// the device writes last. Sectors are 512 bytes, the configuration space has the capacity in sectors
+// The data goes to and comes from a `block::Disk`, which can keep the writes in an overlay
+use color_eyre::eyre::ContextCompat;
+
+use super::{Chain, Queue, VirtioDevice};
+use crate::block::{Disk, OverlaySnapshot, SECTOR};
+use crate::mem::Memory;
+use crate::vm::VM;
+
+pub const BLOCK_ID: u32 = 2;
+/// The disk is read-only, and the device handles flushes
+pub const F_RO: u64 = 1 << 5;
+pub const F_FLUSH: u64 = 1 << 9;
+
+/// Request types
+pub const T_IN: u32 = 0;
+pub const T_OUT: u32 = 1;
+pub const T_FLUSH: u32 = 4;
+pub const T_GET_ID: u32 = 8;
+/// Status of a request
+pub const S_OK: u8 = 0;
+pub const S_IOERR: u8 = 1;
+pub const S_UNSUPP: u8 = 2;
+
+/// struct virtio_blk_req before the data
+pub const HEADER: usize = 16;
+/// Bytes of the answer to GET_ID
+pub const ID_LEN: usize = 20;
+const QUEUE_SIZE: u16 = 128;
+
+pub struct VirtioBlk {
+    disk: Disk,
+}
+impl VirtioBlk {
+    pub fn new(disk: Disk) -> Self {
+        Self { disk }
+    }
+    pub fn disk(&self) -> &Disk {
+        &self.disk
+    }
+    pub fn disk_mut(&mut self) -> &mut Disk {
+        &mut self.disk
+    }
+    /// Runs the request of `chain`, returns what to write back: the data read, then the status
+    fn request(&mut self, chain: &Chain, mem: &mut Memory) -> Vec<u8> {
+        let request = chain.read(mem);
+        let Some(header) = request.get(..HEADER) else {return vec![S_IOERR]};
+        let kind = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..].try_into().unwrap());
+        // The status byte is the last writable one
+        let len = chain.writable_len().saturating_sub(1);
+        let in_bounds = |bytes: usize| sector.checked_mul(SECTOR).and_then(|start| start.checked_add(bytes as u64))
+            .is_some_and(|end| end <= self.disk.sectors() * SECTOR);
+        match kind {
+            T_IN if in_bounds(len) => {
+                let mut data = vec![0; len];
+                match self.disk.read(sector, &mut data) {
+                    Ok(()) => data.push(S_OK),
+                    Err(err) => {
+                        log::warn!("Can't read sector {sector} of {}: {err}", self.disk.path().display());
+                        data.push(S_IOERR);
+                    },
+                }
+                data
+            },
+            T_OUT if in_bounds(request.len() - HEADER) => match self.disk.write(sector, &request[HEADER..]) {
+                Ok(()) => vec![S_OK],
+                Err(err) => {
+                    log::warn!("Can't write sector {sector} of {}: {err}", self.disk.path().display());
+                    vec![S_IOERR]
+                },
+            },
+            T_IN | T_OUT => vec![S_IOERR],
+            T_FLUSH => vec![if self.disk.flush().is_ok() {S_OK} else {S_IOERR}],
+            T_GET_ID => {
+                let name = self.disk.path().file_name().unwrap_or_default().to_string_lossy();
+                let mut id: Vec<u8> = name.bytes().take(ID_LEN).collect();
+                id.resize(ID_LEN.min(len), 0);
+                id.push(S_OK);
+                id
+            },
+            _ => vec![S_UNSUPP],
+        }
+    }
+}
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        BLOCK_ID
+    }
+    fn features(&self) -> u64 {
+        F_FLUSH | if self.disk.read_only() {F_RO} else {0}
+    }
+    fn queue_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+    /// capacity, then the fields of features that aren't offered
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.disk.sectors().to_le_bytes().to_vec();
+        config.resize(24, 0);
+        config
+    }
+    fn process(&mut self, queue: usize, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(mem) {
+            let mut answer = self.request(&chain, mem);
+            // A read that doesn't fill the buffers still puts the status in the last byte
+            let status = answer.pop().unwrap();
+            answer.resize(chain.writable_len().saturating_sub(1), 0);
+            answer.push(status);
+            let written = chain.write(mem, &answer);
+            queues[queue].push(mem, chain.head, written);
+            used = true;
+        }
+        used
+    }
+    /// The overlay, the snapshots of the VM can't undo the writes of a drive without one
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {
+        if self.disk.overlay().is_none() && !self.disk.read_only() {
+            color_eyre::eyre::bail!("{} is written in place, snapshots need its writes in an overlay (snapshot=on)", self.disk.path().display())
+        }
+        Ok(self.disk.overlay_mut().map(|overlay| overlay.snapshot()).transpose()?.map(|snapshot| Box::new(snapshot) as _))
+    }
+    fn restore(&mut self, state: &dyn std::any::Any) -> color_eyre::Result<()> {
+        let snapshot: &OverlaySnapshot = crate::snapshot::state(state)?;
+        self.disk.overlay_mut().context("The drive has no overlay to restore")?.restore(snapshot)?;
+        Ok(())
+    }
+}
+
+impl VM {
+    /// Commits or discards the overlays of the drives, at exit
+    pub fn close_drives(&mut self) -> color_eyre::Result<()> {
+        let mut index = 0;
+        while let Some(drive) = self.virtio_device_mut::<VirtioBlk>(index) {
+            drive.disk.close()?;
+            index += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,353 @@
+// virtio-console (5.3) with VIRTIO_CONSOLE_F_MULTIPORT: port 0 is the console (hvc0), the others are named ports
+// (/dev/vportNpM, linked from /dev/virtio-ports/NAME on Linux)
+// Queues: receive and transmit of port 0, receive and transmit of the control port, then a pair per other port
+// The driver says DEVICE_READY, the device answers DEVICE_ADD for each port, then CONSOLE_PORT, PORT_NAME and PORT_OPEN
+// for each port the driver says is ready; the driver then reports PORT_OPEN when the guest opens or closes a port
+// The host side of a port is files or pipes: what is read from `in` goes to the guest, what the guest writes goes to `out`
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+
+use super::{Queue, VirtioDevice};
+use crate::mem::Memory;
+
+pub const CONSOLE_ID: u32 = 3;
+/// The configuration has the number of ports and there is a control port
+pub const F_MULTIPORT: u64 = 1 << 1;
+/// The driver can write a byte of port 0 through emerg_wr
+pub const F_EMERG_WRITE: u64 = 1 << 2;
+
+pub const CONTROL_RECEIVE: usize = 2;
+pub const CONTROL_TRANSMIT: usize = 3;
+/// Events of the control port, in struct virtio_console_control
+pub const DEVICE_READY: u16 = 0;
+pub const DEVICE_ADD: u16 = 1;
+pub const DEVICE_REMOVE: u16 = 2;
+pub const PORT_READY: u16 = 3;
+pub const CONSOLE_PORT: u16 = 4;
+pub const RESIZE: u16 = 5;
+pub const PORT_OPEN: u16 = 6;
+pub const PORT_NAME: u16 = 7;
+/// Size of struct virtio_console_control: id, event and value
+pub const CONTROL_LEN: usize = 8;
+
+const QUEUE_SIZE: u16 = 64;
+/// Offset of emerg_wr in the configuration space
+const EMERG_WR: usize = 8;
+/// Ticks between two looks at the `in` files
+const POLL_TICKS: u64 = 1024;
+
+/// Receive queue of `port`, its transmit queue follows
+pub fn receive_queue(port: u32) -> usize {
+    if port == 0 {0} else {2 * port as usize + 2}
+}
+
+/// A port of `--console-port`: name=NAME, in=PATH and out=PATH, all optional
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortConfig {
+    pub name: String,
+    pub input: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+}
+impl std::str::FromStr for PortConfig {
+    type Err = String;
+    fn from_str(option: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        for field in option.split(',').filter(|field| !field.is_empty()) {
+            match field.split_once('=') {
+                Some(("name", name)) => config.name = name.into(),
+                Some(("in", path)) => config.input = Some(path.into()),
+                Some(("out", path)) => config.output = Some(path.into()),
+                _ => return Err(format!("Unknown console port field {field:?}, they are name, in and out")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Reads `path` on its own thread, a pipe blocks until it has a writer and a file ends
+fn reader(path: &Path) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    let path = path.to_path_buf();
+    std::thread::spawn(move || {
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) => return log::warn!("Can't open {}: {err}", path.display()),
+        };
+        let mut buffer = [0; 4096];
+        loop {
+            match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => if sender.send(buffer[..read].to_vec()).is_err() {break},
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(err) => break log::warn!("Can't read {}: {err}", path.display()),
+            }
+        }
+    });
+    receiver
+}
+
+pub struct Port {
+    pub name: String,
+    input: Option<Receiver<Vec<u8>>>,
+    output: Option<File>,
+    /// Bytes for the guest
+    received: VecDeque<u8>,
+    /// What the guest wrote, kept when there is no `out`
+    transmitted: Vec<u8>,
+    /// The guest has the port open
+    guest_open: bool,
+    /// The receive queue had no buffer, waiting for the driver to add some
+    starved: bool,
+}
+impl Port {
+    /// A port whose host side is `send` and `transmitted`
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(), input: None, output: None, received: VecDeque::new(), transmitted: Vec::new(),
+            guest_open: false, starved: false,
+        }
+    }
+    /// Opening a pipe for `out` waits for its reader
+    pub fn open(config: &PortConfig) -> Result<Self> {
+        let mut port = Self::new(&config.name);
+        if let Some(path) = &config.input {
+            if !path.exists() {bail!("No console port input {}", path.display())}
+            port.input = Some(reader(path));
+        }
+        if let Some(path) = &config.output {
+            port.output = Some(File::create(path).with_context(|| format!("Can't create the console port output {}", path.display()))?);
+        }
+        Ok(port)
+    }
+    /// Queues `data` for the guest
+    pub fn send(&mut self, data: &[u8]) {
+        self.received.extend(data);
+    }
+    /// What the guest wrote, when the port has no `out`
+    pub fn transmitted(&self) -> &[u8] {
+        &self.transmitted
+    }
+    pub fn is_open(&self) -> bool {
+        self.guest_open
+    }
+    fn fetch(&mut self) {
+        while let Some(data) = self.input.as_ref().and_then(|input| input.try_recv().ok()) {
+            self.received.extend(data);
+        }
+    }
+    fn write(&mut self, data: &[u8]) {
+        match &mut self.output {
+            Some(file) => if let Err(err) = file.write_all(data).and_then(|()| file.flush()) {
+                log::warn!("Can't write the output of console port {:?}: {err}", self.name);
+            },
+            None => self.transmitted.extend(data),
+        }
+    }
+    fn deliver(&mut self, queue: &mut Queue, mem: &mut Memory) -> bool {
+        let mut used = false;
+        while !self.received.is_empty() {
+            let Some(chain) = queue.pop(mem) else {
+                self.starved = true;
+                break
+            };
+            let data: Vec<u8> = self.received.iter().take(chain.writable_len()).copied().collect();
+            let written = chain.write(mem, &data);
+            self.received.drain(..written as usize);
+            queue.push(mem, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
+
+/// struct virtio_console_control, then the name for PORT_NAME
+fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut message = id.to_le_bytes().to_vec();
+    message.extend(event.to_le_bytes());
+    message.extend(value.to_le_bytes());
+    message
+}
+
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    features: u64,
+    /// Control messages for the driver
+    control: VecDeque<Vec<u8>>,
+    control_starved: bool,
+    pub cols: u16,
+    pub rows: u16,
+    ticks: u64,
+}
+impl VirtioConsole {
+    /// A console with `ports`, port 0 is the console, there is always one
+    pub fn new(mut ports: Vec<Port>) -> Self {
+        if ports.is_empty() {ports.push(Port::new(""))}
+        Self { ports, features: 0, control: VecDeque::new(), control_starved: false, cols: 80, rows: 25, ticks: 0 }
+    }
+    pub fn open(configs: &[PortConfig]) -> Result<Self> {
+        Ok(Self::new(configs.iter().map(Port::open).collect::<Result<_>>()?))
+    }
+    pub fn port(&self, id: u32) -> Option<&Port> {
+        self.ports.get(id as usize)
+    }
+    pub fn port_mut(&mut self, id: u32) -> Option<&mut Port> {
+        self.ports.get_mut(id as usize)
+    }
+    fn multiport(&self) -> bool {
+        self.features & F_MULTIPORT != 0
+    }
+    /// Port whose transmit or receive queue is `queue`
+    fn port_of(&self, queue: usize) -> Option<u32> {
+        let port = match queue {
+            0 | 1 => 0,
+            CONTROL_RECEIVE | CONTROL_TRANSMIT => return None,
+            _ => (queue as u32 - 2) / 2,
+        };
+        (port == 0 || self.multiport()).then_some(port).filter(|port| (*port as usize) < self.ports.len())
+    }
+    fn handle_control(&mut self, id: u32, event: u16, value: u16) {
+        match event {
+            DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() as u32 {
+                    self.control.push_back(control(id, DEVICE_ADD, 0));
+                }
+            },
+            DEVICE_READY => log::warn!("The virtio-console driver failed to set up"),
+            PORT_READY => match self.ports.get(id as usize) {
+                Some(port) if value == 1 => {
+                    if id == 0 {self.control.push_back(control(id, CONSOLE_PORT, 1))}
+                    if !port.name.is_empty() {
+                        let mut message = control(id, PORT_NAME, 1);
+                        message.extend(port.name.as_bytes());
+                        self.control.push_back(message);
+                    }
+                    // The host side is always connected
+                    self.control.push_back(control(id, PORT_OPEN, 1));
+                },
+                Some(_) => log::warn!("The virtio-console driver failed to set up port {id}"),
+                None => log::warn!("The virtio-console driver is ready for port {id}, there is none"),
+            },
+            PORT_OPEN => if let Some(port) = self.ports.get_mut(id as usize) {
+                port.guest_open = value != 0;
+            },
+            _ => log::debug!("Ignoring virtio-console control event {event} for port {id}"),
+        }
+    }
+    fn deliver_control(&mut self, queue: &mut Queue, mem: &mut Memory) -> bool {
+        let mut used = false;
+        while let Some(len) = self.control.front().map(Vec::len) {
+            let Some(chain) = queue.pop(mem) else {
+                self.control_starved = true;
+                break
+            };
+            let message = self.control.pop_front().unwrap();
+            let written = if chain.writable_len() < len {
+                log::warn!("Dropping a {len} bytes control message, the buffer is {} bytes", chain.writable_len());
+                0
+            } else {
+                chain.write(mem, &message)
+            };
+            queue.push(mem, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        CONSOLE_ID
+    }
+    fn features(&self) -> u64 {
+        F_MULTIPORT | F_EMERG_WRITE
+    }
+    fn set_features(&mut self, features: u64) {
+        self.features = features;
+    }
+    fn queue_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; 2 * (self.ports.len() + 1)]
+    }
+    /// cols, rows, max_nr_ports and emerg_wr
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.cols.to_le_bytes().to_vec();
+        config.extend(self.rows.to_le_bytes());
+        config.extend((self.ports.len() as u32).to_le_bytes());
+        config.extend(0u32.to_le_bytes());
+        config
+    }
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        if let (EMERG_WR, Some(byte)) = (offset, data.first()) {
+            self.ports[0].write(&[*byte]);
+        }
+    }
+    fn process(&mut self, queue: usize, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        if queue == CONTROL_TRANSMIT && self.multiport() {
+            let mut used = false;
+            while let Some(chain) = queues[queue].pop(mem) {
+                let data = chain.read(mem);
+                if let Some(message) = data.get(..CONTROL_LEN) {
+                    let id = u32::from_le_bytes(message[..4].try_into().unwrap());
+                    self.handle_control(id, u16::from_le_bytes([message[4], message[5]]), u16::from_le_bytes([message[6], message[7]]));
+                }
+                queues[queue].push(mem, chain.head, 0);
+                used = true;
+            }
+            return self.deliver_control(&mut queues[CONTROL_RECEIVE], mem) | used
+        }
+        if queue == CONTROL_RECEIVE {
+            self.control_starved = false;
+            return self.deliver_control(&mut queues[queue], mem)
+        }
+        let Some(id) = self.port_of(queue) else {return false};
+        let port = &mut self.ports[id as usize];
+        if queue == receive_queue(id) {
+            port.starved = false;
+            return port.deliver(&mut queues[queue], mem)
+        }
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(mem) {
+            port.write(&chain.read(mem));
+            queues[queue].push(mem, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+    fn pending(&self) -> bool {
+        (!self.control.is_empty() && !self.control_starved) || self.ports.iter().enumerate().any(|(id, port)| {
+            !port.received.is_empty() && !port.starved && self.port_of(receive_queue(id as u32)).is_some()
+        })
+    }
+    fn poll(&mut self, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        let mut used = false;
+        if !self.control_starved {
+            used |= self.deliver_control(&mut queues[CONTROL_RECEIVE], mem);
+        }
+        for id in 0..self.ports.len() as u32 {
+            if self.port_of(receive_queue(id)).is_none() {continue}
+            let port = &mut self.ports[id as usize];
+            if !port.starved {
+                used |= port.deliver(&mut queues[receive_queue(id)], mem);
+            }
+        }
+        used
+    }
+    fn reset(&mut self) {
+        self.features = 0;
+        self.control.clear();
+        self.control_starved = false;
+        for port in &mut self.ports {
+            (port.guest_open, port.starved) = (false, false);
+        }
+    }
+    fn tick(&mut self) {
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(POLL_TICKS) {
+            self.ports.iter_mut().for_each(Port::fetch);
+        }
+    }
+}
@@ -0,0 +1,433 @@
+// virtio-gpu (5.7) in 2D mode, one scanout: the driver creates resources, backs them with guest pages, copies those into
+// the host copy of the resource (TRANSFER_TO_HOST_2D) and shows one on the scanout, RESOURCE_FLUSH tells it changed
+// There is no window, the scanout is an in-memory framebuffer saved as PNG or PPM frames on flush or by `screendump`
+// The cursor queue moves and changes the cursor, drawn over the frames
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use super::{Queue, VirtioDevice};
+use crate::image::{self, Frame};
+use crate::mem::Memory;
+use crate::uguest;
+
+pub const GPU_ID: u32 = 16;
+pub const CONTROL: usize = 0;
+pub const CURSOR: usize = 1;
+const QUEUE_SIZE: u16 = 256;
+/// QEMU's default resolution
+pub const DEFAULT_WIDTH: u32 = 1280;
+pub const DEFAULT_HEIGHT: u32 = 800;
+/// Resources bigger than this are refused, like a host out of memory
+const MAX_RESOURCE: u64 = 256 << 20;
+
+// Commands
+pub const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+pub const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+pub const CMD_RESOURCE_UNREF: u32 = 0x0102;
+pub const CMD_SET_SCANOUT: u32 = 0x0103;
+pub const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+pub const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+pub const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+pub const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+pub const CMD_UPDATE_CURSOR: u32 = 0x0300;
+pub const CMD_MOVE_CURSOR: u32 = 0x0301;
+// Responses
+pub const RESP_OK_NODATA: u32 = 0x1100;
+pub const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+pub const RESP_ERR_UNSPEC: u32 = 0x1200;
+pub const RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+pub const RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+pub const RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+pub const RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+/// The response is sent once the command is done, with the fence_id of the request
+pub const FLAG_FENCE: u32 = 1;
+/// struct virtio_gpu_ctrl_hdr
+pub const HEADER: usize = 24;
+/// Scanouts in a display info response, only the first one exists
+const MAX_SCANOUTS: usize = 16;
+/// Bit of events_read
+pub const EVENT_DISPLAY: u32 = 1;
+
+// Pixel formats, named after the order of the bytes in memory
+pub const FORMAT_B8G8R8A8: u32 = 1;
+pub const FORMAT_B8G8R8X8: u32 = 2;
+pub const FORMAT_A8R8G8B8: u32 = 3;
+pub const FORMAT_X8R8G8B8: u32 = 4;
+pub const FORMAT_R8G8B8A8: u32 = 67;
+pub const FORMAT_X8B8G8R8: u32 = 68;
+pub const FORMAT_A8B8G8R8: u32 = 121;
+pub const FORMAT_R8G8B8X8: u32 = 134;
+
+/// Red, green, blue and alpha of a pixel of `format`
+fn rgba(format: u32, [a, b, c, d]: [u8; 4]) -> [u8; 4] {
+    match format {
+        FORMAT_B8G8R8A8 => [c, b, a, d],
+        FORMAT_B8G8R8X8 => [c, b, a, 0xFF],
+        FORMAT_A8R8G8B8 => [b, c, d, a],
+        FORMAT_X8R8G8B8 => [b, c, d, 0xFF],
+        FORMAT_R8G8B8A8 => [a, b, c, d],
+        FORMAT_X8B8G8R8 => [d, c, b, 0xFF],
+        FORMAT_A8B8G8R8 => [d, c, b, a],
+        _ => [a, b, c, 0xFF],
+    }
+}
+const FORMATS: [u32; 8] = [
+    FORMAT_B8G8R8A8, FORMAT_B8G8R8X8, FORMAT_A8R8G8B8, FORMAT_X8R8G8B8,
+    FORMAT_R8G8B8A8, FORMAT_X8B8G8R8, FORMAT_A8B8G8R8, FORMAT_R8G8B8X8,
+];
+
+/// struct virtio_gpu_rect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+impl Rect {
+    fn parse(data: &[u8]) -> Self {
+        Self { x: word(data, 0), y: word(data, 4), width: word(data, 8), height: word(data, 12) }
+    }
+    /// Whether it lies in a `width` x `height` surface
+    fn fits(&self, width: u32, height: u32) -> bool {
+        self.x.checked_add(self.width).is_some_and(|right| right <= width)
+            && self.y.checked_add(self.height).is_some_and(|bottom| bottom <= height)
+    }
+}
+/// Little endian word at `offset` of a request, 0 past its end
+fn word(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// A 2D resource, the host copy of its pixels and the guest memory backing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub format: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+    /// Address and length of each part of the backing, in order
+    pub backing: Vec<(uguest, u32)>,
+}
+impl Resource {
+    fn rgba(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        rgba(self.format, self.pixels[i..i + 4].try_into().unwrap())
+    }
+    /// `buffer.len()` bytes of the backing from `offset`, false if it is shorter
+    fn read_backing(&self, mem: &mut Memory, mut offset: u64, buffer: &mut [u8]) -> bool {
+        let mut done = 0;
+        for &(addr, len) in &self.backing {
+            if done == buffer.len() {break}
+            if offset >= len as u64 {
+                offset -= len as u64;
+                continue
+            }
+            let count = ((len as u64 - offset) as usize).min(buffer.len() - done);
+            if mem.read(addr + offset, &mut buffer[done..done + count]).is_err() {return false}
+            done += count;
+            offset = 0;
+        }
+        done == buffer.len()
+    }
+}
+
+/// What the scanout shows, a part of a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Scanout {
+    pub resource: u32,
+    pub rect: Rect,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cursor {
+    /// 0 hides it
+    pub resource: u32,
+    pub x: u32,
+    pub y: u32,
+    pub hot_x: u32,
+    pub hot_y: u32,
+}
+
+/// Where the frames of `RESOURCE_FLUSH` go: frame-00000.png, frame-00001.png...
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameDump {
+    pub dir: PathBuf,
+    pub format: image::Format,
+    /// Number of the next frame
+    pub count: u32,
+}
+
+/// The display of `--gpu`: xres=WIDTH, yres=HEIGHT, frames=DIR and format=png|ppm, all optional
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuConfig {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Option<PathBuf>,
+    pub format: image::Format,
+}
+impl Default for GpuConfig {
+    fn default() -> Self {
+        Self { width: DEFAULT_WIDTH, height: DEFAULT_HEIGHT, frames: None, format: image::Format::Png }
+    }
+}
+impl std::str::FromStr for GpuConfig {
+    type Err = String;
+    fn from_str(option: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        for field in option.split(',').filter(|field| !field.is_empty()) {
+            let size = |value: &str| value.parse().ok().filter(|size| *size > 0).ok_or_else(|| format!("Invalid display size {value:?}"));
+            match field.split_once('=') {
+                Some(("xres", width)) => config.width = size(width)?,
+                Some(("yres", height)) => config.height = size(height)?,
+                Some(("frames", dir)) => config.frames = Some(dir.into()),
+                Some(("format", format)) => config.format = format.parse()?,
+                _ => return Err(format!("Unknown display field {field:?}, they are xres, yres, frames and format")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+pub struct VirtioGpu {
+    /// Size of the scanout the driver is told about
+    pub width: u32,
+    pub height: u32,
+    resources: BTreeMap<u32, Resource>,
+    scanout: Scanout,
+    cursor: Cursor,
+    /// events_read
+    events: u32,
+    /// An event was raised since the last configuration change interrupt
+    display_changed: bool,
+    dump: Option<FrameDump>,
+    /// Flushes of the scanout, whether or not they were saved
+    pub flushes: u64,
+}
+impl VirtioGpu {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width, height, resources: BTreeMap::new(), scanout: Scanout::default(), cursor: Cursor::default(), events: 0,
+            display_changed: false, dump: None, flushes: 0,
+        }
+    }
+    pub fn open(config: &GpuConfig) -> color_eyre::Result<Self> {
+        let mut gpu = Self::new(config.width, config.height);
+        if let Some(dir) = &config.frames {
+            std::fs::create_dir_all(dir)?;
+            gpu.dump = Some(FrameDump { dir: dir.clone(), format: config.format, count: 0 });
+        }
+        Ok(gpu)
+    }
+    /// Saves a frame in `dir` on each flush of the scanout
+    pub fn with_frame_dump(mut self, dir: PathBuf, format: image::Format) -> Self {
+        self.dump = Some(FrameDump { dir, format, count: 0 });
+        self
+    }
+    pub fn resource(&self, id: u32) -> Option<&Resource> {
+        self.resources.get(&id)
+    }
+    pub fn scanout(&self) -> Scanout {
+        self.scanout
+    }
+    pub fn cursor(&self) -> Cursor {
+        self.cursor
+    }
+    /// Changes the display size, the driver gets a display event
+    pub fn resize(&mut self, width: u32, height: u32) {
+        (self.width, self.height) = (width, height);
+        self.events |= EVENT_DISPLAY;
+        self.display_changed = true;
+    }
+    /// What the scanout shows with the cursor over it, None while it is disabled
+    pub fn screendump(&self) -> Option<Frame> {
+        let resource = self.resources.get(&self.scanout.resource)?;
+        let rect = self.scanout.rect;
+        let mut frame = Frame::new(rect.width, rect.height);
+        for y in 0..rect.height {
+            for x in 0..rect.width {
+                let [r, g, b, _] = resource.rgba(rect.x + x, rect.y + y);
+                frame.set_pixel(x, y, [r, g, b]);
+            }
+        }
+        if let Some(cursor) = self.resources.get(&self.cursor.resource).filter(|_| self.cursor.resource != 0) {
+            for cy in 0..cursor.height {
+                for cx in 0..cursor.width {
+                    let (Some(x), Some(y)) = ((self.cursor.x + cx).checked_sub(self.cursor.hot_x), (self.cursor.y + cy).checked_sub(self.cursor.hot_y)) else {continue};
+                    if x >= rect.width || y >= rect.height {continue}
+                    let [r, g, b, a] = cursor.rgba(cx, cy);
+                    let below = frame.pixel(x, y);
+                    let blend = |over: u8, under: u8| ((over as u32 * a as u32 + under as u32 * (255 - a as u32)) / 255) as u8;
+                    frame.set_pixel(x, y, [blend(r, below[0]), blend(g, below[1]), blend(b, below[2])]);
+                }
+            }
+        }
+        Some(frame)
+    }
+
+    /// Runs a control queue command, returns the response type and its payload
+    fn control(&mut self, request: &[u8], mem: &mut Memory) -> (u32, Vec<u8>) {
+        let body = &request[HEADER.min(request.len())..];
+        let id = |offset: usize| word(body, offset);
+        match word(request, 0) {
+            CMD_GET_DISPLAY_INFO => {
+                let mut info = Vec::new();
+                for scanout in 0..MAX_SCANOUTS {
+                    let (width, height, enabled) = if scanout == 0 {(self.width, self.height, 1)} else {(0, 0, 0)};
+                    for value in [0, 0, width, height, enabled, 0] {
+                        info.extend(u32::to_le_bytes(value));
+                    }
+                }
+                (RESP_OK_DISPLAY_INFO, info)
+            },
+            CMD_RESOURCE_CREATE_2D => {
+                let (resource, format, width, height) = (id(0), id(4), id(8), id(12));
+                if resource == 0 || self.resources.contains_key(&resource) {return (RESP_ERR_INVALID_RESOURCE_ID, Vec::new())}
+                if !FORMATS.contains(&format) || width == 0 || height == 0 {return (RESP_ERR_INVALID_PARAMETER, Vec::new())}
+                let size = width as u64 * height as u64 * 4;
+                if size > MAX_RESOURCE {return (RESP_ERR_OUT_OF_MEMORY, Vec::new())}
+                self.resources.insert(resource, Resource { format, width, height, pixels: vec![0; size as usize], backing: Vec::new() });
+                (RESP_OK_NODATA, Vec::new())
+            },
+            CMD_RESOURCE_UNREF => match self.resources.remove(&id(0)) {
+                Some(_) => {
+                    if self.scanout.resource == id(0) {self.scanout = Scanout::default()}
+                    (RESP_OK_NODATA, Vec::new())
+                },
+                None => (RESP_ERR_INVALID_RESOURCE_ID, Vec::new()),
+            },
+            CMD_SET_SCANOUT => {
+                let (rect, scanout, resource) = (Rect::parse(body), id(16), id(20));
+                if scanout != 0 {return (RESP_ERR_INVALID_SCANOUT_ID, Vec::new())}
+                if resource == 0 {
+                    self.scanout = Scanout::default();
+                    return (RESP_OK_NODATA, Vec::new())
+                }
+                let Some(shown) = self.resources.get(&resource) else {return (RESP_ERR_INVALID_RESOURCE_ID, Vec::new())};
+                if !rect.fits(shown.width, shown.height) || rect.width == 0 || rect.height == 0 {return (RESP_ERR_INVALID_PARAMETER, Vec::new())}
+                self.scanout = Scanout { resource, rect };
+                (RESP_OK_NODATA, Vec::new())
+            },
+            CMD_RESOURCE_FLUSH => {
+                let (rect, resource) = (Rect::parse(body), id(16));
+                let Some(flushed) = self.resources.get(&resource) else {return (RESP_ERR_INVALID_RESOURCE_ID, Vec::new())};
+                if !rect.fits(flushed.width, flushed.height) {return (RESP_ERR_INVALID_PARAMETER, Vec::new())}
+                if resource == self.scanout.resource {
+                    self.flushes += 1;
+                    self.dump_frame();
+                }
+                (RESP_OK_NODATA, Vec::new())
+            },
+            CMD_TRANSFER_TO_HOST_2D => {
+                let (rect, offset, resource) = (Rect::parse(body), word(body, 16) as u64 | (word(body, 20) as u64) << 32, id(24));
+                let Some(target) = self.resources.get_mut(&resource) else {return (RESP_ERR_INVALID_RESOURCE_ID, Vec::new())};
+                if !rect.fits(target.width, target.height) {return (RESP_ERR_INVALID_PARAMETER, Vec::new())}
+                if target.backing.is_empty() {return (RESP_ERR_UNSPEC, Vec::new())}
+                // Row h of the rectangle is at offset + h * stride in the backing, like in QEMU
+                let stride = target.width as usize * 4;
+                let mut row = vec![0; rect.width as usize * 4];
+                for h in 0..rect.height as usize {
+                    if !target.read_backing(mem, offset + (h * stride) as u64, &mut row) {return (RESP_ERR_UNSPEC, Vec::new())}
+                    let start = (rect.y as usize + h) * stride + rect.x as usize * 4;
+                    target.pixels[start..start + row.len()].copy_from_slice(&row);
+                }
+                (RESP_OK_NODATA, Vec::new())
+            },
+            CMD_RESOURCE_ATTACH_BACKING => {
+                let (resource, entries) = (id(0), id(4) as usize);
+                let Some(target) = self.resources.get_mut(&resource) else {return (RESP_ERR_INVALID_RESOURCE_ID, Vec::new())};
+                // struct virtio_gpu_mem_entry: address, length and padding
+                let backing: Vec<(uguest, u32)> = body.get(8..).unwrap_or_default().chunks_exact(16).take(entries)
+                    .map(|entry| (word(entry, 0) as uguest | (word(entry, 4) as uguest) << 32, word(entry, 8))).collect();
+                if backing.len() != entries {return (RESP_ERR_INVALID_PARAMETER, Vec::new())}
+                target.backing = backing;
+                (RESP_OK_NODATA, Vec::new())
+            },
+            CMD_RESOURCE_DETACH_BACKING => match self.resources.get_mut(&id(0)) {
+                Some(target) => {
+                    target.backing.clear();
+                    (RESP_OK_NODATA, Vec::new())
+                },
+                None => (RESP_ERR_INVALID_RESOURCE_ID, Vec::new()),
+            },
+            command => {
+                log::warn!("Unsupported virtio-gpu command {command:#x}");
+                (RESP_ERR_UNSPEC, Vec::new())
+            },
+        }
+    }
+    /// Runs a cursor queue command, they have no error
+    fn cursor_command(&mut self, request: &[u8]) {
+        // struct virtio_gpu_cursor_pos, then resource_id, hot_x and hot_y
+        let body = &request[HEADER.min(request.len())..];
+        let (x, y) = (word(body, 4), word(body, 8));
+        match word(request, 0) {
+            CMD_UPDATE_CURSOR => self.cursor = Cursor { resource: word(body, 16), x, y, hot_x: word(body, 20), hot_y: word(body, 24) },
+            CMD_MOVE_CURSOR => (self.cursor.x, self.cursor.y) = (x, y),
+            command => log::warn!("Unsupported virtio-gpu cursor command {command:#x}"),
+        }
+    }
+    fn dump_frame(&mut self) {
+        let (Some(dump), Some(frame)) = (&self.dump, self.screendump()) else {return};
+        let path = dump.dir.join(format!("frame-{:05}.{}", dump.count, dump.format.extension()));
+        if let Err(err) = std::fs::write(&path, frame.encode(dump.format)) {
+            log::warn!("Can't write the frame {}: {err}", path.display());
+        }
+        if let Some(dump) = &mut self.dump {dump.count += 1}
+    }
+}
+/// Response to `request`: its header with `kind`, the fence copied, then `payload`
+fn response(request: &[u8], kind: u32, payload: &[u8]) -> Vec<u8> {
+    let mut response = vec![0; HEADER];
+    response[..4].copy_from_slice(&kind.to_le_bytes());
+    if word(request, 4) & FLAG_FENCE != 0 {
+        // flags, fence_id, ctx_id and ring_idx
+        response[4..4 + 20].copy_from_slice(&request[4..24]);
+        response[4..8].copy_from_slice(&FLAG_FENCE.to_le_bytes());
+    }
+    response.extend(payload);
+    response
+}
+impl VirtioDevice for VirtioGpu {
+    fn device_id(&self) -> u32 {
+        GPU_ID
+    }
+    fn queue_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; 2]
+    }
+    /// events_read, events_clear, num_scanouts and num_capsets
+    fn config(&self) -> Vec<u8> {
+        [self.events, 0, 1, 0].into_iter().flat_map(u32::to_le_bytes).collect()
+    }
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        if let (4, Ok(clear)) = (offset, <[u8; 4]>::try_from(data)) {
+            self.events &= !u32::from_le_bytes(clear);
+        }
+    }
+    fn process(&mut self, queue: usize, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues.get_mut(queue).and_then(|queue| queue.pop(mem)) {
+            let request = chain.read(mem);
+            let written = match queue {
+                CONTROL if request.len() >= HEADER => {
+                    let (kind, payload) = self.control(&request, mem);
+                    chain.write(mem, &response(&request, kind, &payload))
+                },
+                CONTROL => chain.write(mem, &response(&request, RESP_ERR_UNSPEC, &[])),
+                _ => {
+                    self.cursor_command(&request);
+                    0
+                },
+            };
+            queues[queue].push(mem, chain.head, written);
+            used = true;
+        }
+        used
+    }
+    fn take_config_change(&mut self) -> bool {
+        std::mem::take(&mut self.display_changed)
+    }
+    fn reset(&mut self) {
+        self.resources.clear();
+        (self.scanout, self.cursor) = (Scanout::default(), Cursor::default());
+    }
+}
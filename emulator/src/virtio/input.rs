@@ -0,0 +1,326 @@
+// virtio-input (5.8): Linux input events for the driver in the event queue, LED events from it in the status queue
+// The driver picks what to read in the configuration space with select and subsel: the name, the IDs, and a bitmap of
+// the codes the device has for each event type. A keyboard has the keys 1..=248 and the LEDs of QEMU's, a mouse has
+// three buttons, relative X and Y and a wheel
+// Events come from the host side with `send` or from a `Script`
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+
+use super::{Queue, VirtioDevice};
+use crate::mem::Memory;
+
+pub const INPUT_ID: u32 = 18;
+pub const EVENT_QUEUE: usize = 0;
+pub const STATUS_QUEUE: usize = 1;
+
+/// Event types and codes of linux/input-event-codes.h
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_LED: u16 = 0x11;
+pub const SYN_REPORT: u16 = 0;
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+/// NUMLOCK, CAPSLOCK and SCROLLLOCK
+const LEDS: u16 = 3;
+const KEYS: std::ops::RangeInclusive<u16> = 1..=248;
+
+/// What the configuration space holds, `select`
+pub const CFG_UNSET: u8 = 0x00;
+pub const CFG_ID_NAME: u8 = 0x01;
+pub const CFG_ID_SERIAL: u8 = 0x02;
+pub const CFG_ID_DEVIDS: u8 = 0x03;
+pub const CFG_PROP_BITS: u8 = 0x10;
+pub const CFG_EV_BITS: u8 = 0x11;
+pub const CFG_ABS_INFO: u8 = 0x12;
+/// The union of struct virtio_input_config starts after select, subsel, size and 5 reserved bytes
+pub const CFG_DATA: usize = 8;
+const CFG_DATA_LEN: usize = 128;
+
+/// IDs of QEMU's devices, BUS_VIRTUAL
+const BUS_VIRTUAL: u16 = 0x06;
+const VENDOR: u16 = 0x0627;
+const QUEUE_SIZE: u16 = 64;
+/// Events waiting for buffers, later ones are dropped
+const BACKLOG: usize = 1024;
+
+/// struct virtio_input_event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub kind: u16,
+    pub code: u16,
+    pub value: u32,
+}
+impl Event {
+    pub fn new(kind: u16, code: u16, value: i32) -> Self {
+        Self { kind, code, value: value as u32 }
+    }
+    pub fn syn() -> Self {
+        Self::new(EV_SYN, SYN_REPORT, 0)
+    }
+    pub fn bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[..2].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.code.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8; 8]) -> Self {
+        Self {
+            kind: u16::from_le_bytes([bytes[0], bytes[1]]),
+            code: u16::from_le_bytes([bytes[2], bytes[3]]),
+            value: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputKind {
+    #[default]
+    Keyboard,
+    Mouse,
+}
+
+/// A step of a script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Event(Event),
+    /// Steps of the VM before the next events
+    Wait(u64),
+}
+/// Events to play, one per line, `#` starts a comment:
+/// - `key CODE VALUE`, `rel CODE VALUE`, `abs CODE VALUE` and `syn`, or `TYPE CODE VALUE` with a numeric type
+/// - `press CODE`: the key (or button) goes down then up, each followed by a `syn`
+/// - `wait STEPS`
+///
+/// Numbers are decimal or 0x hexadecimal, values can be negative
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script(pub Vec<Step>);
+fn number(word: &str) -> Result<i64, String> {
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, word),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }.map_err(|err| format!("Invalid number {word:?}: {err}"))?;
+    Ok(if negative {-value} else {value})
+}
+impl std::str::FromStr for Script {
+    type Err = String;
+    fn from_str(script: &str) -> Result<Self, String> {
+        let mut steps = Vec::new();
+        for (i, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let event = |kind: &str, code: &str, value: &str| -> Result<Event, String> {
+                let kind = match kind {
+                    "key" => EV_KEY,
+                    "rel" => EV_REL,
+                    "abs" => EV_ABS,
+                    kind => number(kind)? as u16,
+                };
+                Ok(Event::new(kind, number(code)? as u16, number(value)? as i32))
+            };
+            let step = match words.as_slice() {
+                [] => continue,
+                ["syn"] => Ok(vec![Step::Event(Event::syn())]),
+                ["wait", steps] => number(steps).map(|steps| vec![Step::Wait(steps as u64)]),
+                ["press", code] => number(code).map(|code| vec![
+                    Step::Event(Event::new(EV_KEY, code as u16, 1)), Step::Event(Event::syn()),
+                    Step::Event(Event::new(EV_KEY, code as u16, 0)), Step::Event(Event::syn()),
+                ]),
+                [kind, code, value] => event(kind, code, value).map(|event| vec![Step::Event(event)]),
+                _ => Err(format!("Unknown step {:?}, they are key, rel, abs, syn, press and wait", line.trim())),
+            };
+            steps.extend(step.map_err(|err| format!("Line {}: {err}", i + 1))?);
+        }
+        Ok(Self(steps))
+    }
+}
+
+/// An input device of `--input`: keyboard or mouse, then script=PATH
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputConfig {
+    pub kind: InputKind,
+    pub script: Option<PathBuf>,
+}
+impl std::str::FromStr for InputConfig {
+    type Err = String;
+    fn from_str(option: &str) -> Result<Self, String> {
+        let mut fields = option.split(',');
+        let kind = match fields.next().unwrap_or_default() {
+            "keyboard" => InputKind::Keyboard,
+            "mouse" => InputKind::Mouse,
+            _ => return Err(format!("Unknown input device {option:?}, it is keyboard or mouse")),
+        };
+        let mut config = Self { kind, script: None };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("script", path)) => config.script = Some(path.into()),
+                _ => return Err(format!("Unknown input field {field:?}, there is script")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Bitmap of `codes`
+fn bitmap(codes: impl IntoIterator<Item = u16>) -> Vec<u8> {
+    let mut bitmap = Vec::new();
+    for code in codes {
+        let byte = code as usize / 8;
+        if bitmap.len() <= byte {bitmap.resize(byte + 1, 0)}
+        bitmap[byte] |= 1 << (code % 8);
+    }
+    bitmap
+}
+
+pub struct VirtioInput {
+    pub kind: InputKind,
+    select: u8,
+    subsel: u8,
+    /// Events for the driver
+    events: VecDeque<Event>,
+    script: VecDeque<Step>,
+    wait: u64,
+    /// The event queue had no buffer, waiting for the driver to add some
+    starved: bool,
+    /// What the driver sent in the status queue, LED changes of a keyboard
+    pub status: Vec<Event>,
+}
+impl VirtioInput {
+    pub fn new(kind: InputKind) -> Self {
+        Self { kind, select: CFG_UNSET, subsel: 0, events: VecDeque::new(), script: VecDeque::new(), wait: 0, starved: false, status: Vec::new() }
+    }
+    pub fn with_script(mut self, script: Script) -> Self {
+        self.script = script.0.into();
+        self
+    }
+    pub fn open(config: &InputConfig) -> Result<Self> {
+        let device = Self::new(config.kind);
+        let Some(path) = &config.script else {return Ok(device)};
+        let script = std::fs::read_to_string(path).with_context(|| format!("Can't read the input script {}", path.display()))?;
+        let script = script.parse().map_err(color_eyre::Report::msg).with_context(|| format!("In {}", path.display()))?;
+        Ok(device.with_script(script))
+    }
+    /// Queues `event` for the driver
+    pub fn send(&mut self, event: Event) {
+        if self.events.len() >= BACKLOG {
+            return log::warn!("Dropping an input event, the guest doesn't take them")
+        }
+        self.events.push_back(event);
+    }
+    /// Whether the script has steps left
+    pub fn scripted(&self) -> bool {
+        !self.script.is_empty() || self.wait != 0
+    }
+    /// What the configuration space has for `select` and `subsel`
+    fn query(&self) -> Vec<u8> {
+        let keyboard = self.kind == InputKind::Keyboard;
+        match (self.select, self.subsel) {
+            (CFG_ID_NAME, _) => (if keyboard {"QEMU Virtio Keyboard"} else {"QEMU Virtio Mouse"}).into(),
+            (CFG_ID_DEVIDS, _) => {
+                let product: u16 = if keyboard {1} else {2};
+                [BUS_VIRTUAL, VENDOR, product, 1].iter().flat_map(|word| word.to_le_bytes()).collect()
+            },
+            (CFG_EV_BITS, kind) => match (kind as u16, keyboard) {
+                (EV_KEY, true) => bitmap(KEYS),
+                (EV_LED, true) => bitmap(0..LEDS),
+                (EV_KEY, false) => bitmap([BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]),
+                (EV_REL, false) => bitmap([REL_X, REL_Y, REL_WHEEL]),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+    fn deliver(&mut self, queue: &mut Queue, mem: &mut Memory) -> bool {
+        let mut used = false;
+        while let Some(event) = self.events.front() {
+            let Some(chain) = queue.pop(mem) else {
+                self.starved = true;
+                break
+            };
+            let written = chain.write(mem, &event.bytes());
+            queue.push(mem, chain.head, written);
+            self.events.pop_front();
+            used = true;
+        }
+        used
+    }
+}
+impl VirtioDevice for VirtioInput {
+    fn device_id(&self) -> u32 {
+        INPUT_ID
+    }
+    fn queue_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; 2]
+    }
+    /// select, subsel, size, 5 reserved bytes and the union
+    fn config(&self) -> Vec<u8> {
+        let mut data = self.query();
+        data.truncate(CFG_DATA_LEN);
+        let mut config = vec![self.select, self.subsel, data.len() as u8, 0, 0, 0, 0, 0];
+        config.extend(&data);
+        config.resize(CFG_DATA + CFG_DATA_LEN, 0);
+        config
+    }
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            match offset + i {
+                0 => self.select = *byte,
+                1 => self.subsel = *byte,
+                _ => {},
+            }
+        }
+    }
+    fn process(&mut self, queue: usize, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        if queue == EVENT_QUEUE {
+            self.starved = false;
+            return self.deliver(&mut queues[EVENT_QUEUE], mem)
+        }
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(mem) {
+            if let Ok(bytes) = <[u8; 8]>::try_from(chain.read(mem).get(..8).unwrap_or_default()) {
+                self.status.push(Event::from_bytes(&bytes));
+            }
+            queues[queue].push(mem, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+    fn pending(&self) -> bool {
+        !self.events.is_empty() && !self.starved
+    }
+    fn poll(&mut self, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        self.deliver(&mut queues[EVENT_QUEUE], mem)
+    }
+    fn reset(&mut self) {
+        (self.select, self.subsel, self.starved) = (CFG_UNSET, 0, false);
+    }
+    /// Plays the script up to its next wait
+    fn tick(&mut self) {
+        if self.wait > 0 {
+            self.wait -= 1;
+            return
+        }
+        while let Some(step) = self.script.pop_front() {
+            match step {
+                Step::Event(event) => self.send(event),
+                Step::Wait(steps) => {
+                    self.wait = steps;
+                    break
+                },
+            }
+        }
+    }
+}
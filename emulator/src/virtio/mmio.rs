@@ -0,0 +1,247 @@
+// Legacy virtio-mmio transport (4.2.4), the one the kernel drives: version 1, where a queue is placed by its page number
+// QEMU virt has eight of them from 0x1000_1000, one per page, on irqs 1..=8, empty ones read device ID 0
+// The descriptor table starts the page, the available ring follows it and the used ring starts at the next QueueAlign boundary
+use super::{Virtio, VirtioDevice, VirtioSnapshot, F_VERSION_1};
+use crate::board::DeviceKind;
+use crate::mem::{Memory, MemoryRegion};
+use crate::uguest;
+use crate::vm::VM;
+
+/// Transports of QEMU virt
+pub const TRANSPORTS: uguest = 8;
+pub const STRIDE: uguest = 0x1000;
+/// Interrupt source of the first transport, the others follow
+pub const VIRTIO_IRQ: u32 = 1;
+
+/// "virt"
+pub const MAGIC: u32 = 0x7472_6976;
+pub const VERSION: u32 = 1;
+/// "QEMU"
+pub const VENDOR: u32 = 0x554D_4551;
+
+pub const MAGIC_VALUE: uguest = 0x000;
+pub const VERSION_REG: uguest = 0x004;
+pub const DEVICE_ID: uguest = 0x008;
+pub const VENDOR_ID: uguest = 0x00C;
+pub const HOST_FEATURES: uguest = 0x010;
+pub const HOST_FEATURES_SEL: uguest = 0x014;
+pub const GUEST_FEATURES: uguest = 0x020;
+pub const GUEST_FEATURES_SEL: uguest = 0x024;
+pub const GUEST_PAGE_SIZE: uguest = 0x028;
+pub const QUEUE_SEL: uguest = 0x030;
+pub const QUEUE_NUM_MAX: uguest = 0x034;
+pub const QUEUE_NUM: uguest = 0x038;
+pub const QUEUE_ALIGN: uguest = 0x03C;
+pub const QUEUE_PFN: uguest = 0x040;
+pub const QUEUE_NOTIFY: uguest = 0x050;
+pub const INTERRUPT_STATUS: uguest = 0x060;
+pub const INTERRUPT_ACK: uguest = 0x064;
+pub const STATUS: uguest = 0x070;
+pub const CONFIG: uguest = 0x100;
+
+/// Page size and queue alignment until the driver sets them, QEMU's VIRTIO_PCI_VRING_ALIGN
+const DEFAULT_PAGE: u32 = 4096;
+
+/// Where the driver put a queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Placement {
+    align: u32,
+    pfn: u32,
+}
+impl Default for Placement {
+    fn default() -> Self {
+        Self { align: DEFAULT_PAGE, pfn: 0 }
+    }
+}
+
+/// State of a transport in the snapshots of the VM
+struct MmioSnapshot {
+    virtio: Option<VirtioSnapshot>,
+    page_size: u32,
+    placements: Vec<Placement>,
+}
+
+/// A transport, with a device plugged in or empty
+#[derive(Default)]
+pub struct VirtioMmio {
+    pub virtio: Option<Virtio>,
+    page_size: u32,
+    placements: Vec<Placement>,
+}
+impl VirtioMmio {
+    pub fn new(device: impl VirtioDevice) -> Self {
+        let mut transport = Self::default();
+        transport.plug(device);
+        transport
+    }
+    /// Replaces the device, the driver sees a new one after reset
+    pub fn plug(&mut self, device: impl VirtioDevice) {
+        self.virtio = Some(Virtio::new(device));
+        self.reset();
+    }
+    pub fn is_empty(&self) -> bool {
+        self.virtio.is_none()
+    }
+    pub fn device<T: VirtioDevice>(&self) -> Option<&T> {
+        self.virtio.as_ref()?.device_as()
+    }
+    pub fn device_mut<T: VirtioDevice>(&mut self) -> Option<&mut T> {
+        self.virtio.as_mut()?.device_as_mut()
+    }
+    fn reset(&mut self) {
+        self.page_size = DEFAULT_PAGE;
+        self.placements = vec![Placement::default(); self.virtio.as_ref().map_or(0, |virtio| virtio.queues.len())];
+    }
+
+    fn read_register(&mut self, offset: uguest) -> u32 {
+        let Some(virtio) = &mut self.virtio else {
+            return match offset {
+                MAGIC_VALUE => MAGIC,
+                VERSION_REG => VERSION,
+                VENDOR_ID => VENDOR,
+                _ => 0,
+            }
+        };
+        let select = virtio.queue_select as usize;
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => virtio.device.device_id(),
+            VENDOR_ID => VENDOR,
+            // Legacy devices don't offer VIRTIO_F_VERSION_1
+            HOST_FEATURES => {
+                let features = virtio.device_features() & !F_VERSION_1;
+                match virtio.device_features_select {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            },
+            QUEUE_NUM_MAX => virtio.selected_queue().map_or(0, |queue| queue.max_size as u32),
+            QUEUE_PFN => self.placements.get(select).map_or(0, |placement| placement.pfn),
+            INTERRUPT_STATUS => virtio.interrupt_status,
+            STATUS => virtio.status as u32,
+            _ => 0,
+        }
+    }
+    fn write_register(&mut self, offset: uguest, value: u32) {
+        let Some(virtio) = &mut self.virtio else {return};
+        let select = virtio.queue_select as usize;
+        match offset {
+            HOST_FEATURES_SEL => virtio.device_features_select = value,
+            GUEST_FEATURES => virtio.set_driver_features_word(value),
+            GUEST_FEATURES_SEL => virtio.driver_features_select = value,
+            GUEST_PAGE_SIZE => self.page_size = value,
+            QUEUE_SEL => virtio.queue_select = value as u16,
+            QUEUE_NUM => if let Some(queue) = virtio.selected_queue().filter(|queue| !queue.ready) {
+                if (1..=queue.max_size as u32).contains(&value) {queue.size = value as u16}
+            },
+            QUEUE_ALIGN => if let Some(placement) = self.placements.get_mut(select) {
+                placement.align = value;
+            },
+            QUEUE_PFN => {
+                let (Some(queue), Some(placement)) = (virtio.queues.get_mut(select), self.placements.get_mut(select)) else {return};
+                placement.pfn = value;
+                let size = queue.size as uguest;
+                queue.desc = value as uguest * self.page_size as uguest;
+                // flags, idx, the ring and used_event, a layout past the end of the address space leaves the queue off
+                let align = (placement.align as uguest).max(1);
+                let layout = queue.desc.checked_add(16 * size)
+                    .and_then(|driver| Some((driver, driver.checked_add(6 + 2 * size)?.div_ceil(align).checked_mul(align)?)));
+                let Some((driver, device)) = layout else {
+                    queue.ready = false;
+                    return
+                };
+                (queue.driver, queue.device) = (driver, device);
+                queue.ready = value != 0;
+            },
+            QUEUE_NOTIFY => virtio.notify(value as usize),
+            INTERRUPT_ACK => virtio.interrupt_status &= !value,
+            STATUS => {
+                virtio.set_status(value as u8);
+                if value == 0 {self.reset()}
+            },
+            _ => {},
+        }
+    }
+}
+impl MemoryRegion for VirtioMmio {
+    fn read(&mut self, offset: uguest) -> u8 {
+        let mut byte = [0];
+        self.read_bytes(offset, &mut byte);
+        byte[0]
+    }
+    fn read_bytes(&mut self, offset: uguest, buffer: &mut [u8]) {
+        if offset >= CONFIG {
+            match &self.virtio {
+                Some(virtio) => virtio.read_config(offset - CONFIG, buffer),
+                None => buffer.fill(0),
+            }
+            return
+        }
+        let register = self.read_register(offset & !3).to_le_bytes();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = register.get((offset % 4) as usize + i).copied().unwrap_or(0);
+        }
+    }
+    /// Registers are written 32 bits at a time, the configuration space byte by byte too
+    fn write(&mut self, offset: uguest, val: u8) {
+        self.write_bytes(offset, &[val]);
+    }
+    fn write_bytes(&mut self, offset: uguest, buffer: &[u8]) {
+        if offset >= CONFIG {
+            if let Some(virtio) = &mut self.virtio {virtio.write_config(offset - CONFIG, buffer)}
+        } else if let (Ok(word), true) = (<[u8; 4]>::try_from(buffer), offset.is_multiple_of(4)) {
+            self.write_register(offset, u32::from_le_bytes(word));
+        }
+    }
+    fn tick(&mut self) {
+        if let Some(virtio) = &mut self.virtio {virtio.tick()}
+    }
+    fn interrupt_pending(&self) -> bool {
+        self.virtio.as_ref().is_some_and(|virtio| virtio.interrupt_status != 0)
+    }
+    fn dma_pending(&self) -> bool {
+        self.virtio.as_ref().is_some_and(Virtio::dma_pending)
+    }
+    fn dma(&mut self, bus: &mut Memory) {
+        if let Some(virtio) = &mut self.virtio {virtio.dma(bus)}
+    }
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {
+        let virtio = self.virtio.as_mut().map(Virtio::snapshot).transpose()?;
+        Ok(Some(Box::new(MmioSnapshot { virtio, page_size: self.page_size, placements: self.placements.clone() })))
+    }
+    fn restore(&mut self, state: &dyn std::any::Any) -> color_eyre::Result<()> {
+        let snapshot: &MmioSnapshot = crate::snapshot::state(state)?;
+        match (&mut self.virtio, &snapshot.virtio) {
+            (Some(virtio), Some(saved)) => virtio.restore(saved)?,
+            (None, None) => {},
+            _ => color_eyre::eyre::bail!("A device was plugged into the virtio-mmio transport since the snapshot"),
+        }
+        self.page_size = snapshot.page_size;
+        self.placements.clone_from(&snapshot.placements);
+        Ok(())
+    }
+}
+
+impl VM {
+    /// Plugs `device` into the first empty virtio-mmio transport, returns its base
+    pub fn add_virtio_device(&mut self, device: impl VirtioDevice) -> color_eyre::Result<uguest> {
+        let transports: Vec<uguest> = self.board.devices.iter().filter(|device| device.kind == DeviceKind::VirtioMmio).map(|device| device.base).collect();
+        for base in transports {
+            let Some(transport) = self.mem.device_mut::<VirtioMmio>(base) else {continue};
+            if transport.is_empty() {
+                transport.plug(device);
+                return Ok(base)
+            }
+        }
+        color_eyre::eyre::bail!("No free virtio-mmio transport, the machine needs virtio=on")
+    }
+    /// The `index`th device of type `T` on the virtio-mmio transports, in address order
+    pub fn virtio_device_mut<T: VirtioDevice>(&mut self, index: usize) -> Option<&mut T> {
+        let base = self.board.devices.iter().filter(|device| device.kind == DeviceKind::VirtioMmio).map(|device| device.base)
+            .filter(|base| self.mem.device::<VirtioMmio>(*base).is_some_and(|transport| transport.device::<T>().is_some()))
+            .nth(index)?;
+        self.mem.device_mut::<VirtioMmio>(base)?.device_mut()
+    }
+}
@@ -0,0 +1,311 @@
+// VirtIO devices (https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html)
+// A `VirtioDevice` only deals with its queues and configuration space, `Virtio` holds the state every transport has
+// (feature negotiation, status, queues, interrupt status) and the transports map it on the bus: see `pci::VirtioPci` and
+// `mmio::VirtioMmio`. Devices: `block::VirtioBlk`, `net::VirtioNet`, `gpu::VirtioGpu`, `rng::VirtioRng`,
+// `console::VirtioConsole` and `input::VirtioInput`
+// Queues are split virtqueues, without indirect descriptors nor event suppression (neither feature is offered)
+pub mod block;
+pub mod console;
+pub mod gpu;
+pub mod input;
+pub mod mmio;
+pub mod net;
+pub mod pci;
+pub mod rng;
+
+use crate::mem::Memory;
+use crate::uguest;
+
+/// Device status bits (2.1)
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 0x80;
+/// VIRTIO_F_VERSION_1, offered by every device
+pub const F_VERSION_1: u64 = 1 << 32;
+/// Interrupt status bits: a queue has used buffers, the configuration changed
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+/// Most bytes `Chain::read` hands to a device, the rest of a longer chain is dropped
+pub const CHAIN_READ_MAX: usize = 64 << 20;
+
+/// A device type, its queues are processed when the driver notifies them
+pub trait VirtioDevice: std::any::Any {
+    /// Device ID (5. Device Types), 1 for network, 2 for block...
+    fn device_id(&self) -> u32;
+    /// Device specific feature bits, the transport adds `F_VERSION_1`
+    fn features(&self) -> u64 {0}
+    /// Maximum size of each queue, there is one entry per queue
+    fn queue_sizes(&self) -> Vec<u16>;
+    /// The driver accepted `features`, called whenever they change
+    fn set_features(&mut self, _features: u64) {}
+    /// Device configuration space
+    fn config(&self) -> Vec<u8> {Vec::new()}
+    fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+    /// The driver made buffers available in `queue`, returns whether some were used
+    fn process(&mut self, queue: usize, queues: &mut [Queue], mem: &mut Memory) -> bool;
+    /// Whether `poll` has something to hand to the driver, for devices that receive data from the host side
+    fn pending(&self) -> bool {false}
+    /// Fills the buffers of the driver with what the host side sent, returns whether some were used
+    fn poll(&mut self, _queues: &mut [Queue], _mem: &mut Memory) -> bool {false}
+    /// Whether the configuration changed since the last call (e.g. link status), the driver gets an interrupt
+    fn take_config_change(&mut self) -> bool {false}
+    /// The driver reset the device
+    fn reset(&mut self) {}
+    fn tick(&mut self) {}
+    /// Copy of the device's state for the snapshots of the VM, see `mem::MemoryRegion::snapshot`
+    fn snapshot(&mut self) -> color_eyre::Result<Option<Box<dyn std::any::Any>>> {Ok(None)}
+    fn restore(&mut self, _state: &dyn std::any::Any) -> color_eyre::Result<()> {Ok(())}
+}
+
+/// A buffer of a descriptor chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub addr: uguest,
+    pub len: u32,
+    /// Written by the device, read otherwise
+    pub writable: bool,
+}
+/// Descriptor chain the driver made available, device-readable buffers come first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    pub head: u16,
+    pub buffers: Vec<Buffer>,
+}
+impl Chain {
+    /// Content of the device-readable buffers, up to `CHAIN_READ_MAX` bytes, buffers outside memory are skipped
+    pub fn read(&self, mem: &mut Memory) -> Vec<u8> {
+        let mut data = Vec::new();
+        for buffer in self.buffers.iter().filter(|buffer| !buffer.writable) {
+            let len = (buffer.len as usize).min(CHAIN_READ_MAX - data.len());
+            if len == 0 {break}
+            // The guest picks the lengths, only allocate what memory backs
+            if mem.get_region(buffer.addr, len as uguest).is_none() {continue}
+            let start = data.len();
+            data.resize(start + len, 0);
+            if mem.read(buffer.addr, &mut data[start..]).is_err() {data.truncate(start)}
+        }
+        data
+    }
+    /// Bytes the device can write
+    pub fn writable_len(&self) -> usize {
+        self.buffers.iter().filter(|buffer| buffer.writable).map(|buffer| buffer.len as usize).sum()
+    }
+    /// Writes `data` across the device-writable buffers, returns how many bytes fit
+    pub fn write(&self, mem: &mut Memory, data: &[u8]) -> u32 {
+        let mut written = 0;
+        for buffer in self.buffers.iter().filter(|buffer| buffer.writable) {
+            let len = (buffer.len as usize).min(data.len() - written);
+            if len == 0 || mem.write(buffer.addr, &data[written..written + len]).is_err() {break}
+            written += len;
+        }
+        written as u32
+    }
+}
+
+/// A split virtqueue (2.7), the addresses are guest physical ones
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Queue {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    /// Descriptor table, available ring (driver area) and used ring (device area)
+    pub desc: uguest,
+    pub driver: uguest,
+    pub device: uguest,
+    /// Next available ring entry to take, and next used ring entry to fill
+    last_avail: u16,
+    used: u16,
+}
+impl Queue {
+    pub fn new(max_size: u16) -> Self {
+        Self { max_size, size: max_size, ..Default::default() }
+    }
+    /// Takes the next chain the driver made available
+    /// The driver sets the addresses of the rings to anything, the ones that don't fit in the address space don't work
+    pub fn pop(&mut self, mem: &mut Memory) -> Option<Chain> {
+        if !self.ready || self.size == 0 {return None}
+        let avail_idx: u16 = mem.get(self.driver.checked_add(2)?).ok()?;
+        if avail_idx == self.last_avail {return None}
+        let slot = self.driver.checked_add(4 + 2 * (self.last_avail % self.size) as uguest)?;
+        let head: u16 = mem.get(slot).ok()?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        let mut buffers = Vec::new();
+        let mut index = head;
+        // A chain can't be longer than the table, that stops loops
+        for _ in 0..self.size {
+            let desc = self.desc.checked_add(16 * (index % self.size) as uguest).filter(|desc| desc.checked_add(16).is_some())?;
+            let (addr, len, flags, next) = (mem.get::<u64>(desc).ok()?, mem.get::<u32>(desc + 8).ok()?, mem.get::<u16>(desc + 12).ok()?, mem.get::<u16>(desc + 14).ok()?);
+            buffers.push(Buffer { addr, len, writable: flags & DESC_F_WRITE != 0 });
+            if flags & DESC_F_NEXT == 0 {break}
+            index = next;
+        }
+        Some(Chain { head, buffers })
+    }
+    /// Gives the chain starting at `head` back to the driver, `written` bytes were written to it
+    pub fn push(&mut self, mem: &mut Memory, head: u16, written: u32) {
+        if self.size == 0 {return}
+        let entry = self.device.checked_add(4 + 8 * (self.used % self.size) as uguest).filter(|entry| entry.checked_add(8).is_some());
+        if let Some(entry) = entry {
+            let _ = mem.set(entry, head as u32);
+            let _ = mem.set(entry + 4, written);
+        }
+        self.used = self.used.wrapping_add(1);
+        if let Some(idx) = self.device.checked_add(2) {
+            let _ = mem.set(idx, self.used);
+        }
+    }
+}
+
+/// What a snapshot of the VM keeps of a `Virtio`, with the device's own state
+pub struct VirtioSnapshot {
+    queues: Vec<Queue>,
+    device_features_select: u32,
+    driver_features_select: u32,
+    driver_features: u64,
+    status: u8,
+    queue_select: u16,
+    interrupt_status: u32,
+    config_generation: u32,
+    notified: Vec<usize>,
+    device: Option<Box<dyn std::any::Any>>,
+}
+
+/// What all transports keep for a device
+pub struct Virtio {
+    pub device: Box<dyn VirtioDevice>,
+    pub queues: Vec<Queue>,
+    pub device_features_select: u32,
+    pub driver_features_select: u32,
+    /// Features the driver accepted, only offered ones are kept
+    pub driver_features: u64,
+    pub status: u8,
+    pub queue_select: u16,
+    /// ISR status, see `INTERRUPT_USED_BUFFER` and `INTERRUPT_CONFIG_CHANGE`
+    pub interrupt_status: u32,
+    pub config_generation: u32,
+    /// Queues notified and not processed yet
+    notified: Vec<usize>,
+}
+impl Virtio {
+    pub fn new(device: impl VirtioDevice) -> Self {
+        let queues = device.queue_sizes().into_iter().map(Queue::new).collect();
+        Self {
+            device: Box::new(device), queues, device_features_select: 0, driver_features_select: 0, driver_features: 0,
+            status: 0, queue_select: 0, interrupt_status: 0, config_generation: 0, notified: Vec::new(),
+        }
+    }
+    pub fn device_features(&self) -> u64 {
+        self.device.features() | F_VERSION_1
+    }
+    /// 32 bits of the offered features, the select register picks which
+    pub fn device_features_word(&self) -> u32 {
+        match self.device_features_select {
+            0 => self.device_features() as u32,
+            1 => (self.device_features() >> 32) as u32,
+            _ => 0,
+        }
+    }
+    pub fn driver_features_word(&self) -> u32 {
+        match self.driver_features_select {
+            0 => self.driver_features as u32,
+            1 => (self.driver_features >> 32) as u32,
+            _ => 0,
+        }
+    }
+    pub fn set_driver_features_word(&mut self, value: u32) {
+        let shift = match self.driver_features_select {
+            0 => 0,
+            1 => 32,
+            _ => return,
+        };
+        let features = (self.driver_features & !(0xFFFF_FFFF << shift)) | (value as u64) << shift;
+        self.driver_features = features & self.device_features();
+        self.device.set_features(self.driver_features);
+    }
+    /// Writing 0 resets the device
+    pub fn set_status(&mut self, status: u8) {
+        if status == 0 {return self.reset()}
+        self.status = status;
+    }
+    pub fn reset(&mut self) {
+        self.queues = self.device.queue_sizes().into_iter().map(Queue::new).collect();
+        (self.device_features_select, self.driver_features_select, self.driver_features) = (0, 0, 0);
+        (self.status, self.queue_select, self.interrupt_status) = (0, 0, 0);
+        self.notified.clear();
+        self.device.reset();
+    }
+    pub fn snapshot(&mut self) -> color_eyre::Result<VirtioSnapshot> {
+        Ok(VirtioSnapshot {
+            queues: self.queues.clone(), device_features_select: self.device_features_select,
+            driver_features_select: self.driver_features_select, driver_features: self.driver_features, status: self.status,
+            queue_select: self.queue_select, interrupt_status: self.interrupt_status,
+            config_generation: self.config_generation, notified: self.notified.clone(), device: self.device.snapshot()?,
+        })
+    }
+    pub fn restore(&mut self, snapshot: &VirtioSnapshot) -> color_eyre::Result<()> {
+        if let Some(state) = &snapshot.device {self.device.restore(state.as_ref())?}
+        self.queues.clone_from(&snapshot.queues);
+        (self.device_features_select, self.driver_features_select) = (snapshot.device_features_select, snapshot.driver_features_select);
+        (self.driver_features, self.status, self.queue_select) = (snapshot.driver_features, snapshot.status, snapshot.queue_select);
+        (self.interrupt_status, self.config_generation) = (snapshot.interrupt_status, snapshot.config_generation);
+        self.notified.clone_from(&snapshot.notified);
+        self.device.set_features(self.driver_features);
+        Ok(())
+    }
+    pub fn driver_ok(&self) -> bool {
+        self.status & STATUS_DRIVER_OK != 0 && self.status & STATUS_FAILED == 0
+    }
+    /// The queue the queue registers are about
+    pub fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+    pub fn notify(&mut self, queue: usize) {
+        if queue < self.queues.len() && !self.notified.contains(&queue) {
+            self.notified.push(queue);
+        }
+    }
+    pub fn read_config(&self, offset: uguest, buffer: &mut [u8]) {
+        let config = self.device.config();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+    pub fn write_config(&mut self, offset: uguest, data: &[u8]) {
+        self.device.write_config(offset as usize, data);
+    }
+    pub fn tick(&mut self) {
+        self.device.tick();
+        if self.device.take_config_change() {
+            self.config_generation = self.config_generation.wrapping_add(1);
+            if self.driver_ok() {self.interrupt_status |= INTERRUPT_CONFIG_CHANGE}
+        }
+    }
+    pub fn dma_pending(&self) -> bool {
+        self.driver_ok() && (!self.notified.is_empty() || self.device.pending())
+    }
+    /// Processes the notified queues and what the host side sent
+    pub fn dma(&mut self, mem: &mut Memory) {
+        if !self.driver_ok() {return}
+        let mut used = false;
+        for queue in std::mem::take(&mut self.notified) {
+            used |= self.device.process(queue, &mut self.queues, mem);
+        }
+        if self.device.pending() {
+            used |= self.device.poll(&mut self.queues, mem);
+        }
+        if used {self.interrupt_status |= INTERRUPT_USED_BUFFER}
+    }
+    pub fn device_as<T: VirtioDevice>(&self) -> Option<&T> {
+        let device: &dyn std::any::Any = self.device.as_ref();
+        device.downcast_ref()
+    }
+    pub fn device_as_mut<T: VirtioDevice>(&mut self) -> Option<&mut T> {
+        let device: &mut dyn std::any::Any = self.device.as_mut();
+        device.downcast_mut()
+    }
+}
@@ -0,0 +1,229 @@
+// virtio-net (5.1): a receive and a transmit queue, the MAC and the link status in the configuration space
+// Frames go to and come from a `net::Backend`, and are captured when there is a `net::Pcap`
+// Each buffer starts with a virtio_net_hdr, 10 bytes for legacy drivers and 12 with VIRTIO_F_VERSION_1 (num_buffers),
+// no offload is offered so it is all zeros but num_buffers, a frame always fits in one receive chain
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use color_eyre::Result;
+
+use super::{Queue, VirtioDevice, F_VERSION_1};
+use crate::mem::Memory;
+use crate::net::{self, Backend, Mac};
+
+pub const NET_ID: u32 = 1;
+/// The configuration has the MAC, and the link status
+pub const F_MAC: u64 = 1 << 5;
+pub const F_STATUS: u64 = 1 << 16;
+/// Bit of the status field
+pub const S_LINK_UP: u16 = 1;
+
+pub const RECEIVE: usize = 0;
+pub const TRANSMIT: usize = 1;
+const QUEUE_SIZE: u16 = 256;
+/// Frames from the host side waiting for receive buffers, later ones are dropped
+const BACKLOG: usize = 256;
+/// Ticks between two looks at a host side that may get frames on its own (the hub)
+const POLL_TICKS: u64 = 1024;
+
+/// Host side of `--net`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendKind {
+    Loopback,
+    Echo,
+    Hub(PathBuf),
+}
+/// A NIC of `--net`: loopback, echo or hub=PATH, then mac=aa:bb:cc:dd:ee:ff, link=on|off and pcap=PATH
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetConfig {
+    pub backend: BackendKind,
+    pub mac: Mac,
+    pub link: bool,
+    pub pcap: Option<PathBuf>,
+}
+impl std::str::FromStr for NetConfig {
+    type Err = String;
+    fn from_str(option: &str) -> Result<Self, String> {
+        let mut fields = option.split(',');
+        let kind = fields.next().unwrap_or_default();
+        let backend = match kind.split_once('=') {
+            None if kind == "loopback" => BackendKind::Loopback,
+            None if kind == "echo" => BackendKind::Echo,
+            Some(("hub", path)) => BackendKind::Hub(path.into()),
+            _ => return Err(format!("Unknown network {option:?}, it is loopback, echo or hub=PATH")),
+        };
+        let mut config = Self { backend, mac: net::DEFAULT_MAC, link: true, pcap: None };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("mac", mac)) => config.mac = net::parse_mac(mac)?,
+                Some(("link", "on")) => config.link = true,
+                Some(("link", "off")) => config.link = false,
+                Some(("pcap", path)) => config.pcap = Some(path.into()),
+                _ => return Err(format!("Unknown network field {field:?}, they are mac, link=on|off and pcap")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+pub struct VirtioNet {
+    mac: Mac,
+    link: bool,
+    backend: Box<dyn Backend>,
+    pcap: Option<net::Pcap>,
+    /// Frames for the guest
+    received: VecDeque<Vec<u8>>,
+    /// The receive queue had no buffer, waiting for the driver to add some
+    starved: bool,
+    features: u64,
+    config_changed: bool,
+    ticks: u64,
+}
+impl VirtioNet {
+    pub fn new(mac: Mac, backend: impl Backend + 'static) -> Self {
+        Self {
+            mac, link: true, backend: Box::new(backend), pcap: None, received: VecDeque::new(), starved: false,
+            features: 0, config_changed: false, ticks: 0,
+        }
+    }
+    /// The NIC `config` describes, connected to its host side
+    pub fn open(config: &NetConfig) -> Result<Self> {
+        let mut nic = match &config.backend {
+            BackendKind::Loopback => Self::new(config.mac, net::Loopback::default()),
+            BackendKind::Echo => Self::new(config.mac, net::Echo::default()),
+            BackendKind::Hub(path) => Self::new(config.mac, net::Hub::open(path)?),
+        };
+        nic.link = config.link;
+        if let Some(path) = &config.pcap {
+            nic.pcap = Some(net::Pcap::create(path)?);
+        }
+        Ok(nic)
+    }
+    pub fn with_pcap(mut self, pcap: net::Pcap) -> Self {
+        self.pcap = Some(pcap);
+        self
+    }
+    pub fn mac(&self) -> Mac {
+        self.mac
+    }
+    pub fn link(&self) -> bool {
+        self.link
+    }
+    /// Plugs or unplugs the cable, the driver gets a configuration change interrupt
+    pub fn set_link(&mut self, up: bool) {
+        if self.link != up {
+            self.link = up;
+            self.config_changed = true;
+        }
+    }
+    fn header_len(&self) -> usize {
+        if self.features & F_VERSION_1 != 0 {12} else {10}
+    }
+    /// Takes what the host side has for the guest, dropped while the link is down
+    fn fetch(&mut self) {
+        while let Some(frame) = self.backend.receive() {
+            if !self.link {continue}
+            if self.received.len() >= BACKLOG {
+                log::warn!("Dropping a received frame, the guest doesn't take them");
+                continue
+            }
+            self.received.push_back(frame);
+        }
+    }
+    fn transmit(&mut self, queue: &mut Queue, mem: &mut Memory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem) {
+            let data = chain.read(mem);
+            if let (true, Some(frame)) = (self.link, data.get(self.header_len()..)) {
+                if let Some(pcap) = &mut self.pcap {pcap.capture(frame)}
+                self.backend.send(frame);
+            }
+            queue.push(mem, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+    fn deliver(&mut self, queue: &mut Queue, mem: &mut Memory) -> bool {
+        let mut used = false;
+        while let Some(len) = self.received.front().map(Vec::len) {
+            let Some(chain) = queue.pop(mem) else {
+                self.starved = true;
+                break
+            };
+            let frame = self.received.pop_front().unwrap();
+            let mut data = vec![0; self.header_len()];
+            if self.header_len() == 12 {data[10] = 1}
+            data.extend(&frame);
+            if chain.writable_len() < data.len() {
+                log::warn!("Dropping a {len} bytes frame, the receive buffer is {} bytes", chain.writable_len());
+                queue.push(mem, chain.head, 0);
+            } else {
+                if let Some(pcap) = &mut self.pcap {pcap.capture(&frame)}
+                let written = chain.write(mem, &data);
+                queue.push(mem, chain.head, written);
+            }
+            used = true;
+        }
+        used
+    }
+}
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        NET_ID
+    }
+    fn features(&self) -> u64 {
+        F_MAC | F_STATUS
+    }
+    fn set_features(&mut self, features: u64) {
+        self.features = features;
+    }
+    fn queue_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; 2]
+    }
+    /// mac and status
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend((if self.link {S_LINK_UP} else {0}).to_le_bytes());
+        config
+    }
+    /// Legacy drivers set the MAC by writing it
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            if let Some(mac) = self.mac.get_mut(offset + i) {*mac = *byte}
+        }
+    }
+    fn process(&mut self, queue: usize, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        match queue {
+            TRANSMIT => {
+                let used = self.transmit(&mut queues[TRANSMIT], mem);
+                // Loopback and echo answer right away
+                self.fetch();
+                used | self.deliver(&mut queues[RECEIVE], mem)
+            },
+            RECEIVE => {
+                self.starved = false;
+                self.deliver(&mut queues[RECEIVE], mem)
+            },
+            _ => false,
+        }
+    }
+    fn pending(&self) -> bool {
+        self.link && !self.starved && !self.received.is_empty()
+    }
+    fn poll(&mut self, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        self.deliver(&mut queues[RECEIVE], mem)
+    }
+    fn take_config_change(&mut self) -> bool {
+        std::mem::take(&mut self.config_changed)
+    }
+    fn reset(&mut self) {
+        (self.features, self.starved) = (0, false);
+        self.received.clear();
+    }
+    fn tick(&mut self) {
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(POLL_TICKS) {
+            self.fetch();
+        }
+    }
+}
@@ -0,0 +1,243 @@
+// virtio-pci modern transport (4.1): a function whose 64 bits memory BAR holds the common configuration, the ISR status,
+// the notification area and the device configuration, each found through a vendor-specific capability.
+// There is no MSI-X, interrupts go through INTx (pin A) and the ISR status
+use super::{Virtio, VirtioDevice};
+use crate::mem::Memory;
+use crate::pci::{PciConfig, PciFunction};
+use crate::uguest;
+
+/// PCI vendor of virtio devices, device IDs are 0x1040 + the virtio device ID
+pub const VENDOR: u16 = 0x1AF4;
+const DEVICE_BASE: u16 = 0x1040;
+/// Subsystem ID QEMU uses
+const SUBSYSTEM: u16 = 0x1100;
+/// The BAR of the structures, like QEMU's modern BAR
+pub const BAR: usize = 4;
+const BAR_SIZE: uguest = 0x4000;
+/// Where each structure is in the BAR
+pub const COMMON: uguest = 0x0000;
+pub const ISR: uguest = 0x1000;
+pub const DEVICE: uguest = 0x2000;
+pub const NOTIFY: uguest = 0x3000;
+/// Queue `n` is notified at NOTIFY + n * multiplier
+pub const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+const COMMON_SIZE: usize = 0x38;
+
+/// cfg_type of the capabilities
+pub const CAP_COMMON_CFG: u8 = 1;
+pub const CAP_NOTIFY_CFG: u8 = 2;
+pub const CAP_ISR_CFG: u8 = 3;
+pub const CAP_DEVICE_CFG: u8 = 4;
+pub const CAP_PCI_CFG: u8 = 5;
+/// Capability ID of vendor-specific capabilities
+const CAP_VENDOR: u8 = 0x09;
+
+// Common configuration fields (struct virtio_pci_common_cfg), by offset and size
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0C;
+const CONFIG_MSIX_VECTOR: usize = 0x10;
+const NUM_QUEUES: usize = 0x12;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1A;
+const QUEUE_ENABLE: usize = 0x1C;
+const QUEUE_NOTIFY_OFF: usize = 0x1E;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+const FIELDS: [(usize, usize); 16] = [
+    (DEVICE_FEATURE_SELECT, 4), (DEVICE_FEATURE, 4), (DRIVER_FEATURE_SELECT, 4), (DRIVER_FEATURE, 4),
+    (CONFIG_MSIX_VECTOR, 2), (NUM_QUEUES, 2), (DEVICE_STATUS, 1), (CONFIG_GENERATION, 1), (QUEUE_SELECT, 2),
+    (QUEUE_SIZE, 2), (QUEUE_MSIX_VECTOR, 2), (QUEUE_ENABLE, 2), (QUEUE_NOTIFY_OFF, 2),
+    (QUEUE_DESC, 8), (QUEUE_DRIVER, 8), (QUEUE_DEVICE, 8),
+];
+/// VIRTIO_MSI_NO_VECTOR
+const NO_VECTOR: u16 = 0xFFFF;
+
+/// PCI class of each device type, like QEMU
+fn class(device_id: u32) -> u32 {
+    match device_id {
+        1 => 0x02_00_00,
+        2 => 0x01_00_00,
+        3 => 0x07_80_00,
+        16 => 0x03_80_00,
+        18 => 0x09_80_00,
+        _ => 0xFF_00_00,
+    }
+}
+
+/// A virtio device behind the modern PCI transport
+pub struct VirtioPci {
+    config: PciConfig,
+    pub virtio: Virtio,
+    /// Offset of the VIRTIO_PCI_CAP_PCI_CFG capability, an access window into the BAR from the configuration space
+    cfg_access: usize,
+}
+impl VirtioPci {
+    pub fn new(device: impl VirtioDevice) -> Self {
+        let virtio = Virtio::new(device);
+        let id = virtio.device.device_id();
+        let mut config = PciConfig::new(VENDOR, DEVICE_BASE + id as u16, class(id), 1);
+        config.set_subsystem(VENDOR, SUBSYSTEM);
+        config.set_interrupt_pin(1);
+        config.add_memory_bar(BAR, BAR_SIZE, true);
+        let capability = |cfg_type: u8, offset: uguest, length: usize, extra: &[u8]| {
+            // cap_len, cfg_type, bar, id, padding, offset and length
+            let mut body = vec![16 + extra.len() as u8, cfg_type, BAR as u8, 0, 0, 0];
+            body.extend((offset as u32).to_le_bytes());
+            body.extend((length as u32).to_le_bytes());
+            body.extend(extra);
+            body
+        };
+        let queues = virtio.queues.len().max(1);
+        config.add_capability(CAP_VENDOR, &capability(CAP_COMMON_CFG, COMMON, COMMON_SIZE, &[]));
+        config.add_capability(CAP_VENDOR, &capability(CAP_NOTIFY_CFG, NOTIFY, queues * NOTIFY_OFF_MULTIPLIER as usize, &NOTIFY_OFF_MULTIPLIER.to_le_bytes()));
+        config.add_capability(CAP_VENDOR, &capability(CAP_ISR_CFG, ISR, 1, &[]));
+        let device_config = virtio.device.config().len();
+        if device_config != 0 {
+            config.add_capability(CAP_VENDOR, &capability(CAP_DEVICE_CFG, DEVICE, device_config, &[]));
+        }
+        // bar, offset, length and data can be written
+        let cfg_access = config.add_capability(CAP_VENDOR, &capability(CAP_PCI_CFG, 0, 0, &[0; 4]));
+        config.set_writable(cfg_access + 4, &[0xFF]);
+        config.set_writable(cfg_access + 8, &[0xFF; 12]);
+        Self { config, virtio, cfg_access }
+    }
+    pub fn device<T: VirtioDevice>(&self) -> Option<&T> {
+        self.virtio.device_as()
+    }
+    pub fn device_mut<T: VirtioDevice>(&mut self) -> Option<&mut T> {
+        self.virtio.device_as_mut()
+    }
+
+    /// struct virtio_pci_common_cfg as it reads now
+    fn common(&mut self) -> [u8; COMMON_SIZE] {
+        let mut common = [0; COMMON_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| common[offset..offset + bytes.len()].copy_from_slice(bytes);
+        let virtio = &mut self.virtio;
+        put(DEVICE_FEATURE_SELECT, &virtio.device_features_select.to_le_bytes());
+        put(DEVICE_FEATURE, &virtio.device_features_word().to_le_bytes());
+        put(DRIVER_FEATURE_SELECT, &virtio.driver_features_select.to_le_bytes());
+        put(DRIVER_FEATURE, &virtio.driver_features_word().to_le_bytes());
+        put(CONFIG_MSIX_VECTOR, &NO_VECTOR.to_le_bytes());
+        put(NUM_QUEUES, &(virtio.queues.len() as u16).to_le_bytes());
+        put(DEVICE_STATUS, &[virtio.status]);
+        put(CONFIG_GENERATION, &[virtio.config_generation as u8]);
+        put(QUEUE_SELECT, &virtio.queue_select.to_le_bytes());
+        put(QUEUE_MSIX_VECTOR, &NO_VECTOR.to_le_bytes());
+        put(QUEUE_NOTIFY_OFF, &virtio.queue_select.to_le_bytes());
+        // Queues that don't exist read 0
+        if let Some(queue) = virtio.selected_queue().copied() {
+            put(QUEUE_SIZE, &queue.size.to_le_bytes());
+            put(QUEUE_ENABLE, &(queue.ready as u16).to_le_bytes());
+            put(QUEUE_DESC, &queue.desc.to_le_bytes());
+            put(QUEUE_DRIVER, &queue.driver.to_le_bytes());
+            put(QUEUE_DEVICE, &queue.device.to_le_bytes());
+        }
+        common
+    }
+    /// Writes the fields `data` touches, in order
+    fn write_common(&mut self, offset: usize, data: &[u8]) {
+        let mut common = self.common();
+        let end = (offset + data.len()).min(COMMON_SIZE);
+        if offset >= end {return}
+        common[offset..end].copy_from_slice(&data[..end - offset]);
+        for (field, size) in FIELDS.into_iter().filter(|(field, size)| *field < end && offset < field + size) {
+            let mut bytes = [0; 8];
+            bytes[..size].copy_from_slice(&common[field..field + size]);
+            let value = u64::from_le_bytes(bytes);
+            let virtio = &mut self.virtio;
+            match field {
+                DEVICE_FEATURE_SELECT => virtio.device_features_select = value as u32,
+                DRIVER_FEATURE_SELECT => virtio.driver_features_select = value as u32,
+                DRIVER_FEATURE => virtio.set_driver_features_word(value as u32),
+                DEVICE_STATUS => virtio.set_status(value as u8),
+                QUEUE_SELECT => virtio.queue_select = value as u16,
+                _ => {
+                    // Queues can only be set up while disabled
+                    let Some(queue) = virtio.selected_queue().filter(|queue| !queue.ready) else {continue};
+                    match field {
+                        QUEUE_SIZE if (1..=queue.max_size as u64).contains(&value) => queue.size = value as u16,
+                        QUEUE_ENABLE => queue.ready = value == 1,
+                        QUEUE_DESC => queue.desc = value,
+                        QUEUE_DRIVER => queue.driver = value,
+                        QUEUE_DEVICE => queue.device = value,
+                        _ => {},
+                    }
+                },
+            }
+        }
+    }
+    /// BAR and offset of the window of the VIRTIO_PCI_CAP_PCI_CFG capability
+    fn cfg_access_window(&self) -> (usize, uguest, usize) {
+        let cap = self.cfg_access;
+        (self.config.u32(cap + 4) as usize & 0xFF, self.config.u32(cap + 8) as uguest, (self.config.u32(cap + 12) as usize).min(4))
+    }
+}
+impl PciFunction for VirtioPci {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+    /// pci_cfg_data reads the BAR at the window
+    fn read_config(&mut self, offset: usize, buffer: &mut [u8]) {
+        let data = self.cfg_access + 16;
+        if offset == data {
+            let (bar, bar_offset, length) = self.cfg_access_window();
+            let mut window = [0; 4];
+            self.read_bar(bar, bar_offset, &mut window[..length]);
+            self.config.set_u32(data, u32::from_le_bytes(window));
+        }
+        self.config.read(offset, buffer)
+    }
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        self.config.write(offset, data);
+        if offset == self.cfg_access + 16 {
+            let (bar, bar_offset, length) = self.cfg_access_window();
+            self.write_bar(bar, bar_offset, &data[..length.min(data.len())]);
+        }
+    }
+    fn read_bar(&mut self, bar: usize, offset: uguest, buffer: &mut [u8]) {
+        buffer.fill(0);
+        if bar != BAR {return}
+        match offset {
+            COMMON..ISR => {
+                let common = self.common();
+                for (i, byte) in buffer.iter_mut().enumerate() {
+                    *byte = common.get(offset as usize + i).copied().unwrap_or(0);
+                }
+            },
+            // Reading the ISR status acknowledges the interrupt
+            ISR => buffer[0] = std::mem::take(&mut self.virtio.interrupt_status) as u8,
+            DEVICE..NOTIFY => self.virtio.read_config(offset - DEVICE, buffer),
+            _ => {},
+        }
+    }
+    fn write_bar(&mut self, bar: usize, offset: uguest, data: &[u8]) {
+        if bar != BAR {return}
+        match offset {
+            COMMON..ISR => self.write_common(offset as usize, data),
+            DEVICE..NOTIFY => self.virtio.write_config(offset - DEVICE, data),
+            NOTIFY.. => self.virtio.notify(((offset - NOTIFY) / NOTIFY_OFF_MULTIPLIER as uguest) as usize),
+            _ => {},
+        }
+    }
+    fn interrupt_pending(&self) -> bool {
+        self.virtio.interrupt_status != 0
+    }
+    fn tick(&mut self) {
+        self.virtio.tick()
+    }
+    fn dma_pending(&self) -> bool {
+        self.virtio.dma_pending()
+    }
+    fn dma(&mut self, bus: &mut Memory) {
+        self.virtio.dma(bus)
+    }
+}
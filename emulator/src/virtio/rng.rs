@@ -0,0 +1,120 @@
+// virtio-rng (5.4): one queue, the driver makes buffers available and the device fills them with random bytes
+// They come from a seeded SplitMix64, so that runs are reproducible, or from the host's /dev/urandom
+use std::fs::File;
+use std::io::Read;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+
+use super::{Queue, VirtioDevice};
+use crate::mem::Memory;
+
+pub const RNG_ID: u32 = 4;
+const QUEUE_SIZE: u16 = 64;
+/// Bytes given for one buffer at most, Linux asks for a few dozens
+const MAX_REQUEST: usize = 64 * 1024;
+/// Seed of `--rng` without one
+pub const DEFAULT_SEED: u64 = 0;
+
+/// Where the bytes come from, `--rng seed=N` or `--rng host`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngConfig {
+    Seeded(u64),
+    Host,
+}
+impl Default for RngConfig {
+    fn default() -> Self {
+        Self::Seeded(DEFAULT_SEED)
+    }
+}
+impl std::str::FromStr for RngConfig {
+    type Err = String;
+    fn from_str(option: &str) -> Result<Self, String> {
+        match option.split_once('=') {
+            None if option.is_empty() => Ok(Self::default()),
+            None if option == "host" => Ok(Self::Host),
+            Some(("seed", seed)) => {
+                let seed = match seed.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => seed.parse(),
+                };
+                seed.map(Self::Seeded).map_err(|err| format!("Invalid seed: {err}"))
+            },
+            _ => Err(format!("Unknown entropy source {option:?}, it is seed=N or host")),
+        }
+    }
+}
+
+/// SplitMix64, every seed (0 too) gives a full period sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitMix64(u64);
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    pub fn fill(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let word = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+    }
+}
+
+enum Source {
+    Seeded(SplitMix64),
+    Host(File),
+}
+
+pub struct VirtioRng {
+    source: Source,
+    /// Bytes handed to the driver
+    pub generated: u64,
+}
+impl VirtioRng {
+    pub fn seeded(seed: u64) -> Self {
+        Self { source: Source::Seeded(SplitMix64::new(seed)), generated: 0 }
+    }
+    pub fn open(config: RngConfig) -> Result<Self> {
+        let source = match config {
+            RngConfig::Seeded(seed) => Source::Seeded(SplitMix64::new(seed)),
+            RngConfig::Host => Source::Host(File::open("/dev/urandom").context("Can't open /dev/urandom")?),
+        };
+        Ok(Self { source, generated: 0 })
+    }
+    fn fill(&mut self, buffer: &mut [u8]) {
+        match &mut self.source {
+            Source::Seeded(prng) => prng.fill(buffer),
+            Source::Host(file) => if let Err(err) = file.read_exact(buffer) {
+                log::warn!("Can't read /dev/urandom: {err}");
+                buffer.fill(0);
+            },
+        }
+    }
+}
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        RNG_ID
+    }
+    fn queue_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+    fn process(&mut self, queue: usize, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(mem) {
+            let mut data = vec![0; chain.writable_len().min(MAX_REQUEST)];
+            self.fill(&mut data);
+            let written = chain.write(mem, &data);
+            self.generated += written as u64;
+            queues[queue].push(mem, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
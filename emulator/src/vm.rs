@@ -1,78 +1,233 @@
 // We can now access cpu registers using zero, s0, a0 etc... See cpu/reg.rs 
 use crate::cpu::reg::Reg;
-use color_eyre::eyre::{Context, ContextCompat};
+use color_eyre::eyre::{bail, Context, ContextCompat};
 use color_eyre::Result;
-use cpu::instructions::Instruction16;
+use cpu::instructions::Instruction32;
+use cpu::trap::Exception;
 
 use crate::*;
-use crate::cpu::instructions::Instruction;
 
 pub struct VM {
     pub mem: mem::Memory,
     pub cpu: crate::cpu::CPU,
+    /// Description the bus was built from, the device tree is generated from it
+    pub board: board::Board,
+    /// Empty for raw binaries
+    pub symbols: loader::Symbols,
+    pub profiler: Option<profiler::Profiler>,
+    pub semihosting: Option<semihosting::Semihosting>,
+    /// Firmware handling the ecalls of S-mode, see `VM::boot_supervisor`
+    pub sbi: Option<sbi::Sbi>,
+    /// System calls of a Linux program, see `VM::new_linux_user`
+    pub linux: Option<linux::Linux>,
+    /// mtime of all harts
+    pub clock: clock::Clock,
+    /// Commands of `--monitor`, see `monitor::Monitor`
+    pub monitor: Option<monitor::Monitor>,
+    /// Snapshots the monitor's savevm took, by name
+    pub snapshots: std::collections::BTreeMap<String, snapshot::Snapshot>,
+    /// Prints every instruction executed
+    pub trace: bool,
+    pub hooks: machine::Hooks,
 }
 impl VM {
-    pub fn new(program: Vec<u8>) -> Self {
+    /// Loads an ELF file at its physical addresses and starts at its entry point,
+    /// anything else is a raw binary copied at the start of RAM
+    pub fn new(program: Vec<u8>, ram_size: uguest) -> Result<Self> {
+        Self::with_board(program, board::Board::virt().with_ram_size(ram_size))
+    }
+    /// Same as `new` on the machine `board` describes, which can only have one hart (see `machine::Machine` for more)
+    pub fn with_board(program: Vec<u8>, board: board::Board) -> Result<Self> {
         crate::cpu::raw_instructions::set_instructions_funcs();
-        Self {
-            mem: mem::Memory::with_program(program),
-            cpu: crate::cpu::CPU::default(),
-        }
+        if board.harts != 1 {bail!("A VM runs a single hart, {} were asked for", board.harts)}
+        let mut mem = board.bus()?;
+        let mut cpu = board.hart(0)?;
+        let symbols = if loader::is_elf(&program) {
+            let image = loader::load_elf(&mut mem, &program)?;
+            cpu.pc = image.entry;
+            image.symbols
+        } else {
+            mem.write(board.ram_base(), &program).context("Program doesn't fit in RAM")?;
+            loader::Symbols::default()
+        };
+        let mut vm = Self::from_parts(mem, cpu, symbols);
+        vm.board = board;
+        Ok(vm)
+    }
+    /// A VM with no firmware nor callbacks, that traces the instructions it executes
+    /// It is described as QEMU virt, set `board` to what `mem` really is before booting it
+    pub fn from_parts(mem: mem::Memory, cpu: cpu::CPU, symbols: loader::Symbols) -> Self {
+        Self { mem, cpu, board: Default::default(), symbols, profiler: None, semihosting: None, sbi: None, linux: None, clock: Default::default(), monitor: None, snapshots: Default::default(), trace: true, hooks: Default::default() }
     }
     
-    pub fn run(&mut self) -> color_eyre::Result<()> {
-        loop {
-            print!("{:x}", self.cpu.pc);
-            // Fetch
-            let raw_instruction = self.mem.get::<u32>(self.cpu.pc).context("Out of bounds")?;
-            if raw_instruction == 0 {
-                println!("Didn't enter in loop !");
-                return Ok(()) // Don't pollute stdout, for now
+    /// Guest memory accesses, translated (see `cpu::mmu`) and checked against PMP before reaching the bus
+    pub fn load<T: Copy>(&mut self, addr: uguest) -> Result<T, cpu::mmu::Fault> {
+        let val = self.load_as(addr, mem::AccessType::Read, self.cpu.data_mode())?;
+        self.hooks.memory_access(&self.cpu, addr, mem::AccessType::Read, val);
+        Ok(val)
+    }
+    /// Load with the permission checks of a store, for AMOs which write the value back
+    pub fn load_writable<T: Copy>(&mut self, addr: uguest) -> Result<T, cpu::mmu::Fault> {
+        let val = self.load_as(addr, mem::AccessType::Write, self.cpu.data_mode())?;
+        self.hooks.memory_access(&self.cpu, addr, mem::AccessType::Read, val);
+        Ok(val)
+    }
+    pub fn store<T: Copy>(&mut self, addr: uguest, val: T) -> Result<(), cpu::mmu::Fault> {
+        self.store_as(addr, val, self.cpu.data_mode())?;
+        self.hooks.memory_access(&self.cpu, addr, mem::AccessType::Write, val);
+        Ok(())
+    }
+    pub fn fetch<T: Copy>(&mut self, addr: uguest) -> Result<T, cpu::mmu::Fault> {
+        self.load_as(addr, mem::AccessType::Execute, self.cpu.fetch_mode())
+    }
+
+    /// Fetches the instruction at pc, compressed ones are expanded to the 32 bits instruction they stand for
+    /// Returns the instruction and its size, or the exception and tval to raise
+    pub fn fetch_instruction(&mut self) -> Result<(Instruction32, uguest), (Exception, uguest)> {
+        let pc = self.cpu.pc;
+        let fault = |fault: cpu::mmu::Fault| (fault.exception(), fault.addr);
+        // 16 bits at a time, a 32 bits instruction can cross into a page we can't access
+        let low = self.fetch::<u16>(pc).map_err(fault)?;
+        let misa = self.cpu.csr_value(cpu::csr::file::MISA);
+        let (raw, size) = if low & 0b11 == 0b11 {
+            let high = self.fetch::<u16>(pc.wrapping_add(2)).map_err(fault)?;
+            (low as u32 | (high as u32) << 16, 4)
+        } else if misa & cpu::isa::bit('c') == 0 {
+            return Err((Exception::IllegalInstruction, low as uguest))
+        } else {
+            let expanded = cpu::compressed::expand(low).ok_or((Exception::IllegalInstruction, low as uguest))?;
+            (expanded, 2)
+        };
+        match cpu::instructions::Instruction32::new(raw) {
+            Ok(instruction) if cpu::isa::enabled(misa, self.cpu.extensions, instruction) => Ok((instruction, size)),
+            Ok(_) => Err((Exception::IllegalInstruction, raw as uguest)),
+            // Report the original bits for compressed instructions
+            Err(_) => Err((Exception::IllegalInstruction, if size == 2 {low as uguest} else {raw as uguest})),
+        }
+    }
+
+    /// Takes the pending interrupt if there is one and executes a single instruction, returns false when the program stopped
+    /// (fetched a zero instruction without a trap handler to go to, or exited through semihosting or SBI)
+    pub fn step(&mut self) -> color_eyre::Result<bool> {
+        if !self.execute()? {return Ok(false)}
+        self.tick_devices();
+        Ok(self.exit_status().is_none())
+    }
+    /// The hart's part of `step`, without ticking the devices, returns false when the program stopped there
+    /// `Machine::step` runs it on every hart, then ticks the devices once
+    pub(crate) fn execute(&mut self) -> color_eyre::Result<bool> {
+        self.cpu.guest_fault = None;
+        self.update_timers();
+        if let Some(sbi) = &mut self.sbi {
+            sbi.tick(&mut self.cpu);
+        }
+        if self.cpu.waiting {
+            // Whether interrupts are globally enabled or not
+            if self.cpu.csr_value(cpu::csr::file::MIP) & self.cpu.csr_value(cpu::csr::file::MIE) == 0 {
+                return Ok(true)
             }
-            let instructions = Instruction::new(raw_instruction).context("The program didn't enter in a end-loop ! This would've led to UB")?;
-            if let Some(fst) = instructions.1 {
-                let snd = match instructions.0 {
-                    Instruction::Base(_) => unreachable!(),
-                    Instruction::Compressed(c) => c,
-                };
-                todo!();
-                // Execute
-                // println!(" - {}", fst);
-                // let (_name, _fmt, _mask, fun) = crate::cpu::instructions::find_instruction16_desc(fst);
-                // fun(self, fst);
-                // self.cpu.pc += core::mem::size_of::<Instruction16>() as uguest;
-                // *self.cpu.reg(Reg::zero) = 0; // Currently we need to set it manually
-                // println!(" - {}", snd);
-                // let (_name, _fmt, _mask, fun) = crate::cpu::instructions::find_instruction16_desc(snd);
-                // fun(self, snd);
-                self.cpu.pc += core::mem::size_of::<Instruction16>() as uguest;
-            } else {
-                let instruction = match instructions.0 {
-                    Instruction::Base(b) => b,
-                    Instruction::Compressed(_) => unreachable!(),
-                };
+            self.cpu.waiting = false;
+        }
+        if let Some(irq) = self.cpu.pending_interrupt() {
+            self.cpu.interrupt(irq);
+            self.cpu.pc = self.cpu.next_pc;
+            self.hooks.trapped(&self.cpu);
+        }
+        if self.trace {print!("{:x}", self.cpu.pc)}
+        self.cpu.trapped = false;
+        match self.fetch_instruction() {
+            Ok((instruction, size)) => {
                 // Execute
-                println!(" - {}", instruction);
+                if self.trace {println!(" - {}", cpu::disasm::disassemble(instruction, Some(self.cpu.pc)))}
                 let (_name, _fmt, _mask, fun) = crate::cpu::raw_instructions::find_instruction32_desc(instruction);
+                self.cpu.next_pc = self.cpu.pc.wrapping_add(size);
                 fun(self, instruction);
-                self.cpu.pc += core::mem::size_of::<u32>() as uguest;
-            }
-            *self.cpu.reg(Reg::zero) = 0; // Currently we need to set it manually
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.record(self.cpu.pc, instruction.0, size, self.cpu.next_pc, self.cpu.trapped);
+                }
+                if !self.cpu.trapped {self.hooks.retired(&self.cpu, instruction.0, size)}
+            },
+            Err((Exception::IllegalInstruction, 0)) if self.linux.is_none() && self.cpu.csr_value(cpu::csr::file::MTVEC) == 0 => {
+                if self.trace {println!("Didn't enter in loop !")}
+                return Ok(false) // Don't pollute stdout, for now
+            },
+            Err((cause, tval)) => {
+                if self.trace {println!(" - {cause:?}")}
+                self.cpu.exception(cause, tval);
+            },
+        }
+        if self.cpu.trapped {self.hooks.trapped(&self.cpu)}
+        // Linux programs can't handle their traps, they get killed
+        if self.cpu.trapped && self.linux.is_some() {
+            self.linux_fault();
+            return Ok(false)
+        }
+        self.cpu.pc = self.cpu.next_pc;
+        if !self.cpu.trapped {
+            self.cpu.instret = self.cpu.instret.wrapping_add(1);
+            self.clock.retired();
+        }
+        *self.cpu.reg(Reg::zero) = 0; // Currently we need to set it manually
+        Ok(true)
+    }
+    /// End of a step, also done when the hart waits: devices tick and their interrupts are routed
+    /// A single hart waiting for a timer gets to its deadline right away, see `Machine::step` for several
+    fn tick_devices(&mut self) {
+        self.mem.tick();
+        self.hooks.devices_ticked(&self.mem);
+        self.end_step();
+        if let (1, Some(deadline)) = (self.board.harts, self.idle_deadline()) {
+            self.fast_forward(deadline);
+        }
+    }
+    /// The end of a step for the current hart, once the devices ticked: its interrupts and timers are updated
+    pub(crate) fn end_step(&mut self) {
+        self.cpu.cycle = self.cpu.cycle.wrapping_add(1);
+        self.route_plic_interrupts();
+        self.route_interrupts();
+        self.update_timers();
+    }
+    /// Exit status the guest requested through semihosting, SBI or a Linux system call
+    pub fn exit_status(&self) -> Option<i32> {
+        self.semihosting.as_ref().and_then(|semihosting| semihosting.exit_status)
+            .or_else(|| self.sbi.as_ref()?.exit_status())
+            .or_else(|| self.linux.as_ref()?.exit_status)
+    }
+
+    pub fn run(&mut self) -> color_eyre::Result<()> {
+        self.run_for(None)
+    }
+    /// Runs until the program stops or `max_instructions` have been retired
+    pub fn run_for(&mut self, max_instructions: Option<u64>) -> color_eyre::Result<()> {
+        let start = self.cpu.instret;
+        while max_instructions.is_none_or(|max| self.cpu.instret - start < max) && self.step()? {
+            if self.cpu.cycle.is_multiple_of(monitor::PERIOD) && !self.poll_monitor() {break}
             #[cfg(debug_assertions)]
-            std::thread::sleep(std::time::Duration::from_millis(100));
+            if self.trace {std::thread::sleep(std::time::Duration::from_millis(100))}
         }
-        dbg!(&self.cpu);
         Ok(())
     }
-    // pub fn disasm(&mut self, program: Vec<u8>) -> color_eyre::Result<()> {
-    //     for i in (0..program.len()).step_by(core::mem::size_of::<Instruction>()) {
-    //         // Fetch
-    //         let instruction = Instruction::new(self.mem.get::<_>(self.cpu.pc).unwrap()).context("The program didn't enter in a end-loop ! This would've led to UB")?;
-    //         // Execute
-    //         println!("{}\t - ", instruction);
-    //     }
-    //     Ok(())
-    // }
+}
+
+/// Text of the instructions of a raw binary loaded at 0, one per line
+/// Compressed instructions are written as the instruction they expand to
+pub fn disasm(program: Vec<u8>) -> color_eyre::Result<String> {
+    use std::fmt::Write;
+    let mut text = String::new();
+    let mut offset = 0;
+    while offset+2 <= program.len() {
+        let low = u16::from_le_bytes([program[offset], program[offset+1]]);
+        let (raw, size) = if low & 0b11 != 0b11 {
+            (cpu::compressed::expand(low).with_context(|| format!("Invalid compressed instruction {low:#06x} at {offset:#x}"))?, 2)
+        } else {
+            let bytes = program.get(offset..offset+4).with_context(|| format!("Truncated instruction at {offset:#x}"))?;
+            (u32::from_le_bytes(bytes.try_into().unwrap()), 4)
+        };
+        let instruction = cpu::instructions::Instruction32::new(raw).with_context(|| format!("Invalid instruction {raw:#010x} at {offset:#x}"))?;
+        writeln!(text, "{}", cpu::disasm::disassemble(instruction, Some(offset as uguest)))?;
+        offset += size;
+    }
+    Ok(text)
 }
 impl std::fmt::Debug for VM {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -103,6 +258,7 @@ impl std::fmt::Display for VM {
 pub static mut MAIN_VM: Option<VM> = None;
 
 #[cfg(debug_assertions)]
+#[allow(static_mut_refs)]
 /// Sets up a pretty print of the vm state when panicking
 fn setup_dbg_vm(vm: VM) -> &'static mut VM {
     unsafe {
@@ -116,16 +272,88 @@ fn setup_dbg_vm(vm: VM) -> &'static mut VM {
     }
 }
 
-pub fn run(program: Vec<u8>) -> Result<()> {
-    let mut vm = VM::new(program);
+impl VM {
+    /// Gives the RTC its clock, the flash banks their files, fw_cfg the command line, initrd and files asked for,
+    /// and plugs the network cards and the display into virtio-mmio transports
+    pub fn configure_devices(&mut self, args: &args::RunArgs) -> Result<()> {
+        let banks = self.board.devices.iter().filter(|device| device.kind == board::DeviceKind::Flash);
+        for (bank, path) in banks.zip(&args.pflash) {
+            *self.mem.device_mut::<pflash::Pflash>(bank.base).context("No flash on the bus")? = pflash::Pflash::open(path, bank.size())?;
+        }
+        if let (Some(clock), Some(rtc)) = (args.rtc_clock, self.board.device(board::DeviceKind::Rtc)) {
+            self.mem.device_mut::<rtc::GoldfishRtc>(rtc.base).context("No RTC on the bus")?.set_clock(clock);
+        }
+        for drive in &args.drive {
+            self.add_virtio_device(virtio::block::VirtioBlk::new(block::Disk::open_drive(drive)?))?;
+        }
+        for net in &args.net {
+            self.add_virtio_device(virtio::net::VirtioNet::open(net)?)?;
+        }
+        if let Some(gpu) = &args.gpu {
+            self.add_virtio_device(virtio::gpu::VirtioGpu::open(gpu)?)?;
+        }
+        if let Some(rng) = args.rng {
+            self.add_virtio_device(virtio::rng::VirtioRng::open(rng)?)?;
+        }
+        if !args.console_port.is_empty() {
+            self.add_virtio_device(virtio::console::VirtioConsole::open(&args.console_port)?)?;
+        }
+        for input in &args.input {
+            self.add_virtio_device(virtio::input::VirtioInput::open(input)?)?;
+        }
+        let Some(device) = self.board.device(board::DeviceKind::FwCfg) else {return Ok(())};
+        let fw_cfg = self.mem.device_mut::<fw_cfg::FwCfg>(device.base).context("No fw_cfg on the bus")?;
+        fw_cfg.set_cmdline(&args.append);
+        if let Some(initrd) = &args.initrd {
+            fw_cfg.set_initrd(std::fs::read(initrd).with_context(|| format!("Can't read the initrd {}", initrd.display()))?);
+        }
+        for file in &args.fw_cfg {
+            fw_cfg.add_file(&file.name, file.data.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the exit status requested by the guest, if it did
+pub fn run(program: Vec<u8>, args: &args::RunArgs) -> Result<Option<i32>> {
+    let mut vm = VM::with_board(program, args.board()?)?;
+    vm.clock = clock::Clock::new(args.clock);
+    vm.configure_devices(args)?;
+    if args.bios == args::Bios::Sbi {
+        vm.boot_supervisor(sbi::Sbi::default(), &args.append)?;
+    }
+    run_vm(vm, args)
+}
+/// Runs a static Linux program, `argv[0]` is its name
+pub fn run_user(program: &[u8], argv: &[String], envp: &[String], args: &args::RunArgs) -> Result<Option<i32>> {
+    let vm = VM::new_linux_user(program, argv, envp, linux::Linux::default())?;
+    run_vm(vm, args)
+}
+fn run_vm(mut vm: VM, args: &args::RunArgs) -> Result<Option<i32>> {
+    if args.semihosting {
+        vm.semihosting = Some(semihosting::Semihosting::new(&args.semihosting_root, args.semihosting_cmdline.clone().unwrap_or_default()));
+    }
+    if let Some(path) = &args.monitor {
+        vm.monitor = Some(monitor::Monitor::open(path)?);
+    }
+    if args.profile.is_some() {
+        if vm.symbols.is_empty() {
+            log::warn!("No symbols in the kernel, the profile will only show addresses");
+        }
+        vm.profiler = Some(profiler::Profiler::new(vm.cpu.pc, args.profile_period));
+    }
     let vm = setup_dbg_vm(vm);
-    match vm.run() {
-        Ok(_) => Ok(()),
-        Err(err) => {
-            dbg!(vm);
-            Err(err)
-        },
+    let result = vm.run_for(args.max_instructions);
+    if let Err(err) = &result {
+        dbg!(&vm);
+    }
+    vm.close_drives()?;
+    if let (Some(profiler), Some(path)) = (&vm.profiler, &args.profile) {
+        let file = std::fs::File::create(path).with_context(|| format!("Can't create profile {}", path.display()))?;
+        profiler.write_folded(&vm.symbols, std::io::BufWriter::new(file))?;
+        profiler.write_table(&vm.symbols, std::io::stdout().lock())?;
     }
+    result.map(|_| vm.exit_status())
 }
 
 // let (s1, has_s2) = match instruction.s1() {
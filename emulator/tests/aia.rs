@@ -0,0 +1,221 @@
+mod common;
+
+use common::{builder, causes, BASE};
+use emulator::board::{Aia, Board, DeviceKind};
+use emulator::cpu::csr::file::*;
+use emulator::cpu::reg::Reg;
+use emulator::machine::Machine;
+use emulator::mem::{MemMap, MemoryMap, MemoryRegion};
+
+const MEIP: u64 = 1 << 11;
+const SEIP: u64 = 1 << 9;
+/// Where the test device is mapped, its line is source 1
+const DEVICE: u64 = 0x1000_1000;
+
+// APLIC registers
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+const SOURCECFG1: u64 = 0x4;
+const MMSIADDRCFG: u64 = 0x1BC0;
+const SETIENUM: u64 = 0x1EDC;
+const TARGET1: u64 = 0x3004;
+const IDELIVERY: u64 = 0x4000;
+const TOPI: u64 = 0x4018;
+const CLAIMI: u64 = 0x401C;
+
+/// Raises its interrupt line after a few ticks
+struct Countdown(u32);
+impl MemoryRegion for Countdown {
+    fn read(&mut self, _offset: u64) -> u8 {
+        self.0 as u8
+    }
+    fn write(&mut self, _offset: u64, val: u8) {
+        self.0 = val as u32;
+    }
+    fn tick(&mut self) {
+        self.0 = self.0.saturating_sub(1);
+    }
+    fn interrupt_pending(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// virt with the controllers of `aia`, and a device raising source 1 after 3 instructions
+fn machine(aia: Aia, source: &str) -> Machine {
+    let board = Board::virt().with_aia(aia).unwrap();
+    builder(board, source)
+        .device(DEVICE, 0x100, Some(1), Countdown(3)).build().unwrap()
+}
+fn write_aplic(machine: &mut Machine, base: u64, offset: u64, value: u32) {
+    machine.write(base + offset, value).unwrap();
+}
+
+#[test]
+pub fn board_options() {
+    let board = Board::load("virt,aia=aplic-imsic").unwrap();
+    assert_eq!(board.isa, "rv64imac_zicntr_zicsr_zifencei_smaia_ssaia");
+    let kinds: Vec<_> = board.devices.iter().map(|device| (device.kind, device.base)).collect();
+    assert!(kinds.contains(&(DeviceKind::Aplic, MemMap::AplicS.base())));
+    assert!(kinds.contains(&(DeviceKind::Imsic, MemMap::ImsicM.base())));
+    // Direct mode has no IMSIC, so no AIA CSRs
+    let aplic = Board::load("virt,aia=aplic").unwrap();
+    assert!(aplic.devices.iter().all(|device| device.kind != DeviceKind::Imsic));
+    assert_eq!(aplic.isa, Board::virt().isa);
+    assert_eq!(Board::load("virt,aia=none").unwrap(), Board::virt());
+    assert!(Board::load("virt,aia=plic").is_err());
+    assert!(Board::load("virt,acpi=off").is_err());
+
+    let dtb = Machine::builder().board(board).build().unwrap().device_tree("");
+    let contains = |needle: &[u8]| dtb.windows(needle.len()).any(|window| window == needle);
+    for needle in [&b"riscv,aplic\0"[..], b"riscv,imsics\0", b"msi-parent\0", b"riscv,delegation\0", b"interrupt-parent\0"] {
+        assert!(contains(needle), "{}", String::from_utf8_lossy(needle));
+    }
+}
+
+#[test]
+pub fn interrupt_file() {
+    let mut imsic = machine(Aia::AplicImsic, "
+        nop
+        csrrw a0, mtopei, zero
+        csrrs a1, mireg, zero
+    ");
+    let traps = causes(&mut imsic);
+    imsic.set_csr(0, MISELECT, 0x70);
+    imsic.set_csr(0, MIREG, 1);
+    imsic.set_csr(0, MISELECT, 0xC0);
+    imsic.set_csr(0, MIREG, 1 << 5 | 1);
+    assert_eq!(imsic.csr(0, MIREG), 1 << 5, "identity 0 doesn't exist");
+    // An MSI to the M-level page of hart 0, only delivered once the hart steps
+    imsic.write(MemMap::ImsicM.base(), 5u32).unwrap();
+    assert_eq!(imsic.csr(0, MIP) & MEIP, 0);
+    imsic.run(Some(1)).unwrap();
+    assert_eq!(imsic.csr(0, MIP) & MEIP, MEIP);
+    assert_eq!(imsic.csr(0, MTOPEI), 5 << 16 | 5);
+    // The threshold masks identities at or above it
+    imsic.set_csr(0, MISELECT, 0x72);
+    imsic.set_csr(0, MIREG, 5);
+    assert_eq!(imsic.csr(0, MTOPEI), 0);
+    imsic.set_csr(0, MIREG, 0);
+    // Claimed through the csrrw
+    imsic.run(Some(1)).unwrap();
+    assert_eq!(imsic.reg(0, Reg::a0), 5 << 16 | 5);
+    assert_eq!(imsic.csr(0, MIP) & MEIP, 0);
+    // Odd eie registers are RV32 only
+    imsic.set_csr(0, MISELECT, 0xC1);
+    imsic.run(Some(1)).unwrap();
+    assert_eq!(*traps.borrow(), [2]);
+    // The S-level file is apart
+    imsic.set_csr(0, SISELECT, 0x80);
+    assert_eq!(imsic.csr(0, SIREG), 0);
+
+    // Without an IMSIC, the CSRs don't exist
+    let mut direct = machine(Aia::Aplic, "csrrs a0, mtopei, zero");
+    let direct_causes = causes(&mut direct);
+    direct.run(Some(1)).unwrap();
+    assert_eq!(*direct_causes.borrow(), [2]);
+}
+
+#[test]
+pub fn direct_delivery() {
+    let mut machine = machine(Aia::Aplic, "loop: j loop");
+    let root = MemMap::AplicM.base();
+    write_aplic(&mut machine, root, 0, DOMAINCFG_IE);
+    // Level high, priority 3
+    write_aplic(&mut machine, root, SOURCECFG1, 6);
+    write_aplic(&mut machine, root, TARGET1, 3);
+    write_aplic(&mut machine, root, SETIENUM, 1);
+    write_aplic(&mut machine, root, IDELIVERY, 1);
+    machine.run(Some(2)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MEIP, 0);
+    machine.run(Some(2)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MEIP, MEIP);
+    assert_eq!(machine.read::<u32>(root + TOPI).unwrap(), 1 << 16 | 3);
+    // Level sensitive sources stay pending while the line is high
+    assert_eq!(machine.read::<u32>(root + CLAIMI).unwrap(), 1 << 16 | 3);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MEIP, MEIP);
+    machine.write(DEVICE, 100u8).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MEIP, 0);
+
+    // Delegated to the S-level domain, the root doesn't see the source anymore
+    let child = MemMap::AplicS.base();
+    write_aplic(&mut machine, root, SOURCECFG1, 1 << 10);
+    assert_eq!(machine.read::<u32>(root + SOURCECFG1).unwrap(), 1 << 10);
+    machine.run(Some(1)).unwrap();
+    write_aplic(&mut machine, child, 0, DOMAINCFG_IE);
+    write_aplic(&mut machine, child, SOURCECFG1, 4);
+    write_aplic(&mut machine, child, TARGET1, 1);
+    write_aplic(&mut machine, child, SETIENUM, 1);
+    write_aplic(&mut machine, child, IDELIVERY, 1);
+    machine.write(DEVICE, 1u8).unwrap();
+    machine.run(Some(2)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & (MEIP | SEIP), SEIP);
+    // Edge sensitive sources are claimed
+    assert_eq!(machine.read::<u32>(child + CLAIMI).unwrap(), 1 << 16 | 1);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & SEIP, 0);
+}
+
+#[test]
+pub fn msi_delivery() {
+    let mut machine = machine(Aia::AplicImsic, "loop: j loop");
+    let traps = causes(&mut machine);
+    let root = MemMap::AplicM.base();
+    write_aplic(&mut machine, root, 0, DOMAINCFG_IE | DOMAINCFG_DM);
+    write_aplic(&mut machine, root, MMSIADDRCFG, (MemMap::ImsicM.base() >> 12) as u32);
+    // Rising edge, identity 7 of hart 0
+    write_aplic(&mut machine, root, SOURCECFG1, 4);
+    write_aplic(&mut machine, root, TARGET1, 7);
+    write_aplic(&mut machine, root, SETIENUM, 1);
+    machine.set_csr(0, MISELECT, 0x70);
+    machine.set_csr(0, MIREG, 1);
+    machine.set_csr(0, MISELECT, 0xC0);
+    machine.set_csr(0, MIREG, 1 << 7);
+    // The handler is the same loop, mstatus.MIE stays cleared
+    machine.set_csr(0, MTVEC, BASE);
+    machine.set_csr(0, MIE, MEIP);
+    machine.set_csr(0, MSTATUS, 1 << 3);
+    machine.run(Some(10)).unwrap();
+    assert_eq!(*traps.borrow(), [1 << 63 | 11]);
+    assert_eq!(machine.csr(0, MTOPEI), 7 << 16 | 7);
+    machine.set_csr(0, MTOPEI, 0);
+    assert_eq!(machine.csr(0, MIP) & MEIP, 0);
+    // The line stays high, there is no new edge
+    machine.run(Some(5)).unwrap();
+    assert_eq!(machine.csr(0, MTOPEI), 0);
+}
+
+#[test]
+pub fn plic() {
+    let mut machine = machine(Aia::None, "loop: j loop");
+    let plic = MemMap::PLIC.base();
+    // Context 1 is the S-mode one of hart 0
+    let (enable, threshold, claim) = (plic + 0x2080, plic + 0x20_1000, plic + 0x20_1004);
+    machine.write(plic, 7u32).unwrap();
+    assert_eq!(machine.read::<u32>(plic).unwrap(), 0, "source 0 doesn't exist");
+    machine.write(plic + 4, 0xFu32).unwrap();
+    assert_eq!(machine.read::<u32>(plic + 4).unwrap(), 7);
+    machine.write(enable, 0b11u32).unwrap();
+    assert_eq!(machine.read::<u32>(enable).unwrap(), 0b10);
+    machine.run(Some(4)).unwrap();
+    assert_eq!(machine.read::<u32>(plic + 0x1000).unwrap(), 0b10);
+    assert_eq!(machine.csr(0, MIP) & (MEIP | SEIP), SEIP);
+    // Only priorities above the threshold interrupt
+    machine.write(threshold, 7u32).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & SEIP, 0);
+    assert_eq!(machine.read::<u32>(claim).unwrap(), 0);
+    machine.write(threshold, 6u32).unwrap();
+    assert_eq!(machine.read::<u32>(claim).unwrap(), 1);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & SEIP, 0);
+    assert_eq!(machine.read::<u32>(plic + 0x1000).unwrap(), 0);
+    // Once completed, the source is pending again until the line drops
+    machine.write(claim, 1u32).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & SEIP, SEIP);
+    machine.write(DEVICE, 100u8).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & SEIP, 0);
+}
@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use emulator::asm::assemble;
+use emulator::cpu::csr::file::*;
+use emulator::cpu::isa::{self, Extensions};
+use emulator::cpu::reg::Reg;
+use emulator::machine::{Machine, Trap};
+use emulator::vm::disasm;
+
+const BASE: u64 = 0x8000_0000;
+const ZB: &str = "rv64imac_zba_zbb_zbc_zbs";
+/// Encodings from llvm-mc
+const ENCODINGS: [(&str, u32); 16] = [
+    ("sh2add.uw a0 a1 a2", 0x20c5c53b),
+    ("slli.uw a0 a1 35", 0x0a35951b),
+    ("andn a0 a1 a2", 0x40c5f533),
+    ("clzw a0 a1", 0x6005951b),
+    ("cpop a0 a1", 0x60259513),
+    ("sext.h a0 a1", 0x60559513),
+    ("zext.h a0 a1", 0x0805c53b),
+    ("rori a0 a1 45", 0x62d5d513),
+    ("roriw a0 a1 13", 0x60d5d51b),
+    ("orc.b a0 a1", 0x2875d513),
+    ("rev8 a0 a1", 0x6b85d513),
+    ("clmulr a0 a1 a2", 0x0ac5a533),
+    ("bexti a0 a1 33", 0x4a15d513),
+    ("bseti a0 a1 40", 0x2a859513),
+    ("amoadd.d.aqrl a0 a2 (a1)", 0x06c5b52f),
+    ("csrrsi a0 mie 5", 0x3042e573),
+];
+
+fn words(program: &[u8]) -> Vec<u32> {
+    program.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect()
+}
+fn machine(isa: &str, source: &str) -> Machine {
+    let program = assemble(source, BASE).unwrap();
+    Machine::builder().isa(isa).ram_size(1 << 20).image(BASE, program).build().unwrap()
+}
+
+#[test]
+pub fn encodings() {
+    for (text, encoding) in ENCODINGS {
+        let program = assemble(text, 0).unwrap();
+        assert_eq!(words(&program), [encoding], "{text}");
+        assert_eq!(disasm(program).unwrap(), format!("{text}\n"));
+    }
+    // Commas, labels, comments and pseudo-instructions
+    let program = assemble("start: li a0, -1 # all ones\n  not a1, a0\nloop:\n  beq a0, a1, start\n  j loop\n  ret", 0x100).unwrap();
+    // disasm puts the program at 0
+    assert_eq!(disasm(program).unwrap(), "addi a0 zero -1\nxori a1 a0 -1\nbeq a0 a1 0x0\nj 0x8\njalr zero 0(ra)\n");
+    for invalid in ["clz a0", "addi a0 a1 4096", "slli a0 a1 64", "slliw a0 a1 32", "bseti a0 a1 x", "frob a0", "j nowhere", "lr.d a0 8(a1)"] {
+        assert!(assemble(invalid, 0).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+pub fn execution() {
+    let mut machine = machine(ZB, "
+        li a0, -2
+        li a1, 3
+        sh3add.uw s0, a0, a1
+        slli.uw s1, a0, 4
+        clz s2, a1
+        ctzw s3, a0
+        cpop s4, a0
+        orc.b s5, a1
+        rev8 s6, a1
+        sext.b s7, a0
+        rolw s8, a0, a1
+        min s9, a0, a1
+        minu s10, a0, a1
+        xnor s11, a0, a1
+        clmul t0, a1, a1
+        clmulh t1, a0, a1
+        bset t2, zero, a1
+        binvi t3, a0, 0
+        bext t4, a0, a1
+        bclri t5, a0, 63
+    ");
+    machine.run(Some(20)).unwrap();
+    let results = [
+        (Reg::s0, 0x7_FFFF_FFF3), (Reg::s1, 0xF_FFFF_FFE0), (Reg::s2, 62), (Reg::s3, 1), (Reg::s4, 63),
+        (Reg::s5, 0xFF), (Reg::s6, 3 << 56), (Reg::s7, u64::MAX - 1), (Reg::s8, u64::MAX - 8), (Reg::s9, u64::MAX - 1),
+        (Reg::s10, 3), (Reg::s11, 2), (Reg::t0, 5), (Reg::t1, 1), (Reg::t2, 8), (Reg::t3, u64::MAX), (Reg::t4, 1),
+        (Reg::t5, u64::MAX >> 1 & !1),
+    ];
+    for (reg, value) in results {
+        assert_eq!(machine.reg(0, reg), value, "{reg:?}");
+    }
+}
+
+#[test]
+pub fn selection() {
+    let program = "sh1add a0, a1, a2\nclz a0, a1\nclmul a0, a1, a2\nbset a0, a1, a2\n";
+    // Index of the instructions that trapped
+    let illegal = |isa: &str| {
+        let mut machine = machine(isa, program);
+        let traps = Rc::new(RefCell::new(Vec::new()));
+        let recorded = traps.clone();
+        machine.on_trap(move |trap: &Trap| recorded.borrow_mut().push(((trap.epc - BASE) / 4, trap.cause)));
+        for i in 0..4 {
+            machine.set_pc(0, BASE + i * 4);
+            machine.run(Some(1)).unwrap();
+        }
+        let traps = traps.borrow().clone();
+        assert!(traps.iter().all(|(_, cause)| *cause == 2));
+        traps.into_iter().map(|(index, _)| index).collect::<Vec<_>>()
+    };
+    assert_eq!(illegal(ZB), []);
+    assert_eq!(illegal("rv64imac"), [0, 1, 2, 3]);
+    assert_eq!(illegal("rv64imac_zbb_zbc"), [0, 3]);
+    assert_eq!(illegal("rv64imacb"), [2]);
+
+    let b = isa::parse_isa("rv64imacb").unwrap();
+    assert_eq!(b, isa::parse_isa("rv64imac_zba_zbb_zbs").unwrap());
+    assert_eq!(b.extensions.names().collect::<Vec<_>>(), ["zba", "zbb", "zbs"]);
+    assert_ne!(b.misa & isa::bit('b'), 0);
+    let zbc = isa::parse_isa("rv64imac_zicsr_zbc").unwrap();
+    assert_eq!((zbc.misa, zbc.extensions), (MISA_VALUE, Extensions::named("zbc").unwrap()));
+}
+
+#[test]
+pub fn device_tree() {
+    let machine = machine(ZB, "nop");
+    assert_eq!(machine.csr(0, MISA), isa::parse("rv64imacb").unwrap());
+    let dtb = machine.device_tree("");
+    let needle = b"rv64imacb_zicntr_zicsr_zifencei_zba_zbb_zbc_zbs\0";
+    assert!(dtb.windows(needle.len()).any(|window| window == needle));
+    assert!(dtb.windows(4).any(|window| window == b"zbc\0"));
+}
@@ -0,0 +1,228 @@
+use std::path::PathBuf;
+
+mod common;
+
+use common::{descriptor, driver_init, make_available, notify, used, virtio_machine, BASE, DESC_NEXT, DESC_WRITE, TRANSPORT};
+use emulator::block::*;
+use emulator::machine::Machine;
+use emulator::monitor::Command;
+use emulator::virtio::block::*;
+use emulator::virtio::mmio::*;
+
+const QUEUE_PAGE: u64 = BASE + 0x1_0000;
+const HEADER_BUFFER: u64 = BASE + 0x3_0000;
+const DATA_BUFFER: u64 = BASE + 0x3_1000;
+const STATUS_BUFFER: u64 = BASE + 0x3_2000;
+/// Not a whole number of sectors, like the kernel's disk.hdd
+const IMAGE_LEN: usize = 1300;
+
+/// A fresh image in the temporary directory, bytes are their offset
+fn image(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("block-{name}-{}.img", std::process::id()));
+    std::fs::write(&path, (0..IMAGE_LEN).map(|i| i as u8).collect::<Vec<u8>>()).unwrap();
+    path
+}
+fn original() -> Vec<u8> {
+    (0..IMAGE_LEN).map(|i| i as u8).collect()
+}
+fn read(disk: &mut Disk, sector: u64, len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    disk.read(sector, &mut data).unwrap();
+    data
+}
+
+fn machine(disk: Disk) -> Machine {
+    let mut machine = virtio_machine(VirtioBlk::new(disk));
+    driver_init(&mut machine, 0, &[(0, QUEUE_PAGE)]);
+    machine
+}
+/// Sends a request of `kind` for `sector` with `data` to write, or `len` bytes to read, returns the status and what was read
+fn request(machine: &mut Machine, kind: u32, sector: u64, data: &[u8], len: u32) -> (u8, Vec<u8>) {
+    let header = [kind.to_le_bytes().as_slice(), &[0; 4], &sector.to_le_bytes()].concat();
+    machine.write_bytes(HEADER_BUFFER, &header).unwrap();
+    machine.write_bytes(DATA_BUFFER, data).unwrap();
+    machine.write(STATUS_BUFFER, 0xFFu8).unwrap();
+    descriptor(machine, QUEUE_PAGE, 0, HEADER_BUFFER, HEADER as u32, DESC_NEXT);
+    let mut next = 1;
+    if !data.is_empty() {
+        descriptor(machine, QUEUE_PAGE, next, DATA_BUFFER, data.len() as u32, DESC_NEXT);
+        next += 1;
+    }
+    if len != 0 {
+        descriptor(machine, QUEUE_PAGE, next, DATA_BUFFER, len, DESC_WRITE | DESC_NEXT);
+        next += 1;
+    }
+    descriptor(machine, QUEUE_PAGE, next, STATUS_BUFFER, 1, DESC_WRITE);
+    let count = used(machine, QUEUE_PAGE).len();
+    make_available(machine, QUEUE_PAGE, 0);
+    notify(machine, 0);
+    let used = used(machine, QUEUE_PAGE);
+    assert_eq!(used.len(), count + 1);
+    assert_eq!(used[count].1, len + 1);
+    let mut read = vec![0; len as usize];
+    machine.read_bytes(DATA_BUFFER, &mut read).unwrap();
+    (machine.read(STATUS_BUFFER).unwrap(), read)
+}
+fn drive(machine: &mut Machine) -> &mut Disk {
+    machine.vm.virtio_device_mut::<VirtioBlk>(0).unwrap().disk_mut()
+}
+
+#[test]
+pub fn raw() {
+    let path = image("raw");
+    let mut disk = Disk::open(&path, false).unwrap();
+    assert_eq!(disk.sectors(), 3);
+    // Past the end of the file is zeros
+    let last = read(&mut disk, 2, 512);
+    assert_eq!((&last[..IMAGE_LEN - 1024], &last[IMAGE_LEN - 1024..]), (&original()[1024..], [0; 1536 - IMAGE_LEN].as_slice()));
+    disk.write(1, &[0xAA; 512]).unwrap();
+    assert!(disk.write(3, &[0; 512]).is_err(), "past the end");
+    let mut expected = original();
+    expected[512..1024].fill(0xAA);
+    assert_eq!(std::fs::read(&path).unwrap(), expected, "written in place");
+    disk.close().unwrap();
+
+    let mut disk = Disk::open(&path, true).unwrap();
+    assert!(disk.write(0, &[0; 512]).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+pub fn memory_overlay() {
+    let path = image("memory");
+    let mut disk = Disk::with_overlay(&path, Overlay::memory()).unwrap();
+    disk.write(0, &[0x55; 1024]).unwrap();
+    // A partial sector keeps the rest of its content
+    disk.write(2, &[0x66; 10]).unwrap();
+    assert_eq!(disk.overlay().unwrap().len(), 3);
+    assert_eq!(read(&mut disk, 0, 1024), [0x55; 1024]);
+    assert_eq!(read(&mut disk, 2, 20), [[0x66; 10].as_slice(), &original()[1034..1044]].concat());
+    assert_eq!(std::fs::read(&path).unwrap(), original(), "the image isn't modified");
+
+    assert_eq!(disk.discard().unwrap(), 3);
+    assert_eq!(read(&mut disk, 0, 1024), original()[..1024]);
+
+    disk.write(2, &[0x77; 512]).unwrap();
+    assert_eq!(disk.commit().unwrap(), 1);
+    assert!(disk.overlay().unwrap().is_empty());
+    let mut expected = original();
+    expected[1024..].fill(0x77);
+    assert_eq!(std::fs::read(&path).unwrap(), expected, "committed without growing the image");
+    assert_eq!(read(&mut disk, 2, 4), [0x77; 4]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+pub fn file_overlay() {
+    let path = image("file");
+    let overlay = path.with_extension("overlay");
+    let config: DriveConfig = format!("file={},overlay={},on-exit=commit", path.display(), overlay.display()).parse().unwrap();
+    assert!(config.snapshot);
+    let mut disk = Disk::open_drive(&config).unwrap();
+    disk.write(1, &[0x11; 512]).unwrap();
+    // Only the written sector has data in the overlay
+    assert_eq!(std::fs::metadata(&overlay).unwrap().len(), 1024);
+    assert_eq!(read(&mut disk, 1, 512), [0x11; 512]);
+    assert_eq!(read(&mut disk, 0, 512), original()[..512]);
+
+    // An existing file isn't taken as an overlay, it would be lost
+    assert!(Disk::open_drive(&config).is_err());
+    assert_eq!(std::fs::metadata(&overlay).unwrap().len(), 1024);
+
+    disk.close().unwrap();
+    assert!(!overlay.exists(), "the overlay is removed");
+    let mut expected = original();
+    expected[512..1024].fill(0x11);
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+pub fn drive_config() {
+    let config: DriveConfig = "file=disk.hdd".parse().unwrap();
+    assert_eq!(config, DriveConfig { file: "disk.hdd".into(), ..Default::default() });
+    let config: DriveConfig = "file=disk.hdd,snapshot=on,readonly=off".parse().unwrap();
+    assert_eq!((config.snapshot, config.overlay, config.on_exit, config.read_only), (true, None, OnExit::Discard, false));
+    assert!("snapshot=on".parse::<DriveConfig>().is_err());
+    assert!("file=disk.hdd,snapshot=yes".parse::<DriveConfig>().is_err());
+    assert!("file=disk.hdd,on-exit=keep".parse::<DriveConfig>().is_err());
+    assert!("file=disk.hdd,format=raw".parse::<DriveConfig>().is_err());
+}
+
+#[test]
+pub fn virtio_blk() {
+    let path = image("virtio");
+    let mut machine = machine(Disk::with_overlay(&path, Overlay::memory()).unwrap());
+    assert_eq!(machine.read::<u32>(TRANSPORT + DEVICE_ID).unwrap(), BLOCK_ID);
+    assert_eq!(machine.read::<u32>(TRANSPORT + HOST_FEATURES).unwrap() as u64, F_FLUSH);
+    // capacity
+    assert_eq!(machine.read::<u64>(TRANSPORT + CONFIG).unwrap(), 3);
+
+    assert_eq!(request(&mut machine, T_IN, 1, &[], 512), (S_OK, original()[512..1024].to_vec()));
+    assert_eq!(request(&mut machine, T_OUT, 0, &[0x99; 512], 0).0, S_OK);
+    assert_eq!(request(&mut machine, T_IN, 0, &[], 8), (S_OK, vec![0x99; 8]));
+    assert_eq!(request(&mut machine, T_FLUSH, 0, &[], 0).0, S_OK);
+    assert_eq!(request(&mut machine, T_GET_ID, 0, &[], ID_LEN as u32).1[..6], *b"block-");
+    assert_eq!(request(&mut machine, T_IN, 2, &[], 1024).0, S_IOERR, "past the end");
+    assert_eq!(request(&mut machine, T_OUT, 3, &[0; 512], 0).0, S_IOERR);
+    assert_eq!(request(&mut machine, 11, 0, &[], 0).0, S_UNSUPP);
+    assert_eq!(std::fs::read(&path).unwrap(), original());
+
+    let commit: Command = "commit drive0".parse().unwrap();
+    assert_eq!(commit, Command::Commit(0));
+    assert_eq!(commit.execute(&mut machine.vm).unwrap(), format!("1 sectors written to {}", path.display()));
+    assert!("commit drive1".parse::<Command>().unwrap().execute(&mut machine.vm).is_err());
+    assert!("commit hd0".parse::<Command>().is_err());
+    assert_eq!(std::fs::read(&path).unwrap()[..512], [0x99; 512]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+pub fn snapshots() {
+    let path = image("snapshots");
+    let overlay = path.with_extension("overlay");
+    let config: DriveConfig = format!("file={},overlay={}", path.display(), overlay.display()).parse().unwrap();
+    let mut machine = machine(Disk::open_drive(&config).unwrap());
+    let execute = |machine: &mut Machine, command: &str| command.parse::<Command>().unwrap().execute(&mut machine.vm);
+    assert_eq!(request(&mut machine, T_OUT, 0, &[0x99; 512], 0).0, S_OK);
+    assert_eq!("savevm before".parse(), Ok(Command::SaveVm("before".into())));
+    execute(&mut machine, "savevm before").unwrap();
+    let pc = machine.vm.cpu.pc;
+
+    assert_eq!(request(&mut machine, T_OUT, 0, &[0x11; 1024], 0).0, S_OK);
+    machine.write(BASE + 0x5_0000, 42u64).unwrap();
+    machine.run(Some(10)).unwrap();
+    execute(&mut machine, "loadvm before").unwrap();
+    // The drive, RAM and the hart are back, the driver goes on where it was
+    assert_eq!(drive(&mut machine).overlay().unwrap().len(), 1);
+    assert_eq!(machine.read::<u64>(BASE + 0x5_0000).unwrap(), 0);
+    assert_eq!(machine.vm.cpu.pc, pc);
+    assert_eq!(request(&mut machine, T_IN, 0, &[], 1024), (S_OK, [[0x99; 512].as_slice(), &original()[512..1024]].concat()));
+    // A snapshot can be restored again
+    execute(&mut machine, "loadvm before").unwrap();
+    assert_eq!(request(&mut machine, T_IN, 0, &[], 4), (S_OK, vec![0x99; 4]));
+
+    execute(&mut machine, "delvm before").unwrap();
+    assert!(execute(&mut machine, "loadvm before").is_err());
+    assert!(execute(&mut machine, "delvm before").is_err());
+    drive(&mut machine).close().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), original());
+
+    // The writes of a drive without an overlay can't be undone
+    let mut in_place = self::machine(Disk::open(&path, false).unwrap());
+    assert!(execute(&mut in_place, "savevm before").is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+pub fn read_only() {
+    let path = image("readonly");
+    let config: DriveConfig = format!("file={},readonly=on", path.display()).parse().unwrap();
+    let mut machine = machine(Disk::open_drive(&config).unwrap());
+    assert_eq!(machine.read::<u32>(TRANSPORT + HOST_FEATURES).unwrap() as u64, F_FLUSH | F_RO);
+    assert_eq!(request(&mut machine, T_OUT, 0, &[0; 512], 0).0, S_IOERR);
+    assert!(drive(&mut machine).discard().is_err(), "no overlay");
+    machine.vm.close_drives().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), original());
+    std::fs::remove_file(&path).unwrap();
+}
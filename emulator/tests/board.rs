@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use emulator::board::*;
+use emulator::cpu::csr::file::*;
+use emulator::cpu::isa;
+use emulator::cpu::reg::Reg;
+use emulator::machine::{Machine, Trap};
+use emulator::mem::{MemMap, MemoryMap, DRAM};
+use emulator::plic::Plic;
+use emulator::uart::{UART, UART_IRQ};
+use emulator::vm::VM;
+
+const BASE: u64 = 0x8000_0000;
+const MUL: u32 = 0x027302b3; // mul t0, t1, t2
+const C_NOP: u16 = 0x0001;
+const NOP: u32 = 0x00000013;
+/// Two harts, RAM split in two regions and a UART
+const DESCRIPTION: &str = r#"
+model = "test,board"
+compatible = "test,board"
+isa = "rv64imac_zicsr"
+harts = 2
+
+[[ram]]
+base = 0x8000_0000
+size = "1M"
+
+[[ram]]
+base = 0x1_0000_0000
+size = 0x10000
+
+[[devices]]
+kind = "ns16550a"
+base = 0x1000_0000
+size = 0x1000
+irq = 3
+"#;
+const DESCRIPTION_RON: &str = r#"(
+    model: "test,board",
+    compatible: "test,board",
+    isa: "rv64imac_zicsr",
+    harts: 2,
+    ram: [(base: 0x80000000, size: 0x100000), (base: 0x100000000, size: "64K")],
+    devices: [(kind: ns16550a, base: 0x10000000, size: "4K", irq: Some(3))],
+)"#;
+
+fn contains(blob: &[u8], needle: &[u8]) -> bool {
+    blob.windows(needle.len()).any(|window| window == needle)
+}
+
+#[test]
+pub fn presets() {
+    let virt = Board::virt();
+    assert_eq!(Board::load("virt").unwrap(), virt);
+    assert_eq!((virt.harts, virt.ram[0].base, virt.ram[0].size), (1, MemMap::DRAM.base(), 128 << 20));
+    let mut mem = virt.with_ram_size(1 << 20).bus().unwrap();
+    assert_eq!(mem.device::<DRAM>(MemMap::DRAM.base()).unwrap().size(), 1 << 20);
+    // The PLIC comes first
+    let [plic, uart] = [&mem.regions()[0], &mem.regions()[1]];
+    assert_eq!((plic.base, plic.len), (MemMap::PLIC.base(), MemMap::PLIC.len()));
+    assert_eq!((uart.base, uart.len, uart.irq), (MemMap::UART0.base(), MemMap::UART0.len(), Some(UART_IRQ)));
+    assert!(mem.device_mut::<UART>(MemMap::UART0.base()).is_some());
+    assert!(mem.device_mut::<Plic>(MemMap::PLIC.base()).is_some());
+
+    let sifive = Board::preset("sifive_u").unwrap();
+    let mem = sifive.bus().unwrap();
+    let uarts: Vec<_> = mem.regions().iter().filter(|region| region.irq.is_some()).map(|region| (region.base, region.irq)).collect();
+    assert_eq!(uarts, [(0x1001_0000, Some(4)), (0x1001_1000, Some(5))]);
+    assert_eq!(sifive.stdout().unwrap().base, 0x1001_0000);
+
+    assert!(Board::preset("versatile").is_none());
+    let err = Board::load("versatile").unwrap_err();
+    assert!(format!("{err:#}").contains("virt, sifive_u"), "{err:#}");
+}
+
+#[test]
+pub fn formats() {
+    let board = Board::from_toml(DESCRIPTION).unwrap();
+    assert_eq!(Board::from_ron(DESCRIPTION_RON).unwrap(), board);
+    assert_eq!(board.ram[1], Ram { base: 0x1_0000_0000, size: 0x10000 });
+    assert_eq!(board.devices[0].size(), 0x1000);
+    // Defaults
+    let minimal = Board::from_toml("model = \"m\"\ncompatible = \"c\"\nram = [{base = 0, size = 4096}]\n\
+        devices = [{kind = \"ns16550a\", base = 0x1000}]").unwrap();
+    assert_eq!((minimal.harts, minimal.isa.as_str()), (1, "rv64imac"));
+    assert_eq!((minimal.devices[0].size(), minimal.devices[0].irq), (0x100, None));
+
+    let dir = std::env::temp_dir().join(format!("board-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, text) in [("board.toml", DESCRIPTION), ("board.ron", DESCRIPTION_RON), ("board.json", "{}")] {
+        std::fs::write(dir.join(name), text).unwrap();
+    }
+    assert_eq!(Board::load(dir.join("board.toml").to_str().unwrap()).unwrap(), board);
+    assert_eq!(Board::load(dir.join("board.ron").to_str().unwrap()).unwrap(), board);
+    assert!(Board::load(dir.join("board.json").to_str().unwrap()).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Unknown fields and devices, bad sizes
+    assert!(Board::from_toml(&format!("{DESCRIPTION}\ncpus = 4")).is_err());
+    assert!(Board::from_toml(&DESCRIPTION.replace("ns16550a", "pl011")).is_err());
+    assert!(Board::from_toml(&DESCRIPTION.replace("\"1M\"", "\"1X\"")).is_err());
+}
+
+#[test]
+pub fn invalid_machines() {
+    let board = Board::from_toml(DESCRIPTION).unwrap();
+    assert!(board.bus().is_ok());
+    assert!(Board { harts: 0, ..board.clone() }.bus().is_err());
+    assert!(Board { ram: Vec::new(), ..board.clone() }.bus().is_err());
+    assert!(Board { isa: "rv64gc".into(), ..board.clone() }.bus().is_err());
+    let mut overlapping = board.clone();
+    overlapping.devices[0].base = 0x8000_0000;
+    let err = overlapping.bus().err().unwrap();
+    assert!(format!("{err:#}").contains("overlaps"), "{err:#}");
+    // A VM has one hart
+    assert!(VM::with_board(vec![0; 4], board).is_err());
+}
+
+#[test]
+pub fn isa_strings() {
+    let letters = |misa: u64| misa & ((1 << 26) - 1);
+    assert_eq!(isa::parse("rv64imac").unwrap(), MISA_VALUE);
+    assert_eq!(isa::parse("RV64IMAC_Zicsr_Zifencei").unwrap(), MISA_VALUE);
+    assert_eq!(letters(isa::parse("rv64i").unwrap()), isa::bit('i') | isa::bit('s') | isa::bit('u'));
+    assert_eq!(isa::parse("rv64ic").unwrap() >> 62, 2);
+    for invalid in ["rv32imac", "rv64", "rv64mac", "rv64imca", "rv64iima", "rv64imafdc", "rv64gc", "rv64imac_zbb_zba", "rv64imac_zbd", "rv64imxc"] {
+        assert!(isa::parse(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+pub fn disabled_extensions() {
+    let program: Vec<u8> = MUL.to_le_bytes().into_iter().chain(C_NOP.to_le_bytes()).chain([0; 2]).collect();
+    let handler: Vec<u8> = [NOP; 4].iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let machine = |isa: &str| {
+        let mut machine = Machine::builder().isa(isa).ram_size(1 << 20)
+            .image(BASE, program.clone()).image(BASE + 0x100, handler.clone()).build().unwrap();
+        machine.set_csr(0, MTVEC, BASE + 0x100);
+        machine.set_reg(0, Reg::t1, 6);
+        machine.set_reg(0, Reg::t2, 7);
+        machine
+    };
+    let mut full = machine("rv64imac");
+    full.run(Some(2)).unwrap();
+    assert_eq!((full.reg(0, Reg::t0), full.pc(0)), (42, BASE + 6));
+
+    let mut base = machine("rv64i");
+    assert_eq!(base.csr(0, MISA), isa::parse("rv64i").unwrap());
+    let traps = Rc::new(RefCell::new(Vec::new()));
+    let recorded = traps.clone();
+    base.on_trap(move |trap: &Trap| recorded.borrow_mut().push((trap.cause, trap.tval, trap.epc)));
+    base.run(Some(1)).unwrap();
+    base.set_pc(0, BASE + 4);
+    base.run(Some(1)).unwrap();
+    assert_eq!(traps.borrow().as_slice(), [(2, MUL as u64, BASE), (2, C_NOP as u64, BASE + 4)]);
+    assert_eq!(base.reg(0, Reg::t0), 0);
+}
+
+#[test]
+pub fn device_tree() {
+    let board = Board::from_toml(DESCRIPTION).unwrap();
+    let machine = Machine::builder().board(board).build().unwrap();
+    assert_eq!(machine.harts(), 2);
+    let dtb = machine.device_tree("quiet");
+    assert_eq!(u32::from_be_bytes(dtb[..4].try_into().unwrap()), 0xd00dfeed);
+    for needle in [
+        &b"test,board\0"[..], b"quiet\0", b"cpu@0\0", b"cpu@1\0", b"memory@80000000\0", b"memory@100000000\0",
+        b"/soc/serial@10000000\0", b"rv64imac_zicntr_zicsr_zifencei\0",
+    ] {
+        assert!(contains(&dtb, needle), "{}", String::from_utf8_lossy(needle));
+    }
+    assert!(!contains(&dtb, b"cpu@2\0"));
+    // reg of the UART, with the size of the description
+    assert!(contains(&dtb, &[0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0]));
+
+    let mut vm = VM::with_board(vec![0; 4], Board::preset("sifive_u").unwrap()).unwrap();
+    vm.boot_supervisor(emulator::sbi::Sbi::default(), "").unwrap();
+    let mut blob = vec![0; 0x1000];
+    vm.mem.read(vm.cpu.regs[11], &mut blob).unwrap();
+    assert!(contains(&blob, b"SiFive HiFive Unleashed A00\0"));
+    assert!(contains(&blob, b"/soc/serial@10010000\0"));
+    assert!(contains(&blob, b"serial@10011000\0"));
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use common::{builder, causes, BASE};
+use emulator::board::Board;
+use emulator::clock::ClockMode;
+use emulator::cpu::csr::file::*;
+use emulator::machine::Machine;
+
+const CLINT: u64 = 0x200_0000;
+const MTIMECMP: u64 = CLINT + 0x4000;
+const MTIME: u64 = CLINT + 0xBFF8;
+const MSIP: u64 = 1 << 3;
+const STIP: u64 = 1 << 5;
+const MTIP: u64 = 1 << 7;
+
+fn machine(clock: ClockMode, source: &str) -> Machine {
+    let board = Board::virt().with_clint(true);
+    builder(board, source).isa("rv64imac_sstc").clock(clock).build().unwrap()
+}
+
+#[test]
+pub fn modes() {
+    assert_eq!("icount".parse(), Ok(ClockMode::Icount(1)));
+    assert_eq!("icount=8".parse(), Ok(ClockMode::Icount(8)));
+    assert_eq!("realtime".parse(), Ok(ClockMode::Realtime));
+    assert!("icount=0".parse::<ClockMode>().is_err());
+    assert!("walltime".parse::<ClockMode>().is_err());
+
+    // A fixed amount per retired instruction, the CLINT and the time CSR agree
+    let mut icount = machine(ClockMode::Icount(5), "loop: j loop");
+    icount.run(Some(10)).unwrap();
+    assert_eq!(icount.csr(0, TIME), 50);
+    assert_eq!(icount.read::<u64>(MTIME).unwrap(), 50);
+    // Setting mtime sets the clock
+    icount.write(MTIME, 1000u64).unwrap();
+    icount.run(Some(2)).unwrap();
+    assert_eq!(icount.csr(0, TIME), 1010);
+
+    // 10MHz of host time
+    let mut realtime = machine(ClockMode::Realtime, "loop: j loop");
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    realtime.run(Some(1)).unwrap();
+    assert!(realtime.csr(0, TIME) >= 50_000, "{}", realtime.csr(0, TIME));
+}
+
+#[test]
+pub fn clint() {
+    let mut machine = machine(ClockMode::default(), "
+        wfi
+        loop: j loop
+    ");
+    let traps = causes(&mut machine);
+    machine.write(MTIMECMP, 5000u64).unwrap();
+    machine.set_csr(0, MTVEC, BASE + 4);
+    machine.set_csr(0, MIE, MTIP);
+    machine.set_csr(0, MSTATUS, 1 << 3);
+    // Straight to mtimecmp instead of 5000 steps
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, TIME), 5000);
+    assert!(machine.hart(0).waiting);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(*traps.borrow(), [1 << 63 | 7]);
+    assert!(!machine.hart(0).waiting);
+    machine.write(MTIMECMP, u64::MAX).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MTIP, 0);
+
+    // Software interrupts of msip
+    machine.write(CLINT, 1u32).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MSIP, MSIP);
+    machine.write(CLINT, 0u32).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MSIP, 0);
+
+    let dtb = machine.device_tree("");
+    assert!(dtb.windows(13).any(|window| window == b"riscv,clint0\0"));
+}
+
+#[test]
+pub fn wfi() {
+    // Nothing enabled, the hart waits and time doesn't move
+    let mut idle = machine(ClockMode::default(), "
+        wfi
+        addi a0, a0, 1
+    ");
+    idle.run(Some(10)).unwrap();
+    assert_eq!((idle.pc(0), idle.csr(0, TIME), idle.csr(0, MINSTRET)), (BASE + 4, 1, 1));
+
+    // An enabled interrupt wakes it up even when interrupts are globally disabled, it doesn't trap then
+    let mut sstc = machine(ClockMode::Icount(10), "
+        csrrw zero, stimecmp, s0
+        wfi
+        addi a0, a0, 1
+    ");
+    let traps = causes(&mut sstc);
+    sstc.set_csr(0, MENVCFG, ENVCFG_STCE);
+    sstc.set_csr(0, MIE, STIP);
+    sstc.set_reg(0, emulator::cpu::reg::Reg::s0, 1_000_000);
+    sstc.run(Some(2)).unwrap();
+    assert_eq!(sstc.csr(0, TIME), 1_000_000);
+    sstc.run(Some(1)).unwrap();
+    assert_eq!(sstc.reg(0, emulator::cpu::reg::Reg::a0), 1);
+    assert_eq!(*traps.borrow(), []);
+}
+
+#[test]
+pub fn several_harts() {
+    let mut machine = builder(Board::virt(), "
+        wfi
+        loop: j loop
+    ").harts(2).isa("rv64imac_sstc").build().unwrap();
+    for (hart, stimecmp) in [(0, 100), (1, 1000)] {
+        machine.set_csr(hart, MENVCFG, ENVCFG_STCE);
+        machine.set_csr(hart, MIE, STIP);
+        machine.set_csr(hart, STIMECMP, stimecmp);
+    }
+    // Time only skips when both harts wait, to the earliest deadline
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, TIME), 100);
+    machine.run(Some(3)).unwrap();
+    assert!(!machine.hart(0).waiting && machine.hart(1).waiting);
+    assert!(machine.csr(1, TIME) < 1000);
+}
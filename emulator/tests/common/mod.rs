@@ -0,0 +1,87 @@
+// Fixtures of the integration tests: a machine spinning at the start of RAM, the split virtqueues of the legacy
+// virtio-mmio interface the kernel uses (the used ring a page after the descriptors), and recorders of the traps taken
+// Each test file uses some of them
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use emulator::asm::assemble;
+use emulator::board::Board;
+use emulator::machine::{Machine, MachineBuilder, Trap};
+use emulator::virtio::mmio::*;
+use emulator::virtio::{VirtioDevice, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK};
+
+pub const BASE: u64 = 0x8000_0000;
+/// First virtio-mmio transport of virt
+pub const TRANSPORT: u64 = 0x1000_1000;
+/// Entries of the queues
+pub const ENTRIES: u64 = 8;
+/// Descriptor flags: chained to the next one, written by the device
+pub const DESC_NEXT: u16 = 1;
+pub const DESC_WRITE: u16 = 2;
+
+/// `board` with 1MiB of RAM and `source` at its start
+pub fn builder(board: Board, source: &str) -> MachineBuilder {
+    Machine::builder().board(board).ram_size(1 << 20).image(BASE, assemble(source, BASE).unwrap())
+}
+/// virt with the virtio-mmio transports, `device` on the first one
+pub fn virtio_machine(device: impl VirtioDevice) -> Machine {
+    let mut machine = builder(Board::virt().with_virtio_mmio(true), "loop: j loop").build().unwrap();
+    assert_eq!(machine.vm.add_virtio_device(device).unwrap(), TRANSPORT);
+    machine
+}
+
+/// Sets the device up like the kernel does, with `features` and the queues at their page through QueuePFN
+pub fn driver_init(machine: &mut Machine, features: u64, queues: &[(usize, u64)]) {
+    machine.write(TRANSPORT + STATUS, (STATUS_ACKNOWLEDGE | STATUS_DRIVER) as u32).unwrap();
+    machine.write(TRANSPORT + GUEST_FEATURES, features as u32).unwrap();
+    for &(queue, page) in queues {
+        machine.write(TRANSPORT + QUEUE_SEL, queue as u32).unwrap();
+        machine.write(TRANSPORT + QUEUE_NUM, ENTRIES as u32).unwrap();
+        machine.write(TRANSPORT + QUEUE_PFN, (page / 4096) as u32).unwrap();
+    }
+    machine.write(TRANSPORT + STATUS, (STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK) as u32).unwrap();
+}
+/// Descriptor `index` of the queue at `page`, with DESC_NEXT it is chained to `index` + 1
+pub fn descriptor(machine: &mut Machine, page: u64, index: u16, addr: u64, len: u32, flags: u16) {
+    let desc = page + 16 * index as u64;
+    machine.write(desc, addr).unwrap();
+    machine.write(desc + 8, len).unwrap();
+    machine.write(desc + 12, flags).unwrap();
+    machine.write(desc + 14, index.wrapping_add(1)).unwrap();
+}
+/// Puts the chain starting at `head` in the available ring of the queue at `page`
+pub fn make_available(machine: &mut Machine, page: u64, head: u16) {
+    let avail = page + 16 * ENTRIES;
+    let idx = machine.read::<u16>(avail + 2).unwrap();
+    machine.write(avail + 4 + 2 * (idx as u64 % ENTRIES), head).unwrap();
+    machine.write(avail + 2, idx.wrapping_add(1)).unwrap();
+}
+/// Notifies `queue` and lets the device process it
+pub fn notify(machine: &mut Machine, queue: usize) {
+    machine.write(TRANSPORT + QUEUE_NOTIFY, queue as u32).unwrap();
+    machine.run(Some(1)).unwrap();
+}
+/// Entries the device put in the used ring of the queue at `page`, by head and length
+pub fn used(machine: &mut Machine, page: u64) -> Vec<(u32, u32)> {
+    let ring = page + 4096;
+    let idx = machine.read::<u16>(ring + 2).unwrap() as u64;
+    (0..idx).map(|i| (machine.read(ring + 4 + 8 * (i % ENTRIES)).unwrap(), machine.read(ring + 8 + 8 * (i % ENTRIES)).unwrap())).collect()
+}
+
+/// `take` of each trap taken, in order
+fn record<T: 'static>(machine: &mut Machine, take: impl Fn(&Trap) -> T + 'static) -> Rc<RefCell<Vec<T>>> {
+    let recorded = Rc::new(RefCell::new(Vec::new()));
+    let traps = recorded.clone();
+    machine.on_trap(move |trap: &Trap| traps.borrow_mut().push(take(trap)));
+    recorded
+}
+/// Causes of the traps taken
+pub fn causes(machine: &mut Machine) -> Rc<RefCell<Vec<u64>>> {
+    record(machine, |trap| trap.cause)
+}
+/// Causes and tvals of the traps taken
+pub fn traps(machine: &mut Machine) -> Rc<RefCell<Vec<(u64, u64)>>> {
+    record(machine, |trap| (trap.cause, trap.tval))
+}
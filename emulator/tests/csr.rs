@@ -0,0 +1,181 @@
+use emulator::cpu::csr::file::*;
+use emulator::cpu::PrivilegeLevel;
+use emulator::mem::{MemMap, MemoryMap};
+use emulator::vm::VM;
+
+const MTVEC_ADDR: u64 = 0x8000_1000;
+
+fn csr_inst(funct3: u32, rd: u32, rs1: u32, csr: u16) -> u32 {
+    (csr as u32) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | 0b1110011
+}
+fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    ((imm as u32) & 0xFFF) << 20 | rs1 << 15 | rd << 7 | 0b0010011
+}
+
+fn vm_with(program: &[u32]) -> VM {
+    let bytes = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let mut vm = VM::new(bytes, 1 << 20).unwrap();
+    vm.cpu.set_csr_value(MTVEC, MTVEC_ADDR);
+    vm
+}
+fn run(vm: &mut VM, steps: usize) {
+    for _ in 0..steps {
+        assert!(vm.step().unwrap());
+    }
+}
+
+#[test]
+pub fn zicsr_instructions() {
+    let mut vm = vm_with(&[
+        addi(5, 0, 0b1100),
+        csr_inst(0b001, 6, 5, MSCRATCH), // csrrw t1, mscratch, t0
+        csr_inst(0b010, 7, 0, MSCRATCH), // csrrs t2, mscratch, zero
+        csr_inst(0b110, 0, 0b0011, MSCRATCH), // csrrsi zero, mscratch, 3
+        csr_inst(0b111, 0, 0b0110, MSCRATCH), // csrrci zero, mscratch, 6
+        csr_inst(0b101, 28, 0b10000, MSCRATCH), // csrrwi t3, mscratch, 16
+        csr_inst(0b011, 29, 5, MSCRATCH), // csrrc t4, mscratch, t0
+    ]);
+    vm.cpu.set_csr_value(MSCRATCH, 42);
+    run(&mut vm, 3);
+    assert_eq!(vm.cpu.regs[6], 42);
+    assert_eq!(vm.cpu.regs[7], 0b1100);
+    run(&mut vm, 2);
+    assert_eq!(vm.cpu.csr_value(MSCRATCH), 0b1001);
+    run(&mut vm, 2);
+    assert_eq!(vm.cpu.regs[28], 0b1001);
+    assert_eq!(vm.cpu.regs[29], 0b10000);
+    assert_eq!(vm.cpu.csr_value(MSCRATCH), 0b10000);
+}
+
+#[test]
+pub fn illegal_accesses_trap() {
+    let base = MemMap::DRAM.base();
+    let mut vm = vm_with(&[
+        csr_inst(0b001, 5, 5, MHARTID), // csrrw t0, mhartid, t0: read-only
+        csr_inst(0b010, 5, 0, MHARTID), // csrr t0, mhartid: fine
+        csr_inst(0b010, 5, 0, 0x7C0), // Custom CSR that doesn't exist
+    ]);
+    vm.cpu.hartid = 3;
+    vm.cpu.regs[5] = 7;
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.pc, MTVEC_ADDR);
+    assert_eq!(vm.cpu.csr_value(MCAUSE), 2);
+    assert_eq!(vm.cpu.csr_value(MEPC), base);
+    assert_eq!(vm.cpu.csr_value(MTVAL), csr_inst(0b001, 5, 5, MHARTID) as u64);
+    // Destination untouched
+    assert_eq!(vm.cpu.regs[5], 7);
+
+    vm.cpu.pc = base+4;
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.regs[5], 3);
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.pc, MTVEC_ADDR);
+}
+
+#[test]
+pub fn privilege_checks() {
+    let base = MemMap::DRAM.base();
+    let mut vm = vm_with(&[
+        csr_inst(0b010, 5, 0, MSTATUS), // csrr t0, mstatus
+        csr_inst(0b010, 5, 0, SSTATUS), // csrr t0, sstatus
+        csr_inst(0b010, 6, 0, CYCLE), // rdcycle t1
+    ]);
+    // PMP entry 0 covers all memory RWX, S-mode can't fetch otherwise
+    vm.cpu.set_csr_value(0x3B0, u64::MAX);
+    vm.cpu.set_csr_value(0x3A0, 0b11111);
+    vm.cpu.privilege_level = PrivilegeLevel::Supervisor;
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.pc, MTVEC_ADDR);
+    assert_eq!(vm.cpu.csr_value(MCAUSE), 2);
+    // The trap goes to M-mode and remembers S in MPP
+    assert_eq!(vm.cpu.privilege_level, PrivilegeLevel::Machine);
+    assert_eq!((vm.cpu.csr_value(MSTATUS) >> 11) & 0b11, 1);
+
+    vm.cpu.privilege_level = PrivilegeLevel::Supervisor;
+    vm.cpu.pc = base+4;
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.pc, base+8);
+    // Counters need mcounteren from S-mode
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.pc, MTVEC_ADDR);
+    vm.cpu.privilege_level = PrivilegeLevel::Supervisor;
+    vm.cpu.set_csr_value(MCOUNTEREN, 0b111);
+    vm.cpu.pc = base+8;
+    run(&mut vm, 1);
+    assert_ne!(vm.cpu.regs[6], 0);
+}
+
+#[test]
+pub fn masks_and_aliases() {
+    let mut vm = vm_with(&[]);
+    let cpu = &mut vm.cpu;
+    // sstatus only reaches the S bits of mstatus
+    cpu.set_csr_value(SSTATUS, u64::MAX);
+    assert_eq!(cpu.csr_value(MSTATUS) & 0b1010, 0b0010);
+    assert_eq!(cpu.csr_value(SSTATUS) >> 32 & 0b11, 2); // UXL
+
+    // sie/sip are the delegated part of mie/mip
+    cpu.set_csr_value(SIE, u64::MAX);
+    assert_eq!(cpu.csr_value(MIE), 0);
+    cpu.set_csr_value(MIDELEG, u64::MAX);
+    assert_eq!(cpu.csr_value(MIDELEG), S_INTERRUPTS);
+    cpu.set_csr_value(SIE, u64::MAX);
+    assert_eq!(cpu.csr_value(MIE), S_INTERRUPTS);
+    cpu.set_csr_value(MIE, u64::MAX);
+    assert_eq!(cpu.csr_value(SIE), S_INTERRUPTS);
+    cpu.set_csr_value(SIP, u64::MAX);
+    assert_eq!(cpu.csr_value(MIP), 1 << 1);
+
+    // WARL
+    cpu.set_csr_value(MISA, 0);
+    assert_eq!(cpu.csr_value(MISA), MISA_VALUE);
+    // With C, compressed instructions can be returned to
+    cpu.set_csr_value(MEPC, 0x8000_0003);
+    assert_eq!(cpu.csr_value(MEPC), 0x8000_0002);
+    cpu.set_csr_value(SEPC, 0x8000_0006);
+    assert_eq!(cpu.csr_value(SEPC), 0x8000_0006);
+    cpu.csrs[MISA as usize].0 &= !(1 << 2);
+    cpu.set_csr_value(MEPC, 0x8000_0003);
+    assert_eq!(cpu.csr_value(MEPC), 0x8000_0000);
+    cpu.csrs[MISA as usize].0 = MISA_VALUE;
+    cpu.set_csr_value(MTVEC, 0x8000_0003);
+    assert_eq!(cpu.csr_value(MTVEC), MTVEC_ADDR);
+    cpu.set_csr_value(MSTATUS, 2 << 11);
+    assert_eq!((cpu.csr_value(MSTATUS) >> 11) & 0b11, 0);
+}
+
+#[test]
+pub fn external_seip() {
+    let mut vm = vm_with(&[
+        csr_inst(0b110, 0, 0b0010, MIP), // csrrsi zero, mip, 2
+        csr_inst(0b111, 5, 0b0010, MIP), // csrrci t0, mip, 2
+    ]);
+    const SEIP: u64 = 1 << 9;
+    // The interrupt controller drives SEIP, then drops the line once the step is over
+    vm.cpu.external_interrupts = SEIP;
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.external_interrupts, 0);
+    assert_eq!(vm.cpu.csr_value(MIP), 1 << 1);
+    vm.cpu.external_interrupts = SEIP;
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.regs[5], SEIP | 1 << 1);
+    assert_eq!(vm.cpu.csr_value(MIP), 0);
+    // Software still sets its own SEIP
+    vm.cpu.set_csr_value(MIP, SEIP);
+    assert_eq!(vm.cpu.csr_value(MIP), SEIP);
+}
+
+#[test]
+pub fn live_counters() {
+    let mut vm = vm_with(&[
+        addi(0, 0, 0),
+        addi(0, 0, 0),
+        csr_inst(0b010, 5, 0, INSTRET), // rdinstret t0
+        csr_inst(0b010, 6, 0, CYCLE), // rdcycle t1
+        csr_inst(0b010, 7, 0, MHARTID), // csrr t2, mhartid
+    ]);
+    run(&mut vm, 5);
+    assert_eq!(vm.cpu.regs[5], 2);
+    assert_eq!(vm.cpu.regs[6], 3);
+    assert_eq!(vm.cpu.csr_value(INSTRET), 5);
+}
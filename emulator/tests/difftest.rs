@@ -0,0 +1,184 @@
+use std::fmt::Write;
+
+use emulator::cpu::csr::file::*;
+use emulator::difftest::*;
+use emulator::vm::VM;
+
+const BASE: u64 = 0x8000_0000;
+const PROGRAM: [u32; 6] = [
+    0x00500513, // li a0, 5
+    0x00150513, // addi a0, a0, 1
+    0xc00025f3, // rdcycle a1
+    0x00b50633, // add a2, a0, a1
+    0x34051073, // csrw mscratch, a0
+    0x0000006f, // j .
+];
+/// Where QEMU's reset vector leaves the DTB pointer
+const DTB: u64 = 0x87e0_0000;
+const CSRS: [(&str, u16); 5] = [("mstatus", MSTATUS), ("mtvec", MTVEC), ("mepc", MEPC), ("mcause", MCAUSE), ("mscratch", MSCRATCH)];
+
+fn vm(program: &[u32]) -> VM {
+    let mut vm = VM::new(program.iter().flat_map(|inst| inst.to_le_bytes()).collect(), 1 << 20).unwrap();
+    vm.trace = false;
+    vm
+}
+/// The states QEMU would log for `steps` instructions of `program`, after its reset vector
+fn reference(program: &[u32], steps: usize) -> Vec<QemuState> {
+    let mut states = vec![
+        QemuState { pc: 0x1000, ..Default::default() },
+        QemuState { pc: 0x1004, regs: {let mut regs = [0; 32]; regs[5] = 0x1000; regs}, ..Default::default() },
+    ];
+    let mut vm = vm(program);
+    vm.cpu.regs[10] = 0;
+    vm.cpu.regs[5] = BASE;
+    vm.cpu.regs[11] = DTB;
+    for _ in 0..steps {
+        let csrs = CSRS.iter().map(|&(name, id)| (name.to_string(), vm.cpu.csr_value(id))).collect();
+        states.push(QemuState { pc: vm.cpu.pc, regs: vm.cpu.regs, csrs, disasm: Some(format!("insn at {:x}", vm.cpu.pc)) });
+        vm.step().unwrap();
+    }
+    states
+}
+/// Formats states like `-d in_asm,cpu,nochain` does
+fn qemu_log(states: &[QemuState]) -> String {
+    let mut log = String::new();
+    for state in states {
+        if let Some(disasm) = &state.disasm {
+            writeln!(log, "----------------\nIN: \nPriv: 3; Virt: 0").unwrap();
+            writeln!(log, "0x{:016x}:  00000013          {disasm}\n", state.pc).unwrap();
+        }
+        writeln!(log, " {:<8} {:016x}", "pc", state.pc).unwrap();
+        for (name, value) in &state.csrs {
+            writeln!(log, " {name:<8} {value:016x}").unwrap();
+        }
+        for (reg, value) in state.regs.iter().enumerate() {
+            let name = format!("x{reg}/{}", emulator::cpu::reg::REGS[reg]);
+            write!(log, " {name:<8} {value:016x}").unwrap();
+            if reg % 4 == 3 {log.push('\n')}
+        }
+        writeln!(log, " f0/ft0   0000000000000000 f1/ft1   0000000000000000 f2/ft2   0000000000000000 f3/ft3   0000000000000000").unwrap();
+        writeln!(log, " V      =   0").unwrap();
+    }
+    log
+}
+fn parse(log: &str) -> Vec<QemuState> {
+    QemuLog::new(log.as_bytes()).collect::<color_eyre::Result<_>>().unwrap()
+}
+fn run(program: &[u32], states: &[QemuState]) -> Outcome {
+    compare(&mut vm(program), parse(&qemu_log(states)).into_iter().map(Ok), &Options::default()).unwrap()
+}
+fn diverged(outcome: Outcome) -> Divergence {
+    match outcome {
+        Outcome::Diverged(divergence) => *divergence,
+        outcome => panic!("No divergence: {outcome:?}"),
+    }
+}
+
+#[test]
+pub fn parse_log() {
+    let log = "\
+----------------
+IN:
+Priv: 3; Virt: 0
+0x0000000000001000:  00000297          auipc                   t0,0                    # 0x1000
+
+ pc       0000000000001000
+ mhartid  0000000000000000
+ mstatus  0000000a00000000
+ x0/zero  0000000000000000 x1/ra    0000000000000000 x2/sp    0000000000000000 x3/gp    0000000000000000
+ x4/tp    0000000000000000 x5/t0    0000000000000000 x6/t1    0000000000000000 x7/t2    0000000000000000
+ x8/s0    0000000000000000 x9/s1    0000000000000000 x10/a0   0000000000000000 x11/a1   0000000087e00000
+ f0/ft0   ffffffff00000000
+ pc       0000000000001004
+ x5/t0    0000000000001000
+";
+    let states = parse(log);
+    assert_eq!(states.len(), 2);
+    assert_eq!(states[0].pc, 0x1000);
+    assert_eq!(states[0].disasm.as_deref(), Some("auipc t0,0 # 0x1000"));
+    assert_eq!(states[0].csr("mstatus"), Some(0xa_0000_0000));
+    assert_eq!(states[0].csrs.len(), 2);
+    assert_eq!(states[0].regs[11], DTB);
+    assert_eq!((states[1].pc, states[1].regs[5], states[1].disasm.as_deref()), (0x1004, 0x1000, None));
+
+    let states = reference(&PROGRAM, 4);
+    assert_eq!(parse(&qemu_log(&states)), states);
+    assert!(QemuLog::new(" pc 0\n x99/foo 1\n".as_bytes()).next().unwrap().is_err());
+}
+
+#[test]
+pub fn matching_run() {
+    let states = reference(&PROGRAM, 8);
+    let mut machine = vm(&PROGRAM);
+    let outcome = compare(&mut machine, states.into_iter().map(Ok), &Options::default()).unwrap();
+    assert!(matches!(outcome, Outcome::Match { instructions: 7 }), "{outcome:?}");
+    // Registers set by QEMU's reset vector are taken
+    assert_eq!(machine.cpu.regs[5], BASE);
+
+    // Counters read by the program are QEMU's
+    let mut states = reference(&PROGRAM, 8);
+    for state in &mut states[5..] {
+        state.regs[11] += 1000;
+    }
+    for state in &mut states[6..] {
+        state.regs[12] += 1000;
+    }
+    let outcome = run(&PROGRAM, &states);
+    assert!(matches!(outcome, Outcome::Match { instructions: 7 }), "{outcome:?}");
+
+    let mut limited = Options { max_instructions: Some(3), ..Default::default() };
+    limited.csrs.push("unknown".into());
+    let outcome = compare(&mut vm(&PROGRAM), reference(&PROGRAM, 8).into_iter().map(Ok), &limited).unwrap();
+    assert!(matches!(outcome, Outcome::Match { instructions: 3 }));
+}
+
+#[test]
+pub fn register_divergence() {
+    let mut states = reference(&PROGRAM, 6);
+    // Second instruction computed 7
+    states[4].regs[10] = 7;
+    let divergence = diverged(run(&PROGRAM, &states));
+    assert_eq!(divergence.mismatch, Mismatch::Reg { reg: 10, qemu: 7, ours: 6 });
+    assert_eq!(divergence.instructions, 2);
+    assert_eq!(divergence.context.iter().map(|executed| executed.pc).collect::<Vec<_>>(), [BASE, BASE + 4]);
+    assert_eq!(divergence.context[1].qemu.as_deref(), Some("insn at 80000004"));
+    let report = divergence.to_string();
+    assert!(report.contains("Divergence after 2 instructions, x10/a0: QEMU 0x7, emulator 0x6"), "{report}");
+    assert!(report.contains(" *x10 a0"), "{report}");
+    assert!(report.contains("0x0000000080000004"), "{report}");
+}
+
+#[test]
+pub fn pc_and_csr_divergences() {
+    let mut states = reference(&PROGRAM, 8);
+    states[7].csrs.retain(|(name, _)| name != "mscratch");
+    states[7].csrs.push(("mscratch".into(), 0));
+    let divergence = diverged(run(&PROGRAM, &states));
+    assert_eq!(divergence.mismatch, Mismatch::Csr { name: "mscratch".into(), qemu: 0, ours: 6 });
+    assert_eq!(divergence.context.last().unwrap().pc, BASE + 16);
+    // Not compared unless asked for
+    let options = Options { csrs: vec!["mstatus".into()], ..Default::default() };
+    assert!(matches!(compare(&mut vm(&PROGRAM), states.into_iter().map(Ok), &options).unwrap(), Outcome::Match { .. }));
+
+    let mut states = reference(&PROGRAM, 8);
+    states[5].pc += 4;
+    let divergence = diverged(run(&PROGRAM, &states));
+    assert_eq!(divergence.mismatch, Mismatch::Pc { qemu: BASE + 16, ours: BASE + 12 });
+    // With a context of 2
+    let options = Options { context: 2, ..Default::default() };
+    let divergence = diverged(compare(&mut vm(&PROGRAM), states.into_iter().map(Ok), &options).unwrap());
+    assert_eq!(divergence.context.len(), 2);
+}
+
+#[test]
+pub fn stops_and_errors() {
+    // QEMU goes on with the trap the zero instruction raises
+    let program = [0x00000013, 0];
+    let mut states = reference(&program, 2);
+    states.push(QemuState { pc: 0, ..Default::default() });
+    let divergence = diverged(run(&program, &states));
+    assert_eq!(divergence.mismatch, Mismatch::Stopped { pc: BASE + 4 });
+
+    let states = vec![QemuState { pc: 0x1000, ..Default::default() }];
+    assert!(compare(&mut vm(&PROGRAM), states.into_iter().map(Ok), &Options::default()).is_err());
+}
@@ -1,9 +1,11 @@
 use std::fmt::Write;
 pub fn strip_raw(raw: String) -> Option<String> {
     let mut parsed = String::new();
-    for line in raw.split("\n") {
-        if line.ends_with(":") {continue} // Function definition, they are skipped when in binary
-        let parsed_line = line.trim().replace(",", "");
+    for line in raw.lines() {
+        let line = line.trim();
+        // Function definitions are skipped when in binary, so are blank lines
+        if line.ends_with(":") || line.is_empty() {continue}
+        let parsed_line = line.replace(",", "");
         writeln!(parsed, "{}", parsed_line).ok()?;
     }
     Some(parsed)
@@ -11,12 +13,17 @@ pub fn strip_raw(raw: String) -> Option<String> {
 
 #[test]
 pub fn disasm_simple() {
-    let mut cmd = std::process::Command::new("python3");
-    cmd.args(&["compile.py", "test"]);
-    let mut handle = cmd.spawn().unwrap();
-    assert!(handle.wait().unwrap().success());
-
     let raw = std::fs::read_to_string("test.s").unwrap();
-    let parsed = emulator::vm::disasm(std::fs::read("target/test.o").unwrap()).unwrap();
+    let program = emulator::asm::assemble(&raw, 0).unwrap();
+    let parsed = emulator::vm::disasm(program).unwrap();
     assert_eq!(strip_raw(raw).unwrap(),parsed);
+}
+
+#[test]
+pub fn disasm_compressed() {
+    // c.addi a0 1, c.j back to it
+    let program = [0x0505u16, 0xbffd].iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    assert_eq!(emulator::vm::disasm(program).unwrap(), "addi a0 a0 1\nj 0x0\n");
+    assert!(emulator::vm::disasm(vec![0; 4]).is_err());
+    assert!(emulator::vm::disasm(vec![0x13, 0, 0]).is_err());
 }
\ No newline at end of file
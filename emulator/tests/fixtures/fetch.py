@@ -0,0 +1,132 @@
+"""Fetches and builds the riscv-tests and riscv-arch-test suites that tests/riscv_tests.rs runs
+
+The repositories are checked out at the commits of `revisions` next to this script. A suite without one is
+taken from the default branch and its commit is written there, commit the file to pin it. The SHA-256 of every
+copied file goes to `SHA256SUMS`, commit it with the suites (`sha256sum -c SHA256SUMS` checks them).
+Needs git, make and a riscv64-unknown-elf toolchain (--prefix to use another one).
+"""
+import argparse
+import glob
+import hashlib
+import os
+import shutil
+import subprocess
+import sys
+
+FIXTURES = os.path.dirname(os.path.abspath(__file__))
+REVISIONS = os.path.join(FIXTURES, "revisions")
+SHA256SUMS = os.path.join(FIXTURES, "SHA256SUMS")
+REPOSITORIES = {
+    "riscv-tests": "https://github.com/riscv-software-src/riscv-tests",
+    "riscv-arch-test": "https://github.com/riscv-non-isa/riscv-arch-test",
+}
+# The riscv-tests ELFs that are copied, the "p" environment of the RV64IMAC, machine and supervisor suites
+RISCV_TESTS = ["rv64ui-p-", "rv64um-p-", "rv64ua-p-", "rv64uc-p-", "rv64mi-p-", "rv64si-p-"]
+# The riscv-arch-test directories of riscv-test-suite/rv64i_m that are built
+ARCH_TESTS = ["I", "M", "A", "C", "Zifencei", "privilege"]
+ARCH_MARCH = "rv64imac_zicsr_zifencei"
+
+
+def cmd(args, cwd=None):
+    print("+", " ".join(args))
+    res = subprocess.run(args, cwd=cwd)
+    if res.returncode != 0:
+        sys.exit(res.returncode)
+
+
+def output(args, cwd=None):
+    return subprocess.run(args, cwd=cwd, check=True, capture_output=True, text=True).stdout.strip()
+
+
+def read_revisions():
+    revisions = {}
+    if os.path.exists(REVISIONS):
+        with open(REVISIONS) as file:
+            for line in file:
+                line = line.split("#")[0].strip()
+                if line:
+                    name, commit = line.split()
+                    revisions[name] = commit
+    return revisions
+
+
+def write_revisions(revisions):
+    with open(REVISIONS, "w") as file:
+        file.write("# Commits of the suites in tests/fixtures, written by fetch.py\n")
+        for name, commit in sorted(revisions.items()):
+            file.write(f"{name} {commit}\n")
+
+
+def write_hashes():
+    with open(SHA256SUMS, "w") as file:
+        for name in sorted(REPOSITORIES):
+            for path in sorted(glob.glob(os.path.join(FIXTURES, name, "*"))):
+                with open(path, "rb") as fixture:
+                    digest = hashlib.sha256(fixture.read()).hexdigest()
+                file.write(f"{digest}  {name}/{os.path.basename(path)}\n")
+
+
+def checkout(name, work, revisions):
+    """Clones `name` in `work` at its pinned commit, or pins the commit of the default branch"""
+    path = os.path.join(work, name)
+    if not os.path.isdir(path):
+        cmd(["git", "clone", REPOSITORIES[name], path])
+    if name in revisions:
+        cmd(["git", "checkout", "--detach", revisions[name]], cwd=path)
+    else:
+        revisions[name] = output(["git", "rev-parse", "HEAD"], cwd=path)
+        print(f"Pinned {name} to {revisions[name]}")
+    cmd(["git", "submodule", "update", "--init", "--recursive"], cwd=path)
+    return path
+
+
+def riscv_tests(source, prefix):
+    cmd(["make", "-C", "isa", "XLEN=64", f"RISCV_PREFIX={prefix}"], cwd=source)
+    out = os.path.join(FIXTURES, "riscv-tests")
+    os.makedirs(out, exist_ok=True)
+    copied = 0
+    for path in sorted(glob.glob(os.path.join(source, "isa", "rv64*-p-*"))):
+        name = os.path.basename(path)
+        if "." not in name and any(name.startswith(suite) for suite in RISCV_TESTS):
+            shutil.copy(path, out)
+            copied += 1
+    print(f"{copied} riscv-tests in {out}")
+
+
+def arch_tests(source, prefix):
+    model = os.path.join(FIXTURES, "model")
+    out = os.path.join(FIXTURES, "riscv-arch-test")
+    os.makedirs(out, exist_ok=True)
+    built = 0
+    for suite in ARCH_TESTS:
+        directory = os.path.join(source, "riscv-test-suite", "rv64i_m", suite)
+        for test in sorted(glob.glob(os.path.join(directory, "src", "*.S"))):
+            name = os.path.splitext(os.path.basename(test))[0]
+            reference = os.path.join(directory, "references", name + ".reference_output")
+            if not os.path.exists(reference):
+                print(f"Skipping {suite}/{name}, it has no reference output")
+                continue
+            cmd([prefix + "gcc", f"-march={ARCH_MARCH}", "-mabi=lp64", "-static", "-mcmodel=medany",
+                 "-fvisibility=hidden", "-nostdlib", "-nostartfiles", "-T", os.path.join(model, "link.ld"),
+                 "-I", model, "-I", os.path.join(source, "riscv-test-suite", "env"), "-DXLEN=64",
+                 "-DTEST_CASE_1=True", test, "-o", os.path.join(out, name + ".elf")])
+            shutil.copy(reference, out)
+            built += 1
+    print(f"{built} riscv-arch-test tests in {out}")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--prefix", default="riscv64-unknown-elf-", help="Prefix of the toolchain binaries")
+    parser.add_argument("--work", default=os.path.join(FIXTURES, "build"), help="Where the repositories are cloned")
+    args = parser.parse_args()
+    revisions = read_revisions()
+    os.makedirs(args.work, exist_ok=True)
+    riscv_tests(checkout("riscv-tests", args.work, revisions), args.prefix)
+    arch_tests(checkout("riscv-arch-test", args.work, revisions), args.prefix)
+    write_revisions(revisions)
+    write_hashes()
+
+
+if __name__ == "__main__":
+    main()
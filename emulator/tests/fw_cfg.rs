@@ -0,0 +1,118 @@
+use emulator::args::{parse_fw_cfg, FwCfgFile};
+mod common;
+
+use common::{builder, BASE};
+use emulator::board::{Board, DeviceKind};
+use emulator::fw_cfg::*;
+use emulator::machine::Machine;
+use emulator::mem::{MemMap, MemoryMap, DRAM};
+
+const FW_CFG: u64 = 0x1010_0000;
+const SELECTOR: u64 = FW_CFG + 0x08;
+const DMA: u64 = FW_CFG + 0x10;
+/// Where the tests put DMA accesses and their buffers
+const ACCESS: u64 = BASE + 0x1000;
+const BUFFER: u64 = BASE + 0x2000;
+
+fn machine() -> Machine {
+    let board = Board::virt().with_fw_cfg(true);
+    let mut machine = builder(board, "loop: j loop").build().unwrap();
+    let fw_cfg = machine.vm.mem.device_mut::<FwCfg>(FW_CFG).unwrap();
+    fw_cfg.set_cmdline("console=ttyS0");
+    fw_cfg.set_initrd(vec![0xAA; 100]);
+    assert_eq!(fw_cfg.add_file("opt/org.example/b", b"second".to_vec()).unwrap(), FILE_FIRST);
+    assert_eq!(fw_cfg.add_file("opt/org.example/a", b"first".to_vec()).unwrap(), FILE_FIRST + 1);
+    machine
+}
+/// Reads `len` bytes of item `key` through the data register
+fn read_item(machine: &mut Machine, key: u16, len: usize) -> Vec<u8> {
+    machine.write(SELECTOR, key.to_be()).unwrap();
+    (0..len).map(|_| machine.read::<u8>(FW_CFG).unwrap()).collect()
+}
+/// Does a DMA access and returns the control word written back
+fn dma(machine: &mut Machine, control: u32, length: u32, address: u64) -> u32 {
+    machine.write(ACCESS, control.to_be()).unwrap();
+    machine.write(ACCESS + 4, length.to_be()).unwrap();
+    machine.write(ACCESS + 8, address.to_be()).unwrap();
+    machine.write(DMA, ACCESS.to_be()).unwrap();
+    machine.run(Some(1)).unwrap();
+    u32::from_be(machine.read(ACCESS).unwrap())
+}
+
+#[test]
+pub fn data_register() {
+    let mut machine = machine();
+    assert_eq!(read_item(&mut machine, SIGNATURE, 4), b"QEMU");
+    assert_eq!(read_item(&mut machine, ID, 4), 3u32.to_le_bytes());
+    assert_eq!(read_item(&mut machine, NB_CPUS, 2), 1u16.to_le_bytes());
+    assert_eq!(read_item(&mut machine, CMDLINE_SIZE, 4), 14u32.to_le_bytes());
+    assert_eq!(read_item(&mut machine, CMDLINE_DATA, 14), b"console=ttyS0\0");
+    assert_eq!(read_item(&mut machine, INITRD_SIZE, 4), 100u32.to_le_bytes());
+    // Wider reads get the next bytes in order, past the end it's zeros
+    machine.write(SELECTOR, (FILE_FIRST + 1).to_be()).unwrap();
+    assert_eq!(machine.read::<u32>(FW_CFG).unwrap().to_le_bytes(), *b"firs");
+    assert_eq!(machine.read::<u32>(FW_CFG).unwrap().to_le_bytes(), *b"t\0\0\0");
+
+    // The directory is sorted by name
+    let directory = read_item(&mut machine, FILE_DIR, 4 + 2 * 64);
+    assert_eq!(directory[..4], 2u32.to_be_bytes());
+    let entry = |i: usize| &directory[4 + 64 * i..4 + 64 * (i + 1)];
+    assert_eq!(entry(0)[..6], [0, 0, 0, 5, 0, FILE_FIRST as u8 + 1]);
+    assert_eq!(&entry(0)[8..26], b"opt/org.example/a\0");
+    assert_eq!(entry(1)[..6], [0, 0, 0, 6, 0, FILE_FIRST as u8]);
+}
+
+#[test]
+pub fn dma_access() {
+    let mut machine = machine();
+    let mut signature = [0; 8];
+    machine.read_bytes(DMA, &mut signature).unwrap();
+    assert_eq!(&signature, b"QEMU CFG");
+    let key = machine.vm.mem.device::<FwCfg>(FW_CFG).unwrap().file("opt/org.example/b").unwrap();
+    assert_eq!(dma(&mut machine, (key as u32) << 16 | DMA_SELECT | DMA_READ, 3, BUFFER), 0);
+    let mut data = [0; 8];
+    machine.read_bytes(BUFFER, &mut data).unwrap();
+    assert_eq!(&data, b"sec\0\0\0\0\0");
+    // Accesses go on from where the last one stopped
+    assert_eq!(dma(&mut machine, DMA_SKIP, 1, 0), 0);
+    assert_eq!(dma(&mut machine, DMA_READ, 4, BUFFER), 0);
+    machine.read_bytes(BUFFER, &mut data).unwrap();
+    assert_eq!(&data, b"nd\0\0\0\0\0\0");
+    // Items are read-only, and the buffer has to be in memory
+    assert_eq!(dma(&mut machine, DMA_WRITE, 4, BUFFER), DMA_ERROR);
+    assert_eq!(dma(&mut machine, (INITRD_DATA as u32) << 16 | DMA_SELECT | DMA_READ, 100, 0x10), DMA_ERROR);
+
+    // The address written 32 bits at a time, the low half starts the access
+    machine.write(ACCESS, ((INITRD_DATA as u32) << 16 | DMA_SELECT | DMA_READ).to_be()).unwrap();
+    machine.write(ACCESS + 4, 100u32.to_be()).unwrap();
+    machine.write(ACCESS + 8, BUFFER.to_be()).unwrap();
+    machine.write(DMA, ((ACCESS >> 32) as u32).to_be()).unwrap();
+    machine.write(DMA + 4, (ACCESS as u32).to_be()).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.read::<u32>(ACCESS).unwrap(), 0);
+    let mut initrd = [0; 101];
+    machine.read_bytes(BUFFER, &mut initrd).unwrap();
+    assert!(initrd[..100].iter().all(|&byte| byte == 0xAA) && initrd[100] == 0);
+    // An access at the end of the address space is an error, not an overflow
+    let last = u64::MAX - 7;
+    machine.vm.mem.register(last - 8, 15, None, DRAM::new(15)).unwrap();
+    machine.write(last, DMA_READ.to_be()).unwrap();
+    machine.write(DMA, last.to_be()).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(u32::from_be(machine.read(last).unwrap()), DMA_ERROR);
+}
+
+#[test]
+pub fn options() {
+    assert_eq!(parse_fw_cfg("name=opt/a,string=hello"), Ok(FwCfgFile { name: "opt/a".into(), data: b"hello".to_vec() }));
+    assert!(parse_fw_cfg("name=opt/a").is_err());
+    assert!(parse_fw_cfg("name=opt/a,file=/nonexistent").is_err());
+    assert!(FwCfg::new(0, 1).add_file(&"x".repeat(56), Vec::new()).is_err());
+
+    let board = Board::load("virt,fw-cfg=on,rtc=on").unwrap();
+    assert_eq!(board.device(DeviceKind::FwCfg).map(|device| device.base), Some(MemMap::FwCfg.base()));
+    assert!(board.device(DeviceKind::Rtc).is_some());
+    assert!(Board::load("virt,rtc=maybe").is_err());
+    let dtb = Machine::builder().board(board).build().unwrap().device_tree("");
+    assert!(dtb.windows(17).any(|window| window == b"qemu,fw-cfg-mmio\0"));
+}
@@ -0,0 +1,215 @@
+mod common;
+
+use common::{descriptor, driver_init, make_available, notify, used, virtio_machine, BASE, DESC_NEXT, DESC_WRITE, ENTRIES, TRANSPORT};
+use emulator::image::{Format, Frame};
+use emulator::machine::Machine;
+use emulator::monitor::Command;
+use emulator::net::{Loopback, DEFAULT_MAC};
+use emulator::virtio::gpu::*;
+use emulator::virtio::mmio::*;
+use emulator::virtio::net::VirtioNet;
+
+/// Pages of the control and cursor queues
+const CONTROL_PAGE: u64 = BASE + 0x1_0000;
+const CURSOR_PAGE: u64 = BASE + 0x2_0000;
+const REQUEST: u64 = BASE + 0x3_0000;
+const RESPONSE: u64 = BASE + 0x3_1000;
+/// Pixels of the guest framebuffer, in two parts
+const BACKING: [u64; 2] = [BASE + 0x4_0000, BASE + 0x5_0000];
+
+fn machine(gpu: VirtioGpu) -> Machine {
+    let mut machine = virtio_machine(gpu);
+    driver_init(&mut machine, 0, &[(CONTROL, CONTROL_PAGE), (CURSOR, CURSOR_PAGE)]);
+    machine
+}
+fn gpu(machine: &mut Machine) -> &mut VirtioGpu {
+    machine.vm.virtio_device_mut(0).unwrap()
+}
+/// A request with the header of `kind`, then `body` in little endian words
+fn request(kind: u32, body: &[u32]) -> Vec<u8> {
+    let mut request = vec![0; HEADER];
+    request[..4].copy_from_slice(&kind.to_le_bytes());
+    request.extend(body.iter().flat_map(|word| word.to_le_bytes()));
+    request
+}
+/// Sends `request` on `queue` in a chain of a readable and a writable buffer, returns the response
+fn send(machine: &mut Machine, queue: usize, request: &[u8]) -> Vec<u8> {
+    let page = if queue == CONTROL {CONTROL_PAGE} else {CURSOR_PAGE};
+    machine.write_bytes(REQUEST, request).unwrap();
+    machine.write_bytes(RESPONSE, &[0xAA; 512]).unwrap();
+    let count = used(machine, page).len();
+    let head = (count as u64 * 2 % ENTRIES) as u16;
+    descriptor(machine, page, head, REQUEST, request.len() as u32, DESC_NEXT);
+    descriptor(machine, page, head + 1, RESPONSE, 512, DESC_WRITE);
+    make_available(machine, page, head);
+    notify(machine, queue);
+    let used = used(machine, page);
+    assert_eq!(used.len(), count + 1);
+    let (_, len) = used[count];
+    let mut response = vec![0; len as usize];
+    machine.read_bytes(RESPONSE, &mut response).unwrap();
+    response
+}
+/// Type of the response to `kind` with `body`
+fn status(machine: &mut Machine, kind: u32, body: &[u32]) -> u32 {
+    let response = send(machine, CONTROL, &request(kind, body));
+    u32::from_le_bytes(response[..4].try_into().unwrap())
+}
+/// A 4x2 B8G8R8X8 resource on the scanout, its rows are red, green, blue, white then black, grey, yellow, cyan
+fn framebuffer(machine: &mut Machine) {
+    assert_eq!(status(machine, CMD_RESOURCE_CREATE_2D, &[1, FORMAT_B8G8R8X8, 4, 2]), RESP_OK_NODATA);
+    let pixels: Vec<u8> = [
+        [0, 0, 255, 0], [0, 255, 0, 0], [255, 0, 0, 0], [255, 255, 255, 0],
+        [0, 0, 0, 0], [128, 128, 128, 0], [0, 255, 255, 0], [255, 255, 0, 0],
+    ].concat();
+    // The first row and a half in one part, the rest in the other
+    machine.write_bytes(BACKING[0], &pixels[..24]).unwrap();
+    machine.write_bytes(BACKING[1], &pixels[24..]).unwrap();
+    let entries = [BACKING[0] as u32, 0, 24, 0, BACKING[1] as u32, 0, 8, 0];
+    assert_eq!(status(machine, CMD_RESOURCE_ATTACH_BACKING, &[[1, 2].as_slice(), &entries].concat()), RESP_OK_NODATA);
+    assert_eq!(status(machine, CMD_TRANSFER_TO_HOST_2D, &[0, 0, 4, 2, 0, 0, 1, 0]), RESP_OK_NODATA);
+    assert_eq!(status(machine, CMD_SET_SCANOUT, &[0, 0, 4, 2, 0, 1]), RESP_OK_NODATA);
+}
+const COLOURS: [[u8; 3]; 8] = [
+    [255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255],
+    [0, 0, 0], [128, 128, 128], [255, 255, 0], [0, 255, 255],
+];
+
+#[test]
+pub fn display_info() {
+    let mut machine = machine(VirtioGpu::new(640, 480));
+    assert_eq!(machine.read::<u32>(TRANSPORT + DEVICE_ID).unwrap(), GPU_ID);
+    // num_scanouts
+    assert_eq!(machine.read::<u32>(TRANSPORT + CONFIG + 8).unwrap(), 1);
+    let mut fenced = request(CMD_GET_DISPLAY_INFO, &[]);
+    fenced[4] = FLAG_FENCE as u8;
+    fenced[8..16].copy_from_slice(&42u64.to_le_bytes());
+    let response = send(&mut machine, CONTROL, &fenced);
+    assert_eq!(response.len(), HEADER + 16 * 24);
+    let word = |offset: usize| u32::from_le_bytes(response[offset..offset + 4].try_into().unwrap());
+    assert_eq!((word(0), word(4), word(8)), (RESP_OK_DISPLAY_INFO, FLAG_FENCE, 42));
+    assert_eq!((word(HEADER + 8), word(HEADER + 12), word(HEADER + 16)), (640, 480, 1));
+    assert_eq!(word(HEADER + 24 + 16), 0, "only one scanout is enabled");
+
+    // A new size is a display event
+    gpu(&mut machine).resize(800, 600);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.read::<u32>(TRANSPORT + CONFIG).unwrap(), EVENT_DISPLAY);
+    assert_eq!(machine.read::<u32>(TRANSPORT + INTERRUPT_STATUS).unwrap() & 2, 2);
+    machine.write(TRANSPORT + CONFIG + 4, EVENT_DISPLAY).unwrap();
+    assert_eq!(machine.read::<u32>(TRANSPORT + CONFIG).unwrap(), 0);
+}
+
+#[test]
+pub fn framebuffer_frames() {
+    let dir = std::env::temp_dir().join(format!("gpu-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut machine = machine(VirtioGpu::new(4, 2).with_frame_dump(dir.clone(), Format::Ppm));
+    framebuffer(&mut machine);
+    let frame = gpu(&mut machine).screendump().unwrap();
+    assert_eq!((frame.width, frame.height), (4, 2));
+    assert_eq!(frame.rgb, COLOURS.concat());
+
+    // Each flush of the scanout is a frame
+    assert_eq!(status(&mut machine, CMD_RESOURCE_FLUSH, &[0, 0, 4, 2, 1, 0]), RESP_OK_NODATA);
+    assert_eq!(status(&mut machine, CMD_RESOURCE_FLUSH, &[0, 0, 2, 1, 1, 0]), RESP_OK_NODATA);
+    assert_eq!(gpu(&mut machine).flushes, 2);
+    let ppm = std::fs::read(dir.join("frame-00000.ppm")).unwrap();
+    assert_eq!(ppm, [b"P6\n4 2\n255\n".as_slice(), &COLOURS.concat()].concat());
+    assert!(dir.join("frame-00001.ppm").exists());
+
+    // Part of the framebuffer again, from an offset in the backing
+    machine.write_bytes(BACKING[1] + 4, &[0x10, 0x20, 0x30, 0]).unwrap();
+    assert_eq!(status(&mut machine, CMD_TRANSFER_TO_HOST_2D, &[3, 1, 1, 1, 28, 0, 1, 0]), RESP_OK_NODATA);
+    assert_eq!(gpu(&mut machine).screendump().unwrap().pixel(3, 1), [0x30, 0x20, 0x10]);
+
+    // A part of the resource on the scanout
+    assert_eq!(status(&mut machine, CMD_SET_SCANOUT, &[1, 1, 2, 1, 0, 1]), RESP_OK_NODATA);
+    assert_eq!(gpu(&mut machine).screendump().unwrap().rgb, [COLOURS[5], COLOURS[6]].concat());
+    assert_eq!(status(&mut machine, CMD_SET_SCANOUT, &[0, 0, 0, 0, 0, 0]), RESP_OK_NODATA);
+    assert_eq!(gpu(&mut machine).screendump(), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+pub fn png() {
+    let mut frame = Frame::new(2, 2);
+    frame.set_pixel(1, 0, [1, 2, 3]);
+    frame.set_pixel(0, 1, [250, 251, 252]);
+    let png = frame.png();
+    assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+    assert_eq!(png[12..16], *b"IHDR");
+    assert_eq!(png[16..29], [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+    // CRC of the header chunk
+    assert_eq!(png[29..33], emulator::image::crc32(&png[12..29]).to_be_bytes());
+    assert_eq!(png[37..41], *b"IDAT");
+    let len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+    let rows = miniz_oxide::inflate::decompress_to_vec_zlib(&png[41..41 + len]).unwrap();
+    assert_eq!(rows, [0, 0, 0, 0, 1, 2, 3, 0, 250, 251, 252, 0, 0, 0]);
+    assert_eq!(png[png.len() - 8..png.len() - 4], *b"IEND");
+}
+
+#[test]
+pub fn errors() {
+    let mut machine = machine(VirtioGpu::new(4, 2));
+    assert_eq!(status(&mut machine, CMD_RESOURCE_CREATE_2D, &[0, FORMAT_B8G8R8X8, 4, 2]), RESP_ERR_INVALID_RESOURCE_ID);
+    assert_eq!(status(&mut machine, CMD_RESOURCE_CREATE_2D, &[1, 1000, 4, 2]), RESP_ERR_INVALID_PARAMETER);
+    assert_eq!(status(&mut machine, CMD_RESOURCE_CREATE_2D, &[1, FORMAT_B8G8R8X8, 1 << 16, 1 << 16]), RESP_ERR_OUT_OF_MEMORY);
+    assert_eq!(status(&mut machine, CMD_RESOURCE_CREATE_2D, &[1, FORMAT_B8G8R8X8, 4, 2]), RESP_OK_NODATA);
+    assert_eq!(status(&mut machine, CMD_RESOURCE_CREATE_2D, &[1, FORMAT_B8G8R8X8, 4, 2]), RESP_ERR_INVALID_RESOURCE_ID);
+    // No backing yet
+    assert_eq!(status(&mut machine, CMD_TRANSFER_TO_HOST_2D, &[0, 0, 4, 2, 0, 0, 1, 0]), RESP_ERR_UNSPEC);
+    assert_eq!(status(&mut machine, CMD_TRANSFER_TO_HOST_2D, &[0, 0, 4, 2, 0, 0, 2, 0]), RESP_ERR_INVALID_RESOURCE_ID);
+    assert_eq!(status(&mut machine, CMD_SET_SCANOUT, &[0, 0, 4, 2, 1, 1]), RESP_ERR_INVALID_SCANOUT_ID);
+    assert_eq!(status(&mut machine, CMD_SET_SCANOUT, &[0, 0, 5, 2, 0, 1]), RESP_ERR_INVALID_PARAMETER);
+    assert_eq!(status(&mut machine, CMD_RESOURCE_FLUSH, &[2, 0, 4, 2, 1, 0]), RESP_ERR_INVALID_PARAMETER);
+    // GET_EDID, the feature isn't offered
+    assert_eq!(status(&mut machine, 0x010A, &[]), RESP_ERR_UNSPEC);
+    assert_eq!(status(&mut machine, CMD_RESOURCE_UNREF, &[1, 0]), RESP_OK_NODATA);
+    assert_eq!(status(&mut machine, CMD_RESOURCE_UNREF, &[1, 0]), RESP_ERR_INVALID_RESOURCE_ID);
+}
+
+#[test]
+pub fn cursor() {
+    let mut machine = machine(VirtioGpu::new(4, 2));
+    framebuffer(&mut machine);
+    // A 2x1 cursor, opaque magenta then half transparent black
+    assert_eq!(status(&mut machine, CMD_RESOURCE_CREATE_2D, &[2, FORMAT_R8G8B8A8, 2, 1]), RESP_OK_NODATA);
+    machine.write_bytes(BACKING[0] + 0x100, &[255, 0, 255, 255, 0, 0, 0, 128]).unwrap();
+    assert_eq!(status(&mut machine, CMD_RESOURCE_ATTACH_BACKING, &[2, 1, BACKING[0] as u32 + 0x100, 0, 8, 0]), RESP_OK_NODATA);
+    assert_eq!(status(&mut machine, CMD_TRANSFER_TO_HOST_2D, &[0, 0, 2, 1, 0, 0, 2, 0]), RESP_OK_NODATA);
+    // cursor_pos (scanout, x, y), resource, hot_x and hot_y
+    send(&mut machine, CURSOR, &request(CMD_UPDATE_CURSOR, &[0, 2, 1, 0, 2, 1, 0]));
+    assert_eq!(gpu(&mut machine).cursor(), Cursor { resource: 2, x: 2, y: 1, hot_x: 1, hot_y: 0 });
+    let frame = gpu(&mut machine).screendump().unwrap();
+    assert_eq!(frame.pixel(1, 1), [255, 0, 255]);
+    assert_eq!(frame.pixel(2, 1), [127, 127, 0], "half of yellow");
+    send(&mut machine, CURSOR, &request(CMD_MOVE_CURSOR, &[0, 1, 0]));
+    let frame = gpu(&mut machine).screendump().unwrap();
+    assert_eq!((frame.pixel(0, 0), frame.pixel(1, 1)), ([255, 0, 255], COLOURS[5]));
+}
+
+#[test]
+pub fn monitor() {
+    let mut machine = machine(VirtioGpu::new(4, 2));
+    framebuffer(&mut machine);
+    machine.vm.add_virtio_device(VirtioNet::new(DEFAULT_MAC, Loopback::default())).unwrap();
+    let path = std::env::temp_dir().join(format!("gpu-screendump-{}.png", std::process::id()));
+    let screendump: Command = format!("screendump {}", path.display()).parse().unwrap();
+    assert_eq!(screendump.execute(&mut machine.vm).unwrap(), format!("4x2 frame saved to {}", path.display()));
+    assert_eq!(std::fs::read(&path).unwrap(), Frame { width: 4, height: 2, rgb: COLOURS.concat() }.png());
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!("set_link net0 off".parse(), Ok(Command::SetLink(0, false)));
+    "set_link net0 off".parse::<Command>().unwrap().execute(&mut machine.vm).unwrap();
+    assert!(!machine.vm.virtio_device_mut::<VirtioNet>(0).unwrap().link());
+    assert!("set_link net1 off".parse::<Command>().unwrap().execute(&mut machine.vm).is_err());
+    assert!("set_link eth0 off".parse::<Command>().is_err());
+    assert!("screendump".parse::<Command>().is_err());
+    assert_eq!("quit".parse(), Ok(Command::Quit));
+
+    let config: GpuConfig = "xres=320,yres=200,frames=out,format=ppm".parse().unwrap();
+    assert_eq!((config.width, config.height, config.frames, config.format), (320, 200, Some("out".into()), Format::Ppm));
+    assert_eq!("".parse::<GpuConfig>().unwrap(), GpuConfig::default());
+    assert!("xres=0".parse::<GpuConfig>().is_err());
+}
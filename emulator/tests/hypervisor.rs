@@ -0,0 +1,352 @@
+mod common;
+
+use common::{builder, traps, BASE};
+use emulator::asm::assemble;
+use emulator::board::Board;
+use emulator::cpu::csr::file::*;
+use emulator::cpu::isa;
+use emulator::cpu::reg::Reg;
+use emulator::cpu::PrivilegeLevel;
+use emulator::machine::Machine;
+use emulator::vm::disasm;
+
+const DATA: u64 = BASE + 0x1_0000;
+/// hgatp's root page table is 4 pages, aligned to its size
+const G_ROOT: u64 = BASE + 0x4000;
+const ROOT: u64 = BASE + 0x8000;
+const H: &str = "rv64imach";
+/// Sv39, and Sv39x4 in hgatp
+const SV39: u64 = 8 << 60;
+// Page table entry bits
+const V: u64 = 1 << 0;
+const R: u64 = 1 << 1;
+const W: u64 = 1 << 2;
+const X: u64 = 1 << 3;
+const U: u64 = 1 << 4;
+const A: u64 = 1 << 6;
+const D: u64 = 1 << 7;
+/// Encodings from the H instruction listing of the privileged specification
+const ENCODINGS: [(&str, u32); 7] = [
+    ("hlv.b a0 (a1)", 0x6005c573),
+    ("hlv.hu t0 (t1)", 0x641342f3),
+    ("hlvx.wu s0 (s1)", 0x6834c473),
+    ("hlv.d a0 (a1)", 0x6c05c573),
+    ("hsv.w t2 (a1)", 0x6a75c073),
+    ("hfence.vvma a0 a1", 0x22b50073),
+    ("hfence.gvma zero zero", 0x62000073),
+];
+
+fn words(program: &[u8]) -> Vec<u32> {
+    program.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect()
+}
+/// Without PMP entries, S-mode and U-mode reach all of memory
+fn machine(isa: &str, source: &str) -> Machine {
+    let mut machine = builder(Board::virt(), source).isa(isa).build().unwrap();
+    machine.hart_mut(0).pmp_entries = 0;
+    machine
+}
+fn pte(addr: u64, flags: u64) -> u64 {
+    (addr >> 12) << 10 | V | flags
+}
+/// Index of a 1GiB page in the root table
+fn gigapage(addr: u64) -> u64 {
+    (addr >> 30) * 8
+}
+/// Guest physical memory has the RAM at its address (without A and D) and a read-only alias at 0x4000_0000
+/// Guest virtual memory has the RAM at its address for the code, and a read/write alias at 0x4000_0000 without A and D,
+/// 0xC000_0000 is mapped by the guest to a guest physical address with nothing behind it
+fn map_guest(machine: &mut Machine) {
+    machine.write(G_ROOT + gigapage(BASE), pte(BASE, R | W | X | U)).unwrap();
+    machine.write(G_ROOT + gigapage(0x4000_0000), pte(BASE, R | U | A)).unwrap();
+    machine.write(ROOT + gigapage(BASE), pte(BASE, R | W | X | A | D)).unwrap();
+    machine.write(ROOT + gigapage(0x4000_0000), pte(BASE, R | W)).unwrap();
+    machine.write(ROOT + gigapage(0xC000_0000), pte(0xC000_0000, R | W | A | D)).unwrap();
+    machine.set_csr(0, HGATP, SV39 | G_ROOT >> 12);
+    machine.set_csr(0, VSATP, SV39 | ROOT >> 12);
+}
+/// The program starts with an mret to VS-mode
+fn enter_guest(machine: &mut Machine) {
+    machine.set_csr(0, MSTATUS, 1 << 11 | 1 << 39); // MPP = S, MPV
+    machine.set_csr(0, MEPC, BASE + 4);
+}
+
+#[test]
+pub fn encodings() {
+    for (text, encoding) in ENCODINGS {
+        let program = assemble(text, 0).unwrap();
+        assert_eq!(words(&program), [encoding], "{text}");
+        assert_eq!(disasm(program).unwrap(), format!("{text}\n"));
+    }
+    assert_eq!(words(&assemble("sfence.vma a0", 0).unwrap()), [0x12050073]);
+    for invalid in ["hlv.d a0 8(a1)", "hsv.b a0", "hlv.q a0 (a1)", "hfence.gvma a0 a1 a2"] {
+        assert!(assemble(invalid, 0).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+pub fn disabled() {
+    assert_eq!(isa::parse(H).unwrap() & isa::bit('h'), isa::bit('h'));
+    assert!(isa::parse("rv64imahc").is_err());
+    for source in ["hlv.d a0 (a1)", "hfence.gvma", "csrrs a0 hstatus zero", "csrrs a0 vsatp zero"] {
+        let mut machine = machine("rv64imac", source);
+        let traps = traps(&mut machine);
+        machine.run(Some(1)).unwrap();
+        assert_eq!(traps.borrow()[0].0, 2, "{source}");
+    }
+    // The hypervisor bits of the M-level CSRs stay clear
+    let mut machine = machine("rv64imac", "");
+    machine.set_csr(0, MEDELEG, u64::MAX);
+    machine.set_csr(0, MSTATUS, 1 << 39 | 1 << 38);
+    assert_eq!(machine.csr(0, MEDELEG), 0xB3FF);
+    assert_eq!(machine.csr(0, MIDELEG), 0);
+    assert_eq!(machine.csr(0, MSTATUS) >> 38 & 0b11, 0);
+}
+
+#[test]
+pub fn registers() {
+    let mut machine = machine(H, "");
+    machine.set_csr(0, HSTATUS, u64::MAX);
+    // VSXL is 64 bits, there is no guest external interrupt
+    assert_eq!(machine.csr(0, HSTATUS), 0x2_0070_03C0);
+    machine.set_csr(0, MEDELEG, u64::MAX);
+    machine.set_csr(0, HEDELEG, u64::MAX);
+    assert_eq!(machine.csr(0, MEDELEG), 0xF0_B7FF);
+    assert_eq!(machine.csr(0, HEDELEG), 0xB1FF);
+    // VS interrupts are always delegated to HS-mode
+    assert_eq!(machine.csr(0, MIDELEG), 0x444);
+    machine.set_csr(0, HIDELEG, u64::MAX);
+    assert_eq!(machine.csr(0, HIDELEG), 0x444);
+    machine.set_csr(0, HGATP, SV39 | 0xFFFF_FFFF);
+    assert_eq!(machine.csr(0, HGATP), SV39 | 0xFFFF_FFFC);
+    machine.set_csr(0, HGATP, 9 << 60);
+    assert_eq!(machine.csr(0, HGATP), SV39 | 0xFFFF_FFFC);
+    machine.set_csr(0, HGEIE, u64::MAX);
+    assert_eq!(machine.csr(0, HGEIE), 0);
+    // hvip drives the VS bits of mip, guests see them at the S bits when delegated
+    machine.set_csr(0, HVIP, u64::MAX);
+    assert_eq!(machine.csr(0, MIP), 0x444);
+    assert_eq!(machine.csr(0, VSIP), 0x222);
+    machine.set_csr(0, HIDELEG, 1 << 2);
+    assert_eq!(machine.csr(0, VSIP), 0x2);
+    machine.set_csr(0, VSIE, u64::MAX);
+    assert_eq!(machine.csr(0, HIE), 0x4);
+    assert_eq!(machine.csr(0, VSSTATUS), 0x2_0000_0000);
+}
+
+#[test]
+pub fn supervisor_translation() {
+    let mut machine = machine(H, "
+        mret
+        ld a0, 0(s0)
+        sd a0, 8(s0)
+    ");
+    let traps = traps(&mut machine);
+    machine.write(ROOT + gigapage(BASE), pte(BASE, R | W | X | A | D)).unwrap();
+    machine.write(ROOT + gigapage(0x4000_0000), pte(BASE, R)).unwrap();
+    machine.set_csr(0, SATP, SV39 | ROOT >> 12);
+    machine.set_csr(0, MSTATUS, 1 << 11);
+    machine.set_csr(0, MEPC, BASE + 4);
+    machine.set_reg(0, Reg::s0, 0x4001_0000);
+    machine.write(DATA, 42u64).unwrap();
+    machine.run(Some(3)).unwrap();
+    assert_eq!(machine.reg(0, Reg::a0), 42);
+    // Read-only page
+    assert_eq!(*traps.borrow(), [(15, 0x4001_0008)]);
+    assert_eq!(machine.read::<u64>(ROOT + gigapage(0x4000_0000)).unwrap(), pte(BASE, R | A));
+    assert!(!machine.hart(0).virt);
+}
+
+#[test]
+pub fn two_stage_translation() {
+    let mut machine = machine(H, "
+        mret
+        ld a0, 0(s0)
+        sd a0, 8(s0)
+        ld a1, 0(s1)
+    ");
+    let traps = traps(&mut machine);
+    map_guest(&mut machine);
+    enter_guest(&mut machine);
+    machine.set_reg(0, Reg::s0, 0x4001_0000);
+    machine.set_reg(0, Reg::s1, 0xC000_0010);
+    machine.write(DATA, 42u64).unwrap();
+    machine.run(Some(3)).unwrap();
+    assert!(machine.hart(0).virt);
+    assert_eq!(machine.privilege(0), PrivilegeLevel::Supervisor);
+    assert_eq!(machine.reg(0, Reg::a0), 42);
+    assert_eq!(machine.read::<u64>(DATA + 8).unwrap(), 42);
+    // Both stages had their accessed and dirty bits set
+    assert_eq!(machine.read::<u64>(ROOT + gigapage(0x4000_0000)).unwrap(), pte(BASE, R | W | A | D));
+    assert_eq!(machine.read::<u64>(G_ROOT + gigapage(BASE)).unwrap(), pte(BASE, R | W | X | U | A | D));
+    // The guest maps 0xC000_0000 to a guest physical address the hypervisor didn't map
+    machine.run(Some(1)).unwrap();
+    assert_eq!(*traps.borrow(), [(21, 0xC000_0010)]);
+    assert_eq!(machine.csr(0, MTVAL2), 0xC000_0010 >> 2);
+    let mstatus = machine.csr(0, MSTATUS);
+    assert_eq!(mstatus >> 38 & 0b11, 0b11, "MPV and GVA");
+    assert_eq!(mstatus >> 11 & 0b11, 1);
+    assert!(!machine.hart(0).virt);
+    assert_eq!(machine.privilege(0), PrivilegeLevel::Machine);
+}
+
+#[test]
+pub fn trap_routing() {
+    let mut machine = machine(H, "
+        mret
+        csrrs a0, mstatus, zero
+        ecall
+        csrrw zero, sepc, s2
+        sret
+        csrrs a1, hstatus, zero
+    ");
+    let traps = traps(&mut machine);
+    enter_guest(&mut machine);
+    // Illegal instructions go to the guest, its ecalls and virtual instructions to HS-mode
+    machine.set_csr(0, MEDELEG, 1 << 2 | 1 << 10 | 1 << 22);
+    machine.set_csr(0, HEDELEG, 1 << 2);
+    machine.set_csr(0, VSTVEC, BASE + 8);
+    machine.set_csr(0, STVEC, BASE + 12);
+    machine.set_reg(0, Reg::s2, BASE + 20);
+    machine.run(Some(2)).unwrap();
+    assert_eq!((machine.csr(0, VSCAUSE), machine.csr(0, VSEPC)), (2, BASE + 4));
+    assert_eq!(machine.csr(0, VSTVAL), words(&assemble("csrrs a0 mstatus zero", 0).unwrap())[0] as u64);
+    assert_eq!(machine.csr(0, VSSTATUS) >> 8 & 1, 1, "SPP");
+    assert_eq!(machine.csr(0, SCAUSE), 0);
+    assert!(machine.hart(0).virt);
+    machine.run(Some(1)).unwrap();
+    assert_eq!((machine.csr(0, SCAUSE), machine.csr(0, SEPC)), (10, BASE + 8));
+    assert_eq!(machine.csr(0, HSTATUS) >> 7 & 0b11, 0b11, "SPV and SPVP");
+    assert!(!machine.hart(0).virt);
+    assert_eq!(machine.privilege(0), PrivilegeLevel::Supervisor);
+    // sret goes back to the guest
+    machine.run(Some(2)).unwrap();
+    assert!(machine.hart(0).virt);
+    assert_eq!(machine.csr(0, HSTATUS) >> 7 & 1, 0);
+    machine.run(Some(1)).unwrap();
+    let hstatus = words(&assemble("csrrs a1 hstatus zero", 0).unwrap())[0] as u64;
+    assert_eq!((machine.csr(0, SCAUSE), machine.csr(0, STVAL)), (22, hstatus));
+    assert_eq!(*traps.borrow(), [(2, words(&assemble("csrrs a0 mstatus zero", 0).unwrap())[0] as u64), (10, 0), (22, hstatus)]);
+}
+
+#[test]
+pub fn guest_interrupts() {
+    let mut machine = machine(H, "
+        mret
+        nop
+        nop
+    ");
+    let traps = traps(&mut machine);
+    enter_guest(&mut machine);
+    machine.set_csr(0, VSTVEC, BASE + 8);
+    machine.set_csr(0, VSSTATUS, 1 << 1); // SIE
+    machine.set_csr(0, HIDELEG, 1 << 2);
+    machine.set_csr(0, HIE, 1 << 2);
+    machine.set_csr(0, HVIP, 1 << 2);
+    machine.run(Some(2)).unwrap();
+    // Taken in the guest as its supervisor software interrupt
+    assert_eq!(*traps.borrow(), [(1 << 63 | 1, 0)]);
+    assert_eq!(machine.csr(0, VSEPC), BASE + 4);
+    assert_eq!(machine.csr(0, VSSTATUS) & 0b10_0010, 0b10_0000, "SPIE = SIE and SIE = 0");
+    assert!(machine.hart(0).virt);
+    assert_eq!(machine.hart(0).pc, BASE + 12);
+}
+
+#[test]
+pub fn hypervisor_interrupts() {
+    // Not delegated to the guest it interrupts it for HS-mode, whatever sstatus.SIE
+    let mut hypervisor = machine(H, "
+        mret
+        nop
+    ");
+    let hypervisor_traps = traps(&mut hypervisor);
+    enter_guest(&mut hypervisor);
+    hypervisor.set_csr(0, STVEC, BASE + 4);
+    hypervisor.set_csr(0, HIE, 1 << 2);
+    hypervisor.set_csr(0, HVIP, 1 << 2);
+    hypervisor.run(Some(2)).unwrap();
+    assert_eq!(*hypervisor_traps.borrow(), [(1 << 63 | 2, 0)]);
+    assert!(!hypervisor.hart(0).virt);
+    assert_eq!(hypervisor.csr(0, HSTATUS) >> 7 & 1, 1, "SPV");
+}
+
+#[test]
+pub fn hypervisor_loads_and_stores() {
+    let mut machine = machine(H, "
+        hlv.d a0, (s0)
+        hsv.w a0, (s1)
+        hlv.d a1, (s2)
+        hlvx.wu a2, (s0)
+        hlvx.wu a3, (s2)
+    ");
+    let traps = traps(&mut machine);
+    map_guest(&mut machine);
+    // Guest physical addresses, VS-stage is off
+    machine.set_csr(0, VSATP, 0);
+    machine.set_reg(0, Reg::s0, DATA);
+    machine.set_reg(0, Reg::s1, DATA + 8);
+    machine.set_reg(0, Reg::s2, 0x4001_0000);
+    machine.write(DATA, 0xFFFF_FFFF_0000_002Au64).unwrap();
+    machine.run(Some(5)).unwrap();
+    assert_eq!(machine.reg(0, Reg::a0), 0xFFFF_FFFF_0000_002A);
+    assert_eq!(machine.read::<u64>(DATA + 8).unwrap(), 42);
+    assert_eq!(machine.reg(0, Reg::a1), 0xFFFF_FFFF_0000_002A);
+    assert_eq!(machine.reg(0, Reg::a2), 42);
+    // The alias isn't executable
+    assert_eq!(*traps.borrow(), [(21, 0x4001_0000)]);
+    assert_eq!(machine.csr(0, MTVAL2), 0x4001_0000 >> 2);
+    // The hart wasn't in a guest, but tval is a guest address
+    assert_eq!(machine.csr(0, MSTATUS) >> 38 & 0b11, 0b01, "GVA without MPV");
+}
+
+#[test]
+pub fn user_hypervisor_loads() {
+    // U-mode needs hstatus.HU
+    for (hu, cause) in [(0, 2), (1 << 9, 13)] {
+        let mut user = machine(H, "
+            mret
+            hlv.b a0, (zero)
+        ");
+        let user_traps = traps(&mut user);
+        user.set_csr(0, MEPC, BASE + 4);
+        user.set_csr(0, HSTATUS, hu);
+        user.set_csr(0, VSATP, SV39 | ROOT >> 12);
+        user.run(Some(2)).unwrap();
+        assert_eq!(user_traps.borrow()[0].0, cause);
+    }
+}
+
+#[test]
+pub fn virtual_instructions() {
+    const VTVM: u64 = 1 << 20;
+    const VTW: u64 = 1 << 21;
+    const VTSR: u64 = 1 << 22;
+    let cases = [
+        ("wfi", VTW, 22), ("wfi", 0, 0), ("sret", VTSR, 22), ("sfence.vma", VTVM, 22), ("sfence.vma", 0, 0),
+        ("csrrw zero satp zero", VTVM, 22), ("hfence.gvma", 0, 22), ("hlv.d a0 (a1)", 0, 22),
+        ("csrrs a0 hstatus zero", 0, 22), ("csrrs a0 vsstatus zero", 0, 22), ("csrrs a0 mstatus zero", 0, 2),
+        ("mret", 0, 2), ("ecall", 0, 10),
+    ];
+    for (source, hstatus, cause) in cases {
+        let mut machine = machine(H, &format!("mret\n{source}"));
+        let traps = traps(&mut machine);
+        enter_guest(&mut machine);
+        machine.set_csr(0, HSTATUS, hstatus);
+        machine.run(Some(2)).unwrap();
+        let tval = if cause == 10 {0} else {words(&assemble(source, 0).unwrap())[0] as u64};
+        let expected = if cause == 0 {vec![]} else {vec![(cause, tval)]};
+        assert_eq!(*traps.borrow(), expected, "{source}");
+    }
+    // Guests access vsatp and vsstatus in place of satp and sstatus
+    let mut guest = machine(H, "
+        mret
+        csrrs a0, sstatus, zero
+        csrrw zero, satp, s0
+    ");
+    enter_guest(&mut guest);
+    guest.set_reg(0, Reg::s0, SV39 | 0x1234);
+    guest.set_csr(0, VSSTATUS, 1 << 8);
+    guest.run(Some(3)).unwrap();
+    assert_eq!(guest.csr(0, VSATP), SV39 | 0x1234);
+    assert_eq!(guest.csr(0, SATP), 0);
+    assert_eq!(guest.reg(0, Reg::a0), 0x2_0000_0100);
+}
@@ -0,0 +1,265 @@
+use emulator::cpu::compressed::expand;
+use emulator::cpu::csr::file::*;
+use emulator::cpu::PrivilegeLevel;
+use emulator::vm::VM;
+
+const BASE: u64 = 0x8000_0000;
+const MTVEC_ADDR: u64 = 0x8000_1000;
+
+fn vm_with(program: &[u32]) -> VM {
+    let bytes = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let mut vm = VM::new(bytes, 1 << 20).unwrap();
+    vm.cpu.set_csr_value(MTVEC, MTVEC_ADDR);
+    vm
+}
+fn run(vm: &mut VM, steps: usize) {
+    for _ in 0..steps {
+        assert!(vm.step().unwrap());
+    }
+}
+fn run_program(program: &[u32]) -> VM {
+    let mut vm = vm_with(program);
+    run(&mut vm, program.len());
+    assert_eq!(vm.cpu.csr_value(MCAUSE), 0, "Unexpected trap at {:#x}", vm.cpu.csr_value(MEPC));
+    vm
+}
+
+#[test]
+pub fn integer_instructions() {
+    let vm = run_program(&[
+        0xfff00513, // li a0, -1
+        0x00052593, // slti a1, a0, 0
+        0x00053613, // sltiu a2, a0, 0
+        0x03c55693, // srli a3, a0, 60
+        0x42855713, // srai a4, a0, 40
+        0x800002b7, // lui t0, 0x80000
+        0xfff2831b, // addiw t1, t0, -1
+        0x4042d39b, // sraiw t2, t0, 4
+        0x0042de1b, // srliw t3, t0, 4
+        0x00300e93, // li t4, 3
+        0x03ee9f13, // slli t5, t4, 62
+        0x41df5fb3, // sra t6, t5, t4
+        0x01df5433, // srl s0, t5, t4
+        0x01df24b3, // slt s1, t5, t4
+        0x01df3933, // sltu s2, t5, t4
+        0x00de99bb, // sllw s3, t4, a3
+        0x41d2da3b, // sraw s4, t0, t4
+        0x01d30abb, // addw s5, t1, t4
+        0x41d28b3b, // subw s6, t0, t4
+        0xfffffb97, // auipc s7, 0xfffff
+        0x41d00c33, // sub s8, zero, t4
+    ]);
+    let regs = vm.cpu.regs;
+    assert_eq!(regs[10], u64::MAX);
+    assert_eq!((regs[11], regs[12], regs[13], regs[14]), (1, 0, 0xF, u64::MAX));
+    assert_eq!(regs[5], 0xFFFF_FFFF_8000_0000);
+    assert_eq!(regs[6], 0x7FFF_FFFF);
+    assert_eq!(regs[7], 0xFFFF_FFFF_F800_0000);
+    assert_eq!(regs[28], 0x0800_0000);
+    assert_eq!(regs[30], 0xC000_0000_0000_0000);
+    assert_eq!(regs[31], 0xF800_0000_0000_0000);
+    assert_eq!(regs[8], 0x1800_0000_0000_0000);
+    assert_eq!((regs[9], regs[18]), (1, 0));
+    assert_eq!(regs[19], 0x18000);
+    assert_eq!(regs[20], 0xFFFF_FFFF_F000_0000);
+    assert_eq!(regs[21], 0xFFFF_FFFF_8000_0002);
+    assert_eq!(regs[22], 0x7FFF_FFFD);
+    assert_eq!(regs[23], BASE + 19*4 - 0x1000);
+    assert_eq!(regs[24], -3i64 as u64);
+}
+
+#[test]
+pub fn multiplication_and_division() {
+    let vm = run_program(&[
+        0xff900513, // li a0, -7
+        0x00200593, // li a1, 2
+        0x00100293, // li t0, 1
+        0x03f29293, // slli t0, t0, 63
+        0xfff00313, // li t1, -1
+        0x02b50633, // mul a2, a0, a1
+        0x02b516b3, // mulh a3, a0, a1
+        0x02b53733, // mulhu a4, a0, a1
+        0x02b527b3, // mulhsu a5, a0, a1
+        0x02b54833, // div a6, a0, a1
+        0x02b568b3, // rem a7, a0, a1
+        0x02055433, // divu s0, a0, zero
+        0x020564b3, // rem s1, a0, zero
+        0x0262c933, // div s2, t0, t1
+        0x0262e9b3, // rem s3, t0, t1
+        0x02b54a3b, // divw s4, a0, a1
+        0x02b57abb, // remuw s5, a0, a1
+        0x02b28b3b, // mulw s6, t0, a1
+        0x02055bbb, // divuw s7, a0, zero
+    ]);
+    let regs = vm.cpu.regs.map(|reg| reg as i64);
+    assert_eq!((regs[12], regs[13], regs[14], regs[15]), (-14, -1, 1, -1));
+    assert_eq!((regs[16], regs[17]), (-3, -1));
+    // Division by zero and overflow
+    assert_eq!((regs[8], regs[9]), (-1, -7));
+    assert_eq!((regs[18], regs[19]), (i64::MIN, 0));
+    assert_eq!((regs[20], regs[21], regs[22], regs[23]), (-3, 1, 0, -1));
+}
+
+#[test]
+pub fn atomics() {
+    let mut vm = vm_with(&[
+        0x00001417, // auipc s0, 1
+        0x00500513, // li a0, 5
+        0x00a43023, // sd a0, 0(s0)
+        0x100435af, // lr.d a1, (s0)
+        0x00158593, // addi a1, a1, 1
+        0x18b4362f, // sc.d a2, a1, (s0)
+        0x00043683, // ld a3, 0(s0)
+        0x18b4372f, // sc.d a4, a1, (s0)
+        0xffe00293, // li t0, -2
+        0x005427af, // amoadd.w a5, t0, (s0)
+        0x00042803, // lw a6, 0(s0)
+        0xe05438af, // amomaxu.d a7, t0, (s0)
+        0x00043483, // ld s1, 0(s0)
+        0x80a4292f, // amomin.w s2, a0, (s0)
+        0x00042983, // lw s3, 0(s0)
+        0x0ea43a2f, // amoswap.d.aqrl s4, a0, (s0)
+        0x00043a83, // ld s5, 0(s0)
+        0x00140313, // addi t1, s0, 1
+        0x4053302f, // amoor.d zero, t0, (t1)
+    ]);
+    run(&mut vm, 19);
+    let regs = vm.cpu.regs.map(|reg| reg as i64);
+    // The sc after a successful one fails, the reservation is gone
+    assert_eq!((regs[11], regs[12], regs[13], regs[14]), (6, 0, 6, 1));
+    assert_eq!((regs[15], regs[16]), (6, 4));
+    assert_eq!((regs[17], regs[9]), (4, -2));
+    assert_eq!((regs[18], regs[19]), (-2, -2));
+    assert_eq!((regs[20], regs[21]), (-2, 5));
+    // Misaligned AMOs trap
+    assert_eq!(vm.cpu.csr_value(MCAUSE), 6);
+    assert_eq!(vm.cpu.csr_value(MTVAL), 0x8000_1001);
+}
+
+#[test]
+pub fn compressed_expansion() {
+    for (compressed, expanded) in [
+        (0x1141, 0xff010113), // addi sp, sp, -16
+        (0x0028, 0x00810513), // addi a0, sp, 8
+        (0x414c, 0x00452583), // lw a1, 4(a0)
+        (0x7cf0, 0x0f84b603), // ld a2, 248(s1)
+        (0xdd6c, 0x06b52e23), // sw a1, 124(a0)
+        (0xe490, 0x00c4b423), // sd a2, 8(s1)
+        (0x1501, 0xfe050513), // addi a0, a0, -32
+        (0x25fd, 0x01f5859b), // addiw a1, a1, 31
+        (0x566d, 0xffb00613), // li a2, -5
+        (0x617d, 0x1f010113), // addi sp, sp, 496
+        (0x7685, 0xfffe16b7), // lui a3, 0xfffe1
+        (0x9005, 0x02145413), // srli s0, s0, 33
+        (0x97fd, 0x43f7d793), // srai a5, a5, 63
+        (0x98fd, 0xfff4f493), // andi s1, s1, -1
+        (0x8d0d, 0x40b50533), // sub a0, a0, a1
+        (0x8d2d, 0x00b54533), // xor a0, a0, a1
+        (0x8f5d, 0x00f76733), // or a4, a4, a5
+        (0x8c65, 0x00947433), // and s0, s0, s1
+        (0x9d0d, 0x40b5053b), // subw a0, a0, a1
+        (0x9d2d, 0x00b5053b), // addw a0, a0, a1
+        (0x128e, 0x02329293), // slli t0, t0, 35
+        (0x50fe, 0x0fc12083), // lw ra, 252(sp)
+        (0x7ffe, 0x1f813f83), // ld t6, 504(sp)
+        (0x8282, 0x00028067), // jr t0
+        (0x857e, 0x01f00533), // mv a0, t6
+        (0x9002, 0x00100073), // ebreak
+        (0x9682, 0x000680e7), // jalr a3
+        (0x994e, 0x01390933), // add s2, s2, s3
+        (0xdfa6, 0x0e912e23), // sw s1, 252(sp)
+        (0xffa6, 0x1e913c23), // sd s1, 504(sp)
+        (0x0001, 0x00000013), // nop
+        (0xb001, 0x801ff06f), // j -2048
+        (0xaffd, 0x7fe0006f), // j 2046
+        (0xa02d, 0x02a0006f), // j 42
+        (0xd101, 0xf00500e3), // beqz a0, -256
+        (0xecfd, 0x0e049f63), // bnez s1, 254
+        (0xe889, 0x00049963), // bnez s1, 18
+    ] {
+        assert_eq!(expand(compressed), Some(expanded), "{compressed:#06x}");
+    }
+    // Illegal, reserved or floating point
+    for compressed in [0x0000, 0x4002, 0x2002, 0x6101, 0x2001, 0x8002, 0x9c41] {
+        assert_eq!(expand(compressed), None, "{compressed:#06x}");
+    }
+}
+
+#[test]
+pub fn compressed_execution() {
+    let halfwords: [u16; 14] = [
+        0x4515, // li a0, 5
+        0x0593, 0x0645, // addi a1, a0, 100
+        0x952e, // add a0, a0, a1
+        0x0297, 0x0000, // auipc t0, 0
+        0x02b9, // addi t0, t0, 14
+        0x9282, // jalr t0
+        0x4605, // li a2, 1
+        0xc219, // beqz a2, 6
+        0x050a, // slli a0, a0, 2
+        0x0001, // nop
+        0x8082, // ret
+        0x0000,
+    ];
+    let bytes = halfwords.iter().flat_map(|half| half.to_le_bytes()).collect();
+    let mut vm = VM::new(bytes, 1 << 20).unwrap();
+    // li, addi, add, auipc, addi, jalr, nop, ret, li, beqz, slli, nop
+    run(&mut vm, 12);
+    assert_eq!(vm.cpu.regs[1], BASE + 0x10);
+    assert_eq!(vm.cpu.regs[10], 440);
+    assert_eq!(vm.cpu.pc, BASE + 0x18);
+    assert_eq!(vm.cpu.instret, 12);
+}
+
+#[test]
+pub fn illegal_instructions() {
+    // Unknown opcode, R instruction with an unknown fun7, the all-zero and a reserved compressed instruction
+    for (bytes, tval) in [
+        (vec![0xFF, 0xFF, 0xFF, 0xFF], 0xFFFF_FFFF),
+        (0x7e00_0033u32.to_le_bytes().to_vec(), 0x7e00_0033),
+        (vec![0x00, 0x00], 0),
+        (vec![0x02, 0x40], 0x4002),
+    ] {
+        let mut vm = VM::new(bytes, 1 << 20).unwrap();
+        vm.cpu.set_csr_value(MTVEC, MTVEC_ADDR);
+        run(&mut vm, 1);
+        assert_eq!(vm.cpu.csr_value(MCAUSE), 2);
+        assert_eq!(vm.cpu.csr_value(MTVAL), tval);
+        assert_eq!(vm.cpu.pc, MTVEC_ADDR);
+        assert_eq!(vm.cpu.instret, 0);
+    }
+    // Without a trap handler a zero instruction stops the VM
+    let mut vm = VM::new(vec![0; 4], 1 << 20).unwrap();
+    assert!(!vm.step().unwrap());
+}
+
+#[test]
+pub fn delegated_exceptions() {
+    const STVEC_ADDR: u64 = 0x8000_2000;
+    let mut vm = vm_with(&[
+        0x00000073, // ecall
+    ]);
+    // Everything accessible to S and U-mode
+    vm.cpu.set_csr_value(0x3B0, u64::MAX >> 10);
+    vm.cpu.set_csr_value(0x3A0, 0b11111);
+    vm.cpu.set_csr_value(STVEC, STVEC_ADDR);
+    vm.cpu.set_csr_value(MEDELEG, 1 << 8);
+    vm.cpu.set_csr_value(SSTATUS, 1 << 1); // SIE
+    vm.cpu.privilege_level = PrivilegeLevel::User;
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.privilege_level, PrivilegeLevel::Supervisor);
+    assert_eq!((vm.cpu.csr_value(SCAUSE), vm.cpu.csr_value(SEPC)), (8, BASE));
+    assert_eq!(vm.cpu.pc, STVEC_ADDR);
+    let sstatus = vm.cpu.csr_value(SSTATUS);
+    assert_eq!((sstatus >> 1) & 1, 0); // SIE
+    assert_eq!((sstatus >> 5) & 1, 1); // SPIE
+    assert_eq!((sstatus >> 8) & 1, 0); // SPP
+    assert_eq!(vm.cpu.csr_value(MCAUSE), 0);
+
+    // Ecalls from S-mode aren't delegated
+    vm.cpu.pc = BASE;
+    run(&mut vm, 1);
+    assert_eq!(vm.cpu.privilege_level, PrivilegeLevel::Machine);
+    assert_eq!(vm.cpu.csr_value(MCAUSE), 9);
+    assert_eq!(vm.cpu.pc, MTVEC_ADDR);
+}
@@ -0,0 +1,281 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use emulator::cpu::PrivilegeLevel;
+use emulator::linux::*;
+use emulator::vm::VM;
+
+const BASE: u64 = 0x10000;
+/// The code follows the ELF and program headers
+const ENTRY: u64 = BASE + 64 + 56;
+const ECALL: u32 = 0x00000073;
+const AT_FDCWD: u64 = -100i64 as u64;
+
+#[derive(Clone, Default)]
+struct Console(Rc<RefCell<Vec<u8>>>);
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Static executable with a single RWX segment at BASE holding the headers, `code` and `data`
+fn elf(code: &[u32], data: &[u8]) -> Vec<u8> {
+    let mut body: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    body.extend_from_slice(data);
+    let size = (64 + 56 + body.len()) as u64;
+    let mut elf = Vec::new();
+    elf.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf.extend_from_slice(&243u16.to_le_bytes()); // EM_RISCV
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&ENTRY.to_le_bytes());
+    elf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    elf.extend_from_slice(&5u32.to_le_bytes()); // RVC, double float ABI
+    for half in [64u16, 56, 1, 64, 0, 0] {
+        elf.extend_from_slice(&half.to_le_bytes());
+    }
+    elf.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    elf.extend_from_slice(&7u32.to_le_bytes()); // RWX
+    for field in [0, BASE, BASE, size, size, 0x1000] {
+        elf.extend_from_slice(&field.to_le_bytes());
+    }
+    elf.extend_from_slice(&body);
+    elf
+}
+fn user_vm(code: &[u32], data: &[u8], argv: &[&str], envp: &[&str]) -> (VM, Console) {
+    let console = Console::default();
+    let strings = |strings: &[&str]| strings.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let vm = VM::new_linux_user(&elf(code, data), &strings(argv), &strings(envp), Linux::with_stdout(Box::new(console.clone()))).unwrap();
+    (vm, console)
+}
+/// Runs the ecall at ENTRY, returns a0
+fn syscall(vm: &mut VM, nr: u64, args: &[u64]) -> i64 {
+    vm.cpu.pc = ENTRY;
+    vm.cpu.regs[17] = nr;
+    vm.cpu.regs[10..10+args.len()].copy_from_slice(args);
+    assert!(vm.step().unwrap(), "The program stopped");
+    assert_eq!(vm.cpu.pc, ENTRY + 4);
+    vm.cpu.regs[10] as i64
+}
+fn string(vm: &mut VM, addr: u64, string: &str) -> u64 {
+    vm.mem.write(addr, string.as_bytes()).unwrap();
+    vm.mem.set(addr + string.len() as u64, 0u8).unwrap();
+    addr
+}
+fn sandbox(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("linux-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+pub fn hello() {
+    let code = [
+        0x00100513, // li a0, 1
+        0x00000597, // auipc a1, 0
+        0x02058593, // addi a1, a1, 32
+        0x00600613, // li a2, 6
+        0x04000893, // li a7, 64
+        0x00000073, // ecall
+        0x00300513, // li a0, 3
+        0x05e00893, // li a7, 94
+        0x00000073, // ecall
+    ];
+    let (mut vm, console) = user_vm(&code, b"hello\n", &["hello"], &[]);
+    assert_eq!(vm.cpu.privilege_level, PrivilegeLevel::User);
+    vm.run_for(Some(100)).unwrap();
+    assert_eq!(console.0.borrow().as_slice(), b"hello\n");
+    assert_eq!(vm.exit_status(), Some(3));
+    assert_eq!(vm.cpu.instret, code.len() as u64);
+}
+
+#[test]
+pub fn initial_stack() {
+    let (mut vm, _) = user_vm(&[ECALL], &[], &["prog", "-v", "arg"], &["HOME=/", "A=b"]);
+    let sp = vm.cpu.regs[2];
+    assert_eq!(sp % 16, 0);
+    assert!((STACK_TOP - STACK_SIZE..STACK_TOP).contains(&sp));
+    let word = |vm: &mut VM, i: u64| vm.mem.get::<u64>(sp + 8*i).unwrap();
+    let cstring = |vm: &mut VM, mut addr: u64| {
+        let mut bytes = Vec::new();
+        while let byte @ 1.. = vm.mem.get::<u8>(addr).unwrap() {
+            bytes.push(byte);
+            addr += 1;
+        }
+        String::from_utf8(bytes).unwrap()
+    };
+    assert_eq!(word(&mut vm, 0), 3);
+    let argv: Vec<String> = (1..4).map(|i| {let ptr = word(&mut vm, i); cstring(&mut vm, ptr)}).collect();
+    assert_eq!(argv, ["prog", "-v", "arg"]);
+    assert_eq!(word(&mut vm, 4), 0);
+    let envp: Vec<String> = (5..7).map(|i| {let ptr = word(&mut vm, i); cstring(&mut vm, ptr)}).collect();
+    assert_eq!(envp, ["HOME=/", "A=b"]);
+    assert_eq!(word(&mut vm, 7), 0);
+
+    let mut auxv = std::collections::HashMap::new();
+    for i in (8..).step_by(2) {
+        let (key, value) = (word(&mut vm, i), word(&mut vm, i + 1));
+        if key == 0 {break}
+        auxv.insert(key, value);
+    }
+    assert_eq!(auxv[&6], 4096); // AT_PAGESZ
+    assert_eq!(auxv[&9], ENTRY); // AT_ENTRY
+    assert_eq!(auxv[&3], BASE + 64); // AT_PHDR
+    assert_eq!((auxv[&4], auxv[&5]), (56, 1)); // AT_PHENT, AT_PHNUM
+    assert_eq!(auxv[&16], 1 << 0 | 1 << 2 | 1 << 8 | 1 << 12); // AT_HWCAP, IMAC
+    let execfn = auxv[&31];
+    assert_eq!(cstring(&mut vm, execfn), "prog");
+    assert!((sp..STACK_TOP).contains(&auxv[&25])); // AT_RANDOM
+}
+
+#[test]
+pub fn brk_and_mmap() {
+    let (mut vm, _) = user_vm(&[ECALL], &[], &["prog"], &[]);
+    let start = syscall(&mut vm, SYS_BRK, &[0]) as u64;
+    assert_eq!(start % 4096, 0);
+    assert!(start > ENTRY);
+    assert_eq!(syscall(&mut vm, SYS_BRK, &[start + 0x10000]) as u64, start + 0x10000);
+    vm.mem.set(start + 0x8000, 42u64).unwrap();
+    // Out of the heap, nothing changes
+    assert_eq!(syscall(&mut vm, SYS_BRK, &[1]) as u64, start + 0x10000);
+    // Shrinking and growing back gives zeroes
+    assert_eq!(syscall(&mut vm, SYS_BRK, &[start]) as u64, start);
+    assert_eq!(syscall(&mut vm, SYS_BRK, &[start + 0x10000]) as u64, start + 0x10000);
+    assert_eq!(vm.mem.get::<u64>(start + 0x8000).unwrap(), 0);
+
+    const PROT_RW: u64 = 3;
+    const PRIVATE_ANONYMOUS: u64 = 0x22;
+    let a = syscall(&mut vm, SYS_MMAP, &[0, 0x2000, PROT_RW, PRIVATE_ANONYMOUS, u64::MAX, 0]) as u64;
+    assert_eq!(a, MMAP_BASE);
+    let b = syscall(&mut vm, SYS_MMAP, &[0, 100, PROT_RW, PRIVATE_ANONYMOUS, u64::MAX, 0]) as u64;
+    assert_eq!(b, a + 0x2000);
+    vm.mem.set(a, 7u32).unwrap();
+    assert_eq!(syscall(&mut vm, SYS_MPROTECT, &[a, 0x1000, 1]), 0);
+    assert_eq!(syscall(&mut vm, SYS_MUNMAP, &[a, 0x2000]), 0);
+    assert_eq!(syscall(&mut vm, SYS_MMAP, &[0, 0x1000, PROT_RW, PRIVATE_ANONYMOUS, u64::MAX, 0]) as u64, a);
+    assert_eq!(vm.mem.get::<u32>(a).unwrap(), 0);
+
+    assert_eq!(syscall(&mut vm, SYS_MMAP, &[0, 0, PROT_RW, PRIVATE_ANONYMOUS, u64::MAX, 0]), -(EINVAL as i64));
+    assert_eq!(syscall(&mut vm, SYS_MMAP, &[0, 2 << 30, PROT_RW, PRIVATE_ANONYMOUS, u64::MAX, 0]), -(ENOMEM as i64));
+    assert_eq!(syscall(&mut vm, SYS_MMAP, &[0, 0x1000, PROT_RW, 2, 7, 0]), -(EBADF as i64));
+    assert_eq!(syscall(&mut vm, SYS_MUNMAP, &[a + 1, 0x1000]), -(EINVAL as i64));
+    // Past the end of the address space
+    assert_eq!(syscall(&mut vm, SYS_MUNMAP, &[a, u64::MAX]), -(EINVAL as i64));
+    assert_eq!(syscall(&mut vm, SYS_MUNMAP, &[a, u64::MAX - a - 0x10]), -(EINVAL as i64));
+}
+
+#[test]
+pub fn files() {
+    let dir = sandbox("files");
+    std::fs::write(dir.join("input"), b"file contents").unwrap();
+    let (mut vm, console) = user_vm(&[ECALL], &[], &["prog"], &[]);
+    let buf = syscall(&mut vm, SYS_BRK, &[0]) as u64;
+    syscall(&mut vm, SYS_BRK, &[buf + 0x1000]);
+    let path = string(&mut vm, buf, dir.join("input").to_str().unwrap());
+
+    let fd = syscall(&mut vm, SYS_OPENAT, &[AT_FDCWD, path, 0, 0]) as u64;
+    assert_eq!(fd, 3);
+    assert_eq!(syscall(&mut vm, SYS_READ, &[fd, buf + 0x100, 4]), 4);
+    assert_eq!(syscall(&mut vm, SYS_READ, &[fd, buf + 0x104, 100]), 9);
+    let mut data = [0; 13];
+    vm.mem.read(buf + 0x100, &mut data).unwrap();
+    assert_eq!(&data, b"file contents");
+    assert_eq!(syscall(&mut vm, SYS_READ, &[fd, buf + 0x100, 100]), 0);
+    assert_eq!(syscall(&mut vm, SYS_FSTAT, &[fd, buf + 0x200]), 0);
+    assert_eq!(vm.mem.get::<u32>(buf + 0x210).unwrap() & 0o170000, 0o100000); // st_mode is a regular file
+    assert_eq!(vm.mem.get::<i64>(buf + 0x230).unwrap(), 13); // st_size
+    // Mapping a file gives a copy of it
+    let mapped = syscall(&mut vm, SYS_MMAP, &[0, 0x1000, 1, 2, fd, 0]) as u64;
+    vm.mem.read(mapped, &mut data).unwrap();
+    assert_eq!(&data, b"file contents");
+    assert_eq!(syscall(&mut vm, SYS_CLOSE, &[fd]), 0);
+    assert_eq!(syscall(&mut vm, SYS_CLOSE, &[fd]), -(EBADF as i64));
+
+    const O_WRONLY_CREAT_TRUNC: u64 = 0o1101;
+    let path = string(&mut vm, buf, dir.join("output").to_str().unwrap());
+    let fd = syscall(&mut vm, SYS_OPENAT, &[AT_FDCWD, path, O_WRONLY_CREAT_TRUNC, 0o644]) as u64;
+    assert_eq!(fd, 3);
+    vm.mem.write(buf + 0x100, b"written").unwrap();
+    // Two iovecs
+    for (i, field) in [buf + 0x100, 3, buf + 0x103, 4].into_iter().enumerate() {
+        vm.mem.set(buf + 0x300 + 8*i as u64, field).unwrap();
+    }
+    assert_eq!(syscall(&mut vm, SYS_WRITEV, &[fd, buf + 0x300, 2]), 7);
+    assert_eq!(syscall(&mut vm, SYS_READ, &[fd, buf, 1]), -9); // EBADF, write only
+    syscall(&mut vm, SYS_CLOSE, &[fd]);
+    assert_eq!(std::fs::read(dir.join("output")).unwrap(), b"written");
+
+    let path = string(&mut vm, buf, dir.join("missing").to_str().unwrap());
+    assert_eq!(syscall(&mut vm, SYS_OPENAT, &[AT_FDCWD, path, 0, 0]), -2); // ENOENT
+    assert_eq!(syscall(&mut vm, SYS_WRITE, &[1, buf + 0x100, 7]), 7);
+    assert_eq!(console.0.borrow().as_slice(), b"written");
+    // The console looks like a character device
+    assert_eq!(syscall(&mut vm, SYS_FSTAT, &[1, buf + 0x200]), 0);
+    assert_eq!(vm.mem.get::<u32>(buf + 0x210).unwrap() & 0o170000, 0o020000);
+}
+
+#[test]
+pub fn bad_pointers() {
+    let (mut vm, console) = user_vm(&[ECALL], &[], &["prog"], &[]);
+    let efault = -(EFAULT as i64);
+    assert_eq!(syscall(&mut vm, SYS_WRITE, &[1, 0x1234, 5]), efault);
+    assert_eq!(syscall(&mut vm, SYS_READ, &[0, 0, 5]), efault);
+    // Crosses the end of the stack
+    assert_eq!(syscall(&mut vm, SYS_GETRANDOM, &[STACK_TOP - 8, 16, 0]), efault);
+    assert_eq!(syscall(&mut vm, SYS_OPENAT, &[AT_FDCWD, 0x10, 0, 0]), efault);
+    assert_eq!(syscall(&mut vm, SYS_CLOCK_GETTIME, &[1, u64::MAX - 4]), efault);
+    // Huge lengths are cut before anything is allocated
+    assert_eq!(syscall(&mut vm, SYS_WRITE, &[1, ENTRY, u64::MAX]), efault);
+    assert_eq!(syscall(&mut vm, SYS_WRITE, &[42, ENTRY, 4]), -(EBADF as i64));
+    assert!(console.0.borrow().is_empty());
+    assert_eq!(syscall(&mut vm, 1000, &[]), -(ENOSYS as i64));
+}
+
+#[test]
+pub fn time_and_random() {
+    let (mut vm, _) = user_vm(&[ECALL], &[], &["prog"], &[]);
+    let buf = STACK_TOP - 0x100;
+    assert_eq!(syscall(&mut vm, SYS_CLOCK_GETTIME, &[0, buf]), 0);
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    assert!(now.abs_diff(vm.mem.get::<u64>(buf).unwrap()) < 5);
+    assert!(vm.mem.get::<u64>(buf + 8).unwrap() < 1_000_000_000);
+    assert_eq!(syscall(&mut vm, SYS_CLOCK_GETTIME, &[1, buf]), 0);
+    assert!(vm.mem.get::<u64>(buf).unwrap() < 5);
+    assert_eq!(syscall(&mut vm, SYS_CLOCK_GETTIME, &[42, buf]), -(EINVAL as i64));
+
+    assert_eq!(syscall(&mut vm, SYS_GETRANDOM, &[buf, 64, 0]), 64);
+    let mut random = [0; 64];
+    vm.mem.read(buf, &mut random).unwrap();
+    assert!(random.iter().any(|&byte| byte != 0));
+    assert_eq!(syscall(&mut vm, SYS_SET_TID_ADDRESS, &[0]), 1);
+}
+
+#[test]
+pub fn faults_kill_the_program() {
+    let (mut vm, _) = user_vm(&[
+        0x00003503, // ld a0, 0(zero)
+    ], &[], &["prog"], &[]);
+    vm.run_for(Some(10)).unwrap();
+    assert_eq!(vm.exit_status(), Some(128 + 11)); // SIGSEGV
+
+    let (mut vm, _) = user_vm(&[0], &[], &["prog"], &[]);
+    assert!(!vm.step().unwrap());
+    assert_eq!(vm.exit_status(), Some(128 + 4)); // SIGILL
+
+    let (mut vm, _) = user_vm(&[0x00100073], &[], &["prog"], &[]); // ebreak
+    assert!(!vm.step().unwrap());
+    assert_eq!(vm.exit_status(), Some(128 + 5)); // SIGTRAP
+
+    let elf = elf(&[ECALL], &[]);
+    assert!(VM::new_linux_user(&elf, &[], &[], Linux::default()).is_err());
+    assert!(VM::new_linux_user(&elf[..100], &["prog".into()], &[], Linux::default()).is_err());
+}
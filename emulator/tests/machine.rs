@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use emulator::cpu::csr::file::*;
+use emulator::cpu::reg::Reg;
+use emulator::cpu::PrivilegeLevel;
+use emulator::machine::*;
+use emulator::mem::{AccessType, MemoryRegion};
+
+const BASE: u64 = 0x8000_0000;
+const DATA: u64 = BASE + 0x1000;
+const ECALL: u32 = 0x00000073;
+const NOP: u32 = 0x00000013;
+const MEMORY: [u32; 5] = [
+    0x00001297, // auipc t0, 1
+    0x02a00313, // li t1, 42
+    0x0062b423, // sd t1, 8(t0)
+    0x0082b383, // ld t2, 8(t0)
+    0x00138393, // addi t2, t2, 1
+];
+/// Every hart writes its id + 1 at DATA + 8 * a0
+const HARTS: [u32; 6] = [
+    0xf14022f3, // csrr t0, mhartid
+    0x00128293, // addi t0, t0, 1
+    0x00001317, // auipc t1, 1
+    0x00351393, // slli t2, a0, 3
+    0x00730333, // add t1, t1, t2
+    0x00533023, // sd t0, 0(t1)
+];
+
+fn bytes(program: &[u32]) -> Vec<u8> {
+    program.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+}
+fn machine(program: &[u32]) -> Machine {
+    Machine::builder().ram_size(1 << 20).image(BASE, bytes(program)).build().unwrap()
+}
+/// Keeps what a callback was given
+fn recorder<T: Copy + 'static>() -> (Rc<RefCell<Vec<T>>>, impl FnMut(&T) + 'static) {
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let recorded = events.clone();
+    (events, move |event: &T| recorded.borrow_mut().push(*event))
+}
+
+/// Raises its interrupt line after a few ticks
+struct Countdown(u32);
+impl MemoryRegion for Countdown {
+    fn read(&mut self, _offset: u64) -> u8 {
+        self.0 as u8
+    }
+    fn write(&mut self, _offset: u64, val: u8) {
+        self.0 = val as u32;
+    }
+    fn tick(&mut self) {
+        self.0 = self.0.saturating_sub(1);
+    }
+    fn interrupt_pending(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+#[test]
+pub fn run_and_accessors() {
+    let mut machine = machine(&MEMORY);
+    assert_eq!((machine.harts(), machine.pc(0), machine.privilege(0)), (1, BASE, PrivilegeLevel::Machine));
+    assert!(!machine.vm.trace);
+    assert_eq!(machine.run(Some(2)).unwrap(), StopReason::InstructionLimit);
+    assert_eq!((machine.reg(0, Reg::t0), machine.reg(0, Reg::t1)), (DATA, 42));
+    // Falls into the zeroed RAM after the program
+    assert_eq!(machine.run(None).unwrap(), StopReason::Halted { hart: 0, pc: BASE + 20 });
+    assert_eq!(machine.reg(0, Reg::t2), 43);
+    assert_eq!(machine.read::<u64>(DATA + 8).unwrap(), 42);
+    assert_eq!(machine.hart(0).instret, 5);
+
+    machine.set_pc(0, BASE + 12);
+    machine.set_reg(0, Reg::t0, DATA + 0x100);
+    machine.set_reg(0, Reg::zero, 5);
+    machine.write::<u64>(DATA + 0x108, 7).unwrap();
+    assert_eq!(machine.run_until(|machine| machine.pc(0) == BASE + 16, None).unwrap(), StopReason::Condition);
+    assert_eq!((machine.reg(0, Reg::t2), machine.reg(0, Reg::zero)), (7, 0));
+    let mut buffer = [0; 2];
+    machine.write_bytes(DATA, b"ok").unwrap();
+    machine.read_bytes(DATA, &mut buffer).unwrap();
+    assert_eq!(&buffer, b"ok");
+    assert!(machine.read::<u8>(0).is_err());
+
+    machine.set_csr(0, MSCRATCH, 9);
+    assert_eq!(machine.csr(0, MSCRATCH), 9);
+    // Read only bits stay as they are
+    machine.set_csr(0, MISA, 0);
+    assert_eq!(machine.csr(0, MISA), MISA_VALUE);
+}
+
+#[test]
+pub fn retire_and_memory_callbacks() {
+    let mut machine = machine(&MEMORY);
+    let (retired, hook) = recorder::<Retired>();
+    machine.on_retire(hook);
+    let (accesses, hook) = recorder::<MemoryAccess>();
+    machine.on_memory_access(hook);
+    machine.run(None).unwrap();
+    let retired = retired.borrow();
+    assert_eq!(retired.iter().map(|retired| retired.pc).collect::<Vec<_>>(), [BASE, BASE + 4, BASE + 8, BASE + 12, BASE + 16]);
+    assert_eq!(retired[1], Retired { hart: 0, pc: BASE + 4, instruction: MEMORY[1], size: 4 });
+    assert_eq!(accesses.borrow().as_slice(), [
+        MemoryAccess { hart: 0, addr: DATA + 8, size: 8, access: AccessType::Write, value: 42 },
+        MemoryAccess { hart: 0, addr: DATA + 8, size: 8, access: AccessType::Read, value: 42 },
+    ]);
+}
+
+#[test]
+pub fn trap_and_interrupt_callbacks() {
+    let mut machine = Machine::builder()
+        .ram_size(1 << 20)
+        .uart(false)
+        .device(0x1000_0000, 0x100, Some(7), Countdown(3))
+        .image(BASE, bytes(&[ECALL]))
+        .image(BASE + 0x100, bytes(&[NOP; 16]))
+        .build().unwrap();
+    machine.set_csr(0, MTVEC, BASE + 0x100);
+    let (traps, hook) = recorder::<Trap>();
+    machine.on_trap(hook);
+    let (interrupts, hook) = recorder::<DeviceInterrupt>();
+    machine.on_device_interrupt(hook);
+    let (retired, hook) = recorder::<Retired>();
+    machine.on_retire(hook);
+
+    machine.run(Some(6)).unwrap();
+    let trap = traps.borrow()[0];
+    assert_eq!(trap, Trap { hart: 0, cause: 11, tval: 0, epc: BASE, privilege: PrivilegeLevel::Machine });
+    assert!(!trap.is_interrupt());
+    assert_eq!(traps.borrow().len(), 1);
+    // The ecall didn't retire
+    assert_eq!(retired.borrow().first().map(|retired| retired.pc), Some(BASE + 0x100));
+    // Only when the line goes up
+    assert_eq!(interrupts.borrow().as_slice(), [DeviceInterrupt { irq: 7 }]);
+    machine.write::<u8>(0x1000_0000, 3).unwrap();
+    machine.run(Some(3)).unwrap();
+    assert_eq!(interrupts.borrow().len(), 2);
+}
+
+#[test]
+pub fn harts() {
+    let mut machine = Machine::builder()
+        .ram_size(1 << 20)
+        .harts(3)
+        .uart(false)
+        .device(0x1000_0000, 0x100, None, Countdown(10))
+        .image(BASE, bytes(&HARTS))
+        .build().unwrap();
+    assert_eq!(machine.harts(), 3);
+    assert_eq!((0..3).map(|hart| machine.reg(hart, Reg::a0)).collect::<Vec<_>>(), [0, 1, 2]);
+    let (retired, hook) = recorder::<Retired>();
+    machine.on_retire(hook);
+    assert!(machine.step().unwrap().is_none());
+    assert_eq!(retired.borrow().iter().map(|retired| retired.hart).collect::<Vec<_>>(), [0, 1, 2]);
+    // The devices tick once per step of the machine, not once per hart
+    assert_eq!(machine.read::<u8>(0x1000_0000).unwrap(), 9);
+    // boot_cpuid_phys is hart 0, not the last hart stepped
+    assert_eq!(machine.device_tree("")[28..32], 0u32.to_be_bytes());
+    assert_eq!(machine.run(None).unwrap(), StopReason::Halted { hart: 0, pc: BASE + 24 });
+    for hart in 0..3 {
+        assert_eq!(machine.read::<u64>(DATA + 8 + 8*hart).unwrap(), hart + 1);
+        assert_eq!(machine.hart(hart as usize).hartid, hart);
+    }
+    // Hart 0 stopped the machine before the others executed the zero instruction
+    assert_eq!(machine.pc(1), BASE + 24);
+
+    assert!(Machine::builder().harts(0).build().is_err());
+    // Overlaps the RAM
+    assert!(Machine::builder().device(BASE, 0x100, None, Countdown(0)).build().is_err());
+    assert!(Machine::builder().ram_size(0x1000).image(BASE + 0x800, vec![0; 0x1000]).build().is_err());
+}
+
+#[test]
+pub fn exit_status() {
+    let mut machine = Machine::builder().entry(BASE + 0x40).image(BASE + 0x40, bytes(&[ECALL])).build().unwrap();
+    assert_eq!(machine.pc(0), BASE + 0x40);
+    machine.vm.sbi = Some(emulator::sbi::Sbi::default());
+    machine.hart_mut(0).privilege_level = PrivilegeLevel::Supervisor;
+    machine.hart_mut(0).pmp_allow_all();
+    machine.set_reg(0, Reg::a7, emulator::sbi::EXT_SRST);
+    machine.set_reg(0, Reg::a6, 0);
+    machine.set_reg(0, Reg::a0, 0);
+    machine.set_reg(0, Reg::a1, emulator::sbi::RESET_REASON_FAILURE);
+    assert_eq!(machine.run(Some(10)).unwrap(), StopReason::Exited(1));
+}
@@ -0,0 +1,100 @@
+use emulator::mem::{AccessFault, AccessType, MemMap, Memory, MemoryMap, MemoryRegion, DRAM, PAGE_SIZE};
+
+const RAM: u64 = 128 << 20;
+
+fn dram(mem: &Memory) -> &DRAM {
+    mem.device::<DRAM>(MemMap::DRAM.base()).unwrap()
+}
+
+#[test]
+pub fn reads_dont_allocate() {
+    let mut mem = Memory::new(RAM);
+    let base = MemMap::DRAM.base();
+    assert_eq!(mem.get::<u64>(base+RAM-8).unwrap(), 0);
+    assert_eq!(mem.get::<u8>(base).unwrap(), 0);
+    assert_eq!(dram(&mem).allocated_pages(), 0);
+
+    mem.set::<u32>(base+0x10, 0xdead_beef).unwrap();
+    assert_eq!(dram(&mem).allocated_pages(), 1);
+    assert_eq!(mem.get::<u32>(base+0x10).unwrap(), 0xdead_beef);
+    assert_eq!(mem.get::<u16>(base+0x12).unwrap(), 0xdead);
+}
+
+#[test]
+pub fn access_across_pages() {
+    let mut mem = Memory::new(RAM);
+    let addr = MemMap::DRAM.base()+PAGE_SIZE-4;
+    mem.set::<u64>(addr, 0x0123_4567_89ab_cdef).unwrap();
+    assert_eq!(dram(&mem).allocated_pages(), 2);
+    assert_eq!(mem.get::<u64>(addr).unwrap(), 0x0123_4567_89ab_cdef);
+}
+
+#[test]
+pub fn out_of_bounds_faults() {
+    let mut mem = Memory::new(RAM);
+    let end = MemMap::DRAM.base()+RAM;
+    assert_eq!(mem.get::<u8>(end), Err(AccessFault { addr: end, len: 1, access: AccessType::Read }));
+    // Straddling the end of RAM is a fault too
+    assert_eq!(mem.set::<u64>(end-4, 0), Err(AccessFault { addr: end-4, len: 8, access: AccessType::Write }));
+    assert_eq!(mem.fetch::<u32>(0x4000_0000), Err(AccessFault { addr: 0x4000_0000, len: 4, access: AccessType::Execute }));
+    assert_eq!(dram(&mem).size(), RAM);
+}
+
+#[test]
+pub fn parse_mem_size() {
+    use emulator::args::parse_size;
+    assert_eq!(parse_size("128M"), Ok(128 << 20));
+    assert_eq!(parse_size("2G"), Ok(2 << 30));
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert!(parse_size("12X").is_err());
+}
+
+#[derive(Default)]
+struct Scratch {
+    last_write: Option<(u64, u8)>,
+    ticks: usize,
+}
+impl MemoryRegion for Scratch {
+    fn read(&mut self, offset: u64) -> u8 {
+        offset as u8
+    }
+    fn write(&mut self, offset: u64, val: u8) {
+        self.last_write = Some((offset, val))
+    }
+    fn tick(&mut self) {
+        self.ticks += 1
+    }
+    fn interrupt_pending(&self) -> bool {
+        self.ticks >= 2
+    }
+}
+
+#[test]
+pub fn register_devices() {
+    let mut mem = Memory::new(RAM);
+    mem.register(0x3000_0000, 0x100, Some(7), Scratch::default()).unwrap();
+    assert_eq!(mem.get::<u16>(0x3000_0010).unwrap(), 0x1110);
+    mem.set::<u8>(0x3000_0042, 9).unwrap();
+    assert_eq!(mem.device::<Scratch>(0x3000_0000).unwrap().last_write, Some((0x42, 9)));
+
+    assert_eq!(mem.pending_irqs().count(), 0);
+    mem.tick();
+    mem.tick();
+    assert_eq!(mem.pending_irqs().collect::<Vec<_>>(), vec![7]);
+
+    // Unmapped, just after the device
+    assert!(mem.get::<u8>(0x3000_0100).is_err());
+    assert!(mem.get::<u32>(0x3000_00fe).is_err());
+}
+
+#[test]
+pub fn overlapping_devices_are_rejected() {
+    let mut mem = Memory::empty();
+    mem.register(0x1000, 0x1000, None, Scratch::default()).unwrap();
+    assert!(mem.register(0x1800, 0x1000, None, Scratch::default()).is_err());
+    assert!(mem.register(0x0800, 0x1000, None, Scratch::default()).is_err());
+    assert!(mem.register(0x0, 0x10000, None, Scratch::default()).is_err());
+    mem.register(0x2000, 0x1000, None, Scratch::default()).unwrap();
+    mem.register(0x0, 0x1000, None, Scratch::default()).unwrap();
+    assert_eq!(mem.regions().iter().map(|region| region.base).collect::<Vec<_>>(), vec![0x0, 0x1000, 0x2000]);
+}
@@ -0,0 +1,260 @@
+mod common;
+
+use common::{descriptor, notify, used, virtio_machine, BASE, DESC_WRITE, TRANSPORT};
+use emulator::board::{Board, DeviceKind};
+use emulator::machine::Machine;
+use emulator::net::*;
+use emulator::virtio::mmio::*;
+use emulator::virtio::net::*;
+
+const GUEST_MAC: Mac = [0x52, 0x54, 0x00, 0xAA, 0xBB, 0xCC];
+/// Pages of the receive and transmit queues, and of their buffers
+const RECEIVE_PAGE: u64 = BASE + 0x1_0000;
+const TRANSMIT_PAGE: u64 = BASE + 0x2_0000;
+const RECEIVE_BUFFER: u64 = BASE + 0x3_0000;
+const TRANSMIT_BUFFER: u64 = BASE + 0x4_0000;
+/// Legacy virtio_net_hdr
+const HEADER: usize = 10;
+
+fn nic(machine: &mut Machine) -> &mut VirtioNet {
+    machine.vm.mem.device_mut::<VirtioMmio>(TRANSPORT).unwrap().device_mut().unwrap()
+}
+/// Sets the device up like the kernel does, after a reset
+fn driver_init(machine: &mut Machine) {
+    machine.write(TRANSPORT + STATUS, 0u32).unwrap();
+    machine.write(TRANSPORT + GUEST_PAGE_SIZE, 4096u32).unwrap();
+    let queues = [(RECEIVE, RECEIVE_PAGE), (TRANSMIT, TRANSMIT_PAGE)];
+    common::driver_init(machine, F_MAC | F_STATUS, &queues);
+    for (queue, page) in queues {
+        machine.write(TRANSPORT + QUEUE_SEL, queue as u32).unwrap();
+        assert_eq!(machine.read::<u32>(TRANSPORT + QUEUE_NUM_MAX).unwrap(), 256);
+        assert_eq!(machine.read::<u32>(TRANSPORT + QUEUE_PFN).unwrap(), (page / 4096) as u32);
+    }
+}
+/// Makes descriptor `index` of the queue at `page` available, `writable` for the device
+fn make_available(machine: &mut Machine, page: u64, index: u16, buffer: u64, len: u32, writable: bool) {
+    descriptor(machine, page, index, buffer, len, if writable {DESC_WRITE} else {0});
+    common::make_available(machine, page, index);
+}
+/// Sends `frame` from descriptor `index` of the transmit queue
+fn transmit(machine: &mut Machine, index: u16, frame: &[u8]) {
+    let buffer = TRANSMIT_BUFFER + 0x800 * index as u64;
+    machine.write_bytes(buffer, &[vec![0; HEADER], frame.to_vec()].concat()).unwrap();
+    make_available(machine, TRANSMIT_PAGE, index, buffer, (HEADER + frame.len()) as u32, false);
+    notify(machine, TRANSMIT);
+}
+fn received(machine: &mut Machine, index: u16, len: u32) -> Vec<u8> {
+    let mut frame = vec![0; len as usize];
+    machine.read_bytes(RECEIVE_BUFFER + 0x800 * index as u64, &mut frame).unwrap();
+    frame
+}
+fn ethernet(destination: Mac, source: Mac, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    [destination.as_slice(), &source, &ethertype.to_be_bytes(), payload].concat()
+}
+/// ICMP echo request from 10.0.2.15 to 10.0.2.2
+fn ping() -> Vec<u8> {
+    let mut icmp = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1];
+    icmp.extend(b"abcdefgh");
+    let sum = checksum(&icmp);
+    icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+    let mut ip = vec![0x45, 0, 0, 20 + icmp.len() as u8, 0, 0, 0x40, 0, 64, 1, 0, 0, 10, 0, 2, 15, 10, 0, 2, 2];
+    let sum = checksum(&ip);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+    ip.extend(icmp);
+    ethernet(ECHO_MAC, GUEST_MAC, ETHERTYPE_IPV4, &ip)
+}
+
+#[test]
+pub fn transport() {
+    let board = Board::virt().with_virtio_mmio(true);
+    let transports: Vec<_> = board.devices.iter().filter(|device| device.kind == DeviceKind::VirtioMmio).map(|device| (device.base, device.irq)).collect();
+    assert_eq!(transports.len(), 8);
+    assert_eq!((transports[0], transports[7]), ((0x1000_1000, Some(1)), (0x1000_8000, Some(8))));
+    let mut machine = virtio_machine(VirtioNet::new(GUEST_MAC, Loopback::default()));
+    // The others are empty
+    assert_eq!(machine.read::<u32>(TRANSPORT + 0x1000 + MAGIC_VALUE).unwrap(), 0x7472_6976);
+    assert_eq!(machine.read::<u32>(TRANSPORT + 0x1000 + DEVICE_ID).unwrap(), 0);
+    assert_eq!(machine.read::<u32>(TRANSPORT + VERSION_REG).unwrap(), 1);
+    assert_eq!(machine.read::<u32>(TRANSPORT + DEVICE_ID).unwrap(), NET_ID);
+    assert_eq!(machine.read::<u32>(TRANSPORT + VENDOR_ID).unwrap(), 0x554D_4551);
+    // No VIRTIO_F_VERSION_1 on the legacy transport
+    assert_eq!(machine.read::<u32>(TRANSPORT + HOST_FEATURES).unwrap(), (F_MAC | F_STATUS) as u32);
+    machine.write(TRANSPORT + HOST_FEATURES_SEL, 1u32).unwrap();
+    assert_eq!(machine.read::<u32>(TRANSPORT + HOST_FEATURES).unwrap(), 0);
+    let mut config = [0; 8];
+    machine.read_bytes(TRANSPORT + CONFIG, &mut config).unwrap();
+    assert_eq!(config, [0x52, 0x54, 0x00, 0xAA, 0xBB, 0xCC, 1, 0]);
+    // The used ring starts at the next QueueAlign boundary
+    driver_init(&mut machine);
+    machine.write(TRANSPORT + QUEUE_SEL, 0u32).unwrap();
+    machine.write(TRANSPORT + QUEUE_PFN, 0u32).unwrap();
+    machine.write(TRANSPORT + QUEUE_ALIGN, 64u32).unwrap();
+    machine.write(TRANSPORT + QUEUE_PFN, (RECEIVE_PAGE / 4096) as u32).unwrap();
+    let virtio = machine.vm.mem.device_mut::<VirtioMmio>(TRANSPORT).unwrap().virtio.as_mut().unwrap();
+    let queue = virtio.queues[RECEIVE];
+    assert_eq!((queue.desc, queue.driver, queue.device), (RECEIVE_PAGE, RECEIVE_PAGE + 128, RECEIVE_PAGE + 192));
+    // The highest placement the registers allow still fits in the address space
+    machine.write(TRANSPORT + GUEST_PAGE_SIZE, u32::MAX).unwrap();
+    machine.write(TRANSPORT + QUEUE_ALIGN, u32::MAX).unwrap();
+    machine.write(TRANSPORT + QUEUE_PFN, u32::MAX).unwrap();
+    let virtio = machine.vm.mem.device_mut::<VirtioMmio>(TRANSPORT).unwrap().virtio.as_mut().unwrap();
+    let queue = virtio.queues[RECEIVE];
+    assert!(queue.ready && queue.desc < queue.driver && queue.driver < queue.device && queue.device.is_multiple_of(u32::MAX as u64));
+    // Reset
+    machine.write(TRANSPORT + STATUS, 0u32).unwrap();
+    assert_eq!(machine.read::<u32>(TRANSPORT + QUEUE_PFN).unwrap(), 0);
+}
+
+#[test]
+pub fn loopback() {
+    let mut machine = virtio_machine(VirtioNet::new(GUEST_MAC, Loopback::default()));
+    driver_init(&mut machine);
+    for index in 0..2 {
+        make_available(&mut machine, RECEIVE_PAGE, index, RECEIVE_BUFFER + 0x800 * index as u64, 0x800, true);
+    }
+    machine.write(TRANSPORT + QUEUE_NOTIFY, RECEIVE as u32).unwrap();
+    let frame = ethernet(BROADCAST, GUEST_MAC, 0x88B5, b"hello");
+    transmit(&mut machine, 0, &frame);
+    assert_eq!(used(&mut machine, TRANSMIT_PAGE), [(0, 0)]);
+    let length = (HEADER + frame.len()) as u32;
+    assert_eq!(used(&mut machine, RECEIVE_PAGE), [(0, length)]);
+    assert_eq!(received(&mut machine, 0, length), [vec![0; HEADER], frame].concat());
+    assert_eq!(machine.read::<u32>(TRANSPORT + INTERRUPT_STATUS).unwrap(), 1);
+    assert!(machine.vm.mem.pending_irqs().any(|irq| irq == 1));
+    machine.write(TRANSPORT + INTERRUPT_ACK, 1u32).unwrap();
+    assert_eq!(machine.read::<u32>(TRANSPORT + INTERRUPT_STATUS).unwrap(), 0);
+
+    // Frames wait for receive buffers
+    transmit(&mut machine, 1, b"second");
+    transmit(&mut machine, 2, b"third");
+    assert_eq!(used(&mut machine, RECEIVE_PAGE).len(), 2);
+    make_available(&mut machine, RECEIVE_PAGE, 2, RECEIVE_BUFFER + 0x1000, 0x800, true);
+    machine.write(TRANSPORT + QUEUE_NOTIFY, RECEIVE as u32).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(used(&mut machine, RECEIVE_PAGE)[2], (2, HEADER as u32 + 5));
+    assert_eq!(&received(&mut machine, 2, HEADER as u32 + 5)[HEADER..], b"third");
+}
+
+#[test]
+pub fn echo() {
+    let mut echo = Echo::default();
+    // ARP request for 10.0.2.2 from 10.0.2.15
+    let mut arp = vec![0, 1, 8, 0, 6, 4, 0, 1];
+    arp.extend(GUEST_MAC);
+    arp.extend([10, 0, 2, 15, 0, 0, 0, 0, 0, 0, 10, 0, 2, 2]);
+    echo.send(&ethernet(BROADCAST, GUEST_MAC, ETHERTYPE_ARP, &arp));
+    let reply = echo.receive().unwrap();
+    assert_eq!(reply[..14], ethernet(GUEST_MAC, ECHO_MAC, ETHERTYPE_ARP, &[]));
+    assert_eq!(reply[14..22], [0, 1, 8, 0, 6, 4, 0, 2]);
+    assert_eq!(reply[22..28], ECHO_MAC);
+    assert_eq!((&reply[28..32], &reply[32..38], &reply[38..42]), (&[10, 0, 2, 2][..], &GUEST_MAC[..], &[10, 0, 2, 15][..]));
+    // Gratuitous ARP gets no answer
+    arp[24..28].copy_from_slice(&[10, 0, 2, 15]);
+    echo.send(&ethernet(BROADCAST, GUEST_MAC, ETHERTYPE_ARP, &arp));
+    assert_eq!(echo.receive(), None);
+
+    // Through the NIC
+    let mut machine = virtio_machine(VirtioNet::new(GUEST_MAC, Echo::default()));
+    driver_init(&mut machine);
+    make_available(&mut machine, RECEIVE_PAGE, 0, RECEIVE_BUFFER, 0x800, true);
+    machine.write(TRANSPORT + QUEUE_NOTIFY, RECEIVE as u32).unwrap();
+    let request = ping();
+    transmit(&mut machine, 0, &request);
+    let (_, length) = used(&mut machine, RECEIVE_PAGE)[0];
+    let reply = received(&mut machine, 0, length)[HEADER..].to_vec();
+    assert_eq!(reply.len(), request.len());
+    assert_eq!(reply[..14], ethernet(GUEST_MAC, ECHO_MAC, ETHERTYPE_IPV4, &[]));
+    let (ip, icmp) = reply[14..].split_at(20);
+    assert_eq!((&ip[12..16], &ip[16..20]), (&[10, 0, 2, 2][..], &[10, 0, 2, 15][..]));
+    assert_eq!(checksum(ip), 0);
+    assert_eq!((icmp[0], checksum(icmp)), (0, 0));
+    assert_eq!(icmp[4..], request[38..]);
+}
+
+#[test]
+pub fn link_status() {
+    let mut machine = virtio_machine(VirtioNet::new(GUEST_MAC, Loopback::default()));
+    driver_init(&mut machine);
+    make_available(&mut machine, RECEIVE_PAGE, 0, RECEIVE_BUFFER, 0x800, true);
+    machine.write(TRANSPORT + QUEUE_NOTIFY, RECEIVE as u32).unwrap();
+    nic(&mut machine).set_link(false);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.read::<u8>(TRANSPORT + CONFIG + 6).unwrap(), 0);
+    assert_eq!(machine.read::<u32>(TRANSPORT + INTERRUPT_STATUS).unwrap(), 2, "configuration change");
+    // Frames are lost while the cable is out
+    transmit(&mut machine, 0, b"lost");
+    assert_eq!(used(&mut machine, TRANSMIT_PAGE).len(), 1);
+    assert_eq!(used(&mut machine, RECEIVE_PAGE).len(), 0);
+    nic(&mut machine).set_link(true);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.read::<u8>(TRANSPORT + CONFIG + 6).unwrap(), S_LINK_UP as u8);
+    transmit(&mut machine, 1, b"back");
+    assert_eq!(used(&mut machine, RECEIVE_PAGE).len(), 1);
+    // Legacy drivers write the MAC
+    machine.write(TRANSPORT + CONFIG + 5, 0xDDu8).unwrap();
+    assert_eq!(nic(&mut machine).mac()[5], 0xDD);
+
+    let config: NetConfig = "hub=/tmp/net.sock,mac=02:00:00:00:00:01,link=off,pcap=out.pcap".parse().unwrap();
+    assert_eq!(config.backend, BackendKind::Hub("/tmp/net.sock".into()));
+    assert_eq!((config.mac, config.link, config.pcap), ([2, 0, 0, 0, 0, 1], false, Some("out.pcap".into())));
+    assert_eq!("echo".parse::<NetConfig>().unwrap().mac, DEFAULT_MAC);
+    assert!("loopback,mac=02:00".parse::<NetConfig>().is_err());
+    assert!("tap".parse::<NetConfig>().is_err());
+}
+
+#[test]
+pub fn pcap() {
+    let path = std::env::temp_dir().join(format!("net-{}.pcap", std::process::id()));
+    let mut machine = virtio_machine(VirtioNet::new(GUEST_MAC, Echo::default()).with_pcap(Pcap::create(&path).unwrap()));
+    driver_init(&mut machine);
+    make_available(&mut machine, RECEIVE_PAGE, 0, RECEIVE_BUFFER, 0x800, true);
+    machine.write(TRANSPORT + QUEUE_NOTIFY, RECEIVE as u32).unwrap();
+    let request = ping();
+    transmit(&mut machine, 0, &request);
+    let capture = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(capture[..4], 0xA1B2_C3D4u32.to_le_bytes());
+    assert_eq!(capture[20..24], 1u32.to_le_bytes(), "Ethernet");
+    // The request, then the reply
+    let first = &capture[24..];
+    assert_eq!(first[8..12], (request.len() as u32).to_le_bytes());
+    assert_eq!(first[16..16 + request.len()], request);
+    let second = &first[16 + request.len()..];
+    assert_eq!(second.len(), 16 + request.len());
+    assert_eq!(second[16..22], GUEST_MAC);
+}
+
+/// Polls `backend` until it has a frame
+fn wait_frame(backend: &mut impl Backend) -> Vec<u8> {
+    for _ in 0..1000 {
+        if let Some(frame) = backend.receive() {return frame}
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    panic!("No frame")
+}
+
+#[test]
+pub fn hub() {
+    let path = std::env::temp_dir().join(format!("net-hub-{}.sock", std::process::id()));
+    let mut server = Hub::open(&path).unwrap();
+    assert!(server.serving());
+    let mut first = Hub::open(&path).unwrap();
+    let mut second = Hub::open(&path).unwrap();
+    assert!(!first.serving());
+    server.poll();
+    assert_eq!(server.peers(), 2);
+    // The server forwards between its peers
+    first.send(b"from the first");
+    assert_eq!(wait_frame(&mut server), b"from the first");
+    assert_eq!(wait_frame(&mut second), b"from the first");
+    server.send(b"from the server");
+    assert_eq!(wait_frame(&mut first), b"from the server");
+    assert_eq!(wait_frame(&mut second), b"from the server");
+    drop(second);
+    first.send(b"again");
+    assert_eq!(wait_frame(&mut server), b"again");
+    assert_eq!(server.peers(), 1);
+    // The socket goes with the server
+    drop(server);
+    assert!(!path.exists());
+}
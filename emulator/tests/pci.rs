@@ -0,0 +1,237 @@
+mod common;
+
+use common::{builder, causes, descriptor, BASE, DESC_NEXT, DESC_WRITE};
+use emulator::board::{Aia, Board, DeviceKind};
+use emulator::cpu::csr::file::{MIE, MIP, MSTATUS, MTVEC};
+use emulator::machine::Machine;
+use emulator::mem::{MemMap, Memory, MemoryMap};
+use emulator::pci::*;
+use emulator::virtio::pci::{VirtioPci, BAR, COMMON, ISR, NOTIFY};
+use emulator::virtio::*;
+
+const ECAM: u64 = 0x3000_0000;
+/// Where the test assigns the BAR of the virtio function
+const WINDOW: u64 = 0x4000_0000;
+const ECHO_ID: u32 = 0x3F;
+const MEIP: u64 = 1 << 11;
+// PLIC registers, the enable bits and claim/complete register of the M-mode context of hart 0
+const PLIC_PENDING: u64 = 0x1000;
+const PLIC_ENABLE: u64 = 0x2000;
+const PLIC_CLAIM: u64 = 0x20_0004;
+
+/// Sends back what it gets, reversed
+#[derive(Default)]
+struct Echo {
+    resets: usize,
+}
+impl VirtioDevice for Echo {
+    fn device_id(&self) -> u32 {
+        ECHO_ID
+    }
+    fn queue_sizes(&self) -> Vec<u16> {
+        vec![8]
+    }
+    fn config(&self) -> Vec<u8> {
+        b"echo".to_vec()
+    }
+    fn process(&mut self, queue: usize, queues: &mut [Queue], mem: &mut Memory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(mem) {
+            let mut data = chain.read(mem);
+            data.reverse();
+            let written = chain.write(mem, &data);
+            queues[queue].push(mem, chain.head, written);
+            used = true;
+        }
+        used
+    }
+    fn reset(&mut self) {
+        self.resets += 1;
+    }
+}
+
+/// virt with an Echo behind virtio-pci in slot 1
+fn machine(aia: Aia) -> Machine {
+    let board = Board::virt().with_aia(aia).unwrap().with_pcie(true);
+    let mut machine = builder(board, "loop: j loop").build().unwrap();
+    let host = machine.vm.mem.device_mut::<PcieHost>(ECAM).unwrap();
+    assert_eq!(host.add(VirtioPci::new(Echo::default())), Some(1));
+    machine
+}
+fn config_address(slot: u64, register: usize) -> u64 {
+    ECAM + (slot << 15) + register as u64
+}
+/// Assigns the BAR of slot 1 and turns on memory decoding and bus mastering
+fn enable(machine: &mut Machine) {
+    machine.write(config_address(1, BAR0 + 4 * BAR), WINDOW as u32).unwrap();
+    machine.write(config_address(1, BAR0 + 4 * BAR + 4), 0u32).unwrap();
+    machine.write(config_address(1, COMMAND), COMMAND_MEMORY | COMMAND_BUS_MASTER).unwrap();
+}
+
+#[test]
+pub fn enumeration() {
+    let mut machine = machine(Aia::None);
+    let read16 = |machine: &mut Machine, slot, register| machine.read::<u16>(config_address(slot, register)).unwrap();
+    assert_eq!((read16(&mut machine, 0, VENDOR_ID), read16(&mut machine, 0, DEVICE_ID)), (0x1B36, 0x0008));
+    assert_eq!((read16(&mut machine, 1, VENDOR_ID), read16(&mut machine, 1, DEVICE_ID)), (0x1AF4, 0x1040 + ECHO_ID as u16));
+    // Empty slots and other functions
+    assert_eq!(read16(&mut machine, 2, VENDOR_ID), 0xFFFF);
+    assert_eq!(machine.read::<u16>(config_address(1, 0) + (1 << 12)).unwrap(), 0xFFFF);
+    assert_eq!(machine.read::<u8>(config_address(1, INTERRUPT_PIN)).unwrap(), 1);
+    // The virtio structures, through the capability list
+    assert_ne!(read16(&mut machine, 1, STATUS) & STATUS_CAPABILITIES, 0);
+    let mut cfg_types = Vec::new();
+    let mut capability = machine.read::<u8>(config_address(1, CAPABILITIES)).unwrap() as usize;
+    while capability != 0 {
+        assert_eq!(machine.read::<u8>(config_address(1, capability)).unwrap(), 0x09);
+        cfg_types.push(machine.read::<u8>(config_address(1, capability + 3)).unwrap());
+        capability = machine.read::<u8>(config_address(1, capability + 1)).unwrap() as usize;
+    }
+    assert_eq!(cfg_types, [1, 2, 3, 4, 5]);
+    // Read-only registers stay
+    machine.write(config_address(1, VENDOR_ID), 0u16).unwrap();
+    assert_eq!(read16(&mut machine, 1, VENDOR_ID), 0x1AF4);
+}
+
+#[test]
+pub fn bar_sizing() {
+    let mut machine = machine(Aia::None);
+    let bar = config_address(1, BAR0 + 4 * BAR);
+    assert_eq!(machine.read::<u32>(bar).unwrap(), 0b100, "64 bits memory BAR");
+    machine.write(bar, u32::MAX).unwrap();
+    machine.write(bar + 4, u32::MAX).unwrap();
+    assert_eq!(machine.read::<u32>(bar).unwrap(), 0xFFFF_C004);
+    assert_eq!(machine.read::<u32>(bar + 4).unwrap(), u32::MAX);
+    // Unused BARs read 0 whatever is written
+    machine.write(config_address(1, BAR0), u32::MAX).unwrap();
+    assert_eq!(machine.read::<u32>(config_address(1, BAR0)).unwrap(), 0);
+    // Decoded once assigned and with memory space on
+    machine.write(bar, WINDOW as u32).unwrap();
+    machine.write(bar + 4, 0u32).unwrap();
+    assert_eq!(machine.read::<u16>(WINDOW + COMMON + 0x12).unwrap(), 0);
+    machine.write(config_address(1, COMMAND), COMMAND_MEMORY).unwrap();
+    assert_eq!(machine.read::<u16>(WINDOW + COMMON + 0x12).unwrap(), 1, "num_queues");
+    assert_eq!(machine.read::<u32>(WINDOW + 0x2000).unwrap(), u32::from_le_bytes(*b"echo"));
+    // Moved to the end of the address space, the window decodes nothing
+    machine.write(bar, 0xFFFF_C000u32).unwrap();
+    machine.write(bar + 4, u32::MAX).unwrap();
+    assert_eq!(machine.read::<u16>(WINDOW + COMMON + 0x12).unwrap(), 0);
+}
+
+/// Sets up queue 0 of the Echo and sends it "hello", the answer goes to `data` + 0x100
+/// Returns the device area and `data`
+fn send_hello(machine: &mut Machine) -> (u64, u64) {
+    let common = WINDOW + COMMON;
+    let (desc, driver, device, data) = (BASE + 0x1000, BASE + 0x2000, BASE + 0x3000, BASE + 0x4000);
+    machine.write(common + 0x14, STATUS_ACKNOWLEDGE | STATUS_DRIVER).unwrap();
+    // VIRTIO_F_VERSION_1 is offered and accepted
+    machine.write(common, 1u32).unwrap();
+    assert_eq!(machine.read::<u32>(common + 0x04).unwrap(), 1);
+    machine.write(common + 0x08, 1u32).unwrap();
+    machine.write(common + 0x0C, 1u32 | 1 << 5).unwrap();
+    assert_eq!(machine.read::<u32>(common + 0x0C).unwrap(), 1, "only offered features are kept");
+    machine.write(common + 0x14, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK).unwrap();
+    // Queue 0, 4 entries
+    machine.write(common + 0x16, 0u16).unwrap();
+    assert_eq!(machine.read::<u16>(common + 0x18).unwrap(), 8);
+    machine.write(common + 0x18, 4u16).unwrap();
+    machine.write(common + 0x20, desc).unwrap();
+    machine.write(common + 0x28, driver).unwrap();
+    machine.write(common + 0x30, device).unwrap();
+    machine.write(common + 0x1C, 1u16).unwrap();
+    machine.write(common + 0x14, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK).unwrap();
+
+    // "hello" and a 16 bytes buffer for the answer
+    machine.write_bytes(data, b"hello").unwrap();
+    descriptor(machine, desc, 0, data, 5, DESC_NEXT);
+    descriptor(machine, desc, 1, data + 0x100, 16, DESC_WRITE);
+    machine.write(driver + 4, 0u16).unwrap();
+    machine.write(driver + 2, 1u16).unwrap();
+    machine.write(WINDOW + NOTIFY, 0u16).unwrap();
+    (device, data)
+}
+
+#[test]
+pub fn virtqueue() {
+    let mut machine = machine(Aia::Aplic);
+    enable(&mut machine);
+    let (device, data) = send_hello(&mut machine);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.read::<u16>(device + 2).unwrap(), 1);
+    assert_eq!((machine.read::<u32>(device + 4).unwrap(), machine.read::<u32>(device + 8).unwrap()), (0, 5));
+    let mut answer = [0; 5];
+    machine.read_bytes(data + 0x100, &mut answer).unwrap();
+    assert_eq!(&answer, b"olleh");
+
+    // INTA of slot 1 is INTB
+    let irqs = |machine: &Machine| machine.vm.mem.pending_irqs().collect::<Vec<_>>();
+    assert_eq!(irqs(&machine), [PCIE_IRQ + 1]);
+    assert_ne!(machine.read::<u16>(config_address(1, STATUS)).unwrap() & STATUS_INTERRUPT, 0);
+    machine.write(config_address(1, COMMAND), COMMAND_MEMORY | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE).unwrap();
+    assert_eq!(irqs(&machine), []);
+    // Reading the ISR acknowledges it
+    assert_eq!(machine.read::<u8>(WINDOW + ISR).unwrap(), INTERRUPT_USED_BUFFER as u8);
+    assert_eq!(machine.read::<u8>(WINDOW + ISR).unwrap(), 0);
+
+    // Writing 0 to the status resets the device
+    machine.write(WINDOW + COMMON + 0x14, 0u8).unwrap();
+    assert_eq!(machine.read::<u16>(WINDOW + COMMON + 0x1C).unwrap(), 0);
+    let host = machine.vm.mem.device::<PcieHost>(ECAM).unwrap();
+    assert_eq!(host.function::<VirtioPci>(1).unwrap().device::<Echo>().unwrap().resets, 1);
+}
+
+#[test]
+pub fn intx_through_plic() {
+    let mut machine = machine(Aia::None);
+    let causes = causes(&mut machine);
+    enable(&mut machine);
+    // INTA of slot 1 is INTB, enabled in the M-mode context of hart 0
+    let (plic, source) = (MemMap::PLIC.base(), PCIE_IRQ + 1);
+    machine.write(plic + 4 * source as u64, 1u32).unwrap();
+    machine.write(plic + PLIC_ENABLE + 4 * (source / 32) as u64, 1u32 << (source % 32)).unwrap();
+    // The handler is the same loop, mstatus.MIE stays cleared
+    machine.set_csr(0, MTVEC, BASE);
+    machine.set_csr(0, MIE, MEIP);
+    machine.set_csr(0, MSTATUS, 1 << 3);
+    send_hello(&mut machine);
+    machine.run(Some(2)).unwrap();
+    assert_eq!(*causes.borrow(), [1 << 63 | 11]);
+    assert_eq!(machine.read::<u32>(plic + PLIC_PENDING + 4).unwrap(), 1 << (source % 32));
+    assert_eq!(machine.read::<u32>(plic + PLIC_CLAIM).unwrap(), source);
+    assert_eq!(machine.read::<u32>(plic + PLIC_CLAIM).unwrap(), 0);
+    // Claimed until completed, then pending again while the line is high
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MEIP, 0);
+    machine.write(plic + PLIC_CLAIM, source).unwrap();
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MEIP, MEIP);
+    // Reading the ISR lowers the line
+    assert_eq!(machine.read::<u8>(WINDOW + ISR).unwrap(), INTERRUPT_USED_BUFFER as u8);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(machine.csr(0, MIP) & MEIP, 0);
+}
+
+#[test]
+pub fn device_tree() {
+    let contains = |dtb: &[u8], needle: &[u8]| dtb.windows(needle.len()).any(|window| window == needle);
+    let plic = machine(Aia::None).device_tree("");
+    assert!(contains(&plic, b"pci-host-ecam-generic\0"));
+    assert!(contains(&plic, b"riscv,plic0\0"));
+    assert!(contains(&plic, b"interrupt-map\0"));
+    let aplic = machine(Aia::Aplic).device_tree("");
+    assert!(contains(&aplic, b"interrupt-map\0"));
+    assert!(!contains(&aplic, b"riscv,plic0\0"));
+    // Without an interrupt controller, INTx isn't described
+    let board = Board::preset("sifive_u").unwrap().with_pcie(true);
+    let without = Machine::builder().board(board).build().unwrap().device_tree("");
+    assert!(contains(&without, b"pci-host-ecam-generic\0"));
+    assert!(!contains(&without, b"interrupt-map\0"));
+
+    // Only there when asked for
+    let board = Board::load("virt,pcie=on").unwrap();
+    assert!(board.devices.iter().any(|device| device.kind == DeviceKind::Pcie && device.base == MemMap::PcieEcam.base()));
+    assert_eq!(Board::load("virt,pcie=on,pcie=off").unwrap(), Board::virt());
+    assert!(Board::load("virt,pcie=yes").is_err());
+    let dtb = Machine::builder().board(Board::virt()).build().unwrap().device_tree("");
+    assert!(!contains(&dtb, b"pci-host-ecam-generic\0"));
+}
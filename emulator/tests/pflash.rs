@@ -0,0 +1,102 @@
+mod common;
+
+use common::builder;
+use emulator::board::{Board, DeviceKind};
+use emulator::machine::Machine;
+use emulator::mem::{MemMap, MemoryMap};
+use emulator::pflash::*;
+
+const FLASH: u64 = 0x2000_0000;
+
+fn machine() -> Machine {
+    let board = Board::virt().with_pflash(1).unwrap();
+    builder(board, "loop: j loop").build().unwrap()
+}
+fn command(machine: &mut Machine, addr: u64, command: u16) {
+    machine.write(addr, command).unwrap();
+}
+/// Word `index` of the CFI query structure
+fn query(machine: &mut Machine, index: u64) -> u16 {
+    machine.read::<u16>(FLASH + 2 * index).unwrap()
+}
+
+#[test]
+pub fn identify() {
+    let mut machine = machine();
+    command(&mut machine, FLASH + 0x55 * 2, 0x98);
+    assert_eq!([query(&mut machine, 0x10), query(&mut machine, 0x11), query(&mut machine, 0x12)], [b'Q' as u16, b'R' as u16, b'Y' as u16]);
+    // Intel command set, 32MiB of 256KiB blocks
+    assert_eq!(query(&mut machine, 0x13), 1);
+    assert_eq!(query(&mut machine, 0x27), 25);
+    assert_eq!([query(&mut machine, 0x2D), query(&mut machine, 0x2E)], [127, 0]);
+    assert_eq!([query(&mut machine, 0x2F), query(&mut machine, 0x30)], [0, 4]);
+    assert_eq!(query(&mut machine, 0x31), b'P' as u16);
+    command(&mut machine, FLASH, 0x90);
+    assert_eq!([query(&mut machine, 0), query(&mut machine, 1)], [0x89, 0x18]);
+    command(&mut machine, FLASH, 0xFF);
+    assert_eq!(machine.read::<u32>(FLASH).unwrap(), u32::MAX, "erased");
+
+    let dtb = machine.device_tree("");
+    assert!(dtb.windows(10).any(|window| window == b"cfi-flash\0"));
+}
+
+#[test]
+pub fn program_and_erase() {
+    let mut machine = machine();
+    let addr = FLASH + BLOCK_SIZE + 0x10;
+    command(&mut machine, addr, 0x40);
+    machine.write(addr, 0x1234u16).unwrap();
+    assert_eq!(machine.read::<u16>(addr).unwrap(), STATUS_READY as u16, "status after programming");
+    command(&mut machine, addr, 0xFF);
+    assert_eq!(machine.read::<u16>(addr).unwrap(), 0x1234);
+    // Programming only clears bits
+    command(&mut machine, addr, 0x10);
+    machine.write(addr, 0xFF0Fu16).unwrap();
+    command(&mut machine, addr, 0xFF);
+    assert_eq!(machine.read::<u16>(addr).unwrap(), 0x1204);
+    command(&mut machine, FLASH, 0x40);
+    machine.write(FLASH, 0u16).unwrap();
+
+    // Erasing the block of addr leaves the first one alone
+    command(&mut machine, addr + 2, 0x20);
+    command(&mut machine, addr + 2, 0xD0);
+    command(&mut machine, addr, 0x70);
+    assert_eq!(machine.read::<u8>(addr).unwrap(), STATUS_READY);
+    command(&mut machine, addr, 0xFF);
+    assert_eq!(machine.read::<u16>(addr).unwrap(), 0xFFFF);
+    assert_eq!(machine.read::<u16>(FLASH).unwrap(), 0);
+
+    // An erase not confirmed is an error, until the status is cleared
+    command(&mut machine, FLASH, 0x20);
+    command(&mut machine, FLASH, 0xFF);
+    assert_eq!(machine.read::<u8>(FLASH).unwrap(), STATUS_READY | STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR);
+    command(&mut machine, FLASH, 0x50);
+    assert_eq!(machine.read::<u8>(FLASH).unwrap(), STATUS_READY);
+    command(&mut machine, FLASH, 0xFF);
+    assert_eq!(machine.read::<u16>(FLASH).unwrap(), 0);
+}
+
+#[test]
+pub fn persistence() {
+    let path = std::env::temp_dir().join(format!("pflash-{}.img", std::process::id()));
+    std::fs::write(&path, b"boot").unwrap();
+    let mut machine = machine();
+    *machine.vm.mem.device_mut::<Pflash>(FLASH).unwrap() = Pflash::open(&path, BANK_SIZE).unwrap();
+    // Smaller images are padded with erased blocks
+    assert_eq!(machine.read::<u64>(FLASH).unwrap().to_le_bytes(), *b"boot\xFF\xFF\xFF\xFF");
+    command(&mut machine, FLASH + 4, 0x40);
+    machine.write(FLASH + 4, 0x2121u16).unwrap();
+    drop(machine);
+
+    let image = std::fs::read(&path).unwrap();
+    assert_eq!(image.len() as u64, BANK_SIZE);
+    assert_eq!(&image[..8], b"boot!!\xFF\xFF");
+    assert_eq!(&Pflash::open(&path, BANK_SIZE).unwrap().data()[..6], b"boot!!");
+    assert!(Pflash::open(&path, BANK_SIZE / 2).is_err(), "bigger than the bank");
+    std::fs::remove_file(&path).unwrap();
+
+    let board = Board::virt().with_pflash(2).unwrap();
+    let banks: Vec<_> = board.devices.iter().filter(|device| device.kind == DeviceKind::Flash).map(|device| device.base).collect();
+    assert_eq!(banks, [MemMap::FLASH.base(), MemMap::FLASH.base() + BANK_SIZE]);
+    assert!(Board::virt().with_pflash(3).is_err());
+}
@@ -0,0 +1,87 @@
+use emulator::cpu::csr::CsrID;
+use emulator::cpu::{PrivilegeLevel, CPU};
+use emulator::mem::AccessType::{Execute, Read, Write};
+
+const R: u64 = 1;
+const W: u64 = 2;
+const X: u64 = 4;
+const TOR: u64 = 1<<3;
+const NA4: u64 = 2<<3;
+const NAPOT: u64 = 3<<3;
+const L: u64 = 1<<7;
+
+fn pmpcfg(cpu: &mut CPU, reg: u16, val: u64) {
+    cpu.write_csr(CsrID::new(0x3A0+reg), val).unwrap()
+}
+fn pmpaddr(cpu: &mut CPU, i: u16, addr: u64) {
+    cpu.write_csr(CsrID::new(0x3B0+i), addr).unwrap()
+}
+
+#[test]
+pub fn default_rules() {
+    let mut cpu = CPU::default();
+    assert!(cpu.pmp_check(0x8000_0000, 4, Execute).is_ok());
+    cpu.privilege_level = PrivilegeLevel::Supervisor;
+    // Nothing matches, S-mode fails when entries are implemented
+    assert!(cpu.pmp_check(0x8000_0000, 4, Execute).is_err());
+    cpu.pmp_entries = 0;
+    assert!(cpu.pmp_check(0x8000_0000, 4, Execute).is_ok());
+}
+
+#[test]
+pub fn tor_and_napot() {
+    let mut cpu = CPU::default();
+    // Entry 0: NAPOT 0x8000_0000-0x8000_1000 read only
+    pmpaddr(&mut cpu, 0, (0x8000_0000 >> 2) | ((0x1000 >> 3)-1));
+    // Entry 1: TOR 0-0xFFFF_FFFF_FFFF RWX, like the kernel does
+    pmpaddr(&mut cpu, 1, 0xFFFF_FFFF_FFFF);
+    pmpcfg(&mut cpu, 0, (NAPOT|R) | ((TOR|R|W|X) << 8));
+    cpu.privilege_level = PrivilegeLevel::User;
+
+    assert!(cpu.pmp_check(0x8000_0ff8, 8, Read).is_ok());
+    // Entry 0 has priority over entry 1
+    assert!(cpu.pmp_check(0x8000_0000, 8, Write).is_err());
+    assert!(cpu.pmp_check(0x8000_1000, 8, Write).is_ok());
+    // Straddles entry 0 and 1
+    assert!(cpu.pmp_check(0x8000_0ffc, 8, Read).is_err());
+    // Above the TOR
+    assert!(cpu.pmp_check(0xFFFF_FFFF_FFFF << 2, 1, Read).is_err());
+
+    // M-mode ignores unlocked entries
+    cpu.privilege_level = PrivilegeLevel::Machine;
+    assert!(cpu.pmp_check(0x8000_0000, 8, Write).is_ok());
+}
+
+#[test]
+pub fn locked_entries() {
+    let mut cpu = CPU::default();
+    pmpaddr(&mut cpu, 3, 0x1000 >> 2);
+    pmpaddr(&mut cpu, 4, 0x2000 >> 2);
+    pmpcfg(&mut cpu, 0, (NA4|X|L) << 24 | (TOR|R|L) << 32);
+    // Locked entries apply to M-mode
+    assert!(cpu.pmp_check(0x1000, 4, Execute).is_ok());
+    assert!(cpu.pmp_check(0x1000, 4, Read).is_err());
+    assert!(cpu.pmp_check(0x1800, 4, Read).is_ok());
+    assert!(cpu.pmp_check(0x1800, 4, Write).is_err());
+
+    // Writes to the locked configs and addresses are ignored, including the TOR bottom
+    pmpcfg(&mut cpu, 0, 0);
+    pmpaddr(&mut cpu, 3, 0);
+    pmpaddr(&mut cpu, 4, 0);
+    assert_eq!(cpu.pmp_cfg(4).0 as u64, TOR|R|L);
+    assert_eq!(cpu.pmp_addr(3), 0x1000 >> 2);
+    assert_eq!(cpu.pmp_addr(4), 0x2000 >> 2);
+    // The other entries of the register are still writable
+    pmpcfg(&mut cpu, 0, (NA4|X|L) << 24 | (TOR|R|L) << 32 | (NA4|R));
+    assert_eq!(cpu.pmp_cfg(0).0 as u64, NA4|R);
+}
+
+#[test]
+pub fn mprv_uses_mpp() {
+    let mut cpu = CPU::default();
+    // MPRV=1, MPP=U
+    cpu.csrs[0x300].0 = 1 << 17;
+    assert!(cpu.pmp_check(0x8000_0000, 4, Read).is_err());
+    // Fetches are not affected
+    assert!(cpu.pmp_check(0x8000_0000, 4, Execute).is_ok());
+}
@@ -0,0 +1,78 @@
+use emulator::loader::{Symbol, Symbols};
+use emulator::profiler::{FunctionStats, Profiler};
+use emulator::vm::VM;
+
+const BASE: u64 = 0x8000_0000;
+
+// main calls f, which calls g, then calls g directly and spins
+const PROGRAM: [u32; 11] = [
+    0x010000ef, // main: jal f
+    0x020000ef, //       jal g
+    0x0000006f, // loop: j loop
+    0x00000013, //       nop
+    0x00008413, // f:    mv s0, ra
+    0x010000ef, //       jal g
+    0x00040093, //       mv ra, s0
+    0x00008067, //       ret
+    0x00000013, //       nop
+    0x00150513, // g:    addi a0, a0, 1
+    0x00008067, //       ret
+];
+
+fn symbols() -> Symbols {
+    let sym = |addr, size, name: &str| Symbol { addr: BASE + addr, size, name: name.to_string(), code: true };
+    // main is an assembly label without size
+    Symbols::new(vec![sym(0x24, 8, "g"), sym(0, 0, "main"), sym(0x10, 0x10, "f")])
+}
+fn profile(period: u64) -> VM {
+    let bytes = PROGRAM.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let mut vm = VM::new(bytes, 1 << 20).unwrap();
+    vm.symbols = symbols();
+    vm.profiler = Some(Profiler::new(vm.cpu.pc, period));
+    vm.run_for(Some(12)).unwrap();
+    vm
+}
+
+#[test]
+pub fn symbol_lookup() {
+    let symbols = symbols();
+    assert_eq!(symbols.name(BASE + 0x8), "main");
+    assert_eq!(symbols.name(BASE + 0x1c), "f");
+    // Past the end of f, the unsized label before it covers the gap
+    assert_eq!(symbols.name(BASE + 0x20), "main");
+    assert_eq!(symbols.name(BASE + 0x28), "g");
+    assert_eq!(symbols.name(BASE + 0x2c), "main");
+    assert_eq!(symbols.name(0x1000), "0x1000");
+}
+
+#[test]
+pub fn exact_profile() {
+    let vm = profile(1);
+    let profiler = vm.profiler.as_ref().unwrap();
+    assert_eq!(profiler.total_samples(), 12);
+    assert_eq!(profiler.pc_samples(BASE + 0x8), 2);
+    assert_eq!(profiler.pc_samples(BASE + 0x24), 2);
+    assert_eq!(profiler.depth(), 0);
+
+    let folded: Vec<_> = profiler.folded_stacks(&vm.symbols).into_iter().map(|(stack, count)| format!("{stack} {count}")).collect();
+    assert_eq!(folded, ["main 4", "main;f 4", "main;f;g 2", "main;g 2"]);
+
+    let stats = |name: &str, self_samples, total_samples| FunctionStats { name: name.to_string(), self_samples, total_samples };
+    assert_eq!(profiler.functions(&vm.symbols), [stats("main", 4, 12), stats("f", 4, 6), stats("g", 4, 4)]);
+
+    let mut table = Vec::new();
+    profiler.write_table(&vm.symbols, &mut table).unwrap();
+    let table = String::from_utf8(table).unwrap();
+    assert!(table.lines().nth(1).unwrap().ends_with("  main"));
+    assert!(table.ends_with("12 samples, one every 1 instructions\n"));
+}
+
+#[test]
+pub fn sampling_profile() {
+    let vm = profile(3);
+    let profiler = vm.profiler.as_ref().unwrap();
+    assert_eq!(profiler.total_samples(), 4);
+    // Samples at the 3rd, 6th, 9th and 12th instructions: 0x14, 0x18, 0x28, 0x08
+    let folded = profiler.folded_stacks(&vm.symbols);
+    assert_eq!(folded, [("main".to_string(), 1), ("main;f".to_string(), 2), ("main;g".to_string(), 1)]);
+}
@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use emulator::testsuite::*;
+
+const BASE: u64 = 0x8000_0000;
+
+/// Minimal ELF64 executable: one PT_LOAD segment at BASE with `code` and absolute symbols
+fn elf(code: &[u32], symbols: &[(&str, u64)]) -> Vec<u8> {
+    fn align(out: &mut Vec<u8>) {
+        out.resize(out.len().next_multiple_of(8), 0);
+    }
+    let code: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let mut out = vec![0; 128];
+    out.extend_from_slice(&code);
+    align(&mut out);
+
+    let mut strtab = vec![0];
+    let symtab_offset = out.len();
+    out.extend_from_slice(&[0; 24]);
+    for (name, value) in symbols {
+        out.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+        out.push(1 << 4); // Global, no type
+        out.push(0);
+        out.extend_from_slice(&0xFFF1u16.to_le_bytes()); // Absolute
+        out.extend_from_slice(&value.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+    let symtab_size = out.len() - symtab_offset;
+    let strtab_offset = out.len();
+    out.extend_from_slice(&strtab);
+    let shstrtab = b"\0.symtab\0.strtab\0.shstrtab\0";
+    let shstrtab_offset = out.len();
+    out.extend_from_slice(shstrtab);
+    align(&mut out);
+
+    let shoff = out.len();
+    out.extend_from_slice(&[0; 64]);
+    for (name, kind, offset, size, link, entsize) in [
+        (1u32, 2u32, symtab_offset, symtab_size, 2u32, 24u64),
+        (9, 3, strtab_offset, strtab.len(), 0, 0),
+        (17, 3, shstrtab_offset, shstrtab.len(), 0, 0),
+    ] {
+        out.extend_from_slice(&name.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes()); // Flags
+        out.extend_from_slice(&0u64.to_le_bytes()); // Address
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+        out.extend_from_slice(&(size as u64).to_le_bytes());
+        out.extend_from_slice(&link.to_le_bytes());
+        out.extend_from_slice(&(if kind == 2 {1u32} else {0}).to_le_bytes()); // First global symbol
+        out.extend_from_slice(&8u64.to_le_bytes());
+        out.extend_from_slice(&entsize.to_le_bytes());
+    }
+
+    let mut header = vec![0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    header.extend_from_slice(&2u16.to_le_bytes()); // Executable
+    header.extend_from_slice(&243u16.to_le_bytes()); // RISC-V
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&BASE.to_le_bytes()); // Entry
+    header.extend_from_slice(&64u64.to_le_bytes()); // Program headers
+    header.extend_from_slice(&(shoff as u64).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    for half in [64u16, 56, 1, 64, 4, 3] {
+        header.extend_from_slice(&half.to_le_bytes());
+    }
+    // PT_LOAD, R+X
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&5u32.to_le_bytes());
+    for word in [128, BASE, BASE, code.len() as u64, code.len() as u64, 4] {
+        header.extend_from_slice(&word.to_le_bytes());
+    }
+    out[..header.len()].copy_from_slice(&header);
+    out
+}
+
+fn to_host_program(value: u32) -> Vec<u32> {
+    vec![
+        0x00001297, // auipc t0, 1
+        value << 20 | 0x00000313, // li t1, value
+        0x0062b023, // sd t1, 0(t0)
+        0x0000006f, // j .
+    ]
+}
+
+#[test]
+pub fn tohost_results() {
+    let tohost = [("tohost", BASE + 0x1000)];
+    let (outcome, instructions) = run_riscv_test(elf(&to_host_program(1), &tohost), 100).unwrap();
+    assert_eq!(outcome, Outcome::Pass);
+    assert_eq!(instructions, 3);
+    let (outcome, _) = run_riscv_test(elf(&to_host_program(7), &tohost), 100).unwrap();
+    assert_eq!(outcome, Outcome::Fail(3));
+    let (outcome, _) = run_riscv_test(elf(&[0x0000006f], &tohost), 100).unwrap();
+    assert_eq!(outcome, Outcome::Timeout);
+    assert!(run_riscv_test(elf(&to_host_program(1), &[]), 100).is_err());
+}
+
+#[test]
+pub fn signatures() {
+    let program = elf(&[
+        0x00002297, // auipc t0, 2
+        0x12300313, // li t1, 0x123
+        0x0062a023, // sw t1, 0(t0)
+        0xfff00313, // li t1, -1
+        0x0062a223, // sw t1, 4(t0)
+        0x00100313, // li t1, 1
+        0x0062b823, // sd t1, 16(t0)
+        0x0000006f, // j .
+    ], &[("begin_signature", BASE + 0x2000), ("end_signature", BASE + 0x2008), ("tohost", BASE + 0x2010)]);
+    let run = |reference: &str| run_arch_test(program.clone(), reference, 100).unwrap().0;
+    assert_eq!(run("00000123\nffffffff\n"), Outcome::Pass);
+    // 64 bits granularity
+    assert_eq!(run("ffffffff00000123\n"), Outcome::Pass);
+    assert_eq!(run("00000123\nfffffffe\n"), Outcome::SignatureMismatch { index: 1, expected: 0xFFFF_FFFE, found: 0xFFFF_FFFF });
+    assert_eq!(run("00000123\nffffffff\n00000000\n"), Outcome::SignatureLength { expected: 3, found: 2 });
+    assert!(parse_signature("xyz").is_err());
+}
+
+/// Runs the suites pinned in tests/fixtures/revisions, tests/fixtures/fetch.py vendors them (see the README there)
+#[test]
+pub fn suites() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let revisions = std::fs::read_to_string(fixtures.join("revisions")).unwrap();
+    let pinned: Vec<_> = revisions.lines().filter_map(|line| line.split('#').next()?.split_whitespace().next()).collect();
+    let hashes = std::fs::read_to_string(fixtures.join("SHA256SUMS")).unwrap_or_default();
+    let vendored: Vec<_> = hashes.lines().filter_map(|line| line.split_whitespace().nth(1)).collect();
+    let mut reports = Vec::new();
+    for (suite, run) in [("riscv-tests", run_riscv_tests_dir as fn(&Path, u64) -> _), ("riscv-arch-test", run_arch_tests_dir)] {
+        if !pinned.contains(&suite) {
+            eprintln!("{suite} isn't pinned in tests/fixtures/revisions, run tests/fixtures/fetch.py");
+            continue
+        }
+        let files: Vec<_> = vendored.iter().filter(|file| file.starts_with(&format!("{suite}/"))).collect();
+        assert!(!files.is_empty(), "SHA256SUMS has no file of {suite}");
+        for file in files {
+            assert!(fixtures.join(file).is_file(), "{file} is missing, run tests/fixtures/fetch.py");
+        }
+        let dir = fixtures.join(suite);
+        let suite_reports = run(&dir, DEFAULT_MAX_INSTRUCTIONS).unwrap();
+        assert!(!suite_reports.is_empty(), "{} has no tests", dir.display());
+        reports.extend(suite_reports);
+    }
+    print_reports(&reports);
+    let failed: Vec<_> = reports.iter().filter(|report| !report.outcome.passed()).map(|report| &report.name).collect();
+    assert!(failed.is_empty(), "Failed: {failed:?}");
+}
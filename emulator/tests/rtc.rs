@@ -0,0 +1,90 @@
+mod common;
+
+use common::builder;
+use emulator::board::{Aia, Board};
+use emulator::machine::Machine;
+use emulator::mem::{MemMap, MemoryMap};
+use emulator::rtc::*;
+
+const RTC: u64 = 0x101000;
+const EPOCH: u64 = 1_700_000_000;
+/// Nanoseconds per instruction with a fixed clock, one timebase tick
+const STEP_NS: u64 = 100;
+
+const TIME_LOW: u64 = RTC;
+const TIME_HIGH: u64 = RTC + 0x04;
+const ALARM_LOW: u64 = RTC + 0x08;
+const ALARM_HIGH: u64 = RTC + 0x0C;
+const IRQ_ENABLED: u64 = RTC + 0x10;
+const ALARM_STATUS: u64 = RTC + 0x18;
+const CLEAR_INTERRUPT: u64 = RTC + 0x1C;
+
+fn machine(clock: Clock) -> Machine {
+    let board = Board::virt().with_aia(Aia::Aplic).unwrap().with_rtc(true);
+    let mut machine = builder(board, "loop: j loop").build().unwrap();
+    machine.vm.mem.device_mut::<GoldfishRtc>(RTC).unwrap().set_clock(clock);
+    machine
+}
+fn time(machine: &mut Machine) -> u64 {
+    let low = machine.read::<u32>(TIME_LOW).unwrap() as u64;
+    (machine.read::<u32>(TIME_HIGH).unwrap() as u64) << 32 | low
+}
+
+#[test]
+pub fn fixed_clock() {
+    let mut machine = machine(Clock::Fixed(EPOCH));
+    let start = time(&mut machine);
+    assert_eq!(start, EPOCH * 1_000_000_000);
+    machine.run(Some(10)).unwrap();
+    assert_eq!(time(&mut machine), start + 10 * STEP_NS);
+    // The guest sets the time, high half first
+    let set = 2_000_000_000 * 1_000_000_000u64;
+    machine.write(TIME_HIGH, (set >> 32) as u32).unwrap();
+    machine.write(TIME_LOW, set as u32).unwrap();
+    assert_eq!(time(&mut machine), set);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(time(&mut machine), set + STEP_NS);
+
+    assert_eq!("host".parse(), Ok(Clock::Host));
+    assert_eq!("0".parse(), Ok(Clock::Fixed(0)));
+    assert!("yesterday".parse::<Clock>().is_err());
+}
+
+#[test]
+pub fn host_clock() {
+    let mut machine = machine(Clock::Host);
+    let host = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos() as u64;
+    let time = time(&mut machine);
+    assert!(time.abs_diff(host) < 60 * 1_000_000_000, "{time} isn't close to {host}");
+}
+
+#[test]
+pub fn alarm() {
+    let mut machine = machine(Clock::Fixed(EPOCH));
+    let irqs = |machine: &Machine| machine.vm.mem.pending_irqs().collect::<Vec<_>>();
+    let alarm = time(&mut machine) + 5 * STEP_NS;
+    machine.write(IRQ_ENABLED, 1u32).unwrap();
+    machine.write(ALARM_HIGH, (alarm >> 32) as u32).unwrap();
+    machine.write(ALARM_LOW, alarm as u32).unwrap();
+    assert_eq!(machine.read::<u32>(ALARM_STATUS).unwrap(), 1);
+    machine.run(Some(4)).unwrap();
+    assert_eq!(irqs(&machine), []);
+    machine.run(Some(1)).unwrap();
+    assert_eq!(irqs(&machine), [RTC_IRQ]);
+    assert_eq!(machine.read::<u32>(ALARM_STATUS).unwrap(), 0);
+    machine.write(CLEAR_INTERRUPT, 1u32).unwrap();
+    assert_eq!(irqs(&machine), []);
+
+    // In the past, it fires right away, but only reaches the line when enabled
+    machine.write(IRQ_ENABLED, 0u32).unwrap();
+    machine.write(ALARM_LOW, 0u32).unwrap();
+    assert_eq!(machine.read::<u32>(ALARM_STATUS).unwrap(), 0);
+    assert_eq!(irqs(&machine), []);
+    machine.write(IRQ_ENABLED, 1u32).unwrap();
+    assert_eq!(irqs(&machine), [RTC_IRQ]);
+
+    let dtb = machine.device_tree("");
+    let contains = |needle: &[u8]| dtb.windows(needle.len()).any(|window| window == needle);
+    assert!(contains(b"google,goldfish-rtc\0"));
+    assert_eq!(MemMap::RTC.base(), RTC);
+}
@@ -0,0 +1,200 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use emulator::cpu::csr::file::*;
+use emulator::cpu::PrivilegeLevel;
+use emulator::sbi::*;
+use emulator::vm::VM;
+
+const BASE: u64 = 0x8000_0000;
+const STVEC_ADDR: u64 = 0x8000_0100;
+const DATA: u64 = 0x8000_1000;
+const ECALL: u32 = 0x00000073;
+const NOP: u32 = 0x00000013;
+
+#[derive(Clone, Default)]
+struct Console(Rc<RefCell<Vec<u8>>>);
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An ecall followed by nops, booted in S-mode
+fn booted() -> (VM, Console) {
+    let program: Vec<u8> = [ECALL].into_iter().chain([NOP; 127]).flat_map(u32::to_le_bytes).collect();
+    let mut vm = VM::new(program, 16 << 20).unwrap();
+    let console = Console::default();
+    vm.boot_supervisor(Sbi::with_console(Box::new(console.clone())), "console=hvc0").unwrap();
+    vm.cpu.set_csr_value(STVEC, STVEC_ADDR);
+    (vm, console)
+}
+/// Runs the ecall at BASE, returns (a0 as an error, a1)
+fn call(vm: &mut VM, ext: u64, fun: u64, args: &[u64]) -> (i64, u64) {
+    vm.cpu.pc = BASE;
+    vm.cpu.regs[17] = ext;
+    vm.cpu.regs[16] = fun;
+    vm.cpu.regs[10..10+args.len()].copy_from_slice(args);
+    vm.step().unwrap();
+    assert_eq!(vm.cpu.pc, BASE + 4, "The ecall trapped");
+    (vm.cpu.regs[10] as i64, vm.cpu.regs[11])
+}
+const SUCCESS: i64 = 0;
+
+#[test]
+pub fn boot() {
+    let (vm, _) = booted();
+    assert_eq!(vm.cpu.privilege_level, PrivilegeLevel::Supervisor);
+    assert_eq!(vm.cpu.pc, BASE);
+    assert_eq!(vm.cpu.regs[10], 0);
+    let dtb = vm.cpu.regs[11];
+    assert_eq!(dtb % (2 << 20), 0);
+    assert!((BASE..BASE + (16 << 20)).contains(&dtb));
+    // Ecalls from S-mode aren't delegated, the firmware handles them
+    assert_eq!(vm.cpu.csr_value(MEDELEG) & (1 << 9), 0);
+    assert_eq!(vm.cpu.csr_value(MIDELEG), S_INTERRUPTS);
+}
+
+#[test]
+pub fn small_ram() {
+    let mut vm = VM::new(vec![0; 4], 64 << 10).unwrap();
+    vm.boot_supervisor(Sbi::default(), "").unwrap();
+    assert!((BASE..BASE + (64 << 10)).contains(&vm.cpu.regs[11]));
+    // Too small for the device tree, it's an error rather than a write outside RAM
+    let mut vm = VM::new(vec![0; 4], 256).unwrap();
+    assert!(vm.boot_supervisor(Sbi::default(), "").is_err());
+}
+
+#[test]
+pub fn device_tree() {
+    let (mut vm, _) = booted();
+    let dtb = vm.cpu.regs[11];
+    let mut header = [0; 8];
+    vm.mem.read(dtb, &mut header).unwrap();
+    assert_eq!(u32::from_be_bytes(header[..4].try_into().unwrap()), 0xd00dfeed);
+    let size = u32::from_be_bytes(header[4..].try_into().unwrap());
+    let mut blob = vec![0; size as usize];
+    vm.mem.read(dtb, &mut blob).unwrap();
+    let contains = |needle: &[u8]| blob.windows(needle.len()).any(|window| window == needle);
+    assert!(contains(b"rv64imac_zicntr_zicsr_zifencei\0"));
+    assert!(contains(b"console=hvc0\0"));
+    assert!(contains(b"memory@80000000\0"));
+    assert!(contains(b"/soc/serial@10000000\0"));
+    // reg of the memory node
+    assert!(contains(&[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x01, 0, 0, 0]));
+    assert_eq!(emulator::fdt::isa_string(vm.cpu.csr_value(MISA)), "rv64imac_zicntr_zicsr_zifencei");
+}
+
+#[test]
+pub fn base() {
+    let (mut vm, _) = booted();
+    assert_eq!(call(&mut vm, EXT_BASE, 0, &[]), (SUCCESS, SPEC_VERSION));
+    assert_eq!(call(&mut vm, EXT_BASE, 1, &[]), (SUCCESS, IMPL_ID));
+    for ext in [EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST, EXT_DBCN, EXT_LEGACY_PUTCHAR] {
+        assert_eq!(call(&mut vm, EXT_BASE, 3, &[ext]), (SUCCESS, 1));
+    }
+    assert_eq!(call(&mut vm, EXT_BASE, 3, &[0x4C4F4C]), (SUCCESS, 0));
+    assert_eq!(call(&mut vm, EXT_BASE, 4, &[]), (SUCCESS, 0));
+    assert_eq!(call(&mut vm, 0x4C4F4C, 0, &[]).0, SbiError::NotSupported as i64);
+    // RFENCE has nothing to flush
+    assert_eq!(call(&mut vm, EXT_RFENCE, 1, &[1, 0, 0, u64::MAX]).0, SUCCESS);
+    assert_eq!(call(&mut vm, EXT_RFENCE, 3, &[1, 0]).0, SbiError::NotSupported as i64);
+}
+
+#[test]
+pub fn console() {
+    let (mut vm, console) = booted();
+    vm.mem.write(DATA, b"hello").unwrap();
+    assert_eq!(call(&mut vm, EXT_DBCN, 0, &[5, DATA, 0]), (SUCCESS, 5));
+    assert_eq!(call(&mut vm, EXT_DBCN, 2, &[b'!' as u64]).0, SUCCESS);
+    assert_eq!(call(&mut vm, EXT_LEGACY_PUTCHAR, 0, &[b'\n' as u64]).0, 0);
+    assert_eq!(console.0.borrow().as_slice(), b"hello!\n");
+    assert_eq!(call(&mut vm, EXT_DBCN, 0, &[5, 0x10, 0]).0, SbiError::InvalidParam as i64);
+
+    vm.sbi.as_mut().unwrap().input.extend(b"xyz");
+    assert_eq!(call(&mut vm, EXT_DBCN, 1, &[2, DATA, 0]), (SUCCESS, 2));
+    assert_eq!(vm.mem.get::<u16>(DATA).unwrap(), u16::from_le_bytes(*b"xy"));
+    assert_eq!(call(&mut vm, EXT_LEGACY_GETCHAR, 0, &[]).0, b'z' as i64);
+    assert_eq!(call(&mut vm, EXT_LEGACY_GETCHAR, 0, &[]).0, -1);
+    assert_eq!(call(&mut vm, EXT_DBCN, 1, &[2, DATA, 0]), (SUCCESS, 0));
+}
+
+#[test]
+pub fn timer_and_ipi() {
+    let (mut vm, _) = booted();
+    vm.cpu.set_csr_value(SIE, 1 << 5 | 1 << 1);
+    let deadline = vm.cpu.csr_value(TIME) + 5;
+    assert_eq!(call(&mut vm, EXT_TIME, 0, &[deadline]).0, SUCCESS);
+    vm.cpu.set_csr_value(SSTATUS, 1 << 1); // SIE
+    while vm.cpu.csr_value(TIME) < deadline {
+        vm.step().unwrap();
+        assert_eq!(vm.cpu.csr_value(SCAUSE), 0);
+    }
+    vm.step().unwrap();
+    assert_eq!(vm.cpu.csr_value(SCAUSE), 1 << 63 | 5);
+    assert_eq!(vm.cpu.pc, STVEC_ADDR + 4);
+    // Setting the timer clears the pending interrupt
+    assert_ne!(vm.cpu.csr_value(SIP) & (1 << 5), 0);
+    call(&mut vm, EXT_TIME, 0, &[u64::MAX]);
+    assert_eq!(vm.cpu.csr_value(SIP) & (1 << 5), 0);
+
+    assert_eq!(call(&mut vm, EXT_IPI, 0, &[0b10, 0]).0, SbiError::InvalidParam as i64);
+    assert_eq!(vm.cpu.csr_value(SIP) & (1 << 1), 0);
+    assert_eq!(call(&mut vm, EXT_IPI, 0, &[0, u64::MAX]).0, SUCCESS);
+    assert_ne!(vm.cpu.csr_value(SIP) & (1 << 1), 0);
+}
+
+#[test]
+pub fn harts_and_reset() {
+    let (mut vm, _) = booted();
+    assert_eq!(call(&mut vm, EXT_HSM, 2, &[0]), (SUCCESS, HART_STARTED));
+    assert_eq!(call(&mut vm, EXT_HSM, 2, &[1]).0, SbiError::InvalidParam as i64);
+    assert_eq!(call(&mut vm, EXT_HSM, 0, &[0, BASE, 0]).0, SbiError::AlreadyAvailable as i64);
+    assert_eq!(call(&mut vm, EXT_HSM, 3, &[0, 0, 0]).0, SUCCESS);
+    // Non-retentive suspend resumes at the given address
+    vm.cpu.pc = BASE;
+    vm.cpu.regs[17] = EXT_HSM;
+    vm.cpu.regs[16] = 3;
+    vm.cpu.regs[10..13].copy_from_slice(&[0x8000_0000, STVEC_ADDR, 42]);
+    vm.step().unwrap();
+    assert_eq!((vm.cpu.pc, vm.cpu.regs[10], vm.cpu.regs[11]), (STVEC_ADDR, 0, 42));
+
+    assert_eq!(call(&mut vm, EXT_SRST, 0, &[5, 0]).0, SbiError::InvalidParam as i64);
+    assert_eq!(vm.exit_status(), None);
+    vm.cpu.pc = BASE;
+    vm.cpu.regs[17] = EXT_SRST;
+    vm.cpu.regs[16] = 0;
+    vm.cpu.regs[10..12].copy_from_slice(&[0, RESET_REASON_FAILURE]);
+    assert!(!vm.step().unwrap());
+    assert_eq!(vm.exit_status(), Some(1));
+    assert_eq!(vm.sbi.as_ref().unwrap().reset, Some(Reset { kind: ResetType::Shutdown, reason: 1 }));
+
+    let (mut vm, _) = booted();
+    vm.cpu.regs[17] = EXT_HSM;
+    vm.cpu.regs[16] = 1;
+    assert!(!vm.step().unwrap());
+    assert_eq!(vm.exit_status(), Some(0));
+}
+
+#[test]
+pub fn other_modes_trap() {
+    // U-mode ecalls are delegated to the kernel
+    let (mut vm, _) = booted();
+    vm.cpu.privilege_level = PrivilegeLevel::User;
+    vm.cpu.regs[17] = EXT_BASE;
+    vm.step().unwrap();
+    assert_eq!((vm.cpu.pc, vm.cpu.csr_value(SCAUSE)), (STVEC_ADDR, 8));
+    assert_eq!(vm.cpu.privilege_level, PrivilegeLevel::Supervisor);
+
+    // Without SBI, S-mode ecalls go to M-mode
+    let (mut vm, _) = booted();
+    vm.sbi = None;
+    vm.cpu.set_csr_value(MTVEC, DATA);
+    vm.step().unwrap();
+    assert_eq!((vm.cpu.pc, vm.cpu.csr_value(MCAUSE)), (DATA, 9));
+}
@@ -0,0 +1,180 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use emulator::cpu::csr::file::*;
+use emulator::cpu::trap::Exception;
+use emulator::semihosting::*;
+use emulator::vm::VM;
+
+const BASE: u64 = 0x8000_0000;
+/// Parameter blocks go at DATA, strings and buffers at STRING
+const DATA: u64 = 0x8000_1000;
+const STRING: u64 = DATA + 0x100;
+const CALL: [u32; 3] = [
+    0x01f01013, // slli zero, zero, 31
+    0x00100073, // ebreak
+    0x40705013, // srai zero, zero, 7
+];
+
+/// Console output kept around for the test to look at
+#[derive(Clone, Default)]
+struct Console(Rc<RefCell<Vec<u8>>>);
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn sandbox(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("semihosting-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+fn vm_with(root: PathBuf, cmdline: &str) -> (VM, Console) {
+    let bytes = CALL.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let mut vm = VM::new(bytes, 1 << 20).unwrap();
+    vm.cpu.set_csr_value(MTVEC, BASE + 0x800);
+    let console = Console::default();
+    vm.semihosting = Some(Semihosting::with_console(root, cmdline, Box::new(console.clone())));
+    (vm, console)
+}
+/// Runs the semihosting sequence, returns a0
+fn call(vm: &mut VM, op: u64, param: u64) -> u64 {
+    vm.cpu.pc = BASE;
+    vm.cpu.regs[10] = op;
+    vm.cpu.regs[11] = param;
+    for _ in 0..CALL.len() {
+        vm.step().unwrap();
+    }
+    assert_eq!(vm.cpu.csr_value(MCAUSE), 0, "Unexpected trap");
+    vm.cpu.regs[10]
+}
+/// Writes the parameter block at DATA, with `bytes` after it, and returns its address
+fn params(vm: &mut VM, fields: &[u64], bytes: &[u8]) -> u64 {
+    let block: Vec<u8> = fields.iter().flat_map(|field| field.to_le_bytes()).collect();
+    vm.mem.write(DATA, &block).unwrap();
+    vm.mem.write(STRING, bytes).unwrap();
+    DATA
+}
+/// Same with a parameter block
+fn call_block(vm: &mut VM, op: u64, fields: &[u64], bytes: &[u8]) -> u64 {
+    let param = params(vm, fields, bytes);
+    call(vm, op, param)
+}
+
+#[test]
+pub fn console() {
+    let (mut vm, console) = vm_with(std::env::temp_dir(), "");
+    vm.mem.write(STRING, b"Hello\0").unwrap();
+    assert_eq!(call(&mut vm, SYS_WRITE0, STRING), 0);
+    assert_eq!(call(&mut vm, SYS_WRITEC, STRING + 4), 0);
+    let stdout = call_block(&mut vm, SYS_OPEN, &[STRING, 4, 3], b":tt\0");
+    assert_eq!(call_block(&mut vm, SYS_ISTTY, &[stdout], b""), 1);
+    assert_eq!(call_block(&mut vm, SYS_WRITE, &[stdout, STRING, 3], b"!\n\0"), 0);
+    assert_eq!(console.0.borrow().as_slice(), b"Helloo!\n\0");
+    assert!(call(&mut vm, SYS_CLOCK, 0) < 100);
+}
+
+#[test]
+pub fn files() {
+    let root = sandbox("files");
+    let (mut vm, _) = vm_with(root.clone(), "");
+    let open = |vm: &mut VM, name: &str, mode: u64| {
+        let name = [name.as_bytes(), b"\0"].concat();
+        call_block(vm, SYS_OPEN, &[STRING, mode, name.len() as u64 - 1], &name)
+    };
+
+    let file = open(&mut vm, "out.txt", 4); // w
+    assert_eq!(file, 1);
+    assert_eq!(call_block(&mut vm, SYS_WRITE, &[file, STRING, 11], b"hello world"), 0);
+    assert_eq!(call_block(&mut vm, SYS_CLOSE, &[file], b""), 0);
+    assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hello world");
+
+    let file = open(&mut vm, "./out.txt", 0); // r
+    assert_eq!(call_block(&mut vm, SYS_FLEN, &[file], b""), 11);
+    assert_eq!(call_block(&mut vm, SYS_SEEK, &[file, 6], b""), 0);
+    // Returns the number of bytes not read
+    assert_eq!(call_block(&mut vm, SYS_READ, &[file, STRING, 8], b""), 3);
+    let mut read = [0; 5];
+    vm.mem.read(STRING, &mut read).unwrap();
+    assert_eq!(&read, b"world");
+    assert_eq!(call_block(&mut vm, SYS_CLOSE, &[file], b""), 0);
+
+    // Bad handles, missing files and paths outside of the sandbox fail
+    assert_eq!(call_block(&mut vm, SYS_CLOSE, &[file], b""), u64::MAX);
+    assert_eq!(call(&mut vm, SYS_ERRNO, 0), 9);
+    assert_eq!(open(&mut vm, "missing.txt", 0), u64::MAX);
+    assert_eq!(call(&mut vm, SYS_ERRNO, 0), 2);
+    for escape in ["../escape.txt", "/tmp/escape.txt", "dir/../../escape.txt"] {
+        assert_eq!(open(&mut vm, escape, 4), u64::MAX, "{escape}");
+        assert_eq!(call(&mut vm, SYS_ERRNO, 0), 13);
+    }
+    assert!(!root.parent().unwrap().join("escape.txt").exists());
+    // Symbolic links inside the sandbox can't lead out of it either
+    let outside = sandbox("files-outside");
+    std::os::unix::fs::symlink(&outside, root.join("dir")).unwrap();
+    std::os::unix::fs::symlink(outside.join("escape.txt"), root.join("link.txt")).unwrap();
+    for escape in ["dir/escape.txt", "link.txt"] {
+        assert_eq!(open(&mut vm, escape, 4), u64::MAX, "{escape}");
+        assert_eq!(call(&mut vm, SYS_ERRNO, 0), 13);
+    }
+    assert!(!outside.join("escape.txt").exists());
+    // Bad pointer
+    assert_eq!(call(&mut vm, SYS_WRITE0, 0x10), u64::MAX);
+    std::fs::remove_dir_all(outside).unwrap();
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+pub fn cmdline_and_exit() {
+    let (mut vm, _) = vm_with(std::env::temp_dir(), "kernel --test");
+    assert_eq!(call_block(&mut vm, SYS_GET_CMDLINE, &[STRING, 64], b""), 0);
+    let mut cmdline = [0; 14];
+    vm.mem.read(STRING, &mut cmdline).unwrap();
+    assert_eq!(&cmdline, b"kernel --test\0");
+    assert_eq!(vm.mem.get::<u64>(DATA + 8).unwrap(), 13);
+    // Too small
+    assert_eq!(call_block(&mut vm, SYS_GET_CMDLINE, &[STRING, 13], b""), u64::MAX);
+
+    assert_eq!(vm.exit_status(), None);
+    vm.cpu.pc = BASE;
+    vm.cpu.regs[10] = SYS_EXIT;
+    vm.cpu.regs[11] = params(&mut vm, &[ADP_STOPPED_APPLICATION_EXIT, 3], b"");
+    assert!(vm.step().unwrap());
+    assert!(!vm.step().unwrap());
+    assert_eq!(vm.exit_status(), Some(3));
+
+    let (mut vm, _) = vm_with(std::env::temp_dir(), "");
+    vm.cpu.pc = BASE + 4;
+    vm.cpu.regs[10] = SYS_EXIT;
+    // Any other reason is a failure
+    vm.cpu.regs[11] = params(&mut vm, &[0x20023, 0], b"");
+    assert!(!vm.step().unwrap());
+    assert_eq!(vm.exit_status(), Some(1));
+}
+
+#[test]
+pub fn breakpoints() {
+    // Without the nops around, or with semihosting disabled, ebreak is a breakpoint
+    let (mut vm, _) = vm_with(std::env::temp_dir(), "");
+    vm.mem.set::<u32>(BASE + 8, 0x00000013).unwrap();
+    vm.cpu.pc = BASE + 4;
+    vm.cpu.regs[10] = SYS_WRITEC;
+    vm.step().unwrap();
+    assert_eq!(vm.cpu.csr_value(MCAUSE), Exception::Breakpoint as u64);
+    assert_eq!(vm.cpu.csr_value(MEPC), BASE + 4);
+
+    let (mut vm, console) = vm_with(std::env::temp_dir(), "");
+    vm.semihosting = None;
+    vm.cpu.pc = BASE + 4;
+    vm.step().unwrap();
+    assert_eq!(vm.cpu.csr_value(MCAUSE), Exception::Breakpoint as u64);
+    assert!(console.0.borrow().is_empty());
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use common::{builder, causes, BASE};
+use emulator::board::Board;
+use emulator::cpu::csr::file::*;
+use emulator::cpu::isa;
+use emulator::cpu::reg::Reg;
+use emulator::machine::Machine;
+
+const SSTC: &str = "rv64imac_sstc";
+const STIP: u64 = 1 << 5;
+const VSTIP: u64 = 1 << 6;
+/// mcounteren.TM
+const TM: u64 = 1 << 1;
+
+/// Without PMP entries, S-mode reaches all of memory
+fn machine(isa: &str, source: &str) -> Machine {
+    let mut machine = builder(Board::virt(), source).isa(isa).build().unwrap();
+    machine.hart_mut(0).pmp_entries = 0;
+    machine
+}
+/// The program starts with an mret to S-mode, or VS-mode with `virt`
+fn enter_supervisor(machine: &mut Machine, virt: bool) {
+    machine.set_csr(0, MSTATUS, 1 << 11 | (virt as u64) << 39);
+    machine.set_csr(0, MEPC, BASE + 4);
+}
+
+#[test]
+pub fn isa_string() {
+    let sstc = isa::parse_isa("rv64imac_zicsr_sstc").unwrap();
+    assert_eq!(sstc.extensions, isa::Extensions::SSTC);
+    assert_eq!(emulator::fdt::isa_string(sstc), "rv64imac_zicntr_zicsr_zifencei_sstc");
+    assert!(isa::parse_isa("rv64imac_sstc_zba").is_err(), "S extensions come after Z ones");
+
+    // Without Sstc, stimecmp doesn't exist and STCE stays 0
+    let mut without = machine("rv64imac", "csrrs a0, stimecmp, zero");
+    let without_causes = causes(&mut without);
+    without.set_csr(0, MENVCFG, ENVCFG_STCE | 1);
+    assert_eq!(without.csr(0, MENVCFG), 1);
+    without.run(Some(1)).unwrap();
+    assert_eq!(*without_causes.borrow(), [2]);
+}
+
+#[test]
+pub fn supervisor_timer() {
+    let mut machine = machine(SSTC, "
+        mret
+        csrrw zero, stimecmp, s0
+        csrrsi zero, sstatus, 2
+        loop: j loop
+    ");
+    let causes = causes(&mut machine);
+    enter_supervisor(&mut machine, false);
+    machine.set_csr(0, MENVCFG, ENVCFG_STCE);
+    machine.set_csr(0, MCOUNTEREN, TM);
+    machine.set_csr(0, MIDELEG, STIP);
+    machine.set_csr(0, MIE, STIP);
+    machine.set_csr(0, STVEC, BASE + 0x100);
+    machine.set_reg(0, Reg::s0, 20);
+    // stimecmp starts at 0, STIP is pending right away
+    assert_ne!(machine.csr(0, MIP) & STIP, 0);
+    machine.run(Some(3)).unwrap();
+    assert_eq!(machine.csr(0, STIMECMP), 20);
+    assert_eq!(machine.csr(0, MIP) & STIP, 0);
+    // STIP is read-only, it follows stimecmp
+    machine.set_csr(0, MIP, STIP);
+    assert_eq!(machine.csr(0, MIP) & STIP, 0);
+    machine.run_until(|machine| machine.pc(0) == BASE + 0x100, Some(30)).unwrap();
+    assert!(machine.csr(0, TIME) >= 20);
+    assert_eq!(*causes.borrow(), [1 << 63 | 5]);
+    assert_ne!(machine.csr(0, SIP) & STIP, 0);
+}
+
+#[test]
+pub fn access_checks() {
+    let cases = [(0, 0, 2), (ENVCFG_STCE, 0, 2), (0, TM, 2), (ENVCFG_STCE, TM, 0)];
+    for (menvcfg, mcounteren, cause) in cases {
+        let mut supervisor = machine(SSTC, "
+            mret
+            csrrw zero, stimecmp, s0
+        ");
+        let supervisor_causes = causes(&mut supervisor);
+        enter_supervisor(&mut supervisor, false);
+        supervisor.set_csr(0, MENVCFG, menvcfg);
+        supervisor.set_csr(0, MCOUNTEREN, mcounteren);
+        supervisor.run(Some(2)).unwrap();
+        let expected = if cause == 0 {vec![]} else {vec![cause]};
+        assert_eq!(*supervisor_causes.borrow(), expected, "menvcfg {menvcfg:#x}, mcounteren {mcounteren}");
+    }
+    // The sbi firmware turns it on, sbi_set_timer then writes stimecmp
+    let mut vm = emulator::vm::VM::new(vec![0x73, 0, 0, 0], 16 << 20).unwrap();
+    vm.cpu.extensions = isa::Extensions::SSTC;
+    vm.boot_supervisor(emulator::sbi::Sbi::with_console(Box::new(std::io::sink())), "").unwrap();
+    assert_eq!(vm.cpu.csr_value(MENVCFG), ENVCFG_STCE);
+    vm.cpu.regs[17] = emulator::sbi::EXT_TIME;
+    vm.cpu.regs[10] = 1234;
+    vm.step().unwrap();
+    assert_eq!(vm.cpu.csr_value(STIMECMP), 1234);
+}
+
+#[test]
+pub fn guest_timer() {
+    const H_SSTC: &str = "rv64imach_sstc";
+    let mut guest = machine(H_SSTC, "
+        mret
+        csrrw zero, stimecmp, s0
+        csrrw zero, stimecmp, s0
+    ");
+    let guest_causes = causes(&mut guest);
+    enter_supervisor(&mut guest, true);
+    guest.set_csr(0, MENVCFG, ENVCFG_STCE);
+    guest.set_csr(0, MCOUNTEREN, TM);
+    guest.set_csr(0, HCOUNTEREN, TM);
+    guest.set_reg(0, Reg::s0, 1000);
+    // henvcfg.STCE is missing, the hypervisor can emulate stimecmp
+    guest.run(Some(2)).unwrap();
+    assert_eq!(*guest_causes.borrow(), [22]);
+    // Guests write vstimecmp, which is compared with their time
+    guest.set_csr(0, HENVCFG, ENVCFG_STCE);
+    guest.set_pc(0, BASE);
+    enter_supervisor(&mut guest, true);
+    guest.run(Some(2)).unwrap();
+    assert_eq!(guest.csr(0, VSTIMECMP), 1000);
+    assert_eq!(guest.csr(0, STIMECMP), 0);
+    assert_eq!(guest.csr(0, HIP) & VSTIP, 0);
+    guest.set_csr(0, HTIMEDELTA, 1000);
+    assert_ne!(guest.csr(0, HIP) & VSTIP, 0);
+    assert_eq!(guest.csr(0, HVIP) & VSTIP, 0, "hvip only holds what the hypervisor injects");
+    // henvcfg.STCE reads 0 without menvcfg.STCE
+    guest.set_csr(0, MENVCFG, 0);
+    assert_eq!(guest.csr(0, HENVCFG), 0);
+    assert_eq!(guest.csr(0, HIP) & VSTIP, 0);
+}
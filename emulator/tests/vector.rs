@@ -0,0 +1,318 @@
+mod common;
+
+use common::{builder, causes, traps, BASE};
+use emulator::asm::assemble;
+use emulator::board::Board;
+use emulator::cpu::csr::file::*;
+use emulator::cpu::isa;
+use emulator::cpu::reg::Reg;
+use emulator::cpu::vector::VILL;
+use emulator::machine::{Machine, StopReason};
+use emulator::vm::disasm;
+
+const DATA: u64 = BASE + 0x1000;
+const V: &str = "rv64imacv";
+/// Encodings from llvm-mc
+const ENCODINGS: [(&str, u32); 20] = [
+    ("vsetvli a0 a1 e32 m1 ta ma", 0x0d05f557),
+    ("vsetvli zero zero e8 mf2 tu mu", 0x00707057),
+    ("vsetivli t0 17 e64 m8 ta mu", 0xc5b8f2d7),
+    ("vsetvl a0 a1 a2", 0x80c5f557),
+    ("vadd.vx v4 v8 a0 v0.t", 0x00854257),
+    ("vadd.vi v4 v8 -3", 0x028eb257),
+    ("vsll.vi v2 v4 31", 0x964fb157),
+    ("vmsgtu.vi v1 v2 -1", 0x7a2fb0d7),
+    ("vredmax.vs v1 v2 v3 v0.t", 0x1c21a0d7),
+    ("vmxnor.mm v1 v2 v3", 0x7e21a0d7),
+    ("vnmsub.vx v1 a0 v3 v0.t", 0xac3560d7),
+    ("vmerge.vim v1 v2 5 v0", 0x5c22b0d7),
+    ("vmv.v.i v1 -16", 0x5e0830d7),
+    ("vmv8r.v v8 v16", 0x9f03b457),
+    ("vfirst.m a0 v2 v0.t", 0x4028a557),
+    ("vsext.vf8 v8 v16 v0.t", 0x4901a457),
+    ("vlse16.v v1 (a0) a1", 0x0ab55087),
+    ("vluxseg4ei16.v v4 (a0) v8", 0x66855207),
+    ("vsoxei32.v v1 (a0) v2", 0x0e2560a7),
+    ("vl8re64.v v8 (a0)", 0xe2857407),
+];
+
+fn words(program: &[u8]) -> Vec<u32> {
+    program.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect()
+}
+fn machine(isa: &str, source: &str) -> Machine {
+    builder(Board::virt(), source).isa(isa).build().unwrap()
+}
+/// Runs until the program falls off its end
+fn run(machine: &mut Machine) {
+    assert!(matches!(machine.run(Some(10_000)).unwrap(), StopReason::Halted { .. }));
+}
+/// Turns the vector unit on (mstatus.VS = Initial)
+const ENABLE: &str = "
+    li t0, 0x200
+    csrrs zero, mstatus, t0
+";
+
+#[test]
+pub fn encodings() {
+    for (text, encoding) in ENCODINGS {
+        let program = assemble(text, 0).unwrap();
+        assert_eq!(words(&program), [encoding], "{text}");
+        assert_eq!(disasm(program).unwrap(), format!("{text}\n"));
+    }
+    assert_eq!(words(&assemble("vle32.v v1, (a0), v0.t\nvadd.vv v1, v2, v3", 0).unwrap()), [0x00056087, 0x022180d7]);
+    for invalid in ["vadd.vv v1 v2", "vadd.vi v1 v2 16", "vadd.vv v1 v2 v32", "vle32.v v1 8(a0)", "vsetvli a0 a1 e128 m1 ta ma",
+        "vmerge.vvm v1 v2 v3", "vmand.mm v1 v2 v3 v0.t", "vadd.vv v1 v2 v3 v1.t"] {
+        assert!(assemble(invalid, 0).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+pub fn configuration() {
+    let mut machine = machine(V, &format!("{ENABLE}
+        li a1, 100
+        vsetvli s0, a1, e32, m2, ta, ma
+        csrrs s1, vl, zero
+        csrrs s2, vtype, zero
+        csrrs s3, vlenb, zero
+        vsetvli s4, zero, e8, m8, tu, mu
+        li a1, 3
+        vsetvli zero, a1, e64, m1, ta, ma
+        vsetvli s5, zero, e16, mf2, ta, ma
+        vsetvli zero, zero, e64, mf8, ta, ma
+        csrrs s6, vtype, zero
+        csrrs s7, vl, zero
+        vsetivli s8, 5, e16, m1, ta, ma
+        csrrs s9, mstatus, zero
+    "));
+    assert_eq!(machine.csr(0, VTYPE), VILL);
+    run(&mut machine);
+    let results = [
+        // VLMAX = 128 * 2 / 32
+        (Reg::s0, 8), (Reg::s1, 8), (Reg::s2, 0b1101_0001), (Reg::s3, 16), (Reg::s4, 128), (Reg::s5, 4),
+        // SEW 64 with LMUL 1/8 is illegal
+        (Reg::s6, VILL), (Reg::s7, 0), (Reg::s8, 5),
+    ];
+    for (reg, value) in results {
+        assert_eq!(machine.reg(0, reg), value, "{reg:?}");
+    }
+    // Dirty, and SD with it
+    assert_eq!(machine.reg(0, Reg::s9) & MSTATUS_VS, MSTATUS_VS);
+    assert_ne!(machine.reg(0, Reg::s9) & MSTATUS_SD, 0);
+
+    let mut machine = builder(Board::virt(), &format!("{ENABLE}
+        csrrs s0, vlenb, zero
+        vsetvli s1, zero, e8, m8, ta, ma
+    ")).isa(V).vlen(1024).build().unwrap();
+    run(&mut machine);
+    assert_eq!((machine.reg(0, Reg::s0), machine.reg(0, Reg::s1)), (128, 1024));
+    for vlen in [64, 100, 1 << 17] {
+        assert!(Machine::builder().isa(V).vlen(vlen).build().is_err(), "{vlen}");
+    }
+}
+
+#[test]
+pub fn disabled() {
+    let program = "vsetvli a0, a1, e32, m1, ta, ma\nvle32.v v1, (a0)\ncsrrs a0, vl, zero\ncsrrs a0, vcsr, zero\n";
+    let illegal = |isa: &str, enable: bool| {
+        let mut machine = machine(isa, program);
+        if enable {
+            machine.set_csr(0, MSTATUS, 0x200);
+        }
+        let traps = causes(&mut machine);
+        for i in 0..4 {
+            machine.set_pc(0, BASE + i * 4);
+            machine.run(Some(1)).unwrap();
+        }
+        let traps = traps.borrow().clone();
+        traps
+    };
+    // Without V, and with V but mstatus.VS off
+    assert_eq!(illegal("rv64imac", false), [2; 4]);
+    assert_eq!(illegal("rv64imac", true), [2; 4]);
+    assert_eq!(illegal(V, false), [2; 4]);
+    assert_eq!(illegal(V, true), []);
+
+    // VS can't be turned on without V
+    let mut machine = machine("rv64imac", "nop");
+    machine.set_csr(0, MSTATUS, MSTATUS_VS);
+    assert_eq!(machine.csr(0, MSTATUS) & MSTATUS_VS, 0);
+    assert_eq!(machine.csr(0, MISA), isa::parse("rv64imac").unwrap());
+    let machine = self::machine(V, "nop");
+    assert_ne!(machine.csr(0, MISA) & isa::bit('v'), 0);
+    let dtb = machine.device_tree("");
+    let needle = b"rv64imacv_zicntr_zicsr_zifencei\0";
+    assert!(dtb.windows(needle.len()).any(|window| window == needle));
+}
+
+#[test]
+pub fn memcpy() {
+    // Copies a0 bytes from DATA to DATA + 0x1000 with vectors and to DATA + 0x2000 with a scalar loop
+    let mut machine = machine(V, &format!("{ENABLE}
+        li a0, 1000
+        mv t1, a0
+        mv a4, a1
+    vector:
+        vsetvli t0, t1, e8, m8, ta, ma
+        vle8.v v8, (a4)
+        vse8.v v8, (a2)
+        add a4, a4, t0
+        add a2, a2, t0
+        sub t1, t1, t0
+        bne t1, zero, vector
+    scalar:
+        lbu t2, 0(a1)
+        sb t2, 0(a3)
+        addi a1, a1, 1
+        addi a3, a3, 1
+        addi a0, a0, -1
+        bne a0, zero, scalar
+    "));
+    for (reg, address) in [(Reg::a1, DATA), (Reg::a2, DATA + 0x1000), (Reg::a3, DATA + 0x2000)] {
+        machine.set_reg(0, reg, address);
+    }
+    let source: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 3) as u8).collect();
+    machine.write_bytes(DATA, &source).unwrap();
+    run(&mut machine);
+    let (mut vector, mut scalar) = (vec![0; 1001], vec![0; 1001]);
+    machine.read_bytes(DATA + 0x1000, &mut vector).unwrap();
+    machine.read_bytes(DATA + 0x2000, &mut scalar).unwrap();
+    assert_eq!(vector, scalar);
+    assert_eq!(vector[..1000], source);
+    assert_eq!(vector[1000], 0);
+}
+
+#[test]
+pub fn arithmetic() {
+    let mut machine = machine(V, &format!("{ENABLE}
+        li a0, 8
+        vsetvli zero, a0, e16, m1, ta, ma
+        vid.v v1
+        vadd.vi v2, v1, -3
+        vmul.vv v3, v1, v1
+        vrsub.vx v4, v1, a0
+        vmslt.vx v0, v2, zero
+        vmerge.vim v5, v1, 15, v0
+        vredsum.vs v6, v3, v1
+        vmv.x.s s0, v6
+        vcpop.m s1, v0
+        vmsgt.vi v7, v2, 2
+        vfirst.m s2, v7
+        vmv.v.i v8, 0
+        vadd.vv v8, v1, v4, v0.t
+        vredmax.vs v9, v8, v8
+        vmv.x.s s3, v9
+        vmv.v.x v10, a0
+        vsub.vx v10, v10, a0
+        vmsne.vi v11, v10, 0
+        vfirst.m s4, v11
+        vdivu.vx v12, v3, a0
+        vremu.vx v13, v3, a0
+        vdiv.vv v14, v1, v10
+        vsra.vi v15, v2, 1
+        vmv.x.s s5, v15
+        li a1, -1
+        vsaddu.vx v16, v1, a1
+        csrrs s6, vxsat, zero
+        vmv.x.s s7, v16
+        vsetvli zero, a0, e8, m1, ta, ma
+        vmand.mm v17, v0, v7
+        vmnor.mm v18, v0, v7
+        vcpop.m s8, v18
+        vsetvli zero, a0, e64, m4, ta, ma
+        vsext.vf4 v20, v2
+        vmv.x.s s9, v20
+        viota.m v24, v0
+        vredsum.vs v28, v24, v10
+        vmv.x.s s10, v28
+    "));
+    run(&mut machine);
+    let element = |machine: &Machine, reg: usize, index: usize, sew: usize| machine.hart(0).vector.get(reg, index, sew);
+    let results = [
+        // 0 + 1 + 4 + ... + 49 + v1[0]
+        (Reg::s0, 140), (Reg::s1, 3), (Reg::s2, 6), (Reg::s3, 8), (Reg::s4, u64::MAX), (Reg::s5, u64::MAX - 1),
+        // vmv.x.s sign-extends
+        (Reg::s6, 1), (Reg::s7, u64::MAX), (Reg::s8, 3), (Reg::s9, u64::MAX - 2), (Reg::s10, 18),
+    ];
+    for (reg, value) in results {
+        assert_eq!(machine.reg(0, reg), value, "{reg:?}");
+    }
+    for i in 0..8 {
+        assert_eq!(element(&machine, 2, i, 16), (i as u16).wrapping_sub(3) as u64);
+        assert_eq!(element(&machine, 5, i, 16), if i < 3 {15} else {i as u64});
+        assert_eq!(element(&machine, 8, i, 16), if i < 3 {8} else {0});
+        assert_eq!(element(&machine, 12, i, 16), (i * i / 8) as u64);
+        assert_eq!(element(&machine, 13, i, 16), (i * i % 8) as u64);
+        // Division by zero gives all ones
+        assert_eq!(element(&machine, 14, i, 16), 0xFFFF);
+        assert_eq!(element(&machine, 24, i, 64), i.min(3) as u64);
+    }
+    assert_eq!(machine.csr(0, VSTART), 0);
+}
+
+#[test]
+pub fn accesses() {
+    // Strided, indexed and segment loads, masked stores
+    let mut machine = machine(V, &format!("{ENABLE}
+        li a0, 4
+        vsetvli zero, a0, e32, m1, ta, ma
+        li t0, 12
+        vlse32.v v1, (a1), t0
+        vid.v v2
+        vsll.vi v2, v2, 3
+        vluxei32.v v3, (a1), v2
+        vlseg3e32.v v4, (a1)
+        vmseq.vi v0, v2, 8
+        vse32.v v4, (a2), v0.t
+        vsm.v v0, (a2)
+        vsetvli zero, a0, e16, m1, ta, ma
+        addi a3, a2, 16
+        vs1r.v v6, (a3)
+        addi a3, a2, 32
+        vl1re8.v v7, (a3)
+    "));
+    machine.set_reg(0, Reg::a1, DATA);
+    machine.set_reg(0, Reg::a2, DATA + 0x1000);
+    let words: Vec<u8> = (0..64u32).flat_map(u32::to_le_bytes).collect();
+    machine.write_bytes(DATA, &words).unwrap();
+    let marker: Vec<u8> = (100..116).collect();
+    machine.write_bytes(DATA + 0x1000 + 32, &marker).unwrap();
+    run(&mut machine);
+    let element = |reg: usize, index: usize| machine.hart(0).vector.get(reg, index, 32);
+    for i in 0..4 {
+        assert_eq!(element(1, i), 3 * i as u64);
+        assert_eq!(element(3, i), 2 * i as u64);
+        // Fields go to consecutive registers
+        for field in 0..3 {
+            assert_eq!(element(4 + field, i), (3 * i + field) as u64);
+        }
+        assert_eq!(machine.hart(0).vector.get(7, i, 32), u32::from_le_bytes([100, 101, 102, 103].map(|byte| byte + 4 * i as u8)) as u64);
+    }
+    let mut stored = [0; 32];
+    machine.read_bytes(DATA + 0x1000, &mut stored).unwrap();
+    // vsm.v overwrote the first byte with the mask, then the stored element 1 of v4 at 4
+    assert_eq!(stored[..8], [0b10, 0, 0, 0, 3, 0, 0, 0]);
+    assert_eq!(stored[16..32], (0..4u32).flat_map(|i| (3 * i + 2).to_le_bytes()).collect::<Vec<_>>()[..]);
+}
+
+#[test]
+pub fn faults() {
+    // The third element is out of RAM
+    let mut machine = machine(V, &format!("{ENABLE}
+        li a0, 4
+        vsetvli zero, a0, e64, m2, ta, ma
+        vle64ff.v v2, (a1)
+        csrrs s0, vl, zero
+        vsetvli zero, a0, e64, m2, ta, ma
+        vle64.v v4, (a1)
+    "));
+    let traps = traps(&mut machine);
+    machine.set_reg(0, Reg::a1, BASE + (1 << 20) - 16);
+    machine.write(BASE + (1 << 20) - 8, 42u64).unwrap();
+    // Up to the faulting load
+    machine.run(Some(8)).unwrap();
+    // Fault-only-first shortened vl instead of trapping
+    assert_eq!(machine.reg(0, Reg::s0), 2);
+    assert_eq!(machine.hart(0).vector.get(2, 1, 64), 42);
+    assert_eq!(*traps.borrow(), [(5, 0x8010_0000)]);
+    assert_eq!(machine.csr(0, VSTART), 2);
+}
@@ -0,0 +1,277 @@
+mod common;
+
+use common::{descriptor, notify, virtio_machine, BASE, DESC_WRITE, ENTRIES, TRANSPORT};
+use emulator::machine::Machine;
+use emulator::mem::Memory;
+use emulator::virtio::console::*;
+use emulator::virtio::input::*;
+use emulator::virtio::mmio::*;
+use emulator::virtio::rng::*;
+use emulator::virtio::{Buffer, Chain, Queue, VirtioDevice, CHAIN_READ_MAX};
+
+/// Queue `queue` starts this page, the two of them
+fn page(queue: usize) -> u64 {
+    BASE + 0x1_0000 + 0x2000 * queue as u64
+}
+/// Buffer of descriptor `index` of `queue`
+fn buffer(queue: usize, index: u16) -> u64 {
+    BASE + 0x4_0000 + 0x1000 * queue as u64 + 0x100 * index as u64
+}
+/// Sets the device up with `queues` queues
+fn driver_init(machine: &mut Machine, queues: usize, features: u64) {
+    let queues: Vec<_> = (0..queues).map(|queue| (queue, page(queue))).collect();
+    common::driver_init(machine, features, &queues);
+}
+/// Makes descriptor `index` of `queue` available with `len` bytes of its buffer, `writable` for the device, and notifies
+fn make_available(machine: &mut Machine, queue: usize, index: u16, len: u32, writable: bool) {
+    descriptor(machine, page(queue), index, buffer(queue, index), len, if writable {DESC_WRITE} else {0});
+    common::make_available(machine, page(queue), index);
+    notify(machine, queue);
+}
+/// Sends `data` from descriptor `index` of `queue`
+fn transmit(machine: &mut Machine, queue: usize, index: u16, data: &[u8]) {
+    machine.write_bytes(buffer(queue, index), data).unwrap();
+    make_available(machine, queue, index, data.len() as u32, false);
+}
+/// Entries the device put in the used ring of `queue`, by head and length
+fn used(machine: &mut Machine, queue: usize) -> Vec<(u32, u32)> {
+    common::used(machine, page(queue))
+}
+fn received(machine: &mut Machine, queue: usize, index: u16, len: u32) -> Vec<u8> {
+    let mut data = vec![0; len as usize];
+    machine.read_bytes(buffer(queue, index), &mut data).unwrap();
+    data
+}
+fn device<T: VirtioDevice>(machine: &mut Machine) -> &mut T {
+    machine.vm.virtio_device_mut(0).unwrap()
+}
+
+#[test]
+pub fn rng_seeded() {
+    // First outputs of SplitMix64 from 0
+    let mut prng = SplitMix64::new(0);
+    assert_eq!((prng.next_u64(), prng.next_u64()), (0xE220_A839_7B1D_CDAF, 0x6E78_9E6A_A1B9_65F4));
+
+    let bytes = |seed| {
+        let mut machine = virtio_machine(VirtioRng::seeded(seed));
+        assert_eq!(machine.read::<u32>(TRANSPORT + DEVICE_ID).unwrap(), RNG_ID);
+        driver_init(&mut machine, 1, 0);
+        make_available(&mut machine, 0, 0, 20, true);
+        make_available(&mut machine, 0, 1, 12, true);
+        assert_eq!(used(&mut machine, 0), [(0, 20), (1, 12)]);
+        assert_eq!(device::<VirtioRng>(&mut machine).generated, 32);
+        [received(&mut machine, 0, 0, 20), received(&mut machine, 0, 1, 12)].concat()
+    };
+    let mut expected = vec![0; 32];
+    let mut prng = SplitMix64::new(7);
+    prng.fill(&mut expected[..20]);
+    prng.fill(&mut expected[20..]);
+    assert_eq!(bytes(7), expected, "the same on every run");
+    assert_ne!(bytes(8), expected);
+}
+
+#[test]
+pub fn rng_host() {
+    let mut machine = virtio_machine(VirtioRng::open(RngConfig::Host).unwrap());
+    driver_init(&mut machine, 1, 0);
+    make_available(&mut machine, 0, 0, 64, true);
+    assert_eq!(used(&mut machine, 0), [(0, 64)]);
+    assert_ne!(received(&mut machine, 0, 0, 64), [0; 64]);
+
+    assert_eq!("".parse(), Ok(RngConfig::Seeded(DEFAULT_SEED)));
+    assert_eq!("seed=42".parse(), Ok(RngConfig::Seeded(42)));
+    assert_eq!("seed=0x2a".parse(), Ok(RngConfig::Seeded(42)));
+    assert_eq!("host".parse(), Ok(RngConfig::Host));
+    assert!("seed=x".parse::<RngConfig>().is_err());
+    assert!("egd".parse::<RngConfig>().is_err());
+}
+
+#[test]
+pub fn console_single_port() {
+    let mut machine = virtio_machine(VirtioConsole::new(Vec::new()));
+    assert_eq!(machine.read::<u32>(TRANSPORT + DEVICE_ID).unwrap(), CONSOLE_ID);
+    // Without VIRTIO_CONSOLE_F_MULTIPORT there is port 0 alone, on queues 0 and 1
+    driver_init(&mut machine, 2, 0);
+    transmit(&mut machine, 1, 0, b"hello");
+    assert_eq!(used(&mut machine, 1), [(0, 0)]);
+    assert_eq!(device::<VirtioConsole>(&mut machine).port(0).unwrap().transmitted(), b"hello");
+
+    // Kept until the driver has buffers, then split across them
+    device::<VirtioConsole>(&mut machine).port_mut(0).unwrap().send(b"0123456789");
+    machine.run(Some(10)).unwrap();
+    make_available(&mut machine, 0, 0, 4, true);
+    assert_eq!(used(&mut machine, 0), [(0, 4)]);
+    make_available(&mut machine, 0, 1, 16, true);
+    assert_eq!(used(&mut machine, 0), [(0, 4), (1, 6)]);
+    assert_eq!([received(&mut machine, 0, 0, 4), received(&mut machine, 0, 1, 6)].concat(), b"0123456789");
+}
+
+/// struct virtio_console_control
+fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+    [id.to_le_bytes().as_slice(), &event.to_le_bytes(), &value.to_le_bytes()].concat()
+}
+/// Control messages the driver got
+fn controls(machine: &mut Machine) -> Vec<Vec<u8>> {
+    used(machine, CONTROL_RECEIVE).into_iter().map(|(index, len)| received(machine, CONTROL_RECEIVE, index as u16, len)).collect()
+}
+
+#[test]
+pub fn console_multiport() {
+    let ports = vec![Port::new(""), Port::new("org.example.log")];
+    let mut machine = virtio_machine(VirtioConsole::new(ports));
+    // cols, rows and max_nr_ports
+    assert_eq!(machine.read::<u32>(TRANSPORT + CONFIG).unwrap(), 25 << 16 | 80);
+    assert_eq!(machine.read::<u32>(TRANSPORT + CONFIG + 4).unwrap(), 2);
+    assert_eq!(machine.read::<u32>(TRANSPORT + HOST_FEATURES).unwrap() as u64, F_MULTIPORT | F_EMERG_WRITE);
+    driver_init(&mut machine, 6, F_MULTIPORT);
+    for index in 0..ENTRIES as u16 {
+        make_available(&mut machine, CONTROL_RECEIVE, index, 64, true);
+    }
+
+    transmit(&mut machine, CONTROL_TRANSMIT, 0, &control(0, DEVICE_READY, 1));
+    assert_eq!(controls(&mut machine), [control(0, DEVICE_ADD, 0), control(1, DEVICE_ADD, 0)]);
+    transmit(&mut machine, CONTROL_TRANSMIT, 1, &control(0, PORT_READY, 1));
+    transmit(&mut machine, CONTROL_TRANSMIT, 2, &control(1, PORT_READY, 1));
+    assert_eq!(controls(&mut machine)[2..], [
+        control(0, CONSOLE_PORT, 1), control(0, PORT_OPEN, 1),
+        [control(1, PORT_NAME, 1), b"org.example.log".to_vec()].concat(), control(1, PORT_OPEN, 1),
+    ]);
+    assert!(!device::<VirtioConsole>(&mut machine).port(1).unwrap().is_open());
+    transmit(&mut machine, CONTROL_TRANSMIT, 3, &control(1, PORT_OPEN, 1));
+    assert!(device::<VirtioConsole>(&mut machine).port(1).unwrap().is_open());
+
+    // Port 1 is on queues 4 and 5
+    assert_eq!(receive_queue(1), 4);
+    transmit(&mut machine, 5, 0, b"log line\n");
+    make_available(&mut machine, 4, 0, 64, true);
+    device::<VirtioConsole>(&mut machine).port_mut(1).unwrap().send(b"ack");
+    machine.run(Some(1)).unwrap();
+    assert_eq!(used(&mut machine, 4), [(0, 3)]);
+    assert_eq!(received(&mut machine, 4, 0, 3), b"ack");
+    let console = device::<VirtioConsole>(&mut machine);
+    assert_eq!(console.port(1).unwrap().transmitted(), b"log line\n");
+    assert_eq!(console.port(0).unwrap().transmitted(), b"");
+}
+
+#[test]
+pub fn console_files() {
+    let dir = std::env::temp_dir().join(format!("virtio-console-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("in"), b"typed").unwrap();
+    let option = format!("name=org.example.0,in={},out={}", dir.join("in").display(), dir.join("out").display());
+    let config: PortConfig = option.parse().unwrap();
+    assert_eq!((config.name.as_str(), config.input.clone(), config.output.clone()), ("org.example.0", Some(dir.join("in")), Some(dir.join("out"))));
+    assert_eq!("".parse(), Ok(PortConfig::default()));
+    assert!("path=x".parse::<PortConfig>().is_err());
+    assert!(Port::open(&PortConfig { input: Some(dir.join("missing")), ..Default::default() }).is_err());
+
+    let mut machine = virtio_machine(VirtioConsole::open(&[config]).unwrap());
+    driver_init(&mut machine, 2, F_EMERG_WRITE);
+    transmit(&mut machine, 1, 0, b"written ");
+    // emerg_wr
+    machine.write(TRANSPORT + CONFIG + 8, b'!' as u32).unwrap();
+    assert_eq!(std::fs::read(dir.join("out")).unwrap(), b"written !");
+
+    // The input is read on its own thread
+    make_available(&mut machine, 0, 0, 64, true);
+    for _ in 0..1000 {
+        if !used(&mut machine, 0).is_empty() {break}
+        machine.run(Some(1024)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert_eq!(used(&mut machine, 0), [(0, 5)]);
+    assert_eq!(received(&mut machine, 0, 0, 5), b"typed");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// What the configuration space has for `select` and `subsel`
+fn query(machine: &mut Machine, select: u8, subsel: u8) -> Vec<u8> {
+    machine.write(TRANSPORT + CONFIG, select).unwrap();
+    machine.write(TRANSPORT + CONFIG + 1, subsel).unwrap();
+    let size = machine.read::<u8>(TRANSPORT + CONFIG + 2).unwrap();
+    let mut data = vec![0; size as usize];
+    machine.read_bytes(TRANSPORT + CONFIG + CFG_DATA as u64, &mut data).unwrap();
+    data
+}
+
+#[test]
+pub fn input_config() {
+    let mut machine = virtio_machine(VirtioInput::new(InputKind::Keyboard));
+    assert_eq!(machine.read::<u32>(TRANSPORT + DEVICE_ID).unwrap(), INPUT_ID);
+    assert_eq!(query(&mut machine, CFG_ID_NAME, 0), b"QEMU Virtio Keyboard");
+    assert_eq!(query(&mut machine, CFG_ID_DEVIDS, 0), [6, 0, 0x27, 0x06, 1, 0, 1, 0]);
+    let keys = query(&mut machine, CFG_EV_BITS, EV_KEY as u8);
+    assert_eq!(keys.len(), 32);
+    assert_eq!((keys[0], keys[1], keys[31]), (0xFE, 0xFF, 0x01));
+    assert_eq!(query(&mut machine, CFG_EV_BITS, EV_LED as u8), [0x07]);
+    assert_eq!(query(&mut machine, CFG_EV_BITS, EV_REL as u8), []);
+    assert_eq!(query(&mut machine, CFG_ABS_INFO, 0), []);
+
+    let mut machine = virtio_machine(VirtioInput::new(InputKind::Mouse));
+    assert_eq!(query(&mut machine, CFG_ID_NAME, 0), b"QEMU Virtio Mouse");
+    assert_eq!(query(&mut machine, CFG_EV_BITS, EV_REL as u8), [0x03, 0x01]);
+    assert_eq!(query(&mut machine, CFG_EV_BITS, EV_KEY as u8)[BTN_LEFT as usize / 8..], [0x07]);
+
+    assert_eq!("mouse".parse(), Ok(InputConfig { kind: InputKind::Mouse, script: None }));
+    assert_eq!("keyboard,script=keys.txt".parse(), Ok(InputConfig { kind: InputKind::Keyboard, script: Some("keys.txt".into()) }));
+    assert!("tablet".parse::<InputConfig>().is_err());
+    assert!("mouse,speed=2".parse::<InputConfig>().is_err());
+}
+
+#[test]
+pub fn input_script() {
+    let script: Script = "# type a\nwait 100\npress 30 # KEY_A\nrel 0 -5\n0x2 1 0x10\nsyn\n".parse().unwrap();
+    assert_eq!(script.0, [
+        Step::Wait(100),
+        Step::Event(Event::new(EV_KEY, 30, 1)), Step::Event(Event::syn()),
+        Step::Event(Event::new(EV_KEY, 30, 0)), Step::Event(Event::syn()),
+        Step::Event(Event::new(EV_REL, REL_X, -5)), Step::Event(Event::new(EV_REL, REL_Y, 16)), Step::Event(Event::syn()),
+    ]);
+    assert_eq!("syn\nkey 30".parse::<Script>(), Err("Line 2: Unknown step \"key 30\", they are key, rel, abs, syn, press and wait".into()));
+    assert!("key A 1".parse::<Script>().unwrap_err().starts_with("Line 1: Invalid number"));
+
+    let mut machine = virtio_machine(VirtioInput::new(InputKind::Keyboard).with_script(script));
+    driver_init(&mut machine, 2, 0);
+    for index in 0..ENTRIES as u16 {
+        make_available(&mut machine, EVENT_QUEUE, index, 8, true);
+    }
+    machine.run(Some(50)).unwrap();
+    assert!(used(&mut machine, EVENT_QUEUE).is_empty(), "still waiting");
+    machine.run(Some(100)).unwrap();
+    let events: Vec<Event> = used(&mut machine, EVENT_QUEUE).into_iter()
+        .map(|(index, len)| Event::from_bytes(&received(&mut machine, EVENT_QUEUE, index as u16, len).try_into().unwrap()))
+        .collect();
+    assert_eq!(events.len(), 7);
+    assert_eq!(events[..2], [Event::new(EV_KEY, 30, 1), Event::syn()]);
+    assert_eq!(events[4], Event::new(EV_REL, REL_X, -5));
+    assert!(!device::<VirtioInput>(&mut machine).scripted());
+
+    // From the host side, and LEDs from the driver
+    device::<VirtioInput>(&mut machine).send(Event::new(EV_KEY, 58, 1));
+    machine.run(Some(1)).unwrap();
+    assert_eq!(used(&mut machine, EVENT_QUEUE).len(), 8);
+    transmit(&mut machine, STATUS_QUEUE, 0, &Event::new(EV_LED, 1, 1).bytes());
+    assert_eq!(device::<VirtioInput>(&mut machine).status, [Event::new(EV_LED, 1, 1)]);
+}
+
+#[test]
+pub fn queue_bounds() {
+    let mut mem = Memory::new(1 << 20);
+    // Rings at the end of the address space don't work, they don't overflow
+    let mut queue = Queue::new(8);
+    queue.ready = true;
+    (queue.desc, queue.driver, queue.device) = (u64::MAX - 15, u64::MAX - 1, u64::MAX - 3);
+    assert_eq!(queue.pop(&mut mem), None);
+    queue.push(&mut mem, 0, 0);
+
+    // Only what memory backs is read, a chain can't make the host allocate 4GiB
+    mem.write(BASE, b"data").unwrap();
+    let buffers = vec![
+        Buffer { addr: BASE, len: u32::MAX, writable: false },
+        Buffer { addr: BASE, len: 4, writable: false },
+        Buffer { addr: u64::MAX - 1, len: 4, writable: false },
+    ];
+    assert_eq!(Chain { head: 0, buffers }.read(&mut mem), b"data");
+    let buffers = vec![Buffer { addr: BASE, len: 1 << 20, writable: false }; 128];
+    assert_eq!(Chain { head: 0, buffers }.read(&mut mem).len(), CHAIN_READ_MAX);
+}
@@ -39,6 +39,7 @@ def create_parser(name="Kernel script", args: List[Tuple[list, dict]]=[]):
     parser.add_argument("--qemu-args", default="")
     parser.add_argument("--machine", default="virt")
     parser.add_argument("-q", "--quiet", action=argparse.BooleanOptionalAction)
+    parser.add_argument("--semihosting", action=argparse.BooleanOptionalAction, help="Print and exit through semihosting")
     for _args, _kwargs in args:
         parser.add_argument(*_args, **_kwargs)
     return parser
@@ -62,6 +63,7 @@ import subprocess
 def build_kernel(args: argparse.ArgumentParser):
     if args.quiet:args.build_args += " -q "
     args.build_args += "--features log/max_level_"+args.log_level+" "
+    if args.semihosting:args.build_args += "--features semihosting "
     c = list(_strip_empty_cmd(f"cargo b --profile {args.profile} {args.build_args}"))
     try:
         output = subprocess.check_output(c)
@@ -87,12 +89,14 @@ def qemu_cmd(args: argparse.ArgumentParser):
         case unsupported_machine:print(f"Unsupported machine: {unsupported_machine}");exit(1)
     if args.machine != "virt":
         print("Those machines are not greatly supported, beware !")
+    semihosting = "-semihosting-config enable=on,target=native" if args.semihosting else ""
     cmd = f"""qemu-system-riscv64 
                         -kernel {config().kernel_file()}
                         -nographic -serial mon:stdio -bios none 
                         {virtio}
                         -d guest_errors,unimp
                         {machine}
+                        {semihosting}
                         {args.qemu_args}"""
     # gpu: rutabaga: ,gfxstream-vulkan=on,hostmem=2G
     return subprocess.Popen(_strip_empty_cmd(cmd), stdout=subprocess.PIPE)
@@ -0,0 +1,86 @@
+// Advanced Interrupt Architecture backend, used in place of the PLIC on QEMU's -machine virt,aia=aplic-imsic
+// The M-level APLIC domain turns the lines of the devices into MSIs to the M-level IMSIC of hart 0,
+// mtrap then claims them through mtopei, the identity of an interrupt is its source number
+// The device tree tells whether there is one (compatible "riscv,imsics")
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::*;
+
+/// M-level domain of the APLIC and interrupt files of the IMSIC (see QEMU's hw/riscv/virt.c)
+const APLIC_M: usize = 0x0c00_0000;
+const IMSIC_M: usize = 0x2400_0000;
+// APLIC registers
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG: usize = 0x0004;
+const MMSIADDRCFG: usize = 0x1BC0;
+const MMSIADDRCFGH: usize = 0x1BC4;
+const SETIPNUM: usize = 0x1CDC;
+const SETIENUM: usize = 0x1EDC;
+const TARGET: usize = 0x3004;
+/// domaincfg: interrupts enabled, MSI delivery mode
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+/// sourcecfg.SM, level high like the lines of the UART and the virtio devices
+const SOURCE_LEVEL_HIGH: u32 = 6;
+// miselect values
+const EIDELIVERY: u64 = 0x70;
+const EITHRESHOLD: u64 = 0x72;
+const EIE0: u64 = 0xC0;
+
+/// UART0 and the virtio devices, like with the PLIC
+const SOURCES: [u32; 9] = [1, 2, 3, 4, 5, 6, 7, 8, 10];
+
+static AIA: AtomicBool = AtomicBool::new(false);
+
+pub fn enabled() -> bool {
+    AIA.load(Ordering::Relaxed)
+}
+
+fn aplic_write(reg: usize, value: u32) {
+    unsafe {((APLIC_M + reg) as *mut u32).write_volatile(value)}
+}
+
+/// Machine mode part, called by `traps::init` before it leaves M-mode
+/// The interrupt file is only reachable through the M-mode CSRs (by number, for assemblers that don't know Smaia)
+pub fn init_machine() {
+    if !fdt::has_compatible("riscv,imsics") {return}
+    AIA.store(true, Ordering::Relaxed);
+    let enabled = SOURCES.iter().fold(0u64, |bits, source| bits | 1 << source);
+    unsafe {
+        csrw!("0x350", EIDELIVERY);
+        csrw!("0x351", 1u64);
+        csrw!("0x350", EITHRESHOLD);
+        csrw!("0x351", 0u64);
+        csrw!("0x350", EIE0);
+        csrw!("0x351", enabled);
+    }
+}
+
+pub fn init() {
+    info!("Initialising APLIC and IMSIC...");
+    aplic_write(DOMAINCFG, 0);
+    aplic_write(MMSIADDRCFG, (IMSIC_M >> 12) as u32);
+    aplic_write(MMSIADDRCFGH, 0);
+    for source in SOURCES {
+        let offset = (source as usize - 1) * 4;
+        aplic_write(SOURCECFG + offset, SOURCE_LEVEL_HIGH);
+        // Hart 0, the identity is the source number
+        aplic_write(TARGET + offset, source);
+        aplic_write(SETIENUM, source);
+    }
+    aplic_write(DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+}
+
+/// Top interrupt of the interrupt file, it stops being pending
+pub fn claim_int() -> Option<u32> {
+    let topei: u64;
+    unsafe {core::arch::asm!("csrrw {}, 0x35C, zero", out(reg) topei)};
+    let int = (topei >> 16) as u32;
+    if int == 0 {return None}
+    Some(int)
+}
+/// In MSI mode a level sensitive source only sends another MSI on a new edge,
+/// it is made pending again if its line is still high
+pub fn eoi(int: u32) {
+    aplic_write(SETIPNUM, int);
+}
@@ -8,6 +8,7 @@ _start:
     # Any hardware threads (hart) that are not bootstrapping
     # need to wait for an IPI
     csrr t0, mhartid # read our hart identifier into the register t0 (control status register read)
+    mv s1, a1 # Address of the device tree, a1 is used below
     bnez t0, 3f # Check if it is zero, if not, send the hart in a busy loop
     # SATP should be zero, but let's make sure
     csrw satp, zero # Set Supervisor Address Translation and Protection to 0, because we don't have MMU for now
@@ -36,6 +37,7 @@ _start:
     csrw	mstatus, t0
     csrw	mie, zero # Don't want interrupts when we haven't set them up yet
     la		t1, kinit # Load address of our main function (see src/main.rs)
+    mv		a0, s1 # kinit(dtb)
     csrw	mepc, t1 # Set Machine Exception Program Counter
     la		ra, 3f
     # We use mret here so that the mstatus register is properly updated.
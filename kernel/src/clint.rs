@@ -29,9 +29,9 @@ impl CLINT {
         unsafe {*(base.addr::<T>()).byte_add(offset)}
     }
     pub fn current_time(&self) -> Duration {
-        Duration::from_millis(self.current_cycles()/10_000)
+        Duration::from_micros((self.current_cycles() as u128 * 1_000_000 / crate::timer::frequency() as u128) as u64)
     }
-    /// Returns amount of cycles since last reset, 10_000_000/s on QEMU
+    /// Returns amount of cycles since last reset, `timer::frequency` per second
     pub fn current_cycles(&self) -> u64 {
         self.read::<u64>(CLINTRegs::MTime, 0)
     }
@@ -48,9 +48,7 @@ impl CLINT {
     /// Triggers an interrupt in `duration` **cycles**
     /// Try because if msip is set to 0 it won't work ! 
     pub fn try_trigger_in(&mut self, duration: Duration) {
-        // 10k cycles/ms
-        let cycles = duration.as_millis()*10_000;
-        // Can't do nanoseconds tho...
+        let cycles = duration.as_micros() * crate::timer::frequency() as u128 / 1_000_000;
         if cycles > u64::MAX as _ {todo!()}
         let cycles = cycles as u64;
         self.set_mtimecmp(self.current_cycles()+cycles)
@@ -0,0 +1,111 @@
+// Just enough of the flattened device tree format to read properties (Devicetree Specification, chapter 5)
+// QEMU's reset vector leaves its address in a1, boot.s hands it to kinit
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::*;
+
+const MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Address of the device tree blob, 0 when there is none
+static DTB: AtomicUsize = AtomicUsize::new(0);
+
+/// Big endian word at `addr`
+fn read_be32(addr: usize) -> u32 {
+    u32::from_be(unsafe {(addr as *const u32).read_volatile()})
+}
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+/// Null terminated string at `addr`
+fn read_str(addr: usize) -> &'static str {
+    let mut len = 0;
+    while unsafe {*((addr + len) as *const u8)} != 0 {len += 1}
+    core::str::from_utf8(unsafe {core::slice::from_raw_parts(addr as *const u8, len)}).unwrap_or("")
+}
+
+/// Keeps `dtb` if it points to a device tree
+pub fn init(dtb: usize) {
+    if dtb == 0 || dtb % 8 != 0 || read_be32(dtb) != MAGIC {
+        warn!("No device tree at {:#x}", dtb);
+        return
+    }
+    DTB.store(dtb, Ordering::Relaxed);
+}
+pub fn available() -> bool {
+    DTB.load(Ordering::Relaxed) != 0
+}
+
+/// Calls `f` with the name of the node, the name of the property and its value, for every property of the tree
+pub fn for_each_property(mut f: impl FnMut(&str, &str, &[u8])) {
+    let dtb = DTB.load(Ordering::Relaxed);
+    if dtb == 0 {return}
+    let structs = dtb + read_be32(dtb + 8) as usize;
+    let strings = dtb + read_be32(dtb + 12) as usize;
+    let mut node = "";
+    let mut offset = 0;
+    loop {
+        let token = read_be32(structs + offset);
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                node = read_str(structs + offset);
+                offset = align4(offset + node.len() + 1);
+            },
+            FDT_PROP => {
+                let len = read_be32(structs + offset) as usize;
+                let name = read_str(strings + read_be32(structs + offset + 4) as usize);
+                let value = unsafe {core::slice::from_raw_parts((structs + offset + 8) as *const u8, len)};
+                f(node, name, value);
+                offset = align4(offset + 8 + len);
+            },
+            FDT_END_NODE | FDT_NOP => {},
+            FDT_END => return,
+            _ => {
+                warn!("Invalid device tree token {} at {:#x}", token, structs + offset - 4);
+                return
+            },
+        }
+    }
+}
+
+/// Whether a cpu node lists `extension` in riscv,isa-extensions or its riscv,isa string (e.g. "sstc")
+pub fn has_isa_extension(extension: &str) -> bool {
+    let mut found = false;
+    for_each_property(|node, name, value| {
+        if !node.starts_with("cpu@") {return}
+        let strings = || value.split(|byte| *byte == 0).filter_map(|string| core::str::from_utf8(string).ok());
+        found |= match name {
+            "riscv,isa-extensions" => strings().any(|listed| listed == extension),
+            // Multi-letter extensions follow the single letter ones, separated by underscores
+            "riscv,isa" => strings().any(|isa| isa.split('_').skip(1).any(|listed| listed == extension)),
+            _ => false,
+        };
+    });
+    found
+}
+
+/// timebase-frequency of the cpus node, the ticks per second of mtime and the time CSR
+pub fn timebase_frequency() -> Option<u64> {
+    let mut frequency = None;
+    for_each_property(|node, name, value| {
+        if node != "cpus" || name != "timebase-frequency" {return}
+        // One or two cells
+        frequency = value.chunks_exact(4).try_fold(0u64, |acc, cell| Some(acc << 32 | u32::from_be_bytes(cell.try_into().ok()?) as u64));
+    });
+    frequency
+}
+
+/// Whether a node has `compatible` in its compatible list (e.g. "riscv,imsics")
+pub fn has_compatible(compatible: &str) -> bool {
+    let mut found = false;
+    for_each_property(|_, name, value| {
+        if name != "compatible" {return}
+        found |= value.split(|byte| *byte == 0).any(|listed| listed == compatible.as_bytes());
+    });
+    found
+}
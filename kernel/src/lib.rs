@@ -17,6 +17,8 @@ pub use log::{trace,debug,info,warn,error};
 pub mod riscv;
 pub use riscv::*;
 pub mod tests;
+#[cfg(feature="semihosting")]
+pub mod semihosting;
 
 pub mod uart;
 pub mod logging;
@@ -28,7 +30,10 @@ pub mod pmp;
 
 pub mod traps;
 pub mod plic;
+pub mod aia;
 pub mod clint;
+pub mod timer;
+pub mod fdt;
 pub mod virtio;
 
 pub mod thread;
@@ -34,6 +34,9 @@ macro_rules! dbg_bits {
 macro_rules! print {
     ($($args:tt)+) => ({
         use core::fmt::Write;
+        #[cfg(feature="semihosting")]
+        let _ = write!($crate::semihosting::Console, $($args)+);
+        #[cfg(not(feature="semihosting"))]
         #[allow(clippy::macro_metavars_in_unsafe)]
         let _ = write!(unsafe{$crate::logging::STDIO_UART.lock()}, $($args)+);
     });
@@ -5,15 +5,16 @@ use kernel::*;
 core::arch::global_asm!(include_str!("boot.s"));
 
 #[no_mangle] // Machine mode
-extern "C" fn kinit() {
+extern "C" fn kinit(dtb: usize) {
     kernel::logging::init();
+    kernel::fdt::init(dtb);
     kernel::heap::init();
     kernel::pmp::init(); // Needed by QEMU for mret, see https://stackoverflow.com/questions/69133848/risc-v-illegal-instruction-exception-when-switching-to-supervisor-mode
     
     kernel::traps::init();
     // Supervisor mode
-    kernel::plic::init();
-    kernel::clint::init();
+    if kernel::aia::enabled() {kernel::aia::init()} else {kernel::plic::init()}
+    kernel::timer::init();
     kernel::paging::init();
     kernel::virtio::init();
     info!("Done");
@@ -194,8 +194,20 @@ pub fn read_sp() -> u64 {
 
 // Thx to https://unix.stackexchange.com/questions/645618/writing-an-os-shutdown-process-for-qemu-xv6
 pub fn poweroff() {
-    unsafe{crate::map!(0x100000)};
-    unsafe {core::ptr::write_volatile(0x100000 as *mut _, 0x5555u16)}
+    exit(0)
+}
+/// Stops the machine, QEMU exits with `status`
+pub fn exit(status: u32) -> ! {
+    #[cfg(feature="semihosting")]
+    crate::semihosting::exit(status);
+    #[cfg(not(feature="semihosting"))]
+    {
+        unsafe{crate::map!(0x100000)};
+        // The test device takes the exit code in the upper bits of a FAIL
+        let value = if status == 0 {0x5555} else {status << 16 | 0x3333};
+        unsafe {core::ptr::write_volatile(0x100000 as *mut _, value)}
+        spin_loop()
+    }
 }
 pub fn reboot() {
     unsafe{crate::map!(0x100000)};
@@ -0,0 +1,53 @@
+// RISC-V semihosting, lets the host (QEMU with -semihosting-config enable=on, or our emulator with --semihosting)
+// print and stop the machine, without needing the UART or the test device
+// See https://github.com/riscv-non-isa/riscv-semihosting
+
+const SYS_WRITE0: usize = 0x04;
+const SYS_EXIT: usize = 0x18;
+const ADP_STOPPED_APPLICATION_EXIT: usize = 0x20026;
+
+/// # Safety
+/// `arg` has to be what `op` expects, usually a pointer to a parameter block
+pub unsafe fn call(op: usize, arg: usize) -> usize {
+    let mut res = op;
+    // The three instructions have to be uncompressed and in the same page, so that the host can check the nops
+    unsafe {core::arch::asm!(
+        ".option push",
+        ".option norvc",
+        ".balign 16",
+        "slli zero, zero, 0x1f",
+        "ebreak",
+        "srai zero, zero, 7",
+        ".option pop",
+        inout("a0") res,
+        in("a1") arg,
+        options(nostack),
+    )};
+    res
+}
+
+/// Prints on the host's console
+pub fn write(s: &str) {
+    // SYS_WRITE0 takes a null terminated string
+    let mut buf = [0u8; 128];
+    for chunk in s.as_bytes().chunks(buf.len()-1) {
+        buf[..chunk.len()].copy_from_slice(chunk);
+        buf[chunk.len()] = 0;
+        unsafe {call(SYS_WRITE0, buf.as_ptr() as usize)};
+    }
+}
+
+/// Stops the machine, the host exits with `status`
+pub fn exit(status: u32) -> ! {
+    let block = [ADP_STOPPED_APPLICATION_EXIT, status as usize];
+    unsafe {call(SYS_EXIT, block.as_ptr() as usize)};
+    crate::riscv::spin_loop()
+}
+
+pub struct Console;
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write(s);
+        Ok(())
+    }
+}
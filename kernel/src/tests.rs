@@ -17,9 +17,11 @@ pub fn test_all() {
 
 
 pub fn close_qemu() -> ! {
+    exit_qemu(0)
+}
+pub fn exit_qemu(status: u32) -> ! {
     crate::print!("FLAG_EO_TESTS"); // Interpreted in run.py and test.py to close qemu
-    poweroff();
-    unreachable!()
+    riscv::exit(status)
 }
 
 pub fn log_err() {
@@ -60,5 +62,5 @@ fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
             log::warn!("Panic callback returned !");
         }
     }
-    close_qemu()
+    exit_qemu(1)
 }
\ No newline at end of file
@@ -0,0 +1,66 @@
+// Periodic timer interrupt, from one of two backends:
+// - Sstc: S-mode arms stimecmp itself and takes the supervisor timer interrupt in strap
+// - CLINT: only M-mode gets the machine timer interrupt, mtrap re-arms mtimecmp (see `clint::handle_int`)
+// Sstc is used when the device tree reports it
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::*;
+
+/// Time between two timer interrupts
+pub const TICK: Duration = Duration::from_secs(1);
+/// mtime ticks per second when the device tree doesn't say, 10_000_000 on QEMU
+pub const FREQUENCY: u64 = 10_000_000;
+/// menvcfg.STCE, lets S-mode use stimecmp
+const MENVCFG_STCE: u64 = 1 << 63;
+/// mcounteren.TM, lets S-mode read time
+const MCOUNTEREN_TM: u64 = 1 << 1;
+
+static SSTC: AtomicBool = AtomicBool::new(false);
+static TIMEBASE: AtomicU64 = AtomicU64::new(FREQUENCY);
+
+/// mtime ticks per second
+pub fn frequency() -> u64 {
+    TIMEBASE.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sstc,
+    Clint,
+}
+pub fn backend() -> Backend {
+    if SSTC.load(Ordering::Relaxed) {Backend::Sstc} else {Backend::Clint}
+}
+
+/// Machine mode part, called by `traps::init` before it leaves M-mode
+/// Gives S-mode stimecmp, time and the supervisor timer interrupt when the device tree reports Sstc
+pub fn init_machine() {
+    if let Some(frequency) = fdt::timebase_frequency().filter(|frequency| *frequency != 0) {
+        TIMEBASE.store(frequency, Ordering::Relaxed);
+    }
+    if !fdt::has_isa_extension("sstc") {return}
+    SSTC.store(true, Ordering::Relaxed);
+    unsafe {
+        csrw!("menvcfg", csrr!("menvcfg") | MENVCFG_STCE);
+        csrw!("mcounteren", csrr!("mcounteren") | MCOUNTEREN_TM);
+        csrw!("mideleg", csrr!("mideleg") | traps::Interrupts::SupervisorTimer as u64);
+    }
+}
+
+/// Arms the first timer interrupt, every interrupt then arms the next one
+pub fn init() {
+    info!("Initialising timer ({:?})...", backend());
+    handle_int()
+}
+
+pub fn handle_int() {
+    match backend() {
+        Backend::Sstc => {
+            let cycles = TICK.as_micros() as u64 * frequency() / 1_000_000;
+            // stimecmp, by number for assemblers that don't know Sstc
+            unsafe {csrw!("0x14D", csrr!("time") + cycles)}
+        },
+        Backend::Clint => clint::handle_int(),
+    }
+}
@@ -95,6 +95,15 @@ pub enum Exceptions {
 }
 
 
+/// Interrupt source `int` of the PLIC or the APLIC, they are numbered the same
+fn handle_external_int(int: u32) {
+    match int {
+        10 => logging::handle_int(),
+        1..=8 => {virtio::handle_int(int)},
+        _ => todo!("Support this interrupt: {} !", int)
+    }
+}
+
 #[no_mangle]
 extern "C" fn mtrap() {
     let cause = csrr!("mcause", u64);
@@ -108,14 +117,18 @@ extern "C" fn mtrap() {
             5 => {println!("Supervisor timer interrupt")},
             7 => {clint::handle_int()},
             9 => {println!("Supervisor external interrupt")},
+            11 if aia::enabled() => {
+                if let Some(int) = aia::claim_int() {
+                    handle_external_int(int);
+                    aia::eoi(int);
+                } else {
+                    warn!("Spurious interrupt ?!");
+                }
+            },
             11 => {
                 use plic::PLIC;
                 if let Some(int) = PLIC.claim_int() {
-                    match int {
-                        10 => logging::handle_int(),
-                        1..=8 => {virtio::handle_int(int)},
-                        _ => todo!("Support this interrupt: {} !", int)
-                    }
+                    handle_external_int(int);
                     unsafe { PLIC.eoi(int) };
                 } else {
                     warn!("Spurious interrupt ?!");
@@ -163,7 +176,7 @@ extern "C" fn strap() {
         match id {
             1 => {println!("Supervisor software interrupt")},
             3 => {println!("Machine software interrupt")},
-            5 => {println!("Supervisor timer interrupt")},
+            5 => {timer::handle_int()},
             7 => {println!("Machine timer interrupt")},
             9 => {println!("Supervisor external interrupt")},
             11 => {println!("Machine external interrupt")},
@@ -380,6 +393,9 @@ pub fn init() {
     supervisor_mstatus.set_spie(true);
     supervisor_mstatus.set_mie(true);
     supervisor_mstatus.set_sie(true);
+    // Before sie is written, the supervisor timer interrupt can be delegated
+    timer::init_machine();
+    aia::init_machine();
 
     unsafe{
         csrw!("mstatus", supervisor_mstatus.0);
@@ -1,17 +1,35 @@
 use super::*;
 
-pub const SUPPORTED_FEATURES: u32 = 0;
+/// VIRTIO_CONSOLE_F_MULTIPORT, the configuration has the number of ports
+pub const SUPPORTED_FEATURES: u32 = 1<<1;
 
-pub fn init_device(_mmio: StandardVirtIO) -> Option<VirtIODevicePtr> {
-    todo!()
+pub fn init_device(mmio: StandardVirtIO) -> Option<VirtIODevicePtr> {
+    let dev = ConsoleDevice { mmio };
+    let (cols, rows) = dev.size();
+    log::info!("Found console with {} ports ({cols}x{rows})", dev.max_ports());
+    log::warn!("TODO Console queues");
+    Some(VirtIODevicePtr::Console(Box::new(dev)))
 }
 
 
 pub struct ConsoleDevice {
-    
+    mmio: StandardVirtIO,
+}
+impl ConsoleDevice {
+    /// Device configuration space, struct virtio_console_config
+    fn config_byte(&self, offset: usize) -> u8 {
+        unsafe {((self.mmio.base() + MmioOffset::Config as usize + offset) as *const u8).read_volatile()}
+    }
+    pub fn size(&self) -> (u16, u16) {
+        (u16::from_le_bytes([self.config_byte(0), self.config_byte(1)]), u16::from_le_bytes([self.config_byte(2), self.config_byte(3)]))
+    }
+    pub fn max_ports(&self) -> u32 {
+        u32::from_le_bytes([self.config_byte(4), self.config_byte(5), self.config_byte(6), self.config_byte(7)])
+    }
 }
 impl VirtIODevice for ConsoleDevice {
     fn handle_int(&mut self) {
-        todo!()
+        let status = self.mmio.read(MmioOffset::InterruptStatus);
+        unsafe {self.mmio.write(MmioOffset::InterruptAck, status)};
     }
-}
\ No newline at end of file
+}
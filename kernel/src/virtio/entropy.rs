@@ -2,13 +2,18 @@ use super::*;
 
 pub const SUPPORTED_FEATURES: u32 = 0;
 
-pub fn init_device(_mmio: StandardVirtIO) -> Option<VirtIODevicePtr> {
-    todo!()
+pub fn init_device(mmio: StandardVirtIO) -> Option<VirtIODevicePtr> {
+    log::info!("Found entropy source");
+    log::warn!("TODO Entropy queue");
+    Some(VirtIODevicePtr::Entropy(Box::new(EntropyDevice { mmio })))
 }
 
-pub struct EntropyDevice {}
+pub struct EntropyDevice {
+    mmio: StandardVirtIO,
+}
 impl VirtIODevice for EntropyDevice {
     fn handle_int(&mut self) {
-        todo!()
+        let status = self.mmio.read(MmioOffset::InterruptStatus);
+        unsafe {self.mmio.write(MmioOffset::InterruptAck, status)};
     }
 }
@@ -1,14 +1,37 @@
 use super::*;
 
 pub const SUPPORTED_FEATURES: u32 = 0;
+/// VIRTIO_INPUT_CFG_ID_NAME, what select picks in the configuration space
+const CFG_ID_NAME: u8 = 1;
 
-pub fn init_device(_mmio: StandardVirtIO) -> Option<VirtIODevicePtr> {
-    todo!()
+pub fn init_device(mmio: StandardVirtIO) -> Option<VirtIODevicePtr> {
+    let mut dev = InputDevice { mmio };
+    log::info!("Found input device {:?}", dev.name());
+    log::warn!("TODO Input queues");
+    Some(VirtIODevicePtr::Input(Box::new(dev)))
 }
 
-pub struct InputDevice {}
+pub struct InputDevice {
+    mmio: StandardVirtIO,
+}
+impl InputDevice {
+    /// Device configuration space, struct virtio_input_config: select, subsel, size, then the data at 8
+    fn config_ptr(&self, offset: usize) -> *mut u8 {
+        (self.mmio.base() + MmioOffset::Config as usize + offset) as *mut u8
+    }
+    pub fn name(&mut self) -> String {
+        unsafe {
+            self.config_ptr(0).write_volatile(CFG_ID_NAME);
+            self.config_ptr(1).write_volatile(0);
+            let size = self.config_ptr(2).read_volatile() as usize;
+            let bytes: Vec<u8> = (0..size).map(|i| self.config_ptr(8 + i).read_volatile()).collect();
+            String::from_utf8_lossy(&bytes).into()
+        }
+    }
+}
 impl VirtIODevice for InputDevice {
     fn handle_int(&mut self) {
-        todo!()
+        let status = self.mmio.read(MmioOffset::InterruptStatus);
+        unsafe {self.mmio.write(MmioOffset::InterruptAck, status)};
     }
 }
@@ -1,18 +1,48 @@
 use super::*;
 
-pub const SUPPORTED_FEATURES: u32 = 0;
+/// VIRTIO_NET_F_MAC and VIRTIO_NET_F_STATUS, the configuration has the MAC and the link status
+pub const SUPPORTED_FEATURES: u32 = 1<<5 | 1<<16;
+/// VIRTIO_NET_S_LINK_UP
+const LINK_UP: u16 = 1;
 
 pub fn init_device(mmio: StandardVirtIO) -> Option<VirtIODevicePtr> {
-    let dev = NetworkDevice {mmio};
-    log::warn!("TODO Network device");
+    let dev = NetworkDevice::new(mmio);
+    log::info!("Found network card {} (link {})", dev.mac_string(), if dev.link_up() {"up"} else {"down"});
+    log::warn!("TODO Network queues");
     Some(VirtIODevicePtr::Network(Box::new(dev)))
 }
 
 pub struct NetworkDevice {
     mmio: StandardVirtIO,
+    pub mac: [u8; 6],
+}
+impl NetworkDevice {
+    pub fn new(mmio: StandardVirtIO) -> Self {
+        let mut dev = Self { mmio, mac: [0; 6] };
+        for (i, byte) in dev.mac.iter_mut().enumerate() {
+            *byte = dev.config_byte(i);
+        }
+        dev
+    }
+    /// Device configuration space, struct virtio_net_config
+    fn config_byte(&self, offset: usize) -> u8 {
+        unsafe {((self.mmio.base() + MmioOffset::Config as usize + offset) as *const u8).read_volatile()}
+    }
+    pub fn link_up(&self) -> bool {
+        u16::from_le_bytes([self.config_byte(6), self.config_byte(7)]) & LINK_UP != 0
+    }
+    pub fn mac_string(&self) -> String {
+        let m = self.mac;
+        format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+    }
 }
 impl VirtIODevice for NetworkDevice {
     fn handle_int(&mut self) {
-        todo!()
+        let status = self.mmio.read(MmioOffset::InterruptStatus);
+        unsafe {self.mmio.write(MmioOffset::InterruptAck, status)};
+        // Configuration change
+        if status & 2 != 0 {
+            log::info!("Network card {}: link {}", self.mac_string(), if self.link_up() {"up"} else {"down"});
+        }
     }
 }
\ No newline at end of file
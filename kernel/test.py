@@ -1,6 +1,8 @@
 import script_lib
 args = script_lib.parse_args(args=[[["--tests"], {"default": "all"}]])
 args.build_args += '--features testing '
+# Writes go to a temporary overlay, disk.hdd stays as checked in
+args.qemu_args += ' -snapshot'
 
 import os
 raw = ""